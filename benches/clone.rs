@@ -0,0 +1,42 @@
+//! Compares cloning a module sharing the sample data with copying all of it.
+//!
+//! Run with `cargo bench --bench clone`.
+
+use ittech::error::VerboseError;
+use ittech::parser;
+use std::hint::black_box;
+use std::time::Instant;
+
+const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+
+/// Length of the data of every sample, about a minute at 44.1 kHz
+const SAMPLE_LENGTH: usize = 44_100 * 60;
+
+const ITERATIONS: u32 = 100;
+
+fn measure(name: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{:<12} {:>12.3?} per iteration", name, elapsed);
+}
+
+fn main() {
+    let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+    for sample in &mut module.samples {
+        sample.data = Some(vec![0.25; SAMPLE_LENGTH].into());
+    }
+
+    measure("clone", || {
+        black_box(module.clone());
+    });
+    measure("deep copy", || {
+        let mut copy = module.clone();
+        for sample in &mut copy.samples {
+            sample.data.as_mut().unwrap().make_mut();
+        }
+        black_box(copy);
+    });
+}
@@ -0,0 +1,56 @@
+//! Measures decoding of uncompressed and compressed sample data.
+//!
+//! Run with `cargo bench --bench decode`, and with `--features simd` to compare the vectorized
+//! decoding.
+
+use ittech::error::VerboseError;
+use ittech::parser;
+use ittech::writer::{self, WriteOptions};
+use std::hint::black_box;
+use std::time::Instant;
+
+const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+
+/// Length of the data of the sample, about a minute at 44.1 kHz
+const SAMPLE_LENGTH: usize = 44_100 * 60;
+
+const ITERATIONS: u32 = 20;
+
+fn measure(name: &str, mut f: impl FnMut()) {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        f();
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    println!("{:<20} {:>12.3?} per iteration", name, elapsed);
+}
+
+/// Writes the module with a sample of 8-bit or 16-bit data.
+fn module_file(wide: bool, compress_samples: bool) -> Vec<u8> {
+    let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+    let max = if wide { 32767.0 } else { 127.0 };
+    let data = (0..SAMPLE_LENGTH)
+        .map(|i| (f32::sin(i as f32 * 0.01) * max).round() / max)
+        .collect::<Vec<_>>();
+    module.samples[0].data = Some(data.into());
+    module.samples[0].mark_dirty();
+
+    let mut file = Vec::new();
+    let options = WriteOptions { compress_samples, ..WriteOptions::default() };
+    writer::module_file_with(&module, &mut file, options).unwrap();
+    file
+}
+
+fn main() {
+    for (name, wide, compressed) in [
+        ("8-bit", false, false),
+        ("16-bit", true, false),
+        ("8-bit compressed", false, true),
+        ("16-bit compressed", true, true),
+    ] {
+        let file = module_file(wide, compressed);
+        measure(name, || {
+            black_box(parser::module_file::<VerboseError<&[u8]>>(&file).unwrap());
+        });
+    }
+}
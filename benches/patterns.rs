@@ -0,0 +1,70 @@
+//! Measures parsing of a module with many full patterns, counting the allocations made.
+//!
+//! Run with `cargo bench --bench patterns`.
+
+use ittech::error::VerboseError;
+use ittech::parser;
+use ittech::writer;
+use ittech::{Channel, Command, EffectCmd, Note, NoteCmd, Pattern, RangedU8, Row};
+use std::convert::TryFrom;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+
+const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+
+const PATTERNS: usize = 200;
+
+const CHANNELS: u8 = 16;
+
+const ITERATIONS: u32 = 20;
+
+/// Counts the allocations made by the benchmark
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+fn main() {
+    let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+    let mut rows = vec![Row::empty(); 64];
+    for (index, row) in rows.iter_mut().enumerate() {
+        for channel in 1..=CHANNELS {
+            // Alternate notes and effects so the commands don't repeat the previous values.
+            let command = Command {
+                note: Some(NoteCmd::Play(if index % 2 == 0 { Note::C_5 } else { Note::A_4 })),
+                instrument: None,
+                volume: None,
+                effect: Some(EffectCmd::SetSpeed(RangedU8::try_from(if index % 2 == 0 { 3 } else { 6 }).unwrap())),
+            };
+            row.insert(Channel::new(channel), command);
+        }
+    }
+    let pattern = Pattern { rows, ..module.patterns[0].clone() };
+    module.patterns = vec![pattern; PATTERNS];
+    let mut file = Vec::new();
+    writer::module_file(&module, &mut file).unwrap();
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(parser::module_file::<VerboseError<&[u8]>>(&file).unwrap());
+    }
+    let elapsed = start.elapsed() / ITERATIONS;
+    let allocations = (ALLOCATIONS.load(Ordering::Relaxed) - allocations) / usize::try_from(ITERATIONS).unwrap();
+    println!("{} patterns: {:.3?} and {} allocations per parse", PATTERNS, elapsed, allocations);
+}
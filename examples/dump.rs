@@ -1,7 +1,6 @@
 use anyhow::{Context, Result};
-use ittech::error::{convert_error, VerboseError};
+use ittech::error::{ParseFailure, VerboseError};
 use ittech::parser;
-use nom::Err;
 use std::{env, fs};
 
 fn main() -> Result<()> {
@@ -11,10 +10,10 @@ fn main() -> Result<()> {
         .with_context(|| format!("failed to read file {}", &fname))?;
     match parser::module_file::<VerboseError<&[u8]>>(&data) {
         Ok(it) => println!("{:#X?}", it),
-        Err(Err::Error(e)) | Err(Err::Failure(e)) => {
-            eprintln!("parser failed\n\n{}", convert_error(&data, e));
+        Err(e) => {
+            let failure = ParseFailure::new(&data, e);
+            eprintln!("{}\n\n{}", failure, failure.trace);
         }
-        _ => unreachable!(),
     }
     Ok(())
 }
@@ -0,0 +1,62 @@
+use anyhow::{Context, Result};
+use ittech::error::{ParseFailure, VerboseError};
+use ittech::parser;
+use ittech::player::play;
+use std::io::{self, BufRead};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::Duration;
+use std::{env, fs, thread};
+
+const USAGE: &str = "usage: cargo run --example play --features cpal -- <itmodule>";
+
+const HELP: &str = "commands: p (pause/resume), <order> (seek), q (quit)";
+
+fn main() -> Result<()> {
+    let inpname = env::args().nth(1).context(USAGE)?;
+
+    let data = fs::read(&inpname)
+        .with_context(|| format!("failed to read file {}", &inpname))?;
+    let module = match parser::module_file::<VerboseError<_>>(&data) {
+        Ok(module) => module,
+        Err(e) => {
+            let failure = ParseFailure::new(&data, e);
+            eprintln!("{}\n\n{}", failure, failure.trace);
+            return Ok(());
+        }
+    };
+
+    let playback = play(Arc::new(module)).context("failed to start playback")?;
+    eprintln!("{}", HELP);
+
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        for line in io::stdin().lock().lines() {
+            match line {
+                Ok(line) if sender.send(line).is_ok() => {}
+                _ => break,
+            }
+        }
+    });
+
+    while !playback.is_finished() {
+        let line = match receiver.recv_timeout(Duration::from_millis(100)) {
+            Ok(line) => line,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        match line.trim() {
+            "p" if playback.is_paused() => playback.resume(),
+            "p" => playback.pause(),
+            "q" => break,
+            command => match command.parse() {
+                Ok(order) => playback.seek(order),
+                Err(_) => eprintln!("{}", HELP),
+            },
+        }
+        let (order, row) = playback.position();
+        eprintln!("order {} row {}", order, row);
+    }
+
+    Ok(())
+}
@@ -0,0 +1,124 @@
+/*
+ * C interface of ittech, an Impulse Tracker module parser and writer
+ *
+ * Declarations of the functions exported by the `ffi` module of the crate, see its documentation
+ * for the details. Build the library with
+ *
+ *     cargo rustc --release --features ffi --crate-type cdylib
+ *
+ * Keep in sync with src/ffi.rs.
+ */
+
+#ifndef ITTECH_H
+#define ITTECH_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Loop flags of IttechSampleInfo */
+#define ITTECH_SAMPLE_LOOP 0x10
+#define ITTECH_SAMPLE_SUSTAIN_LOOP 0x20
+#define ITTECH_SAMPLE_PINGPONG_LOOP 0x40
+#define ITTECH_SAMPLE_PINGPONG_SUSTAIN 0x80
+
+/* Mask bits of IttechCell */
+#define ITTECH_CELL_NOTE 0x01
+#define ITTECH_CELL_INSTRUMENT 0x02
+#define ITTECH_CELL_VOLUME 0x04
+#define ITTECH_CELL_EFFECT 0x08
+
+/* Parsed module, owned by the caller and freed by ittech_module_free */
+typedef struct IttechModule IttechModule;
+
+/* Sample header fields, filled by ittech_sample_info */
+typedef struct IttechSampleInfo {
+    /* Sample name, null-terminated unless it takes all 26 bytes */
+    uint8_t name[26];
+    /* Length in frames */
+    uint32_t length;
+    /* Playback rate of C-5 */
+    uint32_t c5_speed;
+    uint8_t global_volume;
+    uint8_t default_volume;
+    /* Raw default panning, bit 7 is on if the panning is used */
+    uint8_t default_panning;
+    /* Combination of the ITTECH_SAMPLE_* constants */
+    uint8_t flags;
+    uint32_t loop_start;
+    uint32_t loop_end;
+    uint32_t sustain_loop_start;
+    uint32_t sustain_loop_end;
+    /* 1 for mono, 2 for stereo */
+    uint8_t channels;
+} IttechSampleInfo;
+
+/* Pattern cell encoded as in the module file, only the values marked in mask are set */
+typedef struct IttechCell {
+    /* Combination of the ITTECH_CELL_* constants */
+    uint8_t mask;
+    /* Note 0..=119, 255 note off, 254 note cut, 253 note fade, 252 parameter control and 251
+       smooth parameter control (OpenMPT), the plugin, parameter and value of these are not set */
+    uint8_t note;
+    /* Instrument (or sample) number starting from 1 */
+    uint8_t instrument;
+    /* Raw volume column byte */
+    uint8_t volume;
+    /* Effect number, 1 is A */
+    uint8_t effect;
+    uint8_t param;
+} IttechCell;
+
+/* Description of the last failure on this thread, valid until the next failing call */
+const char *ittech_last_error(void);
+
+/* Parses the module, returns NULL if it fails */
+IttechModule *ittech_parse(const uint8_t *data, size_t len);
+
+/* Frees the module, does nothing for NULL */
+void ittech_module_free(IttechModule *module);
+
+/* Name of the module, 26 bytes null-terminated unless the name takes all of them */
+const uint8_t *ittech_module_name(const IttechModule *module);
+
+uint8_t ittech_module_tempo(const IttechModule *module);
+
+uint8_t ittech_module_speed(const IttechModule *module);
+
+size_t ittech_order_count(const IttechModule *module);
+
+/* Pattern number, 254 for a separator, 255 for the end of the song or past the end */
+uint8_t ittech_order(const IttechModule *module, size_t index);
+
+size_t ittech_sample_count(const IttechModule *module);
+
+/* Returns false if there is no such sample */
+bool ittech_sample_info(const IttechModule *module, size_t index, IttechSampleInfo *info);
+
+/* Normalized sample data borrowed from the module, NULL if there is no data, the channels
+   of stereo samples are interleaved */
+const float *ittech_sample_data(const IttechModule *module, size_t index, size_t *len);
+
+size_t ittech_pattern_count(const IttechModule *module);
+
+/* Number of rows, 0 if there is no such pattern */
+size_t ittech_pattern_rows(const IttechModule *module, size_t pattern);
+
+/* Channel is 0..127, returns false if the position is out of range */
+bool ittech_pattern_cell(const IttechModule *module, size_t pattern, size_t row, uint8_t channel, IttechCell *cell);
+
+/* Writes the module file to a buffer freed by ittech_buffer_free, returns false if it fails */
+bool ittech_write(const IttechModule *module, uint8_t **data, size_t *len);
+
+/* Frees the buffer returned by ittech_write, does nothing for NULL */
+void ittech_buffer_free(uint8_t *data, size_t len);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* ITTECH_H */
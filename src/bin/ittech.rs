@@ -0,0 +1,152 @@
+//! Inspection of Impulse Tracker modules from the command line
+//!
+//! Built with the feature `cli`, run `ittech help` for the list of commands.
+
+use ittech::parser;
+use ittech::{JsonOptions, Module, Order};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::BufReader;
+use std::path::Path;
+use std::process::ExitCode;
+
+const USAGE: &str = "\
+usage: ittech <command> [options] <itmodule>
+
+commands:
+    info                      summary of the module
+    orders                    order list
+    json [--sample-data]      description of the module as JSON, see Module::to_json
+    patterns [--text]         list of patterns, with --text their rows rendered as text
+    samples [--extract DIR]   list of samples, with --extract written to DIR as WAV files
+    validate                  problems which make the module play wrong or fail to load
+    help                      this message";
+
+type Result<T> = std::result::Result<T, Box<dyn Error>>;
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(code) => code,
+        Err(error) => {
+            eprintln!("error: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(args: &[String]) -> Result<ExitCode> {
+    let (command, args) = match args.split_first() {
+        Some((command, args)) if command != "help" && command != "--help" => (command, args),
+        _ => {
+            println!("{}", USAGE);
+            return Ok(ExitCode::SUCCESS);
+        }
+    };
+    match command.as_str() {
+        "info" => info(&read(file(args)?)?),
+        "orders" => orders(&read(file(args)?)?),
+        "json" => {
+            let (sample_data, args) = flag(args, "--sample-data");
+            println!("{}", read(file(args)?)?.to_json_with(JsonOptions { sample_data }));
+        }
+        "patterns" => {
+            let (text, args) = flag(args, "--text");
+            patterns(&read(file(args)?)?, text)
+        }
+        "samples" => match args {
+            [option, dir, path] if option == "--extract" => extract(&read(path)?, Path::new(dir))?,
+            _ => samples(&read(file(args)?)?),
+        },
+        "validate" => return validate(&read(file(args)?)?),
+        _ => return Err(format!("unknown command {}\n\n{}", command, USAGE).into()),
+    }
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Returns the only argument, the path of the module.
+fn file(args: &[String]) -> Result<&String> {
+    match args {
+        [file] if !file.starts_with("--") => Ok(file),
+        _ => Err(USAGE.into()),
+    }
+}
+
+/// Returns whether the arguments start with the flag and the arguments after it.
+fn flag<'a>(args: &'a [String], name: &str) -> (bool, &'a [String]) {
+    match args.split_first() {
+        Some((first, rest)) if first == name => (true, rest),
+        _ => (false, args),
+    }
+}
+
+fn read(path: &str) -> Result<Module> {
+    let file = File::open(path).map_err(|error| format!("failed to open {}: {}", path, error))?;
+    parser::read_module_file(BufReader::new(file))
+        .map_err(|error| format!("failed to read {}: {}", path, error).into())
+}
+
+fn info(module: &Module) {
+    let stats = module.stats();
+    println!("name:         {}", module.name);
+    println!("tracker:      {}", module.detected_tracker());
+    println!("speed/tempo:  {}/{}", module.speed.as_u8(), module.tempo.as_u8());
+    println!("orders:       {}", module.orders.len());
+    println!("patterns:     {}", stats.patterns);
+    println!("instruments:  {}", stats.instruments);
+    println!("samples:      {} ({} bytes)", stats.samples, stats.sample_bytes);
+    println!("channels:     {}", stats.channels.count());
+    println!("duration:     {:.1?}", stats.duration);
+    if !module.message.is_empty() {
+        println!("\n{}", module.message.replace('\r', "\n"));
+    }
+}
+
+fn orders(module: &Module) {
+    for (position, order) in module.orders.iter().enumerate() {
+        match order {
+            Order::Index(pattern) => println!("{:03}  {}", position, u8::from(*pattern)),
+            Order::Separator => println!("{:03}  +++", position),
+            Order::EndOfSong => println!("{:03}  ---", position),
+        }
+    }
+}
+
+fn patterns(module: &Module, text: bool) {
+    let channels = module.active_channels();
+    for (index, pattern) in module.patterns.iter().enumerate() {
+        if text {
+            println!("pattern {}\n{}", index, pattern.render_text(channels));
+        } else {
+            println!("{:03}  {} rows, {} channels", index, pattern.rows.len(), pattern.active_channels.count());
+        }
+    }
+}
+
+fn samples(module: &Module) {
+    for (index, sample) in module.samples.iter().enumerate() {
+        println!("{:03}  {:<26} {:>8} frames  {} Hz", index + 1, sample.name.to_string(), sample.length(), sample.samplerate_c5);
+    }
+}
+
+fn extract(module: &Module, dir: &Path) -> Result<()> {
+    fs::create_dir_all(dir)?;
+    for (index, sample) in module.samples.iter().enumerate() {
+        if sample.length() == 0 {
+            continue;
+        }
+        let path = dir.join(format!("{:03}.wav", index + 1));
+        let mut file = File::create(&path)?;
+        sample.write_wav(&mut file).map_err(|error| format!("failed to write {}: {}", path.display(), error))?;
+        println!("{}", path.display());
+    }
+    Ok(())
+}
+
+fn validate(module: &Module) -> Result<ExitCode> {
+    let issues = module.validate();
+    for issue in &issues {
+        println!("{}", issue);
+    }
+    Ok(if issues.is_empty() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
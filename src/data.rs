@@ -2,14 +2,18 @@
 
 
 use crate::error::OutOfRangeError;
+use alloc::string::String;
+use alloc::vec::Vec;
 pub(crate) use bitflags::bitflags;
-use std::convert::TryFrom;
-use std::fmt::{self, Debug};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug};
 
 
 macro_rules! ranged_u8_newtype {
     ( $name: ident, $low: literal ..= $high: literal ) => {
         #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+        #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+        #[cfg_attr(feature = "serde", serde(try_from = "u8", into = "u8"))]
         pub struct $name(u8);
 
         impl $name {
@@ -68,39 +72,111 @@ where
     }
 }
 
+pub trait GetMut<I>: Get<I> {
+    fn get_mut(&mut self, index: I) -> Option<&mut Self::Output>;
+}
+
 macro_rules! impl_index_from_get {
     ( $for: ty, $idx: ty ) => {
-        impl ::std::ops::Index<$idx> for $for {
+        impl ::core::ops::Index<$idx> for $for {
             type Output = <$for as $crate::data::Get<$idx>>::Output;
             fn index(&self, index: $idx) -> &Self::Output {
                 self.get(index)
-                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::std::any::type_name::<$idx>(), &index))
+                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::core::any::type_name::<$idx>(), &index))
             }
         }
 
-        impl ::std::ops::Index<&$idx> for $for {
+        impl ::core::ops::Index<&$idx> for $for {
             type Output = <$for as $crate::data::Get<$idx>>::Output;
             fn index(&self, index: &$idx) -> &Self::Output {
                 self.get(index)
-                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::std::any::type_name::<$idx>(), &index))
+                    .unwrap_or_else(|| panic!("{} index {:?} out of range", ::core::any::type_name::<$idx>(), &index))
             }
         }
     };
 }
 
 
+mod append;
+mod auto_vibrato;
+mod builder;
+mod c5_speed;
+#[cfg(feature = "sha2")]
+mod cache_key;
 mod channel;
+mod cleanup;
+pub mod convert;
+pub mod diff;
+mod dsp;
+mod encoding;
 mod envelope;
+mod events;
+mod extensions;
+mod flatten;
+mod highlight;
+mod history;
+pub(crate) mod float;
+#[cfg(feature = "arbitrary")]
+mod generate;
 mod instrument;
+mod json;
+mod memory;
+mod message;
+mod midi;
 mod module;
+mod panning;
 mod pattern;
+mod plugins;
+mod repair;
+mod resample;
 mod sample;
+#[cfg(feature = "serde")]
+mod serialization;
+mod slides;
+mod stats;
+mod subsong;
+mod text;
+mod timing;
+mod tracker;
+mod transpose;
+mod tuning;
+mod usage;
 mod util;
+mod validate;
+mod voices;
+mod volume;
 
+pub use auto_vibrato::*;
+pub use builder::*;
+pub use c5_speed::*;
 pub use channel::*;
+pub use cleanup::*;
+pub use encoding::*;
 pub use envelope::*;
+pub use events::*;
+pub use extensions::*;
+pub use flatten::*;
+pub use highlight::*;
+pub use history::*;
 pub use instrument::*;
+pub use json::*;
+pub use memory::*;
+pub use midi::*;
 pub use module::*;
+pub use panning::*;
 pub use pattern::*;
+pub use plugins::*;
+pub use repair::*;
+pub use resample::*;
 pub use sample::*;
+pub use slides::*;
+pub use stats::*;
+pub use subsong::*;
+pub use timing::*;
+pub use tracker::*;
+pub use transpose::*;
+pub use tuning::*;
 pub use util::*;
+pub use validate::*;
+pub use voices::*;
+pub use volume::*;
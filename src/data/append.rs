@@ -0,0 +1,135 @@
+use super::*;
+use crate::error::AppendError;
+
+
+impl Module {
+    /// Appends the song of the other module to the song of this one.
+    ///
+    /// The patterns, samples and instruments of `other` are added after the ones of this module
+    /// and all its references to them (orders, commands, sample maps) and its position jumps
+    /// (`Bxx`) are moved to the new IDs and positions. The orders of `other` follow the orders of
+    /// this module, an [`Order::EndOfSong`] at the end of this module's order list is removed so
+    /// that the songs play one after another.
+    ///
+    /// Channel settings are merged by the channels this module uses in its patterns: those keep
+    /// their panning, volume and name, the settings of all other channels are taken from `other`.
+    /// The declared channel count is the larger of the two. The rest of the header, e.g. the
+    /// initial speed, tempo and volumes, the MIDI configuration and the extensions, is kept from
+    /// this module, the song of `other` plays with them.
+    ///
+    /// Fails without changing anything if the combined module doesn't fit the format limits or if
+    /// only one of the modules uses instruments.
+    pub fn append(&mut self, mut other: Module) -> Result<(), AppendError> {
+        if self.flags.contains(ModuleFlags::USE_INSTRUMENTS) != other.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            return Err(AppendError::MixedModes);
+        }
+        let mut orders_len = self.orders.len();
+        while orders_len > 0 && self.orders[orders_len - 1] == Order::EndOfSong {
+            orders_len -= 1;
+        }
+        let check = |count: usize, max: usize, error: fn(usize) -> AppendError| {
+            if count > max { Err(error(count)) } else { Ok(()) }
+        };
+        check(orders_len + other.orders.len(), 256, AppendError::TooManyOrders)?;
+        check(self.patterns.len() + other.patterns.len(), 200, AppendError::TooManyPatterns)?;
+        check(self.samples.len() + other.samples.len(), 99, AppendError::TooManySamples)?;
+        check(self.instruments.len() + other.instruments.len(), 99, AppendError::TooManyInstruments)?;
+
+        let offset = |len: usize, count: usize| (0..count).map(move |idx| u8::try_from(len + idx).unwrap());
+        let sample_map = offset(self.samples.len(), other.samples.len())
+            .map(|id| SampleId::try_from(id).ok())
+            .collect::<Vec<_>>();
+        let instrument_map = offset(self.instruments.len(), other.instruments.len())
+            .map(|id| InstrumentId::try_from(id).ok())
+            .collect::<Vec<_>>();
+        other.remap_samples(&sample_map);
+        other.remap_instruments(&instrument_map);
+
+        let pattern_offset = u8::try_from(self.patterns.len()).unwrap();
+        let order_offset = u8::try_from(orders_len).unwrap_or(u8::MAX);
+        for order in &mut other.orders {
+            if let Order::Index(pattern) = order {
+                *pattern = u8::from(*pattern).checked_add(pattern_offset)
+                    .and_then(|id| PatternId::try_from(id).ok())
+                    .unwrap_or(*pattern);
+            }
+        }
+        for command in other.patterns.iter_mut().flat_map(|pattern| &mut pattern.rows).flat_map(Row::commands_mut) {
+            if let Some(EffectCmd::JumpOrder(position)) = &mut command.effect {
+                *position = position.saturating_add(order_offset);
+            }
+        }
+
+        let used = self.patterns.iter().fold(ActiveChannels::empty(), |used, pattern| used | pattern.active_channels);
+        for channel in (!used).iter() {
+            let idx = channel.as_usize();
+            self.init_channel_panning[idx] = other.init_channel_panning[idx];
+            self.init_channel_volume[idx] = other.init_channel_volume[idx];
+            if let Some(name) = other.channel_names.as_slice().get(idx) {
+                if self.channel_names.len() <= idx {
+                    self.channel_names.resize(idx + 1, String::new());
+                }
+                self.channel_names[idx] = name.clone();
+            }
+        }
+        self.openmpt_channel_count = match (self.openmpt_channel_count, other.openmpt_channel_count) {
+            (Some(count), Some(other)) => Some(count.max(other)),
+            (count, None) | (None, count) => count,
+        };
+
+        if !other.pattern_names.is_empty() {
+            self.pattern_names.resize(self.patterns.len(), String::new());
+            self.pattern_names.append(&mut other.pattern_names);
+        }
+        self.orders.truncate(orders_len);
+        self.orders.append(&mut other.orders);
+        self.patterns.append(&mut other.patterns);
+        self.samples.append(&mut other.samples);
+        self.instruments.append(&mut other.instruments);
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn append() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.orders.push(Order::EndOfSong);
+        let mut other = module.clone();
+        other.flags.toggle(ModuleFlags::USE_INSTRUMENTS);
+        assert_eq!(module.append(other), Err(AppendError::MixedModes));
+
+        let mut other = module.clone();
+        let sample = InstrumentId::try_from(0).unwrap();
+        other.patterns[0].rows[0].insert(Channel::new(64), Command {
+            note: None,
+            instrument: Some(sample),
+            volume: None,
+            effect: Some(EffectCmd::JumpOrder(1)),
+        });
+        other.init_channel_volume[63] = ChannelVolume::try_from(12).unwrap();
+        other.init_channel_volume[0] = ChannelVolume::try_from(12).unwrap();
+        let orders = module.orders.iter().rposition(|order| *order != Order::EndOfSong).map_or(0, |last| last + 1);
+        let mut expected = module.orders[..orders].to_vec();
+        expected.extend(other.orders.iter().map(|order| match order {
+            Order::Index(id) => Order::Index(PatternId::try_from(u8::from(*id) + 1).unwrap()),
+            order => *order,
+        }));
+        module.append(other).unwrap();
+
+        assert_eq!((module.patterns.len(), module.samples.len()), (2, 2));
+        assert_eq!(module.orders, expected);
+        let command = module.patterns[1].command(0, Channel::new(64)).unwrap();
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(1).unwrap()));
+        assert!(matches!(command.effect, Some(EffectCmd::JumpOrder(position)) if usize::from(position) == orders + 1));
+        assert_eq!(u8::from(module.init_channel_volume[63]), 12);
+        assert_eq!(u8::from(module.init_channel_volume[0]) == 12, !module.patterns[0].active_channels.contains(Channel::new(1)));
+    }
+}
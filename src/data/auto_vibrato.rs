@@ -0,0 +1,180 @@
+//! Sample auto-vibrato
+//!
+//! Impulse Tracker modulates the pitch of every note by the auto-vibrato of its sample. The
+//! waveform is 256 points long and advanced by the speed every tick, the depth grows by the sweep
+//! every tick until it reaches the depth of the sample.
+
+use super::*;
+
+
+/// Sine waveform of the auto-vibrato
+pub const SINE_TABLE: [i8; 256] = [
+    0, 2, 3, 5, 6, 8, 9, 11, 12, 14, 16, 17, 19, 20, 22, 23,
+    24, 26, 27, 29, 30, 32, 33, 34, 36, 37, 38, 39, 41, 42, 43, 44,
+    45, 46, 47, 48, 49, 50, 51, 52, 53, 54, 55, 56, 56, 57, 58, 59,
+    59, 60, 60, 61, 61, 62, 62, 62, 63, 63, 63, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 63, 63, 63, 62, 62, 62, 61, 61, 60, 60,
+    59, 59, 58, 57, 56, 56, 55, 54, 53, 52, 51, 50, 49, 48, 47, 46,
+    45, 44, 43, 42, 41, 39, 38, 37, 36, 34, 33, 32, 30, 29, 27, 26,
+    24, 23, 22, 20, 19, 17, 16, 14, 12, 11, 9, 8, 6, 5, 3, 2,
+    0, -2, -3, -5, -6, -8, -9, -11, -12, -14, -16, -17, -19, -20, -22, -23,
+    -24, -26, -27, -29, -30, -32, -33, -34, -36, -37, -38, -39, -41, -42, -43, -44,
+    -45, -46, -47, -48, -49, -50, -51, -52, -53, -54, -55, -56, -56, -57, -58, -59,
+    -59, -60, -60, -61, -61, -62, -62, -62, -63, -63, -63, -64, -64, -64, -64, -64,
+    -64, -64, -64, -64, -64, -64, -63, -63, -63, -62, -62, -62, -61, -61, -60, -60,
+    -59, -59, -58, -57, -56, -56, -55, -54, -53, -52, -51, -50, -49, -48, -47, -46,
+    -45, -44, -43, -42, -41, -39, -38, -37, -36, -34, -33, -32, -30, -29, -27, -26,
+    -24, -23, -22, -20, -19, -17, -16, -14, -12, -11, -9, -8, -6, -5, -3, -2,
+];
+
+/// Ramp-down waveform of the auto-vibrato
+pub const RAMP_DOWN_TABLE: [i8; 256] = [
+    64, 63, 63, 62, 62, 61, 61, 60, 60, 59, 59, 58, 58, 57, 57, 56,
+    56, 55, 55, 54, 54, 53, 53, 52, 52, 51, 51, 50, 50, 49, 49, 48,
+    48, 47, 47, 46, 46, 45, 45, 44, 44, 43, 43, 42, 42, 41, 41, 40,
+    40, 39, 39, 38, 38, 37, 37, 36, 36, 35, 35, 34, 34, 33, 33, 32,
+    32, 31, 31, 30, 30, 29, 29, 28, 28, 27, 27, 26, 26, 25, 25, 24,
+    24, 23, 23, 22, 22, 21, 21, 20, 20, 19, 19, 18, 18, 17, 17, 16,
+    16, 15, 15, 14, 14, 13, 13, 12, 12, 11, 11, 10, 10, 9, 9, 8,
+    8, 7, 7, 6, 6, 5, 5, 4, 4, 3, 3, 2, 2, 1, 1, 0,
+    0, -1, -1, -2, -2, -3, -3, -4, -4, -5, -5, -6, -6, -7, -7, -8,
+    -8, -9, -9, -10, -10, -11, -11, -12, -12, -13, -13, -14, -14, -15, -15, -16,
+    -16, -17, -17, -18, -18, -19, -19, -20, -20, -21, -21, -22, -22, -23, -23, -24,
+    -24, -25, -25, -26, -26, -27, -27, -28, -28, -29, -29, -30, -30, -31, -31, -32,
+    -32, -33, -33, -34, -34, -35, -35, -36, -36, -37, -37, -38, -38, -39, -39, -40,
+    -40, -41, -41, -42, -42, -43, -43, -44, -44, -45, -45, -46, -46, -47, -47, -48,
+    -48, -49, -49, -50, -50, -51, -51, -52, -52, -53, -53, -54, -54, -55, -55, -56,
+    -56, -57, -57, -58, -58, -59, -59, -60, -60, -61, -61, -62, -62, -63, -63, -64,
+];
+
+/// Square waveform of the auto-vibrato, Impulse Tracker's goes between `64` and `0`
+pub const SQUARE_TABLE: [i8; 256] = [
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64, 64,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+    0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+];
+
+/// Random waveform of the auto-vibrato
+///
+/// Impulse Tracker takes a new random value every tick, this is a fixed sequence of such values
+/// so that playback is reproducible.
+pub const RANDOM_TABLE: [i8; 256] = [
+    -57, -43, 8, -63, 48, -23, -55, 5, 19, -6, 41, 14, 60, -11, -52, 54,
+    13, 46, 39, 0, -19, 42, -23, 15, 55, 52, -20, 1, 42, 12, -49, 56,
+    0, 11, -41, -40, -60, 15, 37, 58, 40, 23, 55, 4, -20, -36, -12, -24,
+    40, -44, -42, -2, -19, 47, -58, 9, 56, -2, -29, 42, 61, -17, -3, -50,
+    -48, 42, 52, -44, -31, -58, 37, -43, -30, 62, 44, -47, 58, -50, -42, -42,
+    14, 2, -3, -42, 43, 13, 38, 14, -24, 44, -34, -55, 48, -60, 12, 62,
+    4, -32, 19, 9, 9, -50, -14, 29, 17, -36, -1, 8, 15, -62, 7, -52,
+    -53, 7, -39, -64, 7, -43, 8, 7, -51, -16, -48, 14, 31, 38, 12, 54,
+    -19, 6, 27, -44, 10, 36, -57, -11, -55, 40, 4, 6, -22, 17, -20, 16,
+    46, -19, -7, 39, 9, 30, -39, -26, -28, 52, -55, -57, -52, -33, -59, -14,
+    26, 25, -40, 14, -16, 6, -46, -33, 30, -37, -2, -31, 40, -56, -9, 21,
+    13, 24, -20, 59, -57, 20, -44, -59, -47, 23, 59, -60, -14, 17, 29, 16,
+    45, 48, 11, -38, 18, -64, 0, -47, 43, -9, -28, 17, -18, -54, 10, -62,
+    48, 24, -3, 13, 49, 44, -36, 62, -53, -19, 45, -23, 38, -23, 32, 18,
+    39, 6, 43, -46, -28, -50, 45, -33, -3, -42, 43, -30, -63, 44, -64, 16,
+    38, 0, 52, 14, -37, -31, 63, -16, 45, -46, -20, 45, 16, 55, 58, -54,
+];
+
+
+/// Auto-vibrato of a sample, see [`Sample::auto_vibrato`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AutoVibrato {
+    /// Points of the waveform advanced per tick (`0..=64`)
+    pub speed: u8,
+
+    /// Depth of the vibrato (`0..=64`), `64` is a semitone up and down
+    pub depth: u8,
+
+    /// Growth of the depth per tick in 1/256ths of the depth, `0` starts at the full depth
+    pub sweep: u8,
+
+    /// Waveform, [`Waveform::Sawtooth`] is the ramp-down one
+    pub waveform: Waveform,
+}
+
+impl AutoVibrato {
+    /// Returns the waveform table (`-64..=64`).
+    pub fn table(&self) -> &'static [i8; 256] {
+        match self.waveform {
+            Waveform::Sine => &SINE_TABLE,
+            Waveform::Sawtooth => &RAMP_DOWN_TABLE,
+            Waveform::Square => &SQUARE_TABLE,
+            Waveform::Random => &RANDOM_TABLE,
+        }
+    }
+
+    /// Returns the depth on the tick since the start of the note, in 1/256ths.
+    pub fn depth_at(&self, tick: u32) -> u32 {
+        let depth = u32::from(self.depth) * 256;
+        match self.sweep {
+            0 => depth,
+            sweep => (tick.saturating_add(1).saturating_mul(u32::from(sweep))).min(depth),
+        }
+    }
+
+    /// Returns the pitch offset on the tick since the start of the note, in 1/64ths of a
+    /// semitone (the units of fine linear slides).
+    ///
+    /// Like in Impulse Tracker the sweep is applied before the value is taken, the waveform starts
+    /// from its first point and the value is `waveform * depth / 64` rounded down.
+    ///
+    /// ```
+    /// # use ittech::*;
+    /// let vibrato = AutoVibrato { speed: 64, depth: 32, sweep: 0, waveform: Waveform::Sine };
+    /// let offsets = (0..5).map(|tick| vibrato.offset_at(tick)).collect::<Vec<_>>();
+    /// assert_eq!(offsets, [0, 32, 0, -32, 0]);
+    /// ```
+    pub fn offset_at(&self, tick: u32) -> i16 {
+        let position = usize::from(u8::try_from(tick.wrapping_mul(u32::from(self.speed)) % 256).unwrap());
+        let depth = i32::from(u16::try_from(self.depth_at(tick) >> 8).unwrap_or(u16::MAX));
+        let value = (i32::from(self.table()[position]) * depth) >> 6;
+        i16::try_from(value).unwrap_or(if value < 0 { i16::MIN } else { i16::MAX })
+    }
+}
+
+impl Sample {
+    /// Returns the auto-vibrato of the sample, types Impulse Tracker doesn't know are sine.
+    pub fn auto_vibrato(&self) -> AutoVibrato {
+        AutoVibrato {
+            speed: self.vibrato_speed,
+            depth: self.vibrato_depth,
+            sweep: self.vibrato_rate,
+            waveform: match self.vibrato_type {
+                1 => Waveform::Sawtooth,
+                2 => Waveform::Square,
+                3 => Waveform::Random,
+                _ => Waveform::Sine,
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sweep() {
+        let vibrato = AutoVibrato { speed: 32, depth: 64, sweep: 128, waveform: Waveform::Square };
+        assert_eq!(vibrato.depth_at(0), 128);
+        assert_eq!(vibrato.depth_at(1000), 64 * 256);
+        let offsets = (0..8).map(|tick| vibrato.offset_at(tick)).collect::<Vec<_>>();
+        assert_eq!(offsets, [0, 1, 1, 2, 0, 0, 0, 0]);
+    }
+}
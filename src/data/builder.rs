@@ -0,0 +1,137 @@
+use super::*;
+use crate::error::InvalidOrderError;
+
+
+/// Builder for constructing modules from scratch
+///
+/// The module starts with the values Impulse Tracker uses for new songs: speed 6, tempo 125,
+/// stereo playback with linear slides, full global volume, mixing volume 48, all channels centred
+/// at full volume and an empty order list. The fields of the module not covered by the builder can
+/// be changed on the result of [`ModuleBuilder::build`].
+///
+/// ```
+/// use ittech::{ActiveChannels, ModuleBuilder, Order, Pattern, Row};
+///
+/// let mut builder = ModuleBuilder::new();
+/// builder.set_name("untitled");
+/// let pattern = builder.add_pattern(Pattern {
+///     active_channels: ActiveChannels::empty(),
+///     rows: vec![Row::empty(); 64],
+///     truncated: false,
+///     highlight: None,
+/// }).unwrap();
+/// builder.push_order(Order::Index(pattern));
+/// let module = builder.build().unwrap();
+/// assert_eq!(module.orders().len(), 1);
+/// ```
+pub struct ModuleBuilder {
+    module: Module,
+}
+
+impl ModuleBuilder {
+    /// Creates a builder of an empty module.
+    pub fn new() -> ModuleBuilder {
+        ModuleBuilder {
+            module: Module {
+                name: Name { bytes: [0; 26] },
+                message: String::new(),
+                highlight: (16, 4),
+                made_with_version: 0x0214,
+                compatible_with_version: 0x0214,
+                flags: ModuleFlags::STEREO | ModuleFlags::LINEAR_SLIDES,
+                stored_flags: 0,
+                global_volume: RangedU8::try_from(128).unwrap(),
+                sample_volume: RangedU8::try_from(48).unwrap(),
+                speed: RangedU8::try_from(6).unwrap(),
+                tempo: RangedU8::try_from(125).unwrap(),
+                pan_separation: RangedU8::try_from(128).unwrap(),
+                pitch_wheel_depth: 0,
+                init_channel_panning: [ChannelPan::CENTRE; MAX_CHANNELS],
+                init_channel_volume: [ChannelVolume::FULL; MAX_CHANNELS],
+                orders: Vec::new(),
+                instruments: Vec::new(),
+                samples: Vec::new(),
+                patterns: Vec::new(),
+                midi_config: None,
+                edit_history: Vec::new(),
+                openmpt_channel_count: None,
+                pattern_names: Vec::new(),
+                channel_names: Vec::new(),
+                openmpt_extensions: None,
+                opaque: OpaqueData::default(),
+            },
+        }
+    }
+
+    /// Sets the song name, names longer than 25 bytes are truncated.
+    pub fn set_name(&mut self, name: &str) {
+        let bytes = name.as_bytes();
+        let len = bytes.len().min(25);
+        self.module.name.bytes = [0; 26];
+        self.module.name.bytes[..len].copy_from_slice(&bytes[..len]);
+    }
+
+    /// Sets the initial speed (ticks per row).
+    pub fn set_speed(&mut self, speed: RangedU8<1, 255>) {
+        self.module.speed = speed;
+    }
+
+    /// Sets the initial tempo.
+    pub fn set_tempo(&mut self, tempo: RangedU8<31, 255>) {
+        self.module.tempo = tempo;
+    }
+
+    /// Adds the sample and returns its ID, or `None` if the module already has 99 samples.
+    pub fn add_sample(&mut self, sample: Sample) -> Option<SampleId> {
+        let id = next_id(self.module.samples.len())?;
+        self.module.samples.push(sample);
+        Some(id)
+    }
+
+    /// Adds the instrument and returns its ID, or `None` if the module already has 99
+    /// instruments.
+    ///
+    /// Adding an instrument switches the module to instrument mode
+    /// ([`ModuleFlags::USE_INSTRUMENTS`]).
+    pub fn add_instrument(&mut self, instrument: Instrument) -> Option<InstrumentId> {
+        let id = next_id(self.module.instruments.len())?;
+        self.module.instruments.push(instrument);
+        self.module.flags |= ModuleFlags::USE_INSTRUMENTS;
+        Some(id)
+    }
+
+    /// Adds the pattern and returns its ID, or `None` if the module already has 200 patterns.
+    ///
+    /// The pattern is not added to the order list, see [`ModuleBuilder::push_order`].
+    pub fn add_pattern(&mut self, pattern: Pattern) -> Option<PatternId> {
+        let id = next_id(self.module.patterns.len())?;
+        self.module.patterns.push(pattern);
+        Some(id)
+    }
+
+    /// Appends the entry to the order list.
+    pub fn push_order(&mut self, order: Order) {
+        self.module.orders.push(order);
+    }
+
+    /// Returns the built module.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if an order references a pattern which was not added.
+    pub fn build(self) -> Result<Module, InvalidOrderError> {
+        self.module.validate_orders()?;
+        Ok(self.module)
+    }
+}
+
+impl Default for ModuleBuilder {
+    fn default() -> ModuleBuilder {
+        ModuleBuilder::new()
+    }
+}
+
+/// Converts the number of existing items to the ID of the next one, `None` if it's out of range.
+fn next_id<I: TryFrom<u8>>(len: usize) -> Option<I> {
+    I::try_from(u8::try_from(len).ok()?).ok()
+}
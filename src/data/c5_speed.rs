@@ -0,0 +1,139 @@
+use super::*;
+
+
+/// C-5 speeds of the ProTracker finetune values 0..=7 and -8..=-1
+const FINETUNE_SPEEDS: [u32; 16] = [
+    8363, 8413, 8463, 8529, 8581, 8651, 8723, 8757,
+    7895, 7941, 7985, 8046, 8107, 8169, 8232, 8280,
+];
+
+
+/// Playback rate of a sample at C-5 in Hz, see [`Sample::samplerate_c5`]
+///
+/// The other formats tune samples relative to [`C5Speed::NTSC`], ProTracker by a finetune of
+/// 1/8 semitones and FastTracker 2 by a relative note and a finetune of 1/128 semitones, these
+/// are converted to the C-5 speed and back here.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "u32", into = "u32"))]
+pub struct C5Speed(u32);
+
+impl C5Speed {
+    /// Rate of the Amiga NTSC clock playing C-5 (period `428`), the C-5 speed of samples without
+    /// finetune and the default of new samples
+    pub const NTSC: C5Speed = C5Speed(8363);
+
+    /// Rate of the Amiga PAL clock playing C-5 (period `428`)
+    pub const PAL: C5Speed = C5Speed(8287);
+
+    pub const fn new(hz: u32) -> C5Speed {
+        C5Speed(hz)
+    }
+
+    pub const fn as_u32(self) -> u32 {
+        self.0
+    }
+
+    /// Returns the speed of a sample tuned by the relative note (in semitones) and the finetune
+    /// (in 1/128 semitones) of FastTracker 2, rounded to the nearest Hz.
+    pub fn from_relative_note(relative_note: i8, finetune: i8) -> C5Speed {
+        let semitones = f32::from(relative_note) + f32::from(finetune) / 128.0;
+        C5Speed::NTSC.scaled(float::powf(2.0, semitones / 12.0))
+    }
+
+    /// Returns the nearest relative note (in semitones) and finetune (in 1/128 semitones) of
+    /// FastTracker 2, the inverse of [`C5Speed::from_relative_note`].
+    ///
+    /// The finetune is in range `-64..64`. Speeds out of the range of the relative note are
+    /// clamped, `0` is treated as `1` Hz.
+    pub fn to_relative_note(self) -> (i8, i8) {
+        #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+        let speed = self.0.max(1) as f32;
+        let steps = float::round(128.0 * 12.0 * float::log10(speed / 8363.0) / float::log10(2.0));
+        // The steps are clamped to the range of the relative note before the cast.
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+        let steps = steps.clamp(-128.0 * 128.0, 127.0 * 128.0) as i32;
+        let note = (steps + 64).div_euclid(128);
+        (i8::try_from(note).unwrap(), i8::try_from(steps - note * 128).unwrap_or(0))
+    }
+
+    /// Returns the speed of the ProTracker finetune, the low nibble is the finetune `0..=7` and
+    /// `-8..=-1` in 1/8 semitones, the high nibble is ignored.
+    pub fn from_mod_finetune(finetune: u8) -> C5Speed {
+        C5Speed(FINETUNE_SPEEDS[usize::from(finetune & 0x0F)])
+    }
+
+    /// Returns the ProTracker finetune nibble with the nearest speed, the inverse of
+    /// [`C5Speed::from_mod_finetune`].
+    pub fn to_mod_finetune(self) -> u8 {
+        let nearest = (0..16u8).min_by_key(|&finetune| FINETUNE_SPEEDS[usize::from(finetune)].abs_diff(self.0));
+        nearest.unwrap()
+    }
+
+    /// Returns the speed playing the sample the semitones higher, lower for negative semitones,
+    /// rounded to the nearest Hz.
+    ///
+    /// Each octave doubles the speed, speeds out of the range of `u32` saturate.
+    pub fn transposed(self, semitones: i16) -> C5Speed {
+        self.scaled(float::powf(2.0, f32::from(semitones) / 12.0))
+    }
+
+    /// Multiplies the speed by the ratio, rounded to the nearest Hz.
+    fn scaled(self, ratio: f32) -> C5Speed {
+        #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+        let speed = float::round(self.0 as f32 * ratio);
+        // Float to integer casts saturate, speeds out of range are clipped.
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let speed = speed as u32;
+        C5Speed(speed)
+    }
+}
+
+impl Default for C5Speed {
+    fn default() -> C5Speed {
+        C5Speed::NTSC
+    }
+}
+
+impl From<u32> for C5Speed {
+    fn from(hz: u32) -> C5Speed {
+        C5Speed(hz)
+    }
+}
+
+impl From<C5Speed> for u32 {
+    fn from(speed: C5Speed) -> u32 {
+        speed.0
+    }
+}
+
+impl Debug for C5Speed {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn conversions() {
+        assert_eq!(C5Speed::from_relative_note(12, 0), C5Speed::new(16726));
+        assert_eq!(C5Speed::from_relative_note(0, 0), C5Speed::NTSC);
+        for (note, finetune) in [(0, 0), (12, 0), (-24, 16), (3, -64), (7, 63)] {
+            assert_eq!(C5Speed::from_relative_note(note, finetune).to_relative_note(), (note, finetune));
+        }
+        assert_eq!(C5Speed::new(0).to_relative_note(), (-128, 0));
+
+        assert_eq!(C5Speed::from_mod_finetune(0x0F), C5Speed::new(8280));
+        assert_eq!(C5Speed::from_mod_finetune(0x20), C5Speed::NTSC);
+        assert_eq!(C5Speed::new(8280).to_mod_finetune(), 0x0F);
+        assert_eq!(C5Speed::new(8400).to_mod_finetune(), 1);
+
+        assert_eq!(C5Speed::NTSC.transposed(12), C5Speed::new(16726));
+        assert_eq!(C5Speed::NTSC.transposed(-12), C5Speed::new(4182));
+        assert_eq!(C5Speed::new(u32::MAX).transposed(1), C5Speed::new(u32::MAX));
+    }
+}
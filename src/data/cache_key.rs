@@ -0,0 +1,325 @@
+use super::*;
+use sha2::{Digest, Sha256};
+use core::convert::TryFrom;
+
+
+/// Bumped whenever the set or encoding of the digested fields changes.
+const CACHE_KEY_VERSION: &[u8] = b"ittech-cache-key-v3";
+
+impl Module {
+    /// Returns a SHA-256 digest of the decoded module content.
+    ///
+    /// The key depends only on the decoded data, not on the file it was read from, a module resaved
+    /// with different sample compression or padding gets the same key. It is meant for use as a
+    /// cache filename, not for detecting tampering.
+    ///
+    /// The digest is computed over the following, in this order. Integers are little-endian,
+    /// lengths and counts are `u32`, names and filenames are taken up to the first null byte and
+    /// prefixed with their length, optional values are prefixed with a `0` (absent) or `1`
+    /// (present) byte.
+    ///
+    /// 1. the string `ittech-cache-key-v3`,
+    /// 2. module header: name, highlight (measure, beat), made with version, compatible with
+    ///    version, flags ([`Module::raw_flags`]), global volume, sample volume, speed, tempo, pan
+    ///    separation, pitch wheel depth, initial channel panning (127 bytes), initial channel
+    ///    volume (127 bytes), the message as UTF-8, the optional OpenMPT channel count and the
+    ///    optional MIDI configuration (the global, parametered and fixed macros, each taken like a
+    ///    name),
+    /// 3. orders: count, then one byte each (pattern index, 254 for separator, 255 for end of
+    ///    song),
+    /// 4. instruments: count, then for each the name, filename, flags, new note action, duplicate
+    ///    check type and action, fadeout, pitch/pan separation and centre, global volume, default
+    ///    panning, random volume and panning variation, tracker version, number of samples,
+    ///    filter cutoff and resonance, MIDI channel, program and bank, the sample map (120 bytes,
+    ///    0 for no sample, sample index + 1 otherwise) and the volume, panning and pitch/filter
+    ///    envelopes, each as flags, optional loop (start, end), optional sustain loop
+    ///    (start, end), node count and the nodes (value, tick), and the optional tuning (name as
+    ///    UTF-8, ratio count, bit pattern of each ratio and of the group ratio),
+    /// 5. samples: count, then for each the name, filename, global volume, default volume, default
+    ///    panning, optional loop and sustain loop (start, end, bidi), C-5 sample rate, vibrato
+    ///    speed, depth, rate and type, channel count, optional data (length, bit pattern of each
+    ///    `f32`) and the optional OPL patch (12 bytes),
+    /// 6. patterns: count, then for each the optional highlight (measure, beat), the row count and
+    ///    for each row the number of non-empty commands followed by the commands sorted by
+    ///    channel, each as the channel index, the
+    ///    optional note ([`NoteCmd::to_raw`], for parameter control events followed by the
+    ///    plugin, parameter and `u16` value), the optional instrument index, the optional volume
+    ///    column byte and the optional effect ([`EffectCmd::to_raw`], effect and parameter).
+    pub fn cache_key(&self) -> [u8; 32] {
+        let mut hasher = CacheKeyHasher(Sha256::new());
+        hasher.bytes(CACHE_KEY_VERSION);
+
+        hasher.name(&self.name.bytes);
+        hasher.u8(self.highlight.0);
+        hasher.u8(self.highlight.1);
+        hasher.u16(self.made_with_version);
+        hasher.u16(self.compatible_with_version);
+        hasher.u32(self.raw_flags());
+        hasher.u8(self.global_volume.as_u8());
+        hasher.u8(self.sample_volume.as_u8());
+        hasher.u8(self.speed.as_u8());
+        hasher.u8(self.tempo.as_u8());
+        hasher.u8(self.pan_separation.as_u8());
+        hasher.u8(self.pitch_wheel_depth);
+        hasher.raw(&self.init_channel_panning.map(u8::from));
+        hasher.raw(&self.init_channel_volume.map(u8::from));
+        hasher.bytes(self.message.as_bytes());
+        hasher.bool(self.openmpt_channel_count.is_some());
+        if let Some(channels) = self.openmpt_channel_count {
+            hasher.u16(channels);
+        }
+        hasher.bool(self.midi_config.is_some());
+        if let Some(config) = &self.midi_config {
+            for midi_macro in config.global.iter().chain(&config.parametered).chain(&config.fixed) {
+                hasher.name(&midi_macro.bytes);
+            }
+        }
+
+        hasher.len(self.orders.len());
+        for order in &self.orders {
+            hasher.u8(match order {
+                Order::Index(pattern) => pattern.as_u8(),
+                Order::Separator => 254,
+                Order::EndOfSong => 255,
+            });
+        }
+
+        hasher.len(self.instruments.len());
+        for instrument in &self.instruments {
+            hasher.instrument(instrument);
+        }
+
+        hasher.len(self.samples.len());
+        for sample in &self.samples {
+            hasher.sample(sample);
+        }
+
+        hasher.len(self.patterns.len());
+        for pattern in &self.patterns {
+            hasher.pattern(pattern);
+        }
+
+        let mut key = [0; 32];
+        key.copy_from_slice(&hasher.0.finalize());
+        key
+    }
+}
+
+struct CacheKeyHasher(Sha256);
+
+impl CacheKeyHasher {
+    fn raw(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.raw(&[value]);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.raw(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.raw(&value.to_le_bytes());
+    }
+
+    fn len(&mut self, len: usize) {
+        self.u32(u32::try_from(len).expect("length does not fit into u32"));
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(u8::from(value));
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.raw(bytes);
+    }
+
+    fn name(&mut self, bytes: &[u8]) {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        self.bytes(&bytes[..len]);
+    }
+
+    fn instrument(&mut self, instrument: &Instrument) {
+        self.name(&instrument.name.bytes);
+        self.name(&instrument.filename.bytes);
+        self.u8(instrument.flags.bits());
+        self.u8(instrument.new_note_action);
+        self.u8(instrument.duplicate_check_type);
+        self.u8(instrument.duplicate_check_action);
+        self.u8(instrument.instrument_fadeout);
+        self.raw(&instrument.pitch_pan_separation.to_le_bytes());
+        self.u8(instrument.pitch_pan_centre);
+        self.u8(instrument.global_volume);
+        self.u8(instrument.default_panning.as_u8());
+        self.u8(instrument.random_volume_variation.as_u8());
+        self.u8(instrument.random_panning_variation.as_u8());
+        self.u16(instrument.trkver);
+        self.u8(instrument.number_of_samples);
+        self.u8(instrument.initial_filter_cutoff.as_u8());
+        self.u8(instrument.initial_filter_resonance.as_u8());
+        self.u8(instrument.mch);
+        self.u8(instrument.mpr);
+        self.raw(&instrument.mbank);
+        for sample in &instrument.sample_map.map {
+            self.u8(sample.map_or(0, |id| id.as_u8() + 1));
+        }
+        self.envelope(&instrument.volume_envelope);
+        self.envelope(&instrument.panning_envelope);
+        self.envelope(&instrument.pitch_filter_envelope);
+        self.bool(instrument.tuning.is_some());
+        if let Some(tuning) = &instrument.tuning {
+            self.bytes(tuning.name.as_bytes());
+            self.len(tuning.ratios.len());
+            for ratio in &tuning.ratios {
+                self.u32(ratio.to_bits());
+            }
+            self.u32(tuning.group_ratio.to_bits());
+        }
+    }
+
+    fn envelope(&mut self, envelope: &Envelope) {
+        self.u8(envelope.flags.bits());
+        for envelope_loop in &[envelope.envelope_loop, envelope.sustain_loop] {
+            self.bool(envelope_loop.is_some());
+            if let Some(envelope_loop) = envelope_loop {
+                self.u8(envelope_loop.start);
+                self.u8(envelope_loop.end);
+            }
+        }
+        self.len(envelope.nodes.len());
+        for node in &envelope.nodes {
+            self.raw(&node.value.to_le_bytes());
+            self.u16(node.tick);
+        }
+    }
+
+    fn sample(&mut self, sample: &Sample) {
+        self.name(&sample.name.bytes);
+        self.name(&sample.filename.bytes);
+        self.u8(sample.global_volume);
+        self.u8(sample.default_volume);
+        self.u8(sample.default_panning);
+        for sample_loop in &[sample.loop_, sample.sustain_loop] {
+            self.bool(sample_loop.is_some());
+            if let Some(sample_loop) = sample_loop {
+                self.u32(sample_loop.start);
+                self.u32(sample_loop.end);
+                self.bool(sample_loop.bidi);
+            }
+        }
+        self.u32(sample.samplerate_c5);
+        self.u8(sample.vibrato_speed);
+        self.u8(sample.vibrato_depth);
+        self.u8(sample.vibrato_rate);
+        self.u8(sample.vibrato_type);
+        self.u8(sample.channels.as_u8());
+        self.bool(sample.data.is_some());
+        if let Some(data) = &sample.data {
+            self.len(data.len());
+            for value in data {
+                self.u32(value.to_bits());
+            }
+        }
+        self.bool(sample.fm_patch.is_some());
+        if let Some(patch) = &sample.fm_patch {
+            self.raw(patch);
+        }
+    }
+
+    fn pattern(&mut self, pattern: &Pattern) {
+        self.bool(pattern.highlight.is_some());
+        if let Some((measure, beat)) = pattern.highlight {
+            self.u8(measure);
+            self.u8(beat);
+        }
+        self.len(pattern.rows.len());
+        for row in &pattern.rows {
+            let mut commands = row.iter()
+                .filter(|(_, command)| !command.is_empty())
+                .collect::<Vec<_>>();
+            commands.sort_by_key(|(channel, _)| channel.as_usize());
+
+            self.len(commands.len());
+            for (channel, command) in commands {
+                self.u8(u8::try_from(channel.as_usize()).expect("channel index fits into u8"));
+                self.command(command);
+            }
+        }
+    }
+
+    fn command(&mut self, command: &Command) {
+        self.bool(command.note.is_some());
+        if let Some(note) = command.note {
+            self.u8(note.to_raw());
+            if let NoteCmd::ParamControl { plugin, param, value, .. } = note {
+                self.u8(plugin);
+                self.u8(param);
+                self.u16(value);
+            }
+        }
+        self.bool(command.instrument.is_some());
+        if let Some(instrument) = command.instrument {
+            self.u8(instrument.as_u8());
+        }
+        self.bool(command.volume.is_some());
+        if let Some(volume) = command.volume {
+            self.u8(u8::from(volume));
+        }
+        self.bool(command.effect.is_some());
+        if let Some(effect) = command.effect {
+            let (effect, param) = effect.to_raw();
+            self.u8(effect);
+            self.u8(param);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::formats::empty_instrument;
+    use crate::parser;
+    use crate::writer::WriteOptions;
+
+    #[test]
+    fn cache_key() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let mut file = Vec::new();
+        module.write_to_with(&mut file, WriteOptions::default()).unwrap();
+        let resaved = parser::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(resaved.cache_key(), module.cache_key());
+
+        let mut changed = module.clone();
+        let command = changed.patterns[0].rows[0].iter().next().map(|(channel, command)| (channel, *command));
+        let (channel, mut command) = command.unwrap();
+        let (effect, param) = command.effect.unwrap().to_raw();
+        command.effect = EffectCmd::from_raw(effect, param ^ 1);
+        changed.patterns[0].rows[0].insert(channel, command);
+        assert_ne!(changed.cache_key(), module.cache_key());
+
+        let mut changed = module.clone();
+        let mut config = MidiConfig::default();
+        changed.midi_config = Some(config.clone());
+        let key = changed.cache_key();
+        assert_ne!(key, module.cache_key());
+        config.fixed[0] = MidiMacro::new("F0F001");
+        changed.midi_config = Some(config);
+        assert_ne!(changed.cache_key(), key);
+
+        let mut changed = module.clone();
+        changed.instruments.push(empty_instrument());
+        let key = changed.cache_key();
+        changed.instruments[0].tuning = Some(Tuning::equal_temperament());
+        assert_ne!(changed.cache_key(), key);
+
+        let mut changed = module.clone();
+        changed.patterns[0].highlight = Some(module.highlight);
+        assert_ne!(changed.cache_key(), module.cache_key());
+    }
+}
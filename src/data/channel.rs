@@ -1,30 +1,39 @@
 use super::*;
-use std::borrow::Borrow;
-use std::convert::TryInto;
-use std::fmt::{self, Debug};
-use std::iter::FromIterator;
-use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign};
+use crate::error::InvalidChannelMapError;
+use core::borrow::Borrow;
+use core::convert::TryInto;
+use core::fmt::{self, Debug};
+use core::iter::FromIterator;
+use core::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign, Not, Sub, SubAssign};
+
+
+/// Number of channels a module can have
+///
+/// Impulse Tracker has 64 channels, OpenMPT extends IT files to 127 channels. The settings of the
+/// channels past the 64th are stored in the OpenMPT `ChnS` chunk.
+pub const MAX_CHANNELS: usize = 127;
 
 
 /// Channel number
 #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
-pub struct Channel(RangedU8<0, 63>);
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Channel(RangedU8<0, 126>);
 
 impl Channel {
     /// Create a channel identifier with the given number
     ///
-    /// Accepted range is 1..=64, this function panics for values out of the range.
+    /// Accepted range is 1..=127, this function panics for values out of the range.
     pub fn new(number: u8) -> Channel {
-        assert!((1..=64).contains(&number), "channel number is out of range");
+        assert!((1..=127).contains(&number), "channel number is out of range");
         Channel::from_u8_index(number - 1)
     }
 
-    /// Returns 0 based channel index (0..=63), as opposed to channel number (1..=64)
+    /// Returns 0 based channel index (0..=126), as opposed to channel number (1..=127)
     pub fn as_usize(self) -> usize {
         self.0.as_u8().into()
     }
 
-    /// Creates channel from channel index (0..=63), as opposed to channel number (1..=64)
+    /// Creates channel from channel index (0..=126), as opposed to channel number (1..=127)
     pub(crate) fn from_u8_index(raw: u8) -> Channel {
         Channel(raw.try_into().expect("channel index out of range"))
     }
@@ -38,12 +47,16 @@ impl Debug for Channel {
 
 
 /// Active channels in a particular pattern or module.
-#[derive(Clone, Copy, PartialEq)]
-pub struct ActiveChannels(u64);
+///
+/// The operators work as set operations, `|` is the union, `&` the intersection, `-` the
+/// difference, `^` the symmetric difference and `!` the complement.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ActiveChannels(u128);
 
 impl ActiveChannels {
     pub const fn all() -> ActiveChannels {
-        ActiveChannels(u64::MAX)
+        ActiveChannels(u128::MAX >> (128 - MAX_CHANNELS))
     }
 
     pub const fn empty() -> ActiveChannels {
@@ -57,16 +70,45 @@ impl ActiveChannels {
     }
 
     pub fn iter(self) -> impl Iterator<Item=Channel> {
-        (0..=63)
-            .filter(move |chan| (self.0 & (1u64 << chan)) != 0)
+        (0..=126)
+            .filter(move |chan| (self.0 & (1u128 << chan)) != 0)
             .map(Channel::from_u8_index)
     }
 
     pub const fn count(self) -> usize {
-        // NOTE 0..64 will always fit into an usize but we can't use .into() because const context
+        // NOTE 0..=127 will always fit into an usize but we can't use .into() because const context
         #[allow(clippy::as_conversions)]
         { self.0.count_ones() as usize }
     }
+
+    /// Returns `true` if no channel is active.
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Returns `true` if the channel is active.
+    pub const fn contains(self, channel: Channel) -> bool {
+        self.0 & Self::bit(channel) != 0
+    }
+
+    /// Returns `true` if all channels of `self` are active in `other`.
+    pub const fn is_subset(self, other: ActiveChannels) -> bool {
+        self.0 & !other.0 == 0
+    }
+
+    /// Marks the channel active.
+    pub fn insert(&mut self, channel: Channel) {
+        self.0 |= Self::bit(channel);
+    }
+
+    /// Marks the channel inactive.
+    pub fn remove(&mut self, channel: Channel) {
+        self.0 &= !Self::bit(channel);
+    }
+
+    const fn bit(channel: Channel) -> u128 {
+        1 << channel.0.as_u8()
+    }
 }
 
 impl BitAnd<ActiveChannels> for ActiveChannels {
@@ -95,16 +137,55 @@ impl BitOrAssign for ActiveChannels {
     }
 }
 
+impl BitXor<ActiveChannels> for ActiveChannels {
+    type Output = ActiveChannels;
+    fn bitxor(self, rhs: ActiveChannels) -> Self::Output {
+        ActiveChannels(self.0 ^ rhs.0)
+    }
+}
+
+impl BitXorAssign for ActiveChannels {
+    fn bitxor_assign(&mut self, rhs: ActiveChannels) {
+        *self = *self ^ rhs;
+    }
+}
+
+impl Sub<ActiveChannels> for ActiveChannels {
+    type Output = ActiveChannels;
+    fn sub(self, rhs: ActiveChannels) -> Self::Output {
+        ActiveChannels(self.0 & !rhs.0)
+    }
+}
+
+impl SubAssign for ActiveChannels {
+    fn sub_assign(&mut self, rhs: ActiveChannels) {
+        *self = *self - rhs;
+    }
+}
+
+impl Not for ActiveChannels {
+    type Output = ActiveChannels;
+    fn not(self) -> Self::Output {
+        ActiveChannels(!self.0) & ActiveChannels::all()
+    }
+}
+
 impl FromIterator<Channel> for ActiveChannels {
     fn from_iter<I: IntoIterator<Item=Channel>>(iter: I) -> ActiveChannels {
         ActiveChannels(
             iter.into_iter()
-                .map(|chan| 1u64 << chan.as_usize())
-                .fold(0u64, u64::bitor)
+                .map(ActiveChannels::bit)
+                .fold(0u128, u128::bitor)
         )
     }
 }
 
+impl Extend<Channel> for ActiveChannels {
+    fn extend<I: IntoIterator<Item=Channel>>(&mut self, iter: I) {
+        *self |= iter.into_iter().collect();
+    }
+}
+
 impl Debug for ActiveChannels {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         f.debug_list()
@@ -112,3 +193,203 @@ impl Debug for ActiveChannels {
             .finish()
     }
 }
+
+
+/// Initial settings of a channel, see [`Module::channel_settings`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChannelSettings {
+    /// Panning, see [`ChannelPan::pan`]
+    pub pan: Pan,
+
+    /// Volume
+    pub volume: ChannelVolume,
+
+    /// Disabled (muted) channels are not played
+    pub enabled: bool,
+}
+
+
+impl Module {
+    /// Returns the initial panning, volume and enabled state of the channel.
+    pub fn channel_settings(&self, channel: Channel) -> ChannelSettings {
+        let pan = self.init_channel_panning[channel.as_usize()];
+        ChannelSettings {
+            pan: pan.pan(),
+            volume: self.init_channel_volume[channel.as_usize()],
+            enabled: pan.is_enabled(),
+        }
+    }
+
+    /// Replaces the initial settings of the channel.
+    pub fn set_channel_settings(&mut self, channel: Channel, settings: ChannelSettings) {
+        self.init_channel_panning[channel.as_usize()] = ChannelPan::new(settings.pan, settings.enabled);
+        self.init_channel_volume[channel.as_usize()] = settings.volume;
+    }
+
+    /// Changes the initial panning of the channel.
+    pub fn set_channel_pan(&mut self, channel: Channel, pan: Pan) {
+        self.init_channel_panning[channel.as_usize()].set_pan(pan);
+    }
+
+    /// Changes the initial volume of the channel.
+    pub fn set_channel_volume(&mut self, channel: Channel, volume: ChannelVolume) {
+        self.init_channel_volume[channel.as_usize()] = volume;
+    }
+
+    /// Enables or mutes the channel.
+    pub fn set_channel_enabled(&mut self, channel: Channel, enabled: bool) {
+        self.init_channel_panning[channel.as_usize()].set_enabled(enabled);
+    }
+
+    /// Moves the channels of the module, `mapping[i]` is the new channel of the channel with index
+    /// `i`.
+    ///
+    /// Rewrites the commands of all patterns, [`Module::init_channel_panning`],
+    /// [`Module::init_channel_volume`] and [`Module::channel_names`]. Channels mapped to `None` or
+    /// past the end of the mapping are removed with their commands, channels no channel is moved to
+    /// are disabled with the default volume and no name. The declared channel count is set to cover
+    /// the highest channel in use after the move.
+    ///
+    /// Compacting the channels used by the song:
+    ///
+    /// ```
+    /// # use ittech::{Channel, Module, MAX_CHANNELS};
+    /// # use std::convert::TryFrom;
+    /// # fn compact(module: &mut Module) {
+    /// let mut mapping = [None; MAX_CHANNELS];
+    /// for (index, channel) in module.active_channels().iter().enumerate() {
+    ///     mapping[channel.as_usize()] = Some(Channel::new(u8::try_from(index + 1).unwrap()));
+    /// }
+    /// module.remap_channels(&mapping).unwrap();
+    /// # }
+    /// ```
+    ///
+    /// Fails without changing anything if two channels are mapped to the same channel. Data about
+    /// channels in the unknown chunks of [`OpaqueData`] is not remapped.
+    pub fn remap_channels(&mut self, mapping: &[Option<Channel>]) -> Result<(), InvalidChannelMapError> {
+        let mut targets = ActiveChannels::empty();
+        for &channel in mapping.iter().take(MAX_CHANNELS).flatten() {
+            if targets.contains(channel) {
+                return Err(InvalidChannelMapError { channel });
+            }
+            targets.insert(channel);
+        }
+        let target = |channel: Channel| mapping.get(channel.as_usize()).copied().flatten();
+
+        for pattern in &mut self.patterns {
+            for row in &mut pattern.rows {
+                let commands = row.iter()
+                    .filter_map(|(channel, command)| Some((target(channel)?, *command)))
+                    .collect();
+                *row = Row::from_vec(commands);
+            }
+            pattern.active_channels = pattern.active_channels.iter().filter_map(target).collect();
+        }
+
+        let mut panning = [ChannelPan::DISABLED; MAX_CHANNELS];
+        let mut volume = [ChannelVolume::FULL; MAX_CHANNELS];
+        let mut names = Vec::new();
+        for source in ActiveChannels::all().iter() {
+            if let Some(channel) = target(source) {
+                panning[channel.as_usize()] = self.init_channel_panning[source.as_usize()];
+                volume[channel.as_usize()] = self.init_channel_volume[source.as_usize()];
+                if let Some(name) = self.channel_names.as_slice().get(source.as_usize()) {
+                    if names.len() <= channel.as_usize() {
+                        names.resize(channel.as_usize() + 1, String::new());
+                    }
+                    names[channel.as_usize()] = name.clone();
+                }
+            }
+        }
+        while names.last().is_some_and(String::is_empty) {
+            names.pop();
+        }
+        if self.channel_names.len() > MAX_CHANNELS {
+            names.resize(MAX_CHANNELS, String::new());
+            names.extend(self.channel_names.drain(MAX_CHANNELS..));
+        }
+        self.init_channel_panning = panning;
+        self.init_channel_volume = volume;
+        self.channel_names = names;
+
+        if let Some(count) = self.openmpt_channel_count.as_mut().filter(|count| usize::from(**count) <= MAX_CHANNELS) {
+            let used = (0..*count).filter_map(|index| target(Channel::from_u8_index(u8::try_from(index).unwrap())));
+            *count = used.map(|channel| u16::try_from(channel.as_usize() + 1).unwrap()).max().unwrap_or(1);
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_operations() {
+        let a = ActiveChannels::new([Channel::new(1), Channel::new(2), Channel::new(127)]);
+        let b = ActiveChannels::new([Channel::new(2), Channel::new(3)]);
+        assert_eq!((a | b).count(), 4);
+        assert_eq!((a & b).iter().collect::<Vec<_>>(), [Channel::new(2)]);
+        assert_eq!((a - b).iter().collect::<Vec<_>>(), [Channel::new(1), Channel::new(127)]);
+        assert_eq!(a ^ b, (a | b) - (a & b));
+        assert!(a.contains(Channel::new(127)) && !a.contains(Channel::new(3)));
+        assert!((a & b).is_subset(b) && !a.is_subset(b));
+        assert_eq!(!ActiveChannels::all(), ActiveChannels::default());
+        assert_eq!(ActiveChannels::all().count(), MAX_CHANNELS);
+
+        let mut c = a;
+        c.remove(Channel::new(127));
+        c.insert(Channel::new(3));
+        c.extend([Channel::new(4)]);
+        assert_eq!(c.iter().map(Channel::as_usize).collect::<Vec<_>>(), [0, 1, 2, 3]);
+        assert!((c - c).is_empty());
+    }
+
+    #[test]
+    fn channel_settings() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = crate::parser::module_file::<crate::error::VerboseError<&[u8]>>(DATA).unwrap();
+        let channel = Channel::new(64);
+        module.set_channel_settings(channel, ChannelSettings {
+            pan: Pan::Surround,
+            volume: ChannelVolume::SILENT,
+            enabled: true,
+        });
+        module.set_channel_enabled(channel, false);
+        assert_eq!(u8::from(module.init_channel_panning[63]), 100 | 0x80);
+        let settings = module.channel_settings(channel);
+        assert_eq!((settings.pan, settings.enabled), (Pan::Surround, false));
+        assert!(settings.volume.is_silent());
+        assert!(ChannelVolume::try_from(65).is_err());
+    }
+
+    #[test]
+    fn remap_channels() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = crate::parser::module_file::<crate::error::VerboseError<&[u8]>>(DATA).unwrap();
+        let original = module.clone();
+        let used = module.patterns[0].active_channels.iter().collect::<Vec<_>>();
+        module.init_channel_volume[used[0].as_usize()] = ChannelVolume::try_from(10).unwrap();
+        module.channel_names = vec![String::from("lead")];
+
+        let mut mapping = [None; MAX_CHANNELS];
+        mapping[used[0].as_usize()] = Some(Channel::new(5));
+        assert!(matches!(
+            module.clone().remap_channels(&[Some(Channel::new(1)), Some(Channel::new(1))]),
+            Err(InvalidChannelMapError { channel }) if channel == Channel::new(1)
+        ));
+        module.remap_channels(&mapping).unwrap();
+
+        let pattern = &module.patterns[0];
+        assert_eq!(pattern.active_channels, ActiveChannels::new([Channel::new(5)]));
+        for (row, original) in pattern.rows.iter().zip(&original.patterns[0].rows) {
+            let moved = row.iter().map(|(channel, command)| (channel, command.to_string())).collect::<Vec<_>>();
+            let kept = original.get(used[0]).map(|command| (Channel::new(5), command.to_string()));
+            assert_eq!(moved, kept.into_iter().collect::<Vec<_>>());
+        }
+        assert_eq!(u8::from(module.init_channel_volume[4]), 10);
+        assert_eq!(module.init_channel_panning[0], ChannelPan::DISABLED);
+        assert_eq!(module.channel_names, ["", "", "", "", "lead"].map(String::from));
+    }
+}
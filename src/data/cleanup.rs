@@ -0,0 +1,434 @@
+use super::*;
+
+
+/// Numbers of entries removed by [`Module::cleanup`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CleanupSummary {
+    pub patterns: usize,
+    pub instruments: usize,
+    pub samples: usize,
+}
+
+/// Two patterns which differ only in a few cells, see [`Module::near_duplicate_patterns`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NearDuplicatePatterns {
+    pub first: PatternId,
+    pub second: PatternId,
+
+    /// Number of cells which differ
+    pub cells: usize,
+}
+
+
+impl Module {
+    /// Removes patterns, instruments and samples which are not used by the song.
+    ///
+    /// Patterns are used if the order list references them, including orders after an
+    /// [`Order::EndOfSong`]. In instrument mode instruments are used if a command of a used pattern
+    /// references them and samples are used if a used instrument maps a note to them. In sample
+    /// mode samples are used if a command of a used pattern references them, instruments are not
+    /// played in sample mode and are all kept.
+    ///
+    /// The remaining entries keep their relative order and all references to them (orders,
+    /// commands and instrument sample maps) are updated to the new IDs. Sample map entries of
+    /// instruments kept in sample mode which point to removed samples are cleared. References to
+    /// entries which don't exist in the module are left unchanged.
+    pub fn cleanup(&mut self) -> CleanupSummary {
+        let mut summary = CleanupSummary::default();
+
+        let mut used = vec![false; self.patterns.len()];
+        for order in &self.orders {
+            if let Order::Index(pattern) = order {
+                mark_used(&mut used, *pattern);
+            }
+        }
+        let patterns = retain_used(&mut self.patterns, &used);
+        summary.patterns = removed(&patterns);
+        for order in &mut self.orders {
+            if let Order::Index(pattern) = order {
+                *pattern = remapped(*pattern, &patterns).unwrap();
+            }
+        }
+
+        if self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            let mut used = vec![false; self.instruments.len()];
+            for instrument in self.command_instruments() {
+                mark_used(&mut used, instrument);
+            }
+            let instruments = retain_used(&mut self.instruments, &used);
+            summary.instruments = removed(&instruments);
+            self.remap_command_instruments(&instruments);
+
+            let mut used = vec![false; self.samples.len()];
+            for sample in self.instruments.iter().flat_map(|instrument| instrument.sample_map.map.iter().flatten()) {
+                mark_used(&mut used, *sample);
+            }
+            let samples = retain_used(&mut self.samples, &used);
+            summary.samples = removed(&samples);
+            self.remap_sample_maps(&samples);
+        } else {
+            let mut used = vec![false; self.samples.len()];
+            for instrument in self.command_instruments() {
+                mark_used(&mut used, instrument);
+            }
+            let samples = retain_used(&mut self.samples, &used);
+            summary.samples = removed(&samples);
+            self.remap_command_instruments(&samples);
+            self.remap_sample_maps(&samples);
+        }
+
+        summary
+    }
+
+    /// Merges duplicate samples into one and returns the number of removed samples.
+    ///
+    /// Samples are duplicates if they have the same sample data (or FM patch), C-5 frequency,
+    /// volumes, panning, auto-vibrato and loops. Names and filenames don't matter. Loops are
+    /// compared after clamping them to the sample length, loops which end up empty count as no
+    /// loop. Samples whose data was not loaded yet ([`Sample::deferred`]) are never duplicates.
+    ///
+    /// The first sample of every group of duplicates is kept, instrument sample maps and in sample
+    /// mode also commands referencing the other ones are updated to reference it. The remaining
+    /// samples keep their relative order, references are updated to the new IDs.
+    pub fn dedup_samples(&mut self) -> usize {
+        let canonical = (0..self.samples.len())
+            .map(|idx| {
+                (0..idx)
+                    .find(|&other| same_sample(&self.samples[other], &self.samples[idx]))
+                    .unwrap_or(idx)
+            })
+            .collect::<Vec<_>>();
+        let used = canonical.iter().enumerate().map(|(idx, &canonical)| idx == canonical).collect::<Vec<_>>();
+        let kept = retain_used(&mut self.samples, &used);
+        let remap = canonical.iter().map(|&canonical| kept[canonical]).collect::<Vec<_>>();
+
+        if !self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            self.remap_command_instruments(&remap);
+        }
+        self.remap_sample_maps(&remap);
+        removed(&kept)
+    }
+
+    /// Merges duplicate patterns into one and returns the number of removed patterns.
+    ///
+    /// Patterns are duplicates if they have the same number of rows and the same command in every
+    /// cell, pattern names don't matter. Patterns which were [truncated](Pattern::truncated) during
+    /// parsing are never duplicates.
+    ///
+    /// The first pattern of every group of duplicates is kept and the orders playing the other ones
+    /// are updated to play it. The remaining patterns and their names keep their relative order,
+    /// orders are updated to the new IDs.
+    pub fn dedup_patterns(&mut self) -> usize {
+        let canonical = (0..self.patterns.len())
+            .map(|idx| {
+                (0..idx)
+                    .find(|&other| same_pattern(&self.patterns[other], &self.patterns[idx]))
+                    .unwrap_or(idx)
+            })
+            .collect::<Vec<_>>();
+        let used = canonical.iter().enumerate().map(|(idx, &canonical)| idx == canonical).collect::<Vec<_>>();
+        let mut names = used.iter();
+        self.pattern_names.retain(|_| names.next().copied().unwrap_or(true));
+        let kept = retain_used(&mut self.patterns, &used);
+        let remap = canonical.iter().map(|&canonical| kept[canonical]).collect::<Vec<_>>();
+
+        for order in &mut self.orders {
+            if let Order::Index(pattern) = order {
+                *pattern = remapped(*pattern, &remap).unwrap();
+            }
+        }
+        removed(&kept)
+    }
+
+    /// Returns the pairs of patterns which differ in at least one and at most `max_cells` cells,
+    /// for reviewing which patterns could be merged by hand.
+    ///
+    /// Only patterns with the same number of rows are compared, truncated patterns are skipped.
+    /// Pairs are listed in the order of the first and then the second pattern, identical patterns
+    /// are merged by [`Module::dedup_patterns`] instead.
+    pub fn near_duplicate_patterns(&self, max_cells: usize) -> Vec<NearDuplicatePatterns> {
+        let id = |idx: usize| PatternId::try_from(u8::try_from(idx).unwrap()).unwrap();
+        let mut pairs = Vec::new();
+        for (first, a) in self.patterns.iter().enumerate().filter(|(_, pattern)| !pattern.truncated) {
+            for (second, b) in self.patterns.iter().enumerate().skip(first + 1) {
+                if b.truncated || a.rows.len() != b.rows.len() {
+                    continue;
+                }
+                let cells = diff::pattern_diff(a, b).map_or(0, |diff| diff.cells.len());
+                if (1..=max_cells).contains(&cells) {
+                    pairs.push(NearDuplicatePatterns { first: id(first), second: id(second), cells });
+                }
+            }
+        }
+        pairs
+    }
+
+    /// Updates all references to samples, `mapping[i]` is the new ID of the sample with index `i`.
+    ///
+    /// Rewrites the sample maps of the instruments and in sample mode also the instrument column
+    /// of the commands. References mapped to `None` are cleared, references past the end of the
+    /// mapping are left unchanged. The samples themselves are not moved, reorder
+    /// [`Module::samples`] to match.
+    pub fn remap_samples(&mut self, mapping: &[Option<SampleId>]) {
+        let remap = mapping.iter().map(|id| id.map(u8::from)).collect::<Vec<_>>();
+        if !self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            self.remap_command_instruments(&remap);
+        }
+        self.remap_sample_maps(&remap);
+    }
+
+    /// Updates all references to instruments, `mapping[i]` is the new ID of the instrument with
+    /// index `i`.
+    ///
+    /// In instrument mode rewrites the instrument column of the commands, in sample mode the
+    /// commands reference samples and nothing is changed. References mapped to `None` are
+    /// cleared, references past the end of the mapping are left unchanged. The instruments
+    /// themselves are not moved, reorder [`Module::instruments`] to match.
+    pub fn remap_instruments(&mut self, mapping: &[Option<InstrumentId>]) {
+        if self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            let remap = mapping.iter().map(|id| id.map(u8::from)).collect::<Vec<_>>();
+            self.remap_command_instruments(&remap);
+        }
+    }
+
+    /// Returns the instruments (or samples in sample mode) referenced by commands.
+    fn command_instruments(&self) -> impl Iterator<Item = InstrumentId> + '_ {
+        self.patterns
+            .iter()
+            .flat_map(|pattern| &pattern.rows)
+            .flat_map(Row::iter)
+            .filter_map(|(_, command)| command.instrument)
+    }
+
+    /// Updates the instrument (or sample in sample mode) references of all commands.
+    ///
+    /// References to removed entries are cleared.
+    fn remap_command_instruments(&mut self, remap: &[Option<u8>]) {
+        let commands = self.patterns
+            .iter_mut()
+            .flat_map(|pattern| &mut pattern.rows)
+            .flat_map(Row::commands_mut);
+        for command in commands {
+            command.instrument = command.instrument.and_then(|instrument| remapped(instrument, remap));
+        }
+    }
+
+    /// Updates the sample references of all instrument sample maps.
+    ///
+    /// References to removed samples are cleared.
+    fn remap_sample_maps(&mut self, remap: &[Option<u8>]) {
+        let samples = self.instruments
+            .iter_mut()
+            .flat_map(|instrument| instrument.sample_map.map.iter_mut());
+        for sample in samples {
+            *sample = sample.and_then(|sample| remapped(sample, remap));
+        }
+    }
+}
+
+/// Returns `true` if the patterns have the same commands, see [`Module::dedup_patterns`].
+fn same_pattern(a: &Pattern, b: &Pattern) -> bool {
+    !a.truncated && !b.truncated && a.rows.len() == b.rows.len() && diff::pattern_diff(a, b).is_none()
+}
+
+/// Returns `true` if the samples play the same way, see [`Module::dedup_samples`].
+fn same_sample(a: &Sample, b: &Sample) -> bool {
+    let loaded = |sample: &Sample| sample.data.is_some() || sample.deferred.is_none();
+    let same_data = match (&a.data, &b.data) {
+        (Some(a), Some(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.to_bits() == b.to_bits()),
+        (None, None) => true,
+        _ => false,
+    };
+    let len = usize::try_from(a.length()).unwrap_or(usize::MAX);
+    loaded(a)
+        && loaded(b)
+        && a.channels == b.channels
+        && same_data
+        && a.fm_patch == b.fm_patch
+        && a.samplerate_c5 == b.samplerate_c5
+        && a.global_volume == b.global_volume
+        && a.default_volume == b.default_volume
+        && a.default_panning == b.default_panning
+        && (a.vibrato_speed, a.vibrato_depth, a.vibrato_rate, a.vibrato_type)
+            == (b.vibrato_speed, b.vibrato_depth, b.vibrato_rate, b.vibrato_type)
+        && normalized_loop(a.loop_, len) == normalized_loop(b.loop_, len)
+        && normalized_loop(a.sustain_loop, len) == normalized_loop(b.sustain_loop, len)
+}
+
+/// Returns the loop clamped to the sample length, `None` if it's empty.
+fn normalized_loop(sample_loop: Option<SampleLoop>, len: usize) -> Option<(u32, u32, bool)> {
+    let len = u32::try_from(len).unwrap_or(u32::MAX);
+    let SampleLoop { start, end, bidi } = sample_loop?;
+    let end = end.min(len);
+    (start < end).then_some((start, end, bidi))
+}
+
+fn mark_used<I: Copy>(used: &mut [bool], id: I)
+where
+    u8: From<I>,
+{
+    if let Some(used) = used.get_mut(usize::from(u8::from(id))) {
+        *used = true;
+    }
+}
+
+/// Removes the entries not marked as used, returns the new index of every original entry.
+fn retain_used<T>(items: &mut Vec<T>, used: &[bool]) -> Vec<Option<u8>> {
+    let mut next = 0u8;
+    let remap = used.iter()
+        .map(|&used| {
+            used.then(|| {
+                next += 1;
+                next - 1
+            })
+        })
+        .collect();
+    let mut used = used.iter();
+    items.retain(|_| *used.next().unwrap());
+    remap
+}
+
+/// Returns the number of entries removed by [`retain_used`].
+fn removed(remap: &[Option<u8>]) -> usize {
+    remap.iter().filter(|new| new.is_none()).count()
+}
+
+/// Returns the new ID of the entry, `None` if it was removed.
+///
+/// IDs past the end of the original list are returned unchanged.
+fn remapped<I: Copy + TryFrom<u8>>(id: I, remap: &[Option<u8>]) -> Option<I>
+where
+    u8: From<I>,
+{
+    match remap.get(usize::from(u8::from(id))) {
+        Some(Some(new)) => I::try_from(*new).ok(),
+        Some(None) => None,
+        None => Some(id),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn cleanup() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.flags.remove(ModuleFlags::USE_INSTRUMENTS);
+        module.samples = vec![module.samples[0].clone(); 3];
+        module.patterns = vec![module.patterns[0].clone(); 3];
+        module.orders = vec![Order::Index(PatternId::try_from(2).unwrap())];
+
+        // Only the last pattern is used and it only references the second sample.
+        let mut rows = vec![Row::empty(); 2];
+        rows[0].insert(Channel::new(1), Command {
+            note: None,
+            instrument: Some(InstrumentId::try_from(1).unwrap()),
+            volume: None,
+            effect: None,
+        });
+        module.patterns[2].rows = rows;
+
+        let summary = module.cleanup();
+        assert_eq!(summary, CleanupSummary { patterns: 2, instruments: 0, samples: 2 });
+        assert_eq!(module.orders, [Order::Index(PatternId::try_from(0).unwrap())]);
+        assert_eq!(module.patterns.len(), 1);
+        assert_eq!(module.samples.len(), 1);
+        let command = module.patterns[0].command(0, Channel::new(1)).unwrap();
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(0).unwrap()));
+    }
+    #[test]
+    fn dedup_samples() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.flags.remove(ModuleFlags::USE_INSTRUMENTS);
+        let sample = Sample {
+            data: Some(vec![0.0, 0.5, -0.5, 0.0].into()),
+            loop_: Some(SampleLoop { start: 1, end: 100, bidi: false }),
+            sustain_loop: None,
+            deferred: None,
+            ..module.samples[0].clone()
+        };
+        let mut other = sample.clone();
+        other.data = Some(vec![0.0, 0.5, 0.5, 0.0].into());
+        let mut renamed = sample.clone();
+        renamed.name.bytes[0] = b'x';
+        renamed.loop_ = Some(SampleLoop { start: 1, end: 4, bidi: false });
+        module.samples = vec![sample, other, renamed];
+
+        let mut rows = vec![Row::empty(); 1];
+        for (channel, sample) in [(1, 1), (2, 2)] {
+            rows[0].insert(Channel::new(channel), Command {
+                note: None,
+                instrument: Some(InstrumentId::try_from(sample).unwrap()),
+                volume: None,
+                effect: None,
+            });
+        }
+        module.patterns[0].rows = rows;
+
+        assert_eq!(module.dedup_samples(), 1);
+        assert_eq!(module.samples.len(), 2);
+        let instrument = |channel| module.patterns[0].command(0, Channel::new(channel)).unwrap().instrument;
+        assert_eq!(instrument(1), Some(InstrumentId::try_from(1).unwrap()));
+        assert_eq!(instrument(2), Some(InstrumentId::try_from(0).unwrap()));
+    }
+
+    #[test]
+    fn dedup_patterns() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let pattern = module.patterns[0].clone();
+        let mut changed = pattern.clone();
+        changed.set_instrument(3, Channel::new(5), InstrumentId::try_from(0).unwrap());
+        module.patterns = vec![pattern.clone(), changed, pattern];
+        module.pattern_names = vec![String::from("a"), String::from("b"), String::from("c")];
+        let id = |idx| PatternId::try_from(idx).unwrap();
+        module.orders = vec![Order::Index(id(2)), Order::Index(id(1)), Order::Index(id(0))];
+
+        assert_eq!(module.near_duplicate_patterns(1), [
+            NearDuplicatePatterns { first: id(0), second: id(1), cells: 1 },
+            NearDuplicatePatterns { first: id(1), second: id(2), cells: 1 },
+        ]);
+        assert_eq!(module.dedup_patterns(), 1);
+        assert_eq!(module.patterns.len(), 2);
+        assert_eq!(module.pattern_names, ["a", "b"]);
+        assert_eq!(module.orders, [Order::Index(id(0)), Order::Index(id(1)), Order::Index(id(0))]);
+        assert!(module.near_duplicate_patterns(0).is_empty());
+    }
+
+    #[test]
+    fn remap_samples() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let (first, second) = (SampleId::try_from(0).unwrap(), SampleId::try_from(1).unwrap());
+        module.instruments = vec![crate::formats::empty_instrument()];
+        module.instruments[0].sample_map.map[60] = Some(first);
+        module.instruments[0].sample_map.map[61] = Some(second);
+        module.patterns[0].rows = vec![Row::empty(); 1];
+        module.patterns[0].set_instrument(0, Channel::new(1), InstrumentId::try_from(0).unwrap());
+        let instrument = |module: &Module| module.patterns[0].command(0, Channel::new(1)).unwrap().instrument;
+
+        // Instrument mode, the commands reference the instrument.
+        module.flags.insert(ModuleFlags::USE_INSTRUMENTS);
+        module.remap_samples(&[Some(second), None]);
+        assert_eq!(module.instruments[0].sample_map.map[60..62], [Some(second), None]);
+        assert_eq!(instrument(&module), Some(InstrumentId::try_from(0).unwrap()));
+        module.remap_instruments(&[Some(InstrumentId::try_from(4).unwrap())]);
+        assert_eq!(instrument(&module), Some(InstrumentId::try_from(4).unwrap()));
+
+        // Sample mode, the commands reference samples and instruments are not referenced.
+        module.flags.remove(ModuleFlags::USE_INSTRUMENTS);
+        module.remap_instruments(&[None; 8]);
+        assert_eq!(instrument(&module), Some(InstrumentId::try_from(4).unwrap()));
+        module.remap_samples(&[None, Some(first)]);
+        assert_eq!(module.instruments[0].sample_map.map[60], Some(first));
+        assert_eq!(instrument(&module), Some(InstrumentId::try_from(4).unwrap()));
+        module.remap_samples(&[None; 8]);
+        assert_eq!(instrument(&module), None);
+    }
+}
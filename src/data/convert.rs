@@ -0,0 +1,121 @@
+//! Sample data conversions
+//!
+//! [`Sample::data`] is kept normalized, 8-bit PCM is decoded as `s / 127` and 16-bit PCM as
+//! `s / 32767`, the same way as the parser does it. The functions here convert between the
+//! normalized values and the stored PCM values, between bit depths and between signed and unsigned
+//! PCM (the signed flag in the convert byte of the header), so that the scaling stays
+//! consistent with the rest of the crate.
+//!
+//! Normalized values are rounded to the nearest PCM value and values out of range are clipped.
+
+use super::*;
+
+
+/// Decodes the signed 8-bit PCM value to the normalized value.
+pub fn i8_to_f32(s: i8) -> f32 {
+    f32::from(s) / f32::from(i8::MAX)
+}
+
+/// Decodes the signed 16-bit PCM value to the normalized value.
+pub fn i16_to_f32(s: i16) -> f32 {
+    f32::from(s) / f32::from(i16::MAX)
+}
+
+/// Encodes the normalized value as signed 8-bit PCM, clipping values out of range.
+pub fn f32_to_i8(x: f32) -> i8 {
+    // Saturating cast, NaN is converted to 0.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    { float::round(x * f32::from(i8::MAX)) as i8 }
+}
+
+/// Encodes the normalized value as signed 16-bit PCM, clipping values out of range.
+pub fn f32_to_i16(x: f32) -> i16 {
+    // Saturating cast, NaN is converted to 0.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    { float::round(x * f32::from(i16::MAX)) as i16 }
+}
+
+/// Widens the 8-bit PCM value to 16 bits, the value is stored in the high byte.
+pub fn i8_to_i16(s: i8) -> i16 {
+    i16::from(s) << 8
+}
+
+/// Narrows the 16-bit PCM value to 8 bits by dropping the low byte.
+///
+/// This is the inverse of [`i8_to_i16`], other values are rounded down.
+pub fn i16_to_i8(s: i16) -> i8 {
+    i8::try_from(s >> 8).unwrap()
+}
+
+/// Converts unsigned 8-bit PCM (silence at `0x80`) to signed PCM.
+pub fn u8_to_i8(s: u8) -> i8 {
+    i8::from_le_bytes([s ^ 0x80])
+}
+
+/// Converts signed 8-bit PCM to unsigned PCM (silence at `0x80`).
+pub fn i8_to_u8(s: i8) -> u8 {
+    s.to_le_bytes()[0] ^ 0x80
+}
+
+/// Converts unsigned 16-bit PCM (silence at `0x8000`) to signed PCM.
+pub fn u16_to_i16(s: u16) -> i16 {
+    i16::from_le_bytes((s ^ 0x8000).to_le_bytes())
+}
+
+/// Converts signed 16-bit PCM to unsigned PCM (silence at `0x8000`).
+pub fn i16_to_u16(s: i16) -> u16 {
+    u16::from_le_bytes(s.to_le_bytes()) ^ 0x8000
+}
+
+/// Mixes interleaved stereo frames down to mono by averaging the channels.
+///
+/// A trailing value without its pair is dropped.
+pub fn stereo_to_mono(data: &[f32]) -> Vec<f32> {
+    data.chunks_exact(2).map(|frame| (frame[0] + frame[1]) / 2.0).collect()
+}
+
+/// Duplicates every value into an interleaved stereo frame.
+pub fn mono_to_stereo(data: &[f32]) -> Vec<f32> {
+    data.iter().flat_map(|&x| [x, x]).collect()
+}
+
+
+impl Sample {
+    /// Returns the sample data as 16-bit PCM, `None` if the sample has no data.
+    ///
+    /// See [`f32_to_i16`], 8-bit data is returned in the high byte.
+    pub fn data_i16(&self) -> Option<Vec<i16>> {
+        self.data.as_ref().map(|data| data.iter().copied().map(f32_to_i16).collect())
+    }
+
+    /// Returns the sample data as 8-bit PCM, `None` if the sample has no data.
+    ///
+    /// See [`f32_to_i8`], 16-bit data loses its precision.
+    pub fn data_i8(&self) -> Option<Vec<i8>> {
+        self.data.as_ref().map(|data| data.iter().copied().map(f32_to_i8).collect())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        for s in i8::MIN..=i8::MAX {
+            assert_eq!(f32_to_i8(i8_to_f32(s)), s);
+            assert_eq!(i16_to_i8(i8_to_i16(s)), s);
+            assert_eq!(u8_to_i8(i8_to_u8(s)), s);
+        }
+        for s in [i16::MIN, -1, 0, 1, i16::MAX] {
+            assert_eq!(f32_to_i16(i16_to_f32(s)), s);
+            assert_eq!(u16_to_i16(i16_to_u16(s)), s);
+        }
+        assert_eq!(i8_to_u8(0), 0x80);
+        assert_eq!(i16_to_u16(i16::MIN), 0);
+        assert_eq!(f32_to_i16(2.0), i16::MAX);
+        assert_eq!(f32_to_i8(f32::NAN), 0);
+        assert_eq!(stereo_to_mono(&mono_to_stereo(&[0.5, -1.0])), [0.5, -1.0]);
+    }
+}
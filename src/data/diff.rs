@@ -0,0 +1,246 @@
+//! Structural differences between modules
+//!
+//! [`diff()`] compares two modules part by part and reports what changed: the header fields, the
+//! patterns down to the cells, the samples and the instruments. Entries are matched by their
+//! index, an entry present only in one of the modules is added or removed. Sample data is compared
+//! by a hash of the decoded values, data which was not loaded ([`Sample::deferred`]) is not
+//! compared.
+//!
+//! ```
+//! # use ittech::Module;
+//! # use ittech::diff::{diff, Change};
+//! # fn review(old: &Module, new: &Module) {
+//! for change in diff(old, new).patterns {
+//!     if let Change::Modified(pattern, changes) = change {
+//!         println!("pattern {:?}: {} cells changed", pattern, changes.cells.len());
+//!     }
+//! }
+//! # }
+//! ```
+
+use super::*;
+use core::fmt::Debug;
+
+
+/// Differences between two modules, see [`diff()`]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModuleDiff {
+    /// Header fields which differ, in their order in [`HeaderField`]
+    pub header: Vec<HeaderField>,
+
+    /// Changed patterns by ID
+    pub patterns: Vec<Change<PatternId, PatternDiff>>,
+
+    /// Changed samples by ID
+    pub samples: Vec<Change<SampleId, SampleDiff>>,
+
+    /// Changed instruments by ID
+    pub instruments: Vec<Change<InstrumentId, ()>>,
+}
+
+/// Change of an entry of the module
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Change<I, D> {
+    /// Entry is only in the new module
+    Added(I),
+
+    /// Entry is only in the old module
+    Removed(I),
+
+    /// Entry is in both modules and differs
+    Modified(I, D),
+}
+
+/// Field of the module outside the patterns, samples and instruments
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HeaderField {
+    Name,
+    Message,
+    Highlight,
+    MadeWithVersion,
+    CompatibleWithVersion,
+    Flags,
+    GlobalVolume,
+    SampleVolume,
+    Speed,
+    Tempo,
+    PanSeparation,
+    PitchWheelDepth,
+    ChannelPanning,
+    ChannelVolume,
+    Orders,
+    MidiConfig,
+    EditHistory,
+    ChannelCount,
+    PatternNames,
+    ChannelNames,
+    Extensions,
+    Opaque,
+}
+
+/// Differences between two versions of a pattern
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PatternDiff {
+    /// Old and new number of rows if it changed
+    pub rows: Option<(usize, usize)>,
+
+    /// Row index and channel of the cells which differ, in row and channel order
+    ///
+    /// Cells of rows present only in one of the patterns count as changed if they have a command.
+    pub cells: Vec<(usize, Channel)>,
+}
+
+/// Differences between two versions of a sample
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SampleDiff {
+    /// Any field apart from the data differs: names, volumes, panning, loops, C-5 speed,
+    /// vibrato or the FM patch
+    pub header: bool,
+
+    /// Old and new hash of the sample data if it differs
+    pub data: Option<(u64, u64)>,
+}
+
+impl ModuleDiff {
+    /// Returns `true` if the modules don't differ.
+    pub fn is_empty(&self) -> bool {
+        self.header.is_empty() && self.patterns.is_empty() && self.samples.is_empty() && self.instruments.is_empty()
+    }
+}
+
+
+/// Compares the modules.
+pub fn diff(old: &Module, new: &Module) -> ModuleDiff {
+    ModuleDiff {
+        header: header(old, new),
+        patterns: entries(&old.patterns, &new.patterns, pattern_diff),
+        samples: entries(&old.samples, &new.samples, sample_diff),
+        instruments: entries(&old.instruments, &new.instruments, |old, new| differs(old, new).then_some(())),
+    }
+}
+
+fn header(old: &Module, new: &Module) -> Vec<HeaderField> {
+    let fields = [
+        (HeaderField::Name, old.name.bytes != new.name.bytes),
+        (HeaderField::Message, old.message != new.message),
+        (HeaderField::Highlight, old.highlight != new.highlight),
+        (HeaderField::MadeWithVersion, old.made_with_version != new.made_with_version),
+        (HeaderField::CompatibleWithVersion, old.compatible_with_version != new.compatible_with_version),
+        (HeaderField::Flags, old.raw_flags() != new.raw_flags()),
+        (HeaderField::GlobalVolume, old.global_volume != new.global_volume),
+        (HeaderField::SampleVolume, old.sample_volume != new.sample_volume),
+        (HeaderField::Speed, old.speed != new.speed),
+        (HeaderField::Tempo, old.tempo != new.tempo),
+        (HeaderField::PanSeparation, old.pan_separation != new.pan_separation),
+        (HeaderField::PitchWheelDepth, old.pitch_wheel_depth != new.pitch_wheel_depth),
+        (HeaderField::ChannelPanning, old.init_channel_panning != new.init_channel_panning),
+        (HeaderField::ChannelVolume, old.init_channel_volume != new.init_channel_volume),
+        (HeaderField::Orders, old.orders != new.orders),
+        (HeaderField::MidiConfig, old.midi_config != new.midi_config),
+        (HeaderField::EditHistory, old.edit_history != new.edit_history),
+        (HeaderField::ChannelCount, old.openmpt_channel_count != new.openmpt_channel_count),
+        (HeaderField::PatternNames, old.pattern_names != new.pattern_names),
+        (HeaderField::ChannelNames, old.channel_names != new.channel_names),
+        (HeaderField::Extensions, old.openmpt_extensions != new.openmpt_extensions),
+        (HeaderField::Opaque, old.opaque != new.opaque),
+    ];
+    fields.iter()
+        .filter(|(_, differs)| *differs)
+        .map(|(field, _)| *field)
+        .collect()
+}
+
+/// Compares the entries with the same index, `compare` returns `None` if they are the same.
+fn entries<T, I, D>(old: &[T], new: &[T], compare: impl Fn(&T, &T) -> Option<D>) -> Vec<Change<I, D>>
+where
+    I: TryFrom<u8>,
+{
+    let id = |idx: usize| {
+        u8::try_from(idx).ok().and_then(|idx| I::try_from(idx).ok()).expect("BUG: module has more entries than IDs")
+    };
+    (0..old.len().max(new.len()))
+        .filter_map(|idx| match (old.get(idx), new.get(idx)) {
+            (Some(old), Some(new)) => compare(old, new).map(|diff| Change::Modified(id(idx), diff)),
+            (Some(_), None) => Some(Change::Removed(id(idx))),
+            (None, Some(_)) => Some(Change::Added(id(idx))),
+            (None, None) => None,
+        })
+        .collect()
+}
+
+pub(crate) fn pattern_diff(old: &Pattern, new: &Pattern) -> Option<PatternDiff> {
+    let empty = Row::empty();
+    let mut cells = Vec::new();
+    for idx in 0..old.rows.len().max(new.rows.len()) {
+        let old = old.rows.as_slice().get(idx).unwrap_or(&empty);
+        let new = new.rows.as_slice().get(idx).unwrap_or(&empty);
+        let channels = old.iter().chain(new.iter()).map(|(channel, _)| channel).collect::<ActiveChannels>();
+        for channel in channels.iter() {
+            if differs(&old.get(channel), &new.get(channel)) {
+                cells.push((idx, channel));
+            }
+        }
+    }
+    let rows = (old.rows.len() != new.rows.len()).then_some((old.rows.len(), new.rows.len()));
+    (rows.is_some() || !cells.is_empty()).then_some(PatternDiff { rows, cells })
+}
+
+fn sample_diff(old: &Sample, new: &Sample) -> Option<SampleDiff> {
+    let fields = |sample: &Sample| (
+        sample.name.bytes,
+        sample.filename.bytes,
+        (sample.global_volume, sample.default_volume, sample.default_panning),
+        (sample.loop_, sample.sustain_loop),
+        (sample.samplerate_c5, sample.channels),
+        (sample.vibrato_speed, sample.vibrato_depth, sample.vibrato_rate, sample.vibrato_type),
+        sample.fm_patch,
+    );
+    let header = differs(&fields(old), &fields(new));
+    let hashes = (fingerprint(old.data.as_deref()), fingerprint(new.data.as_deref()));
+    let data = (hashes.0 != hashes.1).then_some(hashes);
+    (header || data.is_some()).then_some(SampleDiff { header, data })
+}
+
+/// Compares values which don't implement `PartialEq` by their debug representation.
+fn differs<T: Debug>(old: &T, new: &T) -> bool {
+    format!("{:?}", old) != format!("{:?}", new)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn module_diff() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let old = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        assert!(diff(&old, &old.clone()).is_empty());
+
+        let mut new = old.clone();
+        new.tempo = RangedU8::try_from(150).unwrap();
+        new.message = String::from("edited");
+        new.patterns[0].rows[3] = Row::empty();
+        new.patterns[0].rows.push(Row::empty());
+        new.patterns.push(new.patterns[0].clone());
+        new.samples[0].data = Some(vec![0.5; 4].into());
+        new.samples[0].name = Name::new_truncate("new");
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.header, [HeaderField::Message, HeaderField::Tempo]);
+        let cells = old.patterns[0].rows[3].iter().map(|(channel, _)| (3, channel)).collect();
+        let rows = Some((old.patterns[0].rows.len(), old.patterns[0].rows.len() + 1));
+        assert_eq!(changes.patterns, [
+            Change::Modified(PatternId::try_from(0).unwrap(), PatternDiff { rows, cells }),
+            Change::Added(PatternId::try_from(1).unwrap()),
+        ]);
+        assert!(matches!(
+            changes.samples[..],
+            [Change::Modified(_, SampleDiff { header: true, data: Some((old, new)) })] if old != new
+        ));
+        assert!(changes.instruments.is_empty());
+        assert!(!diff(&new, &old).patterns.is_empty());
+    }
+}
@@ -0,0 +1,128 @@
+use super::*;
+
+
+impl Sample {
+    /// Scales the sample data so its loudest value reaches `peak`, returns the gain applied.
+    ///
+    /// A `peak` of `1.0` uses the full range, smaller values leave headroom. Returns `None` and
+    /// leaves the sample unchanged if it has no data or is silent.
+    pub fn normalize(&mut self, peak: f32) -> Option<f32> {
+        let data = self.data.as_mut()?;
+        let loudest = data.iter().fold(0.0f32, |loudest, value| loudest.max(value.abs()));
+        if loudest == 0.0 {
+            return None;
+        }
+        let gain = peak / loudest;
+        if gain != 1.0 {
+            data.iter_mut().for_each(|value| *value *= gain);
+        }
+        Some(gain)
+    }
+
+    /// Removes the frames not louder than `threshold` from the start and the end of the sample
+    /// data, returns the number of frames removed from the start and from the end.
+    ///
+    /// A frame of a stereo sample is loud if either of its channels is. Loops are moved with the data so they cover the same audio. Loops reaching into the
+    /// removed parts are shortened to the remaining data, loops left empty are cleared. A silent
+    /// sample is trimmed to no values at all.
+    pub fn trim_silence(&mut self, threshold: f32) -> (u32, u32) {
+        let channels = usize::from(self.channels.as_u8());
+        let data = match &mut self.data {
+            Some(data) => data,
+            None => return (0, 0),
+        };
+        let frames = data.len() / channels;
+        let loud = |frame: &[f32]| frame.iter().any(|value| value.abs() > threshold);
+        let start = data.chunks_exact(channels).position(loud).unwrap_or(frames);
+        let end = data.chunks_exact(channels).rposition(loud).map_or(start, |last| last + 1);
+        let removed = (start, frames - end);
+        if removed == (0, 0) {
+            return (0, 0);
+        }
+        let values = data.make_mut();
+        values.truncate(end * channels);
+        values.drain(..start * channels);
+
+        let (start, end) = (u32::try_from(start).unwrap_or(u32::MAX), u32::try_from(end).unwrap_or(u32::MAX));
+        for sample_loop in [&mut self.loop_, &mut self.sustain_loop] {
+            *sample_loop = sample_loop.and_then(|sample_loop| {
+                let loop_start = sample_loop.start.clamp(start, end) - start;
+                let loop_end = sample_loop.end.clamp(start, end) - start;
+                (loop_start < loop_end).then_some(SampleLoop { start: loop_start, end: loop_end, ..sample_loop })
+            });
+        }
+        (u32::try_from(removed.0).unwrap_or(u32::MAX), u32::try_from(removed.1).unwrap_or(u32::MAX))
+    }
+
+    /// Fades the first `length` frames of the sample in linearly from silence, returns the length
+    /// of the fade, which is shortened to the length of the data.
+    pub fn fade_in(&mut self, length: u32) -> u32 {
+        let channels = usize::from(self.channels.as_u8());
+        fade(self.data.as_deref_mut(), channels, length, false)
+    }
+
+    /// Fades the last `length` frames of the sample out linearly to silence, see
+    /// [`Sample::fade_in`].
+    pub fn fade_out(&mut self, length: u32) -> u32 {
+        let channels = usize::from(self.channels.as_u8());
+        fade(self.data.as_deref_mut(), channels, length, true)
+    }
+}
+
+
+/// Scales the frames at the start (or the end) of the data from silence (to silence).
+fn fade(data: Option<&mut [f32]>, channels: usize, length: u32, out: bool) -> u32 {
+    let data = match data {
+        Some(data) => data,
+        None => return 0,
+    };
+    let frames = data.len() / channels;
+    let fade = usize::try_from(length).unwrap_or(usize::MAX).min(frames);
+    let values = if out { &mut data[(frames - fade) * channels..frames * channels] } else { &mut data[..fade * channels] };
+    for (i, frame) in values.chunks_exact_mut(channels).enumerate() {
+        let step = if out { fade - 1 - i } else { i };
+        #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+        let gain = step as f32 / fade as f32;
+        frame.iter_mut().for_each(|value| *value *= gain);
+    }
+    u32::try_from(fade).unwrap_or(u32::MAX)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::formats::empty_sample;
+
+    #[test]
+    fn dsp() {
+        let mut sample = empty_sample();
+        sample.data = Some(vec![0.0, 0.01, 0.25, -0.5, 0.25, 0.0, 0.0].into());
+        sample.loop_ = Some(SampleLoop { start: 3, end: 7, bidi: false });
+        sample.sustain_loop = Some(SampleLoop { start: 0, end: 2, bidi: true });
+
+        assert_eq!(sample.normalize(1.0), Some(2.0));
+        assert_eq!(sample.trim_silence(0.05), (2, 2));
+        assert_eq!(sample.data.as_deref(), Some(&[0.5, -1.0, 0.5][..]));
+        assert_eq!(sample.loop_.map(|l| (l.start, l.end)), Some((1, 3)));
+        assert!(sample.sustain_loop.is_none());
+
+        assert_eq!(sample.fade_in(2), 2);
+        assert_eq!(sample.data.as_deref(), Some(&[0.0, -0.5, 0.5][..]));
+        assert_eq!(sample.fade_out(10), 3);
+        assert_eq!(sample.data.as_deref(), Some(&[0.0, -0.5 / 3.0, 0.0][..]));
+
+        let mut silent = empty_sample();
+        silent.data = Some(vec![0.0; 4].into());
+        assert_eq!(silent.normalize(1.0), None);
+        assert_eq!(silent.trim_silence(0.0), (4, 0));
+        assert_eq!(silent.length(), 0);
+
+        let mut stereo = empty_sample();
+        stereo.channels = RangedU8::MAX;
+        stereo.data = Some(vec![0.0, 0.0, 0.0, 0.5, 1.0, 1.0, 0.0, 0.0].into());
+        assert_eq!(stereo.trim_silence(0.0), (1, 1));
+        assert_eq!(stereo.fade_in(1), 1);
+        assert_eq!(stereo.data.as_deref(), Some(&[0.0, 0.0, 1.0, 1.0][..]));
+    }
+}
@@ -0,0 +1,149 @@
+use super::*;
+
+
+/// Code page 437 characters of the bytes 0x80 to 0xFF
+const CP437: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{A0}',
+];
+
+/// Windows-1252 characters of the bytes 0x80 to 0x9F, the rest matches Latin-1
+///
+/// The five unassigned bytes map to the C1 control characters like in Latin-1.
+const WINDOWS_1252: [char; 32] = [
+    '€', '\u{81}', '‚', 'ƒ', '„', '…', '†', '‡', 'ˆ', '‰', 'Š', '‹', 'Œ', '\u{8D}', 'Ž', '\u{8F}',
+    '\u{90}', '‘', '’', '“', '”', '•', '–', '—', '˜', '™', 'š', '›', 'œ', '\u{9D}', 'ž', 'Ÿ',
+];
+
+
+/// Character encoding of the text stored in a module
+///
+/// The format predates Unicode, trackers store the text in the code page of the system they run
+/// on. Impulse Tracker and Schism Tracker use code page 437 of DOS, OpenMPT and ModPlug Tracker
+/// use Windows-1252 on western systems. Newer versions of some trackers write UTF-8.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Encoding {
+    /// UTF-8, invalid sequences are replaced by U+FFFD
+    Utf8,
+
+    /// Code page 437 of IBM PC and DOS
+    Cp437,
+
+    /// Windows-1252, the western code page of Windows
+    Windows1252,
+}
+
+impl Encoding {
+    /// Decodes the text.
+    pub fn decode(self, bytes: &[u8]) -> String {
+        match self {
+            Encoding::Utf8 => String::from_utf8_lossy(bytes).into_owned(),
+            Encoding::Cp437 | Encoding::Windows1252 => bytes.iter().map(|&byte| self.decode_byte(byte)).collect(),
+        }
+    }
+
+    /// Encodes the text, characters missing from the encoding are replaced by `?`.
+    pub fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 => text.as_bytes().to_vec(),
+            Encoding::Cp437 | Encoding::Windows1252 => text.chars().map(|c| self.encode_char(c).unwrap_or(b'?')).collect(),
+        }
+    }
+
+    /// Decodes the song message, the line breaks are converted to `\n`.
+    ///
+    /// Trackers end the lines of the message by `\r`, some converters use `\r\n`.
+    pub fn decode_message(self, bytes: &[u8]) -> String {
+        let text = self.decode(bytes);
+        text.replace("\r\n", "\n").replace('\r', "\n")
+    }
+
+    /// Encodes the song message, the line breaks are converted to `\r`.
+    pub fn encode_message(self, text: &str) -> Vec<u8> {
+        self.encode(&text.replace("\r\n", "\r").replace('\n', "\r"))
+    }
+
+    fn decode_byte(self, byte: u8) -> char {
+        let high = usize::from(byte.wrapping_sub(0x80));
+        match self {
+            _ if byte.is_ascii() => char::from(byte),
+            Encoding::Cp437 => CP437[high],
+            Encoding::Windows1252 => WINDOWS_1252[..].get(high).copied().unwrap_or_else(|| char::from(byte)),
+            Encoding::Utf8 => char::REPLACEMENT_CHARACTER,
+        }
+    }
+
+    fn encode_char(self, c: char) -> Option<u8> {
+        if c.is_ascii() {
+            return u8::try_from(c).ok();
+        }
+        let high = match self {
+            Encoding::Cp437 => CP437.iter().position(|&other| other == c),
+            Encoding::Windows1252 => WINDOWS_1252.iter().position(|&other| other == c).or_else(|| {
+                let byte = u8::try_from(c).ok().filter(|&byte| byte >= 0xA0)?;
+                Some(usize::from(byte - 0x80))
+            }),
+            Encoding::Utf8 => None,
+        }?;
+        u8::try_from(high + 0x80).ok()
+    }
+}
+
+impl Name {
+    /// Decodes the name up to the first null byte.
+    pub fn to_string_lossy(&self, encoding: Encoding) -> String {
+        encoding.decode(null_terminated(&self.bytes))
+    }
+}
+
+impl DosFilename {
+    /// Decodes the file name up to the first null byte.
+    pub fn to_string_lossy(&self, encoding: Encoding) -> String {
+        encoding.decode(null_terminated(&self.bytes))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser::{self, ParseOptions};
+    use crate::writer::WriteOptions;
+
+    #[test]
+    fn encodings() {
+        let bytes = b"\x80ber \xE1 \x9C\r\nline\rend\xFF";
+        assert_eq!(Encoding::Cp437.decode_message(bytes), "Çber ß £\nline\nend\u{A0}");
+        assert_eq!(Encoding::Windows1252.decode_message(bytes), "€ber á œ\nline\nendÿ");
+        assert_eq!(Encoding::Utf8.decode(bytes), "\u{FFFD}ber \u{FFFD} \u{FFFD}\r\nline\rend\u{FFFD}");
+
+        for encoding in [Encoding::Cp437, Encoding::Windows1252] {
+            let all = (0x20..=0xFF).collect::<Vec<u8>>();
+            assert_eq!(encoding.encode(&encoding.decode(&all)), all);
+        }
+        assert_eq!(Encoding::Cp437.encode_message("a€\nb\r\n"), b"a?\rb\r");
+
+        let mut name = Name { bytes: [0; 26] };
+        name.bytes[..5].copy_from_slice(b"Caf\x82\x01");
+        assert_eq!(name.to_string_lossy(Encoding::Cp437), "Café\u{1}");
+
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.message = String::from("Café\nbar");
+        let mut file = Vec::new();
+        let write = WriteOptions { message_encoding: Some(Encoding::Cp437), ..WriteOptions::default() };
+        module.write_to_with(&mut file, write).unwrap();
+        let parse = ParseOptions { message_encoding: Some(Encoding::Cp437), ..ParseOptions::default() };
+        let parsed = parser::module_file_with::<VerboseError<&[u8]>>(&file, parse).unwrap();
+        assert_eq!(parsed.message, module.message);
+        let raw = parser::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(raw.message, "Caf\u{FFFD}\rbar");
+    }
+}
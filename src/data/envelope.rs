@@ -1,7 +1,11 @@
 use super::*;
+use crate::error::InvalidEnvelopeError;
+use core::convert::TryFrom;
+use core::ops::{Deref, DerefMut, RangeInclusive};
 
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Envelope {
     /// Envelope Flags
     pub flags: EnvelopeFlags,
@@ -14,6 +18,44 @@ pub struct Envelope {
     pub nodes: Vec<Node>,
 }
 
+/// Which of the instrument envelopes
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum EnvelopeKind {
+    /// Volume envelope, node values are `0..=64`
+    Volume,
+
+    /// Panning envelope, node values are `-32..=32`
+    Panning,
+
+    /// Pitch or filter envelope, node values are `-32..=32`
+    PitchFilter,
+}
+
+/// Guard for validated editing of an instrument envelope
+///
+/// Returned by [`Instrument::envelope_mut`] and friends, dereferences to the envelope. Like
+/// [`OrdersMut`] the edits are done on a copy which is validated and written back to the
+/// instrument only by a successful [`EnvelopeMut::commit`], the envelope is not validated while it
+/// is being edited. Dropping the guard without committing discards the edits.
+pub struct EnvelopeMut<'i> {
+    kind: EnvelopeKind,
+    envelope: &'i mut Envelope,
+    edited: Envelope,
+}
+
+/// Iterator of the values of an envelope, one per tick
+///
+/// Returned by [`Envelope::ticks`] and [`Envelope::ticks_from`].
+#[derive(Clone, Debug)]
+pub struct EnvelopeTicks<'e> {
+    envelope: &'e Envelope,
+    position: u16,
+    elapsed: u32,
+    released: Option<u32>,
+    finished: bool,
+}
+
 bitflags! {
     pub struct EnvelopeFlags: u8 {
         /// Envelope on/off, 1 = on, 0 = off
@@ -37,6 +79,7 @@ bitflags! {
 }
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Node {
     pub value: i8,
     pub tick: u16,
@@ -45,6 +88,7 @@ pub struct Node {
 // TODO Is the loop an inclusive "interval"? That is, is the node marked as `end` used in the loop?
 //      Our guess would be yes, but check with OpenMPT code or interface first.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EnvelopeLoop {
     /// Start - offset of the node
     pub start: u8,
@@ -54,3 +98,362 @@ pub struct EnvelopeLoop {
     /// Must be always `>= start`
     pub end: u8,
 }
+
+
+impl EnvelopeKind {
+    /// Returns the range of valid node values.
+    pub fn value_range(self) -> RangeInclusive<i8> {
+        match self {
+            EnvelopeKind::Volume => 0..=64,
+            EnvelopeKind::Panning | EnvelopeKind::PitchFilter => -32..=32,
+        }
+    }
+}
+
+impl Envelope {
+    /// Maximum number of nodes in an envelope
+    pub const MAX_NODES: usize = 25;
+
+    /// Checks the envelope invariants.
+    ///
+    /// A valid envelope has at most [`Envelope::MAX_NODES`] nodes, strictly increasing node ticks,
+    /// node values in the range given by `kind` and loops referencing existing nodes with start not
+    /// after end. The first violation found is returned.
+    pub fn validate(&self, kind: EnvelopeKind) -> Result<(), InvalidEnvelopeError> {
+        if self.nodes.len() > Envelope::MAX_NODES {
+            return Err(InvalidEnvelopeError::TooManyNodes(self.nodes.len()));
+        }
+
+        let range = kind.value_range();
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if !range.contains(&node.value) {
+                return Err(InvalidEnvelopeError::ValueOutOfRange { node: idx, value: node.value });
+            }
+            if idx > 0 && self.nodes[idx - 1].tick >= node.tick {
+                return Err(InvalidEnvelopeError::UnorderedTick { node: idx });
+            }
+        }
+
+        let valid_loop = |envelope_loop: &EnvelopeLoop| {
+            envelope_loop.start <= envelope_loop.end
+                && usize::from(envelope_loop.end) < self.nodes.len()
+        };
+        if let Some(envelope_loop) = self.envelope_loop.filter(|l| !valid_loop(l)) {
+            return Err(InvalidEnvelopeError::InvalidLoop(envelope_loop));
+        }
+        if let Some(sustain_loop) = self.sustain_loop.filter(|l| !valid_loop(l)) {
+            return Err(InvalidEnvelopeError::InvalidSustainLoop(sustain_loop));
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if node ticks are strictly increasing.
+    pub fn has_increasing_ticks(&self) -> bool {
+        self.nodes
+            .windows(2)
+            .all(|pair| pair[0].tick < pair[1].tick)
+    }
+
+    /// Snaps node ticks to the nearest multiple of `grid`.
+    ///
+    /// Ticks are required to be strictly increasing, if a node snaps onto the same or an earlier
+    /// grid point than the previous node it is nudged forward to the next free grid point instead.
+    /// Nodes are never merged or removed so the loop points keep referencing the same nodes.
+    ///
+    /// If the nudged ticks would not fit into `u16` the envelope is left unchanged and `false` is
+    /// returned. A `grid` of 0 or 1 leaves the envelope unchanged.
+    pub fn quantize_ticks(&mut self, grid: u16) -> bool {
+        if grid <= 1 {
+            return true;
+        }
+
+        let grid = u32::from(grid);
+        let mut ticks = Vec::with_capacity(self.nodes.len());
+        let mut previous: Option<u32> = None;
+        for node in &self.nodes {
+            let tick = u32::from(node.tick);
+            let mut snapped = (tick + grid / 2) / grid * grid;
+            if let Some(previous) = previous {
+                if snapped <= previous {
+                    snapped = previous + grid;
+                }
+            }
+            match u16::try_from(snapped) {
+                Ok(snapped) => ticks.push(snapped),
+                Err(_) => return false,
+            }
+            previous = Some(snapped);
+        }
+
+        for (node, tick) in self.nodes.iter_mut().zip(ticks) {
+            node.tick = tick;
+        }
+        debug_assert!(self.has_increasing_ticks());
+        true
+    }
+
+    /// Returns the value of the envelope `tick` ticks after the start of the note.
+    ///
+    /// The note is released (Off `==` command) `released` ticks after its start, or held if
+    /// `None`. The sustain loop is played while the note is held and the loop after it is
+    /// released, if they are turned on by the [flags](EnvelopeFlags). Values between the nodes are
+    /// interpolated linearly and the value of the last node is held after the end of the envelope.
+    ///
+    /// The [`EnvelopeFlags::ENABLED`] flag is not checked. Returns `None` for an envelope without
+    /// nodes. Use [`Envelope::ticks`] to get the values of consecutive ticks, this function steps
+    /// through all the preceding ticks.
+    pub fn value_at(&self, tick: u32, released: Option<u32>) -> Option<f32> {
+        let mut ticks = self.ticks(released);
+        for _ in 0..tick {
+            if ticks.finished {
+                break;
+            }
+            ticks.advance();
+        }
+        self.interpolate(ticks.position)
+    }
+
+    /// Returns an iterator of the values of the envelope from the start of the note.
+    ///
+    /// The ticks are played the same way as by [`Envelope::value_at`]. The iterator ends after
+    /// the value of the last node when the envelope is not looping. While a held note is in the
+    /// sustain loop or the loop is on the iterator never ends.
+    pub fn ticks(&self, released: Option<u32>) -> EnvelopeTicks<'_> {
+        self.ticks_from(0, released)
+    }
+
+    /// Returns an iterator of the values of the envelope from the `position`.
+    ///
+    /// Used for envelopes with [`EnvelopeFlags::CARRY`], a new note continues the envelope from
+    /// the [position](EnvelopeTicks::position) reached by the previous note instead of starting
+    /// over. `released` counts the ticks from the new note.
+    pub fn ticks_from(&self, position: u16, released: Option<u32>) -> EnvelopeTicks<'_> {
+        EnvelopeTicks {
+            envelope: self,
+            position,
+            elapsed: 0,
+            released,
+            finished: self.nodes.is_empty(),
+        }
+    }
+
+    /// Returns the value at the position in the envelope, interpolated between the nodes.
+    pub(crate) fn interpolate(&self, position: u16) -> Option<f32> {
+        let nodes = self.nodes.as_slice();
+        match nodes.iter().position(|node| node.tick > position) {
+            Some(0) => Some(f32::from(nodes[0].value)),
+            Some(next) => {
+                let (a, b) = (nodes[next - 1], nodes[next]);
+                let fraction = f32::from(position - a.tick) / f32::from(b.tick - a.tick);
+                Some(f32::from(a.value) + (f32::from(b.value) - f32::from(a.value)) * fraction)
+            }
+            None => nodes.last().map(|node| f32::from(node.value)),
+        }
+    }
+
+    /// Returns the position one tick later, and `true` if the end of the envelope was reached.
+    pub(crate) fn step(&self, position: u16, key_on: bool) -> (u16, bool) {
+        let node_tick = |index: u8| self.nodes.as_slice().get(usize::from(index)).map(|node| node.tick);
+        let envelope_loop = if key_on && self.flags.contains(EnvelopeFlags::SUSTAIN) {
+            self.sustain_loop
+        } else {
+            None
+        };
+        let envelope_loop = envelope_loop.or_else(|| self.envelope_loop.filter(|_| self.flags.contains(EnvelopeFlags::LOOP)));
+
+        let position = position.saturating_add(1);
+        if let Some(envelope_loop) = envelope_loop {
+            if let (Some(start), Some(end)) = (node_tick(envelope_loop.start), node_tick(envelope_loop.end)) {
+                return (if position > end { start } else { position }, false);
+            }
+        }
+        let last = self.nodes.last().map_or(0, |node| node.tick);
+        if position > last {
+            (last, true)
+        } else {
+            (position, false)
+        }
+    }
+}
+
+impl EnvelopeTicks<'_> {
+    /// Returns the position in the envelope of the next value, in envelope ticks.
+    pub fn position(&self) -> u16 {
+        self.position
+    }
+
+    fn advance(&mut self) {
+        let key_on = self.released.is_none_or(|released| self.elapsed < released);
+        let (position, finished) = self.envelope.step(self.position, key_on);
+        self.position = position;
+        self.finished = finished;
+        self.elapsed = self.elapsed.saturating_add(1);
+    }
+}
+
+impl Iterator for EnvelopeTicks<'_> {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.finished {
+            return None;
+        }
+        let value = self.envelope.interpolate(self.position);
+        self.advance();
+        value
+    }
+}
+
+impl<'i> EnvelopeMut<'i> {
+    pub(crate) fn new(kind: EnvelopeKind, envelope: &'i mut Envelope) -> EnvelopeMut<'i> {
+        let edited = envelope.clone();
+        EnvelopeMut { kind, envelope, edited }
+    }
+
+    /// Returns which envelope is being edited.
+    pub fn kind(&self) -> EnvelopeKind {
+        self.kind
+    }
+
+    /// Validates the edited envelope and writes it back to the instrument.
+    ///
+    /// See [`Envelope::validate`] for the checked invariants. If validation fails the instrument
+    /// is left unchanged and the edits are discarded.
+    pub fn commit(self) -> Result<(), InvalidEnvelopeError> {
+        self.edited.validate(self.kind)?;
+        *self.envelope = self.edited;
+        Ok(())
+    }
+}
+
+impl Deref for EnvelopeMut<'_> {
+    type Target = Envelope;
+    fn deref(&self) -> &Self::Target {
+        &self.edited
+    }
+}
+
+impl DerefMut for EnvelopeMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.edited
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::formats::empty_instrument;
+
+    #[test]
+    fn value_at() {
+        let node = |tick, value| Node { tick, value };
+        let envelope = Envelope {
+            flags: EnvelopeFlags::ENABLED | EnvelopeFlags::SUSTAIN,
+            envelope_loop: None,
+            sustain_loop: Some(EnvelopeLoop { start: 1, end: 2 }),
+            nodes: vec![node(0, 0), node(2, 64), node(4, 32), node(6, 0)],
+        };
+
+        assert_eq!(envelope.value_at(1, None), Some(32.0));
+        // The held note loops between ticks 2 and 4.
+        assert_eq!(envelope.ticks(None).take(8).collect::<Vec<_>>(), [0.0, 32.0, 64.0, 48.0, 32.0, 64.0, 48.0, 32.0]);
+        // Released at tick 3, the envelope plays to the end.
+        assert_eq!(envelope.ticks(Some(3)).collect::<Vec<_>>(), [0.0, 32.0, 64.0, 48.0, 32.0, 16.0, 0.0]);
+        assert_eq!(envelope.value_at(100, Some(0)), Some(0.0));
+        assert_eq!(envelope.ticks_from(5, Some(0)).collect::<Vec<_>>(), [16.0, 0.0]);
+    }
+
+    #[test]
+    fn envelope_mut() {
+        let mut module = ModuleBuilder::new().build().unwrap();
+        module.instruments.push(empty_instrument());
+        let id = InstrumentId::try_from(0).unwrap();
+        let node = |tick, value| Node { tick, value };
+        let nodes = |module: &Module| module[id].volume_envelope.nodes.iter().map(|node| (node.tick, node.value)).collect::<Vec<_>>();
+
+        // A valid edit is written back on commit, dropping the guard discards the edits.
+        let mut envelope = module.get_mut(id).unwrap().volume_envelope_mut();
+        envelope.flags = EnvelopeFlags::ENABLED | EnvelopeFlags::LOOP;
+        envelope.nodes = vec![node(0, 64), node(10, 32), node(20, 0)];
+        envelope.envelope_loop = Some(EnvelopeLoop { start: 1, end: 2 });
+        envelope.commit().unwrap();
+        assert_eq!(nodes(&module), [(0, 64), (10, 32), (20, 0)]);
+        module.get_mut(id).unwrap().volume_envelope_mut().nodes.clear();
+        assert_eq!(nodes(&module).len(), 3);
+
+        let edit = |module: &mut Module, kind, change: &dyn Fn(&mut Envelope)| {
+            let mut envelope = module.get_mut(id).unwrap().envelope_mut(kind);
+            change(&mut envelope);
+            envelope.commit()
+        };
+        let volume = EnvelopeKind::Volume;
+        assert!(matches!(
+            edit(&mut module, volume, &|envelope| envelope.nodes[2].tick = 10),
+            Err(InvalidEnvelopeError::UnorderedTick { node: 2 }),
+        ));
+        assert!(matches!(
+            edit(&mut module, volume, &|envelope| envelope.nodes.swap(0, 1)),
+            Err(InvalidEnvelopeError::UnorderedTick { node: 1 }),
+        ));
+        assert!(matches!(
+            edit(&mut module, volume, &|envelope| envelope.envelope_loop = Some(EnvelopeLoop { start: 2, end: 1 })),
+            Err(InvalidEnvelopeError::InvalidLoop(EnvelopeLoop { start: 2, end: 1 })),
+        ));
+        assert!(matches!(
+            edit(&mut module, volume, &|envelope| envelope.sustain_loop = Some(EnvelopeLoop { start: 0, end: 3 })),
+            Err(InvalidEnvelopeError::InvalidSustainLoop(EnvelopeLoop { start: 0, end: 3 })),
+        ));
+        assert!(matches!(
+            edit(&mut module, volume, &|envelope| envelope.nodes[1].value = -1),
+            Err(InvalidEnvelopeError::ValueOutOfRange { node: 1, value: -1 }),
+        ));
+        assert!(matches!(
+            edit(&mut module, volume, &|envelope| envelope.nodes = (0..26).map(|tick| node(tick, 0)).collect()),
+            Err(InvalidEnvelopeError::TooManyNodes(26)),
+        ));
+        // The failed commits left the instrument unchanged.
+        assert_eq!(nodes(&module), [(0, 64), (10, 32), (20, 0)]);
+
+        // Single node loops and negative panning values are valid.
+        edit(&mut module, volume, &|envelope| envelope.sustain_loop = Some(EnvelopeLoop { start: 1, end: 1 })).unwrap();
+        edit(&mut module, EnvelopeKind::Panning, &|envelope| envelope.nodes = vec![node(0, -32), node(5, 32)]).unwrap();
+        assert_eq!(module[id].volume_envelope.sustain_loop.map(|l| (l.start, l.end)), Some((1, 1)));
+        assert_eq!(module[id].panning_envelope.nodes.len(), 2);
+    }
+
+    #[test]
+    fn quantize_ticks() {
+        let envelope = |ticks: &[u16]| Envelope {
+            flags: EnvelopeFlags::ENABLED,
+            envelope_loop: None,
+            sustain_loop: None,
+            nodes: ticks.iter().map(|&tick| Node { tick, value: 32 }).collect(),
+        };
+        let ticks = |envelope: &Envelope| envelope.nodes.iter().map(|node| node.tick).collect::<Vec<_>>();
+
+        // Halfway ticks round up, colliding nodes are nudged to the next grid point.
+        let mut quantized = envelope(&[0, 4, 5, 14, 15, 16, 24]);
+        assert!(quantized.quantize_ticks(10));
+        assert_eq!(ticks(&quantized), [0, 10, 20, 30, 40, 50, 60]);
+        let mut quantized = envelope(&[1, 9, 31]);
+        assert!(quantized.quantize_ticks(4));
+        assert_eq!(ticks(&quantized), [0, 8, 32]);
+
+        // A grid of 0 or 1 changes nothing.
+        for grid in [0, 1] {
+            let mut quantized = envelope(&[0, 3, 7]);
+            assert!(quantized.quantize_ticks(grid));
+            assert_eq!(ticks(&quantized), [0, 3, 7]);
+        }
+
+        // Ticks past the range of u16 leave the envelope unchanged.
+        let mut quantized = envelope(&[0, 65_529, 65_530]);
+        assert!(!quantized.quantize_ticks(10));
+        assert_eq!(ticks(&quantized), [0, 65_529, 65_530]);
+        let mut quantized = envelope(&[65_534, 65_535]);
+        assert!(!quantized.quantize_ticks(2));
+        assert_eq!(ticks(&quantized), [65_534, 65_535]);
+    }
+}
@@ -0,0 +1,167 @@
+use super::*;
+use core::time::Duration;
+
+
+/// Event in the song, see [`Module::events`]
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    /// Playback enters the pattern of the order, the first row played is the one of the
+    /// [`SongEvent`], it's not `0` after a break (`Cxx`)
+    PatternStart {
+        order: usize,
+        pattern: PatternId,
+    },
+
+    /// Note is played on the channel, `instrument` is the instrument (or sample in sample mode)
+    /// in the command, `None` uses the last one played on the channel
+    NoteOn {
+        channel: Channel,
+        note: Note,
+        instrument: Option<InstrumentId>,
+    },
+
+    /// Note on the channel is released, cut or faded out by the note column or `SCx`
+    NoteOff {
+        channel: Channel,
+    },
+
+    /// Tempo or speed (ticks per row) change from this tick on, the first event of the song sets
+    /// the initial ones
+    TempoChange {
+        tempo: u8,
+        speed: u8,
+    },
+}
+
+/// Event with the tick it happens on, see [`Module::events`]
+#[derive(Clone, Copy, Debug)]
+pub struct SongEvent {
+    /// Number of ticks played from the start of the song
+    pub tick: usize,
+
+    /// Time from the start of the song to the tick
+    pub time: Duration,
+
+    /// Position in the order list
+    pub order: usize,
+
+    /// Row in the pattern
+    pub row: usize,
+
+    /// What happens on the tick
+    pub event: Event,
+}
+
+impl Module {
+    /// Returns the events of the whole song in play order.
+    ///
+    /// The song is followed tick by tick the same way as by [`Module::estimated_duration`], rows
+    /// played again by pattern loops (`SBx`) produce their events again and jumps skip the rows
+    /// which are not played. Notes are played on the tick of their note delay (`SDx`) and only on
+    /// the first pass of rows repeated by `SEx`, notes delayed past the end of the row are not
+    /// played. Events on the same tick come in the order pattern start, tempo change, then the
+    /// notes by channel.
+    ///
+    /// The events are collected up front, the iterator doesn't borrow the module.
+    pub fn events(&self) -> impl Iterator<Item = SongEvent> {
+        let mut events = Vec::new();
+        let (mut count, mut time) = (0, Duration::ZERO);
+        let mut previous: Option<Tick> = None;
+        self.walk_ticks(|tick| {
+            let (order, row) = (tick.order, tick.row);
+            let mut push = |event| events.push(SongEvent { tick: count, time, order, row, event });
+            let pattern = match self.orders[tick.order] {
+                Order::Index(pattern) => pattern,
+                _ => unreachable!("only patterns are played"),
+            };
+            if previous.is_none_or(|previous| previous.order != tick.order) {
+                push(Event::PatternStart { order: tick.order, pattern });
+            }
+            if previous.is_none_or(|previous| previous.tempo != tick.tempo || previous.speed != tick.speed) {
+                push(Event::TempoChange { tempo: tick.tempo, speed: tick.speed });
+            }
+            let commands = self.get(pattern).and_then(|pattern| pattern.row(tick.row));
+            for (channel, command) in commands.into_iter().flat_map(Row::iter) {
+                let special = match command.effect {
+                    Some(EffectCmd::Special(special)) => special,
+                    _ => None,
+                };
+                if let Some(Special::NoteCut(cut)) = special {
+                    if tick.tick == usize::from(cut.as_u8()) {
+                        push(Event::NoteOff { channel });
+                    }
+                }
+                let delay = match special {
+                    Some(Special::NoteDelay(delay)) => usize::from(delay.as_u8()),
+                    _ => 0,
+                };
+                if tick.tick != delay || delay >= usize::from(tick.speed) {
+                    continue;
+                }
+                match command.note {
+                    Some(NoteCmd::Play(note)) => {
+                        push(Event::NoteOn { channel, note, instrument: command.instrument });
+                    }
+                    Some(NoteCmd::Off) | Some(NoteCmd::Cut) | Some(NoteCmd::Fade) => {
+                        push(Event::NoteOff { channel });
+                    }
+                    Some(NoteCmd::ParamControl { .. }) | None => {}
+                }
+            }
+            previous = Some(tick);
+            count += 1;
+            time += tick_duration(tick.tempo);
+        });
+        events.into_iter()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn events() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.orders = vec![Order::Index(PatternId::try_from(0).unwrap())];
+        module.speed = RangedU8::try_from(3).unwrap();
+        module.tempo = RangedU8::try_from(125).unwrap();
+        let special = |special| Some(EffectCmd::Special(Some(special)));
+        let mut rows = vec![Row::empty(); 2];
+        rows[0].insert(Channel::new(1), Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: None,
+            volume: None,
+            effect: special(Special::NoteCut(RangedU8::try_from(2).unwrap())),
+        });
+        rows[1].insert(Channel::new(2), Command {
+            note: Some(NoteCmd::Play(Note::C_5)),
+            instrument: None,
+            volume: None,
+            effect: special(Special::NoteDelay(RangedU8::try_from(1).unwrap())),
+        });
+        rows[1].insert(Channel::new(3), Command {
+            note: Some(NoteCmd::Off),
+            instrument: None,
+            volume: None,
+            effect: special(Special::NoteDelay(RangedU8::try_from(3).unwrap())),
+        });
+        module.patterns[0].rows = rows;
+
+        let events = module.events()
+            .map(|event| format!("{} {}:{} {:?}", event.tick, event.order, event.row, event.event))
+            .collect::<Vec<_>>();
+        assert_eq!(events, [
+            "0 0:0 PatternStart { order: 0, pattern: 0 }",
+            "0 0:0 TempoChange { tempo: 125, speed: 3 }",
+            "0 0:0 NoteOn { channel: ch01, note: C-5, instrument: None }",
+            "2 0:0 NoteOff { channel: ch01 }",
+            "4 0:1 NoteOn { channel: ch02, note: C-5, instrument: None }",
+        ]);
+        assert_eq!(module.events().last().unwrap().time, Duration::from_millis(80));
+    }
+}
@@ -0,0 +1,158 @@
+use super::*;
+
+
+/// Codes of the instrument properties with a typed field
+const FILTER_MODE: [u8; 4] = *b"FM..";
+const CUTOFF_SWING: [u8; 4] = *b"CS..";
+const RESONANCE_SWING: [u8; 4] = *b"RS..";
+const VOLUME_SWING: [u8; 4] = *b"VS..";
+const PANNING_SWING: [u8; 4] = *b"PS..";
+const INSTRUMENT_RESAMPLING: [u8; 4] = *b"R...";
+
+/// Codes of the song properties with a typed field
+const TEMPO_MODE: [u8; 4] = *b"TM..";
+const SONG_RESAMPLING: [u8; 4] = *b"RSMP";
+
+
+/// Property of the OpenMPT extension blocks, kept as stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ExtensionProperty {
+    /// 4 byte code identifying the property
+    pub code: [u8; 4],
+
+    /// Little-endian value, at most 65535 bytes
+    pub data: Vec<u8>,
+}
+
+/// Extended instrument properties stored by OpenMPT
+///
+/// *OpenMPT extension.* OpenMPT stores settings of instruments the IT format has no room for in
+/// the `XTPM` block after the module data. The properties listed here are decoded, the others are
+/// kept in [`InstrumentExtensions::other`] so that they are written back unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct InstrumentExtensions {
+    /// Filter mode, 0 is the channel default, 1 low-pass and 2 high-pass (`FM..`)
+    pub filter_mode: Option<u8>,
+
+    /// Random variation of the filter cutoff in percent (`CS..`)
+    pub cutoff_swing: Option<u8>,
+
+    /// Random variation of the filter resonance in percent (`RS..`)
+    pub resonance_swing: Option<u8>,
+
+    /// Random variation of the volume in percent (`VS..`)
+    pub volume_swing: Option<u8>,
+
+    /// Random variation of the panning in percent (`PS..`)
+    pub panning_swing: Option<u8>,
+
+    /// Resampling mode of the instrument's notes, OpenMPT's numbering (`R...`)
+    pub resampling: Option<u8>,
+
+    /// Properties without a typed field, in the order they were stored
+    pub other: Vec<ExtensionProperty>,
+}
+
+/// Extended song properties stored by OpenMPT
+///
+/// *OpenMPT extension.* OpenMPT stores song settings the IT format has no room for in the `STPM`
+/// block at the end of the file. The properties listed here are decoded, the others are kept in
+/// [`SongExtensions::other`] so that they are written back unchanged. The channel count is stored
+/// in the same block, it is kept in [`Module::openmpt_channel_count`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SongExtensions {
+    /// Tempo mode, 0 is classic, 1 alternative and 2 modern (`TM..`)
+    pub tempo_mode: Option<u8>,
+
+    /// Default resampling mode, OpenMPT's numbering (`RSMP`)
+    pub resampling: Option<u8>,
+
+    /// Properties without a typed field, in the order they were stored
+    pub other: Vec<ExtensionProperty>,
+}
+
+
+impl InstrumentExtensions {
+    /// Sorts the properties into the typed fields.
+    pub(crate) fn from_properties(properties: Vec<ExtensionProperty>) -> InstrumentExtensions {
+        let mut extensions = InstrumentExtensions::default();
+        for property in properties {
+            let field = match property.code {
+                FILTER_MODE => &mut extensions.filter_mode,
+                CUTOFF_SWING => &mut extensions.cutoff_swing,
+                RESONANCE_SWING => &mut extensions.resonance_swing,
+                VOLUME_SWING => &mut extensions.volume_swing,
+                PANNING_SWING => &mut extensions.panning_swing,
+                INSTRUMENT_RESAMPLING => &mut extensions.resampling,
+                _ => {
+                    extensions.other.push(property);
+                    continue;
+                }
+            };
+            decode(field, property, &mut extensions.other);
+        }
+        extensions
+    }
+
+    /// Returns all properties as they are stored, the typed fields first.
+    pub fn properties(&self) -> Vec<ExtensionProperty> {
+        let typed = [
+            (FILTER_MODE, self.filter_mode),
+            (CUTOFF_SWING, self.cutoff_swing),
+            (RESONANCE_SWING, self.resonance_swing),
+            (VOLUME_SWING, self.volume_swing),
+            (PANNING_SWING, self.panning_swing),
+            (INSTRUMENT_RESAMPLING, self.resampling),
+        ];
+        encode(&typed, &self.other)
+    }
+}
+
+impl SongExtensions {
+    /// Sorts the properties into the typed fields.
+    pub(crate) fn from_properties(properties: Vec<ExtensionProperty>) -> SongExtensions {
+        let mut extensions = SongExtensions::default();
+        for property in properties {
+            let field = match property.code {
+                TEMPO_MODE => &mut extensions.tempo_mode,
+                SONG_RESAMPLING => &mut extensions.resampling,
+                _ => {
+                    extensions.other.push(property);
+                    continue;
+                }
+            };
+            decode(field, property, &mut extensions.other);
+        }
+        extensions
+    }
+
+    /// Returns all properties as they are stored, the typed fields first.
+    pub fn properties(&self) -> Vec<ExtensionProperty> {
+        let typed = [
+            (TEMPO_MODE, self.tempo_mode),
+            (SONG_RESAMPLING, self.resampling),
+        ];
+        encode(&typed, &self.other)
+    }
+}
+
+/// Decodes the value of a one byte property into the field.
+///
+/// OpenMPT may store the value wider than needed, values which don't fit are kept as they are in
+/// `other`.
+fn decode(field: &mut Option<u8>, property: ExtensionProperty, other: &mut Vec<ExtensionProperty>) {
+    match *property.data.as_slice() {
+        [value, ref rest @ ..] if rest.iter().all(|&byte| byte == 0) => *field = Some(value),
+        _ => other.push(property),
+    }
+}
+
+fn encode(typed: &[([u8; 4], Option<u8>)], other: &[ExtensionProperty]) -> Vec<ExtensionProperty> {
+    typed.iter()
+        .filter_map(|&(code, value)| value.map(|value| ExtensionProperty { code, data: vec![value] }))
+        .chain(other.iter().cloned())
+        .collect()
+}
@@ -0,0 +1,77 @@
+use super::*;
+
+
+/// Row of the song in play order, see [`Module::flatten`]
+#[derive(Clone, Debug)]
+pub struct FlatRow {
+    /// Position in the order list
+    pub order: usize,
+
+    /// Pattern of the order
+    pub pattern: PatternId,
+
+    /// Row in the pattern
+    pub row: usize,
+
+    /// Tempo and speed (ticks per row) at the start of the row
+    pub tempo: u8,
+    pub speed: u8,
+
+    /// Commands of the row, empty for patterns missing from the module
+    pub commands: Row,
+}
+
+impl Module {
+    /// Returns the rows of the whole song in the order they are played.
+    ///
+    /// The song is followed the same way as by [`Module::estimated_duration`]: the order list is
+    /// expanded, jumps and breaks (`Bxx`, `Cxx`) skip the rows which are not played and rows
+    /// played again by pattern loops (`SBx`) are listed again. The sequence stops at the end of
+    /// the song or right before the song would loop back to a row which was already played, rows
+    /// repeated by `SEx` are listed once.
+    ///
+    /// The rows are copied, the result doesn't borrow the module.
+    pub fn flatten(&self) -> Vec<FlatRow> {
+        let mut rows = Vec::new();
+        self.walk_ticks(|tick| {
+            if tick.tick != 0 {
+                return;
+            }
+            let pattern = match self.orders[tick.order] {
+                Order::Index(pattern) => pattern,
+                _ => unreachable!("only patterns are played"),
+            };
+            let commands = self.get(pattern).and_then(|pattern| pattern.row(tick.row)).cloned().unwrap_or_else(Row::empty);
+            rows.push(FlatRow { order: tick.order, pattern, row: tick.row, tempo: tick.tempo, speed: tick.speed, commands });
+        });
+        rows
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flatten() {
+        let command = |effect| Command { note: None, instrument: None, volume: None, effect: Some(effect) };
+        let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 4], truncated: false, highlight: None };
+        pattern.set_command(0, Channel::new(1), command(EffectCmd::Special(Some(Special::SetLoopbackPoint))));
+        pattern.set_command(1, Channel::new(1), command(EffectCmd::Special(Some(Special::LoopbackTimes(RangedU8::MIN)))));
+        pattern.set_command(2, Channel::new(1), command(EffectCmd::BreakRow(3)));
+
+        let mut builder = ModuleBuilder::new();
+        let id = builder.add_pattern(pattern).unwrap();
+        let mut module = builder.build().unwrap();
+        module.orders = vec![Order::Index(id), Order::Index(id)];
+
+        // The loop plays the first two rows twice, the break skips the first three rows of the
+        // second order and the song ends after its last row.
+        let rows = module.flatten();
+        let positions = rows.iter().map(|row| (row.order, row.row)).collect::<Vec<_>>();
+        assert_eq!(positions, [(0, 0), (0, 1), (0, 0), (0, 1), (0, 2), (1, 3)]);
+        assert!(rows[4].commands.get(Channel::new(1)).is_some());
+        assert!(rows.iter().all(|row| row.speed == module.speed.as_u8()));
+    }
+}
@@ -0,0 +1,27 @@
+//! Floating point functions
+//!
+//! The methods of `f32` are only available with `std`, without it the functions from `libm` are
+//! used instead.
+
+#[cfg(feature = "std")]
+pub(crate) fn round(x: f32) -> f32 {
+    x.round()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn powf(x: f32, y: f32) -> f32 {
+    x.powf(y)
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn log10(x: f32) -> f32 {
+    x.log10()
+}
+
+#[cfg(feature = "std")]
+pub(crate) fn sin(x: f32) -> f32 {
+    x.sin()
+}
+
+#[cfg(not(feature = "std"))]
+pub(crate) use libm::{log10f as log10, powf, roundf as round, sinf as sin};
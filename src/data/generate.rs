@@ -0,0 +1,479 @@
+// Generating random valid data for property testing and fuzzing.
+//
+// Everything generated here upholds the invariants the rest of the crate relies on (and which IT
+// files can represent): ranged fields are in range, orders, sample maps and pattern commands only
+// reference existing patterns, samples and instruments, envelopes pass `Envelope::validate`,
+// sample loops lie within the sample data and pattern sizes are within the IT limits. The
+// generated modules are also canonical for the writer (see `writer::module_file`), so writing one
+// and parsing the file gives back the same module, which is what the fuzz targets check.
+
+use super::*;
+use crate::parser::{effect as parse_effect, EDIT_HISTORY};
+use ::arbitrary::{Arbitrary, Error, Result, Unstructured};
+use core::convert::TryFrom;
+
+
+impl<'a, const LOW: u8, const HIGH: u8> Arbitrary<'a> for RangedU8<LOW, HIGH> {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(RangedU8::try_from(u.int_in_range(LOW..=HIGH)?).unwrap())
+    }
+}
+
+macro_rules! arbitrary_id {
+    ( $( $name: ident, $high: literal; )* ) => {
+        $(
+            impl<'a> Arbitrary<'a> for $name {
+                fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+                    Ok($name::try_from(u.int_in_range(0..=$high)?).unwrap())
+                }
+            }
+        )*
+    };
+}
+
+arbitrary_id! {
+    InstrumentId, 98;
+    PatternId, 199;
+    SampleId, 98;
+}
+
+impl<'a> Arbitrary<'a> for Name {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Name { bytes: ascii_bytes(u)? })
+    }
+}
+
+impl<'a> Arbitrary<'a> for DosFilename {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(DosFilename { bytes: ascii_bytes(u)? })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Note {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(Note::try_from(u.int_in_range(0..=119)?).unwrap())
+    }
+}
+
+impl<'a> Arbitrary<'a> for NoteCmd {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        Ok(match u.int_in_range(0..=3)? {
+            0 => NoteCmd::Off,
+            1 => NoteCmd::Cut,
+            2 => NoteCmd::Fade,
+            _ => NoteCmd::Play(u.arbitrary()?),
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for VolumeCmd {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Pick uniformly from the valid byte values skipping the gaps.
+        let x = u.int_in_range(0..=124 + (212 - 128 + 1))?;
+        let x = if x <= 124 { x } else { x - 125 + 128 };
+        Ok(VolumeCmd::try_from(x).expect("BUG: generated invalid volume byte"))
+    }
+}
+
+impl<'a> Arbitrary<'a> for EffectCmd {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        // Not all parameters are valid for all effects, the draw is retried a few times before
+        // falling back to an effect that accepts any parameter.
+        for _ in 0..8 {
+            let effect = u.int_in_range(1..=26)?;
+            if let Some(cmd) = parse_effect(effect, u.arbitrary()?) {
+                return Ok(cmd);
+            }
+        }
+        Ok(EffectCmd::SetSampleOffset(SetSampleOffset::Low(u.arbitrary()?)))
+    }
+}
+
+impl<'a> Arbitrary<'a> for Command {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        command(u, 99)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Pattern {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        pattern(u, 99)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Sample {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let (loop_, sustain_loop, data, fm_patch);
+        if u.int_in_range(0..=15)? == 0 {
+            let mut patch = [0; 12];
+            for byte in &mut patch {
+                *byte = u.arbitrary()?;
+            }
+            fm_patch = Some(patch);
+            data = None;
+            loop_ = None;
+            sustain_loop = None;
+        } else {
+            fm_patch = None;
+            data = if u.arbitrary()? {
+                // Values on the 16-bit grid so the data survives encoding.
+                let len = u.int_in_range(0..=4096)?;
+                let data = (0..len)
+                    .map(|_| Ok(f32::from(u.int_in_range(-i16::MAX..=i16::MAX)?) / f32::from(i16::MAX)))
+                    .collect::<Result<Vec<_>>>()?;
+                Some(data)
+            } else {
+                None
+            };
+            let len = data.as_ref().map_or(0, Vec::len);
+            loop_ = sample_loop(u, len)?;
+            sustain_loop = sample_loop(u, len)?;
+        }
+
+        let pan = u.int_in_range(0..=64)?;
+        Ok(Sample {
+            name: u.arbitrary()?,
+            filename: u.arbitrary()?,
+            global_volume: u.int_in_range(0..=64)?,
+            default_volume: u.int_in_range(0..=64)?,
+            default_panning: if u.arbitrary()? { pan | Sample::dfp_usePanning } else { pan },
+            loop_,
+            sustain_loop,
+            samplerate_c5: u.int_in_range(0..=9_999_999)?,
+            vibrato_speed: u.int_in_range(0..=64)?,
+            vibrato_depth: u.int_in_range(0..=64)?,
+            vibrato_rate: u.int_in_range(0..=64)?,
+            vibrato_type: u.int_in_range(0..=3)?,
+            channels: RangedU8::MIN,
+            data: data.map(SampleData::from),
+            fm_patch,
+            conversion: SampleConversion::empty(),
+            encoded: None,
+            deferred: None,
+        })
+    }
+}
+
+impl<'a> Arbitrary<'a> for Instrument {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        instrument(u, 99)
+    }
+}
+
+impl<'a> Arbitrary<'a> for Module {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let stored_flags = u.arbitrary::<u32>()?;
+        let mut flags = ModuleFlags::from_bits_truncate(stored_flags);
+        flags.remove(ModuleFlags::MIDI_CONIFG_EMBEDDED);
+
+        let samples = (0..u.int_in_range(0..=99)?)
+            .map(|_| u.arbitrary())
+            .collect::<Result<Vec<Sample>>>()?;
+
+        let instruments = if flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            (0..u.int_in_range(0..=99)?)
+                .map(|_| instrument(u, samples.len()))
+                .collect::<Result<Vec<_>>>()?
+        } else {
+            Vec::new()
+        };
+
+        // The instrument column references samples directly in sample mode.
+        let instrument_count = if flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            instruments.len()
+        } else {
+            samples.len()
+        };
+        let patterns = (0..u.int_in_range(0..=200)?)
+            .map(|_| pattern(u, instrument_count))
+            .collect::<Result<Vec<_>>>()?;
+
+        let mut orders = (0..u.int_in_range(0..=255)?)
+            .map(|_| {
+                if patterns.is_empty() || u.int_in_range(0..=15)? == 0 {
+                    return Ok(Order::Separator);
+                }
+                let idx = u.int_in_range(0..=patterns.len() - 1)?;
+                Ok(Order::Index(PatternId::try_from(u8::try_from(idx).unwrap()).unwrap()))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if u.arbitrary()? {
+            orders.push(Order::EndOfSong);
+        }
+
+        let message = (0..u.int_in_range(0..=256)?)
+            .map(|_| Ok(char::from(*u.choose(MESSAGE_CHARS)?)))
+            .collect::<Result<String>>()?;
+        flags.set(ModuleFlags::MESSAGE_ATTACHED, !message.is_empty());
+
+        let midi_config = if u.arbitrary()? {
+            let mut config = MidiConfig {
+                global: [MidiMacro::new(""); 9],
+                parametered: [MidiMacro::new(""); 16],
+                fixed: [MidiMacro::new(""); 128],
+            };
+            for macro_ in config.global.iter_mut().chain(&mut config.parametered).chain(config.fixed.iter_mut()) {
+                *macro_ = MidiMacro::new(&ascii_string(u, 31)?);
+            }
+            Some(config)
+        } else {
+            None
+        };
+        flags.set(ModuleFlags::MIDI_CONIFG_EMBEDDED, midi_config.is_some());
+        let mut stored_flags = (stored_flags & !ModuleFlags::all().bits()) | flags.bits();
+
+        // The writer stores the history if there is one, the parser then sees the bit.
+        let edit_history = (0..u.int_in_range(0..=8)?)
+            .map(|_| Ok(EditHistoryEntry { fat_date: u.arbitrary()?, fat_time: u.arbitrary()?, run_time: u.arbitrary()? }))
+            .collect::<Result<Vec<_>>>()?;
+        if !edit_history.is_empty() {
+            stored_flags |= EDIT_HISTORY;
+        }
+
+        let mut init_channel_panning = [ChannelPan::CENTRE; MAX_CHANNELS];
+        for pan in init_channel_panning.iter_mut().take(64) {
+            let value = if u.int_in_range(0..=15)? == 0 { Pan::Surround } else { Pan::Position(u.arbitrary()?) };
+            *pan = ChannelPan::new(value, u.int_in_range(0..=15)? != 0);
+        }
+        let mut init_channel_volume = [ChannelVolume::FULL; MAX_CHANNELS];
+        for volume in init_channel_volume.iter_mut().take(64) {
+            *volume = ChannelVolume::new_clamped(u.int_in_range(0..=64)?);
+        }
+
+        let pattern_names = names(u, patterns.len(), 32)?;
+        let channel_names = names(u, 64, 20)?;
+
+        Ok(Module {
+            name: u.arbitrary()?,
+            message,
+            highlight: (u.arbitrary()?, u.arbitrary()?),
+            made_with_version: u.arbitrary()?,
+            compatible_with_version: u.arbitrary()?,
+            flags,
+            stored_flags,
+            global_volume: u.arbitrary()?,
+            sample_volume: u.arbitrary()?,
+            speed: u.arbitrary()?,
+            tempo: u.arbitrary()?,
+            pan_separation: u.arbitrary()?,
+            pitch_wheel_depth: u.arbitrary()?,
+            init_channel_panning,
+            init_channel_volume,
+            orders,
+            instruments,
+            samples,
+            patterns,
+            midi_config,
+            edit_history,
+            openmpt_channel_count: None,
+            pattern_names,
+            channel_names,
+            openmpt_extensions: None,
+            // Unknown chunks could be mistaken for known ones when parsing, only the reserved
+            // field is generated.
+            opaque: OpaqueData { header_reserved: u.arbitrary()?, ..OpaqueData::default() },
+        })
+    }
+}
+
+
+/// Printable ASCII characters and the IT line break
+const MESSAGE_CHARS: &[u8] = b" !\"#$%&'()*+,-./0123456789:;<=>?@ABCDEFGHIJKLMNOPQRSTUVWXYZ[\\]^_`abcdefghijklmnopqrstuvwxyz{|}~\r";
+
+/// Generates a null-terminated string of printable ASCII characters padded with nulls.
+fn ascii_bytes<const N: usize>(u: &mut Unstructured) -> Result<[u8; N]> {
+    let mut bytes = [0; N];
+    let len = u.int_in_range(0..=N - 1)?;
+    for byte in &mut bytes[..len] {
+        *byte = u.int_in_range(b' '..=b'~')?;
+    }
+    Ok(bytes)
+}
+
+/// Generates a string of at most `max_len` printable ASCII characters.
+fn ascii_string(u: &mut Unstructured, max_len: usize) -> Result<String> {
+    (0..u.int_in_range(0..=max_len)?)
+        .map(|_| Ok(char::from(u.int_in_range(b' '..=b'~')?)))
+        .collect()
+}
+
+/// Generates at most `count` names of at most `max_len` characters, the last one is never empty.
+fn names(u: &mut Unstructured, count: usize, max_len: usize) -> Result<Vec<String>> {
+    if !u.arbitrary()? {
+        return Ok(Vec::new());
+    }
+    let mut names = (0..u.int_in_range(0..=count)?)
+        .map(|_| if u.arbitrary()? { ascii_string(u, max_len) } else { Ok(String::new()) })
+        .collect::<Result<Vec<_>>>()?;
+    while names.last().is_some_and(String::is_empty) {
+        names.pop();
+    }
+    Ok(names)
+}
+
+/// Generates an optional instrument id below `count`.
+fn instrument_id(u: &mut Unstructured, count: usize) -> Result<Option<InstrumentId>> {
+    if count == 0 || !u.arbitrary()? {
+        return Ok(None);
+    }
+    let idx = u.int_in_range(0..=count.min(99) - 1)?;
+    Ok(Some(InstrumentId::try_from(u8::try_from(idx).unwrap()).unwrap()))
+}
+
+/// Generates a non-empty command referencing only instruments below `instruments`.
+fn command(u: &mut Unstructured, instruments: usize) -> Result<Command> {
+    let mut command = Command {
+        note: u.arbitrary()?,
+        instrument: instrument_id(u, instruments)?,
+        volume: u.arbitrary()?,
+        effect: u.arbitrary()?,
+    };
+    if command.is_empty() {
+        command.note = Some(NoteCmd::Off);
+    }
+    Ok(command)
+}
+
+/// Generates a pattern of 1 to 200 rows referencing only instruments below `instruments`.
+fn pattern(u: &mut Unstructured, instruments: usize) -> Result<Pattern> {
+    let rows = (0..u.int_in_range(1..=200)?)
+        .map(|_| {
+            let mut channels = (0..u.int_in_range(0..=8)?)
+                .map(|_| u.int_in_range(0..=63).map(Channel::from_u8_index))
+                .collect::<Result<Vec<_>>>()?;
+            channels.sort_unstable();
+            channels.dedup();
+            let commands = channels.into_iter()
+                .map(|channel| Ok((channel, command(u, instruments)?)))
+                .collect::<Result<Vec<_>>>()?;
+            Ok(Row::from_vec(commands))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Pattern {
+        active_channels: rows.iter()
+            .flat_map(|row| row.iter().map(|(channel, _)| channel))
+            .collect(),
+        rows,
+        truncated: false,
+        highlight: None,
+    })
+}
+
+/// Generates an optional loop lying within `len` samples.
+fn sample_loop(u: &mut Unstructured, len: usize) -> Result<Option<SampleLoop>> {
+    let len = u32::try_from(len).map_err(|_| Error::IncorrectFormat)?;
+    if len < 2 || !u.arbitrary()? {
+        return Ok(None);
+    }
+    let start = u.int_in_range(0..=len - 2)?;
+    let end = u.int_in_range(start + 1..=len)?;
+    Ok(Some(SampleLoop { start, end, bidi: u.arbitrary()? }))
+}
+
+/// Generates a valid envelope of the given kind.
+fn envelope(u: &mut Unstructured, kind: EnvelopeKind) -> Result<Envelope> {
+    let range = kind.value_range();
+    let mut tick = 0u16;
+    let nodes = (0..u.int_in_range(2..=Envelope::MAX_NODES)?)
+        .map(|idx| {
+            if idx > 0 {
+                tick += u.int_in_range(1..=64)?;
+            }
+            Ok(Node { value: u.int_in_range(range.clone())?, tick })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let flags = EnvelopeFlags::from_bits_truncate(u.arbitrary()?);
+    let len = u8::try_from(nodes.len()).unwrap();
+    // The loop points are always stored, the flags tell if the loops are used.
+    let mut envelope_loop = || -> Result<Option<EnvelopeLoop>> {
+        let start = u.int_in_range(0..=len - 1)?;
+        let end = u.int_in_range(start..=len - 1)?;
+        Ok(Some(EnvelopeLoop { start, end }))
+    };
+
+    let envelope = Envelope {
+        flags,
+        envelope_loop: envelope_loop()?,
+        sustain_loop: envelope_loop()?,
+        nodes,
+    };
+    debug_assert!(envelope.validate(kind).is_ok());
+    Ok(envelope)
+}
+
+/// Generates an instrument mapping only to samples below `samples`.
+fn instrument(u: &mut Unstructured, samples: usize) -> Result<Instrument> {
+    let mut sample_map = SampleMap::default();
+    if samples > 0 {
+        for entry in sample_map.map.iter_mut() {
+            if u.arbitrary()? {
+                let idx = u.int_in_range(0..=samples.min(99) - 1)?;
+                *entry = Some(SampleId::try_from(u8::try_from(idx).unwrap()).unwrap());
+            }
+        }
+    }
+    let mut mapped = sample_map.map.iter().flatten().collect::<Vec<_>>();
+    mapped.sort_unstable();
+    mapped.dedup();
+
+    Ok(Instrument {
+        name: u.arbitrary()?,
+        filename: u.arbitrary()?,
+        flags: InstrumentFlags::from_bits_truncate(u.arbitrary()?),
+        new_note_action: u.int_in_range(0..=3)?,
+        duplicate_check_type: u.int_in_range(0..=3)?,
+        duplicate_check_action: u.int_in_range(0..=2)?,
+        instrument_fadeout: u.arbitrary()?,
+        pitch_pan_separation: u.int_in_range(-32..=32)?,
+        pitch_pan_centre: u.int_in_range(0..=119)?,
+        global_volume: u.int_in_range(0..=128)?,
+        default_panning: RangedU8::try_from(u.int_in_range(0..=64)?).unwrap(),
+        random_volume_variation: u.arbitrary()?,
+        random_panning_variation: u.arbitrary()?,
+        trkver: u.arbitrary()?,
+        number_of_samples: u8::try_from(mapped.len()).unwrap(),
+        initial_filter_cutoff: RangedU8::try_from(u.int_in_range(0..=127)?).unwrap(),
+        initial_filter_resonance: RangedU8::try_from(u.int_in_range(0..=127)?).unwrap(),
+        mch: u.int_in_range(0..=17)?,
+        mpr: u.arbitrary()?,
+        mbank: [u.arbitrary()?, u.arbitrary()?],
+        sample_map,
+        volume_envelope: envelope(u, EnvelopeKind::Volume)?,
+        panning_envelope: envelope(u, EnvelopeKind::Panning)?,
+        pitch_filter_envelope: envelope(u, EnvelopeKind::PitchFilter)?,
+        openmpt_extensions: None,
+        tuning: None,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn round_trip() {
+        for seed in 1..=8u32 {
+            // Xorshift noise stands in for the fuzzer input.
+            let mut state = seed;
+            let bytes = (0..200_000)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    state.to_le_bytes()[1]
+                })
+                .collect::<Vec<_>>();
+            let module = Unstructured::new(&bytes).arbitrary::<Module>().unwrap();
+
+            let mut file = Vec::new();
+            module.write_to(&mut file).unwrap();
+            let parsed = parser::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", module), "seed {}", seed);
+        }
+    }
+}
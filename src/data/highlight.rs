@@ -0,0 +1,155 @@
+use super::*;
+
+
+/// Position of a row in the beats and measures of the row highlight, see [`Module::row_to_beat`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BeatPosition {
+    /// Measure in the pattern, counted from `0`
+    pub measure: usize,
+
+    /// Beat in the measure, counted from `0`
+    pub beat: usize,
+
+    /// Row in the beat, counted from `0`
+    pub row: usize,
+}
+
+impl BeatPosition {
+    /// Returns `true` for the first row of a beat.
+    pub fn is_beat_start(&self) -> bool {
+        self.row == 0
+    }
+
+    /// Returns `true` for the first row of a measure.
+    pub fn is_measure_start(&self) -> bool {
+        self.beat == 0 && self.row == 0
+    }
+}
+
+/// Iterator over the rows of a pattern which start a beat, see [`Module::beat_grid`]
+#[derive(Clone, Debug)]
+pub struct BeatGrid {
+    highlight: Option<Highlight>,
+    row: usize,
+    rows: usize,
+}
+
+impl Iterator for BeatGrid {
+    type Item = (usize, BeatPosition);
+
+    fn next(&mut self) -> Option<(usize, BeatPosition)> {
+        let highlight = self.highlight?;
+        if self.row >= self.rows {
+            return None;
+        }
+        let row = self.row;
+        let position = highlight.position(row);
+        self.row += highlight.beat.min(highlight.measure - (row % highlight.measure));
+        Some((row, position))
+    }
+}
+
+/// Rows per beat and per measure of a pattern, both non-zero
+#[derive(Clone, Copy, Debug)]
+struct Highlight {
+    beat: usize,
+    measure: usize,
+}
+
+impl Highlight {
+    fn position(self, row: usize) -> BeatPosition {
+        let in_measure = row % self.measure;
+        BeatPosition {
+            measure: row / self.measure,
+            beat: in_measure / self.beat,
+            row: in_measure % self.beat,
+        }
+    }
+}
+
+
+impl Module {
+    /// Returns the position of the row of the pattern in the beats and measures.
+    ///
+    /// The position follows the row highlight of the pattern ([`Pattern::highlight`]) or of the
+    /// module ([`Module::highlight`]), measures start again at the first row of every pattern. A
+    /// measure which is not a multiple of the beat ends with a shorter beat. Returns `None` if the
+    /// highlight has no rows per beat or the row is not in the pattern, patterns missing from the
+    /// module have 64 rows.
+    pub fn row_to_beat(&self, pattern: PatternId, row: usize) -> Option<BeatPosition> {
+        let highlight = self.highlight_of(pattern)?;
+        (row < self.pattern_rows(pattern)).then(|| highlight.position(row))
+    }
+
+    /// Returns `true` if the row of the pattern starts a beat, see [`Module::row_to_beat`].
+    pub fn is_beat_start(&self, pattern: PatternId, row: usize) -> bool {
+        self.row_to_beat(pattern, row).is_some_and(|position| position.is_beat_start())
+    }
+
+    /// Returns `true` if the row of the pattern starts a measure, see [`Module::row_to_beat`].
+    pub fn is_measure_start(&self, pattern: PatternId, row: usize) -> bool {
+        self.row_to_beat(pattern, row).is_some_and(|position| position.is_measure_start())
+    }
+
+    /// Returns the rows of the pattern which start a beat with their positions.
+    ///
+    /// Empty if the highlight has no rows per beat, see [`Module::row_to_beat`].
+    pub fn beat_grid(&self, pattern: PatternId) -> BeatGrid {
+        BeatGrid {
+            highlight: self.highlight_of(pattern),
+            row: 0,
+            rows: self.pattern_rows(pattern),
+        }
+    }
+
+    /// Returns the rows per beat and measure, a highlight without measures has a single measure
+    /// covering the whole pattern.
+    fn highlight_of(&self, pattern: PatternId) -> Option<Highlight> {
+        let (measure, beat) = self.get(pattern).and_then(|pattern| pattern.highlight).unwrap_or(self.highlight);
+        let beat = usize::from(beat);
+        let measure = match usize::from(measure) {
+            0 => self.pattern_rows(pattern).max(1),
+            measure => measure,
+        };
+        (beat != 0).then_some(Highlight { beat, measure })
+    }
+
+    fn pattern_rows(&self, pattern: PatternId) -> usize {
+        self.get(pattern).map_or(EMPTY_PATTERN_ROWS, |pattern| pattern.rows.len())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn beats() {
+        let mut builder = ModuleBuilder::new();
+        let pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 40], truncated: false, highlight: None };
+        let id = builder.add_pattern(pattern).unwrap();
+        let mut module = builder.build().unwrap();
+
+        assert_eq!(module.row_to_beat(id, 21), Some(BeatPosition { measure: 1, beat: 1, row: 1 }));
+        assert!(module.is_measure_start(id, 32));
+        assert!(module.is_beat_start(id, 4) && !module.is_measure_start(id, 4));
+        assert_eq!(module.row_to_beat(id, 40), None);
+        let grid = module.beat_grid(id).map(|(row, position)| (row, position.is_measure_start())).collect::<Vec<_>>();
+        assert_eq!(grid.len(), 10);
+        assert_eq!(grid[4], (16, true));
+
+        // Six rows per measure end every measure with a beat of two rows.
+        module.highlight = (6, 4);
+        let grid = module.beat_grid(id).take(4).map(|(row, _)| row).collect::<Vec<_>>();
+        assert_eq!(grid, [0, 4, 6, 10]);
+
+        module.patterns[0].highlight = Some((12, 3));
+        assert_eq!(module.row_to_beat(id, 13), Some(BeatPosition { measure: 1, beat: 0, row: 1 }));
+        module.patterns[0].highlight = None;
+
+        module.highlight = (16, 0);
+        assert_eq!(module.row_to_beat(id, 0), None);
+        assert_eq!(module.beat_grid(id).count(), 0);
+    }
+}
@@ -0,0 +1,78 @@
+use super::*;
+
+
+/// One session of editing the module
+///
+/// Impulse Tracker 2.04+ records when the module was loaded and how long it was open each time it
+/// is saved. The history is stored after the offset tables of the module header if the second
+/// bit of the special flags is set, as a `u16` count followed by 8 bytes for every entry. Other
+/// trackers (Schism Tracker, OpenMPT) write it too.
+///
+/// The date and time are in the MS-DOS format, in local time of the machine which saved the file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EditHistoryEntry {
+    /// MS-DOS date the module was loaded, bits 0-4 are the day, 5-8 the month and 9-15 the years
+    /// since 1980
+    pub fat_date: u16,
+
+    /// MS-DOS time the module was loaded, bits 0-4 are the seconds divided by 2, 5-10 the minutes
+    /// and 11-15 the hours
+    pub fat_time: u16,
+
+    /// Time the module was open in DOS timer ticks, see [`EditHistoryEntry::TICKS_PER_SECOND`]
+    pub run_time: u32,
+}
+
+impl EditHistoryEntry {
+    /// Frequency of the DOS timer counting the run time (1193182 / 65536 Hz)
+    pub const TICKS_PER_SECOND: f64 = 1_193_182.0 / 65_536.0;
+
+    /// Returns the date the module was loaded as year, month and day.
+    pub fn date(&self) -> (u16, u8, u8) {
+        let field = |shift: u16, mask: u16| u8::try_from((self.fat_date >> shift) & mask).unwrap();
+        (1980 + (self.fat_date >> 9), field(5, 0x0F), field(0, 0x1F))
+    }
+
+    /// Returns the time the module was loaded as hours, minutes and seconds.
+    pub fn time(&self) -> (u8, u8, u8) {
+        let field = |shift: u16, mask: u16| u8::try_from((self.fat_time >> shift) & mask).unwrap();
+        (field(11, 0x1F), field(5, 0x3F), 2 * field(0, 0x1F))
+    }
+
+    /// Returns the time the module was open in seconds.
+    pub fn run_time_seconds(&self) -> f64 {
+        f64::from(self.run_time) / EditHistoryEntry::TICKS_PER_SECOND
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn edit_history() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        assert_eq!(module.edit_history.len(), 9);
+        let first = module.edit_history[0];
+        assert_eq!((first.date(), first.time()), ((2021, 2, 5), (16, 2, 48)));
+        assert_eq!(first.run_time_seconds().round(), 1407.0);
+
+        let mut file = Vec::new();
+        module.write_to(&mut file).unwrap();
+        let parsed = parser::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(parsed.edit_history, module.edit_history);
+
+        // The flag stays set without the entries.
+        module.edit_history.clear();
+        let mut file = Vec::new();
+        module.write_to(&mut file).unwrap();
+        let parsed = parser::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert!(parsed.edit_history.is_empty());
+        assert_eq!(parsed.raw_flags(), module.raw_flags());
+    }
+}
@@ -1,15 +1,17 @@
 use super::*;
-use std::convert::TryFrom;
-use std::fmt::{self, Debug};
-use std::ops::Index;
+use core::convert::TryFrom;
+use core::fmt::{self, Debug};
+use core::ops::Index;
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct InstrumentFile {
     pub instrument: Instrument,
     pub samples: Vec<Sample>,
 }
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Instrument {
     /// Instrument Name, null-terminated (but may also contain nulls)
     pub name: Name,
@@ -85,6 +87,18 @@ pub struct Instrument {
 
     /// Pitch / Filter Envelope
     pub pitch_filter_envelope: Envelope,
+
+    /// Extended instrument properties
+    ///
+    /// *OpenMPT extension.* `None` if the file has no extended instrument properties.
+    pub openmpt_extensions: Option<InstrumentExtensions>,
+
+    /// Custom tuning of the notes, `None` plays the notes in the tuning of the slide mode
+    ///
+    /// *MPTM extension.* The tunings are stored only in MPTM files, which this crate doesn't read,
+    /// so the tuning is `None` in parsed instruments and it is not written to IT files.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub tuning: Option<Tuning>,
 }
 
 bitflags! {
@@ -97,10 +111,71 @@ bitflags! {
 
         const ENABLE_FILTER_CUTOFF = 1 << 1;
 
-        const ENABLE_FILTER_RESONANCE = 1 << 1;
+        const ENABLE_FILTER_RESONANCE = 1 << 2;
     }
 }
 
+/// Instrument class guessed by [`Instrument::classify`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum InstrumentClass {
+    /// Pitched instrument played across the keyboard
+    Melodic,
+
+    /// Drums and other one-shot sounds
+    Percussive,
+
+    /// Not enough evidence either way
+    Unknown,
+}
+
+/// What happens to the playing note when a new note is played on its channel, see
+/// [`Instrument::note_action`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum NewNoteAction {
+    /// The note stops
+    Cut,
+
+    /// The note keeps playing in the background
+    Continue,
+
+    /// The note is released and keeps playing in the background
+    Off,
+
+    /// The note fades out in the background
+    Fade,
+}
+
+/// Which of the notes playing on the channel a new note stops, see
+/// [`Instrument::duplicate_check`]
+///
+/// Only notes of the same instrument are checked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DuplicateCheckType {
+    /// No notes are checked
+    Off,
+
+    /// Notes of the same note
+    Note,
+
+    /// Notes playing the same sample
+    Sample,
+
+    /// All notes of the instrument
+    Instrument,
+}
+
+/// What happens to the notes found by the duplicate check, see [`Instrument::duplicate_action`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DuplicateCheckAction {
+    Cut,
+    Off,
+    Fade,
+}
+
 #[derive(Clone, Copy)]
 pub struct SampleMap {
     pub(crate) map: [Option<SampleId>; 120],
@@ -114,6 +189,179 @@ impl Instrument {
     pub(crate) const ifr_enableResonance: u8 = 0x80;
 }
 
+impl Instrument {
+    /// Returns the new note action, values out of range are [`NewNoteAction::Cut`].
+    pub fn note_action(&self) -> NewNoteAction {
+        match self.new_note_action {
+            1 => NewNoteAction::Continue,
+            2 => NewNoteAction::Off,
+            3 => NewNoteAction::Fade,
+            _ => NewNoteAction::Cut,
+        }
+    }
+
+    /// Returns the duplicate check type, values out of range are [`DuplicateCheckType::Off`].
+    pub fn duplicate_check(&self) -> DuplicateCheckType {
+        match self.duplicate_check_type {
+            1 => DuplicateCheckType::Note,
+            2 => DuplicateCheckType::Sample,
+            3 => DuplicateCheckType::Instrument,
+            _ => DuplicateCheckType::Off,
+        }
+    }
+
+    /// Returns the duplicate check action, values out of range are [`DuplicateCheckAction::Cut`].
+    pub fn duplicate_action(&self) -> DuplicateCheckAction {
+        match self.duplicate_check_action {
+            1 => DuplicateCheckAction::Off,
+            2 => DuplicateCheckAction::Fade,
+            _ => DuplicateCheckAction::Cut,
+        }
+    }
+
+    /// Returns the envelope of the given kind.
+    pub fn envelope(&self, kind: EnvelopeKind) -> &Envelope {
+        match kind {
+            EnvelopeKind::Volume => &self.volume_envelope,
+            EnvelopeKind::Panning => &self.panning_envelope,
+            EnvelopeKind::PitchFilter => &self.pitch_filter_envelope,
+        }
+    }
+
+    /// Returns a guard for editing the envelope of the given kind.
+    ///
+    /// The edited envelope is validated when the guard is committed, see [`EnvelopeMut`].
+    pub fn envelope_mut(&mut self, kind: EnvelopeKind) -> EnvelopeMut<'_> {
+        let envelope = match kind {
+            EnvelopeKind::Volume => &mut self.volume_envelope,
+            EnvelopeKind::Panning => &mut self.panning_envelope,
+            EnvelopeKind::PitchFilter => &mut self.pitch_filter_envelope,
+        };
+        EnvelopeMut::new(kind, envelope)
+    }
+
+    /// Returns a guard for editing the volume envelope, see [`Instrument::envelope_mut`].
+    pub fn volume_envelope_mut(&mut self) -> EnvelopeMut<'_> {
+        self.envelope_mut(EnvelopeKind::Volume)
+    }
+
+    /// Returns a guard for editing the panning envelope, see [`Instrument::envelope_mut`].
+    pub fn panning_envelope_mut(&mut self) -> EnvelopeMut<'_> {
+        self.envelope_mut(EnvelopeKind::Panning)
+    }
+
+    /// Returns a guard for editing the pitch/filter envelope, see [`Instrument::envelope_mut`].
+    pub fn pitch_filter_envelope_mut(&mut self) -> EnvelopeMut<'_> {
+        self.envelope_mut(EnvelopeKind::PitchFilter)
+    }
+
+    /// Guesses whether the instrument is melodic or percussive.
+    ///
+    /// **This is only a heuristic** and can easily be wrong, it is meant for things like routing
+    /// drums to the MIDI percussion channel in converters. The evidence taken into account is:
+    ///
+    /// - samples the instrument maps to: looping samples suggest a melodic instrument, one-shot
+    ///   samples shorter than half a second (at C-5) suggest a percussive one,
+    /// - keyboard mapping: several different samples each mapped to only one or two notes looks
+    ///   like a drum kit, one sample spread over at least two octaves looks melodic,
+    /// - volume envelope: an enabled envelope with a loop or sustain loop suggests a melodic
+    ///   instrument, one that decays to silence without any loop suggests a percussive one.
+    ///
+    /// Every piece of evidence adds points to one of the classes. If the difference between the
+    /// scores is less than 2 points, or the instrument doesn't map to any samples,
+    /// [`InstrumentClass::Unknown`] is returned.
+    pub fn classify(&self, module: &Module) -> InstrumentClass {
+        let mapped = self.sample_map.map.iter().flatten().copied().collect::<Vec<_>>();
+        let mut distinct = mapped.clone();
+        distinct.sort_unstable();
+        distinct.dedup();
+
+        if distinct.is_empty() {
+            return InstrumentClass::Unknown;
+        }
+
+        let mut melodic = 0;
+        let mut percussive = 0;
+
+        // Sample shape.
+        let (mut looped, mut one_shot) = (0, 0);
+        for sample in distinct.iter().filter_map(|&id| module.get(id)) {
+            if sample.loop_.is_some() || sample.sustain_loop.is_some() {
+                looped += 1;
+            } else if sample.data.is_some() {
+                #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+                let duration = sample.length() as f32 / sample.samplerate_c5 as f32;
+                if sample.samplerate_c5 > 0 && duration < 0.5 {
+                    one_shot += 1;
+                }
+            }
+        }
+        if looped * 2 > distinct.len() {
+            melodic += 2;
+        }
+        if one_shot * 2 > distinct.len() {
+            percussive += 2;
+        }
+
+        // Keyboard mapping.
+        if distinct.len() >= 3 && mapped.len() <= distinct.len() * 2 {
+            percussive += 2;
+        } else if distinct.len() == 1 && mapped.len() >= 24 {
+            melodic += 1;
+        }
+
+        // Volume envelope shape.
+        let envelope = &self.volume_envelope;
+        if envelope.flags.contains(EnvelopeFlags::ENABLED) {
+            let looping = (envelope.flags.contains(EnvelopeFlags::LOOP) && envelope.envelope_loop.is_some())
+                || (envelope.flags.contains(EnvelopeFlags::SUSTAIN) && envelope.sustain_loop.is_some());
+            if looping {
+                melodic += 1;
+            } else if matches!(envelope.nodes.last(), Some(node) if node.value == 0) {
+                percussive += 1;
+            }
+        }
+
+        match melodic - percussive {
+            diff if diff >= 2 => InstrumentClass::Melodic,
+            diff if diff <= -2 => InstrumentClass::Percussive,
+            _ => InstrumentClass::Unknown,
+        }
+    }
+}
+
+impl From<NewNoteAction> for u8 {
+    fn from(action: NewNoteAction) -> u8 {
+        match action {
+            NewNoteAction::Cut => 0,
+            NewNoteAction::Continue => 1,
+            NewNoteAction::Off => 2,
+            NewNoteAction::Fade => 3,
+        }
+    }
+}
+
+impl From<DuplicateCheckType> for u8 {
+    fn from(check: DuplicateCheckType) -> u8 {
+        match check {
+            DuplicateCheckType::Off => 0,
+            DuplicateCheckType::Note => 1,
+            DuplicateCheckType::Sample => 2,
+            DuplicateCheckType::Instrument => 3,
+        }
+    }
+}
+
+impl From<DuplicateCheckAction> for u8 {
+    fn from(action: DuplicateCheckAction) -> u8 {
+        match action {
+            DuplicateCheckAction::Cut => 0,
+            DuplicateCheckAction::Off => 1,
+            DuplicateCheckAction::Fade => 2,
+        }
+    }
+}
+
 impl Default for SampleMap {
     fn default() -> SampleMap {
         SampleMap {
@@ -147,3 +395,66 @@ impl Index<&Note> for SampleMap {
         self.index(*index)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::formats::{empty_instrument, empty_sample};
+
+    /// Classifies an instrument mapping the notes to the samples, with a volume envelope of two
+    /// nodes ending at the value if given.
+    fn classify(samples: Vec<Sample>, map: &[(usize, u8)], envelope: Option<(EnvelopeFlags, i8)>) -> InstrumentClass {
+        let mut module = ModuleBuilder::new().build().unwrap();
+        module.samples = samples;
+        let mut instrument = empty_instrument();
+        for &(note, sample) in map {
+            instrument.sample_map.map[note] = Some(SampleId::try_from(sample).unwrap());
+        }
+        if let Some((flags, last)) = envelope {
+            let envelope_loop = Some(EnvelopeLoop { start: 0, end: 1 });
+            instrument.volume_envelope = Envelope {
+                flags,
+                envelope_loop,
+                sustain_loop: envelope_loop,
+                nodes: vec![Node { value: 64, tick: 0 }, Node { value: last, tick: 10 }],
+            };
+        }
+        instrument.classify(&module)
+    }
+
+    #[test]
+    fn classify_instruments() {
+        let looped = Sample { loop_: Some(SampleLoop { start: 0, end: 100, bidi: false }), ..empty_sample() };
+        let one_shot = Sample { data: Some(vec![0.0; 1000].into()), ..empty_sample() };
+        let long = Sample { data: Some(vec![0.0; 10000].into()), ..empty_sample() };
+        let keyboard = (0..120).map(|note| (note, 0)).collect::<Vec<_>>();
+        let kit = [(36, 0), (38, 1), (42, 2)];
+
+        // No samples mapped.
+        assert_eq!(classify(vec![looped.clone()], &[], None), InstrumentClass::Unknown);
+
+        // Looping sample over the whole keyboard.
+        assert_eq!(classify(vec![looped.clone()], &keyboard, None), InstrumentClass::Melodic);
+        // Short one-shot samples on single notes.
+        assert_eq!(classify(vec![one_shot.clone(); 3], &kit, None), InstrumentClass::Percussive);
+        // Long one-shot samples on single notes, only the mapping looks like a drum kit.
+        assert_eq!(classify(vec![long.clone(); 3], &kit, None), InstrumentClass::Percussive);
+        // A single long one-shot sample over the keyboard is not enough evidence.
+        assert_eq!(classify(vec![long.clone()], &keyboard, None), InstrumentClass::Unknown);
+
+        // A looping envelope tips the keyboard mapping to melodic, loops of a disabled envelope
+        // are ignored.
+        let (looping, sustain) = (EnvelopeFlags::ENABLED | EnvelopeFlags::LOOP, EnvelopeFlags::ENABLED | EnvelopeFlags::SUSTAIN);
+        assert_eq!(classify(vec![long.clone()], &keyboard, Some((looping, 32))), InstrumentClass::Melodic);
+        assert_eq!(classify(vec![long.clone()], &keyboard, Some((sustain, 32))), InstrumentClass::Melodic);
+        assert_eq!(classify(vec![long.clone()], &keyboard, Some((EnvelopeFlags::LOOP, 32))), InstrumentClass::Unknown);
+
+        // A short one-shot sample is enough for percussive, a decaying envelope alone is not and
+        // it only weakens a looping sample.
+        assert_eq!(classify(vec![one_shot], &[(60, 0)], None), InstrumentClass::Percussive);
+        assert_eq!(classify(vec![long], &[(60, 0)], Some((EnvelopeFlags::ENABLED, 0))), InstrumentClass::Unknown);
+        assert_eq!(classify(vec![looped.clone()], &[(60, 0)], None), InstrumentClass::Melodic);
+        assert_eq!(classify(vec![looped], &[(60, 0)], Some((EnvelopeFlags::ENABLED, 0))), InstrumentClass::Unknown);
+    }
+}
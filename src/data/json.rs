@@ -0,0 +1,255 @@
+//! JSON description of modules for tools outside of Rust
+
+use super::*;
+use alloc::string::ToString;
+use core::fmt::{self, Write};
+
+
+/// Options of [`Module::to_json_with`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JsonOptions {
+    /// Include the sample data, it is omitted by default
+    pub sample_data: bool,
+}
+
+impl Module {
+    /// Describes the module as JSON without the sample data.
+    ///
+    /// The schema is versioned by the `schema` field, fields are only added within a version.
+    /// Numbers are the values stored in the file unless noted otherwise, names are decoded as by
+    /// [`Name`]'s [`Display`](fmt::Display).
+    ///
+    /// ```txt
+    /// {
+    ///   "schema": 1,
+    ///   "name": "...", "message": "...", "tracker": "OpenMPT 1.29.00.00",
+    ///   "speed": 6, "tempo": 125, "global_volume": 128, "mixing_volume": 48,
+    ///   "stereo": true, "instrument_mode": false, "linear_slides": true,
+    ///   "orders": [0, 1, 254, 255],
+    ///   "instruments": [
+    ///     {"name": "...", "filename": "...", "global_volume": 128, "fadeout": 0,
+    ///      "new_note_action": 0, "sample_map": [1, null, ...]}
+    ///   ],
+    ///   "samples": [
+    ///     {"name": "...", "filename": "...", "length": 1000, "channels": 1, "c5_speed": 8363,
+    ///      "global_volume": 64, "default_volume": 64, "default_panning": null,
+    ///      "loop": {"start": 0, "end": 1000, "pingpong": false}, "sustain_loop": null,
+    ///      "data": "AAAAAA..."}
+    ///   ],
+    ///   "patterns": [
+    ///     {"name": null, "rows": 64, "cells": [
+    ///       {"row": 0, "channel": 1, "note": 60, "instrument": 1, "volume": 64, "effect": "A", "param": 6}
+    ///     ]}
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// - `orders` are pattern numbers, `254` marks a separator and `255` the end of the song.
+    /// - `sample_map` has an entry for each of the 120 notes, the sample number starting from `1` or
+    ///   `null` if the note plays nothing.
+    /// - `length` is in frames, `channels` is `1` for mono and `2` for stereo samples.
+    /// - `default_panning` is `0..=64` or `null` if the sample doesn't use its panning.
+    /// - `data` is only present if [`JsonOptions::sample_data`] is set and the sample has data. It is
+    ///   the normalized data as little-endian 32-bit floats encoded as standard base64, the
+    ///   channels of stereo samples are interleaved.
+    /// - `cells` lists only the non-empty cells, channels start from `1`. A cell has only the columns
+    ///   which are set: `note` is `0..=119`, `255` note off, `254` note cut and `253` note fade,
+    ///   `instrument` starts from `1`, `volume` is the raw volume column byte and `effect` is the
+    ///   effect letter with its `param`.
+    pub fn to_json(&self) -> String {
+        self.to_json_with(JsonOptions::default())
+    }
+
+    /// Describes the module as JSON using the options.
+    pub fn to_json_with(&self, options: JsonOptions) -> String {
+        let mut out = String::new();
+        write_module(&mut out, self, options).unwrap();
+        out
+    }
+}
+
+
+fn write_module(out: &mut String, module: &Module, options: JsonOptions) -> fmt::Result {
+    write!(out, "{{\"schema\":1,\"name\":")?;
+    string(out, &module.name.to_string())?;
+    write!(out, ",\"message\":")?;
+    string(out, &module.message)?;
+    write!(out, ",\"tracker\":")?;
+    string(out, &module.detected_tracker().to_string())?;
+    write!(
+        out,
+        ",\"speed\":{},\"tempo\":{},\"global_volume\":{},\"mixing_volume\":{},\"stereo\":{},\"instrument_mode\":{},\"linear_slides\":{}",
+        module.speed.as_u8(),
+        module.tempo.as_u8(),
+        module.global_volume.as_u8(),
+        module.sample_volume.as_u8(),
+        module.flags.contains(ModuleFlags::STEREO),
+        module.flags.contains(ModuleFlags::USE_INSTRUMENTS),
+        module.flags.contains(ModuleFlags::LINEAR_SLIDES),
+    )?;
+
+    write!(out, ",\"orders\":")?;
+    list(out, &module.orders, |out, order| match order {
+        Order::Index(pattern) => write!(out, "{}", pattern.as_u8()),
+        Order::Separator => write!(out, "254"),
+        Order::EndOfSong => write!(out, "255"),
+    })?;
+
+    write!(out, ",\"instruments\":")?;
+    list(out, &module.instruments, |out, instrument| {
+        write!(out, "{{\"name\":")?;
+        string(out, &instrument.name.to_string())?;
+        write!(out, ",\"filename\":")?;
+        string(out, &instrument.filename.to_string())?;
+        write!(
+            out,
+            ",\"global_volume\":{},\"fadeout\":{},\"new_note_action\":{},\"sample_map\":",
+            instrument.global_volume, instrument.instrument_fadeout, instrument.new_note_action,
+        )?;
+        list(out, &instrument.sample_map.map, |out, sample| match sample {
+            Some(sample) => write!(out, "{}", sample.as_u8() + 1),
+            None => write!(out, "null"),
+        })?;
+        write!(out, "}}")
+    })?;
+
+    write!(out, ",\"samples\":")?;
+    list(out, &module.samples, |out, sample| {
+        write!(out, "{{\"name\":")?;
+        string(out, &sample.name.to_string())?;
+        write!(out, ",\"filename\":")?;
+        string(out, &sample.filename.to_string())?;
+        write!(
+            out,
+            ",\"length\":{},\"channels\":{},\"c5_speed\":{},\"global_volume\":{},\"default_volume\":{},\"default_panning\":",
+            sample.length(), sample.channels.as_u8(), sample.samplerate_c5, sample.global_volume, sample.default_volume,
+        )?;
+        match sample.default_pan() {
+            Some(pan) => write!(out, "{}", pan.as_u8())?,
+            None => write!(out, "null")?,
+        }
+        for (name, sample_loop) in [("loop", sample.loop_), ("sustain_loop", sample.sustain_loop)] {
+            match sample_loop {
+                Some(l) => write!(out, ",\"{}\":{{\"start\":{},\"end\":{},\"pingpong\":{}}}", name, l.start, l.end, l.bidi)?,
+                None => write!(out, ",\"{}\":null", name)?,
+            }
+        }
+        if let Some(data) = sample.data.as_deref().filter(|_| options.sample_data) {
+            write!(out, ",\"data\":\"")?;
+            base64(out, data.iter().flat_map(|value| value.to_le_bytes()))?;
+            write!(out, "\"")?;
+        }
+        write!(out, "}}")
+    })?;
+
+    write!(out, ",\"patterns\":")?;
+    let patterns = module.patterns.iter().enumerate().collect::<Vec<_>>();
+    list(out, &patterns, |out, &(index, pattern)| {
+        write!(out, "{{\"name\":")?;
+        match module.pattern_names.as_slice().get(index).filter(|name| !name.is_empty()) {
+            Some(name) => string(out, name)?,
+            None => write!(out, "null")?,
+        }
+        write!(out, ",\"rows\":{},\"cells\":", pattern.rows.len())?;
+        let cells = pattern.rows.iter().enumerate()
+            .flat_map(|(row, commands)| commands.iter().map(move |(channel, command)| (row, channel, command)))
+            .collect::<Vec<_>>();
+        list(out, &cells, |out, &(row, channel, command)| {
+            write!(out, "{{\"row\":{},\"channel\":{}", row, channel.as_usize() + 1)?;
+            if let Some(note) = command.note {
+                write!(out, ",\"note\":{}", note.to_raw())?;
+            }
+            if let Some(instrument) = command.instrument {
+                write!(out, ",\"instrument\":{}", instrument.as_u8() + 1)?;
+            }
+            if let Some(volume) = command.volume {
+                write!(out, ",\"volume\":{}", u8::from(volume))?;
+            }
+            if let Some(effect) = command.effect {
+                let (effect, param) = effect.to_raw();
+                write!(out, ",\"effect\":\"{}\",\"param\":{}", char::from(b'A' + effect - 1), param)?;
+            }
+            write!(out, "}}")
+        })?;
+        write!(out, "}}")
+    })?;
+    write!(out, "}}")
+}
+
+/// Writes the items as a JSON array.
+fn list<T>(out: &mut String, items: &[T], mut item: impl FnMut(&mut String, &T) -> fmt::Result) -> fmt::Result {
+    out.push('[');
+    for (index, value) in items.iter().enumerate() {
+        if index > 0 {
+            out.push(',');
+        }
+        item(out, value)?;
+    }
+    out.push(']');
+    Ok(())
+}
+
+/// Writes the text as a JSON string.
+fn string(out: &mut String, text: &str) -> fmt::Result {
+    out.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c < ' ' => write!(out, "\\u{:04x}", u32::from(c))?,
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    Ok(())
+}
+
+/// Writes the bytes in standard base64 with padding.
+fn base64(out: &mut String, bytes: impl Iterator<Item = u8>) -> fmt::Result {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut bytes = bytes.peekable();
+    while bytes.peek().is_some() {
+        let chunk = [bytes.next(), bytes.next(), bytes.next()];
+        let [a, b, c] = chunk.map(Option::unwrap_or_default);
+        let group = u32::from(a) << 16 | u32::from(b) << 8 | u32::from(c);
+        let present = chunk.iter().filter(|byte| byte.is_some()).count();
+        for position in 0..4 {
+            if position <= present {
+                let index = (group >> (18 - 6 * position)) & 0x3F;
+                out.push(char::from(ALPHABET[usize::try_from(index).unwrap()]));
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn to_json() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.message = String::from("\"quoted\"\r\u{1}");
+
+        let json = module.to_json();
+        assert!(json.starts_with("{\"schema\":1,\"name\":\"\",\"message\":\"\\\"quoted\\\"\\r\\u0001\","));
+        assert!(json.contains("\"tempo\":125,"));
+        assert!(json.contains("\"orders\":[0,255],"));
+        assert!(json.contains("{\"row\":0,\"channel\":1,\"effect\":\"A\",\"param\":18}"));
+        assert!(!json.contains("\"data\""));
+
+        module.samples[0].data = Some(vec![0.0, 1.0].into());
+        let json = module.to_json_with(JsonOptions { sample_data: true });
+        assert!(json.contains("\"data\":\"AAAAAAAAgD8=\""));
+    }
+}
@@ -0,0 +1,283 @@
+use super::*;
+
+
+/// Volume column `g0x` portamento speeds
+pub(crate) const TONE_PORTAMENTO_SPEEDS: [u8; 10] = [0x00, 0x01, 0x04, 0x08, 0x10, 0x20, 0x40, 0x60, 0x80, 0xFF];
+
+
+/// Effect memory of a channel, see [`Module::flatten_resolved`]
+///
+/// Commands with the parameter `None` recall the last value of the command, or of the commands
+/// sharing the memory with it. [`EffectMemory::resolve`] remembers the parameters of the commands
+/// and fills in the recalled ones, which makes every command self-contained. Before a value is
+/// remembered the memory holds `0`, the commands recalling it keep the parameter `None`.
+///
+/// The memories follow Impulse Tracker:
+///
+/// - `Dxy`, `Kxy` and `Lxy` share the memory, `Nxy`, `Pxy` and `Wxy` have their own,
+/// - the volume column slides `a0x` to `d0x` share one memory separate from `Dxy`,
+/// - `Exx` and `Fxx` share the memory, `e0x` and `f0x` set it to the coarse slide four times
+///   their parameter,
+/// - `Gxx` and `g0x` share their memory, it's the memory of `Exx` and `Fxx` too if the module has
+///   [`ModuleFlags::LINK_G_E_EFFECTS`],
+/// - the speed and the depth of `Hxy` and `Uxy` have a memory each, `h0x` uses the same depth,
+///   `Rxy` and `Yxy` have memories of their own,
+/// - `Ixy`, `Jxy`, `Qxy` and `Sxy` have their own memory, `Txx` recalls only the last tempo slide.
+///
+/// The volume column can't store every value, commands there keep `None` if the recalled value
+/// has no volume column parameter.
+#[derive(Clone, Debug, Default)]
+pub struct EffectMemory {
+    link_g_e: bool,
+    volume_slide: Option<VolumeSlide>,
+    channel_volume_slide: Option<VolumeSlide>,
+    global_volume_slide: Option<VolumeSlide>,
+    panning_slide: Option<PanningSlide>,
+    volume_column_slide: u8,
+    /// Raw parameters of `Exx`/`Fxx` and `Gxx`, the same value if they are linked
+    portamento: u8,
+    tone_portamento: u8,
+    vibrato: (u8, u8),
+    tremolo: (u8, u8),
+    panbrello: (u8, u8),
+    tremor: Option<(RangedU8<1, 0x0F>, RangedU8<1, 0x0F>)>,
+    arpeggio: Option<(RangedU8<0, 0x0F>, RangedU8<0, 0x0F>)>,
+    retrigger: Option<(RangedU8<1, 0x0F>, RangedU8<1, 0x0F>)>,
+    special: Option<Special>,
+    tempo_slide: Option<Tempo>,
+}
+
+impl EffectMemory {
+    /// Creates empty memory for a channel of the module, the module decides whether `Gxx` shares
+    /// the memory with `Exx` and `Fxx`.
+    pub fn new(module: &Module) -> EffectMemory {
+        EffectMemory {
+            link_g_e: module.flags.contains(ModuleFlags::LINK_G_E_EFFECTS),
+            ..EffectMemory::default()
+        }
+    }
+
+    /// Remembers the parameters of the command and returns it with the recalled parameters
+    /// filled in.
+    ///
+    /// The commands are expected in the order they are played on the channel, see
+    /// [`Module::flatten`].
+    pub fn resolve(&mut self, command: &Command) -> Command {
+        Command {
+            volume: command.volume.map(|volume| self.volume(volume)),
+            effect: command.effect.map(|effect| self.effect(effect)),
+            ..*command
+        }
+    }
+
+    fn volume(&mut self, volume: VolumeCmd) -> VolumeCmd {
+        match volume {
+            VolumeCmd::FineVolumeUp(value) => VolumeCmd::FineVolumeUp(self.volume_column_slide(value)),
+            VolumeCmd::FineVolumeDown(value) => VolumeCmd::FineVolumeDown(self.volume_column_slide(value)),
+            VolumeCmd::VolumeSlideUp(value) => VolumeCmd::VolumeSlideUp(self.volume_column_slide(value)),
+            VolumeCmd::VolumeSlideDown(value) => VolumeCmd::VolumeSlideDown(self.volume_column_slide(value)),
+            VolumeCmd::PortamentoDown(value) => VolumeCmd::PortamentoDown(self.volume_column_portamento(value)),
+            VolumeCmd::PortamentoUp(value) => VolumeCmd::PortamentoUp(self.volume_column_portamento(value)),
+            VolumeCmd::TonePortamento(value) => {
+                if let Some(value) = value {
+                    self.set_tone_portamento(TONE_PORTAMENTO_SPEEDS[usize::from(value.as_u8())]);
+                }
+                let speed = self.tone_portamento;
+                VolumeCmd::TonePortamento(value.or_else(|| {
+                    let index = TONE_PORTAMENTO_SPEEDS.iter().position(|&known| known == speed)?;
+                    RangedU8::new(u8::try_from(index).unwrap())
+                }))
+            }
+            VolumeCmd::Vibrato(depth) => {
+                if let Some(depth) = depth {
+                    self.vibrato.1 = depth.as_u8();
+                }
+                VolumeCmd::Vibrato(depth.or_else(|| RangedU8::new(self.vibrato.1)))
+            }
+            VolumeCmd::SetVolume(_) | VolumeCmd::Panning(_) => volume,
+        }
+    }
+
+    fn effect(&mut self, effect: EffectCmd) -> EffectCmd {
+        match effect {
+            EffectCmd::VolumeSlide(slide) => EffectCmd::VolumeSlide(recall(&mut self.volume_slide, slide)),
+            EffectCmd::VolumeSlideAndVibrato(slide) => EffectCmd::VolumeSlideAndVibrato(recall(&mut self.volume_slide, slide)),
+            EffectCmd::VolumeSlideAndPortamento(slide) => EffectCmd::VolumeSlideAndPortamento(recall(&mut self.volume_slide, slide)),
+            EffectCmd::ChannelVolumeSlide(slide) => EffectCmd::ChannelVolumeSlide(recall(&mut self.channel_volume_slide, slide)),
+            EffectCmd::GlobalVolumeSlide(slide) => EffectCmd::GlobalVolumeSlide(recall(&mut self.global_volume_slide, slide)),
+            EffectCmd::PanningSlide(slide) => EffectCmd::PanningSlide(recall(&mut self.panning_slide, slide)),
+            EffectCmd::PortamentoDown(portamento) => EffectCmd::PortamentoDown(self.portamento(portamento)),
+            EffectCmd::PortamentoUp(portamento) => EffectCmd::PortamentoUp(self.portamento(portamento)),
+            EffectCmd::TonePortamento(speed) => {
+                if let Some(speed) = speed {
+                    self.set_tone_portamento(speed.as_u8());
+                }
+                EffectCmd::TonePortamento(speed.or_else(|| RangedU8::new(self.tone_portamento)))
+            }
+            EffectCmd::Vibrato(speed, depth) => {
+                let (speed, depth) = oscillator(&mut self.vibrato, speed, depth);
+                EffectCmd::Vibrato(speed, depth)
+            }
+            EffectCmd::FineVibrato(speed, depth) => {
+                let (speed, depth) = oscillator(&mut self.vibrato, speed, depth);
+                EffectCmd::FineVibrato(speed, depth)
+            }
+            EffectCmd::Tremolo(speed, depth) => {
+                let (speed, depth) = oscillator(&mut self.tremolo, speed, depth);
+                EffectCmd::Tremolo(speed, depth)
+            }
+            EffectCmd::Panbrello(speed, depth) => {
+                let (speed, depth) = oscillator(&mut self.panbrello, speed, depth);
+                EffectCmd::Panbrello(speed, depth)
+            }
+            EffectCmd::Tremor(tremor) => EffectCmd::Tremor(recall(&mut self.tremor, tremor)),
+            EffectCmd::Arpeggio(arpeggio) => EffectCmd::Arpeggio(recall(&mut self.arpeggio, arpeggio)),
+            EffectCmd::Retrigger(retrigger) => EffectCmd::Retrigger(recall(&mut self.retrigger, retrigger)),
+            EffectCmd::Special(special) => EffectCmd::Special(recall(&mut self.special, special)),
+            EffectCmd::Tempo(Some(Tempo::Set(tempo))) => EffectCmd::Tempo(Some(Tempo::Set(tempo))),
+            EffectCmd::Tempo(slide) => EffectCmd::Tempo(recall(&mut self.tempo_slide, slide)),
+            EffectCmd::SetSpeed(_)
+            | EffectCmd::JumpOrder(_)
+            | EffectCmd::BreakRow(_)
+            | EffectCmd::SetChannelVolume(_)
+            | EffectCmd::SetSampleOffset(_)
+            | EffectCmd::SetGlobalVolume(_)
+            | EffectCmd::SetPanningPosition(_)
+            | EffectCmd::Midi(_) => effect,
+        }
+    }
+
+    fn volume_column_slide(&mut self, value: Option<RangedU8<1, 9>>) -> Option<RangedU8<1, 9>> {
+        if let Some(value) = value {
+            self.volume_column_slide = value.as_u8();
+        }
+        value.or_else(|| RangedU8::new(self.volume_column_slide))
+    }
+
+    fn volume_column_portamento(&mut self, value: Option<RangedU8<1, 9>>) -> Option<RangedU8<1, 9>> {
+        if let Some(value) = value {
+            self.set_portamento(value.as_u8() * 4);
+        }
+        let coarse = self.portamento;
+        value.or_else(|| coarse.is_multiple_of(4).then(|| RangedU8::new(coarse / 4)).flatten())
+    }
+
+    fn portamento(&mut self, portamento: Option<Portamento>) -> Option<Portamento> {
+        if let Some(portamento) = portamento {
+            self.set_portamento(match portamento {
+                Portamento::Coarse(value) => value.as_u8(),
+                Portamento::Fine(value) => 0xF0 | value.as_u8(),
+                Portamento::ExtraFine(value) => 0xE0 | value.as_u8(),
+            });
+        }
+        portamento.or(match self.portamento {
+            0 => None,
+            raw @ 0xF0..=0xFF => Some(Portamento::Fine(RangedU8::new(raw & 0x0F).unwrap())),
+            raw @ 0xE0..=0xEF => Some(Portamento::ExtraFine(RangedU8::new(raw & 0x0F).unwrap())),
+            raw => RangedU8::new(raw).map(Portamento::Coarse),
+        })
+    }
+
+    fn set_portamento(&mut self, raw: u8) {
+        self.portamento = raw;
+        if self.link_g_e {
+            self.tone_portamento = raw;
+        }
+    }
+
+    fn set_tone_portamento(&mut self, raw: u8) {
+        self.tone_portamento = raw;
+        if self.link_g_e {
+            self.portamento = raw;
+        }
+    }
+}
+
+
+impl Module {
+    /// Returns the rows of the whole song in play order with the effect memory resolved.
+    ///
+    /// Same as [`Module::flatten`] except that the commands recalling the last value have the
+    /// recalled parameters filled in, see [`EffectMemory`]. Every command can then be interpreted
+    /// on its own, which is what analyzers and exporters to formats with different memory need.
+    pub fn flatten_resolved(&self) -> Vec<FlatRow> {
+        let mut memory = Vec::new();
+        let mut rows = self.flatten();
+        for row in &mut rows {
+            let channels = row.commands.iter().map(|(channel, _)| channel).collect::<Vec<_>>();
+            for channel in channels {
+                let index = channel.as_usize();
+                if memory.len() <= index {
+                    memory.resize_with(index + 1, || EffectMemory::new(self));
+                }
+                let command = row.commands.get_mut(channel).unwrap();
+                *command = memory[index].resolve(command);
+            }
+        }
+        rows
+    }
+}
+
+
+/// Replaces the memory with the value if it is set and returns the value in effect.
+fn recall<T: Copy>(memory: &mut Option<T>, value: Option<T>) -> Option<T> {
+    if value.is_some() {
+        *memory = value;
+    }
+    *memory
+}
+
+/// Remembers the speed and the depth of an oscillator separately and returns the values in
+/// effect.
+fn oscillator(
+    memory: &mut (u8, u8),
+    speed: Option<RangedU8<1, 0x0F>>,
+    depth: Option<RangedU8<1, 0x0F>>,
+) -> (Option<RangedU8<1, 0x0F>>, Option<RangedU8<1, 0x0F>>) {
+    if let Some(speed) = speed {
+        memory.0 = speed.as_u8();
+    }
+    if let Some(depth) = depth {
+        memory.1 = depth.as_u8();
+    }
+    (RangedU8::new(memory.0), RangedU8::new(memory.1))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flatten_resolved() {
+        let effect = |effect| Command { note: None, instrument: None, volume: None, effect: Some(effect) };
+        let coarse = |value| Some(Portamento::Coarse(RangedU8::new(value).unwrap()));
+        let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 4], truncated: false, highlight: None };
+        let slide = Some(VolumeSlide::Down(RangedU8::new(3).unwrap()));
+        pattern.set_command(0, Channel::new(1), effect(EffectCmd::VolumeSlide(slide)));
+        pattern.set_command(1, Channel::new(1), effect(EffectCmd::VolumeSlideAndVibrato(None)));
+        pattern.set_command(1, Channel::new(2), effect(EffectCmd::VolumeSlide(None)));
+        pattern.set_command(0, Channel::new(3), effect(EffectCmd::PortamentoDown(coarse(0x20))));
+        pattern.set_command(1, Channel::new(3), effect(EffectCmd::TonePortamento(None)));
+        pattern.set_command(2, Channel::new(3), Command { note: None, instrument: None, volume: Some(VolumeCmd::PortamentoUp(None)), effect: None });
+        pattern.set_command(3, Channel::new(3), effect(EffectCmd::Vibrato(RangedU8::new(4), None)));
+
+        let mut builder = ModuleBuilder::new();
+        let id = builder.add_pattern(pattern).unwrap();
+        let mut module = builder.build().unwrap();
+        module.orders = vec![Order::Index(id)];
+
+        let command = |rows: &[FlatRow], row: usize, channel| *rows[row].commands.get(Channel::new(channel)).unwrap();
+        let rows = module.flatten_resolved();
+        assert_eq!(command(&rows, 1, 1).effect, Some(EffectCmd::VolumeSlideAndVibrato(slide)));
+        assert_eq!(command(&rows, 1, 2).effect, Some(EffectCmd::VolumeSlide(None)));
+        assert_eq!(command(&rows, 1, 3).effect, Some(EffectCmd::TonePortamento(None)));
+        assert!(matches!(command(&rows, 2, 3).volume, Some(VolumeCmd::PortamentoUp(Some(value))) if value.as_u8() == 8));
+        assert_eq!(command(&rows, 3, 3).effect, Some(EffectCmd::Vibrato(RangedU8::new(4), None)));
+
+        // Linked, `Gxx` recalls the last `Exx`.
+        module.flags |= ModuleFlags::LINK_G_E_EFFECTS;
+        let rows = module.flatten_resolved();
+        assert_eq!(command(&rows, 1, 3).effect, Some(EffectCmd::TonePortamento(RangedU8::new(0x20))));
+    }
+}
@@ -0,0 +1,86 @@
+use super::*;
+use crate::error::MessageTooLongError;
+
+
+impl Module {
+    /// Returns the song message with the line breaks converted to `\n`.
+    ///
+    /// The message is kept with the line breaks of the file (`\r`) unless it was decoded by
+    /// [`ParseOptions::message_encoding`](crate::parser::ParseOptions::message_encoding), this
+    /// returns the same text in both cases.
+    pub fn message_text(&self) -> String {
+        self.message.replace("\r\n", "\n").replace('\r', "\n")
+    }
+
+    /// Replaces the song message, the line breaks are converted to `\r` which Impulse Tracker
+    /// expects.
+    ///
+    /// The message is written as UTF-8, which is the default of
+    /// [`WriteOptions::message_encoding`](crate::writer::WriteOptions::message_encoding), see
+    /// [`Module::set_message_with`] for modules written in a code page.
+    /// [`ModuleFlags::MESSAGE_ATTACHED`] is set if the message is not empty and cleared otherwise.
+    ///
+    /// Fails and leaves the message unchanged if the message is longer than the 8000 bytes Impulse
+    /// Tracker can load.
+    pub fn set_message(&mut self, text: &str) -> Result<(), MessageTooLongError> {
+        let message = text.replace("\r\n", "\r").replace('\n', "\r");
+        if message.len() > MAX_MESSAGE_LENGTH {
+            return Err(MessageTooLongError { length: message.len() });
+        }
+        self.flags.set(ModuleFlags::MESSAGE_ATTACHED, !message.is_empty());
+        self.message = message;
+        Ok(())
+    }
+
+    /// Replaces the song message for a module written in the encoding.
+    ///
+    /// Same as [`Module::set_message`] except that the length is checked after encoding and the
+    /// characters missing from the encoding are replaced by `?`, so the message is what a tracker
+    /// using the encoding shows.
+    pub fn set_message_with(&mut self, text: &str, encoding: Encoding) -> Result<(), MessageTooLongError> {
+        let bytes = encoding.encode_message(text);
+        if bytes.len() > MAX_MESSAGE_LENGTH {
+            return Err(MessageTooLongError { length: bytes.len() });
+        }
+        self.flags.set(ModuleFlags::MESSAGE_ATTACHED, !bytes.is_empty());
+        self.message = encoding.decode(&bytes);
+        Ok(())
+    }
+
+    /// Returns the song message as it is stored in the file written with the encoding, see
+    /// [`WriteOptions::message_encoding`](crate::writer::WriteOptions::message_encoding).
+    pub fn message_bytes(&self, encoding: Option<Encoding>) -> Vec<u8> {
+        match encoding {
+            Some(encoding) => encoding.encode_message(&self.message),
+            None => self.message.as_bytes().to_vec(),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn set_message() {
+        let mut module = ModuleBuilder::new().build().unwrap();
+        module.set_message("first\r\nsecond\nthird").unwrap();
+        assert_eq!(module.message, "first\rsecond\rthird");
+        assert_eq!(module.message_text(), "first\nsecond\nthird");
+        assert!(module.flags.contains(ModuleFlags::MESSAGE_ATTACHED));
+
+        module.set_message_with("Café €\n", Encoding::Cp437).unwrap();
+        assert_eq!(module.message, "Café ?\r");
+        assert_eq!(module.message_bytes(Some(Encoding::Cp437)), b"Caf\x82 ?\r");
+
+        // The limit is on the encoded length, `é` takes two bytes in UTF-8 and one in code pages.
+        let long = "é".repeat(MAX_MESSAGE_LENGTH);
+        assert_eq!(module.set_message(&long), Err(MessageTooLongError { length: 2 * MAX_MESSAGE_LENGTH }));
+        assert_eq!(module.message, "Café ?\r");
+        module.set_message_with(&long, Encoding::Windows1252).unwrap();
+
+        module.set_message("").unwrap();
+        assert!(!module.flags.contains(ModuleFlags::MESSAGE_ATTACHED));
+    }
+}
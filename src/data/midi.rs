@@ -0,0 +1,61 @@
+use super::*;
+
+
+/// MIDI configuration embedded in the module
+///
+/// The macros are sent to MIDI instruments and drive the resonant filters of the samples, the
+/// `Zxx` effect with a parameter below `0x80` runs the parametered macro selected by the last
+/// `SFx` effect of the channel with `z` set to the parameter, `Zxx` with a parameter of `0x80` or
+/// above runs the fixed macro `xx - 0x80`.
+///
+/// Stored after the offset tables of the module header if [`ModuleFlags::MIDI_CONIFG_EMBEDDED`]
+/// is set. Modules without an embedded configuration use the one of the player,
+/// [`MidiConfig::default`] is the one Impulse Tracker starts with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MidiConfig {
+    /// Global macros
+    ///
+    /// In order: start, stop, tick, note on, note off, volume, panning, bank select and program
+    /// change.
+    pub global: [MidiMacro; 9],
+
+    /// Parametered macros `SF0` to `SFF`, selected by the `SFx` effect
+    pub parametered: [MidiMacro; 16],
+
+    /// Fixed macros `Z80` to `ZFF`
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serialization::array"))]
+    pub fixed: [MidiMacro; 128],
+}
+
+
+impl MidiConfig {
+    /// Returns the macro the `Zxx` effect with the parameter runs on a channel where `SFx` last
+    /// selected `selected`.
+    pub fn zxx(&self, selected: u8, param: u8) -> &MidiMacro {
+        match param.checked_sub(0x80) {
+            Some(fixed) => &self.fixed[usize::from(fixed)],
+            None => &self.parametered[usize::from(selected & 0x0F)],
+        }
+    }
+}
+
+impl Default for MidiConfig {
+    /// The configuration Impulse Tracker starts with, `SF0` controls the filter cutoff and the
+    /// fixed macros are empty.
+    fn default() -> MidiConfig {
+        let empty = MidiMacro::new("");
+        let mut config = MidiConfig {
+            global: [empty; 9],
+            parametered: [empty; 16],
+            fixed: [empty; 128],
+        };
+        config.global[0] = MidiMacro::new("FF");
+        config.global[1] = MidiMacro::new("FC");
+        config.global[3] = MidiMacro::new("9c n v");
+        config.global[4] = MidiMacro::new("9c n 0");
+        config.global[8] = MidiMacro::new("Cc p");
+        config.parametered[0] = MidiMacro::new("F0F000z");
+        config
+    }
+}
@@ -1,15 +1,22 @@
 use super::*;
+use crate::error::InvalidOrderError;
+use core::ops::{Deref, DerefMut};
 
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Module {
     /// Song Name, null-terminated (but may also contain nulls)
     pub name: Name,
 
     /// Comment message
+    ///
+    /// See [`Module::set_message`] which keeps the message loadable by Impulse Tracker.
     pub message: String,
 
     /// Rows per Measure highlight, Rows per Beat highlight
+    ///
+    /// See [`Module::row_to_beat`] and [`Module::beat_grid`] for the beats of the rows.
     pub highlight: (u8, u8),
 
     /// "Made With" Tracker
@@ -21,6 +28,12 @@ pub struct Module {
     /// combined Header Flags and Special Flags, for embedding extra information
     pub flags: ModuleFlags,
 
+    /// combined Header Flags and Special Flags as stored in the file, including unknown bits
+    ///
+    /// Only the bits not covered by [`ModuleFlags`] are used from this value, the known bits are
+    /// always taken from [`Module::flags`]. See [`Module::raw_flags`].
+    pub stored_flags: u32,
+
     /// Global Volume (0...128)
     pub global_volume: RangedU8<0, 128>,
 
@@ -40,12 +53,18 @@ pub struct Module {
     pub pitch_wheel_depth: u8,
 
     /// Initial Channel Panning
-    pub init_channel_panning: [u8; 64],
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serialization::array"))]
+    pub init_channel_panning: [ChannelPan; MAX_CHANNELS],
 
     /// Initial Channel Volume
-    pub init_channel_volume: [u8; 64],
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serialization::array"))]
+    pub init_channel_volume: [ChannelVolume; MAX_CHANNELS],
 
     /// Orders
+    ///
+    /// All [`Order::Index`] entries should reference existing patterns, editing the list directly
+    /// can break this invariant. Use [`Module::orders_mut`] for validated edits or check the list
+    /// with [`Module::validate_orders`] afterwards.
     pub orders: Vec<Order>,
 
     /// Instrument headers (without samples)
@@ -56,6 +75,70 @@ pub struct Module {
 
     /// Patterns
     pub patterns: Vec<Pattern>,
+
+    /// Embedded MIDI configuration
+    ///
+    /// `None` if the module doesn't embed one, the writer sets
+    /// [`ModuleFlags::MIDI_CONIFG_EMBEDDED`] accordingly.
+    pub midi_config: Option<MidiConfig>,
+
+    /// Edit history, oldest session first
+    ///
+    /// Empty if the module doesn't store one. The history is announced by a special flag bit
+    /// which this crate keeps in [`Module::stored_flags`], the writer stores the history if it's
+    /// not empty or the bit is set.
+    pub edit_history: Vec<EditHistoryEntry>,
+
+    /// Number of channels stored by OpenMPT
+    ///
+    /// *OpenMPT extension.* OpenMPT can store modules with more than 64 channels, the channel count
+    /// is then stored in an extension chunk after the module data. `None` if the extension is not
+    /// present. See [`Module::declared_channel_count`].
+    pub openmpt_channel_count: Option<u16>,
+
+    /// Pattern names
+    ///
+    /// *OpenMPT extension.* Indexed by pattern, patterns past the end of the list have no name.
+    /// OpenMPT stores names of up to 32 bytes, the writer truncates longer names.
+    pub pattern_names: Vec<String>,
+
+    /// Channel names
+    ///
+    /// *OpenMPT extension.* Indexed by channel, channels past the end of the list have no name.
+    /// OpenMPT stores names of up to 20 bytes, the writer truncates longer names.
+    pub channel_names: Vec<String>,
+
+    /// Extended song properties
+    ///
+    /// *OpenMPT extension.* `None` if the file has no extended song properties.
+    pub openmpt_extensions: Option<SongExtensions>,
+
+    /// Parts of the file the parser doesn't understand
+    pub opaque: OpaqueData,
+}
+
+/// Data of a module file which is not part of the format as this crate knows it
+///
+/// Trackers store their own data in reserved fields and in chunks the format doesn't document,
+/// the parser keeps it so the module can be written back without losing anything. The undocumented
+/// flag bits are kept in [`Module::stored_flags`]. The writer places the data where the parser
+/// found it, so a file is reproduced byte-for-byte if it was laid out the way the writer does it
+/// and nothing was modified.
+///
+/// The data is copied without interpreting it, it may refer to the parts of the module which are
+/// moved or changed by the writer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct OpaqueData {
+    /// Reserved field of the module header at offset 0x3C
+    pub header_reserved: u32,
+
+    /// Chunks between the offset tables, the MIDI configuration and name chunks, and the first part
+    /// of the module (e.g. plugin data of OpenMPT)
+    pub header_chunks: Vec<u8>,
+
+    /// Data following the module and the OpenMPT extensions
+    pub trailing: Vec<u8>,
 }
 
 pub(crate) struct ModuleHeader {
@@ -64,6 +147,7 @@ pub(crate) struct ModuleHeader {
     pub(crate) made_with_version: u16,
     pub(crate) compatible_with_version: u16,
     pub(crate) flags: ModuleFlags,
+    pub(crate) stored_flags: u32,
     pub(crate) global_volume: RangedU8<0, 128>,
     pub(crate) sample_volume: RangedU8<0, 128>,
     pub(crate) speed: RangedU8<1, 255>,
@@ -72,21 +156,35 @@ pub(crate) struct ModuleHeader {
     pub(crate) pitch_wheel_depth: u8,
     pub(crate) message_length: u16,
     pub(crate) message_offset: u32,
+    pub(crate) reserved: u32,
     pub(crate) init_channel_panning: [u8; 64],
-    pub(crate) init_channel_volume: [u8; 64],
+    pub(crate) init_channel_volume: [ChannelVolume; 64],
     pub(crate) orders: Vec<Order>,
     pub(crate) instrument_offsets: Vec<u32>,
     pub(crate) sample_offsets: Vec<u32>,
     pub(crate) pattern_offsets: Vec<u32>,
+    /// Out of range values replaced by the parser, offsets are relative to the header
+    pub(crate) fixes: Vec<(usize, String)>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Order {
     Index(PatternId),
     Separator,
     EndOfSong,
 }
 
+/// Guard for validated editing of the order list
+///
+/// Returned by [`Module::orders_mut`], dereferences to the order list. All edits are done on a
+/// copy of the list, they are only written back to the module by a successful
+/// [`OrdersMut::commit`]. Dropping the guard without committing discards the edits.
+pub struct OrdersMut<'m> {
+    module: &'m mut Module,
+    orders: Vec<Order>,
+}
+
 bitflags! {
     pub struct ModuleFlags: u32 {
         // Originally `flags` field. This field has 16 bits however only the lower 8 bits are
@@ -148,9 +246,12 @@ bitflags! {
 
 
 impl ModuleFlags {
+    pub(crate) fn raw_from_parts(flags: u16, special: u16) -> u32 {
+        u32::from(flags) | (u32::from(special) << 16)
+    }
+
     pub(crate) fn from_parts(flags: u16, special: u16) -> ModuleFlags {
-        let bits = u32::from(flags) | (u32::from(special) << 16);
-        ModuleFlags::from_bits_truncate(bits)
+        ModuleFlags::from_bits_truncate(ModuleFlags::raw_from_parts(flags, special))
     }
 }
 
@@ -161,6 +262,12 @@ impl Get<SampleId> for Module {
     }
 }
 
+impl GetMut<SampleId> for Module {
+    fn get_mut(&mut self, index: SampleId) -> Option<&mut Self::Output> {
+        self.samples.as_mut_slice().get_mut(usize::from(index.as_u8()))
+    }
+}
+
 impl_index_from_get!(Module, SampleId);
 
 impl Get<InstrumentId> for Module {
@@ -170,6 +277,12 @@ impl Get<InstrumentId> for Module {
     }
 }
 
+impl GetMut<InstrumentId> for Module {
+    fn get_mut(&mut self, index: InstrumentId) -> Option<&mut Self::Output> {
+        self.instruments.as_mut_slice().get_mut(usize::from(index.as_u8()))
+    }
+}
+
 impl_index_from_get!(Module, InstrumentId);
 
 impl Get<PatternId> for Module {
@@ -179,9 +292,158 @@ impl Get<PatternId> for Module {
     }
 }
 
+impl GetMut<PatternId> for Module {
+    fn get_mut(&mut self, index: PatternId) -> Option<&mut Self::Output> {
+        self.patterns.as_mut_slice().get_mut(usize::from(index.as_u8()))
+    }
+}
+
 impl_index_from_get!(Module, PatternId);
 
 impl Module {
+    /// Returns the combined Header Flags and Special Flags value to be stored in a file.
+    ///
+    /// Bits known to [`ModuleFlags`] are taken from [`Module::flags`], all other bits from
+    /// [`Module::stored_flags`]. Editing the typed flags therefore always takes effect, while bits
+    /// this crate doesn't know about survive a round-trip. Setting known bits in `stored_flags` has
+    /// no effect.
+    pub fn raw_flags(&self) -> u32 {
+        (self.stored_flags & !ModuleFlags::all().bits()) | self.flags.bits()
+    }
+
+    /// Returns the order list.
+    pub fn orders(&self) -> &[Order] {
+        &self.orders
+    }
+
+    /// Returns a guard for editing the order list.
+    ///
+    /// The edited list is validated when the guard is committed, see [`OrdersMut`].
+    pub fn orders_mut(&mut self) -> OrdersMut<'_> {
+        let orders = self.orders.clone();
+        OrdersMut { module: self, orders }
+    }
+
+    /// Checks that all orders reference existing patterns.
+    ///
+    /// Returns an error for the first order which references a pattern not present in the module.
+    pub fn validate_orders(&self) -> Result<(), InvalidOrderError> {
+        validate_orders(&self.orders, &self.patterns)
+    }
+
+    /// Merges runs of consecutive [`Order::Separator`]s into one and removes leading and trailing
+    /// separators.
+    ///
+    /// Separators are skipped during playback so this doesn't change how the module plays. A run of
+    /// separators directly before [`Order::EndOfSong`] counts as trailing, the end of song marker
+    /// itself is kept. Removing entries shifts the positions of the following orders, `Bxx` (jump to
+    /// order) effects in all patterns are updated to keep jumping to the same entries.
+    ///
+    /// Returns the number of removed separators.
+    pub fn collapse_separators(&mut self) -> usize {
+        let orders = self.orders.as_slice();
+        let keep = (0..orders.len())
+            .map(|idx| {
+                if orders[idx] != Order::Separator {
+                    true
+                } else if idx == 0 || orders[idx - 1] == Order::Separator {
+                    false
+                } else {
+                    // First separator of a run, keep it only if a pattern follows the run.
+                    !matches!(
+                        orders[idx..].iter().find(|&&order| order != Order::Separator),
+                        None | Some(Order::EndOfSong),
+                    )
+                }
+            })
+            .collect::<Vec<_>>();
+        self.remove_orders(keep)
+    }
+
+    /// Removes the orders with `false` in `keep` and updates `Bxx` effects to keep jumping to the
+    /// same entries, returns the number of removed orders.
+    ///
+    /// Jumps to a removed entry go to the next entry kept.
+    pub(crate) fn remove_orders(&mut self, keep: Vec<bool>) -> usize {
+        // Number of removed entries before each position.
+        let removed_before = keep.iter()
+            .scan(0usize, |removed, &keep| {
+                let before = *removed;
+                if !keep {
+                    *removed += 1;
+                }
+                Some(before)
+            })
+            .collect::<Vec<_>>();
+        let removed = keep.iter().filter(|&&keep| !keep).count();
+
+        if removed == 0 {
+            return 0;
+        }
+
+        let mut keep = keep.into_iter();
+        self.orders.retain(|_| keep.next().unwrap());
+
+        let commands = self.patterns
+            .iter_mut()
+            .flat_map(|pattern| &mut pattern.rows)
+            .flat_map(Row::commands_mut);
+        for command in commands {
+            if let Some(EffectCmd::JumpOrder(position)) = &mut command.effect {
+                let shift = removed_before.as_slice().get(usize::from(*position)).copied().unwrap_or(removed);
+                *position -= u8::try_from(shift).unwrap();
+            }
+        }
+
+        removed
+    }
+
+    /// Returns order entries that can never be reached during playback, with their positions.
+    ///
+    /// Playback starts at the first order and continues with the next one, [`Order::EndOfSong`]
+    /// stops it and `Bxx` (jump to order) effects jump to other positions. Typically this lists
+    /// patterns stashed after the end of song marker which are not a target of any jump.
+    ///
+    /// The analysis is conservative, an order is considered reachable if any row of a reachable
+    /// pattern jumps to it, even if the row is never played. Reported entries are guaranteed to be
+    /// unreachable, but some unreachable entries may not be reported.
+    pub fn unreachable_orders(&self) -> Vec<(usize, Order)> {
+        let orders = self.orders.as_slice();
+        let mut reachable = vec![false; orders.len()];
+        let mut pending = vec![0];
+
+        while let Some(position) = pending.pop() {
+            match orders.get(position) {
+                Some(_) if reachable[position] => continue,
+                Some(_) => reachable[position] = true,
+                None => continue,
+            }
+
+            match orders[position] {
+                Order::Index(idx) => {
+                    pending.push(position + 1);
+                    let jumps = self.get(idx)
+                        .into_iter()
+                        .flat_map(|pattern| &pattern.rows)
+                        .flat_map(|row| row.iter())
+                        .filter_map(|(_, command)| match command.effect {
+                            Some(EffectCmd::JumpOrder(target)) => Some(usize::from(target)),
+                            _ => None,
+                        });
+                    pending.extend(jumps);
+                }
+                Order::Separator => pending.push(position + 1),
+                Order::EndOfSong => {}
+            }
+        }
+
+        orders.iter()
+            .copied()
+            .enumerate()
+            .filter(|&(position, _)| !reachable[position])
+            .collect()
+    }
+
     /// Returns an iterator over patterns as listed in the orders list.
     ///
     /// It can yield any pattern multiple times or not yield some patterns at all.
@@ -194,14 +456,301 @@ impl Module {
             })
     }
 
+    /// Returns an iterator over all patterns which contain at least one non-empty command.
+    ///
+    /// See [`Pattern::is_empty`] for what is considered empty.
+    pub fn non_empty_patterns(&self) -> impl Iterator<Item = (PatternId, &Pattern)> + '_ {
+        self.patterns
+            .iter()
+            .enumerate()
+            .filter(|(_, pattern)| !pattern.is_empty())
+            .filter_map(|(idx, pattern)| {
+                let id = PatternId::try_from(u8::try_from(idx).ok()?).ok()?;
+                Some((id, pattern))
+            })
+    }
+
+    /// Returns the lowest and highest C-5 sample rate across all samples.
+    ///
+    /// Returns `None` if the module has no samples.
+    pub fn sample_rate_range(&self) -> Option<(u32, u32)> {
+        let rates = self.samples.iter().map(|sample| sample.samplerate_c5);
+        Some((rates.clone().min()?, rates.max()?))
+    }
+
+    /// Returns the number of channels the module is declared to have.
+    ///
+    /// This is the channel count from the OpenMPT extension if present, otherwise 64 which is the
+    /// fixed number of channels in Impulse Tracker. At most [`MAX_CHANNELS`] channels can be
+    /// represented in patterns, [`Module::init_channel_panning`] and
+    /// [`Module::init_channel_volume`].
+    pub fn declared_channel_count(&self) -> usize {
+        self.openmpt_channel_count.map_or(64, usize::from)
+    }
+
+    /// Returns the number of channels needed to play the pattern.
+    ///
+    /// This is the highest active channel of the pattern plus one, or 0 if the pattern is empty
+    /// or does not exist.
+    pub fn channels_in_pattern(&self, id: PatternId) -> u8 {
+        self.get(id)
+            .and_then(|pattern| pattern.active_channels.iter().last())
+            .map_or(0, |channel| u8::try_from(channel.as_usize() + 1).unwrap())
+    }
+
     /// Returns active channels when playing the module.
     ///
     /// Does not account for channels in patterns which are not present in the orders list.
     pub fn active_channels(&self) -> ActiveChannels {
-        use std::ops::BitOr;
+        use core::ops::BitOr;
 
         self.ordered_patterns()
             .map(|pat| pat.active_channels)
             .fold(ActiveChannels::empty(), BitOr::bitor)
     }
 }
+
+impl Module {
+    /// Maximum number of rows in a pattern produced by [`Module::with_single_pattern`].
+    pub const SINGLE_PATTERN_MAX_ROWS: usize = 200;
+
+    /// Returns a copy of the module with its first-pass play sequence baked into linear patterns.
+    ///
+    /// The order list is followed from the start, honoring `Bxx` (jump to order) and `Cxx` (break
+    /// to row) effects, and the played rows are concatenated. The jump and break effects are
+    /// removed from the result, all other commands are kept as they are. Playback ends at the end
+    /// of the order list, at an [`Order::EndOfSong`], on a jump past the end of the order list or
+    /// when a row is about to be played the second time (the song loops).
+    ///
+    /// IT patterns have at most 200 rows, so the rows are split into consecutive patterns of
+    /// [`Module::SINGLE_PATTERN_MAX_ROWS`] rows (the last one may be shorter) and the order list
+    /// becomes `[0, 1, 2, ...]`. For short songs this is a single pattern and the order list
+    /// `[0]`. As there can be at most 200 patterns, rows beyond 200 full patterns are dropped. A
+    /// module without any playable orders results in no patterns and an empty order list.
+    ///
+    /// Active channels of the new patterns cover only the commands they contain, the channel count
+    /// of the result is the maximum over the played source patterns. Pattern loops (`SBx`) are not
+    /// unrolled and may behave differently if they end up split across patterns.
+    pub fn with_single_pattern(&self) -> Module {
+        // There can be at most 200 patterns.
+        let max_rows = Module::SINGLE_PATTERN_MAX_ROWS * 200;
+        let rows = self.first_pass_rows(max_rows);
+
+        let patterns = rows
+            .chunks(Module::SINGLE_PATTERN_MAX_ROWS)
+            .map(|rows| Pattern {
+                active_channels: rows.iter()
+                    .flat_map(|row| row.iter().map(|(channel, _)| channel))
+                    .collect(),
+                rows: rows.to_vec(),
+                truncated: false,
+                highlight: None,
+            })
+            .collect::<Vec<_>>();
+
+        let orders = (0..patterns.len())
+            .filter_map(|idx| PatternId::try_from(u8::try_from(idx).ok()?).ok())
+            .map(Order::Index)
+            .collect();
+
+        Module {
+            orders,
+            patterns,
+            ..self.clone()
+        }
+    }
+
+    /// Returns where each note-triggering cell is played and which sample it triggers.
+    ///
+    /// Walks the first-pass play order the same way as [`Module::with_single_pattern`] and yields
+    /// the order position, row and channel of every cell which starts a new note together with the
+    /// sample that sounds. Cells without an instrument use the last instrument played on the
+    /// channel. In instrument mode the sample is looked up in the instrument sample map, in sample
+    /// mode the instrument column selects the sample directly.
+    ///
+    /// Skipped are cells without a note, with note off, cut or fade, cells with tone portamento
+    /// (`Gxx`, `Lxx` or the volume column `G`) which slide the playing note instead of starting a
+    /// new one, and notes which don't resolve to an existing sample.
+    pub fn sample_triggers(&self) -> impl Iterator<Item = (usize, u16, Channel, SampleId)> + '_ {
+        let mut last_instrument = [None; MAX_CHANNELS];
+        let mut triggers = Vec::new();
+
+        self.first_pass(|position, row_idx, row| {
+            for (channel, command) in row.iter() {
+                let last_instrument = &mut last_instrument[channel.as_usize()];
+                if command.instrument.is_some() {
+                    *last_instrument = command.instrument;
+                }
+
+                let note = match command.note {
+                    Some(NoteCmd::Play(note)) => note,
+                    _ => continue,
+                };
+                let portamento = matches!(
+                    command.effect,
+                    Some(EffectCmd::TonePortamento(_)) | Some(EffectCmd::VolumeSlideAndPortamento(_)),
+                ) || matches!(command.volume, Some(VolumeCmd::TonePortamento(_)));
+                if portamento {
+                    continue;
+                }
+
+                let sample = last_instrument.and_then(|instrument| {
+                    if self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+                        self.get(instrument)?.sample_map[note]
+                    } else {
+                        SampleId::try_from(instrument.as_u8()).ok()
+                    }
+                });
+                if let Some(sample) = sample.filter(|&sample| self.get(sample).is_some()) {
+                    let row_idx = u16::try_from(row_idx).expect("BUG: pattern has more than u16::MAX rows");
+                    triggers.push((position, row_idx, channel, sample));
+                }
+            }
+            true
+        });
+
+        triggers.into_iter()
+    }
+
+    /// Returns at most `max_rows` rows in first-pass play order with jumps and breaks removed.
+    fn first_pass_rows(&self, max_rows: usize) -> Vec<Row> {
+        let mut rows = Vec::new();
+        self.first_pass(|_, _, row| {
+            let commands = row.iter()
+                .filter_map(|(channel, command)| {
+                    let mut command = *command;
+                    if let Some(EffectCmd::JumpOrder(_)) | Some(EffectCmd::BreakRow(_)) = command.effect {
+                        command.effect = None;
+                    }
+                    if command.is_empty() {
+                        None
+                    } else {
+                        Some((channel, command))
+                    }
+                })
+                .collect();
+            rows.push(Row::from_vec(commands));
+            rows.len() < max_rows
+        });
+        rows
+    }
+
+    /// Visits rows in first-pass play order.
+    ///
+    /// The visitor gets the order position, row index and the row, it returns `false` to stop the
+    /// walk. See [`Module::with_single_pattern`] for the rules of when playback stops.
+    fn first_pass(&self, visit: impl FnMut(usize, usize, &Row) -> bool) {
+        self.first_pass_from(0, visit);
+    }
+
+    /// Visits rows in first-pass play order starting at the order position, see
+    /// [`Module::first_pass`].
+    pub(crate) fn first_pass_from(&self, start: usize, mut visit: impl FnMut(usize, usize, &Row) -> bool) {
+        use alloc::collections::BTreeSet;
+
+        let mut visited = BTreeSet::new();
+        let (mut position, mut start_row) = (start, 0usize);
+
+        'orders: while let Some(order) = self.orders.as_slice().get(position) {
+            let pattern = match order {
+                Order::Index(idx) => match self.get(idx) {
+                    Some(pattern) => pattern,
+                    None => break,
+                },
+                Order::Separator => {
+                    position += 1;
+                    start_row = 0;
+                    continue;
+                }
+                Order::EndOfSong => break,
+            };
+
+            // Break rows past the end of the pattern are treated as 0.
+            if start_row >= pattern.rows.len() {
+                start_row = 0;
+            }
+
+            let mut next = (position + 1, 0);
+            for (row_idx, row) in pattern.rows.iter().enumerate().skip(start_row) {
+                if !visited.insert((position, row_idx)) || !visit(position, row_idx, row) {
+                    break 'orders;
+                }
+
+                let (mut jump, mut break_row) = (None, None);
+                for (_, command) in row.iter() {
+                    match command.effect {
+                        Some(EffectCmd::JumpOrder(order)) => jump = Some(usize::from(order)),
+                        Some(EffectCmd::BreakRow(row)) => break_row = Some(usize::from(row)),
+                        _ => {}
+                    }
+                }
+
+                if jump.is_some() || break_row.is_some() {
+                    next = (jump.unwrap_or(position + 1), break_row.unwrap_or(0));
+                    break;
+                }
+            }
+
+            position = next.0;
+            start_row = next.1;
+        }
+    }
+}
+
+impl<'m> OrdersMut<'m> {
+    /// Appends the pattern to the song.
+    ///
+    /// If the list ends with an [`Order::EndOfSong`] the pattern is inserted before it, so that
+    /// it's played as a part of the song.
+    pub fn push_pattern(&mut self, pattern: PatternId) {
+        match self.orders.last() {
+            Some(Order::EndOfSong) => self.orders.insert(self.orders.len() - 1, Order::Index(pattern)),
+            _ => self.orders.push(Order::Index(pattern)),
+        }
+    }
+
+    /// Merges runs of consecutive [`Order::Separator`]s into one.
+    ///
+    /// Only the list is edited, `Bxx` (jump to order) effects targeting entries after a removed
+    /// separator are not updated. Use [`Module::collapse_separators`] to keep the jumps intact.
+    ///
+    /// Returns the number of removed separators.
+    pub fn dedup_separators(&mut self) -> usize {
+        let len = self.orders.len();
+        self.orders.dedup_by(|next, prev| *next == Order::Separator && *prev == Order::Separator);
+        len - self.orders.len()
+    }
+
+    /// Validates the edited order list and writes it back to the module.
+    ///
+    /// If validation fails the module is left unchanged and the edits are discarded.
+    pub fn commit(self) -> Result<(), InvalidOrderError> {
+        validate_orders(&self.orders, &self.module.patterns)?;
+        self.module.orders = self.orders;
+        Ok(())
+    }
+}
+
+impl Deref for OrdersMut<'_> {
+    type Target = Vec<Order>;
+    fn deref(&self) -> &Self::Target {
+        &self.orders
+    }
+}
+
+impl DerefMut for OrdersMut<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.orders
+    }
+}
+
+fn validate_orders(orders: &[Order], patterns: &[Pattern]) -> Result<(), InvalidOrderError> {
+    for (position, order) in orders.iter().enumerate() {
+        if let Order::Index(pattern) = *order {
+            if usize::from(pattern.as_u8()) >= patterns.len() {
+                return Err(InvalidOrderError { position, pattern });
+            }
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,237 @@
+use super::*;
+use core::convert::TryFrom;
+
+
+/// Maximum (absolute right) panning position
+const MAX_PAN: u8 = 64;
+
+/// Initial channel panning value marking a surround channel
+const CHANNEL_PAN_SURROUND: u8 = 100;
+
+/// Flag in initial channel panning marking a disabled channel
+const CHANNEL_PAN_DISABLED: u8 = 0x80;
+
+
+/// Panning of a note
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Pan {
+    /// Panning position from `0` (absolute left) through `32` (centre) to `64` (absolute right)
+    Position(RangedU8<0, 64>),
+
+    /// Surround sound
+    Surround,
+}
+
+/// Initial panning of a channel
+///
+/// Stored as in the file, the panning position `0..=64` or `100` for surround with the bit `0x80`
+/// set if the channel is disabled. Other values can appear in files saved by some trackers, they
+/// are kept as they are and [`ChannelPan::pan`] clips them.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(from = "u8", into = "u8"))]
+pub struct ChannelPan(u8);
+
+impl ChannelPan {
+    /// Enabled channel panned to the centre
+    pub const CENTRE: ChannelPan = ChannelPan(32);
+
+    /// Disabled channel panned to the centre, the value of unused channels
+    pub const DISABLED: ChannelPan = ChannelPan(32 | CHANNEL_PAN_DISABLED);
+
+    /// Creates the panning of an enabled or disabled channel.
+    pub fn new(pan: Pan, enabled: bool) -> ChannelPan {
+        let raw = match pan {
+            Pan::Position(position) => position.as_u8(),
+            Pan::Surround => CHANNEL_PAN_SURROUND,
+        };
+        ChannelPan(if enabled { raw } else { raw | CHANNEL_PAN_DISABLED })
+    }
+
+    /// Returns the panning of the channel whether it's enabled or not.
+    pub fn pan(self) -> Pan {
+        channel_pan(self.0)
+    }
+
+    /// Returns `true` unless the channel is disabled, disabled channels are not played.
+    pub fn is_enabled(self) -> bool {
+        self.0 & CHANNEL_PAN_DISABLED == 0
+    }
+
+    /// Returns `true` if the value is a panning position or surround.
+    pub fn is_valid(self) -> bool {
+        let raw = self.0 & !CHANNEL_PAN_DISABLED;
+        raw <= MAX_PAN || raw == CHANNEL_PAN_SURROUND
+    }
+
+    /// Changes the panning keeping the channel enabled or disabled.
+    pub fn set_pan(&mut self, pan: Pan) {
+        *self = ChannelPan::new(pan, self.is_enabled());
+    }
+
+    /// Enables or disables the channel keeping its panning.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        *self = ChannelPan(if enabled { self.0 & !CHANNEL_PAN_DISABLED } else { self.0 | CHANNEL_PAN_DISABLED });
+    }
+}
+
+impl From<u8> for ChannelPan {
+    fn from(raw: u8) -> ChannelPan {
+        ChannelPan(raw)
+    }
+}
+
+impl From<ChannelPan> for u8 {
+    fn from(pan: ChannelPan) -> u8 {
+        pan.0
+    }
+}
+
+impl Debug for ChannelPan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ChannelPan")
+            .field("pan", &self.pan())
+            .field("enabled", &self.is_enabled())
+            .finish()
+    }
+}
+
+/// Resolves the initial panning of a note played on a channel
+///
+/// Composes the panning components in the order Impulse Tracker applies them:
+///
+/// 1. the initial channel panning is used as the base, the disabled channel flag is ignored,
+/// 2. if the instrument has default panning enabled it replaces the channel panning,
+/// 3. if the sample has default panning enabled it replaces the previous value,
+/// 4. pitch/pan separation of the instrument is added as
+///    `(note - pitch_pan_centre) * pitch_pan_separation / 8` and the result is clipped to
+///    `0..=64`.
+///
+/// The instrument is only taken into account if the module is in instrument mode
+/// ([`ModuleFlags::USE_INSTRUMENTS`]). Pitch/pan separation doesn't apply to surround panning.
+/// Random pan variation (pan swing) is not applied, the result is the pan without any randomness.
+pub fn resolve_initial_pan(
+    module: &Module,
+    channel: Channel,
+    instrument: Option<&Instrument>,
+    sample: Option<&Sample>,
+    note: Note,
+) -> Pan {
+    let instrument = instrument.filter(|_| module.flags.contains(ModuleFlags::USE_INSTRUMENTS));
+
+    let mut pan = module.init_channel_panning[channel.as_usize()].pan();
+
+    if let Some(instrument) = instrument {
+        if instrument.flags.contains(InstrumentFlags::ENABLE_PANNING) {
+            pan = Pan::position(instrument.default_panning.as_u8());
+        }
+    }
+
+    if let Some(position) = sample.and_then(Sample::default_pan) {
+        pan = Pan::Position(position);
+    }
+
+    match (pan, instrument) {
+        (Pan::Position(position), Some(instrument)) => Pan::position(pitch_pan(
+            position.as_u8(),
+            note,
+            instrument.pitch_pan_centre,
+            instrument.pitch_pan_separation,
+        )),
+        _ => pan,
+    }
+}
+
+impl Pan {
+    /// Creates a panning position clipping the value to `0..=64`.
+    fn position(pan: u8) -> Pan {
+        Pan::Position(RangedU8::try_from(pan.min(MAX_PAN)).unwrap())
+    }
+}
+
+/// Decodes initial channel panning ignoring the disabled channel flag.
+fn channel_pan(raw: u8) -> Pan {
+    match raw & !CHANNEL_PAN_DISABLED {
+        CHANNEL_PAN_SURROUND => Pan::Surround,
+        pan => Pan::position(pan),
+    }
+}
+
+/// Applies pitch/pan separation to a panning position.
+fn pitch_pan(pan: u8, note: Note, centre: u8, separation: i8) -> u8 {
+    let offset = (i32::from(u8::from(note)) - i32::from(centre)) * i32::from(separation) / 8;
+    let pan = (i32::from(pan) + offset).clamp(0, i32::from(MAX_PAN));
+    u8::try_from(pan).unwrap()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn note(raw: u8) -> Note {
+        Note::try_from(raw).unwrap()
+    }
+
+    #[test]
+    fn channel_pan() {
+        assert_eq!(super::channel_pan(0), Pan::position(0));
+        assert_eq!(super::channel_pan(32), Pan::position(32));
+        assert_eq!(super::channel_pan(32 | 0x80), Pan::position(32));
+        assert_eq!(super::channel_pan(100), Pan::Surround);
+        assert_eq!(super::channel_pan(100 | 0x80), Pan::Surround);
+
+        let mut pan = ChannelPan::from(100 | 0x80);
+        assert_eq!((pan.pan(), pan.is_enabled()), (Pan::Surround, false));
+        pan.set_enabled(true);
+        pan.set_pan(Pan::position(16));
+        assert_eq!(pan, ChannelPan::new(Pan::position(16), true));
+        assert_eq!(u8::from(pan), 16);
+        assert_eq!(ChannelPan::from(0xFF).pan(), Pan::position(64));
+    }
+
+    #[test]
+    fn channel_pan_encoding() {
+        assert_eq!(u8::from(ChannelPan::CENTRE), 32);
+        assert_eq!(u8::from(ChannelPan::DISABLED), 32 | 0x80);
+        assert_eq!(u8::from(ChannelPan::new(Pan::Surround, true)), 100);
+        assert_eq!(u8::from(ChannelPan::new(Pan::Surround, false)), 100 | 0x80);
+        assert_eq!(u8::from(ChannelPan::new(Pan::position(64), false)), 64 | 0x80);
+
+        let mut pan = ChannelPan::new(Pan::Surround, true);
+        pan.set_enabled(false);
+        assert_eq!((pan.pan(), pan.is_enabled()), (Pan::Surround, false));
+        pan.set_pan(Pan::position(0));
+        assert_eq!(u8::from(pan), 0x80);
+
+        // Every byte is kept as it is, only positions and surround are valid.
+        for raw in 0..=255 {
+            let pan = ChannelPan::from(raw);
+            assert_eq!(u8::from(pan), raw);
+            assert_eq!(pan.is_enabled(), raw < 0x80);
+            assert_eq!(pan.is_valid(), raw & 0x7F <= 64 || raw & 0x7F == 100);
+            if pan.is_valid() {
+                assert_eq!(ChannelPan::new(pan.pan(), pan.is_enabled()), pan);
+            }
+        }
+    }
+
+    #[test]
+    fn pitch_pan() {
+        // No separation, or playing the centre note, leaves the pan unchanged.
+        assert_eq!(super::pitch_pan(32, note(72), 60, 0), 32);
+        assert_eq!(super::pitch_pan(32, note(60), 60, 32), 32);
+
+        // An octave above the centre with separation 8 moves by 12.
+        assert_eq!(super::pitch_pan(32, note(72), 60, 8), 44);
+        assert_eq!(super::pitch_pan(32, note(48), 60, 8), 20);
+
+        // Negative separation pans the other way.
+        assert_eq!(super::pitch_pan(32, note(72), 60, -8), 20);
+
+        // Results are clipped.
+        assert_eq!(super::pitch_pan(32, note(119), 0, 32), 64);
+        assert_eq!(super::pitch_pan(32, note(0), 119, 32), 0);
+    }
+}
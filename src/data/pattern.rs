@@ -1,8 +1,8 @@
 use super::*;
-use crate::error::OutOfRangeError;
-use std::convert::TryFrom;
-use std::fmt::{self, Debug, Display};
-use std::str;
+use crate::error::{InvalidRowCountError, InvalidVolumeError, OutOfRangeError};
+use core::convert::TryFrom;
+use core::fmt::{self, Debug, Display};
+use core::str;
 
 
 /// Pattern
@@ -17,6 +17,7 @@ use std::str;
 /// **This API will change in the future because it doesn't impose the invariant that
 /// `active_channels` and `rows` stay in sync.**
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Pattern {
     /// Active channels
     ///
@@ -26,12 +27,27 @@ pub struct Pattern {
 
     /// Pattern rows
     pub rows: Vec<Row>,
+
+    /// Only some of the rows were decoded
+    ///
+    /// Set by [`Pattern::parse_limited`] when the pattern has more rows than requested, `rows`
+    /// then doesn't contain the whole pattern and `active_channels` only covers the decoded rows.
+    pub truncated: bool,
+
+    /// Rows per measure and rows per beat overriding [`Module::highlight`] in this pattern
+    ///
+    /// *OpenMPT extension.* OpenMPT keeps the override only in MPTM files, IT files have no room
+    /// for it. It is `None` in parsed patterns and it is not written to IT files, see
+    /// [`Module::row_to_beat`] for its use.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub highlight: Option<(u8, u8)>,
 }
 
 /// Pattern row
 ///
 /// Row is represented by a sparse vector. It can be iterated or indexed by a [`Channel`].
 #[derive(Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Row {
     map: Vec<(Channel, Command)>,
 }
@@ -40,6 +56,7 @@ pub struct Row {
 ///
 /// Command is one cell on the pattern table.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Command {
     pub note: Option<NoteCmd>,
     pub instrument: Option<InstrumentId>,
@@ -49,23 +66,48 @@ pub struct Command {
 
 /// Note column commands
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum NoteCmd {
     Play(Note),
     Off,
     Cut,
     Fade,
+
+    /// Parameter control event, sets a parameter of a plugin
+    ///
+    /// *MPTM extension.* Stored as the note `252` (`251` if `smooth`) with the plugin in the
+    /// instrument column, the parameter in the volume column and the value in the effect number
+    /// and parameter. The other columns of the command are taken by the event and are empty.
+    ParamControl {
+        /// The value is reached gradually over the row (`PCs`)
+        smooth: bool,
+
+        /// Plugin, counted from `1`, `0` is no plugin
+        plugin: u8,
+
+        /// Index of the parameter of the plugin
+        param: u8,
+
+        /// Value of the parameter, `0..=999`
+        value: u16,
+    },
 }
 
 /// Note pitch representation
 ///
 /// Ranges from C-0 to B-9, only exact pitches can be represented.
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "u8", into = "u8"))]
 pub struct Note(u8);
 
 /// Volume column commands
 ///
 /// All parameters are displayed in **decimal**.
+///
+/// Converts from the raw volume column byte with [`TryFrom<u8>`] and back with [`From`].
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum VolumeCmd {
     /// `vxx` Set volume
     ///
@@ -179,6 +221,7 @@ pub enum VolumeCmd {
 /// - <https://modarchive.org/forums/index.php?topic=2222.0>
 // Documentation for these is adapted from the Schism Tracker help text and OpenMPT wiki.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EffectCmd {
     /// `Axx` Set Speed
     ///
@@ -461,6 +504,7 @@ pub enum EffectCmd {
 ///
 /// Categorization is taken from OpenMPT wiki.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EffectCategory {
     GlobalTiming,
     GlobalPattern,
@@ -490,6 +534,7 @@ pub enum EffectCategory {
 /// for compatibilty with these trackers.
 // TODO describe volume slide units
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum VolumeSlide {
     /// `D0x`, `K0x`, `L0x`, `N0x`, `W0x` Volume slide down by `x`
     ///
@@ -520,6 +565,7 @@ pub enum VolumeSlide {
 /// Slide can be either smooth or semitone-wise, see [`Special::SetGlissando`] for details.
 // TODO describe frequency units
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Portamento {
     /// `Exx`, `Fxx` Pitch slide down/up by `xx`
     ///
@@ -561,6 +607,7 @@ pub enum Portamento {
 ///
 /// We leave this encoded as a full range `u8` and don't treat the zero specially.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SetSampleOffset {
     Low(u8),
     High(RangedU8<0, 0x0F>),
@@ -578,6 +625,7 @@ pub enum SetSampleOffset {
 /// Value `0xFF` gets parsed like `FineLeft(0xE)`, see [`VolumeSlide`] for details, it uses the
 /// same underlying encoding.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PanningSlide {
     /// `P0x` Panning slide to right by `x`
     ///
@@ -606,6 +654,7 @@ pub enum PanningSlide {
 ///
 /// All the `Sxx` commands share the same memory, this should include the `SAy` command.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Special {
     // Schism Tracker and OpenMPT (IT Effects) documentation disagree on this one. OpenMPT says
     // `S00` recalls `Sxx` command memory but Schism Tracker says `S0x` sets filter, it is marked
@@ -737,6 +786,7 @@ pub enum Special {
 /// ## Canonicalization
 /// The valid values for waveforms are `0..=3`, all out-of-range values are parsed as `3`.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Waveform {
     /// Sine wave `0`
     Sine,
@@ -752,6 +802,7 @@ pub enum Waveform {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SetPastNote {
     Cut,
     Off,
@@ -759,6 +810,7 @@ pub enum SetPastNote {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SetNewNoteAction {
     Cut,
     Off,
@@ -767,6 +819,7 @@ pub enum SetNewNoteAction {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SurroundMode {
     /// `S9A` Sets the surround mode to Center Surround for all channels.
     ///
@@ -782,6 +835,7 @@ pub enum SurroundMode {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum FilterMode {
     /// `S9C` Sets filter mode to Global on all channels (Impulse Tracker behaviour).
     ///
@@ -798,6 +852,7 @@ pub enum FilterMode {
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum PlayDirection {
     /// `S9E` Forces the current sample to play forward.
     Forward,
@@ -816,6 +871,7 @@ pub enum PlayDirection {
 /// `0x00` is used for memory and parses as `None`, but `0x10` would increase tempo by 0 which is
 /// useless so the parser just skips the effect.
 #[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum Tempo {
     /// `T0x` Tempo slide down by `x`
     SlideDown(RangedU8<1, 0x0F>),
@@ -828,6 +884,196 @@ pub enum Tempo {
 }
 
 
+impl Pattern {
+    /// Largest number of rows of a pattern
+    pub const MAX_ROWS: usize = 200;
+
+    /// Returns `true` if the pattern doesn't contain any non-empty command.
+    ///
+    /// The number of rows doesn't matter, a pattern is empty if none of its rows contain anything.
+    pub fn is_empty(&self) -> bool {
+        self.rows.iter().all(Row::is_empty)
+    }
+
+    /// Returns an iterator over the rows of the pattern.
+    pub fn rows(&self) -> impl Iterator<Item=&Row> + '_ {
+        self.rows.iter()
+    }
+
+    /// Returns the row at the index, or `None` if the pattern is shorter.
+    pub fn row(&self, index: usize) -> Option<&Row> {
+        self.rows.as_slice().get(index)
+    }
+
+    /// Returns the command at the row and channel, or `None` if there is no command.
+    pub fn command(&self, row: usize, channel: Channel) -> Option<&Command> {
+        self.row(row)?.get(channel)
+    }
+
+    /// Sets the command at the row and channel and marks the channel as active.
+    ///
+    /// Returns the command which was replaced. Panics if the row index is out of range.
+    pub fn set_command(&mut self, row: usize, channel: Channel, command: Command) -> Option<Command> {
+        self.active_channels |= ActiveChannels::new([channel]);
+        self.rows[row].insert(channel, command)
+    }
+
+    /// Sets the note at the row and channel, keeping the rest of the command.
+    ///
+    /// Panics if the row index is out of range.
+    pub fn set_note(&mut self, row: usize, channel: Channel, note: NoteCmd) {
+        self.cell_mut(row, channel).note = Some(note);
+    }
+
+    /// Sets the instrument at the row and channel, keeping the rest of the command.
+    ///
+    /// Panics if the row index is out of range.
+    pub fn set_instrument(&mut self, row: usize, channel: Channel, instrument: InstrumentId) {
+        self.cell_mut(row, channel).instrument = Some(instrument);
+    }
+
+    /// Sets the volume column at the row and channel, keeping the rest of the command.
+    ///
+    /// Panics if the row index is out of range.
+    pub fn set_volume(&mut self, row: usize, channel: Channel, volume: VolumeCmd) {
+        self.cell_mut(row, channel).volume = Some(volume);
+    }
+
+    /// Sets the effect at the row and channel, keeping the rest of the command.
+    ///
+    /// Panics if the row index is out of range.
+    pub fn set_effect(&mut self, row: usize, channel: Channel, effect: EffectCmd) {
+        self.cell_mut(row, channel).effect = Some(effect);
+    }
+
+    /// Removes the command at the row and channel and returns it.
+    ///
+    /// The channel stops being active if it has no command left in any row. Panics if the row
+    /// index is out of range.
+    pub fn clear_cell(&mut self, row: usize, channel: Channel) -> Option<Command> {
+        let command = self.rows[row].remove(channel);
+        if self.rows.iter().all(|row| row.get(channel).is_none()) {
+            self.active_channels &= !ActiveChannels::new([channel]);
+        }
+        command
+    }
+
+    /// Changes the number of rows, dropping the rows past the new end or appending empty rows.
+    ///
+    /// Channels which have no command left stop being active. Fails if `rows` is zero or more
+    /// than [`Pattern::MAX_ROWS`], the pattern is unchanged then.
+    pub fn resize(&mut self, rows: usize) -> Result<(), InvalidRowCountError> {
+        check_rows(rows)?;
+        self.rows.resize(rows, Row::empty());
+        self.update_active_channels();
+        Ok(())
+    }
+
+    /// Splits the pattern before the row, returns the rows before it and the rows from it on.
+    ///
+    /// The commands are moved with their rows, effects referencing rows (`Cxx`, `SBx`) are not
+    /// changed. Both patterns keep the [highlight](Pattern::highlight), the second one is
+    /// [truncated](Pattern::truncated) if this one is. Fails if either pattern would have no rows.
+    pub fn split_at(&self, row: usize) -> Result<(Pattern, Pattern), InvalidRowCountError> {
+        check_rows(row)?;
+        check_rows(self.rows.len().saturating_sub(row))?;
+        let (first, second) = self.rows.split_at(row);
+        let first = Pattern::from_rows(first.to_vec(), false, self.highlight);
+        let second = Pattern::from_rows(second.to_vec(), self.truncated, self.highlight);
+        Ok((first, second))
+    }
+
+    /// Returns the pattern with the rows of `other` appended to the rows of this one.
+    ///
+    /// The result has the [highlight](Pattern::highlight) of this pattern and is
+    /// [truncated](Pattern::truncated) if `other` is. Fails if the result would have more than
+    /// [`Pattern::MAX_ROWS`] rows.
+    pub fn concat(&self, other: &Pattern) -> Result<Pattern, InvalidRowCountError> {
+        let length = self.rows.len() + other.rows.len();
+        check_rows(length)?;
+        let mut rows = Vec::with_capacity(length);
+        rows.extend_from_slice(&self.rows);
+        rows.extend_from_slice(&other.rows);
+        Ok(Pattern::from_rows(rows, other.truncated, self.highlight))
+    }
+
+    /// Returns the pattern with an empty row inserted after every row, doubling its length.
+    ///
+    /// Played at double speed the pattern sounds the same, effects which depend on the rows or
+    /// the ticks (`Cxx`, `SBx`, slides) are not changed. Mirrors OpenMPT's grow selection. Fails
+    /// if the result would have more than [`Pattern::MAX_ROWS`] rows.
+    pub fn expand(&self) -> Result<Pattern, InvalidRowCountError> {
+        check_rows(2 * self.rows.len())?;
+        let rows = self.rows.iter().flat_map(|row| [row.clone(), Row::empty()]).collect();
+        Ok(Pattern::from_rows(rows, self.truncated, self.highlight))
+    }
+
+    /// Returns the pattern with every pair of rows merged into one, halving its length.
+    ///
+    /// Mirrors OpenMPT's shrink selection. The commands of the first row of a pair are kept, the
+    /// columns they leave empty are filled from the second row: the note together with the
+    /// instrument, the volume column and the effect each on their own. Whatever doesn't fit is
+    /// dropped. A last row without a pair is kept as it is.
+    pub fn shrink(&self) -> Pattern {
+        let rows = self.rows
+            .chunks(2)
+            .map(|pair| {
+                let mut row = pair[0].clone();
+                for (channel, second) in pair.iter().skip(1).flat_map(Row::iter) {
+                    let command = match row.get_mut(channel) {
+                        Some(command) => command,
+                        None => {
+                            row.insert(channel, *second);
+                            continue;
+                        }
+                    };
+                    if command.note.is_none() && command.instrument.is_none() {
+                        command.note = second.note;
+                        command.instrument = second.instrument;
+                    }
+                    command.volume = command.volume.or(second.volume);
+                    command.effect = command.effect.or(second.effect);
+                }
+                row
+            })
+            .collect();
+        Pattern::from_rows(rows, self.truncated, self.highlight)
+    }
+
+    fn from_rows(rows: Vec<Row>, truncated: bool, highlight: Option<(u8, u8)>) -> Pattern {
+        let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows, truncated, highlight };
+        pattern.update_active_channels();
+        pattern
+    }
+
+    /// Sets the active channels to the channels with a command in any row.
+    fn update_active_channels(&mut self) {
+        self.active_channels = self.rows.iter().flat_map(Row::iter).map(|(channel, _)| channel).collect();
+    }
+
+    /// Returns the command at the row and channel, inserting an empty one if there is none.
+    fn cell_mut(&mut self, row: usize, channel: Channel) -> &mut Command {
+        self.active_channels |= ActiveChannels::new([channel]);
+        let map = &mut self.rows[row].map;
+        let idx = match map.binary_search_by_key(&channel, |(chan, _)| *chan) {
+            Ok(idx) => idx,
+            Err(idx) => {
+                map.insert(idx, (channel, Command { note: None, instrument: None, volume: None, effect: None }));
+                idx
+            }
+        };
+        &mut map[idx].1
+    }
+}
+
+/// Checks that a pattern can have the number of rows.
+fn check_rows(rows: usize) -> Result<(), InvalidRowCountError> {
+    match rows {
+        1..=Pattern::MAX_ROWS => Ok(()),
+        _ => Err(InvalidRowCountError { rows }),
+    }
+}
+
 impl Row {
     /// Create new empty row
     pub const fn empty() -> Row {
@@ -839,11 +1085,78 @@ impl Row {
         Row { map: vec }
     }
 
+    /// Sorts the commands in place and copies them into a new row of their exact size.
+    pub(crate) fn from_slice(commands: &mut [(Channel, Command)]) -> Row {
+        commands.sort_unstable_by_key(|(chan, _)| *chan);
+        Row { map: commands.to_vec() }
+    }
+
     pub fn iter(&self) -> impl Iterator<Item=(Channel, &Command)> + '_ {
         self.map
             .iter()
             .map(|(chan, command)| (*chan, command))
     }
+
+    pub(crate) fn iter_mut(&mut self) -> impl Iterator<Item=(Channel, &mut Command)> + '_ {
+        self.map
+            .iter_mut()
+            .map(|(chan, command)| (*chan, command))
+    }
+
+    pub(crate) fn commands_mut(&mut self) -> impl Iterator<Item=&mut Command> + '_ {
+        self.map
+            .iter_mut()
+            .map(|(_, command)| command)
+    }
+
+    /// Returns `true` if the row doesn't contain any non-empty command.
+    pub fn is_empty(&self) -> bool {
+        self.map.iter().all(|(_, command)| command.is_empty())
+    }
+
+    /// Sets the command for the channel, returns the command which was replaced.
+    pub fn insert(&mut self, channel: Channel, command: Command) -> Option<Command> {
+        match self.map.binary_search_by_key(&channel, |(chan, _)| *chan) {
+            Ok(idx) => Some(core::mem::replace(&mut self.map[idx].1, command)),
+            Err(idx) => {
+                self.map.insert(idx, (channel, command));
+                None
+            }
+        }
+    }
+
+    /// Removes the command for the channel and returns it.
+    pub fn remove(&mut self, channel: Channel) -> Option<Command> {
+        let idx = self.map.binary_search_by_key(&channel, |(chan, _)| *chan).ok()?;
+        Some(self.map.remove(idx).1)
+    }
+}
+
+impl NoteCmd {
+    /// Returns the value of the note column as it is stored in the pattern data.
+    ///
+    /// Notes are `0..=119`, note off is `255`, note cut `254`, note fade `253` and parameter
+    /// control `252` or `251` if smooth.
+    pub fn to_raw(self) -> u8 {
+        match self {
+            NoteCmd::Play(note) => u8::from(note),
+            NoteCmd::Off => 255,
+            NoteCmd::Cut => 254,
+            NoteCmd::Fade => 253,
+            NoteCmd::ParamControl { smooth: false, .. } => 252,
+            NoteCmd::ParamControl { smooth: true, .. } => 251,
+        }
+    }
+}
+
+impl Command {
+    /// Returns `true` if the command has no note, instrument, volume or effect.
+    pub fn is_empty(&self) -> bool {
+        self.note.is_none()
+            && self.instrument.is_none()
+            && self.volume.is_none()
+            && self.effect.is_none()
+    }
 }
 
 impl Debug for Row {
@@ -868,6 +1181,15 @@ impl Get<Channel> for Row {
     }
 }
 
+impl GetMut<Channel> for Row {
+    fn get_mut(&mut self, index: Channel) -> Option<&mut Self::Output> {
+        self.map
+            .binary_search_by_key(&index, |(chan, _)| *chan)
+            .ok()
+            .map(move |idx| &mut self.map[idx].1)
+    }
+}
+
 impl_index_from_get!(Row, Channel);
 
 
@@ -889,14 +1211,16 @@ impl From<Note> for u8 {
     }
 }
 
+/// Names of the semitones, the octave follows
+pub(crate) const NOTE_NAMES: [&[u8; 2]; 12] = [b"C-", b"C#", b"D-", b"D#", b"E-", b"F-", b"F#", b"G-", b"G#", b"A-", b"A#", b"B-"];
+
 /// Creates a formatted string for the note in the given buffer
 fn note_string(Note(idx): Note, buf: &mut [u8; 3]) -> &str {
     // This is just a sanity check for the macros. This invariant should be already enforced by the
     // Note type itself at the module boundary.
     assert!(idx < 120, "BUG: Note inner value is out of range of 0..=119");
 
-    const NAMES: [&[u8; 2]; 12] = [b"C-", b"C#", b"D-", b"D#", b"E-", b"F-", b"F#", b"G-", b"G#", b"A-", b"A#", b"B-"];
-    let name = NAMES[usize::from(idx % 12)];
+    let name = NOTE_NAMES[usize::from(idx % 12)];
     let octave = b'0' + (idx / 12);
 
     buf[0] = name[0];
@@ -926,7 +1250,56 @@ impl Note {
     pub fn freq(self) -> f32 {
         let (Note(idx), Note(base)) = (self, Note::A_4);
         let exp = (f32::from(idx) - f32::from(base)) / 12.0f32;
-        440.0f32 * 2.0f32.powf(exp)
+        440.0f32 * float::powf(2.0, exp)
+    }
+
+    /// Returns the frequency the note plays a sample with the C-5 speed at, in Hz.
+    ///
+    /// With linear slides ([`ModuleFlags::LINEAR_SLIDES`]) the pitch is equal tempered, every
+    /// semitone changes the frequency by `2^(1/12)`. Otherwise the frequency goes through the Amiga
+    /// period table the same way as in Impulse Tracker, the integer period is rounded so the
+    /// pitches differ slightly from equal temperament. C-5 plays at `c5_speed` in both modes.
+    pub fn frequency(self, c5_speed: u32, linear_slides: bool) -> f32 {
+        const PERIODS: [u64; 12] = [1712, 1616, 1524, 1440, 1356, 1280, 1208, 1140, 1076, 1016, 960, 907];
+        const AMIGA_CLOCK: u64 = 8363;
+
+        if linear_slides || c5_speed == 0 {
+            let semitones = f32::from(self.0) - f32::from(Note::C_5.0);
+            #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+            return c5_speed as f32 * float::powf(2.0, semitones / 12.0);
+        }
+        let period = AMIGA_CLOCK * 32 * PERIODS[usize::from(self.semitone())]
+            / (u64::from(c5_speed) << self.octave());
+        let frequency = AMIGA_CLOCK * PERIODS[0] / period.max(1);
+        // Frequencies are far below the range where `f32` loses integer precision.
+        #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+        { frequency as f32 }
+    }
+
+    /// Returns the MIDI note number, C-5 is MIDI note 60 (middle C).
+    pub fn midi_number(self) -> u8 {
+        self.0
+    }
+
+    /// Converts the MIDI note number, `None` for the notes above B-9 (MIDI notes 120 to 127).
+    pub fn from_midi(number: u8) -> Option<Note> {
+        Note::try_from(number).ok()
+    }
+
+    /// Returns the octave of the note, `0..=9`.
+    pub fn octave(self) -> u8 {
+        self.0 / 12
+    }
+
+    /// Returns the semitone of the note within its octave, `0` is C and `11` is B.
+    pub fn semitone(self) -> u8 {
+        self.0 % 12
+    }
+
+    /// Shifts the note by the semitones, clamped to the range C-0 to B-9.
+    pub fn transposed(self, semitones: i8) -> Note {
+        let note = (i16::from(self.0) + i16::from(semitones)).clamp(0, i16::from(Note::B_9.0));
+        Note(u8::try_from(note).unwrap())
     }
 }
 
@@ -985,6 +1358,50 @@ impl VolumeCmd {
     }
 }
 
+/// Decodes the volume column byte
+///
+/// Fails for values in the gaps between the command ranges.
+impl TryFrom<u8> for VolumeCmd {
+    type Error = InvalidVolumeError;
+
+    fn try_from(x: u8) -> Result<Self, Self::Error> {
+        let param = |base: u8| (x > base).then(|| RangedU8::try_from(x - base).unwrap());
+        let volume = match x {
+              0 ..=  64 => VolumeCmd::SetVolume(RangedU8::try_from(x).unwrap()),
+            128 ..= 192 => VolumeCmd::Panning(RangedU8::try_from(x - 128).unwrap()),
+             65 ..=  74 => VolumeCmd::FineVolumeUp(param(65)),
+             75 ..=  84 => VolumeCmd::FineVolumeDown(param(75)),
+             85 ..=  94 => VolumeCmd::VolumeSlideUp(param(85)),
+             95 ..= 104 => VolumeCmd::VolumeSlideDown(param(95)),
+            105 ..= 114 => VolumeCmd::PortamentoDown(param(105)),
+            115 ..= 124 => VolumeCmd::PortamentoUp(param(115)),
+            193 ..= 202 => VolumeCmd::TonePortamento(param(193)),
+            203 ..= 212 => VolumeCmd::Vibrato(param(203)),
+            _ => return Err(InvalidVolumeError(x)),
+        };
+        Ok(volume)
+    }
+}
+
+/// Encodes the volume column byte
+impl From<VolumeCmd> for u8 {
+    fn from(volume: VolumeCmd) -> u8 {
+        let param = |param: Option<RangedU8<1, 9>>| param.map_or(0, RangedU8::as_u8);
+        match volume {
+            VolumeCmd::SetVolume(volume) => volume.as_u8(),
+            VolumeCmd::Panning(panning) => 128 + panning.as_u8(),
+            VolumeCmd::FineVolumeUp(p) => 65 + param(p),
+            VolumeCmd::FineVolumeDown(p) => 75 + param(p),
+            VolumeCmd::VolumeSlideUp(p) => 85 + param(p),
+            VolumeCmd::VolumeSlideDown(p) => 95 + param(p),
+            VolumeCmd::PortamentoDown(p) => 105 + param(p),
+            VolumeCmd::PortamentoUp(p) => 115 + param(p),
+            VolumeCmd::TonePortamento(p) => 193 + param(p),
+            VolumeCmd::Vibrato(p) => 203 + param(p),
+        }
+    }
+}
+
 impl EffectCmd {
     pub fn category(&self) -> EffectCategory {
         match self {
@@ -1031,4 +1448,314 @@ impl EffectCmd {
             _ => EffectCategory::Misc,
         }
     }
+
+    /// Parse structured effect from raw effect number and parameter
+    ///
+    /// Same as [`parser::effect`](crate::parser::effect), returns `None` for unknown effects and
+    /// for effects which are skipped by the canonicalization.
+    pub fn from_raw(effect: u8, param: u8) -> Option<EffectCmd> {
+        crate::parser::effect(effect, param)
+    }
+
+    /// Encode structured effect into raw effect number and parameter
+    ///
+    /// This is the inverse of [`EffectCmd::from_raw`], parsing the result gives back the same
+    /// effect for every effect the parser can produce.
+    ///
+    /// Values the parser never produces are encoded as the raw value they correspond to, which may
+    /// parse differently, e.g. `EffectCmd::Arpeggio(Some((0, 0)))` is encoded as `J00` which is
+    /// parsed as `EffectCmd::Arpeggio(None)`.
+    pub fn to_raw(self) -> (u8, u8) {
+        // Effects are numbered 0x1..=0x1A, see `parse_effect` for the conversion from letters.
+        let code = |letter: u8| letter - b'A' + 1;
+        let nibbles = |x: u8, y: u8| (x << 4) | y;
+        let opt = |value: Option<u8>| value.unwrap_or(0);
+
+        let (letter, param) = match self {
+            EffectCmd::SetSpeed(speed) => (b'A', speed.as_u8()),
+            EffectCmd::JumpOrder(order) => (b'B', order),
+            EffectCmd::BreakRow(row) => (b'C', row),
+            EffectCmd::VolumeSlide(slide) => (b'D', raw_volume_slide(slide)),
+            EffectCmd::PortamentoDown(portamento) => (b'E', raw_portamento(portamento)),
+            EffectCmd::PortamentoUp(portamento) => (b'F', raw_portamento(portamento)),
+            EffectCmd::TonePortamento(speed) => (b'G', opt(speed.map(RangedU8::as_u8))),
+            EffectCmd::Vibrato(speed, depth) => (
+                b'H',
+                nibbles(opt(speed.map(RangedU8::as_u8)), opt(depth.map(RangedU8::as_u8))),
+            ),
+            EffectCmd::Tremor(times) => (
+                b'I',
+                times.map_or(0, |(on, off)| nibbles(on.as_u8(), off.as_u8())),
+            ),
+            EffectCmd::Arpeggio(notes) => (
+                b'J',
+                notes.map_or(0, |(x, y)| nibbles(x.as_u8(), y.as_u8())),
+            ),
+            EffectCmd::VolumeSlideAndVibrato(slide) => (b'K', raw_volume_slide(slide)),
+            EffectCmd::VolumeSlideAndPortamento(slide) => (b'L', raw_volume_slide(slide)),
+            EffectCmd::SetChannelVolume(volume) => (b'M', volume.as_u8()),
+            EffectCmd::ChannelVolumeSlide(slide) => (b'N', raw_volume_slide(slide)),
+            EffectCmd::SetSampleOffset(SetSampleOffset::Low(offset)) => (b'O', offset),
+            EffectCmd::SetSampleOffset(SetSampleOffset::High(offset)) => (b'S', nibbles(0xA, offset.as_u8())),
+            EffectCmd::PanningSlide(slide) => (b'P', match slide {
+                None => 0x00,
+                Some(PanningSlide::Left(p)) => nibbles(p.as_u8(), 0x0),
+                Some(PanningSlide::Right(p)) => nibbles(0x0, p.as_u8()),
+                Some(PanningSlide::FineLeft(p)) => nibbles(p.as_u8(), 0xF),
+                Some(PanningSlide::FineRight(p)) => nibbles(0xF, p.as_u8()),
+            }),
+            EffectCmd::Retrigger(retrigger) => (
+                b'Q',
+                retrigger.map_or(0, |(x, y)| nibbles(x.as_u8(), y.as_u8())),
+            ),
+            EffectCmd::Tremolo(speed, depth) => (
+                b'R',
+                nibbles(opt(speed.map(RangedU8::as_u8)), opt(depth.map(RangedU8::as_u8))),
+            ),
+            EffectCmd::Special(special) => (b'S', special.map_or(0x00, raw_special)),
+            EffectCmd::Tempo(tempo) => (b'T', match tempo {
+                None => 0x00,
+                Some(Tempo::SlideDown(y)) => nibbles(0x0, y.as_u8()),
+                Some(Tempo::SlideUp(y)) => nibbles(0x1, y.as_u8()),
+                Some(Tempo::Set(tempo)) => tempo.as_u8(),
+            }),
+            EffectCmd::FineVibrato(speed, depth) => (
+                b'U',
+                nibbles(opt(speed.map(RangedU8::as_u8)), opt(depth.map(RangedU8::as_u8))),
+            ),
+            EffectCmd::SetGlobalVolume(volume) => (b'V', volume.as_u8()),
+            EffectCmd::GlobalVolumeSlide(slide) => (b'W', raw_volume_slide(slide)),
+            EffectCmd::SetPanningPosition(panning) => (b'X', panning),
+            EffectCmd::Panbrello(speed, depth) => (
+                b'Y',
+                nibbles(opt(speed.map(RangedU8::as_u8)), opt(depth.map(RangedU8::as_u8))),
+            ),
+            EffectCmd::Midi(param) => (b'Z', param),
+        };
+
+        (code(letter), param)
+    }
+}
+
+impl From<EffectCmd> for (u8, u8) {
+    fn from(effect: EffectCmd) -> (u8, u8) {
+        effect.to_raw()
+    }
+}
+
+fn raw_volume_slide(slide: Option<VolumeSlide>) -> u8 {
+    match slide {
+        None => 0x00,
+        Some(VolumeSlide::Up(p)) => p.as_u8() << 4,
+        Some(VolumeSlide::Down(p)) => p.as_u8(),
+        Some(VolumeSlide::FineUp(p)) => p.as_u8() << 4 | 0xF,
+        Some(VolumeSlide::FineDown(p)) => 0xF0 | p.as_u8(),
+    }
+}
+
+fn raw_portamento(portamento: Option<Portamento>) -> u8 {
+    match portamento {
+        None => 0x00,
+        Some(Portamento::Coarse(p)) => p.as_u8(),
+        Some(Portamento::Fine(p)) => 0xF0 | p.as_u8(),
+        Some(Portamento::ExtraFine(p)) => 0xE0 | p.as_u8(),
+    }
+}
+
+fn raw_special(special: Special) -> u8 {
+    let waveform = |waveform| match waveform {
+        Waveform::Sine => 0x0,
+        Waveform::Sawtooth => 0x1,
+        Waveform::Square => 0x2,
+        Waveform::Random => 0x3,
+    };
+    let (x, y) = match special {
+        Special::SetGlissando(on) => (0x1, u8::from(on)),
+        Special::SetFinetune(y) => (0x2, y.as_u8()),
+        Special::SetVibratoWaveform(w) => (0x3, waveform(w)),
+        Special::SetTremoloWaveform(w) => (0x4, waveform(w)),
+        Special::SetPanbrelloWaveform(w) => (0x5, waveform(w)),
+        Special::PatternTickDelay(y) => (0x6, y.as_u8()),
+        Special::PastNote(SetPastNote::Cut) => (0x7, 0x0),
+        Special::PastNote(SetPastNote::Off) => (0x7, 0x1),
+        Special::PastNote(SetPastNote::Fade) => (0x7, 0x2),
+        Special::SetNewNoteAction(SetNewNoteAction::Cut) => (0x7, 0x3),
+        Special::SetNewNoteAction(SetNewNoteAction::Continue) => (0x7, 0x4),
+        Special::SetNewNoteAction(SetNewNoteAction::Off) => (0x7, 0x5),
+        Special::SetNewNoteAction(SetNewNoteAction::Fade) => (0x7, 0x6),
+        Special::SetVolumeEnvelope(on) => (0x7, 0x7 + u8::from(on)),
+        Special::SetPanningEnvelope(on) => (0x7, 0x9 + u8::from(on)),
+        Special::SetPitchEnvelope(on) => (0x7, 0xB + u8::from(on)),
+        Special::SetPanning(y) => (0x8, y.as_u8()),
+        Special::SetSurround(on) => (0x9, u8::from(on)),
+        Special::SetReverb(on) => (0x9, 0x8 + u8::from(on)),
+        Special::SetSurroundMode(SurroundMode::Center) => (0x9, 0xA),
+        Special::SetSurroundMode(SurroundMode::Quad) => (0x9, 0xB),
+        Special::SetFilterMode(FilterMode::Global) => (0x9, 0xC),
+        Special::SetFilterMode(FilterMode::Local) => (0x9, 0xD),
+        Special::SetDirection(PlayDirection::Forward) => (0x9, 0xE),
+        Special::SetDirection(PlayDirection::Backward) => (0x9, 0xF),
+        Special::SetLoopbackPoint => (0xB, 0x0),
+        Special::LoopbackTimes(y) => (0xB, y.as_u8()),
+        Special::NoteCut(y) => (0xC, y.as_u8()),
+        Special::NoteDelay(y) => (0xD, y.as_u8()),
+        Special::PatternRowDelay(y) => (0xE, y.as_u8()),
+        Special::SetMidiParam(y) => (0xF, y.as_u8()),
+    };
+    x << 4 | y
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn effect_raw_roundtrip() {
+        for param in 0..=0xFF {
+            assert_eq!(EffectCmd::from_raw(0, param), None);
+            for effect in 27..=0xFF {
+                assert_eq!(EffectCmd::from_raw(effect, param), None);
+            }
+        }
+
+        // Every effect letter parses to some effect and every parsed effect encodes losslessly.
+        for effect in 1..=26 {
+            let parsed = (0..=0xFF).filter_map(|param| EffectCmd::from_raw(effect, param)).collect::<Vec<_>>();
+            assert!(!parsed.is_empty(), "no parameter of effect {} parses", effect);
+            for cmd in parsed {
+                assert_eq!(EffectCmd::from_raw(cmd.to_raw().0, cmd.to_raw().1), Some(cmd));
+            }
+        }
+    }
+
+    #[test]
+    fn note_conversions() {
+        assert_eq!(Note::C_5.midi_number(), 60);
+        assert_eq!((Note::Gs4.octave(), Note::Gs4.semitone()), (4, 8));
+        assert!(matches!(Note::from_midi(69), Some(note) if u8::from(note) == u8::from(Note::A_5)));
+        assert!(Note::from_midi(120).is_none());
+
+        for linear in [false, true] {
+            assert_eq!(Note::C_5.frequency(8363, linear), 8363.0);
+            assert!((Note::C_6.frequency(8363, linear) - 16726.0).abs() < 1.0);
+        }
+        // The Amiga periods are rounded, A-4 is slightly sharp.
+        assert!((Note::A_4.frequency(8363, true) - 7032.4).abs() < 0.1);
+        assert_eq!(Note::A_4.frequency(8363, false), 7045.0);
+    }
+
+    #[test]
+    fn set_command() {
+        let command = |volume: u8| Command {
+            note: None,
+            instrument: None,
+            volume: Some(VolumeCmd::SetVolume(RangedU8::try_from(volume).unwrap())),
+            effect: None,
+        };
+        let volume = |command: Option<&Command>| match command.and_then(|command| command.volume) {
+            Some(VolumeCmd::SetVolume(volume)) => Some(volume.as_u8()),
+            _ => None,
+        };
+
+        let mut pattern = Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: vec![Row::empty(); 4],
+            truncated: false,
+            highlight: None,
+        };
+        assert!(pattern.set_command(2, Channel::new(5), command(10)).is_none());
+        assert!(pattern.set_command(2, Channel::new(1), command(20)).is_none());
+        assert_eq!(volume(pattern.set_command(2, Channel::new(5), command(30)).as_ref()), Some(10));
+
+        let row = pattern.row(2).unwrap();
+        assert_eq!(row.iter().map(|(channel, _)| channel).collect::<Vec<_>>(), [Channel::new(1), Channel::new(5)]);
+        assert_eq!(volume(pattern.command(2, Channel::new(5))), Some(30));
+        assert_eq!(volume(pattern.command(1, Channel::new(5))), None);
+        assert_eq!(pattern.active_channels, ActiveChannels::new([Channel::new(1), Channel::new(5)]));
+    }
+
+    #[test]
+    fn edit_cells() {
+        let mut pattern = Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: vec![Row::empty(); 4],
+            truncated: false,
+            highlight: None,
+        };
+        pattern.set_note(1, Channel::new(3), NoteCmd::Cut);
+        pattern.set_effect(1, Channel::new(3), EffectCmd::BreakRow(0));
+        pattern.set_effect(2, Channel::new(3), EffectCmd::BreakRow(1));
+        let command = pattern.command(1, Channel::new(3)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Cut)));
+        assert_eq!(command.effect, Some(EffectCmd::BreakRow(0)));
+        assert_eq!(pattern.active_channels, ActiveChannels::new([Channel::new(3)]));
+
+        assert!(pattern.clear_cell(1, Channel::new(3)).is_some());
+        assert!(pattern.clear_cell(1, Channel::new(3)).is_none());
+        assert_eq!(pattern.active_channels, ActiveChannels::new([Channel::new(3)]));
+        assert!(pattern.clear_cell(2, Channel::new(3)).is_some());
+        assert_eq!(pattern.active_channels, ActiveChannels::empty());
+    }
+
+    #[test]
+    fn resize_split_concat() {
+        let mut pattern = Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: vec![Row::empty(); 4],
+            truncated: false,
+            highlight: None,
+        };
+        pattern.set_effect(0, Channel::new(1), EffectCmd::BreakRow(0));
+        pattern.set_effect(3, Channel::new(2), EffectCmd::BreakRow(0));
+
+        let (first, second) = pattern.split_at(1).unwrap();
+        assert_eq!((first.rows.len(), second.rows.len()), (1, 3));
+        assert_eq!(first.active_channels, ActiveChannels::new([Channel::new(1)]));
+        assert_eq!(second.command(2, Channel::new(2)).and_then(|command| command.effect), Some(EffectCmd::BreakRow(0)));
+        assert_eq!(pattern.split_at(4).unwrap_err(), InvalidRowCountError { rows: 0 });
+
+        let joined = second.concat(&first).unwrap();
+        assert_eq!(joined.rows.len(), 4);
+        assert!(joined.command(3, Channel::new(1)).is_some());
+        assert_eq!(joined.active_channels, pattern.active_channels);
+
+        pattern.resize(2).unwrap();
+        assert_eq!(pattern.active_channels, ActiveChannels::new([Channel::new(1)]));
+        pattern.resize(Pattern::MAX_ROWS).unwrap();
+        assert_eq!(pattern.rows.len(), 200);
+        assert_eq!(pattern.concat(&first).unwrap_err(), InvalidRowCountError { rows: 201 });
+        assert!(pattern.resize(0).is_err());
+    }
+
+    #[test]
+    fn expand_shrink() {
+        let mut pattern = Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: vec![Row::empty(); 3],
+            truncated: false,
+            highlight: None,
+        };
+        pattern.set_note(0, Channel::new(1), NoteCmd::Play(Note::C_5));
+        pattern.set_effect(1, Channel::new(1), EffectCmd::BreakRow(0));
+        pattern.set_note(1, Channel::new(1), NoteCmd::Off);
+        pattern.set_effect(2, Channel::new(2), EffectCmd::SetSpeed(RangedU8::MIN));
+
+        let expanded = pattern.expand().unwrap();
+        assert_eq!(expanded.rows.len(), 6);
+        assert!(expanded.rows[1].is_empty() && expanded.rows[5].is_empty());
+        assert_eq!(expanded.command(4, Channel::new(2)).and_then(|command| command.effect), Some(EffectCmd::SetSpeed(RangedU8::MIN)));
+        assert_eq!(format!("{:?}", expanded.shrink().rows), format!("{:?}", pattern.rows));
+
+        // The note of the first row wins, the effect comes from the second one.
+        let shrunk = pattern.shrink();
+        assert_eq!(shrunk.rows.len(), 2);
+        let command = shrunk.command(0, Channel::new(1)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == u8::from(Note::C_5)));
+        assert_eq!(command.effect, Some(EffectCmd::BreakRow(0)));
+        assert_eq!(shrunk.active_channels, pattern.active_channels);
+
+        pattern.resize(101).unwrap();
+        assert_eq!(pattern.expand().unwrap_err(), InvalidRowCountError { rows: 202 });
+    }
 }
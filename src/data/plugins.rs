@@ -0,0 +1,232 @@
+use super::*;
+use core::convert::TryInto;
+use core::ops::Range;
+
+
+/// Code of the extended instrument property with the plugin of the instrument
+const INSTRUMENT_PLUGIN: [u8; 4] = *b"MiP.";
+
+/// Size of the plugin information at the start of a `FX??` chunk
+const PLUGIN_INFO_SIZE: usize = 128;
+
+
+/// Plugin of the OpenMPT mixer, see [`Module::plugins`]
+///
+/// *OpenMPT extension.* Only the information at the start of the chunk is decoded, the settings of
+/// the plugin are kept as they are stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PluginSlot {
+    /// Slot of the plugin, counted from `0` (OpenMPT shows `FX01` for the first one)
+    pub slot: u8,
+
+    /// Identifiers of the plugin, the type and the unique ID for VST plugins
+    pub id: [u32; 2],
+
+    /// Name given to the plugin in the module
+    pub name: String,
+
+    /// Name of the library implementing the plugin
+    pub library: String,
+
+    /// Routing flags, e.g. bypass or the dry mix, OpenMPT's bits
+    pub routing_flags: u8,
+
+    /// Mix mode, OpenMPT's numbering
+    pub mix_mode: u8,
+
+    /// Gain, `10` is unity
+    pub gain: u8,
+
+    /// Slot of the plugin the output goes to, `None` for the master output
+    pub output: Option<u8>,
+
+    /// Settings of the plugin and the extra properties, as stored after the information
+    pub settings: Vec<u8>,
+}
+
+/// Iterator over the chunks of a block of header chunks, see [`header_chunks`]
+pub(crate) struct HeaderChunks<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Iterator for HeaderChunks<'a> {
+    /// Code and range of the whole chunk in the block
+    type Item = ([u8; 4], Range<usize>);
+
+    fn next(&mut self) -> Option<([u8; 4], Range<usize>)> {
+        let chunk = self.data.get(self.offset..)?;
+        let header = chunk.get(..8)?;
+        let length = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+        let end = usize::try_from(length).ok()?.checked_add(8).filter(|&end| end <= chunk.len())?;
+        let range = self.offset..self.offset + end;
+        self.offset = range.end;
+        Some((header[..4].try_into().unwrap(), range))
+    }
+}
+
+/// Returns the chunks of the block, each is a 4 byte code and a 32-bit length followed by the
+/// data. The iteration stops at the first chunk which doesn't fit.
+pub(crate) fn header_chunks(data: &[u8]) -> HeaderChunks<'_> {
+    HeaderChunks { data, offset: 0 }
+}
+
+
+impl Module {
+    /// Returns the plugins of the OpenMPT mixer stored in the `FX00` to `FX99` header chunks.
+    ///
+    /// *OpenMPT extension.* The chunks are kept in [`OpaqueData::header_chunks`] and written back
+    /// unchanged, chunks too short for the plugin information are skipped. See
+    /// [`Module::channel_plugins`] and [`Instrument::plugin`] for the routing.
+    pub fn plugins(&self) -> Vec<PluginSlot> {
+        let chunks = &self.opaque.header_chunks;
+        header_chunks(chunks)
+            .filter_map(|(code, range)| Some((plugin_slot(code)?, &chunks[range.start + 8..range.end])))
+            .filter_map(|(slot, data)| {
+                let info = data.get(..PLUGIN_INFO_SIZE)?;
+                let u32_at = |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+                let text = |range: Range<usize>| String::from_utf8_lossy(null_terminated(&info[range])).into_owned();
+                let output = u32_at(12);
+                Some(PluginSlot {
+                    slot,
+                    id: [u32_at(0), u32_at(4)],
+                    name: text(32..64),
+                    library: text(64..128),
+                    routing_flags: info[8],
+                    mix_mode: info[9],
+                    gain: info[10],
+                    output: output.checked_sub(0x80).and_then(|slot| u8::try_from(slot).ok()),
+                    settings: data[PLUGIN_INFO_SIZE..].to_vec(),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the plugin slot of every channel stored in the `CHFX` header chunk, `None` for the
+    /// channels which are not routed to a plugin.
+    ///
+    /// *OpenMPT extension.* Empty if the module has no channel plugins.
+    pub fn channel_plugins(&self) -> Vec<Option<u8>> {
+        let chunks = &self.opaque.header_chunks;
+        header_chunks(chunks)
+            .find(|(code, _)| code == b"CHFX")
+            .map(|(_, range)| {
+                chunks[range.start + 8..range.end]
+                    .chunks_exact(4)
+                    .map(|value| plugin_number(u32::from_le_bytes(value.try_into().unwrap())))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Removes the plugins, the channel plugins and the plugin of every instrument, returns the
+    /// number of plugins removed.
+    ///
+    /// The other header chunks are kept. The effects controlling plugins stay in the patterns.
+    pub fn strip_plugins(&mut self) -> usize {
+        let chunks = &self.opaque.header_chunks;
+        let mut kept = Vec::new();
+        let mut removed = 0;
+        let mut end = 0;
+        for (code, range) in header_chunks(chunks) {
+            end = range.end;
+            if plugin_slot(code).is_some() {
+                removed += 1;
+            } else if &code != b"CHFX" {
+                kept.extend_from_slice(&chunks[range]);
+            }
+        }
+        kept.extend_from_slice(&chunks[end..]);
+        self.opaque.header_chunks = kept;
+
+        for instrument in &mut self.instruments {
+            if let Some(extensions) = &mut instrument.openmpt_extensions {
+                extensions.other.retain(|property| property.code != INSTRUMENT_PLUGIN);
+            }
+        }
+        removed
+    }
+}
+
+impl Instrument {
+    /// Returns the plugin slot the instrument plays through, stored in the `MiP.` extended
+    /// property.
+    ///
+    /// *OpenMPT extension.* `None` if the instrument isn't routed to a plugin.
+    pub fn plugin(&self) -> Option<u8> {
+        let extensions = self.openmpt_extensions.as_ref()?;
+        let property = extensions.other.iter().find(|property| property.code == INSTRUMENT_PLUGIN)?;
+        let mut value = [0; 4];
+        let length = property.data.len().min(4);
+        value[..length].copy_from_slice(&property.data[..length]);
+        plugin_number(u32::from_le_bytes(value))
+    }
+}
+
+
+/// Returns the slot of a `FX00` to `FX99` chunk code.
+fn plugin_slot(code: [u8; 4]) -> Option<u8> {
+    match code {
+        [b'F', b'X', tens @ b'0'..=b'9', ones @ b'0'..=b'9'] => Some((tens - b'0') * 10 + (ones - b'0')),
+        _ => None,
+    }
+}
+
+/// Converts a plugin number counted from `1` to a slot, `0` is no plugin.
+fn plugin_number(number: u32) -> Option<u8> {
+    number.checked_sub(1).and_then(|slot| u8::try_from(slot).ok())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::formats::empty_instrument;
+
+    fn chunk(out: &mut Vec<u8>, code: &[u8; 4], data: &[u8]) {
+        out.extend_from_slice(code);
+        out.extend_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+        out.extend_from_slice(data);
+    }
+
+    #[test]
+    fn plugins() {
+        let mut info = vec![0; PLUGIN_INFO_SIZE];
+        info[..4].copy_from_slice(b"VstP");
+        info[4..8].copy_from_slice(&0x1234u32.to_le_bytes());
+        info[10] = 10;
+        info[12] = 0x81;
+        info[32..37].copy_from_slice(b"Delay");
+        info[64..73].copy_from_slice(b"delay.dll");
+        info.extend_from_slice(&[4, 0, 0, 0, 1, 2, 3, 4]);
+
+        let mut chunks = Vec::new();
+        chunk(&mut chunks, b"FX02", &info);
+        chunk(&mut chunks, b"CHFX", &[0, 0, 0, 0, 3, 0, 0, 0]);
+        chunk(&mut chunks, b"FX01", &[0; 16]);
+        chunk(&mut chunks, b"XYZ.", &[1]);
+        chunks.push(0xFF);
+
+        let mut module = ModuleBuilder::new().build().unwrap();
+        module.opaque.header_chunks = chunks;
+        let mut instrument = empty_instrument();
+        instrument.openmpt_extensions = Some(InstrumentExtensions {
+            other: vec![ExtensionProperty { code: INSTRUMENT_PLUGIN, data: vec![3] }],
+            ..InstrumentExtensions::default()
+        });
+        module.instruments.push(instrument);
+
+        let plugins = module.plugins();
+        assert_eq!(plugins.len(), 1);
+        assert_eq!((plugins[0].slot, plugins[0].id, plugins[0].output), (2, [u32::from_le_bytes(*b"VstP"), 0x1234], Some(1)));
+        assert_eq!((plugins[0].name.as_str(), plugins[0].library.as_str()), ("Delay", "delay.dll"));
+        assert_eq!(plugins[0].settings, [4, 0, 0, 0, 1, 2, 3, 4]);
+        assert_eq!(module.channel_plugins(), [None, Some(2)]);
+        assert_eq!(module.instruments[0].plugin(), Some(2));
+
+        assert_eq!(module.strip_plugins(), 2);
+        assert_eq!(module.opaque.header_chunks, [b'X', b'Y', b'Z', b'.', 1, 0, 0, 0, 1, 0xFF]);
+        assert!(module.channel_plugins().is_empty());
+        assert_eq!(module.instruments[0].plugin(), None);
+    }
+}
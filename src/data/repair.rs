@@ -0,0 +1,223 @@
+use super::*;
+use crate::error::InvalidOrderError;
+
+
+/// Fix applied by [`Module::repair`]
+#[derive(Clone, Copy, Debug)]
+pub enum Repair {
+    /// Order referencing a pattern which is not present in the module was removed
+    RemovedOrder(InvalidOrderError),
+
+    /// Sample map entry playing a sample which is not present in the module was cleared
+    ClearedSampleMapEntry {
+        instrument: InstrumentId,
+        note: Note,
+        sample: SampleId,
+    },
+
+    /// Loop ending past the end of the sample data was shortened to end with the data, `None` if
+    /// it was removed because it was empty
+    TruncatedSampleLoop {
+        sample: SampleId,
+
+        /// The loop is the sustain loop
+        sustain: bool,
+        old: SampleLoop,
+        new: Option<SampleLoop>,
+    },
+
+    /// Default or global volume of the sample above `64` was clipped, `value` is the old value
+    ClampedSampleVolume {
+        sample: SampleId,
+
+        /// The volume is the global volume
+        global: bool,
+        value: u8,
+    },
+
+    /// Global volume of the instrument above `128` was clipped, `value` is the old value
+    ClampedInstrumentVolume {
+        instrument: InstrumentId,
+        value: u8,
+    },
+
+    /// Initial channel panning which was neither a position nor surround was clipped, `value` is
+    /// the old value
+    ClampedChannelPan {
+        channel: Channel,
+        value: u8,
+    },
+
+    /// Text after a null character, trailing whitespace and control characters or the text
+    /// Impulse Tracker can't load were removed from the message
+    TruncatedMessage {
+        old_length: usize,
+        new_length: usize,
+    },
+}
+
+impl Module {
+    /// Applies safe fixes of common corruptions and returns the list of the fixes applied.
+    ///
+    /// Fixes the out of range values and the dangling references Impulse Tracker would ignore or
+    /// clip when loading the module, so the module plays the same after the repair:
+    ///
+    /// - orders referencing missing patterns are removed, `Bxx` jumps are moved to keep jumping to
+    ///   the same entries,
+    /// - sample map entries playing missing samples are cleared,
+    /// - sample loops are shortened to the length of the sample, empty loops are removed,
+    /// - sample volumes above `64`, instrument global volumes above `128` and initial channel
+    ///   panning values out of range are clipped,
+    /// - the message is cut at the first null character, stripped of trailing whitespace and
+    ///   control characters and shortened to the length Impulse Tracker can load.
+    ///
+    /// Problems which can't be fixed without guessing the intent, like invalid envelopes or
+    /// commands using missing instruments, are left for [`Module::validate`] to report.
+    pub fn repair(&mut self) -> Vec<Repair> {
+        let mut repairs = Vec::new();
+
+        let patterns = self.patterns.len();
+        let keep = self.orders.iter()
+            .enumerate()
+            .map(|(position, order)| match *order {
+                Order::Index(pattern) if usize::from(pattern.as_u8()) >= patterns => {
+                    repairs.push(Repair::RemovedOrder(InvalidOrderError { position, pattern }));
+                    false
+                }
+                _ => true,
+            })
+            .collect::<Vec<_>>();
+        self.remove_orders(keep);
+
+        let samples = self.samples.len();
+        for (instrument_id, instrument) in self.instruments.iter_mut().enumerate() {
+            let instrument_id = InstrumentId::try_from(u8::try_from(instrument_id).unwrap()).unwrap();
+            for (note, entry) in instrument.sample_map.map.iter_mut().enumerate() {
+                if let Some(sample) = entry.filter(|sample| usize::from(sample.as_u8()) >= samples) {
+                    let note = Note::try_from(u8::try_from(note).unwrap()).unwrap();
+                    repairs.push(Repair::ClearedSampleMapEntry { instrument: instrument_id, note, sample });
+                    *entry = None;
+                }
+            }
+            if instrument.global_volume > 128 {
+                let value = instrument.global_volume;
+                repairs.push(Repair::ClampedInstrumentVolume { instrument: instrument_id, value });
+                instrument.global_volume = 128;
+            }
+        }
+
+        for (sample_id, sample) in self.samples.iter_mut().enumerate() {
+            let sample_id = SampleId::try_from(u8::try_from(sample_id).unwrap()).unwrap();
+            let length = sample.length();
+            for (sustain, sample_loop) in [(false, &mut sample.loop_), (true, &mut sample.sustain_loop)] {
+                if let Some(old) = sample_loop.filter(|old| old.end > length) {
+                    let new = Some(SampleLoop { end: length, ..old }).filter(|new| new.start < new.end);
+                    repairs.push(Repair::TruncatedSampleLoop { sample: sample_id, sustain, old, new });
+                    *sample_loop = new;
+                }
+            }
+            for (global, volume) in [(false, &mut sample.default_volume), (true, &mut sample.global_volume)] {
+                if *volume > 64 {
+                    repairs.push(Repair::ClampedSampleVolume { sample: sample_id, global, value: *volume });
+                    *volume = 64;
+                }
+            }
+        }
+
+        for (channel, pan) in self.init_channel_panning.iter_mut().enumerate() {
+            if !pan.is_valid() {
+                let channel = Channel::from_u8_index(u8::try_from(channel).unwrap());
+                repairs.push(Repair::ClampedChannelPan { channel, value: u8::from(*pan) });
+                *pan = ChannelPan::new(pan.pan(), pan.is_enabled());
+            }
+        }
+
+        let old_length = self.message.len();
+        let mut message = self.message.split('\0').next().unwrap_or_default();
+        if message.len() > MAX_MESSAGE_LENGTH {
+            let end = (0..=MAX_MESSAGE_LENGTH).rev().find(|&end| message.is_char_boundary(end)).unwrap_or(0);
+            message = &message[..end];
+        }
+        let message = message.trim_end_matches(|c: char| c.is_whitespace() || c.is_control());
+        if message.len() != old_length {
+            repairs.push(Repair::TruncatedMessage { old_length, new_length: message.len() });
+            self.message = String::from(message);
+        }
+
+        repairs
+    }
+}
+
+impl fmt::Display for Repair {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Repair::RemovedOrder(error) => {
+                write!(f, "removed order {} referencing missing pattern {:?}", error.position, error.pattern)
+            }
+            Repair::ClearedSampleMapEntry { instrument, note, sample } => {
+                write!(f, "cleared note {:?} of instrument {:?} playing missing sample {:?}", note, instrument, sample)
+            }
+            Repair::TruncatedSampleLoop { sample, sustain, old, new } => {
+                let kind = if *sustain { "sustain loop" } else { "loop" };
+                match new {
+                    Some(new) => write!(
+                        f,
+                        "shortened {} {}..{} of sample {:?} to {}..{}",
+                        kind, old.start, old.end, sample, new.start, new.end,
+                    ),
+                    None => write!(f, "removed empty {} {}..{} of sample {:?}", kind, old.start, old.end, sample),
+                }
+            }
+            Repair::ClampedSampleVolume { sample, global, value } => {
+                let kind = if *global { "global volume" } else { "default volume" };
+                write!(f, "clipped {} {} of sample {:?} to 64", kind, value, sample)
+            }
+            Repair::ClampedInstrumentVolume { instrument, value } => {
+                write!(f, "clipped global volume {} of instrument {:?} to 128", value, instrument)
+            }
+            Repair::ClampedChannelPan { channel, value } => write!(f, "clipped panning {} of {:?}", value, channel),
+            Repair::TruncatedMessage { old_length, new_length } => {
+                write!(f, "shortened message from {} to {} bytes", old_length, new_length)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+    use alloc::string::ToString;
+
+    #[test]
+    fn repair() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        assert!(module.clone().repair().is_empty());
+
+        let missing = Order::Index(PatternId::try_from(5).unwrap());
+        module.orders.insert(0, missing);
+        module.samples[0].data = Some(vec![0.0; 100].into());
+        let length = module.samples[0].length();
+        module.samples[0].loop_ = Some(SampleLoop { start: 0, end: length + 10, bidi: false });
+        module.samples[0].sustain_loop = Some(SampleLoop { start: length, end: length + 1, bidi: false });
+        module.samples[0].global_volume = 70;
+        module.init_channel_panning[2] = ChannelPan::from(0xFF);
+        module.message = String::from("song\r \0garbage");
+        let repairs = module.repair().iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(repairs, [
+            String::from("removed order 0 referencing missing pattern 5"),
+            format!("shortened loop 0..{} of sample 0 to 0..{}", length + 10, length),
+            format!("removed empty sustain loop {}..{} of sample 0", length, length + 1),
+            String::from("clipped global volume 70 of sample 0 to 64"),
+            String::from("clipped panning 255 of ch03"),
+            String::from("shortened message from 14 to 4 bytes"),
+        ]);
+        assert!(module.validate().is_empty());
+        assert!(!module.orders.contains(&missing));
+        assert_eq!(u8::from(module.init_channel_panning[2]), 64 | 0x80);
+        assert_eq!(module.message, "song");
+    }
+}
@@ -0,0 +1,166 @@
+use super::*;
+use core::f32::consts::PI;
+
+
+/// Number of zero crossings of the windowed sinc on each side of the centre
+const SINC_ZEROS: f32 = 8.0;
+
+
+/// Interpolation used by [`Sample::resample`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResampleQuality {
+    /// Takes the nearest sample value, fast but aliases and adds noise
+    Nearest,
+
+    /// Interpolates linearly between the two nearest values
+    Linear,
+
+    /// Windowed sinc (Lanczos) interpolation of 8 zero crossings on each side, low-pass filtered
+    /// when the rate is lowered
+    Sinc,
+}
+
+
+impl Sample {
+    /// Converts the sample data to a different sample rate.
+    ///
+    /// The data is interpolated as selected by `quality`, the C-5 speed is set to `target_rate`
+    /// and the loop points are scaled by the same ratio as the data, so the sample plays at the
+    /// same pitch and loops over the same audio as before. Loops are kept at least one sample
+    /// long.
+    ///
+    /// Samples without data only get the new C-5 speed. Does nothing if either the C-5 speed or
+    /// `target_rate` is zero, or if the sample is an FM instrument.
+    pub fn resample(&mut self, target_rate: u32, quality: ResampleQuality) {
+        let rate = self.samplerate_c5;
+        if rate == 0 || target_rate == 0 || self.is_fm() {
+            return;
+        }
+        self.samplerate_c5 = target_rate;
+        let channels = usize::from(self.channels.as_u8());
+        let data = match &mut self.data {
+            Some(data) => data,
+            None => return,
+        };
+
+        let len = u32::try_from(data.len() / channels).unwrap_or(u32::MAX);
+        let scale = |position: u32| {
+            let scaled = (u64::from(position) * u64::from(target_rate) + u64::from(rate) / 2) / u64::from(rate);
+            u32::try_from(scaled).unwrap_or(u32::MAX)
+        };
+        let new_len = scale(len);
+        let step = f64::from(rate) / f64::from(target_rate);
+        *data = if channels == 1 {
+            resampled(data, new_len, step, quality).into()
+        } else {
+            // The channels are resampled one by one and interleaved again.
+            let resampled = (0..channels)
+                .map(|channel| {
+                    let values = data.iter().skip(channel).step_by(channels).copied().collect::<Vec<_>>();
+                    resampled(&values, new_len, step, quality)
+                })
+                .collect::<Vec<_>>();
+            (0..resampled[0].len()).flat_map(|index| resampled.iter().map(move |values| values[index])).collect()
+        };
+
+        for sample_loop in self.loop_.iter_mut().chain(self.sustain_loop.iter_mut()) {
+            let start = scale(sample_loop.start.min(len)).min(new_len.saturating_sub(1));
+            sample_loop.start = start;
+            sample_loop.end = scale(sample_loop.end.min(len)).clamp(start + 1, new_len.max(start + 1));
+        }
+    }
+}
+
+/// Interpolates `len` values, `step` is the distance between them in the source data.
+fn resampled(data: &[f32], len: u32, step: f64, quality: ResampleQuality) -> Vec<f32> {
+    let value = |index: isize| usize::try_from(index).ok().and_then(|index| data.get(index)).copied().unwrap_or(0.0);
+    // Filter cutoff relative to the source rate, lowered with the rate to avoid aliasing.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    let cutoff = (1.0 / step).min(1.0) as f32;
+    let width = SINC_ZEROS / cutoff;
+
+    (0..len)
+        .map(|index| {
+            let (index, fraction) = split_position(f64::from(index) * step);
+            match quality {
+                ResampleQuality::Nearest => value(index + isize::from(fraction >= 0.5)),
+                ResampleQuality::Linear => value(index) + (value(index + 1) - value(index)) * fraction,
+                ResampleQuality::Sinc => {
+                    // The taps reach `width` source samples to both sides, the cast cannot truncate.
+                    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+                    let taps = width as isize + 1;
+                    (index - taps + 1..=index + taps)
+                        .map(|tap| {
+                            #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+                            let x = (tap - index) as f32 - fraction;
+                            value(tap) * cutoff * lanczos(x * cutoff)
+                        })
+                        .sum()
+                }
+            }
+        })
+        .collect()
+}
+
+/// Returns the Lanczos kernel, the sinc windowed by a wider sinc.
+fn lanczos(x: f32) -> f32 {
+    if x == 0.0 {
+        1.0
+    } else if x.abs() >= SINC_ZEROS {
+        0.0
+    } else {
+        let x = PI * x;
+        SINC_ZEROS * float::sin(x) * float::sin(x / SINC_ZEROS) / (x * x)
+    }
+}
+
+/// Splits a position in the source data into the index and the fractional part.
+fn split_position(position: f64) -> (isize, f32) {
+    // Positions are never negative and stay in range of the sample data, the casts cannot truncate.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    {
+        let index = position as isize;
+        (index, (position - index as f64) as f32)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn resample() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let sample = Sample {
+            data: Some(vec![0.0, 1.0, 0.0, -1.0].into()),
+            loop_: Some(SampleLoop { start: 1, end: 3, bidi: false }),
+            sustain_loop: None,
+            samplerate_c5: 8000,
+            fm_patch: None,
+            conversion: SampleConversion::empty(),
+            ..module.samples[0].clone()
+        };
+
+        let mut linear = sample.clone();
+        linear.resample(16000, ResampleQuality::Linear);
+        assert_eq!(linear.samplerate_c5, 16000);
+        assert_eq!(linear.data.as_deref(), Some(&[0.0, 0.5, 1.0, 0.5, 0.0, -0.5, -1.0, -0.5][..]));
+        assert!(matches!(linear.loop_, Some(SampleLoop { start: 2, end: 6, .. })));
+
+        let mut nearest = sample.clone();
+        nearest.resample(4000, ResampleQuality::Nearest);
+        assert_eq!(nearest.data.as_deref(), Some(&[0.0, 0.0][..]));
+        assert!(matches!(nearest.loop_, Some(SampleLoop { start: 1, end: 2, .. })));
+
+        // The sinc passes the original values through when the rate is unchanged.
+        let mut sinc = sample.clone();
+        sinc.resample(8000, ResampleQuality::Sinc);
+        for (resampled, original) in sinc.data.unwrap().iter().zip(sample.data.as_deref().unwrap()) {
+            assert!((resampled - original).abs() < 1e-6);
+        }
+    }
+}
@@ -1,7 +1,12 @@
 use super::*;
+use crate::error::InvalidSampleLoopError;
+use alloc::sync::Arc;
+use core::iter::FromIterator;
+use core::ops::{Deref, DerefMut};
 
 
 #[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Sample {
     /// Sample Name, null-terminated (but may also contain nulls)
     pub name: Name,
@@ -16,6 +21,9 @@ pub struct Sample {
     pub default_volume: u8,
 
     /// Default Panning
+    ///
+    /// Raw value as stored in the sample header, bits 0-6 are the panning position and bit 7 is on
+    /// if the panning should be used. Use [`Sample::default_pan`] to get the effective value.
     pub default_panning: u8,
 
     /// Loop after the note has been released (Off ==) command, or directly after reaching the end
@@ -28,7 +36,7 @@ pub struct Sample {
     /// C-5 playback frequency.
     ///
     /// If set to the native sampling rate of the sound sample playing the sample at C-5 will play
-    /// it back unchanged.
+    /// it back unchanged. See [`C5Speed`] for the conversions.
     pub samplerate_c5: u32,
 
     /// Auto-Vibrato Rate (called Sweep in IT)
@@ -43,10 +51,79 @@ pub struct Sample {
     /// Auto-Vibrato Type
     pub vibrato_type: u8,
 
+    /// Number of channels of the sample data, `1` (mono) or `2` (stereo)
+    ///
+    /// Stereo data is interleaved, each frame is a left and a right value. Lengths and loop points
+    /// of the sample count frames.
+    pub channels: RangedU8<1, 2>,
+
     /// Sample samples converted to a normalized `f32` representation (values from -1.0 to 1.0)
-    pub data: Option<Vec<f32>>,
+    ///
+    /// The data is shared between clones of the sample, see [`SampleData`]. Stereo data is
+    /// interleaved, see [`Sample::channels`].
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serialization::sample_data"))]
+    pub data: Option<SampleData>,
+
+    /// OPL (FM synthesis) patch
+    ///
+    /// *OpenMPT extension.* FM instruments are marked by a flag in the convert field of the sample
+    /// header, in that case the sample data region contains a 12 byte OPL register patch instead
+    /// of PCM data and [`Sample::data`] is always `None`.
+    pub fm_patch: Option<[u8; 12]>,
+
+    /// Conversions applied to the stored sample data when it was decoded
+    ///
+    /// Taken from the convert field of the sample header, empty for samples not parsed from a file
+    /// or stored as signed PCM. The writer always stores the data as signed PCM.
+    pub conversion: SampleConversion,
+
+    /// Sample data as it was encoded in the parsed file
+    ///
+    /// Lets the writer emit the original bytes verbatim instead of re-encoding the data, see
+    /// [`Sample::is_dirty`]. Set to `None` for newly created samples.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub encoded: Option<EncodedData>,
+
+    /// Location of sample data which was not loaded yet
+    ///
+    /// Set for samples parsed by [`module_headers`](crate::parser::module_headers) and
+    /// [`read_module_headers`](crate::parser::read_module_headers), [`Sample::data`] is `None`
+    /// until the data is loaded by [`Sample::load_data`] or [`Sample::read_data`].
+    pub deferred: Option<DeferredData>,
+}
+
+/// Decoded sample data shared between clones
+///
+/// Cloning the data, and so cloning a [`Sample`] or a whole [`Module`], only increments a
+/// reference count. The values are copied on the first change made through a clone which still
+/// shares them (copy on write), the other clones keep the old values.
+///
+/// Dereferences to the slice of the normalized values, `Vec<f32>` and iterators of `f32` convert
+/// into it.
+#[derive(Clone, Default, PartialEq)]
+pub struct SampleData(Arc<Vec<f32>>);
+
+/// Original encoding of sample data
+///
+/// Keeps the stored bytes together with a fingerprint of the decoded data so edits to
+/// [`Sample::data`] can be detected. The bytes are shared between clones like [`SampleData`].
+#[derive(Clone)]
+pub struct EncodedData {
+    pub(crate) flags: SampleFlags,
+    pub(crate) bytes: Arc<[u8]>,
+    fingerprint: u64,
 }
 
+/// Location and encoding of sample data in the module file
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct DeferredData {
+    pub(crate) flags: SampleFlags,
+    pub(crate) offset: u32,
+    pub(crate) length: u32,
+}
+
+#[derive(Clone)]
 pub(crate) struct SampleHeader {
     pub(crate) name: Name,
     pub(crate) filename: DosFilename,
@@ -64,9 +141,12 @@ pub(crate) struct SampleHeader {
     pub(crate) flags: SampleFlags,
     pub(crate) data_offset: u32,
     pub(crate) data_length: u32,
+    /// Invalid values ignored by the parser, offsets are relative to the header
+    pub(crate) fixes: Vec<(usize, String)>,
 }
 
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct SampleLoop {
     /// Start - offset into the sample in samples
     pub start: u32,
@@ -96,7 +176,7 @@ bitflags! {
         /// On = 16 bit, Off = 8 bit.
         const DATA_16BIT = 1 << 1;
 
-        /// On = stereo, Off = mono. Stereo samples not supported yet
+        /// On = stereo, Off = mono. The channels are stored one after the other.
         const STEREO = 1 << 2;
 
         /// On = compressed samples.
@@ -158,9 +238,544 @@ bitflags! {
     }
 }
 
+bitflags! {
+    /// Conversions of the stored sample data, see [`Sample::conversion`]
+    #[derive(Default)]
+    pub struct SampleConversion: u8 {
+        /// Samples were stored unsigned (silence at `0x80` or `0x8000`)
+        const UNSIGNED = 1 << 0;
+
+        /// 16-bit samples were stored in big endian byte order
+        const BIG_ENDIAN = 1 << 1;
+
+        /// Samples were stored as differences to the previous sample
+        const DELTA = 1 << 2;
+
+        /// Bytes of the data were stored as differences to the previous byte (PTM)
+        const BYTE_DELTA = 1 << 3;
+
+        /// Samples were stored as TX-Wave 12-bit values, two samples packed in three bytes
+        const TX_WAVE = 1 << 4;
+    }
+}
+
+impl SampleConversion {
+    /// Conversions of PCM data stored with the flags, compressed data has none.
+    pub(crate) fn from_flags(flags: SampleFlags) -> SampleConversion {
+        let mut conversion = SampleConversion::empty();
+        if !flags.contains(SampleFlags::DATA_PRESENT)
+            || flags.intersects(SampleFlags::COMPRESSED | SampleFlags::OPL_INSTRUMENT)
+        {
+            return conversion;
+        }
+        conversion.set(SampleConversion::UNSIGNED, !flags.contains(SampleFlags::DATA_SIGNED));
+        conversion.set(
+            SampleConversion::BIG_ENDIAN,
+            flags.contains(SampleFlags::DATA_BIG_ENDIAN) && flags.contains(SampleFlags::DATA_16BIT),
+        );
+        conversion.set(SampleConversion::DELTA, flags.contains(SampleFlags::DELTA));
+        conversion.set(SampleConversion::BYTE_DELTA, flags.contains(SampleFlags::PTM8_TO_16));
+        conversion.set(SampleConversion::TX_WAVE, flags.contains(SampleFlags::TX_WAVE));
+        conversion
+    }
+}
+
 impl SampleFlags {
     pub(crate) fn from_parts(flags: u8, cvt: u8) -> SampleFlags {
         let bits = u16::from(flags) | (u16::from(cvt) << 8);
         SampleFlags::from_bits_truncate(bits)
     }
 }
+
+#[allow(non_upper_case_globals)]
+impl Sample {
+    pub(crate) const dfp_usePanning: u8 = 0x80;
+}
+
+impl Sample {
+    /// Returns `true` if the sample default panning is enabled.
+    ///
+    /// Note that unlike panning there is no flag for sample global volume, it always applies.
+    pub fn uses_panning(&self) -> bool {
+        self.default_panning & Sample::dfp_usePanning != 0
+    }
+
+    /// Returns the sample default panning (`0..=64`) if it is enabled.
+    ///
+    /// Returns `None` if the panning is disabled, even if a panning value is stored. Values above
+    /// `64` are clipped.
+    pub fn default_pan(&self) -> Option<RangedU8<0, 64>> {
+        if self.uses_panning() {
+            let pan = (self.default_panning & !Sample::dfp_usePanning).min(64);
+            Some(RangedU8::try_from(pan).unwrap())
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if the sample data has to be encoded again when writing.
+    ///
+    /// The original encoding can only be reused if all of the following hold:
+    ///
+    /// - the sample was parsed from a file and the encoding was kept ([`Sample::encoded`] is
+    ///   `Some`), newly created samples are always dirty,
+    /// - the decoded data is unchanged since parsing, this is checked by comparing a fingerprint of
+    ///   the current data with the one taken when the sample was parsed,
+    /// - [`Sample::mark_dirty`] was not called.
+    ///
+    /// Changing the sample header (loops, volumes, ...) doesn't make the data dirty.
+    pub fn is_dirty(&self) -> bool {
+        match &self.encoded {
+            Some(encoded) => {
+                encoded.fingerprint != fingerprint(self.data.as_deref())
+                    || encoded.flags.contains(SampleFlags::STEREO) != self.is_stereo()
+            }
+            None => true,
+        }
+    }
+
+    /// Drops the original encoding so the data is always encoded again when writing.
+    pub fn mark_dirty(&mut self) {
+        self.encoded = None;
+    }
+
+    /// Returns `true` if the sample data was loaded, or if there is no data to load.
+    pub fn is_loaded(&self) -> bool {
+        self.deferred.is_none()
+    }
+
+    /// Returns `true` if the sample data has two channels.
+    pub fn is_stereo(&self) -> bool {
+        self.channels.as_u8() == 2
+    }
+
+    /// Returns `true` if the sample is an OPL (FM synthesis) instrument and has no PCM data.
+    pub fn is_fm(&self) -> bool {
+        self.fm_patch.is_some()
+    }
+
+    /// Returns the smallest bit depth (8 or 16) the sample data can be stored in without loss.
+    ///
+    /// Sample data is kept normalized, 8-bit PCM is decoded as `s / 127` and 16-bit PCM as
+    /// `s / 32767`. A value is considered losslessly representable in 8 bits if either
+    ///
+    /// - it is exactly the decoding of some 8-bit PCM value, or
+    /// - it is exactly the decoding of some 16-bit PCM value whose low byte is zero (8-bit content
+    ///   that was stored as 16-bit).
+    ///
+    /// If all values satisfy this, the result is `8`, otherwise `16`. Samples without data also
+    /// return `8`.
+    pub fn minimal_bit_depth(&self) -> u8 {
+        match &self.data {
+            Some(data) if !data.iter().all(|&x| quantize_8bit(x).is_some()) => 16,
+            _ => 8,
+        }
+    }
+
+    /// Returns a copy of the sample with its data requantized to 8-bit precision.
+    ///
+    /// Returns `None` if the conversion would be lossy, see [`Sample::minimal_bit_depth`] for the
+    /// exact criterion. For 8-bit content stored as 16-bit the PCM values are preserved exactly
+    /// (`s >> 8`), however the normalized values change slightly because 8-bit and 16-bit data are
+    /// normalized by `127` and `32767` respectively.
+    pub fn to_8bit(&self) -> Option<Sample> {
+        let data = match &self.data {
+            Some(data) => Some(
+                data.iter()
+                    .map(|&x| quantize_8bit(x).map(|q| f32::from(q) / f32::from(i8::MAX)))
+                    .collect::<Option<SampleData>>()?
+            ),
+            None => None,
+        };
+        Some(Sample { data, ..self.clone() })
+    }
+
+    /// Reverses the sample data in place.
+    ///
+    /// Loops are kept and mirrored so they cover the same audio as before. Loop end points are
+    /// exclusive (the sample after the last one in the loop), so a loop `start..end` becomes
+    /// `len - end..len - start`. Loop points past the end of the data are clamped to the data
+    /// length first.
+    ///
+    /// The frames of stereo samples are reversed, the channels stay in place. Samples without
+    /// data, including FM instruments, are left unchanged.
+    pub fn reverse(&mut self) {
+        let channels = usize::from(self.channels.as_u8());
+        let data = match &mut self.data {
+            Some(data) => data,
+            None => return,
+        };
+        data.reverse();
+        if channels > 1 {
+            data.chunks_exact_mut(channels).for_each(<[f32]>::reverse);
+        }
+
+        let len = u32::try_from(data.len() / channels).unwrap_or(u32::MAX);
+        for sample_loop in self.loop_.iter_mut().chain(self.sustain_loop.iter_mut()) {
+            let (start, end) = (sample_loop.start.min(len), sample_loop.end.min(len));
+            sample_loop.start = len - end;
+            sample_loop.end = len - start;
+        }
+    }
+
+    /// Returns the length of the sample in frames (samples of each channel), also for data which
+    /// was not loaded yet.
+    ///
+    /// FM instruments and samples without data have length `0`.
+    pub fn length(&self) -> u32 {
+        match (&self.data, &self.deferred) {
+            (Some(data), _) => u32::try_from(data.len() / usize::from(self.channels.as_u8())).unwrap_or(u32::MAX),
+            (None, Some(deferred)) => deferred.length,
+            (None, None) => 0,
+        }
+    }
+
+    /// Sets the loop, see [`Sample::loop_`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if the loop is empty (`start >= end`) or ends past the [end of the
+    /// sample](Sample::length), the loop is left unchanged.
+    pub fn set_loop(&mut self, sample_loop: SampleLoop) -> Result<(), InvalidSampleLoopError> {
+        self.loop_ = Some(self.checked_loop(sample_loop)?);
+        Ok(())
+    }
+
+    /// Sets the sustain loop, see [`Sample::sustain_loop`].
+    ///
+    /// # Errors
+    ///
+    /// Fails the same way as [`Sample::set_loop`], the sustain loop is left unchanged.
+    pub fn set_sustain_loop(&mut self, sample_loop: SampleLoop) -> Result<(), InvalidSampleLoopError> {
+        self.sustain_loop = Some(self.checked_loop(sample_loop)?);
+        Ok(())
+    }
+
+    /// Turns the loop off.
+    pub fn clear_loop(&mut self) {
+        self.loop_ = None;
+    }
+
+    /// Turns the sustain loop off.
+    pub fn clear_sustain_loop(&mut self) {
+        self.sustain_loop = None;
+    }
+
+    /// Crossfades the end of the loop into the audio before the loop start to remove the click at
+    /// the loop seam.
+    ///
+    /// The last `length` samples of the loop are blended linearly from their original values to
+    /// the `length` samples preceding the loop start, so the playback reaching the loop end
+    /// continues smoothly into the loop start. The fade is shortened to fit both the loop and the
+    /// audio before it, the length actually used is returned.
+    ///
+    /// Nothing is done and `0` is returned if there is no loop, the loop is bidirectional (there is
+    /// no seam to fade) or the sample data is not loaded.
+    pub fn crossfade_loop(&mut self, length: u32) -> u32 {
+        let channels = self.channels.as_u8();
+        crossfade(self.data.as_deref_mut(), channels, self.loop_, length)
+    }
+
+    /// Crossfades the seam of the sustain loop, see [`Sample::crossfade_loop`].
+    pub fn crossfade_sustain_loop(&mut self, length: u32) -> u32 {
+        let channels = self.channels.as_u8();
+        crossfade(self.data.as_deref_mut(), channels, self.sustain_loop, length)
+    }
+
+    /// Converts a bidirectional loop into a forward loop playing the same audio.
+    ///
+    /// The looped values are copied after the loop end in reverse order and the loop is extended
+    /// over them. A bidirectional loop doesn't play its first and last value twice when it turns
+    /// around, so the copy leaves them out and a loop of `n` values grows to `2 * n - 2`. The audio
+    /// after the loop is moved back with the sustain loop points in it.
+    ///
+    /// Returns `true` if the loop was unrolled, `false` if it wasn't bidirectional or the sample
+    /// data is not loaded.
+    pub fn unroll_pingpong_loop(&mut self) -> bool {
+        unroll(self.data.as_mut(), self.channels.as_u8(), &mut self.loop_, &mut self.sustain_loop)
+    }
+
+    /// Converts a bidirectional sustain loop into a forward one, see
+    /// [`Sample::unroll_pingpong_loop`].
+    pub fn unroll_pingpong_sustain_loop(&mut self) -> bool {
+        unroll(self.data.as_mut(), self.channels.as_u8(), &mut self.sustain_loop, &mut self.loop_)
+    }
+
+    pub(crate) fn checked_loop(&self, sample_loop: SampleLoop) -> Result<SampleLoop, InvalidSampleLoopError> {
+        let length = self.length();
+        if sample_loop.start < sample_loop.end && sample_loop.end <= length {
+            Ok(sample_loop)
+        } else {
+            Err(InvalidSampleLoopError { sample_loop, length })
+        }
+    }
+}
+
+impl SampleData {
+    /// Returns the values for modification, copies them first if they are shared with a clone.
+    pub fn make_mut(&mut self) -> &mut Vec<f32> {
+        Arc::make_mut(&mut self.0)
+    }
+
+    /// Returns the values, copies them if they are shared with a clone.
+    pub fn into_vec(self) -> Vec<f32> {
+        Arc::try_unwrap(self.0).unwrap_or_else(|data| Vec::clone(&data))
+    }
+
+    /// Returns `true` if the data is shared with a clone.
+    pub fn is_shared(&self) -> bool {
+        Arc::strong_count(&self.0) > 1
+    }
+
+    /// Returns `true` if both refer to the same values, which means one is a clone of the other
+    /// and neither was modified since.
+    pub fn ptr_eq(this: &SampleData, other: &SampleData) -> bool {
+        Arc::ptr_eq(&this.0, &other.0)
+    }
+}
+
+impl Deref for SampleData {
+    type Target = [f32];
+
+    fn deref(&self) -> &[f32] {
+        &self.0
+    }
+}
+
+impl DerefMut for SampleData {
+    /// Copies the values first if they are shared with a clone, see [`SampleData::make_mut`].
+    fn deref_mut(&mut self) -> &mut [f32] {
+        self.make_mut()
+    }
+}
+
+impl From<Vec<f32>> for SampleData {
+    fn from(data: Vec<f32>) -> SampleData {
+        SampleData(Arc::new(data))
+    }
+}
+
+impl From<SampleData> for Vec<f32> {
+    fn from(data: SampleData) -> Vec<f32> {
+        data.into_vec()
+    }
+}
+
+impl FromIterator<f32> for SampleData {
+    fn from_iter<I: IntoIterator<Item = f32>>(iter: I) -> SampleData {
+        SampleData::from(iter.into_iter().collect::<Vec<f32>>())
+    }
+}
+
+impl<'d> IntoIterator for &'d SampleData {
+    type Item = &'d f32;
+    type IntoIter = core::slice::Iter<'d, f32>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl Debug for SampleData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&self[..], f)
+    }
+}
+
+impl EncodedData {
+    pub(crate) fn new(flags: SampleFlags, bytes: Vec<u8>, data: Option<&[f32]>) -> EncodedData {
+        EncodedData {
+            flags,
+            bytes: Arc::from(bytes),
+            fingerprint: fingerprint(data),
+        }
+    }
+}
+
+impl DeferredData {
+    /// Returns the offset of the sample data from the start of the module.
+    pub fn offset(&self) -> u32 {
+        self.offset
+    }
+
+    /// Returns the length of the sample in samples.
+    pub fn length(&self) -> u32 {
+        self.length
+    }
+}
+
+impl Debug for EncodedData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EncodedData")
+            .field("flags", &self.flags)
+            .field("length", &self.bytes.len())
+            .finish()
+    }
+}
+
+/// Hashes the bit patterns of the decoded sample data.
+///
+/// Uses 64-bit FNV-1a, the fingerprint is only compared to detect edits so it doesn't need to be
+/// resistant to collisions.
+pub(crate) fn fingerprint(data: Option<&[f32]>) -> u64 {
+    const OFFSET_BASIS: u64 = 0xCBF2_9CE4_8422_2325;
+    const PRIME: u64 = 0x0100_0000_01B3;
+
+    let write = |hash: u64, bytes: &[u8]| {
+        bytes.iter().fold(hash, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME))
+    };
+
+    let mut hash = OFFSET_BASIS;
+    if let Some(data) = data {
+        hash = write(hash, &u64::try_from(data.len()).unwrap().to_le_bytes());
+        for value in data {
+            hash = write(hash, &value.to_bits().to_le_bytes());
+        }
+    }
+    hash
+}
+
+/// Crossfades the seam of the forward loop, returns the length of the fade.
+fn crossfade(data: Option<&mut [f32]>, channels: u8, sample_loop: Option<SampleLoop>, length: u32) -> u32 {
+    let (data, sample_loop) = match (data, sample_loop) {
+        (Some(data), Some(sample_loop)) if !sample_loop.bidi => (data, sample_loop),
+        _ => return 0,
+    };
+    let channels = usize::from(channels);
+    let end = sample_loop.end.min(u32::try_from(data.len() / channels).unwrap_or(u32::MAX));
+    let start = sample_loop.start.min(end);
+    let length = length.min(start).min(end - start);
+
+    let (end, start, fade) = (usize::try_from(end).unwrap(), usize::try_from(start).unwrap(), usize::try_from(length).unwrap());
+    for i in 0..fade {
+        // The fraction moves from the original values towards the audio before the loop start,
+        // neither end of the fade is taken fully so both seams are softened.
+        #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+        let t = (i + 1) as f32 / (fade + 1) as f32;
+        let (target, source) = (end - fade + i, start - fade + i);
+        for channel in 0..channels {
+            let (target, source) = (target * channels + channel, source * channels + channel);
+            data[target] = data[target] * (1.0 - t) + data[source] * t;
+        }
+    }
+    length
+}
+
+fn unroll(data: Option<&mut SampleData>, channels: u8, sample_loop: &mut Option<SampleLoop>, other: &mut Option<SampleLoop>) -> bool {
+    let (data, unrolled) = match (data, sample_loop.as_mut()) {
+        (Some(data), Some(sample_loop)) if sample_loop.bidi => (data, sample_loop),
+        _ => return false,
+    };
+    let channels = usize::from(channels);
+    let len = u32::try_from(data.len() / channels).unwrap_or(u32::MAX);
+    let end = unrolled.end.min(len);
+    let start = unrolled.start.min(end);
+    let (from, to) = (usize::try_from(start).unwrap(), usize::try_from(end).unwrap());
+    // The end points are played once when a bidirectional loop turns around.
+    let inner = data[..].get((from + 1) * channels..to.saturating_sub(1) * channels).unwrap_or_default();
+    let mirrored = inner.chunks_exact(channels).rev().flatten().copied().collect::<Vec<_>>();
+    let added = u32::try_from(mirrored.len() / channels).unwrap_or(u32::MAX);
+    data.make_mut().splice(to * channels..to * channels, mirrored);
+
+    *unrolled = SampleLoop { start, end: end + added, bidi: false };
+    if let Some(other) = other {
+        if other.start >= end {
+            other.start += added;
+        }
+        if other.end > end {
+            other.end += added;
+        }
+    }
+    true
+}
+
+/// Returns the 8-bit PCM value which decodes exactly to the normalized sample value, if any.
+pub(crate) fn exact_8bit(x: f32) -> Option<i8> {
+    // The value is checked for range before the cast, NaNs are caught by the comparison.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    {
+        let q = float::round(x * f32::from(i8::MAX));
+        if !(f32::from(i8::MIN)..=f32::from(i8::MAX)).contains(&q) {
+            return None;
+        }
+        let q = q as i8;
+        (f32::from(q) / f32::from(i8::MAX) == x).then_some(q)
+    }
+}
+
+/// Returns the 8-bit PCM value the normalized sample value was decoded from, if there is one.
+fn quantize_8bit(x: f32) -> Option<i8> {
+    if let Some(q) = exact_8bit(x) {
+        return Some(q);
+    }
+
+    // The value is checked for range before the cast, NaNs are caught by the comparison.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    {
+        let s = float::round(x * f32::from(i16::MAX));
+        if (f32::from(i16::MIN)..=f32::from(i16::MAX)).contains(&s) {
+            let s = s as i16;
+            if s & 0xFF == 0 && f32::from(s) / f32::from(i16::MAX) == x {
+                return i8::try_from(s >> 8).ok();
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn loops() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let mut sample = Sample {
+            data: Some(vec![1.0, 1.0, 0.0, 0.0, 0.0, 0.0].into()),
+            loop_: None,
+            sustain_loop: None,
+            fm_patch: None,
+            conversion: SampleConversion::empty(),
+            ..module.samples[0].clone()
+        };
+
+        assert!(sample.set_loop(SampleLoop { start: 2, end: 7, bidi: false }).is_err());
+        assert!(sample.set_sustain_loop(SampleLoop { start: 3, end: 3, bidi: false }).is_err());
+        assert!(sample.loop_.is_none() && sample.sustain_loop.is_none());
+        sample.set_loop(SampleLoop { start: 2, end: 6, bidi: false }).unwrap();
+
+        // The fade is limited by the two samples before the loop start.
+        assert_eq!(sample.crossfade_loop(3), 2);
+        let data = sample.data.as_deref().unwrap();
+        assert!((data[4] - 1.0 / 3.0).abs() < 1e-6 && (data[5] - 2.0 / 3.0).abs() < 1e-6);
+        assert_eq!(sample.crossfade_sustain_loop(3), 0);
+
+        sample.clear_loop();
+        assert!(sample.loop_.is_none());
+
+        sample.data = Some(vec![0.0, 1.0, 2.0, 3.0, 4.0].into());
+        sample.set_loop(SampleLoop { start: 1, end: 4, bidi: true }).unwrap();
+        sample.set_sustain_loop(SampleLoop { start: 4, end: 5, bidi: false }).unwrap();
+        assert!(sample.unroll_pingpong_loop());
+        assert_eq!(sample.data.as_deref().unwrap(), [0.0, 1.0, 2.0, 3.0, 2.0, 4.0]);
+        assert!(matches!(sample.loop_, Some(SampleLoop { start: 1, end: 5, bidi: false })));
+        assert!(matches!(sample.sustain_loop, Some(SampleLoop { start: 5, end: 6, bidi: false })));
+        assert!(!sample.unroll_pingpong_loop() && !sample.unroll_pingpong_sustain_loop());
+    }
+
+    #[test]
+    fn shared_data() {
+        let data = SampleData::from(vec![0.0, 0.5, 1.0]);
+        let mut clone = data.clone();
+        assert!(SampleData::ptr_eq(&data, &clone) && data.is_shared());
+
+        clone[0] = -1.0;
+        assert!(!SampleData::ptr_eq(&data, &clone) && !data.is_shared());
+        assert_eq!(&data[..], [0.0, 0.5, 1.0]);
+        assert_eq!(clone.into_vec(), [-1.0, 0.5, 1.0]);
+    }
+}
@@ -0,0 +1,217 @@
+//! Serde support
+//!
+//! Most types derive the implementations, this module contains the parts with a custom
+//! representation:
+//!
+//! - flags are stored as a list of the flag names, e.g. `["STEREO", "USE_INSTRUMENTS"]`,
+//! - fixed size byte arrays (names, MIDI macros, initial channel settings) and sample data are
+//!   stored as bytes, sample data as little-endian `f32` values,
+//! - arrays longer than serde supports (the fixed MIDI macros) are stored as sequences,
+//! - ranged numbers and IDs are stored as plain numbers and checked for range when deserializing.
+
+use super::*;
+use serde::de::{self, SeqAccess, Visitor};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use core::convert::TryInto;
+
+
+macro_rules! flags_as_names {
+    ( $flags: ty { $( $name: ident ),* $(,)? } ) => {
+        impl Serialize for $flags {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                const FLAGS: &[(&str, $flags)] = &[ $( (stringify!($name), <$flags>::$name), )* ];
+                serializer.collect_seq(
+                    FLAGS.iter()
+                        .filter(|(_, flag)| self.contains(*flag))
+                        .map(|(name, _)| name)
+                )
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $flags {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                const NAMES: &[&str] = &[ $( stringify!($name), )* ];
+                Vec::<String>::deserialize(deserializer)?
+                    .iter()
+                    .try_fold(<$flags>::empty(), |flags, name| match name.as_str() {
+                        $( stringify!($name) => Ok(flags | <$flags>::$name), )*
+                        _ => Err(de::Error::unknown_variant(name, NAMES)),
+                    })
+            }
+        }
+    };
+}
+
+flags_as_names!(ModuleFlags {
+    STEREO,
+    VOL_0_MIX_OPTIMIZATIONS,
+    USE_INSTRUMENTS,
+    LINEAR_SLIDES,
+    OLD_EFFECTS,
+    LINK_G_E_EFFECTS,
+    USE_MIDI_PITCH,
+    REQUEST_MIDI_CONFIG_EMBEDDED,
+    MESSAGE_ATTACHED,
+    MIDI_CONIFG_EMBEDDED,
+});
+
+flags_as_names!(InstrumentFlags {
+    ENABLE_PANNING,
+    ENABLE_FILTER_CUTOFF,
+    ENABLE_FILTER_RESONANCE,
+});
+
+flags_as_names!(EnvelopeFlags {
+    ENABLED,
+    LOOP,
+    SUSTAIN,
+    CARRY,
+    FILTER,
+});
+
+// `ADPCM_SAMPLE` is left out, it overlaps all the convert flags.
+flags_as_names!(SampleFlags {
+    DATA_PRESENT,
+    DATA_16BIT,
+    STEREO,
+    COMPRESSED,
+    LOOP,
+    SUSTAIN,
+    BIDI_LOOP,
+    BIDI_SUSTAIN,
+    DATA_SIGNED,
+    DATA_BIG_ENDIAN,
+    DELTA,
+    PTM8_TO_16,
+    TX_WAVE,
+    STEREO_PROMPT,
+    OPL_INSTRUMENT,
+    EXTERNAL_SAMPLE,
+});
+
+flags_as_names!(SampleConversion {
+    UNSIGNED,
+    BIG_ENDIAN,
+    DELTA,
+    BYTE_DELTA,
+    TX_WAVE,
+});
+
+
+impl Serialize for SampleMap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.map.iter())
+    }
+}
+
+impl<'de> Deserialize<'de> for SampleMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let map = Vec::<Option<SampleId>>::deserialize(deserializer)?;
+        let length = map.len();
+        map.try_into()
+            .map(|map| SampleMap { map })
+            .map_err(|_| de::Error::invalid_length(length, &"120 entries"))
+    }
+}
+
+
+/// Fixed size byte arrays stored as bytes
+pub(crate) mod byte_array {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer, const N: usize>(bytes: &[u8; N], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(bytes)
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>, const N: usize>(deserializer: D) -> Result<[u8; N], D::Error> {
+        let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+        let length = bytes.len();
+        bytes.try_into()
+            .map_err(|_| de::Error::invalid_length(length, &BytesVisitor))
+    }
+}
+
+/// Fixed size arrays of any length stored as sequences
+pub(crate) mod array {
+    use super::*;
+
+    pub(crate) fn serialize<S: Serializer, T: Serialize, const N: usize>(array: &[T; N], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(array.iter())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(deserializer: D) -> Result<[T; N], D::Error> {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        let length = items.len();
+        items.try_into()
+            .map_err(|_| de::Error::invalid_length(length, &"a fixed number of entries"))
+    }
+}
+
+/// Sample data stored as bytes of little-endian `f32` values
+pub(crate) mod sample_data {
+    use super::*;
+
+    struct Data<'d>(&'d [f32]);
+
+    impl Serialize for Data<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let bytes = self.0.iter()
+                .flat_map(|value| value.to_le_bytes())
+                .collect::<Vec<u8>>();
+            serializer.serialize_bytes(&bytes)
+        }
+    }
+
+    struct DataBuf(Vec<f32>);
+
+    impl<'de> Deserialize<'de> for DataBuf {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let bytes = deserializer.deserialize_byte_buf(BytesVisitor)?;
+            if bytes.len() % 4 != 0 {
+                return Err(de::Error::invalid_length(bytes.len(), &"a multiple of 4 bytes"));
+            }
+            let data = bytes.chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            Ok(DataBuf(data))
+        }
+    }
+
+    pub(crate) fn serialize<S: Serializer>(data: &Option<SampleData>, serializer: S) -> Result<S::Ok, S::Error> {
+        match data {
+            Some(data) => serializer.serialize_some(&Data(data)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<SampleData>, D::Error> {
+        Ok(Option::<DataBuf>::deserialize(deserializer)?.map(|data| SampleData::from(data.0)))
+    }
+}
+
+/// Accepts bytes, or a sequence of bytes for formats without native byte strings.
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("a byte array")
+    }
+
+    fn visit_bytes<E: de::Error>(self, bytes: &[u8]) -> Result<Self::Value, E> {
+        Ok(bytes.to_vec())
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, bytes: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(bytes)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut bytes = Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+        while let Some(byte) = seq.next_element()? {
+            bytes.push(byte);
+        }
+        Ok(bytes)
+    }
+}
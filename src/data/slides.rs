@@ -0,0 +1,190 @@
+//! Pitch slide math of linear and Amiga slides
+
+use super::*;
+
+
+/// Frequency of the Amiga period `1`, C-5 at 8363 Hz has the period `1712`
+pub const AMIGA_CLOCK: u32 = 1712 * C5Speed::NTSC.as_u32();
+
+/// Frequency multipliers (16.16 fixed point) of linear slides up by 1/16th of a semitone steps
+pub const LINEAR_SLIDE_UP_TABLE: [u32; 256] = [
+    65536, 65773, 66010, 66249, 66489, 66729, 66971, 67213, 67456, 67700, 67945, 68190,
+    68437, 68685, 68933, 69182, 69432, 69684, 69936, 70189, 70442, 70697, 70953, 71209,
+    71467, 71725, 71985, 72245, 72507, 72769, 73032, 73296, 73561, 73827, 74094, 74362,
+    74631, 74901, 75172, 75444, 75717, 75991, 76265, 76541, 76818, 77096, 77375, 77655,
+    77935, 78217, 78500, 78784, 79069, 79355, 79642, 79930, 80219, 80509, 80800, 81093,
+    81386, 81680, 81976, 82272, 82570, 82868, 83168, 83469, 83771, 84074, 84378, 84683,
+    84989, 85297, 85605, 85915, 86225, 86537, 86850, 87164, 87480, 87796, 88113, 88432,
+    88752, 89073, 89395, 89718, 90043, 90369, 90695, 91023, 91353, 91683, 92015, 92347,
+    92681, 93017, 93353, 93691, 94029, 94370, 94711, 95053, 95397, 95742, 96088, 96436,
+    96785, 97135, 97486, 97839, 98193, 98548, 98904, 99262, 99621, 99981, 100343, 100706,
+    101070, 101435, 101802, 102170, 102540, 102911, 103283, 103657, 104031, 104408, 104785, 105164,
+    105545, 105926, 106309, 106694, 107080, 107467, 107856, 108246, 108637, 109030, 109425, 109820,
+    110217, 110616, 111016, 111418, 111821, 112225, 112631, 113038, 113447, 113857, 114269, 114682,
+    115097, 115514, 115931, 116351, 116771, 117194, 117618, 118043, 118470, 118898, 119328, 119760,
+    120193, 120628, 121064, 121502, 121941, 122382, 122825, 123269, 123715, 124162, 124611, 125062,
+    125514, 125968, 126424, 126881, 127340, 127801, 128263, 128727, 129192, 129660, 130129, 130599,
+    131072, 131546, 132021, 132499, 132978, 133459, 133942, 134426, 134912, 135400, 135890, 136381,
+    136875, 137370, 137866, 138365, 138865, 139368, 139872, 140378, 140885, 141395, 141906, 142419,
+    142935, 143451, 143970, 144491, 145014, 145538, 146064, 146593, 147123, 147655, 148189, 148725,
+    149263, 149803, 150344, 150888, 151434, 151982, 152531, 153083, 153637, 154192, 154750, 155310,
+    155871, 156435, 157001, 157569, 158138, 158710, 159284, 159860, 160439, 161019, 161601, 162186,
+    162772, 163361, 163952, 164545,
+];
+
+/// Frequency multipliers (16.16 fixed point) of linear slides down by 1/16th of a semitone steps
+pub const LINEAR_SLIDE_DOWN_TABLE: [u32; 256] = [
+    65535, 65300, 65065, 64830, 64596, 64364, 64132, 63901, 63670, 63441, 63212, 62984,
+    62757, 62531, 62306, 62081, 61858, 61635, 61413, 61191, 60971, 60751, 60532, 60314,
+    60097, 59880, 59664, 59449, 59235, 59022, 58809, 58597, 58386, 58176, 57966, 57757,
+    57549, 57341, 57135, 56929, 56724, 56519, 56316, 56113, 55911, 55709, 55508, 55308,
+    55109, 54910, 54713, 54515, 54319, 54123, 53928, 53734, 53540, 53347, 53155, 52963,
+    52773, 52582, 52393, 52204, 52016, 51829, 51642, 51456, 51270, 51085, 50901, 50718,
+    50535, 50353, 50172, 49991, 49811, 49631, 49452, 49274, 49097, 48920, 48743, 48568,
+    48393, 48218, 48044, 47871, 47699, 47527, 47356, 47185, 47015, 46846, 46677, 46509,
+    46341, 46174, 46008, 45842, 45677, 45512, 45348, 45185, 45022, 44859, 44698, 44537,
+    44376, 44216, 44057, 43898, 43740, 43582, 43425, 43269, 43113, 42958, 42803, 42649,
+    42495, 42342, 42189, 42037, 41886, 41735, 41584, 41434, 41285, 41136, 40988, 40840,
+    40693, 40547, 40400, 40255, 40110, 39965, 39821, 39678, 39535, 39392, 39250, 39109,
+    38968, 38828, 38688, 38548, 38409, 38271, 38133, 37996, 37859, 37722, 37586, 37451,
+    37316, 37181, 37047, 36914, 36781, 36648, 36516, 36385, 36254, 36123, 35993, 35863,
+    35734, 35605, 35477, 35349, 35221, 35095, 34968, 34842, 34716, 34591, 34467, 34343,
+    34219, 34095, 33973, 33850, 33728, 33607, 33486, 33365, 33245, 33125, 33005, 32887,
+    32768, 32650, 32532, 32415, 32298, 32182, 32066, 31950, 31835, 31720, 31606, 31492,
+    31379, 31266, 31153, 31041, 30929, 30817, 30706, 30596, 30485, 30376, 30266, 30157,
+    30048, 29940, 29832, 29725, 29618, 29511, 29405, 29299, 29193, 29088, 28983, 28879,
+    28774, 28671, 28567, 28464, 28362, 28260, 28158, 28056, 27955, 27855, 27754, 27654,
+    27554, 27455, 27356, 27258, 27159, 27062, 26964, 26867, 26770, 26674, 26577, 26482,
+    26386, 26291, 26196, 26102,
+];
+
+/// Frequency multipliers (16.16 fixed point) of linear slides up by 1/64th of a semitone steps
+pub const FINE_LINEAR_SLIDE_UP_TABLE: [u32; 16] = [
+    65536, 65595, 65654, 65714, 65773, 65832, 65892, 65951,
+    66011, 66071, 66130, 66190, 66250, 66309, 66369, 66429,
+];
+
+/// Frequency multipliers (16.16 fixed point) of linear slides down by 1/64th of a semitone steps
+pub const FINE_LINEAR_SLIDE_DOWN_TABLE: [u32; 16] = [
+    65535, 65477, 65418, 65359, 65300, 65241, 65182, 65123,
+    65065, 65006, 64947, 64889, 64830, 64772, 64713, 64655,
+];
+
+
+/// How pitch slides change the frequency
+///
+/// [`ModuleFlags::LINEAR_SLIDES`] switches between two ways Impulse Tracker changes the frequency
+/// of a note by portamento and vibrato. Both take the slide in the same units, `Exx`/`Fxx` slide
+/// by `4 * xx` every tick, `EFx`/`FFx` by `4 * x` once and `EEx`/`FEx` by `x` once.
+///
+/// - Linear slides change the frequency by 1/64th of a semitone per unit using the lookup tables
+///   of Impulse Tracker. The tables hold 16.16 fixed point multipliers, with the rounding of the
+///   original: the coarse table up is rounded down and the tables down start at `65535`.
+/// - Amiga slides change the period [`AMIGA_CLOCK`]` / frequency` by one per unit, so the same slide
+///   changes high notes more than low ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlideMode {
+    Linear,
+    Amiga,
+}
+
+impl SlideMode {
+    /// Returns the slide mode of the module.
+    pub fn of(module: &Module) -> SlideMode {
+        if module.flags.contains(ModuleFlags::LINEAR_SLIDES) {
+            SlideMode::Linear
+        } else {
+            SlideMode::Amiga
+        }
+    }
+
+    /// Slides the frequency up (positive units) or down (negative units).
+    ///
+    /// Vibrato applies its value the same way to the frequency of the note without keeping the
+    /// result.
+    pub fn slide(self, frequency: u32, units: i32) -> u32 {
+        match self {
+            SlideMode::Linear => linear_slide(frequency, units),
+            SlideMode::Amiga => amiga_slide(frequency, units),
+        }
+    }
+}
+
+/// Slides the frequency by 1/64ths of a semitone with the tables of Impulse Tracker.
+///
+/// Slides by less than 16 units use the fine tables, larger ones the coarse tables (dropping the
+/// remainder of the division by 4) like Impulse Tracker does.
+///
+/// ```
+/// # use ittech::linear_slide;
+/// assert_eq!(linear_slide(8363, 64 * 12), 16726);
+/// assert_eq!(linear_slide(8363, -64 * 12), 4181);
+/// ```
+pub fn linear_slide(frequency: u32, units: i32) -> u32 {
+    let (coarse, fine) = if units < 0 {
+        (&LINEAR_SLIDE_DOWN_TABLE, &FINE_LINEAR_SLIDE_DOWN_TABLE)
+    } else {
+        (&LINEAR_SLIDE_UP_TABLE, &FINE_LINEAR_SLIDE_UP_TABLE)
+    };
+    let mut units = units.unsigned_abs();
+    let mut frequency = u64::from(frequency);
+    let mut multiply = |table: &[u32], index: u32| {
+        frequency = (frequency * u64::from(table[usize::try_from(index).unwrap()])) >> 16;
+    };
+    // Slides larger than the coarse table are applied in parts.
+    while units > 4 * 255 {
+        multiply(coarse, 255);
+        units -= 4 * 255;
+    }
+    if units < 16 {
+        multiply(fine, units);
+    } else {
+        multiply(coarse, units / 4);
+    }
+    u32::try_from(frequency).unwrap_or(u32::MAX)
+}
+
+/// Slides the frequency by Amiga periods, [`AMIGA_CLOCK`]` / frequency`.
+///
+/// Sliding up past the period `0` gives `u32::MAX`, a frequency of `0` stays `0`.
+///
+/// ```
+/// # use ittech::amiga_slide;
+/// // C-5 at the period 1712 slides by 4 periods.
+/// assert_eq!(amiga_slide(8363, 4), 8382);
+/// assert_eq!(amiga_slide(8363, -4), 8343);
+/// ```
+pub fn amiga_slide(frequency: u32, units: i32) -> u32 {
+    let clock = i64::from(AMIGA_CLOCK);
+    let frequency = i64::from(frequency);
+    // clock / (clock / frequency - units) without the rounding of the period
+    let divisor = clock - i64::from(units) * frequency;
+    if frequency == 0 {
+        0
+    } else if divisor <= 0 {
+        u32::MAX
+    } else {
+        u32::try_from(clock * frequency / divisor).unwrap_or(u32::MAX)
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn slides() {
+        assert_eq!(linear_slide(8363, 0), 8363);
+        assert_eq!(linear_slide(65536, 1), FINE_LINEAR_SLIDE_UP_TABLE[1]);
+        assert_eq!(linear_slide(65536, -8), FINE_LINEAR_SLIDE_DOWN_TABLE[8]);
+        // The coarse table drops the units below 4.
+        assert_eq!(linear_slide(65536, 18), LINEAR_SLIDE_UP_TABLE[4]);
+        // Slides beyond the table are applied in parts.
+        assert_eq!(linear_slide(1000, 64 * 12 * 3), 7997);
+
+        assert_eq!(amiga_slide(8363, 1712), u32::MAX);
+        assert_eq!(amiga_slide(0, 4), 0);
+        assert_eq!(SlideMode::Amiga.slide(8363, -1712), 4181);
+    }
+}
@@ -0,0 +1,124 @@
+use super::*;
+use alloc::collections::BTreeMap;
+use core::time::Duration;
+
+
+/// Summary of a module, see [`Module::stats`]
+#[derive(Clone, Debug)]
+pub struct ModuleStats {
+    pub patterns: usize,
+    pub instruments: usize,
+    pub samples: usize,
+
+    /// Size of the sample data as uncompressed PCM in bytes, in the bit depth it's stored in
+    pub sample_bytes: usize,
+
+    /// Size of the sample data as stored in the parsed file in bytes
+    ///
+    /// Compressed samples count their compressed size. Samples without their original encoding
+    /// (newly created or edited ones) count their uncompressed size.
+    pub stored_sample_bytes: usize,
+
+    /// Number of effects in all patterns by their letter
+    pub effects: BTreeMap<char, usize>,
+
+    /// Lowest and highest note played in the patterns, `None` if no note is played
+    pub note_range: Option<(Note, Note)>,
+
+    /// Channels used by the song, see [`Module::active_channels`]
+    pub channels: ActiveChannels,
+
+    /// Estimated duration of the song, see [`Module::estimated_duration`]
+    pub duration: Duration,
+}
+
+impl Module {
+    /// Collects statistics about the module.
+    ///
+    /// All patterns are counted, also those the order list doesn't play.
+    pub fn stats(&self) -> ModuleStats {
+        let mut effects = BTreeMap::new();
+        let mut note_range: Option<(Note, Note)> = None;
+        for (_, command) in self.patterns.iter().flat_map(|pattern| &pattern.rows).flat_map(Row::iter) {
+            if let Some(effect) = command.effect {
+                let letter = char::from(b'A' + effect.to_raw().0 - 1);
+                *effects.entry(letter).or_insert(0) += 1;
+            }
+            if let Some(NoteCmd::Play(note)) = command.note {
+                note_range = Some(match note_range {
+                    Some((low, high)) => (
+                        if u8::from(note) < u8::from(low) { note } else { low },
+                        if u8::from(note) > u8::from(high) { note } else { high },
+                    ),
+                    None => (note, note),
+                });
+            }
+        }
+
+        ModuleStats {
+            patterns: self.patterns.len(),
+            instruments: self.instruments.len(),
+            samples: self.samples.len(),
+            sample_bytes: self.samples.iter().map(pcm_size).sum(),
+            stored_sample_bytes: self.samples.iter().map(stored_size).sum(),
+            effects,
+            note_range,
+            channels: self.active_channels(),
+            duration: self.estimated_duration().0,
+        }
+    }
+}
+
+/// Returns the size of the sample data as uncompressed PCM.
+fn pcm_size(sample: &Sample) -> usize {
+    if let Some(patch) = &sample.fm_patch {
+        return patch.len();
+    }
+    let flags = sample.encoded.as_ref().map(|encoded| encoded.flags)
+        .or_else(|| sample.deferred.map(|deferred| deferred.flags));
+    let (sixteen_bit, stereo) = match flags {
+        Some(flags) => (flags.contains(SampleFlags::DATA_16BIT), flags.contains(SampleFlags::STEREO)),
+        None => (sample.minimal_bit_depth() == 16, sample.is_stereo()),
+    };
+    let bytes = if sixteen_bit { 2 } else { 1 } * if stereo { 2 } else { 1 };
+    usize::try_from(sample.length()).unwrap_or(usize::MAX).saturating_mul(bytes)
+}
+
+/// Returns the size of the sample data in the parsed file.
+fn stored_size(sample: &Sample) -> usize {
+    match &sample.encoded {
+        Some(encoded) if !sample.is_dirty() => encoded.bytes.len(),
+        _ => pcm_size(sample),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+    use crate::writer::WriteOptions;
+
+    #[test]
+    fn stats() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.samples[0].data = Some(vec![0.0; 1000].into());
+        let mut file = Vec::new();
+        module.write_to_with(&mut file, WriteOptions { compress_samples: true, ..WriteOptions::default() }).unwrap();
+        let module = parser::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+
+        let stats = module.stats();
+        assert_eq!((stats.patterns, stats.instruments, stats.samples), (1, 0, 1));
+        assert_eq!(stats.sample_bytes, 1000);
+        assert!(stats.stored_sample_bytes < 1000);
+        assert_eq!(stats.channels, module.active_channels());
+        assert_eq!(stats.duration, module.estimated_duration().0);
+        let effects = module.patterns.iter().flat_map(|pattern| &pattern.rows).flat_map(Row::iter)
+            .filter(|(_, command)| command.effect.is_some())
+            .count();
+        assert_eq!(stats.effects.values().sum::<usize>(), effects);
+        assert!(stats.effects.contains_key(&'A'));
+    }
+}
@@ -0,0 +1,73 @@
+use super::*;
+use alloc::collections::BTreeSet;
+
+
+/// Song in a module with several songs, see [`Module::subsongs`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Subsong {
+    /// Order position the song starts at
+    pub start: usize,
+
+    /// Order positions the song plays, in ascending order
+    pub orders: Vec<usize>,
+}
+
+impl Module {
+    /// Finds the songs of the module.
+    ///
+    /// Modules can contain several songs, usually separated by an [`Order::EndOfSong`] or reached
+    /// only by a position jump (`Bxx`). The first song starts at the first order, it plays the
+    /// orders reached by following the song the same way as [`Module::estimated_duration`] does.
+    /// Every other song starts at the first order which none of the previous songs play, skipping
+    /// separators, ends of song and orders of missing patterns. The songs are returned by their
+    /// start.
+    ///
+    /// Orders which a song reaches by a jump into the middle of a pattern count as played.
+    pub fn subsongs(&self) -> Vec<Subsong> {
+        let mut played = BTreeSet::new();
+        let mut subsongs = Vec::new();
+        for start in 0..self.orders.len() {
+            let playable = matches!(self.orders[start], Order::Index(pattern) if self.get(pattern).is_some());
+            if !playable || played.contains(&start) {
+                continue;
+            }
+            let mut orders = BTreeSet::new();
+            self.first_pass_from(start, |position, _, _| {
+                orders.insert(position);
+                true
+            });
+            played.extend(orders.iter().copied());
+            subsongs.push(Subsong { start, orders: orders.into_iter().collect() });
+        }
+        subsongs
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subsongs() {
+        let mut module = ModuleBuilder::new().build().unwrap();
+        let pattern = |jump: Option<u8>| {
+            let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 4], truncated: false, highlight: None };
+            if let Some(position) = jump {
+                pattern.set_effect(1, Channel::new(1), EffectCmd::JumpOrder(position));
+            }
+            pattern
+        };
+        // The first song jumps over the hidden song at 2, the third song follows the end.
+        module.patterns = vec![pattern(None), pattern(Some(3)), pattern(Some(0))];
+        let id = |id: u8| Order::Index(PatternId::try_from(id).unwrap());
+        module.orders = vec![id(0), id(1), id(2), id(0), Order::EndOfSong, Order::Separator, id(1), id(9)];
+
+        let subsongs = module.subsongs();
+        assert_eq!(subsongs, [
+            Subsong { start: 0, orders: vec![0, 1, 3] },
+            Subsong { start: 2, orders: vec![0, 1, 2, 3] },
+            Subsong { start: 6, orders: vec![3, 6] },
+        ]);
+    }
+}
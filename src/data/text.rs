@@ -0,0 +1,439 @@
+//! Tracker text notation of pattern cells
+//!
+//! A cell is written as `C-5 01 v64 D01`: the note, the instrument number (decimal, starting from
+//! `01`), the volume column command (a letter followed by a decimal parameter) and the effect (a
+//! letter followed by a hexadecimal parameter). Empty columns are written as dots (`...`, `..`).
+//! Notes are written with their octave (`C-5`, `G#4`), note off as `===`, note cut as `^^^` and
+//! note fade as `~~~`. Parameter control events are written as `PC` (`PCs` if smooth) followed by
+//! the plugin, the parameter and the value in decimal, `PC  01 005 999`.
+//!
+//! Whole patterns are rendered by [`Pattern::render_text`] and written by [`pattern!`] or
+//! [`Pattern::from_text_rows`], pattern selections copied from OpenMPT are parsed by
+//! [`Pattern::from_openmpt_clipboard`].
+
+use super::*;
+use crate::error::ParseCommandError;
+use core::fmt::{self, Display, Write};
+use core::str::FromStr;
+
+
+/// Text of a cell without any command
+const EMPTY_CELL: &str = "... .. ... ...";
+
+/// Letters of the volume column commands with the first raw value and the largest parameter
+const VOLUME_COMMANDS: [(u8, u8, u8); 10] = [
+    (b'v', 0, 64),
+    (b'a', 65, 9),
+    (b'b', 75, 9),
+    (b'c', 85, 9),
+    (b'd', 95, 9),
+    (b'e', 105, 9),
+    (b'f', 115, 9),
+    (b'p', 128, 64),
+    (b'g', 193, 9),
+    (b'h', 203, 9),
+];
+
+
+impl FromStr for Note {
+    type Err = ParseCommandError;
+
+    /// Parses the note with its octave, `C-5` or `G#4`.
+    fn from_str(text: &str) -> Result<Note, ParseCommandError> {
+        let error = ParseCommandError("note");
+        match text.as_bytes() {
+            &[name, accidental, octave @ b'0'..=b'9'] => {
+                let semitone = NOTE_NAMES.iter().position(|&n| n == &[name, accidental]).ok_or(error)?;
+                let semitone = u8::try_from(semitone).unwrap();
+                Ok(Note::try_from((octave - b'0') * 12 + semitone).unwrap())
+            }
+            _ => Err(error),
+        }
+    }
+}
+
+impl Display for NoteCmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NoteCmd::Play(note) => Display::fmt(note, f),
+            NoteCmd::Off => f.write_str("==="),
+            NoteCmd::Cut => f.write_str("^^^"),
+            NoteCmd::Fade => f.write_str("~~~"),
+            NoteCmd::ParamControl { smooth: false, .. } => f.write_str("PC "),
+            NoteCmd::ParamControl { smooth: true, .. } => f.write_str("PCs"),
+        }
+    }
+}
+
+impl FromStr for NoteCmd {
+    type Err = ParseCommandError;
+
+    fn from_str(text: &str) -> Result<NoteCmd, ParseCommandError> {
+        match text {
+            "===" => Ok(NoteCmd::Off),
+            "^^^" => Ok(NoteCmd::Cut),
+            "~~~" => Ok(NoteCmd::Fade),
+            "PC" | "PCs" => Ok(NoteCmd::ParamControl { smooth: text == "PCs", plugin: 0, param: 0, value: 0 }),
+            _ => text.parse().map(NoteCmd::Play),
+        }
+    }
+}
+
+impl Display for VolumeCmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let raw = u8::from(*self);
+        let &(letter, base, _) = VOLUME_COMMANDS.iter()
+            .rev()
+            .find(|&&(_, base, _)| base <= raw)
+            .unwrap();
+        write!(f, "{}{:02}", char::from(letter), raw - base)
+    }
+}
+
+impl FromStr for VolumeCmd {
+    type Err = ParseCommandError;
+
+    /// Parses the volume column command, a letter followed by two decimal digits.
+    fn from_str(text: &str) -> Result<VolumeCmd, ParseCommandError> {
+        let error = ParseCommandError("volume");
+        match text.as_bytes() {
+            &[letter, high @ b'0'..=b'9', low @ b'0'..=b'9'] => {
+                let &(_, base, max) = VOLUME_COMMANDS.iter().find(|&&(l, _, _)| l == letter).ok_or(error)?;
+                let param = (high - b'0') * 10 + (low - b'0');
+                if param > max {
+                    return Err(error);
+                }
+                VolumeCmd::try_from(base + param).map_err(|_| error)
+            }
+            _ => Err(error),
+        }
+    }
+}
+
+impl Display for EffectCmd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (effect, param) = self.to_raw();
+        write!(f, "{}{:02X}", char::from(b'A' + effect - 1), param)
+    }
+}
+
+impl FromStr for EffectCmd {
+    type Err = ParseCommandError;
+
+    /// Parses the effect, a letter followed by two hexadecimal digits.
+    ///
+    /// Fails for the effects the parser skips, like `A00`.
+    fn from_str(text: &str) -> Result<EffectCmd, ParseCommandError> {
+        let error = ParseCommandError("effect");
+        match text.as_bytes() {
+            &[letter @ b'A'..=b'Z', _, _] => {
+                let param = text.get(1..).and_then(|param| u8::from_str_radix(param, 16).ok()).ok_or(error)?;
+                EffectCmd::from_raw(letter - b'A' + 1, param).ok_or(error)
+            }
+            _ => Err(error),
+        }
+    }
+}
+
+/// Writes the cell as `C-5 01 v64 D01`, empty columns are written as dots.
+impl Display for Command {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(note @ NoteCmd::ParamControl { plugin, param, value, .. }) = self.note {
+            return write!(f, "{} {:02} {:03} {:03}", note, plugin, param, value);
+        }
+        match self.note {
+            Some(note) => write!(f, "{}", note)?,
+            None => f.write_str("...")?,
+        }
+        match self.instrument {
+            Some(instrument) => write!(f, " {:02}", instrument.as_u8() + 1)?,
+            None => f.write_str(" ..")?,
+        }
+        match self.volume {
+            Some(volume) => write!(f, " {}", volume)?,
+            None => f.write_str(" ...")?,
+        }
+        match self.effect {
+            Some(effect) => write!(f, " {}", effect),
+            None => f.write_str(" ..."),
+        }
+    }
+}
+
+/// Parses the cell written as by [`Display`], the columns are separated by whitespace.
+///
+/// Missing columns at the end are empty, `C-5 01` plays the note with the instrument without any
+/// volume command or effect.
+impl FromStr for Command {
+    type Err = ParseCommandError;
+
+    fn from_str(text: &str) -> Result<Command, ParseCommandError> {
+        let mut columns = text.split_whitespace();
+        let mut column = || columns.next().filter(|column| column.bytes().any(|byte| byte != b'.'));
+        let note = column().map(str::parse).transpose()?;
+        if let Some(NoteCmd::ParamControl { smooth, .. }) = note {
+            let mut number = |name, max| {
+                let number = column().and_then(|column| column.parse::<u16>().ok()).filter(|&number| number <= max);
+                number.ok_or(ParseCommandError(name))
+            };
+            let plugin = u8::try_from(number("plugin", 255)?).unwrap();
+            let param = u8::try_from(number("parameter", 255)?).unwrap();
+            let value = number("value", 999)?;
+            let note = NoteCmd::ParamControl { smooth, plugin, param, value };
+            if columns.next().is_some() {
+                return Err(ParseCommandError("cell"));
+            }
+            return Ok(Command { note: Some(note), instrument: None, volume: None, effect: None });
+        }
+        let command = Command {
+            note,
+            instrument: column().map(parse_instrument).transpose()?,
+            volume: column().map(str::parse).transpose()?,
+            effect: column().map(str::parse).transpose()?,
+        };
+        if columns.next().is_some() {
+            return Err(ParseCommandError("cell"));
+        }
+        Ok(command)
+    }
+}
+
+impl Pattern {
+    /// Renders the pattern as text in the usual tracker layout.
+    ///
+    /// The first line numbers the channels, it is followed by a line for every row starting with
+    /// the row number. The cells are written as by [`Command`]'s [`Display`] and separated by
+    /// `|`, only the `channels` are rendered:
+    ///
+    /// ```txt
+    ///     | 01             | 02
+    /// 000 | C-5 01 v64 D01 | ... .. ... ...
+    /// 001 | === .. ... ... | ... .. ... C02
+    /// ```
+    pub fn render_text(&self, channels: ActiveChannels) -> String {
+        let mut text = String::from("   ");
+        for channel in channels.iter() {
+            write!(text, " | {:02}{:width$}", channel.as_usize() + 1, "", width = EMPTY_CELL.len() - 2).unwrap();
+        }
+        text.truncate(text.trim_end().len());
+        text.push('\n');
+
+        for (index, row) in self.rows.iter().enumerate() {
+            write!(text, "{:03}", index).unwrap();
+            for channel in channels.iter() {
+                match row.get(channel) {
+                    Some(command) => write!(text, " | {}", command).unwrap(),
+                    None => write!(text, " | {}", EMPTY_CELL).unwrap(),
+                }
+            }
+            text.push('\n');
+        }
+        text
+    }
+
+    /// Creates the pattern from rows of cells written as text, see [`pattern!`](crate::pattern!).
+    ///
+    /// The cells of a row are separated by `|` and parsed as by [`Command`]'s [`FromStr`], the
+    /// first cell is in channel 1. Channels past the last cell of a row and empty cells are empty.
+    /// The active channels are the channels with a command.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a row has more than 64 cells or one of the cells can't be parsed.
+    pub fn from_text_rows<'t>(rows: impl IntoIterator<Item = &'t str>) -> Result<Pattern, ParseCommandError> {
+        let mut pattern = Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: Vec::new(),
+            truncated: false,
+            highlight: None,
+        };
+        for line in rows {
+            let mut row = Row::empty();
+            for (index, cell) in line.split('|').enumerate() {
+                let number = u8::try_from(index + 1).ok().filter(|&number| number <= 64);
+                let channel = Channel::new(number.ok_or(ParseCommandError("channel"))?);
+                let command = cell.parse::<Command>()?;
+                if !command.is_empty() {
+                    pattern.active_channels |= ActiveChannels::new([channel]);
+                    row.insert(channel, command);
+                }
+            }
+            pattern.rows.push(row);
+        }
+        Ok(pattern)
+    }
+
+    /// Parses the pattern selection copied to the clipboard by OpenMPT.
+    ///
+    /// The text starts with a `ModPlug Tracker` header line naming the format, every following
+    /// line is a row of cells starting with `|`. A cell is the note, instrument, volume and effect
+    /// columns without separators, e.g. `|C-501v64D01`. Columns left out of the selection are
+    /// spaces and are empty in the result, as are the columns written as dots.
+    ///
+    /// The first copied channel becomes channel 1 and the first copied row becomes row 0, pasting
+    /// the result at the position of the selection is left to the caller.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the header is missing, a row doesn't start with `|`, there are more than 64
+    /// channels or one of the cells can't be parsed.
+    pub fn from_openmpt_clipboard(text: &str) -> Result<Pattern, ParseCommandError> {
+        let mut lines = text.lines();
+        if !lines.next().is_some_and(|header| header.starts_with("ModPlug Tracker")) {
+            return Err(ParseCommandError("header"));
+        }
+
+        let mut pattern = Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: Vec::new(),
+            truncated: false,
+            highlight: None,
+        };
+        for line in lines.map(str::trim_end).filter(|line| !line.is_empty()) {
+            let cells = line.strip_prefix('|').ok_or(ParseCommandError("row"))?;
+            let mut row = Row::empty();
+            for (index, cell) in cells.split('|').enumerate() {
+                let number = u8::try_from(index + 1).ok().filter(|&number| number <= 64);
+                let channel = Channel::new(number.ok_or(ParseCommandError("channel"))?);
+                let command = clipboard_cell(cell)?;
+                if !command.is_empty() {
+                    pattern.active_channels |= ActiveChannels::new([channel]);
+                    row.insert(channel, command);
+                }
+            }
+            pattern.rows.push(row);
+        }
+        Ok(pattern)
+    }
+}
+
+/// Creates a [`Pattern`] from rows of cells written as text.
+///
+/// Every argument is a row, its cells are separated by `|` and written like in
+/// [`Pattern::render_text`]. Missing columns at the end of a cell and channels past the last cell
+/// are empty. See [`Pattern::from_text_rows`] for the details.
+///
+/// # Panics
+///
+/// Panics if a row can't be parsed.
+///
+/// ```
+/// let pattern = ittech::pattern![
+///     "C-5 01 v64 ... | E-5 01",
+///     "... .. ... ... | ... .. ... A06",
+///     "===",
+/// ];
+/// assert_eq!(pattern.rows.len(), 3);
+/// assert_eq!(pattern.active_channels.count(), 2);
+/// ```
+#[macro_export]
+macro_rules! pattern {
+    ( $( $row: expr ),* $(,)? ) => {
+        match $crate::Pattern::from_text_rows([$( $row ),*]) {
+            Ok(pattern) => pattern,
+            Err(error) => panic!("{}", error),
+        }
+    };
+}
+
+/// Parses the cell of the OpenMPT clipboard, the columns are 3, 2, 3 and 3 characters wide.
+fn clipboard_cell(cell: &str) -> Result<Command, ParseCommandError> {
+    let mut rest = cell;
+    let mut column = |width: usize| {
+        let split = width.min(rest.len());
+        let (column, tail) = if rest.is_char_boundary(split) { rest.split_at(split) } else { (rest, "") };
+        rest = tail;
+        Some(column).filter(|column| column.bytes().any(|byte| byte != b'.' && byte != b' '))
+    };
+    let command = Command {
+        note: column(3).map(str::parse).transpose()?,
+        instrument: column(2).map(parse_instrument).transpose()?,
+        volume: column(3).map(str::parse).transpose()?,
+        effect: column(3).map(str::parse).transpose()?,
+    };
+    if !rest.trim().is_empty() {
+        return Err(ParseCommandError("cell"));
+    }
+    Ok(command)
+}
+
+/// Parses the instrument number, `01` is the first instrument.
+fn parse_instrument(text: &str) -> Result<InstrumentId, ParseCommandError> {
+    let error = ParseCommandError("instrument");
+    if text.len() != 2 || !text.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(error);
+    }
+    let number = text.parse::<u8>().map_err(|_| error)?;
+    number.checked_sub(1).and_then(|id| InstrumentId::try_from(id).ok()).ok_or(error)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cell_text() {
+        for text in ["C-5 01 v64 D01", "G#4 99 p32 SD3", "=== .. ... ...", "^^^ .. h09 T80", "... 10 a00 ...", "PCs 01 005 999"] {
+            let command = text.parse::<Command>().unwrap();
+            assert_eq!(command.to_string(), text);
+        }
+        let command = "C-5 01".parse::<Command>().unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 60));
+        assert!(command.volume.is_none() && command.effect.is_none());
+        assert_eq!("B-9".parse::<Note>().map(u8::from).ok(), Some(119));
+
+        for text in ["E#5 .. ... ...", "C-5 00", "C-5 01 v65", "C-5 01 v64 A00", "C-5 01 v64 D01 x", "PC  01 005 1000"] {
+            assert!(text.parse::<Command>().is_err(), "{}", text);
+        }
+    }
+
+    #[test]
+    fn render_text() {
+        let mut pattern = Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: vec![Row::empty(); 2],
+            truncated: false,
+            highlight: None,
+        };
+        pattern.set_command(0, Channel::new(1), "C-5 01 v64 D01".parse().unwrap());
+        pattern.set_command(1, Channel::new(1), "===".parse().unwrap());
+        pattern.set_command(1, Channel::new(2), "... .. ... C02".parse().unwrap());
+
+        let text = pattern.render_text(ActiveChannels::new([Channel::new(1), Channel::new(2)]));
+        assert_eq!(text, concat!(
+            "    | 01             | 02\n",
+            "000 | C-5 01 v64 D01 | ... .. ... ...\n",
+            "001 | === .. ... ... | ... .. ... C02\n",
+        ));
+    }
+
+    #[test]
+    fn openmpt_clipboard() {
+        let text = "ModPlug Tracker  IT\r\n|C-501v64D01|...........\r\n|===........|        SD3\r\n";
+        let pattern = Pattern::from_openmpt_clipboard(text).unwrap();
+        assert_eq!(pattern.rows.len(), 2);
+        assert_eq!(pattern.render_text(pattern.active_channels), concat!(
+            "    | 01             | 02\n",
+            "000 | C-5 01 v64 D01 | ... .. ... ...\n",
+            "001 | === .. ... ... | ... .. ... SD3\n",
+        ));
+
+        assert!(Pattern::from_openmpt_clipboard("|C-501v64D01").is_err());
+        assert!(Pattern::from_openmpt_clipboard("ModPlug Tracker  IT\n|C-501v64D01X").is_err());
+    }
+
+    #[test]
+    fn text_rows() {
+        let pattern = pattern!["C-5 01 v64 D01", "=== | ... .. ... C02"];
+        assert_eq!(pattern.render_text(pattern.active_channels), concat!(
+            "    | 01             | 02\n",
+            "000 | C-5 01 v64 D01 | ... .. ... ...\n",
+            "001 | === .. ... ... | ... .. ... C02\n",
+        ));
+        assert_eq!(pattern![].rows.len(), 0);
+
+        assert!(Pattern::from_text_rows(["C-5 | C-5 01 v65"]).is_err());
+        assert!(Pattern::from_text_rows([["..."; 65].join("|").as_str()]).is_err());
+    }
+}
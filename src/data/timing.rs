@@ -0,0 +1,405 @@
+use super::*;
+use alloc::collections::BTreeSet;
+use core::time::Duration;
+
+
+/// Number of rows of a pattern missing from the module
+pub(crate) const EMPTY_PATTERN_ROWS: usize = 64;
+
+/// Number of rows after which a song stuck in pattern loops is considered looping
+const MAX_ROWS: usize = 1 << 20;
+
+
+/// Position where a song stops playing, see [`Module::estimated_duration`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SongEnd {
+    /// Playback reached the order, it is an [`Order::EndOfSong`] or past the end of the order list
+    Stop {
+        order: usize,
+    },
+
+    /// Playback jumped to a row which was already played, from there the song repeats
+    Loop {
+        order: usize,
+        row: usize,
+    },
+}
+
+/// Point in the song where the tempo or speed changes, see [`Module::timeline`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimingChange {
+    /// Position in the order list
+    pub order: usize,
+
+    /// Row in the pattern
+    pub row: usize,
+
+    /// Tick in the row, ticks of rows repeated by `SEx` continue counting after the first pass
+    pub tick: usize,
+
+    /// Time from the start of the song to the tick
+    pub time: Duration,
+
+    /// Tempo from this tick on
+    pub tempo: u8,
+
+    /// Speed (ticks per row) from this tick on
+    pub speed: u8,
+}
+
+/// Part of the song which repeats forever, see [`Module::song_loop`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SongLoop {
+    /// Position in the order list the loop jumps back to
+    pub order: usize,
+
+    /// Row in the pattern the loop jumps back to
+    pub row: usize,
+
+    /// Number of rows played in one pass of the loop, rows repeated by `SBx` and `SEx` count every
+    /// time they are played
+    pub rows: usize,
+
+    /// Number of ticks in one pass of the loop
+    pub ticks: usize,
+
+    /// Time one pass of the loop plays for
+    pub duration: Duration,
+}
+
+/// Single tick of the song visited by [`Module::walk_ticks`]
+#[derive(Clone, Copy)]
+pub(crate) struct Tick {
+    pub(crate) order: usize,
+    pub(crate) row: usize,
+    pub(crate) tick: usize,
+    pub(crate) tempo: u8,
+    pub(crate) speed: u8,
+}
+
+/// Timing effect memory of a channel
+#[derive(Clone, Copy, Default)]
+struct ChannelTiming {
+    tempo_slide: Option<Tempo>,
+    special: Option<Special>,
+    loop_start: usize,
+    loop_count: u8,
+}
+
+
+impl Module {
+    /// Estimates how long the song plays and where it stops.
+    ///
+    /// The song is followed from the first order the same way it's played, taking speed and
+    /// tempo (`Axx`, `Txx`), jumps and breaks (`Bxx`, `Cxx`), pattern loops (`SBx`) and row and
+    /// tick delays (`SEx`, `S6x`) into account. Playback stops at the end of the order list or an
+    /// [`Order::EndOfSong`], or when a jump would play a row that was already played.
+    ///
+    /// The duration doesn't include the release of notes still playing when the song stops.
+    pub fn estimated_duration(&self) -> (Duration, SongEnd) {
+        let mut duration = Duration::ZERO;
+        let end = self.walk_ticks(|tick| duration += tick_duration(tick.tempo));
+        (duration, end)
+    }
+
+    /// Finds the part of the song which repeats forever, `None` if the song stops.
+    ///
+    /// A song loops when a jump or break (`Bxx`, `Cxx`) goes back to a row which was already
+    /// played, see [`SongEnd::Loop`]. The loop is the part of the song from the last time that row
+    /// was played up to the jump, renderers can play it again or fade out during it. Songs which
+    /// stop at the end of the orders or an [`Order::EndOfSong`] are restarted from the first order
+    /// by players which loop, then the whole song of [`Module::estimated_duration`] repeats.
+    pub fn song_loop(&self) -> Option<SongLoop> {
+        use alloc::collections::BTreeMap;
+
+        let mut starts = BTreeMap::new();
+        let (mut rows, mut ticks, mut time) = (0, 0, Duration::ZERO);
+        let end = self.walk_ticks(|tick| {
+            if tick.tick == 0 {
+                starts.insert((tick.order, tick.row), (rows, ticks, time));
+                rows += 1;
+            }
+            ticks += 1;
+            time += tick_duration(tick.tempo);
+        });
+        match end {
+            SongEnd::Loop { order, row } => {
+                let &(start_rows, start_ticks, start_time) = starts.get(&(order, row))?;
+                Some(SongLoop {
+                    order,
+                    row,
+                    rows: rows - start_rows,
+                    ticks: ticks - start_ticks,
+                    duration: time - start_time,
+                })
+            }
+            SongEnd::Stop { .. } => None,
+        }
+    }
+
+    /// Returns the points where tempo or speed change during the song, in play order.
+    ///
+    /// The first entry holds the initial tempo and speed at the start of the song, every other
+    /// entry is a tick where either of them differs from the previous tick. Tempo slides (`T0x`,
+    /// `T1x`) produce an entry for every tick they change the tempo on. The song is followed the
+    /// same way as by [`Module::estimated_duration`].
+    pub fn timeline(&self) -> Vec<TimingChange> {
+        let mut changes = Vec::<TimingChange>::new();
+        let mut time = Duration::ZERO;
+        self.walk_ticks(|tick| {
+            let changed = changes.last()
+                .is_none_or(|last| last.tempo != tick.tempo || last.speed != tick.speed);
+            if changed {
+                changes.push(TimingChange {
+                    order: tick.order,
+                    row: tick.row,
+                    tick: tick.tick,
+                    time,
+                    tempo: tick.tempo,
+                    speed: tick.speed,
+                });
+            }
+            time += tick_duration(tick.tempo);
+        });
+        changes
+    }
+
+    /// Visits every tick of the song in play order.
+    ///
+    /// The effects processed are the ones listed under [`Module::estimated_duration`], effect
+    /// memory of `Txx` and `Sxx` is taken into account.
+    pub(crate) fn walk_ticks(&self, mut visit: impl FnMut(Tick)) -> SongEnd {
+        let mut channels = [ChannelTiming::default(); MAX_CHANNELS];
+        let mut visited = BTreeSet::new();
+        let mut slides = Vec::new();
+        let (mut speed, mut tempo) = (self.speed.as_u8(), self.tempo.as_u8());
+        let (mut order, mut row) = (0, 0);
+
+        for _ in 0..MAX_ROWS {
+            let rows = loop {
+                match self.orders.as_slice().get(order) {
+                    Some(Order::Index(pattern)) => {
+                        let rows = self.get(pattern).map_or(EMPTY_PATTERN_ROWS, |pattern| pattern.rows.len());
+                        if rows > 0 {
+                            break rows;
+                        }
+                    }
+                    Some(Order::Separator) => {}
+                    Some(Order::EndOfSong) | None => return SongEnd::Stop { order },
+                }
+                order += 1;
+                row = 0;
+            };
+            if row >= rows {
+                row = 0;
+            }
+            if !visited.insert((order, row)) {
+                return SongEnd::Loop { order, row };
+            }
+
+            let (mut jump, mut break_row, mut loop_row, mut row_delay, mut tick_delay) = (None, None, None, None, 0);
+            slides.clear();
+            let commands = match self.orders[order] {
+                Order::Index(pattern) => self.get(pattern).and_then(|pattern| pattern.row(row)),
+                _ => None,
+            };
+            for (channel, command) in commands.into_iter().flat_map(Row::iter) {
+                let state = &mut channels[channel.as_usize()];
+                match command.effect {
+                    Some(EffectCmd::SetSpeed(value)) => speed = value.as_u8(),
+                    Some(EffectCmd::JumpOrder(value)) => jump = Some(usize::from(value)),
+                    Some(EffectCmd::BreakRow(value)) => break_row = Some(usize::from(value)),
+                    Some(EffectCmd::Tempo(value)) => match value.or(state.tempo_slide) {
+                        Some(Tempo::Set(value)) => tempo = value.as_u8(),
+                        slide => {
+                            state.tempo_slide = slide;
+                            slides.extend(slide);
+                        }
+                    },
+                    Some(EffectCmd::Special(special)) => {
+                        if special.is_some() {
+                            state.special = special;
+                        }
+                        match state.special {
+                            Some(Special::PatternTickDelay(ticks)) => tick_delay = ticks.as_u8(),
+                            Some(Special::PatternRowDelay(rows)) => {
+                                row_delay.get_or_insert(rows.as_u8());
+                            }
+                            Some(Special::SetLoopbackPoint) => state.loop_start = row,
+                            Some(Special::LoopbackTimes(times)) => {
+                                if state.loop_count == 0 {
+                                    state.loop_count = times.as_u8();
+                                    loop_row = Some(state.loop_start);
+                                } else {
+                                    state.loop_count -= 1;
+                                    if state.loop_count > 0 {
+                                        loop_row = Some(state.loop_start);
+                                    } else {
+                                        state.loop_start = row + 1;
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            // Ticks of the repeats of the row don't count the tick delay and all slide the tempo.
+            let ticks = usize::from(speed) * (usize::from(row_delay.unwrap_or(0)) + 1) + usize::from(tick_delay);
+            for tick in 0..ticks {
+                if tick > 0 {
+                    for slide in &slides {
+                        tempo = match slide {
+                            Tempo::SlideDown(value) => tempo.saturating_sub(value.as_u8()),
+                            Tempo::SlideUp(value) => tempo.saturating_add(value.as_u8()),
+                            Tempo::Set(_) => tempo,
+                        }.max(0x20);
+                    }
+                }
+                visit(Tick { order, row, tick, tempo, speed });
+            }
+
+            if let Some(loop_row) = loop_row {
+                // The looped rows are going to be played again, that's not a song loop.
+                visited.retain(|&(o, r)| o != order || r < loop_row);
+                row = loop_row;
+                continue;
+            }
+            if jump.is_some() || break_row.is_some() {
+                order = jump.unwrap_or(order + 1);
+                row = break_row.unwrap_or(0);
+            } else if row + 1 < rows {
+                row += 1;
+                continue;
+            } else {
+                order += 1;
+                row = 0;
+            }
+            for state in &mut channels {
+                state.loop_start = 0;
+                state.loop_count = 0;
+            }
+        }
+
+        SongEnd::Loop { order, row }
+    }
+}
+
+/// Returns the length of a tick at the tempo, 2.5 / tempo seconds.
+pub(crate) fn tick_duration(tempo: u8) -> Duration {
+    Duration::from_nanos(2_500_000_000 / u64::from(tempo.max(1)))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    fn command(effect: EffectCmd) -> Command {
+        Command { note: None, instrument: None, volume: None, effect: Some(effect) }
+    }
+
+    #[test]
+    fn estimated_duration() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.speed = RangedU8::try_from(6).unwrap();
+        module.tempo = RangedU8::try_from(125).unwrap();
+
+        // Row 0 sets speed 3, row 1 is played twice and row 2 jumps back to row 1.
+        let mut rows = vec![Row::empty(); 4];
+        rows[0].insert(Channel::new(1), command(EffectCmd::SetSpeed(RangedU8::try_from(3).unwrap())));
+        rows[1].insert(Channel::new(1), command(EffectCmd::Special(Some(Special::PatternRowDelay(RangedU8::try_from(1).unwrap())))));
+        rows[2].insert(Channel::new(1), command(EffectCmd::JumpOrder(0)));
+        rows[2].insert(Channel::new(2), command(EffectCmd::BreakRow(1)));
+        module.patterns = vec![Pattern { active_channels: ActiveChannels::all(), rows, truncated: false, highlight: None }];
+        module.orders = vec![Order::Index(PatternId::try_from(0).unwrap()), Order::EndOfSong];
+
+        // 3 + 6 + 3 ticks of 20ms each.
+        let (duration, end) = module.estimated_duration();
+        assert_eq!(duration, Duration::from_millis(240));
+        assert_eq!(end, SongEnd::Loop { order: 0, row: 1 });
+        assert_eq!(module.song_loop(), Some(SongLoop {
+            order: 0,
+            row: 1,
+            rows: 2,
+            ticks: 9,
+            duration: Duration::from_millis(180),
+        }));
+
+        module.patterns[0].rows[2] = Row::empty();
+        let (duration, end) = module.estimated_duration();
+        assert_eq!(duration, Duration::from_millis(300));
+        assert_eq!(end, SongEnd::Stop { order: 1 });
+        assert_eq!(module.song_loop(), None);
+    }
+
+    #[test]
+    fn song_loop() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.speed = RangedU8::try_from(6).unwrap();
+        module.tempo = RangedU8::try_from(125).unwrap();
+
+        // The intro plays once, the chorus in the second order jumps back to its start.
+        let pattern = |rows| Pattern { active_channels: ActiveChannels::all(), rows, truncated: false, highlight: None };
+        let mut rows = vec![Row::empty(); 4];
+        rows[3].insert(Channel::new(1), command(EffectCmd::JumpOrder(1)));
+        module.patterns = vec![pattern(vec![Row::empty(); 2]), pattern(rows)];
+        let order = |pattern| Order::Index(PatternId::try_from(pattern).unwrap());
+        module.orders = vec![order(0), order(1), order(1), Order::EndOfSong];
+
+        // 2 intro rows and 4 rows looping, 6 ticks of 20ms each.
+        let (duration, end) = module.estimated_duration();
+        assert_eq!(end, SongEnd::Loop { order: 1, row: 0 });
+        assert_eq!(duration, Duration::from_millis(720));
+        assert_eq!(module.song_loop(), Some(SongLoop {
+            order: 1,
+            row: 0,
+            rows: 4,
+            ticks: 24,
+            duration: Duration::from_millis(480),
+        }));
+
+        // Jumping forward stops at the end of the song.
+        module.patterns[1].rows[3] = Row::empty();
+        module.patterns[1].rows[3].insert(Channel::new(1), command(EffectCmd::JumpOrder(3)));
+        assert_eq!(module.estimated_duration(), (Duration::from_millis(720), SongEnd::Stop { order: 3 }));
+        assert_eq!(module.song_loop(), None);
+    }
+
+    #[test]
+    fn timeline() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.speed = RangedU8::try_from(4).unwrap();
+        module.tempo = RangedU8::try_from(125).unwrap();
+
+        // Row 1 sets tempo 250, row 2 slides it down by 10 on each tick after the first one.
+        let mut rows = vec![Row::empty(); 3];
+        rows[1].insert(Channel::new(1), command(EffectCmd::Tempo(Some(Tempo::Set(RangedU8::try_from(250).unwrap())))));
+        rows[2].insert(Channel::new(1), command(EffectCmd::Tempo(Some(Tempo::SlideDown(RangedU8::try_from(10).unwrap())))));
+        module.patterns = vec![Pattern { active_channels: ActiveChannels::all(), rows, truncated: false, highlight: None }];
+        module.orders = vec![Order::Index(PatternId::try_from(0).unwrap())];
+
+        let change = |row, tick, nanos, tempo| TimingChange {
+            order: 0,
+            row,
+            tick,
+            time: Duration::from_nanos(nanos),
+            tempo,
+            speed: 4,
+        };
+        assert_eq!(module.timeline(), [
+            change(0, 0, 0, 125),
+            change(1, 0, 80_000_000, 250),
+            change(2, 1, 130_000_000, 240),
+            change(2, 2, 140_416_666, 230),
+            change(2, 3, 151_286_231, 220),
+        ]);
+    }
+}
@@ -0,0 +1,236 @@
+use super::*;
+
+
+/// `reserved` field of the header written by OpenMPT, it marks modules saved in OpenMPT's own
+/// flavour of the format
+const OPENMPT_RESERVED: u32 = u32::from_le_bytes(*b"OMPT");
+
+/// Days from 1970-01-01 to 2009-10-31, Schism Tracker counts its build dates from there
+const SCHISM_EPOCH: i32 = 14548;
+
+
+/// Program which saved a module, see [`Module::detected_tracker`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tracker {
+    /// Impulse Tracker, `version` is in binary coded decimal as in the header (`0x0214` is 2.14)
+    ///
+    /// `patch` is the patch level of the updates of version 2.14, 2.14p3 is the common one.
+    ImpulseTracker {
+        version: u16,
+        patch: u8,
+    },
+
+    /// OpenMPT or ModPlug Tracker, `version` has a byte for each of the four parts of the version
+    /// number in binary coded decimal (`0x0129_0000` is 1.29.00.00)
+    ///
+    /// Older files only say in which release series they were saved, then the lower parts are zero.
+    /// Versions older than 1.17 are ModPlug Tracker.
+    OpenMpt {
+        version: u32,
+    },
+
+    /// Schism Tracker
+    SchismTracker(SchismVersion),
+
+    /// BeRoTracker
+    BeRoTracker,
+
+    /// Unpacked from MO3 by UNMO3
+    Unmo3,
+
+    /// None of the known heuristics matched
+    Unknown,
+}
+
+/// Version of Schism Tracker, see [`Tracker::SchismTracker`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SchismVersion {
+    /// Early version 0.x, `minor` is in binary coded decimal (`0x50` is 0.50)
+    ///
+    /// Version 0.50 stands for any build between 2007-04-17 and 2009-10-31.
+    Release {
+        minor: u16,
+    },
+
+    /// Later builds, identified by their date
+    Build {
+        year: i32,
+        month: u8,
+        day: u8,
+    },
+}
+
+impl Module {
+    /// Guesses which program saved the module.
+    ///
+    /// Applies the heuristics OpenMPT uses to the version fields, the reserved field of the header
+    /// and the chunks of extra data. Files can claim any version and the heuristics are only as
+    /// good as the knowledge of the quirks of each program, modules made by rare trackers or
+    /// edited by converters may be misattributed. Works only on modules parsed from IT files, the
+    /// converted ones carry the version of the original format.
+    pub fn detected_tracker(&self) -> Tracker {
+        let made_with = self.made_with_version;
+        let compatible_with = self.compatible_with_version;
+        let reserved = self.opaque.header_reserved;
+
+        if has_header_chunk(&self.opaque.header_chunks, b"MODU") {
+            return Tracker::BeRoTracker;
+        }
+
+        match made_with >> 12 {
+            0x0 => {},
+            0x1 => return Tracker::SchismTracker(schism_version(made_with & 0x0FFF, reserved)),
+            0x5 => {
+                let version = u32::from(made_with & 0x0FFF) << 16;
+                // Since 1.29 the two lower parts are in the reserved field, unless it's the marker.
+                let version = if reserved != OPENMPT_RESERVED && version >= 0x0129_0000 {
+                    version | (reserved & 0xFFFF)
+                } else {
+                    version
+                };
+                return Tracker::OpenMpt { version };
+            },
+            0x6 => return Tracker::BeRoTracker,
+            _ => return Tracker::Unknown,
+        }
+
+        if made_with == 0x0888 || compatible_with == 0x0888 {
+            return Tracker::OpenMpt { version: 0x0117_0000 };
+        }
+        if made_with == 0x0217 && compatible_with == 0x0200 && reserved == 0 {
+            // OpenMPT 1.17 disguises itself like this in its compatibility export, but never
+            // writes the invalid panning 0xFF for unused channels like ModPlug Tracker did.
+            let version = if self.init_channel_panning.contains(&ChannelPan::from(0xFF)) { 0x0116_0000 } else { 0x0117_0000 };
+            return Tracker::OpenMpt { version };
+        }
+        if made_with == 0x0214 && compatible_with == 0x0202 && reserved == 0 {
+            return Tracker::OpenMpt { version: 0x0109_0000 };
+        }
+        if made_with == 0x0214 && compatible_with == 0x0214 && reserved == 0 && self.edit_history.is_empty() {
+            // Impulse Tracker 2.14 always keeps either the edit history or the total edit time in
+            // the reserved field, UNMO3 writes neither.
+            return Tracker::Unmo3;
+        }
+        if made_with == 0 {
+            return Tracker::Unknown;
+        }
+
+        if compatible_with > 0x0214 {
+            Tracker::ImpulseTracker { version: 0x0215, patch: 0 }
+        } else if made_with > 0x0214 {
+            // The updates of 2.14 count up the version, 0x0215 to 0x0217 are patches 1 to 3.
+            let patch = u8::try_from(made_with - 0x0214).unwrap_or(u8::MAX);
+            Tracker::ImpulseTracker { version: 0x0214, patch }
+        } else {
+            Tracker::ImpulseTracker { version: made_with, patch: 0 }
+        }
+    }
+}
+
+impl fmt::Display for Tracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Tracker::ImpulseTracker { version, patch: 0 } => {
+                write!(f, "Impulse Tracker {:x}.{:02x}", version >> 8, version & 0xFF)
+            },
+            Tracker::ImpulseTracker { version, patch } => {
+                write!(f, "Impulse Tracker {:x}.{:02x}p{}", version >> 8, version & 0xFF, patch)
+            },
+            Tracker::OpenMpt { version } => {
+                let name = if version < 0x0117_0000 { "ModPlug Tracker" } else { "OpenMPT" };
+                let [major, minor, revision, build] = version.to_be_bytes();
+                write!(f, "{} {:x}.{:02x}.{:02x}.{:02x}", name, major, minor, revision, build)
+            },
+            Tracker::SchismTracker(SchismVersion::Release { minor }) => write!(f, "Schism Tracker 0.{:02x}", minor),
+            Tracker::SchismTracker(SchismVersion::Build { year, month, day }) => {
+                write!(f, "Schism Tracker {:04}-{:02}-{:02}", year, month, day)
+            },
+            Tracker::BeRoTracker => f.write_str("BeRoTracker"),
+            Tracker::Unmo3 => f.write_str("UNMO3"),
+            Tracker::Unknown => f.write_str("unknown"),
+        }
+    }
+}
+
+/// Decodes the version Schism Tracker stores in the lower 12 bits of `made_with_version`.
+///
+/// Above 0x050 it's the days since [`SCHISM_EPOCH`] plus 0x050, 0xFFF means the days are in the
+/// reserved field.
+fn schism_version(version: u16, reserved: u32) -> SchismVersion {
+    if version <= 0x050 {
+        return SchismVersion::Release { minor: version };
+    }
+    let days = if version < 0xFFF { u32::from(version - 0x050) } else { reserved };
+    let (year, month, day) = civil_from_days(SCHISM_EPOCH.saturating_add(i32::try_from(days).unwrap_or(i32::MAX)));
+    SchismVersion::Build { year, month, day }
+}
+
+/// Converts days since 1970-01-01 to a date in the proleptic Gregorian calendar.
+fn civil_from_days(days: i32) -> (i32, u8, u8) {
+    // <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+    let days = i64::from(days) + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (
+        i32::try_from(year).unwrap_or(i32::MAX),
+        u8::try_from(month).unwrap(),
+        u8::try_from(day).unwrap(),
+    )
+}
+
+/// Checks whether the unknown chunks after the header contain the chunk `code`.
+///
+/// Each chunk is a 4 byte code and a 32-bit length followed by the data, the search stops at the
+/// first chunk which doesn't fit.
+fn has_header_chunk(chunks: &[u8], code: &[u8; 4]) -> bool {
+    header_chunks(chunks).any(|(other, _)| &other == code)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+    use alloc::string::ToString;
+
+    #[test]
+    fn detected_tracker() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        assert_eq!(module.detected_tracker(), Tracker::OpenMpt { version: 0x0129_0000 });
+        assert_eq!(module.detected_tracker().to_string(), "OpenMPT 1.29.00.00");
+
+        module.opaque.header_reserved = 0x0A_03;
+        assert_eq!(module.detected_tracker().to_string(), "OpenMPT 1.29.0a.03");
+
+        module.made_with_version = 0x0217;
+        assert_eq!(module.detected_tracker().to_string(), "Impulse Tracker 2.14p3");
+        module.compatible_with_version = 0x0215;
+        assert_eq!(module.detected_tracker().to_string(), "Impulse Tracker 2.15");
+
+        module.made_with_version = 0x1050;
+        assert_eq!(module.detected_tracker().to_string(), "Schism Tracker 0.50");
+        module.made_with_version = 0x1051;
+        assert_eq!(module.detected_tracker().to_string(), "Schism Tracker 2009-11-01");
+        module.made_with_version = 0x1FFF;
+        module.opaque.header_reserved = 4015;
+        assert_eq!(module.detected_tracker().to_string(), "Schism Tracker 2020-10-28");
+
+        module.made_with_version = 0x0214;
+        module.compatible_with_version = 0x0214;
+        module.opaque.header_reserved = 0;
+        assert_eq!(module.detected_tracker(), Tracker::ImpulseTracker { version: 0x0214, patch: 0 });
+        module.edit_history.clear();
+        assert_eq!(module.detected_tracker(), Tracker::Unmo3);
+
+        module.opaque.header_chunks = b"FX00\x02\x00\x00\x00abMODU\x00\x00\x00\x00".to_vec();
+        assert_eq!(module.detected_tracker(), Tracker::BeRoTracker);
+    }
+}
@@ -0,0 +1,103 @@
+use super::*;
+
+
+/// Notes changed by [`Pattern::transpose_filtered`] and [`Module::transpose_filtered`]
+#[derive(Clone, Debug)]
+pub struct TransposeFilter {
+    /// Channels whose notes are transposed
+    pub channels: ActiveChannels,
+
+    /// Instruments whose notes are transposed, `None` transposes the notes of all instruments
+    ///
+    /// In sample mode the commands reference samples, the IDs are then the samples. Notes without
+    /// an instrument belong to the last instrument on their channel in the same pattern, notes
+    /// before the first instrument of the channel are not transposed when filtering.
+    pub instruments: Option<Vec<InstrumentId>>,
+}
+
+impl Default for TransposeFilter {
+    /// Transposes all notes.
+    fn default() -> TransposeFilter {
+        TransposeFilter { channels: ActiveChannels::all(), instruments: None }
+    }
+}
+
+impl Pattern {
+    /// Shifts all notes of the pattern by the semitones, see [`Pattern::transpose_filtered`].
+    pub fn transpose(&mut self, semitones: i8) {
+        self.transpose_filtered(semitones, &TransposeFilter::default());
+    }
+
+    /// Shifts the notes selected by the filter by the semitones.
+    ///
+    /// Notes are clamped to the range C-0 to B-9, note off, cut and fade are left untouched.
+    pub fn transpose_filtered(&mut self, semitones: i8, filter: &TransposeFilter) {
+        let mut instruments = [None; MAX_CHANNELS];
+        for row in &mut self.rows {
+            for (channel, command) in row.iter_mut() {
+                let instrument = &mut instruments[channel.as_usize()];
+                if command.instrument.is_some() {
+                    *instrument = command.instrument;
+                }
+                let selected = match &filter.instruments {
+                    Some(ids) => instrument.is_some_and(|instrument| ids.contains(&instrument)),
+                    None => true,
+                };
+                if let Some(NoteCmd::Play(note)) = &mut command.note {
+                    if selected && filter.channels.contains(channel) {
+                        *note = note.transposed(semitones);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Module {
+    /// Shifts all notes of all patterns by the semitones, see [`Pattern::transpose_filtered`].
+    pub fn transpose(&mut self, semitones: i8) {
+        self.transpose_filtered(semitones, &TransposeFilter::default());
+    }
+
+    /// Shifts the notes of all patterns selected by the filter by the semitones.
+    ///
+    /// Every pattern is transposed on its own as by [`Pattern::transpose_filtered`], instruments
+    /// set in the previous pattern of the song don't carry over.
+    pub fn transpose_filtered(&mut self, semitones: i8, filter: &TransposeFilter) {
+        for pattern in &mut self.patterns {
+            pattern.transpose_filtered(semitones, filter);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transpose() {
+        let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 3], truncated: false, highlight: None };
+        let (first, second) = (InstrumentId::try_from(0).unwrap(), InstrumentId::try_from(1).unwrap());
+        let (left, right) = (Channel::new(1), Channel::new(2));
+        pattern.set_note(0, left, NoteCmd::Play(Note::C_5));
+        pattern.set_note(1, left, NoteCmd::Play(Note::A_9));
+        pattern.set_instrument(1, left, first);
+        pattern.set_note(2, left, NoteCmd::Play(Note::D_5));
+        pattern.set_note(0, right, NoteCmd::Off);
+        pattern.set_note(1, right, NoteCmd::Play(Note::C_0));
+        pattern.set_instrument(1, right, second);
+
+        let notes = |pattern: &Pattern| {
+            pattern.rows().flat_map(|row| row.iter().map(|(_, command)| command.note.map(|note| note.to_string())))
+                .collect::<Vec<_>>()
+        };
+        let mut all = pattern.clone();
+        all.transpose(-3);
+        assert_eq!(notes(&all), ["A-4", "===", "F#9", "C-0", "B-4"].map(|note| Some(String::from(note))));
+
+        let filter = TransposeFilter { instruments: Some(vec![first]), ..TransposeFilter::default() };
+        pattern.transpose_filtered(12, &filter);
+        assert_eq!(notes(&pattern), ["C-5", "===", "B-9", "C-0", "D-6"].map(|note| Some(String::from(note))));
+    }
+}
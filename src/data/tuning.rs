@@ -0,0 +1,92 @@
+use super::*;
+
+
+/// Custom tuning of an instrument, see [`Instrument::tuning`]
+///
+/// *MPTM extension.* The tuning gives the frequency ratio of every note to C-5, which plays at
+/// the C-5 speed of the sample. The ratios of a group of notes starting at C-5 are listed, the
+/// notes outside of the group repeat it multiplied by the ratio of the group, the same way the
+/// octaves repeat in equal temperament. OpenMPT's geometric and group geometric tunings map to
+/// this directly, a general tuning is a group covering all notes.
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Tuning {
+    pub name: String,
+
+    /// Ratios of the notes of the group to C-5, the first one is usually `1.0`
+    pub ratios: Vec<f32>,
+
+    /// Ratio of a note to the note one group higher, `2.0` for groups of an octave
+    pub group_ratio: f32,
+}
+
+impl Tuning {
+    /// Returns the 12 tone equal temperament, which is used by instruments without a tuning.
+    pub fn equal_temperament() -> Tuning {
+        Tuning {
+            name: String::from("12TET"),
+            ratios: (0..12u8).map(|step| float::powf(2.0, f32::from(step) / 12.0)).collect(),
+            group_ratio: 2.0,
+        }
+    }
+
+    /// Returns the frequency ratio of the note to C-5.
+    ///
+    /// A tuning without ratios has a group of one note, every note is then `group_ratio` higher
+    /// than the previous one.
+    pub fn ratio(&self, note: Note) -> f32 {
+        let size = i32::try_from(self.ratios.len().max(1)).unwrap_or(i32::MAX);
+        let steps = i32::from(u8::from(note)) - i32::from(u8::from(Note::C_5));
+        let group = i16::try_from(steps.div_euclid(size)).unwrap();
+        let index = usize::try_from(steps.rem_euclid(size)).unwrap();
+        let ratio = self.ratios.as_slice().get(index).copied().unwrap_or(1.0);
+        ratio * float::powf(self.group_ratio, f32::from(group))
+    }
+
+    /// Returns the frequency the note plays a sample with the C-5 speed at, in Hz.
+    pub fn frequency(&self, note: Note, c5_speed: u32) -> f32 {
+        #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+        let c5_speed = c5_speed as f32;
+        c5_speed * self.ratio(note)
+    }
+}
+
+impl Instrument {
+    /// Returns the frequency the instrument plays a sample with the C-5 speed at, in Hz.
+    ///
+    /// Uses the [tuning](Instrument::tuning) of the instrument if it has one, the frequency
+    /// doesn't depend on the slide mode then. See [`Note::frequency`] otherwise.
+    pub fn note_frequency(&self, note: Note, c5_speed: u32, linear_slides: bool) -> f32 {
+        match &self.tuning {
+            Some(tuning) => tuning.frequency(note, c5_speed),
+            None => note.frequency(c5_speed, linear_slides),
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::formats::empty_instrument;
+
+    #[test]
+    fn tunings() {
+        let tuning = Tuning::equal_temperament();
+        for note in [Note::C_5, Note::A_4, Note::C_6] {
+            assert!((tuning.frequency(note, 8363) - note.frequency(8363, true)).abs() < 0.01);
+        }
+
+        // Groups of the root, the just major third and the just fifth.
+        let tuning = Tuning { name: String::from("just"), ratios: vec![1.0, 1.25, 1.5], group_ratio: 2.0 };
+        let mut instrument = empty_instrument();
+        instrument.tuning = Some(tuning);
+        let note = |raw| Note::try_from(raw).unwrap();
+        assert_eq!(instrument.note_frequency(note(61), 1000, false), 1250.0);
+        assert_eq!(instrument.note_frequency(note(63), 1000, false), 2000.0);
+        assert_eq!(instrument.note_frequency(note(59), 1000, false), 750.0);
+
+        let steps = Tuning { name: String::new(), ratios: Vec::new(), group_ratio: 1.5 };
+        assert_eq!(steps.ratio(note(58)), 1.0 / 2.25);
+    }
+}
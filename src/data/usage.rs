@@ -0,0 +1,124 @@
+use super::*;
+
+
+impl Module {
+    /// Returns the pattern, row index and channel of the cells referencing the sample, in pattern,
+    /// row and channel order.
+    ///
+    /// In sample mode these are the cells with the sample in the instrument column. In instrument
+    /// mode they are the cells playing a note which the instrument sample map maps to the
+    /// sample, the note plays the instrument of the cell or the last one on the channel earlier
+    /// in the same pattern. Use [`Module::instruments_using_sample`] for the sample maps
+    /// themselves.
+    pub fn find_sample_uses(&self, sample: SampleId) -> Vec<(PatternId, usize, Channel)> {
+        if !self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            return self.find_cells(|_, command| command.instrument.is_some_and(|id| id.as_u8() == sample.as_u8()));
+        }
+        let mut uses = Vec::new();
+        for (pattern_id, pattern) in self.indexed_patterns() {
+            let mut instruments = [None; MAX_CHANNELS];
+            for (row_idx, row) in pattern.rows.iter().enumerate() {
+                for (channel, command) in row.iter() {
+                    let instrument = &mut instruments[channel.as_usize()];
+                    if command.instrument.is_some() {
+                        *instrument = command.instrument;
+                    }
+                    let note = match command.note {
+                        Some(NoteCmd::Play(note)) => note,
+                        _ => continue,
+                    };
+                    let mapped = instrument.and_then(|id| self.get(id)).and_then(|instrument| instrument.sample_map[note]);
+                    if mapped == Some(sample) {
+                        uses.push((pattern_id, row_idx, channel));
+                    }
+                }
+            }
+        }
+        uses
+    }
+
+    /// Returns the cells with the instrument in the instrument column, in pattern, row and
+    /// channel order.
+    ///
+    /// Instruments are only played in instrument mode, in sample mode the instrument column
+    /// references samples and nothing is returned.
+    pub fn find_instrument_uses(&self, instrument: InstrumentId) -> Vec<(PatternId, usize, Channel)> {
+        if !self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            return Vec::new();
+        }
+        self.find_cells(|_, command| command.instrument == Some(instrument))
+    }
+
+    /// Returns the cells playing the note, in pattern, row and channel order.
+    pub fn find_note_uses(&self, note: Note) -> Vec<(PatternId, usize, Channel)> {
+        self.find_cells(|_, command| matches!(command.note, Some(NoteCmd::Play(played)) if u8::from(played) == u8::from(note)))
+    }
+
+    /// Returns the instruments whose sample map references the sample.
+    pub fn instruments_using_sample(&self, sample: SampleId) -> Vec<InstrumentId> {
+        self.instruments
+            .iter()
+            .enumerate()
+            .filter(|(_, instrument)| instrument.sample_map.map.contains(&Some(sample)))
+            .map(|(idx, _)| InstrumentId::try_from(u8::try_from(idx).unwrap()).unwrap())
+            .collect()
+    }
+
+    fn find_cells(&self, mut matches: impl FnMut(Channel, &Command) -> bool) -> Vec<(PatternId, usize, Channel)> {
+        let mut cells = Vec::new();
+        for (pattern_id, pattern) in self.indexed_patterns() {
+            for (row_idx, row) in pattern.rows.iter().enumerate() {
+                for (channel, command) in row.iter() {
+                    if matches(channel, command) {
+                        cells.push((pattern_id, row_idx, channel));
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    fn indexed_patterns(&self) -> impl Iterator<Item = (PatternId, &Pattern)> + '_ {
+        self.patterns
+            .iter()
+            .enumerate()
+            .map(|(idx, pattern)| (PatternId::try_from(u8::try_from(idx).unwrap()).unwrap(), pattern))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn find_uses() {
+        let mut module = ModuleBuilder::new().build().unwrap();
+        let (first, second) = (SampleId::try_from(0).unwrap(), SampleId::try_from(1).unwrap());
+        let instrument = InstrumentId::try_from(0).unwrap();
+        module.samples = vec![crate::formats::empty_sample(); 2];
+        module.instruments = vec![crate::formats::empty_instrument()];
+        module.instruments[0].sample_map.map.fill(Some(first));
+        module.instruments[0].sample_map.map[usize::from(u8::from(Note::C_6))] = Some(second);
+
+        let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 4], truncated: false, highlight: None };
+        pattern.set_note(0, Channel::new(2), NoteCmd::Play(Note::C_5));
+        pattern.set_instrument(0, Channel::new(2), instrument);
+        pattern.set_note(1, Channel::new(2), NoteCmd::Play(Note::C_6));
+        pattern.set_note(2, Channel::new(2), NoteCmd::Off);
+        pattern.set_instrument(3, Channel::new(1), InstrumentId::try_from(1).unwrap());
+        module.patterns = vec![Pattern { active_channels: ActiveChannels::empty(), rows: Vec::new(), truncated: false, highlight: None }, pattern];
+        let pattern = PatternId::try_from(1).unwrap();
+
+        module.flags.insert(ModuleFlags::USE_INSTRUMENTS);
+        assert_eq!(module.find_sample_uses(first), [(pattern, 0, Channel::new(2))]);
+        assert_eq!(module.find_sample_uses(second), [(pattern, 1, Channel::new(2))]);
+        assert_eq!(module.find_instrument_uses(instrument), [(pattern, 0, Channel::new(2))]);
+        assert_eq!(module.find_note_uses(Note::C_6), [(pattern, 1, Channel::new(2))]);
+        assert_eq!(module.instruments_using_sample(second), [instrument]);
+
+        module.flags.remove(ModuleFlags::USE_INSTRUMENTS);
+        assert_eq!(module.find_sample_uses(second), [(pattern, 3, Channel::new(1))]);
+        assert!(module.find_instrument_uses(instrument).is_empty());
+    }
+}
@@ -1,25 +1,146 @@
-use crate::error::OutOfRangeError;
-use std::convert::TryFrom;
-use std::fmt::{self, Write};
+use crate::error::{InvalidNameError, OutOfRangeError};
+use alloc::string::String;
+use core::convert::TryFrom;
+use core::fmt::{self, Write};
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Name {
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serialization::byte_array"))]
     pub bytes: [u8; 26],
 }
 
 #[derive(Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DosFilename {
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serialization::byte_array"))]
     pub bytes: [u8; 13],
 }
 
+/// MIDI macro, null-terminated text of hexadecimal bytes and parameter letters
+///
+/// For example `F0F000z` sets the filter cutoff to the value of the `z` parameter.
+#[derive(Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MidiMacro {
+    #[cfg_attr(feature = "serde", serde(with = "crate::data::serialization::byte_array"))]
+    pub bytes: [u8; 32],
+}
+
 #[derive(Clone, Copy, PartialOrd, Ord, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "u8", into = "u8"))]
 pub struct RangedU8<const LOW: u8, const HIGH: u8>(u8);
 
 
+impl Name {
+    /// Maximum length of the name in bytes, the last byte is kept for the null terminator
+    pub const MAX_LEN: usize = 25;
+
+    /// Creates the name from the text, truncated to [`Name::MAX_LEN`] bytes.
+    ///
+    /// The text is cut at the first null character and before the first UTF-8 character which
+    /// doesn't fit, the rest of the name is padded by null bytes.
+    pub fn new_truncate(text: &str) -> Name {
+        Name { bytes: truncated(text) }
+    }
+}
+
+impl TryFrom<&str> for Name {
+    type Error = InvalidNameError;
+
+    /// Creates the name padded by null bytes, fails if the text doesn't fit in [`Name::MAX_LEN`]
+    /// bytes or contains a null character.
+    fn try_from(text: &str) -> Result<Name, InvalidNameError> {
+        Ok(Name { bytes: exact(text)? })
+    }
+}
+
+impl DosFilename {
+    /// Maximum length of the file name in bytes, the last byte is kept for the null terminator
+    pub const MAX_LEN: usize = 12;
+
+    /// Creates the file name from the text, truncated like [`Name::new_truncate`].
+    pub fn new_truncate(text: &str) -> DosFilename {
+        DosFilename { bytes: truncated(text) }
+    }
+}
+
+impl TryFrom<&str> for DosFilename {
+    type Error = InvalidNameError;
+
+    /// Creates the file name padded by null bytes, fails if the text doesn't fit in
+    /// [`DosFilename::MAX_LEN`] bytes or contains a null character.
+    fn try_from(text: &str) -> Result<DosFilename, InvalidNameError> {
+        Ok(DosFilename { bytes: exact(text)? })
+    }
+}
+
+impl MidiMacro {
+    /// Creates the macro from the text, truncated to 31 bytes to keep the null terminator.
+    pub fn new(text: &str) -> MidiMacro {
+        let mut bytes = [0; 32];
+        let len = text.len().min(31);
+        bytes[..len].copy_from_slice(&text.as_bytes()[..len]);
+        MidiMacro { bytes }
+    }
+
+    /// Returns `true` if the macro does nothing.
+    pub fn is_empty(&self) -> bool {
+        null_terminated(&self.bytes).is_empty()
+    }
+}
+
 impl<const LOW: u8, const HIGH: u8> RangedU8<LOW, HIGH> {
-    pub fn as_u8(self) -> u8 {
+    /// Lowest value of the range
+    pub const MIN: Self = RangedU8(LOW);
+
+    /// Highest value of the range
+    pub const MAX: Self = RangedU8(HIGH);
+
+    /// Creates the value, returns `None` if it's out of the range.
+    pub const fn new(raw: u8) -> Option<Self> {
+        if LOW <= raw && raw <= HIGH {
+            Some(RangedU8(raw))
+        } else {
+            None
+        }
+    }
+
+    /// Creates the value, clamped to the range.
+    pub const fn clamp_from(raw: u8) -> Self {
+        if raw < LOW {
+            RangedU8(LOW)
+        } else if raw > HIGH {
+            RangedU8(HIGH)
+        } else {
+            RangedU8(raw)
+        }
+    }
+
+    pub const fn as_u8(self) -> u8 {
         self.0
     }
+
+    /// Adds to the value, returns `None` if the result is out of the range.
+    pub fn checked_add(self, rhs: u8) -> Option<Self> {
+        self.0.checked_add(rhs).and_then(Self::new)
+    }
+
+    /// Subtracts from the value, returns `None` if the result is out of the range.
+    pub fn checked_sub(self, rhs: u8) -> Option<Self> {
+        self.0.checked_sub(rhs).and_then(Self::new)
+    }
+
+    /// Adds to the value, stopping at the top of the range.
+    pub fn saturating_add(self, rhs: u8) -> Self {
+        Self::clamp_from(self.0.saturating_add(rhs))
+    }
+
+    /// Subtracts from the value, stopping at the bottom of the range.
+    pub fn saturating_sub(self, rhs: u8) -> Self {
+        Self::clamp_from(self.0.saturating_sub(rhs))
+    }
 }
 
 impl<const LOW: u8, const HIGH: u8> TryFrom<u8> for RangedU8<LOW, HIGH> {
@@ -40,7 +161,30 @@ impl<const LOW: u8, const HIGH: u8> From<RangedU8<LOW, HIGH>> for u8 {
     }
 }
 
-fn null_terminated(bytes: &[u8]) -> &[u8] {
+/// Pads the text by null bytes, keeping at least one at the end.
+fn exact<const N: usize>(text: &str) -> Result<[u8; N], InvalidNameError> {
+    if let Some(position) = text.bytes().position(|byte| byte == 0) {
+        return Err(InvalidNameError::ContainsNull { position });
+    }
+    if text.len() >= N {
+        return Err(InvalidNameError::TooLong { length: text.len(), max: N - 1 });
+    }
+    let mut bytes = [0; N];
+    bytes[..text.len()].copy_from_slice(text.as_bytes());
+    Ok(bytes)
+}
+
+/// Pads the text by null bytes, cutting it at the first null character and at a character
+/// boundary to keep at least one null byte at the end.
+fn truncated<const N: usize>(text: &str) -> [u8; N] {
+    let text = text.split('\0').next().unwrap_or_default();
+    let len = (0..N).rev().find(|&len| text.is_char_boundary(len)).unwrap_or(0);
+    let mut bytes = [0; N];
+    bytes[..len].copy_from_slice(&text.as_bytes()[..len]);
+    bytes
+}
+
+pub(crate) fn null_terminated(bytes: &[u8]) -> &[u8] {
     let null_pos = bytes.iter()
         .position(|&b| b == 0)
         .unwrap_or(bytes.len());
@@ -71,6 +215,12 @@ impl fmt::Debug for DosFilename {
     }
 }
 
+impl fmt::Debug for MidiMacro {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        debug_bytestring(&self.bytes, f)
+    }
+}
+
 impl fmt::Display for Name {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         String::from_utf8_lossy(null_terminated(&self.bytes)).fmt(f)
@@ -83,8 +233,54 @@ impl fmt::Display for DosFilename {
     }
 }
 
+impl fmt::Display for MidiMacro {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        String::from_utf8_lossy(null_terminated(&self.bytes)).fmt(f)
+    }
+}
+
 impl<const LOW: u8, const HIGH: u8> fmt::Debug for RangedU8<LOW, HIGH> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.as_u8().fmt(f)
     }
 }
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::string::ToString;
+
+    #[test]
+    fn names() {
+        assert_eq!(Name::new_truncate("Lead").bytes[..5], *b"Lead\0");
+        assert_eq!(Name::new_truncate("Lead").to_string(), "Lead");
+        assert_eq!(Name::try_from("Lead").unwrap().bytes, Name::new_truncate("Lead").bytes);
+
+        let long = "abcdefghijklmnopqrstuvwxé";
+        assert_eq!(Name::new_truncate(long).to_string(), "abcdefghijklmnopqrstuvwx");
+        assert_eq!(Name::try_from(long).unwrap_err(), InvalidNameError::TooLong { length: 26, max: 25 });
+        assert_eq!(Name::new_truncate("a\0b").to_string(), "a");
+        assert_eq!(Name::try_from("a\0b").unwrap_err(), InvalidNameError::ContainsNull { position: 1 });
+
+        assert_eq!(DosFilename::try_from("SAMPLE01.WAV").unwrap().to_string(), "SAMPLE01.WAV");
+        assert!(DosFilename::try_from("SAMPLE001.WAV").is_err());
+    }
+
+    #[test]
+    fn ranged_u8() {
+        type Tempo = RangedU8<31, 255>;
+        const DEFAULT: Tempo = match Tempo::new(125) {
+            Some(tempo) => tempo,
+            None => panic!(),
+        };
+        assert_eq!(Tempo::new(30), None);
+        assert_eq!(Tempo::clamp_from(0), Tempo::MIN);
+        assert_eq!(DEFAULT.checked_add(130), Some(Tempo::MAX));
+        assert_eq!(DEFAULT.checked_add(131), None);
+        assert_eq!(DEFAULT.checked_sub(95), None);
+        assert_eq!(DEFAULT.saturating_add(200), Tempo::MAX);
+        assert_eq!(DEFAULT.saturating_sub(100).as_u8(), 31);
+        assert_eq!(Tempo::try_from(31).ok(), Some(Tempo::MIN));
+    }
+}
@@ -0,0 +1,179 @@
+use super::*;
+use crate::error::{InvalidEnvelopeError, InvalidOrderError, InvalidSampleLoopError};
+
+
+/// Maximum length of the song message Impulse Tracker can load, in bytes
+pub(crate) const MAX_MESSAGE_LENGTH: usize = 8000;
+
+
+/// Problem found by [`Module::validate`]
+#[derive(Clone, Copy, Debug)]
+pub enum ValidationIssue {
+    /// Order references a pattern which is not present in the module
+    MissingPattern(InvalidOrderError),
+
+    /// Sample map of the instrument plays a sample which is not present in the module
+    MissingSample {
+        instrument: InstrumentId,
+
+        /// Note of the sample map entry
+        note: Note,
+        sample: SampleId,
+    },
+
+    /// Command in a pattern uses an instrument (or sample in sample mode) which is not present in
+    /// the module
+    MissingInstrument {
+        pattern: PatternId,
+        row: usize,
+        channel: Channel,
+        instrument: InstrumentId,
+    },
+
+    /// Loop of the sample is empty or ends past the end of the sample data
+    InvalidSampleLoop {
+        sample: SampleId,
+
+        /// The loop is the sustain loop
+        sustain: bool,
+        error: InvalidSampleLoopError,
+    },
+
+    /// Envelope of the instrument breaks one of the envelope invariants
+    InvalidEnvelope {
+        instrument: InstrumentId,
+        kind: EnvelopeKind,
+        error: InvalidEnvelopeError,
+    },
+
+    /// Song message is longer than Impulse Tracker can load, the length is in bytes
+    MessageTooLong(usize),
+}
+
+impl Module {
+    /// Checks the module for problems which make it play wrong or fail to load in trackers.
+    ///
+    /// Returns all problems found, an empty list if the module is valid. Modules read from files
+    /// can have any of them, editing the public fields directly can introduce them.
+    pub fn validate(&self) -> Vec<ValidationIssue> {
+        let mut issues = Vec::new();
+
+        for (position, order) in self.orders.iter().enumerate() {
+            if let Order::Index(pattern) = *order {
+                if self.get(pattern).is_none() {
+                    issues.push(ValidationIssue::MissingPattern(InvalidOrderError { position, pattern }));
+                }
+            }
+        }
+
+        for (instrument_id, instrument) in self.instruments.iter().enumerate() {
+            let instrument_id = InstrumentId::try_from(u8::try_from(instrument_id).unwrap()).unwrap();
+            for (note, sample) in instrument.sample_map.map.iter().enumerate() {
+                if let Some(sample) = sample.filter(|&sample| self.get(sample).is_none()) {
+                    let note = Note::try_from(u8::try_from(note).unwrap()).unwrap();
+                    issues.push(ValidationIssue::MissingSample { instrument: instrument_id, note, sample });
+                }
+            }
+            for kind in [EnvelopeKind::Volume, EnvelopeKind::Panning, EnvelopeKind::PitchFilter] {
+                if let Err(error) = instrument.envelope(kind).validate(kind) {
+                    issues.push(ValidationIssue::InvalidEnvelope { instrument: instrument_id, kind, error });
+                }
+            }
+        }
+
+        let instruments = if self.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            self.instruments.len()
+        } else {
+            self.samples.len()
+        };
+        for (pattern_id, pattern) in self.patterns.iter().enumerate() {
+            let pattern_id = PatternId::try_from(u8::try_from(pattern_id).unwrap()).unwrap();
+            for (row, commands) in pattern.rows.iter().enumerate() {
+                for (channel, command) in commands.iter() {
+                    let missing = command.instrument.filter(|&id| usize::from(id.as_u8()) >= instruments);
+                    if let Some(instrument) = missing {
+                        issues.push(ValidationIssue::MissingInstrument { pattern: pattern_id, row, channel, instrument });
+                    }
+                }
+            }
+        }
+
+        for (sample_id, sample) in self.samples.iter().enumerate() {
+            let sample_id = SampleId::try_from(u8::try_from(sample_id).unwrap()).unwrap();
+            for (sample_loop, sustain) in [(sample.loop_, false), (sample.sustain_loop, true)] {
+                if let Some(Err(error)) = sample_loop.map(|sample_loop| sample.checked_loop(sample_loop)) {
+                    issues.push(ValidationIssue::InvalidSampleLoop { sample: sample_id, sustain, error });
+                }
+            }
+        }
+
+        if self.message.len() > MAX_MESSAGE_LENGTH {
+            issues.push(ValidationIssue::MessageTooLong(self.message.len()));
+        }
+
+        issues
+    }
+}
+
+impl fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ValidationIssue::MissingPattern(error) => fmt::Display::fmt(error, f),
+            ValidationIssue::MissingSample { instrument, note, sample } => {
+                write!(f, "instrument {:?} plays sample {:?} on note {:?}, it does not exist", instrument, sample, note)
+            }
+            ValidationIssue::MissingInstrument { pattern, row, channel, instrument } => {
+                write!(
+                    f,
+                    "pattern {:?} row {} {:?} uses instrument {:?} which does not exist",
+                    pattern, row, channel, instrument,
+                )
+            }
+            ValidationIssue::InvalidSampleLoop { sample, sustain: false, error } => {
+                write!(f, "sample {:?}: {}", sample, error)
+            }
+            ValidationIssue::InvalidSampleLoop { sample, sustain: true, error } => {
+                write!(f, "sample {:?} sustain loop: {}", sample, error)
+            }
+            ValidationIssue::InvalidEnvelope { instrument, kind, error } => {
+                write!(f, "instrument {:?} {:?} envelope: {}", instrument, kind, error)
+            }
+            ValidationIssue::MessageTooLong(length) => {
+                write!(f, "message has {} bytes, at most {} are allowed", length, MAX_MESSAGE_LENGTH)
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+    use alloc::string::ToString;
+
+    #[test]
+    fn validate() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        assert!(module.validate().is_empty());
+
+        module.orders.push(Order::Index(PatternId::try_from(5).unwrap()));
+        module.samples[0].loop_ = Some(SampleLoop { start: 10, end: 5, bidi: false });
+        module.message = "x".repeat(MAX_MESSAGE_LENGTH + 1);
+        module.patterns[0].rows[0].insert(Channel::new(2), Command {
+            note: None,
+            instrument: Some(InstrumentId::try_from(3).unwrap()),
+            volume: None,
+            effect: None,
+        });
+        let issues = module.validate().iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(issues, [
+            format!("order {} references pattern 5 which does not exist", module.orders.len() - 1),
+            String::from("pattern 0 row 0 ch02 uses instrument 3 which does not exist"),
+            format!("sample 0: invalid sample loop 10..5 in a sample of {} samples", module.samples[0].length()),
+            String::from("message has 8001 bytes, at most 8000 are allowed"),
+        ]);
+    }
+}
@@ -0,0 +1,293 @@
+use super::*;
+
+
+/// Identifier of a voice started by [`VoiceAllocator::note_on`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct VoiceId(u32);
+
+/// State of a virtual voice
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VoiceState {
+    /// The note is held
+    Playing,
+
+    /// The note was released, it plays until its envelope or sample ends
+    Released,
+
+    /// The note is fading out
+    Fading,
+}
+
+/// Note playing in a virtual voice, see [`VoiceAllocator`]
+#[derive(Clone, Copy, Debug)]
+pub struct VirtualVoice {
+    pub id: VoiceId,
+
+    /// Channel which played the note
+    pub channel: Channel,
+    pub note: Note,
+
+    /// Instrument of the note, `None` in sample mode
+    pub instrument: Option<InstrumentId>,
+    pub sample: SampleId,
+    pub state: VoiceState,
+
+    /// The voice is controlled by its channel, the other voices were moved to the background by
+    /// new note actions and only play out
+    pub foreground: bool,
+
+    /// New note action applied when the next note is played on the channel
+    pub note_action: NewNoteAction,
+}
+
+/// Simulation of the virtual voices of Impulse Tracker
+///
+/// Every note starts a voice. A note played on a channel which already plays one first runs the
+/// duplicate check of the instrument of every voice of the channel, and then the new note action
+/// of the voice in the foreground. Voices which are continued, released or faded out stay in the
+/// background until they are cut or [ended](VoiceAllocator::end) by the caller. In sample mode
+/// new notes cut the previous one.
+///
+/// The rules follow Impulse Tracker:
+///
+/// - the duplicate check of a voice is done with the [type](Instrument::duplicate_check) and the
+///   [action](Instrument::duplicate_action) of the instrument of the voice, only voices of the
+///   same instrument as the new note are checked,
+/// - the new note action is the one of the instrument unless changed by `S73`-`S76` through
+///   [`VoiceAllocator::set_note_action`],
+/// - note off, note cut and note fade only affect the voice in the foreground, past note actions
+///   (`S70`-`S72`) the voices in the background.
+///
+/// The allocator doesn't know how long the notes play, voices are only removed when they are cut.
+/// The number of voices is not limited.
+///
+/// ```
+/// # use ittech::*;
+/// # fn count(module: &Module) {
+/// let mut voices = VoiceAllocator::new(module);
+/// for event in module.events() {
+///     voices.process(&event.event);
+/// }
+/// println!("at most {} notes at once", voices.peak());
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct VoiceAllocator<'m> {
+    module: &'m Module,
+    voices: Vec<VirtualVoice>,
+
+    /// Last instrument played on every channel
+    instruments: [Option<InstrumentId>; MAX_CHANNELS],
+    next_id: u32,
+    peak: usize,
+}
+
+impl<'m> VoiceAllocator<'m> {
+    pub fn new(module: &'m Module) -> VoiceAllocator<'m> {
+        VoiceAllocator {
+            module,
+            voices: Vec::new(),
+            instruments: [None; MAX_CHANNELS],
+            next_id: 0,
+            peak: 0,
+        }
+    }
+
+    /// Returns the voices currently playing in the order they were started.
+    pub fn voices(&self) -> &[VirtualVoice] {
+        &self.voices
+    }
+
+    /// Returns the voice controlled by the channel.
+    pub fn foreground(&self, channel: Channel) -> Option<&VirtualVoice> {
+        self.voices.iter().find(|voice| voice.channel == channel && voice.foreground)
+    }
+
+    /// Returns the largest number of voices which played at once.
+    pub fn peak(&self) -> usize {
+        self.peak
+    }
+
+    /// Plays the note on the channel, returns the new voice.
+    ///
+    /// `instrument` is the instrument (or sample in sample mode) of the command, `None` uses the
+    /// last one played on the channel. Returns `None` if there is no sample to play, the notes
+    /// playing on the channel are still checked and moved to the background then.
+    pub fn note_on(&mut self, channel: Channel, note: Note, instrument: Option<InstrumentId>) -> Option<VoiceId> {
+        let slot = &mut self.instruments[channel.as_usize()];
+        let instrument = instrument.or(*slot);
+        *slot = instrument;
+
+        let module = self.module;
+        let (instrument, sample) = if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+            let sample = instrument.and_then(|id| module.get(id)).and_then(|instrument| instrument.sample_map[note]);
+            self.duplicate_check(channel, note, instrument, sample);
+            self.voices.retain_mut(|voice| {
+                if voice.channel != channel || !voice.foreground {
+                    return true;
+                }
+                voice.foreground = false;
+                voice.apply(voice.note_action)
+            });
+            (instrument, sample)
+        } else {
+            let sample = instrument.and_then(|id| SampleId::try_from(id.as_u8()).ok());
+            self.voices.retain(|voice| voice.channel != channel || !voice.foreground);
+            (None, sample)
+        };
+
+        let sample = sample.filter(|&id| module.get(id).is_some())?;
+        let id = VoiceId(self.next_id);
+        self.next_id += 1;
+        self.voices.push(VirtualVoice {
+            id,
+            channel,
+            note,
+            instrument,
+            sample,
+            state: VoiceState::Playing,
+            foreground: true,
+            note_action: instrument.and_then(|id| module.get(id)).map_or(NewNoteAction::Cut, Instrument::note_action),
+        });
+        self.peak = self.peak.max(self.voices.len());
+        Some(id)
+    }
+
+    /// Releases the note playing in the foreground of the channel.
+    pub fn note_off(&mut self, channel: Channel) {
+        self.foreground_action(channel, NewNoteAction::Off);
+    }
+
+    /// Cuts the note playing in the foreground of the channel.
+    pub fn note_cut(&mut self, channel: Channel) {
+        self.foreground_action(channel, NewNoteAction::Cut);
+    }
+
+    /// Fades out the note playing in the foreground of the channel.
+    pub fn note_fade(&mut self, channel: Channel) {
+        self.foreground_action(channel, NewNoteAction::Fade);
+    }
+
+    /// Applies the past note action (`S70`-`S72`) to the voices in the background of the channel.
+    pub fn past_notes(&mut self, channel: Channel, action: SetPastNote) {
+        let action = match action {
+            SetPastNote::Cut => NewNoteAction::Cut,
+            SetPastNote::Off => NewNoteAction::Off,
+            SetPastNote::Fade => NewNoteAction::Fade,
+        };
+        self.voices.retain_mut(|voice| voice.channel != channel || voice.foreground || voice.apply(action));
+    }
+
+    /// Changes the new note action of the note playing in the foreground of the channel
+    /// (`S73`-`S76`).
+    pub fn set_note_action(&mut self, channel: Channel, action: NewNoteAction) {
+        if let Some(voice) = self.voices.iter_mut().find(|voice| voice.channel == channel && voice.foreground) {
+            voice.note_action = action;
+        }
+    }
+
+    /// Removes the voice, for voices which stopped playing on their own.
+    pub fn end(&mut self, id: VoiceId) {
+        self.voices.retain(|voice| voice.id != id);
+    }
+
+    /// Applies the event of [`Module::events`], [`Event::NoteOff`] releases the note.
+    pub fn process(&mut self, event: &Event) {
+        match *event {
+            Event::NoteOn { channel, note, instrument } => {
+                self.note_on(channel, note, instrument);
+            }
+            Event::NoteOff { channel } => self.note_off(channel),
+            Event::PatternStart { .. } | Event::TempoChange { .. } => {}
+        }
+    }
+
+    /// Applies the duplicate check actions to the voices of the channel the new note stops.
+    fn duplicate_check(&mut self, channel: Channel, note: Note, instrument: Option<InstrumentId>, sample: Option<SampleId>) {
+        let module = self.module;
+        self.voices.retain_mut(|voice| {
+            let header = voice.instrument
+                .filter(|&id| voice.channel == channel && Some(id) == instrument)
+                .and_then(|id| module.get(id));
+            let header = match header {
+                Some(header) => header,
+                None => return true,
+            };
+            let duplicate = match header.duplicate_check() {
+                DuplicateCheckType::Off => false,
+                DuplicateCheckType::Note => u8::from(voice.note) == u8::from(note),
+                DuplicateCheckType::Sample => Some(voice.sample) == sample,
+                DuplicateCheckType::Instrument => true,
+            };
+            !duplicate || voice.apply(match header.duplicate_action() {
+                DuplicateCheckAction::Cut => NewNoteAction::Cut,
+                DuplicateCheckAction::Off => NewNoteAction::Off,
+                DuplicateCheckAction::Fade => NewNoteAction::Fade,
+            })
+        });
+    }
+
+    fn foreground_action(&mut self, channel: Channel, action: NewNoteAction) {
+        self.voices.retain_mut(|voice| voice.channel != channel || !voice.foreground || voice.apply(action));
+    }
+}
+
+impl VirtualVoice {
+    /// Changes the state by the action, returns `false` if the voice is cut.
+    fn apply(&mut self, action: NewNoteAction) -> bool {
+        match action {
+            NewNoteAction::Cut => return false,
+            NewNoteAction::Continue => {}
+            NewNoteAction::Off => {
+                if self.state == VoiceState::Playing {
+                    self.state = VoiceState::Released;
+                }
+            }
+            NewNoteAction::Fade => self.state = VoiceState::Fading,
+        }
+        true
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::formats::{empty_instrument, empty_sample};
+
+    #[test]
+    fn note_actions() {
+        let mut builder = ModuleBuilder::new();
+        let sample = builder.add_sample(empty_sample()).unwrap();
+        let mut instrument = empty_instrument();
+        instrument.sample_map.map = [Some(sample); 120];
+        instrument.new_note_action = NewNoteAction::Fade.into();
+        instrument.duplicate_check_type = DuplicateCheckType::Note.into();
+        instrument.duplicate_check_action = DuplicateCheckAction::Cut.into();
+        let instrument = builder.add_instrument(instrument).unwrap();
+        let module = builder.build().unwrap();
+        let (one, two) = (Channel::new(1), Channel::new(2));
+
+        let mut voices = VoiceAllocator::new(&module);
+        let first = voices.note_on(one, Note::C_5, Some(instrument)).unwrap();
+        // The previous note fades out in the background.
+        let second = voices.note_on(one, Note::A_4, None).unwrap();
+        assert_eq!(voices.voices().len(), 2);
+        assert_eq!(voices.voices()[0].state, VoiceState::Fading);
+        assert_eq!(voices.foreground(one).map(|voice| voice.id), Some(second));
+        // The duplicate check cuts the first note, the second one fades out.
+        voices.note_on(one, Note::C_5, None).unwrap();
+        assert!(voices.voices().iter().all(|voice| voice.id != first));
+        assert_eq!(voices.voices().len(), 2);
+
+        voices.note_on(two, Note::C_5, Some(instrument)).unwrap();
+        voices.note_off(two);
+        assert_eq!(voices.foreground(two).map(|voice| voice.state), Some(VoiceState::Released));
+        assert_eq!(voices.peak(), 3);
+
+        voices.past_notes(one, SetPastNote::Cut);
+        voices.set_note_action(one, NewNoteAction::Cut);
+        voices.note_on(one, Note::C_5, None).unwrap();
+        assert_eq!(voices.voices().iter().filter(|voice| voice.channel == one).count(), 1);
+    }
+}
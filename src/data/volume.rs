@@ -0,0 +1,221 @@
+use super::*;
+use crate::error::OutOfRangeError;
+use core::convert::TryFrom;
+
+
+// Impulse Tracker treats all volumes as linear amplitude multipliers, the final volume is just a
+// product of all the volume components (see the "Mathematics" section of ITTECH.TXT), there is no
+// logarithmic curve involved anywhere.
+
+/// Maximum note volume
+const MAX_VOLUME: u8 = 64;
+
+/// Converts note volume (`0..=64`) to linear amplitude (`0.0..=1.0`)
+///
+/// Impulse Tracker scales amplitude linearly with volume, `64` is the full amplitude. Values above
+/// `64` are clipped.
+pub fn volume_to_linear(volume: u8) -> f32 {
+    f32::from(volume.min(MAX_VOLUME)) / f32::from(MAX_VOLUME)
+}
+
+/// Converts note volume (`0..=64`) to decibels relative to the full amplitude
+///
+/// Volume `64` is `0.0` dB, volume `0` is negative infinity. Values above `64` are clipped.
+pub fn volume_to_db(volume: u8) -> f32 {
+    20.0 * float::log10(volume_to_linear(volume))
+}
+
+/// Converts linear amplitude (`0.0..=1.0`) to the nearest note volume (`0..=64`)
+///
+/// Values out of range are clipped, NaN is converted to `0`.
+pub fn linear_to_volume(linear: f32) -> u8 {
+    let volume = float::round(linear * f32::from(MAX_VOLUME));
+    if volume >= f32::from(MAX_VOLUME) {
+        MAX_VOLUME
+    } else if volume > 0.0 {
+        // The value is in range 1..64, the cast cannot truncate.
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        { volume as u8 }
+    } else {
+        0
+    }
+}
+
+/// Converts decibels relative to the full amplitude to the nearest note volume (`0..=64`)
+///
+/// Positive values are clipped to `64`, negative infinity and NaN are converted to `0`.
+pub fn db_to_volume(db: f32) -> u8 {
+    linear_to_volume(float::powf(10.0, db / 20.0))
+}
+
+
+/// Initial volume of a channel (`0..=64`)
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(feature = "serde", serde(try_from = "u8", into = "u8"))]
+pub struct ChannelVolume(u8);
+
+impl ChannelVolume {
+    /// Silent channel
+    pub const SILENT: ChannelVolume = ChannelVolume(0);
+
+    /// Full volume, the default of all channels
+    pub const FULL: ChannelVolume = ChannelVolume(MAX_VOLUME);
+
+    /// Creates the volume clipping the value to `0..=64`.
+    pub fn new_clamped(volume: u8) -> ChannelVolume {
+        ChannelVolume(volume.min(MAX_VOLUME))
+    }
+
+    /// Returns `true` if the channel is silent, notes played on it are not heard until the
+    /// channel volume is raised by `Mxx` or `Nxy`.
+    pub fn is_silent(self) -> bool {
+        self.0 == 0
+    }
+
+    pub(crate) fn as_u8(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for ChannelVolume {
+    type Error = OutOfRangeError<0, MAX_VOLUME>;
+
+    fn try_from(raw: u8) -> Result<ChannelVolume, Self::Error> {
+        if raw <= MAX_VOLUME {
+            Ok(ChannelVolume(raw))
+        } else {
+            Err(OutOfRangeError(raw))
+        }
+    }
+}
+
+impl From<ChannelVolume> for u8 {
+    fn from(volume: ChannelVolume) -> u8 {
+        volume.0
+    }
+}
+
+impl Debug for ChannelVolume {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        Debug::fmt(&self.0, f)
+    }
+}
+
+/// Breakdown of all the volume components making up the initial volume of a note
+///
+/// Created by [`resolve_initial_volume`], useful mainly for debugging why a note plays at some
+/// particular volume.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct VolumeChain {
+    /// Note volume (`0..=64`), either from the volume column or the sample default volume
+    pub note_volume: u8,
+
+    /// Sample global volume (`0..=64`)
+    pub sample_global_volume: u8,
+
+    /// Instrument global volume (`0..=128`), `None` if the module is in sample mode
+    pub instrument_global_volume: Option<u8>,
+
+    /// Initial channel volume (`0..=64`)
+    pub channel_volume: u8,
+
+    /// Module global volume (`0..=128`)
+    pub global_volume: u8,
+
+    /// Module mixing volume (`0..=128`)
+    ///
+    /// This doesn't take part in computing the final volume, Impulse Tracker only applies it when
+    /// mixing the channels together.
+    pub mix_volume: u8,
+}
+
+impl VolumeChain {
+    /// Computes the final volume (`0..=128`) as defined by ITTECH.TXT
+    ///
+    /// In sample mode the final volume is `Vol * SV * CV * GV / 2^18`.
+    ///
+    /// In instrument mode it is `Vol * SV * IV * CV * GV * VEV * NFC / 2^41`. Volume envelope
+    /// value (`VEV`) and note fade component (`NFC`) are taken at their initial values of `64` and
+    /// `1024` respectively, this struct only describes the volume at the moment the note starts.
+    ///
+    /// Components out of their ranges are clipped.
+    pub fn final_volume(&self) -> u8 {
+        let vol = u64::from(self.note_volume.min(64));
+        let sv = u64::from(self.sample_global_volume.min(64));
+        let cv = u64::from(self.channel_volume.min(64));
+        let gv = u64::from(self.global_volume.min(128));
+        let fv = match self.instrument_global_volume {
+            None => (vol * sv * cv * gv) >> 18,
+            Some(iv) => (vol * sv * u64::from(iv.min(128)) * cv * gv * 64 * 1024) >> 41,
+        };
+        u8::try_from(fv).expect("BUG: final volume is out of range 0..=128")
+    }
+
+    /// Computes the linear amplitude (`0.0..=1.0`) of the note including the mixing volume
+    pub fn linear(&self) -> f32 {
+        f32::from(self.final_volume()) / 128.0 * f32::from(self.mix_volume) / 128.0
+    }
+}
+
+/// Resolves the initial volume of a note played on a channel
+///
+/// The note volume is taken from the volume column command if it sets the volume, otherwise the
+/// sample default volume is used. The instrument is only taken into account if the module is in
+/// instrument mode ([`ModuleFlags::USE_INSTRUMENTS`]), in sample mode it is ignored.
+///
+/// All components are clipped to their valid ranges.
+pub fn resolve_initial_volume(
+    module: &Module,
+    channel: Channel,
+    instrument: Option<&Instrument>,
+    sample: &Sample,
+    volume: Option<VolumeCmd>,
+) -> VolumeChain {
+    let note_volume = match volume {
+        Some(VolumeCmd::SetVolume(volume)) => volume.as_u8(),
+        _ => sample.default_volume,
+    };
+    let instrument_global_volume = if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        Some(instrument.map_or(128, |instrument| instrument.global_volume.min(128)))
+    } else {
+        None
+    };
+    VolumeChain {
+        note_volume: note_volume.min(MAX_VOLUME),
+        sample_global_volume: sample.global_volume.min(MAX_VOLUME),
+        instrument_global_volume,
+        channel_volume: module.init_channel_volume[channel.as_usize()].as_u8(),
+        global_volume: module.global_volume.as_u8(),
+        mix_volume: module.sample_volume.as_u8(),
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chain(note_volume: u8, instrument_global_volume: Option<u8>) -> VolumeChain {
+        VolumeChain {
+            note_volume,
+            sample_global_volume: 64,
+            instrument_global_volume,
+            channel_volume: 64,
+            global_volume: 128,
+            mix_volume: 128,
+        }
+    }
+
+    #[test]
+    fn final_volume() {
+        assert_eq!(chain(64, None).final_volume(), 128);
+        assert_eq!(chain(64, Some(128)).final_volume(), 128);
+        assert_eq!(chain(32, None).final_volume(), 64);
+        assert_eq!(chain(32, Some(64)).final_volume(), 32);
+        assert_eq!(chain(0, Some(128)).final_volume(), 0);
+        assert_eq!(VolumeChain { global_volume: 48, ..chain(48, None) }.final_volume(), 36);
+        assert_eq!(VolumeChain { sample_global_volume: 255, channel_volume: 255, global_volume: 255, ..chain(255, Some(255)) }.final_volume(), 128);
+    }
+}
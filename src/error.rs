@@ -7,11 +7,17 @@
 use nom::error::{ErrorKind, ParseError};
 use nom::{Err, IResult};
 use nom::{Offset, Parser};
-use std::borrow::Cow;
-use std::fmt::{self, Debug, Display, Write};
-use std::iter;
+use alloc::borrow::Cow;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt::{self, Debug, Display, Write};
+#[cfg(feature = "std")]
+use std::io;
+use core::convert::TryFrom;
+use core::iter;
 
 pub use crate::parser::scan::ScanError;
+use crate::{Channel, EnvelopeLoop, PatternId, SampleLoop};
 
 
 #[derive(Debug)]
@@ -23,9 +29,505 @@ impl<const LOW: u8, const HIGH: u8> Display for OutOfRangeError<LOW, HIGH> {
     }
 }
 
+#[cfg(feature = "std")]
 impl<const LOW: u8, const HIGH: u8> std::error::Error for OutOfRangeError<LOW, HIGH> {}
 
 
+/// Text can't be stored as a fixed length [`Name`](crate::Name) or
+/// [`DosFilename`](crate::DosFilename)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidNameError {
+    /// Text is longer than `max` bytes
+    TooLong {
+        length: usize,
+        max: usize,
+    },
+
+    /// Text contains a null character, which would end the name early
+    ContainsNull {
+        /// Byte offset of the null character
+        position: usize,
+    },
+}
+
+impl Display for InvalidNameError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidNameError::TooLong { length, max } => {
+                write!(f, "name has {} bytes, at most {} are allowed", length, max)
+            }
+            InvalidNameError::ContainsNull { position } => {
+                write!(f, "name contains a null character at byte {}", position)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidNameError {}
+
+
+/// Order list entry references a pattern which is not present in the module
+#[derive(Clone, Copy, Debug)]
+pub struct InvalidOrderError {
+    /// Position of the invalid entry in the order list
+    pub position: usize,
+
+    /// Referenced pattern
+    pub pattern: PatternId,
+}
+
+impl Display for InvalidOrderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "order {} references pattern {:?} which does not exist", self.position, self.pattern)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidOrderError {}
+
+
+/// Channel mapping moves two channels to the same channel
+#[derive(Clone, Copy, Debug)]
+pub struct InvalidChannelMapError {
+    /// Channel which is the target of more than one channel
+    pub channel: Channel,
+}
+
+impl Display for InvalidChannelMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "more than one channel is mapped to {:?}", self.channel)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidChannelMapError {}
+
+
+/// Module can't be appended to another one, see [`Module::append`](crate::Module::append)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AppendError {
+    /// Combined order list would have more than 256 entries
+    TooManyOrders(usize),
+
+    /// Combined module would have more than 200 patterns
+    TooManyPatterns(usize),
+
+    /// Combined module would have more than 99 samples
+    TooManySamples(usize),
+
+    /// Combined module would have more than 99 instruments
+    TooManyInstruments(usize),
+
+    /// One module uses instruments and the other plays samples directly
+    MixedModes,
+}
+
+impl Display for AppendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AppendError::TooManyOrders(count) => write!(f, "combined module has {} orders, at most 256 are allowed", count),
+            AppendError::TooManyPatterns(count) => write!(f, "combined module has {} patterns, at most 200 are allowed", count),
+            AppendError::TooManySamples(count) => write!(f, "combined module has {} samples, at most 99 are allowed", count),
+            AppendError::TooManyInstruments(count) => {
+                write!(f, "combined module has {} instruments, at most 99 are allowed", count)
+            }
+            AppendError::MixedModes => write!(f, "only one of the modules uses instruments"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AppendError {}
+
+
+/// Pattern would have no rows or more than [`Pattern::MAX_ROWS`](crate::Pattern::MAX_ROWS) rows
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidRowCountError {
+    /// Number of rows the pattern would have
+    pub rows: usize,
+}
+
+impl Display for InvalidRowCountError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pattern would have {} rows, patterns have 1 to 200 rows", self.rows)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidRowCountError {}
+
+
+/// Song message is longer than Impulse Tracker can load, see
+/// [`Module::set_message`](crate::Module::set_message)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MessageTooLongError {
+    /// Length of the encoded message in bytes
+    pub length: usize,
+}
+
+impl Display for MessageTooLongError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "message has {} bytes, at most 8000 are allowed", self.length)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for MessageTooLongError {}
+
+
+/// Volume column byte falls into one of the gaps between the command ranges
+#[derive(Clone, Copy, Debug)]
+pub struct InvalidVolumeError(pub(crate) u8);
+
+impl Display for InvalidVolumeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "value {} is not a valid volume column command", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidVolumeError {}
+
+
+/// Text is not a valid note, pattern cell or one of its columns, or pattern clipboard text
+///
+/// The field names the part of the text which could not be parsed.
+#[derive(Clone, Copy, Debug)]
+pub struct ParseCommandError(pub(crate) &'static str);
+
+impl Display for ParseCommandError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid {} in pattern text", self.0)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseCommandError {}
+
+
+/// Envelope breaks one of the envelope invariants
+#[derive(Clone, Copy, Debug)]
+pub enum InvalidEnvelopeError {
+    /// Envelope has more than 25 nodes
+    TooManyNodes(usize),
+
+    /// Node tick is not greater than the tick of the previous node
+    UnorderedTick {
+        /// Index of the offending node
+        node: usize,
+    },
+
+    /// Node value is out of range for the envelope kind
+    ValueOutOfRange {
+        /// Index of the offending node
+        node: usize,
+
+        /// The value
+        value: i8,
+    },
+
+    /// Envelope loop references nodes which do not exist or has start after end
+    InvalidLoop(EnvelopeLoop),
+
+    /// Sustain loop references nodes which do not exist or has start after end
+    InvalidSustainLoop(EnvelopeLoop),
+}
+
+impl Display for InvalidEnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InvalidEnvelopeError::TooManyNodes(len) => {
+                write!(f, "envelope has {} nodes, at most 25 are allowed", len)
+            }
+            InvalidEnvelopeError::UnorderedTick { node } => {
+                write!(f, "tick of envelope node {} is not greater than the previous tick", node)
+            }
+            InvalidEnvelopeError::ValueOutOfRange { node, value } => {
+                write!(f, "value {} of envelope node {} is out of range", value, node)
+            }
+            InvalidEnvelopeError::InvalidLoop(EnvelopeLoop { start, end }) => {
+                write!(f, "invalid envelope loop {}..={}", start, end)
+            }
+            InvalidEnvelopeError::InvalidSustainLoop(EnvelopeLoop { start, end }) => {
+                write!(f, "invalid sustain loop {}..={}", start, end)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidEnvelopeError {}
+
+
+/// Sample loop is empty or extends past the end of the sample data
+#[derive(Clone, Copy, Debug)]
+pub struct InvalidSampleLoopError {
+    /// The rejected loop
+    pub sample_loop: SampleLoop,
+
+    /// Length of the sample in samples
+    pub length: u32,
+}
+
+impl Display for InvalidSampleLoopError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let SampleLoop { start, end, .. } = self.sample_loop;
+        write!(f, "invalid sample loop {}..{} in a sample of {} samples", start, end, self.length)
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for InvalidSampleLoopError {}
+
+
+/// Error returned by [`read_module_file`](crate::parser::read_module_file)
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub enum ReadError {
+    /// Reading from or seeking in the reader failed
+    Io(io::Error),
+
+    /// Data read from the reader could not be parsed
+    ///
+    /// The offset of the failure is relative to the start of the module.
+    Parse(ParseFailure),
+}
+
+#[cfg(feature = "std")]
+impl Display for ReadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ReadError::Io(e) => write!(f, "reading module failed: {}", e),
+            ReadError::Parse(failure) => write!(f, "reading module failed: {}", failure),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReadError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ReadError::Io(e) => Some(e),
+            ReadError::Parse(failure) => Some(failure),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<io::Error> for ReadError {
+    fn from(e: io::Error) -> ReadError {
+        ReadError::Io(e)
+    }
+}
+
+
+/// Error returned by [`play`](crate::player::play)
+#[cfg(feature = "cpal")]
+#[derive(Debug)]
+pub enum PlaybackError {
+    /// There is no default output device
+    NoDevice,
+
+    /// The device doesn't support `f32` output
+    UnsupportedFormat,
+
+    /// Querying the default output configuration failed
+    DefaultConfig(cpal::DefaultStreamConfigError),
+
+    /// Querying the supported output configurations failed
+    SupportedConfigs(cpal::SupportedStreamConfigsError),
+
+    /// Opening the output stream failed
+    BuildStream(cpal::BuildStreamError),
+
+    /// Starting the output stream failed
+    PlayStream(cpal::PlayStreamError),
+}
+
+#[cfg(feature = "cpal")]
+impl Display for PlaybackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlaybackError::NoDevice => write!(f, "no default output device available"),
+            PlaybackError::UnsupportedFormat => write!(f, "output device doesn't support f32 samples"),
+            PlaybackError::DefaultConfig(e) => write!(f, "querying output configuration failed: {}", e),
+            PlaybackError::SupportedConfigs(e) => write!(f, "querying output configurations failed: {}", e),
+            PlaybackError::BuildStream(e) => write!(f, "opening output stream failed: {}", e),
+            PlaybackError::PlayStream(e) => write!(f, "starting output stream failed: {}", e),
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl std::error::Error for PlaybackError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PlaybackError::NoDevice | PlaybackError::UnsupportedFormat => None,
+            PlaybackError::DefaultConfig(e) => Some(e),
+            PlaybackError::SupportedConfigs(e) => Some(e),
+            PlaybackError::BuildStream(e) => Some(e),
+            PlaybackError::PlayStream(e) => Some(e),
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<cpal::DefaultStreamConfigError> for PlaybackError {
+    fn from(e: cpal::DefaultStreamConfigError) -> PlaybackError {
+        PlaybackError::DefaultConfig(e)
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<cpal::SupportedStreamConfigsError> for PlaybackError {
+    fn from(e: cpal::SupportedStreamConfigsError) -> PlaybackError {
+        PlaybackError::SupportedConfigs(e)
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<cpal::BuildStreamError> for PlaybackError {
+    fn from(e: cpal::BuildStreamError) -> PlaybackError {
+        PlaybackError::BuildStream(e)
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl From<cpal::PlayStreamError> for PlaybackError {
+    fn from(e: cpal::PlayStreamError) -> PlaybackError {
+        PlaybackError::PlayStream(e)
+    }
+}
+
+
+/// Parse error located in the input
+///
+/// Built from the [`VerboseError`] returned by the parsers with [`ParseFailure::new`], collects
+/// what the parser was doing at the point of the failure in a form which can be shown to the user
+/// or inspected by a program.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseFailure {
+    /// Offset of the failure from the start of the input
+    pub offset: u64,
+
+    /// Structures being parsed at the failure, from the outermost one
+    ///
+    /// For example `["pattern 3", "row 17", "in command", "reading note"]`.
+    pub context: Vec<String>,
+
+    /// Value the parser expected, if known
+    pub expected: Option<String>,
+
+    /// Value found in the input instead, if known
+    pub found: Option<String>,
+
+    /// Error trace formatted by [`convert_error`] over the input
+    pub trace: String,
+}
+
+impl ParseFailure {
+    /// Locates the error returned by a parser run on `input`.
+    pub fn new(input: &[u8], error: Err<VerboseError<&[u8]>>) -> ParseFailure {
+        let error = match error {
+            Err::Error(error) | Err::Failure(error) => error,
+            Err::Incomplete(_) => return ParseFailure {
+                offset: u64::try_from(input.len()).unwrap(),
+                context: Vec::new(),
+                expected: Some(String::from("more data")),
+                found: Some(String::from("end of input")),
+                trace: String::from("incomplete input"),
+            },
+        };
+
+        let offset = error.errors.first().map_or(0, |(position, _)| input.offset(position));
+        let mut failure = ParseFailure {
+            offset: u64::try_from(offset).unwrap(),
+            context: Vec::new(),
+            expected: None,
+            found: None,
+            trace: String::new(),
+        };
+        // The errors are accumulated from the innermost parser outwards.
+        for (_, kind) in error.errors.iter().rev() {
+            match kind {
+                VerboseErrorKind::Context(context) => failure.context.push(context.to_string()),
+                VerboseErrorKind::Expected { expected, found } => {
+                    failure.expected = Some(expected.to_string());
+                    failure.found = Some(found.to_string());
+                }
+                VerboseErrorKind::Nom(ErrorKind::Eof) if failure.expected.is_none() => {
+                    failure.expected = Some(String::from("more data"));
+                    failure.found = Some(String::from("end of input"));
+                }
+                VerboseErrorKind::Nom(kind) if failure.expected.is_none() => {
+                    failure.expected = Some(alloc::format!("{:?}", kind));
+                }
+                VerboseErrorKind::Nom(_) => {}
+            }
+        }
+        failure.trace = convert_error(input, error);
+        failure
+    }
+}
+
+impl Display for ParseFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parsing failed at offset {:#x}", self.offset)?;
+        if !self.context.is_empty() {
+            write!(f, " in {}", self.context.join(", "))?;
+        }
+        match (&self.expected, &self.found) {
+            (Some(expected), Some(found)) => write!(f, ": expected {}, found {}", expected, found),
+            (Some(expected), None) => write!(f, ": expected {}", expected),
+            (None, Some(found)) => write!(f, ": found {}", found),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ParseFailure {}
+
+
+/// Recoverable problem found by [`parse_lenient`](crate::parser::parse_lenient)
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    /// Offset of the problem from the start of the input
+    pub offset: u64,
+
+    /// Structures being parsed at the problem, from the outermost one, see
+    /// [`ParseFailure::context`]
+    pub context: Vec<String>,
+
+    /// What is wrong and how it was recovered from
+    pub message: String,
+}
+
+impl Warning {
+    /// Reports a failure of a part of the module, `recovery` tells what was used instead.
+    pub(crate) fn from_failure(failure: ParseFailure, recovery: &str) -> Warning {
+        let message = match (failure.expected, failure.found) {
+            (Some(expected), Some(found)) => alloc::format!("expected {}, found {}, {}", expected, found, recovery),
+            (Some(expected), None) => alloc::format!("expected {}, {}", expected, recovery),
+            (None, Some(found)) => alloc::format!("found {}, {}", found, recovery),
+            (None, None) => recovery.to_string(),
+        };
+        Warning { offset: failure.offset, context: failure.context, message }
+    }
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "offset {:#x}", self.offset)?;
+        if !self.context.is_empty() {
+            write!(f, " in {}", self.context.join(", "))?;
+        }
+        write!(f, ": {}", self.message)
+    }
+}
+
+
 /// This error type accumulates errors and their position when backtracking
 /// through a parse tree. With some post processing (cf `examples/json.rs`),
 /// it can be used to display user friendly error messages
@@ -42,6 +544,15 @@ pub enum VerboseErrorKind {
     /// String added by the `context` function
     Context(Cow<'static, str>),
 
+    /// Value found in the input is not the expected one
+    Expected {
+        /// Description of the expected value
+        expected: Cow<'static, str>,
+
+        /// Description of the found value
+        found: Cow<'static, str>,
+    },
+
     /// Error kind given by various nom parsers
     Nom(ErrorKind),
 }
@@ -66,6 +577,11 @@ impl<I> ParseError<I> for VerboseError<I> {
 pub trait ContextError<I>: Sized {
     fn add_context(_input: I, _ctx: Cow<'static, str>, other: Self) -> Self;
     fn new(_input: I, _ctx: Cow<'static, str>) -> Self;
+
+    /// Creates an error for a value in the input which is not the expected one.
+    fn expected(input: I, expected: Cow<'static, str>, found: Cow<'static, str>) -> Self {
+        Self::new(input, Cow::Owned(alloc::format!("expected {}, found {}", expected, found)))
+    }
 }
 
 impl<I> ContextError<I> for VerboseError<I> {
@@ -79,6 +595,12 @@ impl<I> ContextError<I> for VerboseError<I> {
             errors: vec![(input, VerboseErrorKind::Context(ctx))],
         }
     }
+
+    fn expected(input: I, expected: Cow<'static, str>, found: Cow<'static, str>) -> Self {
+        VerboseError {
+            errors: vec![(input, VerboseErrorKind::Expected { expected, found })],
+        }
+    }
 }
 
 /// Create a new error from an input position, a static string and an existing error.
@@ -103,31 +625,31 @@ where
 
 macro_rules! context {
     ( $parser: expr, $msg: literal $(,)? ) => {
-        $crate::error::context(move || ::std::borrow::Cow::Borrowed($msg), $parser)
+        $crate::error::context(move || ::alloc::borrow::Cow::Borrowed($msg), $parser)
     };
     ( $parser: expr, $fmt: literal $(, $args: expr )+ $(,)? ) => {
-        $crate::error::context(move || ::std::borrow::Cow::Owned(::std::format!($fmt, $($args),+)), $parser)
+        $crate::error::context(move || ::alloc::borrow::Cow::Owned(::alloc::format!($fmt, $($args),+)), $parser)
     };
     ( $parser: expr, $payload: expr $(,)? ) => {
-        $crate::error::context(move || ::std::borrow::Cow::Owned($payload.to_string()), $parser)
+        $crate::error::context(move || ::alloc::borrow::Cow::Owned($payload.to_string()), $parser)
     };
 }
 
 macro_rules! error {
     ( $input: expr, $msg: literal $(,)? ) => {
-        E::new($input, ::std::borrow::Cow::Borrowed($msg))
+        E::new($input, ::alloc::borrow::Cow::Borrowed($msg))
     };
     ( $input: expr, $fmt: literal $(, $args: expr )+ $(,)? ) => {
-        E::new($input, ::std::borrow::Cow::Owned(::std::format!($fmt, $($args),+)))
+        E::new($input, ::alloc::borrow::Cow::Owned(::alloc::format!($fmt, $($args),+)))
     };
     ( $input: expr, $payload: expr $(,)? ) => {
-        E::new($input, ::std::borrow::Cow::Owned($payload.to_string()))
+        E::new($input, ::alloc::borrow::Cow::Owned($payload.to_string()))
     };
 }
 
 macro_rules! bail {
     ($($tt:tt)*) => {
-        return ::std::result::Result::Err(::nom::Err::Error(error!($($tt)*)))
+        return ::core::result::Result::Err(::nom::Err::Error(error!($($tt)*)))
     };
 }
 
@@ -149,6 +671,9 @@ pub fn convert_error(
             use VerboseErrorKind::*;
             match kind {
                 Context(s) => write!(&mut result, "{}: in {}, got empty input\n\n", i, s).unwrap(),
+                Expected { expected, .. } => {
+                    write!(&mut result, "{}: expected {}, got empty input\n\n", i, expected).unwrap()
+                }
                 Nom(e) => write!(&mut result, "{}: in {:?}, got empty input\n\n", i, e).unwrap(),
             }
         } else {
@@ -225,6 +750,19 @@ pub fn convert_error(
                     caret = CARET,
                     column = caret_position,
                 ).unwrap(),
+                VerboseErrorKind::Expected { expected, found } => write!(
+                    &mut result,
+                    "{i}: at offset {offset:#x}, expected {expected}, found {found}:\n\
+                    {line}\n\
+                    {caret:>column$}\n\n",
+                    i = i,
+                    offset = offset,
+                    expected = expected,
+                    found = found,
+                    line = line,
+                    caret = CARET,
+                    column = caret_position,
+                ).unwrap(),
                 VerboseErrorKind::Nom(_) => {},
                 // write!(
                 //     &mut result,
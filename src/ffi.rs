@@ -0,0 +1,448 @@
+//! C interface
+//!
+//! Exports functions with the C ABI to parse modules, inspect the parsed [`Module`] and write it
+//! back, the declarations are in `include/ittech.h`. The shared library to link against is built
+//! by `cargo rustc --release --features ffi --crate-type cdylib`.
+//!
+//! Modules are returned as opaque pointers owned by the caller, they are freed by
+//! [`ittech_module_free`]. The pointers returned by the accessors borrow from the module and stay
+//! valid until it is freed. Panics are caught at the boundary and reported as failures.
+//!
+//! Functions which fail return `NULL` or `false` and store a description of the problem for
+//! [`ittech_last_error`], the description is kept per thread.
+
+use crate::error::{ParseFailure, VerboseError};
+use crate::writer;
+use crate::{Channel, Get, Module, Order, Sample, MAX_CHANNELS};
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::ptr;
+use std::slice;
+
+
+/// Sample has a loop, see [`IttechSampleInfo::flags`]
+pub const ITTECH_SAMPLE_LOOP: u8 = 0x10;
+
+/// Sample has a sustain loop
+pub const ITTECH_SAMPLE_SUSTAIN_LOOP: u8 = 0x20;
+
+/// The loop is bidirectional
+pub const ITTECH_SAMPLE_PINGPONG_LOOP: u8 = 0x40;
+
+/// The sustain loop is bidirectional
+pub const ITTECH_SAMPLE_PINGPONG_SUSTAIN: u8 = 0x80;
+
+/// Cell has a note, see [`IttechCell::mask`]
+pub const ITTECH_CELL_NOTE: u8 = 0x01;
+
+/// Cell has an instrument
+pub const ITTECH_CELL_INSTRUMENT: u8 = 0x02;
+
+/// Cell has a volume column command
+pub const ITTECH_CELL_VOLUME: u8 = 0x04;
+
+/// Cell has an effect
+pub const ITTECH_CELL_EFFECT: u8 = 0x08;
+
+
+/// Sample header fields, filled by [`ittech_sample_info`]
+#[repr(C)]
+pub struct IttechSampleInfo {
+    /// Sample name, null-terminated unless it takes all 26 bytes
+    pub name: [u8; 26],
+
+    /// Length in frames
+    pub length: u32,
+
+    /// Playback rate of C-5
+    pub c5_speed: u32,
+    pub global_volume: u8,
+    pub default_volume: u8,
+
+    /// Raw default panning, bit 7 is on if the panning is used
+    pub default_panning: u8,
+
+    /// Loop flags, combination of the `ITTECH_SAMPLE_*` constants
+    pub flags: u8,
+    pub loop_start: u32,
+    pub loop_end: u32,
+    pub sustain_loop_start: u32,
+    pub sustain_loop_end: u32,
+
+    /// `1` for mono, `2` for stereo
+    pub channels: u8,
+}
+
+/// Pattern cell, filled by [`ittech_pattern_cell`]
+///
+/// The values are encoded as in the module file, only the ones marked in `mask` are set.
+#[repr(C)]
+pub struct IttechCell {
+    /// Combination of the `ITTECH_CELL_*` constants
+    pub mask: u8,
+
+    /// Note `0..=119`, `255` note off, `254` note cut, `253` note fade, `252` parameter control
+    /// and `251` smooth parameter control (OpenMPT), the plugin, parameter and value of these are
+    /// not set
+    pub note: u8,
+
+    /// Instrument (or sample) number starting from `1`
+    pub instrument: u8,
+
+    /// Raw volume column byte
+    pub volume: u8,
+
+    /// Effect number, `1` is `A`
+    pub effect: u8,
+    pub param: u8,
+}
+
+
+thread_local! {
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Stores the error for [`ittech_last_error`].
+fn set_error(error: impl Into<Vec<u8>>) {
+    let mut error = error.into();
+    error.retain(|&byte| byte != 0);
+    LAST_ERROR.with(|last| *last.borrow_mut() = CString::new(error).unwrap());
+}
+
+/// Runs the function, reports a panic as a failure.
+fn catch<T>(fallback: T, f: impl FnOnce() -> T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(result) => result,
+        Err(payload) => {
+            let message = payload.downcast_ref::<&str>().copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+                .unwrap_or("unknown panic");
+            set_error(format!("panicked: {}", message));
+            fallback
+        }
+    }
+}
+
+/// Returns the sample of the module.
+///
+/// # Safety
+///
+/// `module` must be a valid module, the sample borrows from it.
+unsafe fn sample<'m>(module: *const Module, index: usize) -> Option<&'m Sample> {
+    (*module).samples.as_slice().get(index)
+}
+
+/// Returns the description of the last failure on this thread, empty if nothing failed yet.
+///
+/// The string stays valid until the next failing call on the thread.
+#[no_mangle]
+pub extern "C" fn ittech_last_error() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Parses the Impulse Tracker module, returns `NULL` if it fails.
+///
+/// The module has to be freed by [`ittech_module_free`]. The input is not used after the call.
+///
+/// # Safety
+///
+/// `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_parse(data: *const u8, len: usize) -> *mut Module {
+    if data.is_null() {
+        set_error("data is NULL");
+        return ptr::null_mut();
+    }
+    let input = slice::from_raw_parts(data, len);
+    catch(ptr::null_mut(), || match crate::parser::module_file::<VerboseError<&[u8]>>(input) {
+        Ok(module) => Box::into_raw(Box::new(module)),
+        Err(error) => {
+            set_error(ParseFailure::new(input, error).to_string());
+            ptr::null_mut()
+        }
+    })
+}
+
+/// Frees the module, does nothing for `NULL`.
+///
+/// # Safety
+///
+/// `module` must be returned by [`ittech_parse`] and not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_free(module: *mut Module) {
+    if !module.is_null() {
+        drop(Box::from_raw(module));
+    }
+}
+
+/// Returns the name of the module, 26 bytes null-terminated unless the name takes all of them.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_name(module: *const Module) -> *const u8 {
+    (*module).name.bytes.as_ptr()
+}
+
+/// Returns the initial tempo of the module.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_tempo(module: *const Module) -> u8 {
+    (*module).tempo.as_u8()
+}
+
+/// Returns the initial speed (ticks per row) of the module.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_module_speed(module: *const Module) -> u8 {
+    (*module).speed.as_u8()
+}
+
+/// Returns the length of the order list.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_order_count(module: *const Module) -> usize {
+    (*module).orders.len()
+}
+
+/// Returns the order as stored in the file, the pattern number, `254` for a separator and `255`
+/// for the end of the song or an index past the end of the list.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_order(module: *const Module, index: usize) -> u8 {
+    match (*module).orders.as_slice().get(index) {
+        Some(Order::Index(pattern)) => pattern.as_u8(),
+        Some(Order::Separator) => 254,
+        Some(Order::EndOfSong) | None => 255,
+    }
+}
+
+/// Returns the number of samples.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_sample_count(module: *const Module) -> usize {
+    (*module).samples.len()
+}
+
+/// Fills the header fields of the sample, returns `false` if there is no such sample.
+///
+/// # Safety
+///
+/// `module` must be a valid module and `info` must point to writable memory for the structure.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_sample_info(module: *const Module, index: usize, info: *mut IttechSampleInfo) -> bool {
+    let sample = match sample(module, index) {
+        Some(sample) => sample,
+        None => {
+            set_error(format!("sample {} does not exist", index));
+            return false;
+        }
+    };
+    let mut flags = 0;
+    for (sample_loop, on, pingpong) in [
+        (sample.loop_, ITTECH_SAMPLE_LOOP, ITTECH_SAMPLE_PINGPONG_LOOP),
+        (sample.sustain_loop, ITTECH_SAMPLE_SUSTAIN_LOOP, ITTECH_SAMPLE_PINGPONG_SUSTAIN),
+    ] {
+        if let Some(sample_loop) = sample_loop {
+            flags |= if sample_loop.bidi { on | pingpong } else { on };
+        }
+    }
+    let range = |sample_loop: Option<crate::SampleLoop>| sample_loop.map_or((0, 0), |l| (l.start, l.end));
+    let (loop_start, loop_end) = range(sample.loop_);
+    let (sustain_loop_start, sustain_loop_end) = range(sample.sustain_loop);
+    info.write(IttechSampleInfo {
+        name: sample.name.bytes,
+        length: sample.length(),
+        c5_speed: sample.samplerate_c5,
+        global_volume: sample.global_volume,
+        default_volume: sample.default_volume,
+        default_panning: sample.default_panning,
+        flags,
+        loop_start,
+        loop_end,
+        sustain_loop_start,
+        sustain_loop_end,
+        channels: sample.channels.as_u8(),
+    });
+    true
+}
+
+/// Returns the normalized sample data (values from -1.0 to 1.0) and stores its length in `len`,
+/// `NULL` if the sample doesn't exist or has no data. The channels of stereo samples are
+/// interleaved.
+///
+/// # Safety
+///
+/// `module` must be a valid module and `len` must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_sample_data(module: *const Module, index: usize, len: *mut usize) -> *const f32 {
+    let data = sample(module, index).and_then(|sample| sample.data.as_deref()).unwrap_or_default();
+    len.write(data.len());
+    if data.is_empty() { ptr::null() } else { data.as_ptr() }
+}
+
+/// Returns the number of patterns.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_pattern_count(module: *const Module) -> usize {
+    (*module).patterns.len()
+}
+
+/// Returns the number of rows of the pattern, `0` if there is no such pattern.
+///
+/// # Safety
+///
+/// `module` must be a valid module.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_pattern_rows(module: *const Module, pattern: usize) -> usize {
+    (*module).patterns.as_slice().get(pattern).map_or(0, |pattern| pattern.rows.len())
+}
+
+/// Fills the cell of the pattern on the row and channel (`0..127`), returns `false` if the
+/// position is out of range. Empty cells have the mask `0`.
+///
+/// # Safety
+///
+/// `module` must be a valid module and `cell` must point to writable memory for the structure.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_pattern_cell(
+    module: *const Module,
+    pattern: usize,
+    row: usize,
+    channel: u8,
+    cell: *mut IttechCell,
+) -> bool {
+    let commands = (*module).patterns.as_slice().get(pattern).and_then(|pattern| pattern.rows.as_slice().get(row));
+    let commands = match commands {
+        Some(commands) if usize::from(channel) < MAX_CHANNELS => commands,
+        _ => {
+            set_error(format!("pattern {} has no row {} with channel {}", pattern, row, channel));
+            return false;
+        }
+    };
+    let mut out = IttechCell { mask: 0, note: 0, instrument: 0, volume: 0, effect: 0, param: 0 };
+    if let Some(command) = commands.get(Channel::from_u8_index(channel)) {
+        if let Some(note) = command.note {
+            out.mask |= ITTECH_CELL_NOTE;
+            out.note = note.to_raw();
+        }
+        if let Some(instrument) = command.instrument {
+            out.mask |= ITTECH_CELL_INSTRUMENT;
+            out.instrument = instrument.as_u8() + 1;
+        }
+        if let Some(volume) = command.volume {
+            out.mask |= ITTECH_CELL_VOLUME;
+            out.volume = u8::from(volume);
+        }
+        if let Some(effect) = command.effect {
+            out.mask |= ITTECH_CELL_EFFECT;
+            let (effect, param) = effect.to_raw();
+            out.effect = effect;
+            out.param = param;
+        }
+    }
+    cell.write(out);
+    true
+}
+
+/// Writes the module as an Impulse Tracker module file, returns `false` if it fails.
+///
+/// The file is stored in `data` and its length in `len`, the buffer has to be freed by
+/// [`ittech_buffer_free`].
+///
+/// # Safety
+///
+/// `module` must be a valid module, `data` and `len` must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_write(module: *const Module, data: *mut *mut u8, len: *mut usize) -> bool {
+    let module = &*module;
+    catch(false, || {
+        let mut file = Vec::new();
+        match writer::module_file(module, &mut file) {
+            Ok(()) => {
+                let file = file.into_boxed_slice();
+                len.write(file.len());
+                data.write(Box::into_raw(file).cast::<u8>());
+                true
+            }
+            Err(error) => {
+                set_error(error.to_string());
+                false
+            }
+        }
+    })
+}
+
+/// Frees the buffer returned by [`ittech_write`], does nothing for `NULL`.
+///
+/// # Safety
+///
+/// `data` and `len` must be returned by [`ittech_write`] and the buffer not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn ittech_buffer_free(data: *mut u8, len: usize) {
+    if !data.is_null() {
+        drop(Box::from_raw(ptr::slice_from_raw_parts_mut(data, len)));
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::ffi::CStr;
+    use std::mem::MaybeUninit;
+
+    #[test]
+    fn parse_inspect_write() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        unsafe {
+            assert!(ittech_parse(DATA.as_ptr(), 10).is_null());
+            assert!(!CStr::from_ptr(ittech_last_error()).to_bytes().is_empty());
+
+            let module = ittech_parse(DATA.as_ptr(), DATA.len());
+            assert!(!module.is_null());
+            assert_eq!(ittech_module_tempo(module), 125);
+            assert_eq!(ittech_order(module, ittech_order_count(module)), 255);
+
+            let mut info = MaybeUninit::uninit();
+            assert!(ittech_sample_info(module, 0, info.as_mut_ptr()));
+            assert_eq!(info.assume_init_ref().length, 0);
+            assert!(!ittech_sample_info(module, ittech_sample_count(module), info.as_mut_ptr()));
+
+            let mut cell = MaybeUninit::uninit();
+            let rows = ittech_pattern_rows(module, 0);
+            assert!(ittech_pattern_cell(module, 0, rows - 1, 126, cell.as_mut_ptr()));
+            assert!(!ittech_pattern_cell(module, 0, rows, 0, cell.as_mut_ptr()));
+            assert!(!ittech_pattern_cell(module, 0, 0, 127, cell.as_mut_ptr()));
+
+            let (mut data, mut len) = (ptr::null_mut(), 0);
+            assert!(ittech_write(module, &mut data, &mut len));
+            let written = ittech_parse(data, len);
+            assert_eq!(ittech_pattern_count(written), ittech_pattern_count(module));
+            ittech_buffer_free(data, len);
+            ittech_module_free(written);
+            ittech_module_free(module);
+        }
+    }
+}
@@ -0,0 +1,107 @@
+//! Importers for other tracker module formats
+//!
+//! Impulse Tracker was designed as a successor of these formats so most of their features have a
+//! direct equivalent in the IT data model. The importers convert the files into a [`Module`]
+//! which can be used with the rest of the crate like any parsed IT file. Parts of the files which
+//! have no equivalent are documented on each importer, with the `log` feature enabled the
+//! importers also log an info message whenever something is lost. IT modules stored in Unreal
+//! music packages are extracted by [`umx`].
+//!
+//! With the feature `std` samples can also be imported from WAV files by [`Sample::from_wav`].
+//!
+//! [`Module`]: crate::Module
+//! [`Sample::from_wav`]: crate::Sample::from_wav
+
+use crate::data::*;
+use crate::parser::util::Cast;
+use alloc::vec::Vec;
+
+macro_rules! info {
+    ( $($tt:tt)* ) => {
+        #[cfg(feature = "tracing")]
+        ::tracing::info!($($tt)*);
+    };
+}
+
+
+pub mod protracker;
+pub mod s3m;
+pub mod umx;
+#[cfg(feature = "std")]
+mod wav;
+pub mod xm;
+
+
+/// Converts a name, names longer than 25 bytes are truncated.
+fn name(bytes: &[u8]) -> Name {
+    let mut name = Name { bytes: [0; 26] };
+    let len = bytes.iter().position(|&byte| byte == 0).unwrap_or(bytes.len()).min(25);
+    name.bytes[..len].copy_from_slice(&bytes[..len]);
+    name
+}
+
+/// Sample without data playing at 8363 Hz at C-5 with full volume and no panning.
+pub(crate) fn empty_sample() -> Sample {
+    Sample {
+        name: name(&[]),
+        filename: DosFilename { bytes: [0; 13] },
+        global_volume: 64,
+        default_volume: 64,
+        default_panning: 32,
+        loop_: None,
+        sustain_loop: None,
+        samplerate_c5: C5Speed::NTSC.as_u32(),
+        vibrato_speed: 0,
+        vibrato_depth: 0,
+        vibrato_rate: 0,
+        vibrato_type: 0,
+        channels: RangedU8::MIN,
+        data: None,
+        fm_patch: None,
+        conversion: SampleConversion::empty(),
+        encoded: None,
+        deferred: None,
+    }
+}
+
+/// Instrument without samples or envelopes, with full volume and no panning.
+pub(crate) fn empty_instrument() -> Instrument {
+    Instrument {
+        name: name(&[]),
+        filename: DosFilename { bytes: [0; 13] },
+        flags: InstrumentFlags::default(),
+        new_note_action: 0,
+        duplicate_check_type: 0,
+        duplicate_check_action: 0,
+        instrument_fadeout: 0,
+        pitch_pan_separation: 0,
+        pitch_pan_centre: 60,
+        global_volume: 128,
+        default_panning: 32.cast(),
+        random_volume_variation: 0.cast(),
+        random_panning_variation: 0.cast(),
+        trkver: 0x0214,
+        number_of_samples: 0,
+        initial_filter_cutoff: 0.cast(),
+        initial_filter_resonance: 0.cast(),
+        mch: 0,
+        mpr: 0xFF,
+        mbank: [0xFF; 2],
+        sample_map: SampleMap::default(),
+        volume_envelope: disabled_envelope(),
+        panning_envelope: disabled_envelope(),
+        pitch_filter_envelope: disabled_envelope(),
+        openmpt_extensions: None,
+        tuning: None,
+    }
+}
+
+/// Envelope without nodes, turned off.
+fn disabled_envelope() -> Envelope {
+    Envelope {
+        flags: EnvelopeFlags::empty(),
+        envelope_loop: None,
+        sustain_loop: None,
+        nodes: Vec::new(),
+    }
+}
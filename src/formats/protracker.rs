@@ -0,0 +1,291 @@
+//! ProTracker module files (.mod)
+//!
+//! Files with 31 samples and the `M.K.`, `M!K!`, `FLT4`, `xCHN` or `xxCH` signatures are
+//! supported, which covers ProTracker and the trackers compatible with it. They are converted into
+//! modules in sample mode with Amiga slides and old effects, the channels are panned hard left and
+//! right in the Amiga order (left, right, right, left).
+//!
+//! Notes are stored as Amiga periods and converted to the nearest note, the period 428 (C-2 in
+//! ProTracker) is played as C-5. Sample finetune is converted to the C-5 speed of the sample. The
+//! effects are the same as the first 16 effects of XM files and are converted the same way, see
+//! the [`xm`] importer.
+//!
+//! # Lossy conversion
+//!
+//! - Periods between notes are rounded to the nearest note.
+//! - The restart position of the song is not kept, IT songs always restart from the beginning.
+//! - Effects without an IT equivalent are dropped, see the [`xm`] importer.
+
+use super::{empty_sample, name, xm};
+use crate::data::*;
+use crate::data::float;
+use crate::error::ContextError;
+use crate::parser::util::{byte_array, Cast};
+use alloc::string::String;
+use alloc::vec::Vec;
+use nom::bytes::complete::take;
+use nom::error::ParseError;
+use nom::multi::count;
+use nom::number::complete::{be_u16, le_u8};
+use nom::{Err, IResult};
+
+
+/// Number of samples of a file
+const SAMPLES: usize = 31;
+
+/// Number of rows of every pattern
+const ROWS: usize = 64;
+
+
+
+/// ProTracker sample header, converted to a sample once the data is read
+struct SampleHeader {
+    name: [u8; 22],
+    length: usize,
+    finetune: u8,
+    volume: u8,
+    loop_start: usize,
+    loop_length: usize,
+}
+
+
+/// Parse ProTracker module file (.mod) into a module
+///
+/// See the [module documentation](self) for how the file is converted.
+pub fn module_file<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, title) = byte_array::<_, 20>(input)?;
+    let (rest, headers) = count(sample_header, SAMPLES)(rest)?;
+    let (rest, song_length) = le_u8(rest)?;
+    let (rest, _restart) = le_u8(rest)?;
+    let (rest, order_table) = byte_array::<_, 128>(rest)?;
+    let (rest, signature) = byte_array::<_, 4>(rest)?;
+    let channels = match channel_count(&signature) {
+        Some(channels) => channels,
+        None => bail!(input, "unknown signature {:?}", signature),
+    };
+
+    // Patterns which are not played are stored too, the highest one in the whole table counts.
+    let pattern_count = order_table.iter().max().map_or(0, |&max| usize::from(max) + 1);
+    let mut rest = rest;
+    let mut patterns = Vec::with_capacity(pattern_count);
+    for index in 0..pattern_count {
+        let (tail, pattern) = context!(|input| pattern(input, channels), "in pattern {}", index)(rest)?;
+        rest = tail;
+        patterns.push(pattern);
+    }
+
+    let mut samples = Vec::with_capacity(SAMPLES);
+    for header in headers {
+        // Files are often cut short at the end of the last sample.
+        let (data, tail) = rest.split_at(header.length.min(rest.len()));
+        rest = tail;
+        samples.push(sample(header, data));
+    }
+
+    let orders = order_table[..usize::from(song_length).min(128)]
+        .iter()
+        .map(|&order| Order::Index(order.cast()))
+        .collect();
+
+    let mut init_channel_panning = [ChannelPan::DISABLED; MAX_CHANNELS];
+    for (channel, pan) in init_channel_panning.iter_mut().enumerate().take(channels) {
+        *pan = ChannelPan::from(match channel % 4 {
+            0 | 3 => 0,
+            _ => 64,
+        });
+    }
+
+    Ok(Module {
+        name: name(&title),
+        message: String::new(),
+        highlight: (16, 4),
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags: ModuleFlags::STEREO | ModuleFlags::OLD_EFFECTS,
+        stored_flags: 0,
+        global_volume: 128.cast(),
+        sample_volume: 48.cast(),
+        speed: 6.cast(),
+        tempo: 125.cast(),
+        pan_separation: 128.cast(),
+        pitch_wheel_depth: 0,
+        init_channel_panning,
+        init_channel_volume: [ChannelVolume::FULL; MAX_CHANNELS],
+        orders,
+        instruments: Vec::new(),
+        samples,
+        patterns,
+        midi_config: None,
+        edit_history: Vec::new(),
+        openmpt_channel_count: (channels > 64).then(|| channels.min(MAX_CHANNELS).cast()),
+        pattern_names: Vec::new(),
+        channel_names: Vec::new(),
+        openmpt_extensions: None,
+        opaque: OpaqueData::default(),
+    })
+}
+
+/// Returns the number of channels of the signature, `None` if it's not supported.
+fn channel_count(signature: &[u8; 4]) -> Option<usize> {
+    let digit = |byte: u8| byte.is_ascii_digit().then(|| usize::from(byte - b'0'));
+    let channels = match *signature {
+        [b'M', b'.', b'K', b'.'] | [b'M', b'!', b'K', b'!'] | [b'F', b'L', b'T', b'4'] => 4,
+        [x, b'C', b'H', b'N'] => digit(x)?,
+        [x, y, b'C', b'H'] => digit(x)? * 10 + digit(y)?,
+        _ => return None,
+    };
+    (1..=MAX_CHANNELS).contains(&channels).then_some(channels)
+}
+
+fn sample_header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], SampleHeader, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    // Lengths and loop points are stored in 16-bit words.
+    let (input, name) = byte_array(input)?;
+    let (input, length) = be_u16(input)?;
+    let (input, finetune) = le_u8(input)?;
+    let (input, volume) = le_u8(input)?;
+    let (input, loop_start) = be_u16(input)?;
+    let (input, loop_length) = be_u16(input)?;
+    Ok((
+        input,
+        SampleHeader {
+            name,
+            length: usize::from(length) * 2,
+            finetune: finetune & 0x0F,
+            volume,
+            loop_start: usize::from(loop_start) * 2,
+            loop_length: usize::from(loop_length) * 2,
+        },
+    ))
+}
+
+fn sample(header: SampleHeader, data: &[u8]) -> Sample {
+    let mut sample = empty_sample();
+    sample.name = name(&header.name);
+    sample.default_volume = header.volume.min(64);
+    sample.samplerate_c5 = C5Speed::from_mod_finetune(header.finetune).into();
+
+    // Loops of one word are the way to store no loop.
+    let end = (header.loop_start + header.loop_length).min(data.len());
+    if header.loop_length > 2 && header.loop_start < end {
+        sample.loop_ = Some(SampleLoop {
+            start: header.loop_start.cast(),
+            end: end.cast(),
+            bidi: false,
+        });
+    }
+    if !data.is_empty() {
+        sample.data = Some(data.iter().map(|&byte| convert::i8_to_f32(i8::from_le_bytes([byte]))).collect());
+    }
+    sample
+}
+
+fn pattern<'i, E>(input: &'i [u8], channels: usize) -> IResult<&'i [u8], Pattern, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, data) = take(ROWS * channels * 4)(input)?;
+    let mut active_channels = ActiveChannels::empty();
+    let mut rows = vec![Row::empty(); ROWS];
+    for (row, cells) in rows.iter_mut().zip(data.chunks_exact(channels * 4)) {
+        for (channel, cell) in cells.chunks_exact(4).enumerate() {
+            // The sample number is split into the high nibbles of the first and third byte, the
+            // rest of the first two bytes is the period.
+            let sample = cell[0] & 0xF0 | cell[2] >> 4;
+            let period = u16::from_be_bytes([cell[0] & 0x0F, cell[1]]);
+            let command = xm::command([note(period), sample, 0, cell[2] & 0x0F, cell[3]]);
+            if !command.is_empty() {
+                let channel = Channel::from_u8_index(channel.cast());
+                active_channels |= ActiveChannels::new([channel]);
+                row.insert(channel, command);
+            }
+        }
+    }
+
+    Ok((
+        rest,
+        Pattern {
+            active_channels,
+            rows,
+            truncated: false,
+            highlight: None,
+        },
+    ))
+}
+
+/// Converts the Amiga period to the nearest XM note, 0 if there is no note.
+fn note(period: u16) -> u8 {
+    if period == 0 {
+        return 0;
+    }
+    // Period 428 is C-5 in IT, that's note 49 in XM.
+    let note = 49.0 + float::round(12.0 * float::log10(428.0 / f32::from(period)) / float::log10(2.0));
+    if (1.0..=96.0).contains(&note) {
+        // The value is in range 1..=96, the cast cannot truncate.
+        #[allow(clippy::as_conversions, clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        { note as u8 }
+    } else {
+        info!(period, "period is out of the range of notes, skipping");
+        0
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use core::convert::TryFrom;
+
+    #[test]
+    fn module_file() {
+        let mut file = b"song".to_vec();
+        file.resize(20, 0);
+        let mut header = vec![0; 30];
+        header[..4].copy_from_slice(b"bass");
+        header[23] = 2; // 4 bytes long
+        header[24] = 0xF; // finetune -1
+        header[25] = 48;
+        header[27] = 1; // loop from byte 2
+        header[29] = 1; // loop of one word, that's no loop
+        file.extend(header);
+        file.resize(950, 0);
+        file.extend([2, 127, 0, 1]);
+        file.resize(1080, 0);
+        file.extend(b"6CHN");
+
+        // Pattern 0: C-2 sample 1 on channel 1 with `C20`, pattern 1: `F03` on channel 6.
+        let mut pattern = vec![0; 64 * 6 * 4];
+        pattern[..4].copy_from_slice(&[0x01, 0xAC, 0x1C, 0x20]);
+        file.extend(pattern);
+        let mut pattern = vec![0; 64 * 6 * 4];
+        pattern[20..24].copy_from_slice(&[0, 0, 0x0F, 0x03]);
+        file.extend(pattern);
+        file.extend([0, 127, 0x81, 0]);
+
+        let module = super::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(&module.name.bytes[..5], b"song\0");
+        assert_eq!(module.orders, [Order::Index(PatternId::try_from(0).unwrap()), Order::Index(PatternId::try_from(1).unwrap())]);
+        assert!(!module.flags.contains(ModuleFlags::LINEAR_SLIDES | ModuleFlags::USE_INSTRUMENTS));
+        assert_eq!(module.init_channel_panning.map(u8::from)[..7], [0, 64, 64, 0, 0, 64, 32 | 128]);
+
+        let sample = &module.samples[0];
+        assert_eq!(sample.data.as_deref(), Some(&[0.0, 1.0, -1.0, 0.0][..]));
+        assert_eq!(sample.samplerate_c5, 8280);
+        assert_eq!(sample.default_volume, 48);
+        assert!(sample.loop_.is_none());
+        assert!(module.samples[1].data.is_none());
+
+        let command = module.patterns[0].command(0, Channel::new(1)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 60));
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(0).unwrap()));
+        assert!(matches!(command.volume, Some(VolumeCmd::SetVolume(volume)) if volume.as_u8() == 32));
+        let command = module.patterns[1].command(0, Channel::new(6)).unwrap();
+        assert!(matches!(command.effect, Some(EffectCmd::SetSpeed(speed)) if speed.as_u8() == 3));
+    }
+}
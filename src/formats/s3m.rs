@@ -0,0 +1,532 @@
+//! Scream Tracker 3 module files (.s3m)
+//!
+//! S3M files are converted into modules in sample mode with Amiga slides and old effects, which
+//! is how Impulse Tracker plays them. Notes are moved up by one octave, S3M plays C-4 at the C2
+//! speed of the sample where IT plays C-5 at the C-5 speed. Most effects have the same letter and
+//! meaning in both formats, the exceptions are converted:
+//!
+//! - `Cxx` stores the row in binary coded decimal,
+//! - `Vxx` (global volume) ranges to `0x40` instead of `0x80`,
+//! - `Xxx` (panning) ranges to `0x80` instead of `0xFF` and `XA4` sets surround,
+//! - `SAx` is the obsolete ST3 stereo control, it is dropped,
+//! - `Txx` below `0x20` is ignored by ST3, it is dropped.
+//!
+//! # Lossy conversion
+//!
+//! - ST3 shares the effect memory of all effects in a channel, IT keeps separate memory for most
+//!   effects, effects with a zero parameter relying on the shared memory may play differently.
+//! - Adlib instruments are kept as OPL patches ([`Sample::fm_patch`]) without sample data, Adlib
+//!   drum instruments are imported as empty samples.
+//! - Packed (ADPCM) samples are imported as empty.
+//! - Only the first 99 samples and 200 patterns are imported.
+
+use super::{empty_sample, name};
+use crate::data::*;
+use crate::error::ContextError;
+use crate::parser::interleave;
+use crate::parser::util::{byte_array, Cast};
+use alloc::string::String;
+use alloc::vec::Vec;
+use nom::bytes::complete::{tag, take};
+use nom::error::{ErrorKind, ParseError};
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::sequence::tuple;
+use nom::{Err, IResult};
+use core::convert::TryFrom;
+
+
+/// Number of rows of every pattern
+const ROWS: usize = 64;
+
+/// Number of channels an S3M file can store
+const CHANNELS: usize = 32;
+
+/// Number of samples and patterns the IT data model can hold
+const MAX_SAMPLES: usize = 99;
+const MAX_PATTERNS: usize = 200;
+
+/// Default value of the header panning field requesting the stored channel panning
+const STORED_PANNING: u8 = 252;
+
+
+struct Header {
+    name: [u8; 28],
+    flags: u16,
+    signed_samples: bool,
+    global_volume: u8,
+    speed: u8,
+    tempo: u8,
+    master_volume: u8,
+    channel_settings: [u8; CHANNELS],
+    orders: Vec<Order>,
+    instrument_offsets: Vec<u16>,
+    pattern_offsets: Vec<u16>,
+    channel_panning: Option<[u8; CHANNELS]>,
+}
+
+/// Parse Scream Tracker 3 module file (.s3m) into a module
+///
+/// See the [module documentation](self) for how the file is converted.
+pub fn module_file<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (_, header) = context!(header, "reading S3M header")(input)?;
+
+    if header.instrument_offsets.len() > MAX_SAMPLES {
+        info!(count = header.instrument_offsets.len(), "only 99 samples are supported, skipping the rest");
+    }
+    if header.pattern_offsets.len() > MAX_PATTERNS {
+        info!(count = header.pattern_offsets.len(), "only 200 patterns are supported, skipping the rest");
+    }
+
+    // Parapointers are offsets from the start of the file in 16 byte paragraphs.
+    let samples = header.instrument_offsets
+        .iter()
+        .take(MAX_SAMPLES)
+        .map(|&offset| sample(input, usize::from(offset) * 16, header.signed_samples))
+        .collect::<Result<Vec<_>, _>>()?;
+    let patterns = header.pattern_offsets
+        .iter()
+        .take(MAX_PATTERNS)
+        .map(|&offset| match usize::from(offset) * 16 {
+            0 => Ok(Pattern {
+                active_channels: ActiveChannels::empty(),
+                rows: vec![Row::empty(); ROWS],
+                truncated: false,
+                highlight: None,
+            }),
+            offset => {
+                let input = input.get(offset..).ok_or_else(|| Err::Error(E::from_error_kind(input, ErrorKind::Eof)))?;
+                let (_, pattern) = context!(pattern, "in pattern")(input)?;
+                Ok(pattern)
+            }
+        })
+        .collect::<Result<Vec<_>, Err<E>>>()?;
+
+    let stereo = header.master_volume & 0x80 != 0;
+    let mut flags = ModuleFlags::OLD_EFFECTS;
+    if stereo {
+        flags |= ModuleFlags::STEREO;
+    }
+    if header.flags & 0x08 != 0 {
+        flags |= ModuleFlags::VOL_0_MIX_OPTIMIZATIONS;
+    }
+
+    let mut init_channel_panning = [ChannelPan::DISABLED; MAX_CHANNELS];
+    for (channel, &setting) in header.channel_settings.iter().enumerate() {
+        init_channel_panning[channel] = channel_panning(setting, header.channel_panning.map(|pan| pan[channel]), stereo);
+    }
+
+    let speed = match header.speed {
+        0 | 255 => {
+            info!(speed = header.speed, "invalid speed, using default of 6");
+            6
+        }
+        speed => speed,
+    };
+    let tempo = if header.tempo < 33 {
+        info!(tempo = header.tempo, "tempo must be at least 33, using default of 125");
+        125
+    } else {
+        header.tempo
+    };
+    let sample_volume = header.master_volume & 0x7F;
+
+    Ok(Module {
+        name: name(&header.name),
+        message: String::new(),
+        highlight: (16, 4),
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags,
+        stored_flags: 0,
+        global_volume: (header.global_volume.min(64) * 2).cast(),
+        sample_volume: sample_volume.cast(),
+        speed: speed.cast(),
+        tempo: tempo.cast(),
+        pan_separation: 128.cast(),
+        pitch_wheel_depth: 0,
+        init_channel_panning,
+        init_channel_volume: [ChannelVolume::FULL; MAX_CHANNELS],
+        orders: header.orders,
+        instruments: Vec::new(),
+        samples,
+        patterns,
+        midi_config: None,
+        edit_history: Vec::new(),
+        openmpt_channel_count: None,
+        pattern_names: Vec::new(),
+        channel_names: Vec::new(),
+        openmpt_extensions: None,
+        opaque: OpaqueData::default(),
+    })
+}
+
+fn header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Header, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, name) = byte_array(input)?;
+    let (input, _eof) = le_u8(input)?;
+    let (input, _) = context!(tag(b"\x10"), "file type is not a module")(input)?;
+    let (input, _reserved) = le_u16(input)?;
+    let (input, ordnum) = le_u16(input)?;
+    let (input, insnum) = le_u16(input)?;
+    let (input, patnum) = le_u16(input)?;
+    let (input, flags) = le_u16(input)?;
+    let (input, _cwtv) = le_u16(input)?;
+    let (input, ffi) = le_u16(input)?;
+    let (input, _) = tag(b"SCRM")(input)?;
+    let (input, global_volume) = le_u8(input)?;
+    let (input, speed) = le_u8(input)?;
+    let (input, tempo) = le_u8(input)?;
+    let (input, master_volume) = le_u8(input)?;
+    let (input, _ultra_click) = le_u8(input)?;
+    let (input, default_panning) = le_u8(input)?;
+    let (input, _reserved) = take(8usize)(input)?;
+    let (input, _special) = le_u16(input)?;
+    let (input, channel_settings) = byte_array(input)?;
+    let (input, orders) = count(le_u8, ordnum.into())(input)?;
+    let (input, instrument_offsets) = count(le_u16, insnum.into())(input)?;
+    let (input, pattern_offsets) = count(le_u16, patnum.into())(input)?;
+    let (input, channel_panning) = if default_panning == STORED_PANNING {
+        let (input, panning) = byte_array(input)?;
+        (input, Some(panning))
+    } else {
+        (input, None)
+    };
+
+    let orders = orders.into_iter()
+        .filter_map(|order| match order {
+            0 ..= 199 => Some(Order::Index(order.cast())),
+            254 => Some(Order::Separator),
+            255 => Some(Order::EndOfSong),
+            _ => {
+                info!(order, "order value is out of range 0..=199,254,255, skipping");
+                None
+            }
+        })
+        .collect();
+
+    Ok((
+        input,
+        Header {
+            name,
+            flags,
+            signed_samples: ffi == 1,
+            global_volume,
+            speed,
+            tempo,
+            master_volume,
+            channel_settings,
+            orders,
+            instrument_offsets,
+            pattern_offsets,
+            channel_panning,
+        },
+    ))
+}
+
+/// Converts the channel setting and the stored panning to the IT channel panning.
+///
+/// Channels 0-7 of the setting are on the left, 8-15 on the right and 16-31 are Adlib channels
+/// which are centred. Bit 7 mutes the channel, 255 marks an unused channel which is disabled too.
+fn channel_panning(setting: u8, stored: Option<u8>, stereo: bool) -> ChannelPan {
+    let muted = if setting & 0x80 != 0 { 128 } else { 0 };
+    let pan = match (setting & 0x7F, stored) {
+        _ if !stereo => 32,
+        (_, Some(stored)) if stored & 0x20 != 0 => nibble_panning(stored & 0x0F),
+        (0 ..= 7, _) => nibble_panning(0x3),
+        (8 ..= 15, _) => nibble_panning(0xC),
+        _ => 32,
+    };
+    ChannelPan::from(pan | muted)
+}
+
+/// Converts panning in range 0..=15 to range 0..=64.
+fn nibble_panning(pan: u8) -> u8 {
+    ((u16::from(pan) * 64 + 7) / 15).cast()
+}
+
+/// Parses the instrument at the offset, `offset` 0 is an empty sample.
+fn sample<'i, E>(file: &'i [u8], offset: usize, signed: bool) -> Result<Sample, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let mut sample = empty_sample();
+    if offset == 0 {
+        return Ok(sample);
+    }
+
+    let input = file.get(offset..).ok_or_else(|| Err::Error(E::from_error_kind(file, ErrorKind::Eof)))?;
+    let (input, kind) = le_u8(input)?;
+    let (input, filename) = byte_array::<_, 12>(input)?;
+    let (input, memseg_high) = le_u8(input)?;
+    let (input, memseg_low) = le_u16(input)?;
+    // Adlib instruments store the OPL registers in place of the length and loop points.
+    let (_, fm_patch) = byte_array(input)?;
+    let (input, length) = le_u32(input)?;
+    let (input, loop_start) = le_u32(input)?;
+    let (input, loop_end) = le_u32(input)?;
+    let (input, volume) = le_u8(input)?;
+    let (input, _reserved) = le_u8(input)?;
+    let (input, pack) = le_u8(input)?;
+    let (input, flags) = le_u8(input)?;
+    let (input, c2spd) = le_u32(input)?;
+    let (input, _internal) = take(12usize)(input)?;
+    let (_, sample_name) = byte_array::<_, 28>(input)?;
+
+    sample.name = name(&sample_name);
+    sample.filename.bytes[..12].copy_from_slice(&filename);
+    sample.default_volume = volume.min(64);
+    if c2spd != 0 {
+        sample.samplerate_c5 = c2spd;
+    }
+
+    match kind {
+        0 => {}
+        1 if pack != 0 => {
+            info!(pack, "packed samples are not supported, skipping sample data");
+        }
+        1 => {
+            let offset = (usize::from(memseg_high) << 16 | usize::from(memseg_low)) * 16;
+            let sixteen_bit = flags & 0x04 != 0;
+            let stereo = flags & 0x02 != 0;
+            let channels = if stereo { 2 } else { 1 };
+            let data = pcm_data(file.get(offset..).unwrap_or(&[]), channels * length.cast::<usize>(), sixteen_bit, signed);
+            let data = if stereo { stereo_data(data, length.cast()) } else { data };
+            if stereo {
+                sample.channels = RangedU8::MAX;
+            }
+            let len = u32::try_from(data.len() / channels).unwrap();
+            let loop_end = loop_end.min(len);
+            if flags & 0x01 != 0 && loop_start < loop_end {
+                sample.loop_ = Some(SampleLoop { start: loop_start, end: loop_end, bidi: false });
+            }
+            sample.data = Some(data.into());
+        }
+        2 => sample.fm_patch = Some(fm_patch),
+        _ => {
+            info!(kind, "Adlib drum instruments are not supported, importing an empty sample");
+        }
+    }
+    Ok(sample)
+}
+
+/// Decodes PCM sample data, files cut short keep the samples present.
+fn pcm_data(input: &[u8], length: usize, sixteen_bit: bool, signed: bool) -> Vec<f32> {
+    if sixteen_bit {
+        input.chunks_exact(2)
+            .take(length)
+            .map(|bytes| {
+                let s = u16::from_le_bytes([bytes[0], bytes[1]]);
+                if signed { i16::from_le_bytes(s.to_le_bytes()) } else { convert::u16_to_i16(s) }
+            })
+            .map(convert::i16_to_f32)
+            .collect()
+    } else {
+        input.iter()
+            .take(length)
+            .map(|&byte| if signed { i8::from_le_bytes([byte]) } else { convert::u8_to_i8(byte) })
+            .map(convert::i8_to_f32)
+            .collect()
+    }
+}
+
+/// Interleaves the left and the right channel stored one after the other, the right channel of
+/// files cut short is padded with silence.
+fn stereo_data(mut data: Vec<f32>, length: usize) -> Vec<f32> {
+    let frames = data.len().min(length);
+    let mut right = data.split_off(frames);
+    right.resize(frames, 0.0);
+    data.extend(right);
+    interleave(data, 2)
+}
+
+fn pattern<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Pattern, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    // The stored length is not reliable, the pattern always ends after 64 rows.
+    let (mut input, _length) = le_u16(input)?;
+    let mut active_channels = ActiveChannels::empty();
+    let mut rows = Vec::with_capacity(ROWS);
+    for _ in 0..ROWS {
+        let mut row = Row::empty();
+        loop {
+            let (rest, what) = context!(le_u8, "reading channel")(input)?;
+            input = rest;
+            if what == 0 {
+                break;
+            }
+            let channel = Channel::from_u8_index(what & 0x1F);
+            let mut command = Command { note: None, instrument: None, volume: None, effect: None };
+            if what & 0x20 != 0 {
+                let (rest, (note, instrument)) = tuple((le_u8, le_u8))(input)?;
+                input = rest;
+                command.note = self::note(note);
+                command.instrument = match instrument {
+                    1 ..= 99 => Some((instrument - 1).cast()),
+                    _ => None,
+                };
+            }
+            if what & 0x40 != 0 {
+                let (rest, volume) = le_u8(input)?;
+                input = rest;
+                command.volume = match volume {
+                    0 ..= 64 | 128 ..= 192 => VolumeCmd::try_from(volume).ok(),
+                    _ => None,
+                };
+            }
+            if what & 0x80 != 0 {
+                let (rest, (effect, param)) = tuple((le_u8, le_u8))(input)?;
+                input = rest;
+                command.effect = self::effect(effect, param);
+            }
+            if !command.is_empty() {
+                active_channels |= ActiveChannels::new([channel]);
+                row.insert(channel, command);
+            }
+        }
+        rows.push(row);
+    }
+
+    Ok((
+        input,
+        Pattern {
+            active_channels,
+            rows,
+            truncated: false,
+            highlight: None,
+        },
+    ))
+}
+
+/// Converts the S3M note, the high nibble is the octave and the low nibble the semitone.
+fn note(note: u8) -> Option<NoteCmd> {
+    match note {
+        255 => None,
+        254 => Some(NoteCmd::Cut),
+        _ if note & 0x0F > 11 => {
+            info!(note, "note semitone is out of range 0..=11, skipping");
+            None
+        }
+        _ => Note::try_from((note >> 4) * 12 + (note & 0x0F) + 12).ok().map(NoteCmd::Play),
+    }
+}
+
+/// Converts S3M effect to the IT effect, see the [module documentation](self).
+fn effect(effect: u8, param: u8) -> Option<EffectCmd> {
+    let (x, y) = (param >> 4, param & 0x0F);
+    let param = match effect {
+        0 => return None,
+        // `Cxx`
+        0x03 => x * 10 + y,
+        // `SAx`
+        0x13 if x == 0xA => {
+            info!("command `SAx` (stereo control) is not supported, skipping");
+            return None;
+        }
+        // `Txx`
+        0x14 if param < 0x20 => {
+            info!(param, "tempo below 0x20 is ignored by ST3, skipping");
+            return None;
+        }
+        // `Vxx`
+        0x16 => param.min(0x40) * 2,
+        // `Xxx`
+        0x18 if param == 0xA4 => return Some(EffectCmd::Special(Some(Special::SetSurround(true)))),
+        0x18 if param >= 0x80 => 0xFF,
+        0x18 => param * 2,
+        0x1B ..= 0xFF => {
+            info!(code = effect, "invalid effect, code out of range 0x0..=0x1A, skipping");
+            return None;
+        }
+        _ => param,
+    };
+    crate::parser::effect(effect, param)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    /// Builds a file with one order, one 8-bit sample and one pattern.
+    fn file() -> Vec<u8> {
+        let mut file = vec![0; 0x60];
+        file[..4].copy_from_slice(b"song");
+        file[0x1C] = 0x1A;
+        file[0x1D] = 0x10;
+        file[0x20] = 2; // orders
+        file[0x22] = 1; // instruments
+        file[0x24] = 1; // patterns
+        file[0x2A] = 2; // unsigned samples
+        file[0x2C..0x30].copy_from_slice(b"SCRM");
+        file[0x30] = 64;
+        file[0x31] = 3;
+        file[0x32] = 150;
+        file[0x33] = 0x80 | 0x30;
+        file[0x40..0x60].fill(255);
+        file[0x40] = 0;
+        file[0x41] = 8;
+        file.extend([0, 255]);
+        // Parapointers of the instrument at 0x70 and the pattern at 0xC0, padded to 0x70.
+        file.extend([0x07, 0, 0x0C, 0]);
+        file.resize(0x70, 0);
+
+        let mut instrument = vec![0; 0x50];
+        instrument[0] = 1;
+        instrument[0x0E] = 0x11; // data at 0x110
+        instrument[0x10] = 4; // length
+        instrument[0x14] = 1; // loop start
+        instrument[0x18] = 4; // loop end
+        instrument[0x1C] = 48;
+        instrument[0x1F] = 1; // loop
+        instrument[0x20..0x22].copy_from_slice(&16726u16.to_le_bytes());
+        instrument[0x30..0x34].copy_from_slice(b"kick");
+        instrument[0x4C..0x50].copy_from_slice(b"SCRS");
+        file.extend(instrument);
+
+        // Row 0: C-4 sample 1 on channel 1 with volume 32 and `C10`, row 1: note cut on channel 2.
+        let mut pattern = vec![0, 0, 0x20 | 0x40 | 0x80, 0x40, 1, 32, 0x03, 0x10, 0, 0x21, 254, 0, 0];
+        pattern.resize(pattern.len() + 62, 0);
+        let length = u16::try_from(pattern.len()).unwrap();
+        pattern[..2].copy_from_slice(&length.to_le_bytes());
+        file.extend(pattern);
+        file.resize(0x110, 0);
+        file.extend([0x80, 0xFF, 0x01, 0x80]);
+        file
+    }
+
+    #[test]
+    fn module_file() {
+        let file = file();
+        let module = super::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(&module.name.bytes[..5], b"song\0");
+        assert_eq!(module.orders, [Order::Index(PatternId::try_from(0).unwrap()), Order::EndOfSong]);
+        assert_eq!((module.speed.as_u8(), module.tempo.as_u8()), (3, 150));
+        assert_eq!(module.global_volume.as_u8(), 128);
+        assert!(module.flags.contains(ModuleFlags::STEREO));
+        assert!(!module.flags.contains(ModuleFlags::LINEAR_SLIDES | ModuleFlags::USE_INSTRUMENTS));
+        assert_eq!(module.init_channel_panning.map(u8::from)[..3], [13, 51, 32 | 128]);
+
+        let sample = &module.samples[0];
+        assert_eq!(sample.data.as_deref(), Some(&[0.0, 1.0, -1.0, 0.0][..]));
+        assert_eq!(sample.samplerate_c5, 16726);
+        assert_eq!(sample.default_volume, 48);
+        assert!(matches!(sample.loop_, Some(SampleLoop { start: 1, end: 4, bidi: false })));
+
+        let pattern = &module.patterns[0];
+        assert_eq!(pattern.rows.len(), 64);
+        let command = pattern.command(0, Channel::new(1)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 60));
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(0).unwrap()));
+        assert!(matches!(command.volume, Some(VolumeCmd::SetVolume(volume)) if volume.as_u8() == 32));
+        assert!(matches!(command.effect, Some(EffectCmd::BreakRow(10))));
+        assert!(matches!(pattern.command(1, Channel::new(2)).unwrap().note, Some(NoteCmd::Cut)));
+    }
+}
@@ -0,0 +1,269 @@
+//! Unreal music packages (.umx)
+//!
+//! Music of Unreal and Unreal Tournament is stored in Unreal packages, each package holds a
+//! `Music` object whose data is the module file stored verbatim after a short preamble which
+//! differs between the package versions. Most of them contain IT modules, the module is located
+//! through the name, import and export tables of the package and parsed by
+//! [`find_and_parse`](crate::parser::find_and_parse) from the data of the object.
+//!
+//! Some packages contain S3M, XM or MOD modules instead, their data can be taken from
+//! [`music_data`] and passed to the importers of those formats.
+
+use crate::data::Module;
+use crate::error::ContextError;
+use crate::parser::{self, util::Cast};
+use alloc::vec::Vec;
+use nom::bytes::complete::take;
+use nom::error::ParseError;
+use nom::multi::count;
+use nom::number::complete::{le_u16, le_u32, le_u8};
+use nom::sequence::tuple;
+use nom::{Err, IResult};
+use core::convert::TryFrom;
+
+
+/// Magic number at the start of Unreal packages
+const TAG: u32 = 0x9E2A_83C1;
+
+/// First package version storing names with their length
+const LENGTH_PREFIXED_NAMES: u16 = 64;
+
+
+/// Offsets of the tables of the package
+struct Header {
+    version: u16,
+    names: (u32, u32),
+    exports: (u32, u32),
+    imports: (u32, u32),
+}
+
+/// Object stored in the package
+struct Export {
+    /// Class reference, negative values are imports
+    class: i32,
+    size: i32,
+    offset: i32,
+}
+
+
+/// Parse Impulse Tracker module stored in Unreal music package (.umx)
+///
+/// The module is looked for in the data of the first `Music` object of the package. Offsets in
+/// the errors of the module are relative to the start of the package.
+pub fn module_file<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let data = music_data(input)?;
+    parser::find_and_parse(data).map(|(_, module)| module)
+}
+
+/// Returns the data of the first `Music` object in Unreal music package (.umx)
+///
+/// The data is the module file preceded by a few bytes of the package version specific object
+/// header.
+pub fn music_data<'i, E>(input: &'i [u8]) -> Result<&'i [u8], Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (_, header) = context!(header, "reading package header")(input)?;
+    let version = header.version;
+    let (_, names) = context!(|input| table(input, header.names, name(version)), "reading name table")(input)?;
+    let (_, imports) = context!(|input| table(input, header.imports, import), "reading import table")(input)?;
+    let (_, exports) = context!(|input| table(input, header.exports, export), "reading export table")(input)?;
+
+    let is_music = |export: &Export| {
+        let import = usize::try_from(-i64::from(export.class) - 1).ok().and_then(|index| imports.get(index));
+        let name = import.and_then(|&name| usize::try_from(name).ok()).and_then(|index| names.get(index));
+        name.is_some_and(|name| name.eq_ignore_ascii_case(b"Music"))
+    };
+    let music = match exports.iter().find(|export| is_music(export) && export.size > 0) {
+        Some(music) => music,
+        None => bail!(input, "no music object in the package"),
+    };
+
+    let start = usize::try_from(music.offset).unwrap_or(usize::MAX);
+    let end = start.saturating_add(music.size.cast());
+    match input.get(start..end) {
+        Some(data) => Ok(data),
+        None => Err(parser::util::past_end(input, end)),
+    }
+}
+
+fn header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Header, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, tag) = le_u32(input)?;
+    if tag != TAG {
+        bail!(input, "expected Unreal package tag, found {:#010x}", tag);
+    }
+    let (rest, version) = le_u16(rest)?;
+    let (rest, _licensee) = le_u16(rest)?;
+    let (rest, _flags) = le_u32(rest)?;
+    let (rest, names) = tuple((le_u32, le_u32))(rest)?;
+    let (rest, exports) = tuple((le_u32, le_u32))(rest)?;
+    let (rest, imports) = tuple((le_u32, le_u32))(rest)?;
+    Ok((rest, Header { version, names, exports, imports }))
+}
+
+/// Parses the table of `count` entries at the offset.
+fn table<'i, O, E>(
+    input: &'i [u8],
+    (entries, offset): (u32, u32),
+    entry: impl FnMut(&'i [u8]) -> IResult<&'i [u8], O, E>,
+) -> IResult<&'i [u8], Vec<O>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let offset = offset.cast::<usize>();
+    if offset > input.len() {
+        return Err(parser::util::past_end(input, offset));
+    }
+    // Every entry takes at least one byte, this bounds the allocation by the input size.
+    let entries = entries.cast::<usize>().min(input.len() - offset);
+    count(entry, entries)(&input[offset..])
+}
+
+/// Parses a name of the name table, the name is returned without the terminating null.
+fn name<'i, E>(version: u16) -> impl Fn(&'i [u8]) -> IResult<&'i [u8], &'i [u8], E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    move |input| {
+        let (rest, name) = if version >= LENGTH_PREFIXED_NAMES {
+            let (rest, length) = compact_index(input)?;
+            take(usize::try_from(length).unwrap_or(0))(rest)?
+        } else {
+            let length = input.iter().position(|&byte| byte == 0).map_or(input.len(), |end| end + 1);
+            take(length)(input)?
+        };
+        let (rest, _flags) = le_u32(rest)?;
+        let end = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+        Ok((rest, &name[..end]))
+    }
+}
+
+/// Parses an entry of the import table, returns the index of the object name.
+fn import<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], i32, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, _class_package) = compact_index(input)?;
+    let (rest, _class_name) = compact_index(rest)?;
+    let (rest, _package) = le_u32(rest)?;
+    compact_index(rest)
+}
+
+fn export<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Export, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, class) = compact_index(input)?;
+    let (rest, _super) = compact_index(rest)?;
+    let (rest, _package) = le_u32(rest)?;
+    let (rest, _name) = compact_index(rest)?;
+    let (rest, _flags) = le_u32(rest)?;
+    let (rest, size) = compact_index(rest)?;
+    let (rest, offset) = if size > 0 { compact_index(rest)? } else { (rest, 0) };
+    Ok((rest, Export { class, size, offset }))
+}
+
+/// Parses a compact index, a signed integer of 1 to 5 bytes.
+///
+/// The first byte holds the sign in bit 7, a continuation flag in bit 6 and the lowest 6 bits of
+/// the value, the following bytes hold a continuation flag in bit 7 and the next 7 bits.
+fn compact_index<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], i32, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (mut rest, first) = le_u8(input)?;
+    let mut value = u32::from(first & 0x3F);
+    let mut more = first & 0x40 != 0;
+    let mut shift = 6;
+    while more && shift < 32 {
+        let (next, byte) = le_u8(rest)?;
+        value |= u32::from(byte & 0x7F) << shift;
+        more = byte & 0x80 != 0;
+        shift += 7;
+        rest = next;
+    }
+    let value = i32::try_from(value).unwrap_or(i32::MAX);
+    Ok((rest, if first & 0x80 != 0 { -value } else { value }))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    /// Encodes a non-negative compact index.
+    fn compact(value: usize) -> Vec<u8> {
+        let mut bytes = vec![u8::try_from(value & 0x3F).unwrap()];
+        let mut value = value >> 6;
+        if value > 0 {
+            bytes[0] |= 0x40;
+        }
+        while value > 0 {
+            let byte = u8::try_from(value & 0x7F).unwrap();
+            value >>= 7;
+            bytes.push(if value > 0 { byte | 0x80 } else { byte });
+        }
+        bytes
+    }
+
+    #[test]
+    fn module_file() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+
+        // Names `None`, `Music`, `Class`, `Core` and `song`, the import of the `Music` class and
+        // one export of it, laid out the way Unreal Tournament (version 69) saves them.
+        let mut names = Vec::new();
+        for name in ["None", "Music", "Class", "Core", "song"] {
+            names.extend(compact(name.len() + 1));
+            names.extend(name.bytes().chain([0]));
+            names.extend([0; 4]);
+        }
+        let mut imports = vec![3, 2];
+        imports.extend([0; 4]);
+        imports.push(1);
+        let mut object = vec![0];
+        object.extend(compact(DATA.len()));
+        object.extend([0; 4]);
+        object.extend(compact(DATA.len()));
+        object.extend(DATA);
+
+        let names_offset = 36;
+        let imports_offset = names_offset + names.len();
+        let object_offset = imports_offset + imports.len();
+        let exports_offset = object_offset + object.len();
+        let mut exports = vec![0x81, 0];
+        exports.extend([0; 4]);
+        exports.push(4);
+        exports.extend([0; 4]);
+        exports.extend(compact(object.len()));
+        exports.extend(compact(object_offset));
+
+        let mut file = TAG.to_le_bytes().to_vec();
+        file.extend(69u16.to_le_bytes());
+        file.extend([0; 6]);
+        for (entries, offset) in [(5, names_offset), (1, exports_offset), (1, imports_offset)] {
+            file.extend(u32::try_from(entries).unwrap().to_le_bytes());
+            file.extend(u32::try_from(offset).unwrap().to_le_bytes());
+        }
+        file.extend(names);
+        file.extend(imports);
+        file.extend(object);
+        file.extend(exports);
+
+        assert_eq!(music_data::<VerboseError<&[u8]>>(&file).unwrap().len(), exports_offset - object_offset);
+        let module = super::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        let expected = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        assert_eq!(format!("{:?}", module), format!("{:?}", expected));
+
+        assert!(music_data::<VerboseError<&[u8]>>(DATA).is_err());
+        file[exports_offset] = 0; // class is not an import anymore
+        assert!(music_data::<VerboseError<&[u8]>>(&file).is_err());
+    }
+}
@@ -0,0 +1,188 @@
+//! Import of samples from WAV files (.wav)
+
+use super::empty_sample;
+use crate::data::*;
+use std::convert::{TryFrom, TryInto};
+use std::io::{self, Read};
+
+
+/// Format tags of the `fmt ` chunk
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+
+/// Contents of the `fmt ` chunk needed to decode the data
+struct Format {
+    tag: u16,
+    channels: u16,
+    sample_rate: u32,
+    bits: u16,
+}
+
+
+impl Sample {
+    /// Reads the sample from a WAV file (.wav)
+    ///
+    /// Integer PCM with 8, 16, 24 or 32 bits and floating point PCM with 32 or 64 bits are
+    /// supported, also in the extensible format. Mono and stereo files keep their channels, files
+    /// with more channels are mixed down to mono. The C-5 speed is set to the sample rate of the
+    /// file so that C-5 plays the sample unchanged, the first loop of the `smpl` chunk is taken as
+    /// the sample loop if there is one. The other fields are set the same way as by the importers in
+    /// [`formats`](crate::formats): full volume, no panning and no auto-vibrato.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidData`] if the file is not a WAV file or the format is
+    /// not supported, errors of the reader are passed through.
+    pub fn from_wav(mut reader: impl Read) -> io::Result<Sample> {
+        let mut file = Vec::new();
+        reader.read_to_end(&mut file)?;
+        sample(&file)
+    }
+}
+
+
+fn sample(file: &[u8]) -> io::Result<Sample> {
+    let mut chunks = match file {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', rest @ ..] => rest,
+        _ => return Err(invalid("not a WAV file")),
+    };
+
+    let (mut format, mut data, mut sample_loop) = (None, None, None);
+    while let [a, b, c, d, s0, s1, s2, s3, rest @ ..] = chunks {
+        let size = usize::try_from(u32::from_le_bytes([*s0, *s1, *s2, *s3])).unwrap_or(usize::MAX);
+        // Files are often cut short in the middle of the data chunk, the rest is still used.
+        let chunk = &rest[..size.min(rest.len())];
+        match &[*a, *b, *c, *d] {
+            b"fmt " => format = Some(self::format(chunk)?),
+            b"data" => data = Some(chunk),
+            b"smpl" => sample_loop = self::sample_loop(chunk),
+            _ => {}
+        }
+        // Chunks are padded to an even size.
+        chunks = rest.get(size.saturating_add(size % 2)..).unwrap_or(&[]);
+    }
+    let format = format.ok_or_else(|| invalid("WAV file has no format chunk"))?;
+    let data = data.ok_or_else(|| invalid("WAV file has no data chunk"))?;
+
+    let decode = decoder(&format)?;
+    let value_size = usize::from(format.bits / 8);
+    let frames = data.chunks_exact(usize::from(format.channels) * value_size);
+    let pcm = if format.channels <= 2 {
+        frames.flat_map(|frame| frame.chunks_exact(value_size).map(decode)).collect::<Vec<f32>>()
+    } else {
+        let channels = f32::from(format.channels);
+        frames.map(|frame| frame.chunks_exact(value_size).map(decode).sum::<f32>() / channels).collect()
+    };
+
+    let mut sample = empty_sample();
+    sample.samplerate_c5 = format.sample_rate;
+    if format.channels == 2 {
+        sample.channels = RangedU8::MAX;
+    }
+    let length = u32::try_from(pcm.len() / usize::from(sample.channels.as_u8())).map_err(|_| invalid("WAV file is too long"))?;
+    sample.loop_ = sample_loop.filter(|l: &SampleLoop| l.start < l.end && l.end <= length);
+    sample.data = Some(pcm.into());
+    Ok(sample)
+}
+
+fn format(chunk: &[u8]) -> io::Result<Format> {
+    if chunk.len() < 16 {
+        return Err(invalid("WAV format chunk is too short"));
+    }
+    let u16 = |offset: usize| u16::from_le_bytes([chunk[offset], chunk[offset + 1]]);
+    let mut tag = u16(0);
+    if tag == FORMAT_EXTENSIBLE {
+        // The actual format is in the first two bytes of the sub-format GUID.
+        tag = match chunk.get(24..26) {
+            Some(&[low, high]) => u16::from_le_bytes([low, high]),
+            _ => return Err(invalid("WAV format chunk is too short")),
+        };
+    }
+    let format = Format {
+        tag,
+        channels: u16(2),
+        sample_rate: u32::from_le_bytes(chunk[4..8].try_into().unwrap()),
+        bits: u16(14),
+    };
+    if format.channels == 0 {
+        return Err(invalid("WAV file has no channels"));
+    }
+    Ok(format)
+}
+
+/// Returns the function converting a sample value of the format to the normalized value.
+fn decoder(format: &Format) -> io::Result<fn(&[u8]) -> f32> {
+    // The wider values are converted through `f64` to keep their precision, the result always fits.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    let decode: fn(&[u8]) -> f32 = match (format.tag, format.bits) {
+        // 8-bit data is unsigned.
+        (FORMAT_PCM, 8) => |bytes| convert::i8_to_f32(convert::u8_to_i8(bytes[0])),
+        (FORMAT_PCM, 16) => |bytes| convert::i16_to_f32(i16::from_le_bytes([bytes[0], bytes[1]])),
+        (FORMAT_PCM, 24) => |bytes| {
+            let value = i32::from_le_bytes([0, bytes[0], bytes[1], bytes[2]]) >> 8;
+            (f64::from(value) / f64::from(0x7F_FFFF)) as f32
+        },
+        (FORMAT_PCM, 32) => |bytes| (f64::from(i32::from_le_bytes(bytes.try_into().unwrap())) / f64::from(i32::MAX)) as f32,
+        (FORMAT_FLOAT, 32) => |bytes| f32::from_le_bytes(bytes.try_into().unwrap()),
+        (FORMAT_FLOAT, 64) => |bytes| f64::from_le_bytes(bytes.try_into().unwrap()) as f32,
+        _ => return Err(invalid("WAV sample format is not supported")),
+    };
+    Ok(decode)
+}
+
+/// Reads the first loop of the `smpl` chunk, the end stored in the chunk is inclusive.
+fn sample_loop(chunk: &[u8]) -> Option<SampleLoop> {
+    let u32 = |offset: usize| chunk.get(offset..offset + 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()));
+    if u32(28)? == 0 {
+        return None;
+    }
+    // Loop type 0 is forward and 1 ping-pong, the other types are played forward.
+    Some(SampleLoop {
+        start: u32(36 + 8)?,
+        end: u32(36 + 12)?.checked_add(1)?,
+        bidi: u32(36 + 4)? == 1,
+    })
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn from_wav() {
+        let mut file = b"RIFF\0\0\0\0WAVE".to_vec();
+        file.extend_from_slice(b"fmt \x10\0\0\0");
+        file.extend_from_slice(&[1, 0, 2, 0]); // PCM, stereo
+        file.extend_from_slice(&22_050u32.to_le_bytes());
+        file.extend_from_slice(&[0; 4]); // byte rate
+        file.extend_from_slice(&[4, 0, 16, 0]);
+        file.extend_from_slice(b"LIST\x03\0\0\0abc\0"); // padded to an even size
+        file.extend_from_slice(b"data\x10\0\0\0");
+        for frame in [[0, 0], [i16::MAX, i16::MAX], [i16::MAX, -i16::MAX], [-i16::MAX, -i16::MAX]] {
+            file.extend(frame.iter().flat_map(|value| value.to_le_bytes()));
+        }
+        let mut smpl = vec![0; 60];
+        smpl[28] = 1; // one loop
+        smpl[40] = 1; // ping-pong
+        smpl[44] = 1; // from frame 1
+        smpl[48] = 2; // to frame 2 inclusive
+        file.extend_from_slice(b"smpl\x3C\0\0\0");
+        file.extend_from_slice(&smpl);
+
+        let sample = Sample::from_wav(file.as_slice()).unwrap();
+        assert!(sample.is_stereo());
+        assert_eq!(sample.length(), 4);
+        assert_eq!(sample.data.as_deref(), Some(&[0.0, 0.0, 1.0, 1.0, 1.0, -1.0, -1.0, -1.0][..]));
+        assert_eq!(sample.samplerate_c5, 22_050);
+        assert!(matches!(sample.loop_, Some(SampleLoop { start: 1, end: 3, bidi: true })));
+
+        assert!(Sample::from_wav(&b"RIFF\0\0\0\0AVI "[..]).is_err());
+    }
+}
@@ -0,0 +1,743 @@
+//! FastTracker 2 extended module files (.xm)
+//!
+//! XM files are converted into modules in instrument mode. Every XM instrument becomes an IT
+//! instrument mapping its notes to its samples, the samples of all instruments are stored one
+//! after another in the module. The frequency table of the file selects linear or Amiga slides,
+//! old effects are enabled because FT2 vibrato matches them more closely. Notes are moved up by
+//! one octave, XM plays C-4 at the base frequency of the sample where IT plays C-5, the C-5
+//! speed of the samples is computed from their relative note and finetune.
+//!
+//! Effects are converted to their IT counterparts, `Cxx` (set volume) is moved to the volume
+//! column and `K00` (key off) to the note column if they are free.
+//!
+//! # Lossy conversion
+//!
+//! - The restart position of the song is not kept, IT songs always restart from the beginning.
+//! - Effects without an IT equivalent are dropped: `Kxx` with a non-zero tick, `Lxx` (set envelope
+//!   position), `EFx` (set active macro), `F00` (stop) and `Cxx` or `K00` if the target column
+//!   is already used.
+//! - IT volume column slides and vibrato only go up to 9, larger XM values are clipped, volume
+//!   column vibrato speed and panning slides are dropped. Volume column tone portamento uses the
+//!   nearest IT speed.
+//! - FT2 prefers sliding up when both nibbles of a volume slide are set, IT ignores such slides,
+//!   the down nibble is dropped. Coarse portamento parameters from `0xE0` are clipped to `0xDF`,
+//!   IT reads them as fine slides.
+//! - Auto-vibrato settings are copied from the instrument to each of its samples unchanged, the
+//!   ramp up waveform is replaced by ramp down.
+//! - Envelopes keep only the first point of the sustain loop, XM only has a sustain point.
+//! - ADPCM compressed samples are imported as empty.
+//! - Only the first 99 instruments and samples, 200 patterns and 127 channels are imported.
+
+use super::{empty_instrument, empty_sample, name};
+use crate::data::*;
+use crate::error::ContextError;
+use crate::parser::interleave;
+use crate::parser::util::{byte_array, Cast};
+use alloc::string::String;
+use alloc::vec::Vec;
+use nom::bytes::complete::{tag, take};
+use nom::error::ParseError;
+use nom::multi::count;
+use nom::number::complete::{le_i8, le_u16, le_u32, le_u8};
+use nom::sequence::tuple;
+use nom::{Err, IResult};
+use core::convert::TryFrom;
+
+
+/// Number of instruments and samples the IT data model can hold
+const MAX_INSTRUMENTS: usize = 99;
+const MAX_SAMPLES: usize = 99;
+
+/// Number of patterns the IT data model can hold
+const MAX_PATTERNS: usize = 200;
+
+/// Number of notes of the XM keyboard, C-0 to B-7
+const NOTES: usize = 96;
+
+/// Size of a sample header, used when the instrument header stores 0
+const SAMPLE_HEADER_SIZE: usize = 40;
+
+/// Tone portamento speeds of the values 1..=9 of the IT volume column
+const TONE_PORTAMENTO_SPEEDS: [u16; 9] = [1, 4, 8, 16, 32, 64, 96, 128, 255];
+
+
+struct Header<'i> {
+    name: [u8; 20],
+    channels: u16,
+    linear_slides: bool,
+    speed: u16,
+    tempo: u16,
+    orders: Vec<u8>,
+    patterns: u16,
+    instruments: u16,
+    // Patterns and instruments following the header.
+    data: &'i [u8],
+}
+
+/// XM sample header, converted to a sample once the data is read
+struct SampleHeader {
+    length: u32,
+    loop_start: u32,
+    loop_length: u32,
+    volume: u8,
+    finetune: i8,
+    flags: u8,
+    panning: u8,
+    relative_note: i8,
+    compression: u8,
+    name: [u8; 22],
+}
+
+/// Instrument settings copied to the samples
+#[derive(Clone, Copy, Default)]
+struct Vibrato {
+    kind: u8,
+    sweep: u8,
+    depth: u8,
+    rate: u8,
+}
+
+
+/// Parse FastTracker 2 extended module file (.xm) into a module
+///
+/// Only files of version 1.04, which is the version FastTracker 2 and all later trackers save,
+/// are supported. See the [module documentation](self) for how the file is converted.
+pub fn module_file<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (_, header) = context!(header, "reading XM header")(input)?;
+    let channels = usize::from(header.channels);
+    if channels > MAX_CHANNELS {
+        info!(channels, "only 127 channels are supported, skipping the rest");
+    }
+
+    let mut data = header.data;
+    let mut patterns = Vec::with_capacity(usize::from(header.patterns));
+    for index in 0..header.patterns {
+        let (rest, pattern) = context!(|input| pattern(input, channels), "in pattern {}", index)(data)?;
+        data = rest;
+        patterns.push(pattern);
+    }
+    if patterns.len() > MAX_PATTERNS {
+        info!(count = patterns.len(), "only 200 patterns are supported, skipping the rest");
+        patterns.truncate(MAX_PATTERNS);
+    }
+
+    let mut instruments = Vec::with_capacity(usize::from(header.instruments));
+    let mut samples = Vec::new();
+    for index in 0..header.instruments {
+        let (rest, (instrument, instrument_samples)) = context!(
+            |input| instrument(input, samples.len()),
+            "in instrument {}",
+            index,
+        )(data)?;
+        data = rest;
+        if instruments.len() < MAX_INSTRUMENTS {
+            instruments.push(instrument);
+            samples.extend(instrument_samples);
+        }
+    }
+    if usize::from(header.instruments) > MAX_INSTRUMENTS {
+        info!(count = header.instruments, "only 99 instruments are supported, skipping the rest");
+    }
+    if samples.len() > MAX_SAMPLES {
+        info!(count = samples.len(), "only 99 samples are supported, skipping the rest");
+        samples.truncate(MAX_SAMPLES);
+    }
+
+    let orders = header.orders
+        .iter()
+        .filter_map(|&order| {
+            if usize::from(order) < patterns.len() {
+                Some(Order::Index(order.cast()))
+            } else {
+                info!(order, "order references a missing pattern, skipping");
+                None
+            }
+        })
+        .collect();
+
+    let mut flags = ModuleFlags::STEREO | ModuleFlags::USE_INSTRUMENTS | ModuleFlags::OLD_EFFECTS;
+    if header.linear_slides {
+        flags |= ModuleFlags::LINEAR_SLIDES;
+    }
+    let mut init_channel_panning = [ChannelPan::DISABLED; MAX_CHANNELS];
+    init_channel_panning[..channels.min(MAX_CHANNELS)].fill(ChannelPan::CENTRE);
+
+    let speed = match header.speed {
+        1 ..= 255 => header.speed,
+        _ => {
+            info!(speed = header.speed, "speed is out of range 1..=255, using default of 6");
+            6
+        }
+    };
+    let tempo = match header.tempo {
+        32 ..= 255 => header.tempo,
+        _ => {
+            info!(tempo = header.tempo, "tempo is out of range 32..=255, using default of 125");
+            125
+        }
+    };
+
+    Ok(Module {
+        name: name(&header.name),
+        message: String::new(),
+        highlight: (16, 4),
+        made_with_version: 0x0214,
+        compatible_with_version: 0x0214,
+        flags,
+        stored_flags: 0,
+        global_volume: 128.cast(),
+        sample_volume: 48.cast(),
+        speed: u8::try_from(speed).unwrap().cast(),
+        tempo: u8::try_from(tempo).unwrap().cast(),
+        pan_separation: 128.cast(),
+        pitch_wheel_depth: 0,
+        init_channel_panning,
+        init_channel_volume: [ChannelVolume::FULL; MAX_CHANNELS],
+        orders,
+        instruments,
+        samples,
+        patterns,
+        midi_config: None,
+        edit_history: Vec::new(),
+        openmpt_channel_count: (channels > 64).then(|| channels.min(MAX_CHANNELS).cast()),
+        pattern_names: Vec::new(),
+        channel_names: Vec::new(),
+        openmpt_extensions: None,
+        opaque: OpaqueData::default(),
+    })
+}
+
+fn header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Header<'i>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, _) = tag(b"Extended Module: ")(input)?;
+    let (input, name) = byte_array(input)?;
+    let (input, _eof) = le_u8(input)?;
+    let (input, _tracker) = take(20usize)(input)?;
+    let (input, version) = le_u16(input)?;
+    if version != 0x0104 {
+        bail!(input, "XM version {:#06x} is not supported", version);
+    }
+
+    // The header size is counted from its own position.
+    let (_, header) = le_u32(input)?;
+    let (data, input) = context!(take(header), "reading header data")(input)?;
+    let (input, _header_size) = le_u32(input)?;
+    let (input, song_length) = le_u16(input)?;
+    let (input, _restart) = le_u16(input)?;
+    let (input, channels) = le_u16(input)?;
+    let (input, patterns) = le_u16(input)?;
+    let (input, instruments) = le_u16(input)?;
+    let (input, flags) = le_u16(input)?;
+    let (input, speed) = le_u16(input)?;
+    let (input, tempo) = le_u16(input)?;
+    let (_, order_table) = byte_array::<_, 256>(input)?;
+    let orders = order_table[..usize::from(song_length).min(256)].to_vec();
+
+    Ok((
+        data,
+        Header {
+            name,
+            channels,
+            linear_slides: flags & 0x01 != 0,
+            speed,
+            tempo,
+            orders,
+            patterns,
+            instruments,
+            data,
+        },
+    ))
+}
+
+fn pattern<'i, E>(input: &'i [u8], channels: usize) -> IResult<&'i [u8], Pattern, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (_, header_length) = le_u32(input)?;
+    let (rest, header) = take(header_length)(input)?;
+    let (header, _) = take(4usize)(header)?;
+    let (header, _packing) = le_u8(header)?;
+    let (header, rows) = le_u16(header)?;
+    let (_, packed_size) = le_u16(header)?;
+    let (rest, mut input) = take(packed_size)(rest)?;
+
+    // Patterns without data are empty.
+    let mut active_channels = ActiveChannels::empty();
+    let mut pattern_rows = vec![Row::empty(); usize::from(rows)];
+    if packed_size == 0 {
+        return Ok((rest, Pattern { active_channels, rows: pattern_rows, truncated: false, highlight: None }));
+    }
+
+    for row in &mut pattern_rows {
+        for channel in 0..channels {
+            let (tail, cell) = cell(input)?;
+            input = tail;
+            let command = command(cell);
+            if channel < MAX_CHANNELS && !command.is_empty() {
+                let channel = Channel::from_u8_index(channel.cast());
+                active_channels |= ActiveChannels::new([channel]);
+                row.insert(channel, command);
+            }
+        }
+    }
+
+    Ok((
+        rest,
+        Pattern {
+            active_channels,
+            rows: pattern_rows,
+            truncated: false,
+            highlight: None,
+        },
+    ))
+}
+
+/// Reads the note, instrument, volume, effect and parameter bytes of a packed cell.
+///
+/// If the high bit of the first byte is set, its low bits select which of the bytes follow,
+/// otherwise the first byte is the note and all the others follow.
+fn cell<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], [u8; 5], E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (mut input, first) = le_u8(input)?;
+    let mut cell = [0; 5];
+    if first & 0x80 == 0 {
+        cell[0] = first;
+        let (rest, bytes) = byte_array::<_, 4>(input)?;
+        cell[1..].copy_from_slice(&bytes);
+        return Ok((rest, cell));
+    }
+    for (index, byte) in cell.iter_mut().enumerate() {
+        if first & (1 << index) != 0 {
+            let (rest, value) = le_u8(input)?;
+            *byte = value;
+            input = rest;
+        }
+    }
+    Ok((input, cell))
+}
+
+/// Converts the cell bytes, also used for MOD files which share the effects with XM.
+pub(super) fn command([note, instrument, volume, effect, param]: [u8; 5]) -> Command {
+    let mut command = Command {
+        note: match note {
+            0 => None,
+            1 ..= 96 => Note::try_from(note - 1 + 12).ok().map(NoteCmd::Play),
+            97 => Some(NoteCmd::Off),
+            _ => {
+                info!(note, "note is out of range 1..=97, skipping");
+                None
+            }
+        },
+        instrument: match instrument {
+            1 ..= 99 => Some((instrument - 1).cast()),
+            _ => None,
+        },
+        volume: self::volume(volume),
+        effect: None,
+    };
+
+    match effect {
+        0x0C if command.volume.is_none() => {
+            command.volume = Some(VolumeCmd::SetVolume(param.min(64).cast()));
+        }
+        0x14 if param == 0 && command.note.is_none() => command.note = Some(NoteCmd::Off),
+        0x0C => {
+            info!("volume column is already used, skipping command `Cxx`");
+        }
+        0x14 if param == 0 => {
+            info!("note column is already used, skipping command `K00`");
+        }
+        _ => command.effect = self::effect(effect, param),
+    }
+    command
+}
+
+/// Converts the XM volume column, see the [module documentation](self).
+fn volume(volume: u8) -> Option<VolumeCmd> {
+    let (x, y) = (volume >> 4, volume & 0x0F);
+    // IT only has parameters 1..=9, zero uses the effect memory.
+    let limited = |param: u8| (param > 0).then(|| param.min(9).cast());
+    Some(match x {
+        0x0 => return None,
+        0x1 ..= 0x4 => VolumeCmd::SetVolume((volume - 0x10).cast()),
+        0x5 if y == 0 => VolumeCmd::SetVolume(64.cast()),
+        0x6 => VolumeCmd::VolumeSlideDown(limited(y)),
+        0x7 => VolumeCmd::VolumeSlideUp(limited(y)),
+        0x8 => VolumeCmd::FineVolumeDown(limited(y)),
+        0x9 => VolumeCmd::FineVolumeUp(limited(y)),
+        0xB => VolumeCmd::Vibrato(limited(y)),
+        0xC => VolumeCmd::Panning((y * 4).cast()),
+        0xF => VolumeCmd::TonePortamento((y > 0).then(|| {
+            let speed = u16::from(y) * 16;
+            let nearest = TONE_PORTAMENTO_SPEEDS.iter()
+                .enumerate()
+                .min_by_key(|&(_, it)| it.abs_diff(speed))
+                .map_or(0, |(index, _)| index);
+            (nearest + 1).cast::<u8>().cast()
+        })),
+        _ => {
+            info!(volume, "volume column command has no IT equivalent, skipping");
+            return None;
+        }
+    })
+}
+
+/// Converts XM effect to the IT effect, see the [module documentation](self).
+fn effect(effect: u8, param: u8) -> Option<EffectCmd> {
+    let (x, y) = (param >> 4, param & 0x0F);
+    let slide = if x > 0 { x << 4 } else { y };
+    let coarse = param.min(0xDF);
+    let (letter, param) = match effect {
+        0x00 if param == 0 => return None,
+        0x00 => (b'J', param),
+        0x01 => (b'F', coarse),
+        0x02 => (b'E', coarse),
+        0x03 => (b'G', param),
+        0x04 => (b'H', param),
+        0x05 => (b'L', slide),
+        0x06 => (b'K', slide),
+        0x07 => (b'R', param),
+        0x08 => (b'X', param),
+        0x09 => (b'O', param),
+        0x0A => (b'D', slide),
+        0x0B => (b'B', param),
+        0x0D => (b'C', x * 10 + y),
+        0x0E => match x {
+            0x1 => (b'F', 0xF0 | y),
+            0x2 => (b'E', 0xF0 | y),
+            0x3 => (b'S', 0x10 | y),
+            0x4 => (b'S', 0x30 | y),
+            0x5 => (b'S', 0x20 | y),
+            0x6 => (b'S', 0xB0 | y),
+            0x7 => (b'S', 0x40 | y),
+            0x8 => (b'S', 0x80 | y),
+            0x9 => (b'Q', y),
+            0xA => (b'D', y << 4 | 0xF),
+            0xB => (b'D', 0xF0 | y),
+            0xC => (b'S', 0xC0 | y),
+            0xD => (b'S', 0xD0 | y),
+            0xE => (b'S', 0xE0 | y),
+            _ => {
+                info!(x, "command `Exy` has no IT equivalent, skipping");
+                return None;
+            }
+        },
+        0x0F if param == 0 => {
+            info!("command `F00` (stop) has no IT equivalent, skipping");
+            return None;
+        }
+        0x0F if param < 0x20 => (b'A', param),
+        0x0F => (b'T', param),
+        0x10 => (b'V', param.min(0x40) * 2),
+        0x11 => (b'W', slide),
+        // XM slides right with the high nibble, IT with the low one.
+        0x19 => (b'P', if x > 0 { x } else { y << 4 }),
+        0x1B => (b'Q', param),
+        0x1D => (b'I', param),
+        0x21 if x == 1 => (b'F', 0xE0 | y),
+        0x21 if x == 2 => (b'E', 0xE0 | y),
+        _ => {
+            info!(code = effect, param, "effect has no IT equivalent, skipping");
+            return None;
+        }
+    };
+    crate::parser::effect(letter - b'A' + 1, param)
+}
+
+/// Parses the instrument with its samples, `first_sample` is the ID of its first sample.
+fn instrument<'i, E>(input: &'i [u8], first_sample: usize) -> IResult<&'i [u8], (Instrument, Vec<Sample>), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (_, size) = le_u32(input)?;
+    let (mut rest, header) = take(size)(input)?;
+    let (header, _) = take(4usize)(header)?;
+    let (header, instrument_name) = byte_array::<_, 22>(header)?;
+    let (header, _kind) = le_u8(header)?;
+    let (header, sample_count) = le_u16(header)?;
+
+    let mut instrument = Instrument {
+        name: name(&instrument_name),
+        ..empty_instrument()
+    };
+    if sample_count == 0 {
+        return Ok((rest, (instrument, Vec::new())));
+    }
+
+    let (header, sample_header_size) = le_u32(header)?;
+    let (header, keyboard) = byte_array::<_, NOTES>(header)?;
+    let (header, volume_points) = count(tuple((le_u16, le_u16)), 12)(header)?;
+    let (header, panning_points) = count(tuple((le_u16, le_u16)), 12)(header)?;
+    let (header, (volume_count, panning_count)) = tuple((le_u8, le_u8))(header)?;
+    let (header, volume_loop) = byte_array::<_, 3>(header)?;
+    let (header, panning_loop) = byte_array::<_, 3>(header)?;
+    let (header, (volume_flags, panning_flags)) = tuple((le_u8, le_u8))(header)?;
+    let (header, (vibrato_kind, sweep, depth, rate)) = tuple((le_u8, le_u8, le_u8, le_u8))(header)?;
+    let (_, fadeout) = le_u16(header)?;
+
+    instrument.volume_envelope = envelope(&volume_points, volume_count, volume_loop, volume_flags, |y| y);
+    instrument.panning_envelope = envelope(&panning_points, panning_count, panning_loop, panning_flags, |y| y - 32);
+    instrument.instrument_fadeout = (fadeout.min(0xFFF) / 32).cast();
+    instrument.number_of_samples = sample_count.min(16).cast();
+    let vibrato = Vibrato {
+        // XM has sine, square, ramp down and ramp up, IT has sine, ramp down, square and random.
+        kind: match vibrato_kind {
+            1 => 2,
+            2 | 3 => 1,
+            _ => 0,
+        },
+        sweep,
+        depth,
+        rate,
+    };
+
+    let header_size = match sample_header_size.cast::<usize>() {
+        0 => SAMPLE_HEADER_SIZE,
+        size => size,
+    };
+    let mut headers = Vec::with_capacity(usize::from(sample_count));
+    for _ in 0..sample_count {
+        let (tail, header) = take(header_size)(rest)?;
+        rest = tail;
+        let (_, header) = sample_header(header)?;
+        headers.push(header);
+    }
+
+    let mut samples = Vec::with_capacity(headers.len());
+    for header in headers {
+        let (tail, sample) = sample(rest, header, vibrato)?;
+        rest = tail;
+        samples.push(sample);
+    }
+
+    for (note, &sample) in keyboard.iter().enumerate() {
+        let id = first_sample + usize::from(sample);
+        if usize::from(sample) < samples.len() && id < MAX_SAMPLES {
+            instrument.sample_map.map[note + 12] = Some(id.cast::<u8>().cast());
+        }
+    }
+
+    Ok((rest, (instrument, samples)))
+}
+
+/// Converts the envelope, `loop_points` are the sustain point, loop start and loop end.
+fn envelope(
+    points: &[(u16, u16)],
+    len: u8,
+    [sustain, start, end]: [u8; 3],
+    xm_flags: u8,
+    value: impl Fn(i8) -> i8,
+) -> Envelope {
+    let len = len.min(12);
+    let nodes = points[..usize::from(len)]
+        .iter()
+        .map(|&(tick, y)| Node { tick, value: value(y.min(64).cast()) })
+        .collect();
+    let mut flags = EnvelopeFlags::empty();
+    if xm_flags & 0x01 != 0 {
+        flags |= EnvelopeFlags::ENABLED;
+    }
+    let sustain_loop = (xm_flags & 0x02 != 0 && sustain < len).then_some(EnvelopeLoop { start: sustain, end: sustain });
+    let envelope_loop = (xm_flags & 0x04 != 0 && start <= end && end < len).then_some(EnvelopeLoop { start, end });
+    if sustain_loop.is_some() {
+        flags |= EnvelopeFlags::SUSTAIN;
+    }
+    if envelope_loop.is_some() {
+        flags |= EnvelopeFlags::LOOP;
+    }
+    Envelope { flags, envelope_loop, sustain_loop, nodes }
+}
+
+fn sample_header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], SampleHeader, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (input, length) = le_u32(input)?;
+    let (input, loop_start) = le_u32(input)?;
+    let (input, loop_length) = le_u32(input)?;
+    let (input, volume) = le_u8(input)?;
+    let (input, finetune) = le_i8(input)?;
+    let (input, flags) = le_u8(input)?;
+    let (input, panning) = le_u8(input)?;
+    let (input, relative_note) = le_i8(input)?;
+    let (input, compression) = le_u8(input)?;
+    let (input, name) = byte_array(input)?;
+    Ok((
+        input,
+        SampleHeader {
+            length,
+            loop_start,
+            loop_length,
+            volume,
+            finetune,
+            flags,
+            panning,
+            relative_note,
+            compression,
+            name,
+        },
+    ))
+}
+
+/// Reads the sample data following the sample headers and converts the sample.
+fn sample<'i, E>(input: &'i [u8], header: SampleHeader, vibrato: Vibrato) -> IResult<&'i [u8], Sample, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, bytes) = context!(take(header.length), "reading sample data")(input)?;
+
+    let sixteen_bit = header.flags & 0x10 != 0;
+    let width: usize = if sixteen_bit { 2 } else { 1 };
+    let mut sample = empty_sample();
+    sample.name = name(&header.name);
+    sample.default_volume = header.volume.min(64);
+    sample.default_panning = ((u16::from(header.panning) * 64 + 127) / 255).cast::<u8>() | Sample::dfp_usePanning;
+    sample.samplerate_c5 = C5Speed::from_relative_note(header.relative_note, header.finetune).into();
+    sample.vibrato_type = vibrato.kind;
+    sample.vibrato_speed = vibrato.rate;
+    sample.vibrato_depth = vibrato.depth;
+    sample.vibrato_rate = vibrato.sweep;
+
+    if header.compression == 0xAD {
+        info!("ADPCM compressed samples are not supported, skipping sample data");
+        return Ok((rest, sample));
+    }
+    // Stereo samples (an OpenMPT extension) store the left channel and then the right one, the
+    // length and the loop count the bytes of both channels.
+    let stereo = header.flags & 0x20 != 0;
+    let (channels, bytes) = if stereo {
+        sample.channels = RangedU8::MAX;
+        (2, &bytes[..bytes.len() / (2 * width) * 2 * width])
+    } else {
+        (1, bytes)
+    };
+
+    // Sample data is stored as deltas of signed values, for each channel from zero.
+    let data = bytes.chunks((bytes.len() / channels).max(1)).flat_map(|bytes| deltas(bytes, sixteen_bit)).collect();
+    let data = interleave(data, channels);
+
+    let len = u32::try_from(data.len() / channels).unwrap();
+    let frame_size = u32::try_from(width * channels).unwrap();
+    let start = header.loop_start / frame_size;
+    let end = (header.loop_start.saturating_add(header.loop_length) / frame_size).min(len);
+    if header.flags & 0x03 != 0 && start < end {
+        sample.loop_ = Some(SampleLoop { start, end, bidi: header.flags & 0x03 == 2 });
+    }
+    sample.data = Some(data.into());
+    Ok((rest, sample))
+}
+
+/// Decodes sample data stored as deltas of signed values.
+fn deltas(bytes: &[u8], sixteen_bit: bool) -> Vec<f32> {
+    if sixteen_bit {
+        let mut value = 0i16;
+        bytes.chunks_exact(2)
+            .map(|bytes| {
+                value = value.wrapping_add(i16::from_le_bytes([bytes[0], bytes[1]]));
+                convert::i16_to_f32(value)
+            })
+            .collect()
+    } else {
+        let mut value = 0i8;
+        bytes.iter()
+            .map(|&byte| {
+                value = value.wrapping_add(i8::from_le_bytes([byte]));
+                convert::i8_to_f32(value)
+            })
+            .collect()
+    }
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    /// Builds a file with one order, one pattern and one instrument with one 8-bit sample.
+    fn file() -> Vec<u8> {
+        let mut file = b"Extended Module: song".to_vec();
+        file.resize(37, 0);
+        file.push(0x1A);
+        file.resize(58, 0);
+        file.extend(0x0104u16.to_le_bytes());
+        file.extend(276u32.to_le_bytes());
+        for value in [1u16, 0, 2, 1, 1, 1, 3, 150] {
+            file.extend(value.to_le_bytes());
+        }
+        file.resize(60 + 276, 0);
+
+        // Row 0: C-4 instrument 1 on channel 1 with `C20`, row 1: key off on channel 2.
+        let cells = [0x80 | 0x1B, 49, 1, 0x0C, 0x20, 0x80, 0x80, 0x80 | 0x01, 97];
+        file.extend(9u32.to_le_bytes());
+        file.push(0);
+        file.extend(2u16.to_le_bytes());
+        file.extend(u16::try_from(cells.len()).unwrap().to_le_bytes());
+        file.extend(cells);
+
+        let mut instrument = vec![0; 263];
+        instrument[..4].copy_from_slice(&263u32.to_le_bytes());
+        instrument[4..8].copy_from_slice(b"lead");
+        instrument[27] = 1; // samples
+        instrument[29] = 40; // sample header size
+        // Volume envelope from 64 to 0 over 10 ticks, sustained at the first point.
+        instrument[129..137].copy_from_slice(&[0, 0, 64, 0, 10, 0, 0, 0]);
+        instrument[225] = 2;
+        instrument[233] = 0x01 | 0x02;
+        instrument[239..241].copy_from_slice(&64u16.to_le_bytes());
+        file.extend(instrument);
+
+        let mut sample = vec![0; 40];
+        sample[0] = 4; // length
+        sample[4] = 1; // loop start
+        sample[8] = 3; // loop length
+        sample[12] = 48;
+        sample[14] = 0x02; // ping-pong loop
+        sample[15] = 255;
+        sample[16] = 12; // relative note
+        file.extend(sample);
+        file.extend([0, 127, 2, 127]);
+        file
+    }
+
+    #[test]
+    fn module_file() {
+        let file = file();
+        let module = super::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(&module.name.bytes[..5], b"song\0");
+        assert_eq!(module.orders, [Order::Index(PatternId::try_from(0).unwrap())]);
+        assert_eq!((module.speed.as_u8(), module.tempo.as_u8()), (3, 150));
+        assert!(module.flags.contains(ModuleFlags::USE_INSTRUMENTS | ModuleFlags::LINEAR_SLIDES));
+        assert_eq!(module.init_channel_panning.map(u8::from)[..3], [32, 32, 32 | 128]);
+
+        let instrument = &module.instruments[0];
+        assert_eq!(instrument.instrument_fadeout, 2);
+        assert_eq!(instrument.sample_map.map[60], Some(SampleId::try_from(0).unwrap()));
+        let envelope = &instrument.volume_envelope;
+        assert!(envelope.flags.contains(EnvelopeFlags::ENABLED | EnvelopeFlags::SUSTAIN));
+        assert_eq!(envelope.nodes.iter().map(|node| (node.tick, node.value)).collect::<Vec<_>>(), [(0, 64), (10, 0)]);
+
+        let sample = &module.samples[0];
+        assert_eq!(sample.data.as_deref(), Some(&[0.0, 1.0, -1.0, 0.0][..]));
+        assert_eq!(sample.samplerate_c5, 16726);
+        assert_eq!(sample.default_pan().map(RangedU8::as_u8), Some(64));
+        assert!(matches!(sample.loop_, Some(SampleLoop { start: 1, end: 4, bidi: true })));
+
+        let pattern = &module.patterns[0];
+        assert_eq!(pattern.rows.len(), 2);
+        let command = pattern.command(0, Channel::new(1)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 60));
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(0).unwrap()));
+        assert!(matches!(command.volume, Some(VolumeCmd::SetVolume(volume)) if volume.as_u8() == 32));
+        assert!(command.effect.is_none());
+        assert!(matches!(pattern.command(1, Channel::new(2)).unwrap().note, Some(NoteCmd::Off)));
+    }
+}
@@ -1,7 +1,9 @@
+#![cfg_attr(not(feature = "std"), no_std)]
 #![feature(const_mut_refs)]
 #![feature(const_panic)]
 #![feature(const_str_from_utf8_unchecked)]
 #![feature(stmt_expr_attributes)]
+#![cfg_attr(feature = "simd", feature(portable_simd))]
 
 // We use all these clippy lints to help avoid silent data loss in the parser/serializer,
 // none of these warnings should make it into a release.
@@ -21,8 +23,7 @@
 //! >
 //! > -- Wikipedia ([article link](https://en.wikipedia.org/wiki/Impulse_Tracker))
 //!
-//! This crate is a parser (and in the near future also hopefuly a writer) for the Impulse Tracker
-//! native module file format. The Rust representation attempts to both express the module file in
+//! This crate is a parser and writer for the Impulse Tracker native module file format. The Rust representation attempts to both express the module file in
 //! a manner that's lossless for valid files and that forbids creating invalid files.
 //!
 //!
@@ -35,17 +36,76 @@
 //! Currently the parser will panic in cases that are not yet supported (implemented), but this
 //! should not be the case once enough of the format is implemented.
 //!
+//! Files which are broken beyond that can still be read with [`parser::parse_lenient`], it
+//! replaces the parts which fail to parse and reports every problem as an [`error::Warning`].
+//!
 //! If the feature `log` is enabled, the crate with log an info message whenever some data from the
 //! input is lost and should explain what value was found, what is wrong with it and how it has
 //! been fixed. The canonicalization logic is documented under the "Canonicalization" section on
 //! each specific value type. Please report issues with any inconsistencies between the parsed
 //! results of and the documentation.
 //!
+//! If the feature `serde` is enabled, the data types implement `Serialize` and `Deserialize`.
+//! Flags are represented as lists of the flag names and sample data as bytes of little-endian
+//! `f32` values. The original encoding of sample data ([`Sample::encoded`]) is not serialized.
+//!
+//! The feature `std` is enabled by default. Without it the crate is `no_std` and only needs
+//! `alloc`, the writer, reading from [`std::io`] readers and the `Error` implementations of the
+//! error types are not available. The floating point functions missing from `core` are then taken
+//! from `libm`, the feature `libm` has to be enabled instead.
+//!
+//! If the feature `arbitrary` is enabled, [`Module`], [`Pattern`], [`Sample`], [`Instrument`]
+//! and the types they are made of implement [`arbitrary::Arbitrary`](https://docs.rs/arbitrary)
+//! for fuzzing. The generated values are valid and survive writing and parsing unchanged.
+//!
+//! If the feature `proptest` is enabled, the [`testing`] module contains
+//! [`proptest`](https://docs.rs/proptest) strategies for modules, patterns, instruments and
+//! samples, meant for testing code which transforms modules.
+//!
+//! If the feature `player` is enabled, the [`player`] module contains a playback engine rendering
+//! modules to PCM samples. The feature `cpal` adds real-time playback on the default output device
+//! through [`cpal`](https://docs.rs/cpal).
+//!
+//! Modules in other tracker formats can be imported with the [`formats`] module, they are
+//! converted into the same data model.
+//!
+//! If the feature `memmap2` is enabled, [`Module::open_mmap`] parses module files mapped into
+//! memory by [`memmap2`](https://docs.rs/memmap2) and decodes the sample data only when it's
+//! loaded. The feature implies `std`.
+//!
+//! If the feature `mmcmp` is enabled, modules packed by MMCMP (ziRCONia), common in scene
+//! archives, are unpacked by the parsers transparently, see [`parser::unpack_mmcmp`].
+//!
+//! If the feature `rayon` is enabled, [`parser::module_file`] and the other parsers of modules in
+//! memory decode the patterns and the sample data in parallel on the
+//! [`rayon`](https://docs.rs/rayon) thread pool, the lenient parser and the readers decode them
+//! one by one. The feature implies `std`.
+//!
+//! If the feature `async` is enabled, [`Module::read_async`] reads modules from
+//! [`tokio`](https://docs.rs/tokio) readers the same way as [`Module::read`] reads them from
+//! [`std::io`] readers. The feature implies `std`.
+//!
+//! If the feature `cli` is enabled, the binary `ittech` is built. It prints a summary, the order
+//! list, the patterns or the samples of a module, extracts the samples as WAV files and validates
+//! modules, run `ittech help` for the commands.
+//!
+//! If the feature `ffi` is enabled, the [`ffi`] module exports a C interface to parse, inspect
+//! and write modules, declared in `include/ittech.h`. The feature implies `std`.
+//!
+//! If the feature `simd` is enabled, the integration of delta encoded and compressed sample data
+//! and its conversion to the normalized values are vectorized with `core::simd`. The results are
+//! the same as without it.
+//!
+//! If the feature `wasm` is enabled, the [`wasm`] module exposes the parser to JavaScript through
+//! `wasm-bindgen`, with the patterns and the sample data as typed arrays. The crate builds for
+//! `wasm32-unknown-unknown` with or without it. The feature implies `std`.
+//!
 //!
 //! ## Structure and modfile representation
 //!
 //! The general structure of a complete modfile (.it) can be simplified to this self-referencing tree.
-//! Complete modfiles are parsed using the [`parser::module_file`] function.
+//! Complete modfiles are parsed using the [`parser::module_file`] function and written using the
+//! [`writer::module_file`] function.
 //!
 //! ```txt
 //! Module
@@ -71,7 +131,8 @@
 //!
 //! Instruments can also exist on their own in instrument files (.iti). [`InstrumentFile`] only
 //! contains a single Instrument and its referenced samples. These files are parsed using the
-//! [`parser::instrument_file`] function.
+//! [`parser::instrument_file`] function, read with [`Instrument::read_iti`] and written with
+//! [`Instrument::write_iti`].
 //!
 //! ```txt
 //! InstrumentFile
@@ -81,7 +142,8 @@
 //! ```
 //!
 //! Samples can also exist in their own files (.its) as just a lone [`Sample`]. These files are
-//! parsed using the [`parser::sample_file`] function.
+//! parsed using the [`parser::sample_file`] function, read with [`Sample::read_its`] and written
+//! with [`Sample::write_its`].
 //!
 //!
 //! ## Additional resources
@@ -99,6 +161,12 @@
 #[doc = include_str!("../ITTECH.txt")]
 pub mod ittech_txt {}
 
+#[cfg_attr(not(feature = "std"), macro_use)]
+extern crate alloc;
+
+#[cfg(not(any(feature = "std", feature = "libm")))]
+compile_error!("either the `std` or the `libm` feature has to be enabled");
+
 #[macro_use]
 // Macro exporting is still weird. We want the macros to be `pub(crate)`, the combination of
 // `#[macro_use]`, the module containing them being lexically first, never importing the macros
@@ -109,6 +177,16 @@ mod data;
 pub use data::*;
 
 pub mod parser;
+pub mod formats;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "player")]
+pub mod player;
+#[cfg(feature = "proptest")]
+pub mod testing;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "std")]
 pub mod writer;
 
 pub use parser::scan::FileType;
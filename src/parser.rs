@@ -1,18 +1,25 @@
 //! Parsing functions
 
 use crate::data::*;
-use crate::error::ContextError;
+use crate::error::{ContextError, ParseFailure, VerboseError, Warning};
+use crate::formats::{empty_instrument, empty_sample};
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec;
+use alloc::vec::Vec;
 use bitflags::bitflags;
-use nom::bytes::complete::{tag, take};
+use nom::bytes::complete::take;
 use nom::combinator::{all_consuming, map};
 use nom::error::{ErrorKind, ParseError};
-use nom::multi::{count, many_till};
-use nom::number::complete::{be_i16, le_i16, le_i8, le_u16, le_u32, le_u8};
+use nom::multi::count;
+use nom::number::complete::{le_i8, le_u16, le_u32, le_u8};
 use nom::sequence::tuple;
 use nom::{Err, IResult};
+pub(crate) use compression::compressed_sample;
 use pattern::pattern;
-use std::convert::{TryFrom, TryInto};
-use std::ops::RangeInclusive;
+use core::convert::{TryFrom, TryInto};
+use core::ops::RangeInclusive;
 
 
 macro_rules! info {
@@ -23,68 +30,524 @@ macro_rules! info {
 }
 
 
+mod compression;
+mod limits;
+#[cfg(feature = "memmap2")]
+mod mmap;
+#[cfg(feature = "mmcmp")]
+mod mmcmp;
+#[cfg(feature = "rayon")]
+mod parallel;
 mod pattern;
+mod pcm;
+#[cfg(feature = "std")]
+mod read;
 pub(crate) mod scan;
-mod util;
+pub(crate) mod util;
 
+pub use limits::ParseOptions;
+#[cfg(feature = "memmap2")]
+pub use mmap::MappedModule;
+#[cfg(feature = "mmcmp")]
+pub use mmcmp::unpack_mmcmp;
 pub use pattern::parse_effect as effect;
+pub use pattern::PatternEvents;
+#[cfg(feature = "std")]
+pub use read::{read_module_file, read_module_file_with, read_module_headers, read_module_headers_with};
+#[cfg(feature = "async")]
+pub use read::{read_module_file_async, read_module_file_async_with};
+#[cfg(feature = "std")]
+pub(crate) use pattern::{ChannelMask, Mask};
 
+use limits::Limits;
 use util::*;
 pub use scan::scan;
 
 
+/// Special bit announcing the edit history after the offset tables
+pub(crate) const EDIT_HISTORY: u32 = 1 << (1 + 16);
+
+/// Size of the MIDI configuration embedded after the offset tables, 153 macros of 32 bytes
+const MIDI_CONFIG_SIZE: usize = 4896;
+
+/// Lengths of the names in the OpenMPT `PNAM` and `CNAM` chunks
+pub(crate) const PATTERN_NAME_LENGTH: usize = 32;
+pub(crate) const CHANNEL_NAME_LENGTH: usize = 20;
+
+/// Size of the instrument header
+pub(crate) const INSTRUMENT_SIZE: usize = 554;
+
+/// Size of the sample header
+pub(crate) const SAMPLE_HEADER_SIZE: usize = 80;
+
+/// Size of the pattern header preceeding the packed data
+const PATTERN_HEADER_SIZE: usize = 8;
+
+/// Size of the OPL patch stored in place of sample data
+const OPL_PATCH_SIZE: usize = 12;
+
+
+/// Properties stored by OpenMPT, see [`openmpt_extensions`]
+struct OpenMptExtensions {
+    channel_count: Option<u16>,
+    song: Option<SongExtensions>,
+    instruments: Option<Vec<InstrumentExtensions>>,
+    /// Data following the last property read
+    trailing: Vec<u8>,
+}
+
+/// Data stored after the offset tables of the header, see [`header_extras`]
+struct HeaderExtras {
+    edit_history: Vec<EditHistoryEntry>,
+    midi_config: Option<MidiConfig>,
+    pattern_names: Vec<String>,
+    channel_names: Vec<String>,
+    /// Panning and volume of the channels above 64 from the `ChnS` chunk
+    channel_settings: Vec<u8>,
+    /// Data following the known chunks
+    chunks: Vec<u8>,
+}
+
+
 /// Parse Impulse Tracker module file (.it)
+///
+/// With the feature `mmcmp` modules packed by MMCMP are unpacked first, see [`unpack_mmcmp`].
+/// With the feature `rayon` the patterns and the sample data are decoded in parallel, the result
+/// and the errors are the same.
 pub fn module_file<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
 {
-    let (_, header) = module_header(input)?;
+    module(input, true, ParseOptions::default())
+}
+
+/// Parse Impulse Tracker module file (.it) using the options
+///
+/// Same as [`module_file`] which uses the default options, fails if the module exceeds the
+/// limits of the options.
+pub fn module_file_with<'i, E>(input: &'i [u8], options: ParseOptions) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    module(input, true, options)
+}
+
+/// Parse Impulse Tracker module file (.it) without decoding the sample data
+///
+/// Everything except the PCM sample data is parsed the same way as by [`module_file`]. The samples
+/// have [`Sample::data`] set to `None` and [`Sample::deferred`] pointing to the data in the input,
+/// it can be loaded later by calling [`Sample::load_data`] with the same input. Modules packed by
+/// MMCMP are parsed whole, their sample data can't be loaded from the packed input.
+pub fn module_headers<'i, E>(input: &'i [u8]) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    module(input, false, ParseOptions::default())
+}
+
+/// Parse Impulse Tracker module file (.it) without decoding the sample data using the options
+///
+/// Same as [`module_headers`] which uses the default options. The limits on the sample data are
+/// not checked, the data is not decoded. They don't apply to [`Sample::load_data`] either.
+pub fn module_headers_with<'i, E>(input: &'i [u8], options: ParseOptions) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    module(input, false, options)
+}
+
+fn module<'i, E>(input: &'i [u8], load_samples: bool, options: ParseOptions) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    #[cfg(feature = "mmcmp")]
+    if mmcmp::is_mmcmp(input) {
+        return unpacked_module(input, options);
+    }
+
+    let mut limits = Limits::new(options);
+    let (tables_end, header) = context!(
+        |input| {
+            let (rest, header) = module_header(input)?;
+            limits.header(input, &header)?;
+            Ok((rest, header))
+        },
+        "module header",
+    )(input)?;
 
     // Offsets are relative to the start of the file, use the whole input every time.
-    let (_, instruments) = offset_list(instrument, header.instrument_offsets)(input)?;
-    let (_, sample_headers) = offset_list(sample_header, header.sample_offsets)(input)?;
-    let patterns = {
-        let mut patterns = Vec::with_capacity(header.pattern_offsets.len());
-        for offset in header.pattern_offsets.into_iter().map(<_>::cast) {
-            // Pattern parsing is inlined from `offset_list` because we need to handle the special
-            // case of offset 0 here.
-            if offset == 0 {
-                patterns.push(Pattern {
-                    active_channels: ActiveChannels::empty(),
-                    rows: vec![Row::empty(); 64]
-                });
-                continue
-            }
-            if offset >= input.len() {
-                return Err(Err::Error(E::from_error_kind(input, ErrorKind::Eof)));
-            }
-            let (_, pat) = pattern(&input[offset..])?;
-            patterns.push(pat);
+    let (_, instruments) = offset_list(
+        module_instrument(header.compatible_with_version),
+        header.instrument_offsets.clone(),
+        "instrument",
+    )(input)?;
+    let (_, sample_headers) = offset_list(sample_header, header.sample_offsets.clone(), "sample header")(input)?;
+    let patterns = patterns(input, &header.pattern_offsets, &mut limits)?;
+
+    let end = module_end(input, &header, tables_end, &sample_headers);
+    let samples = samples(input, sample_headers, load_samples, &mut limits)?;
+
+    let message = {
+        let offset = header.message_offset.cast::<usize>();
+        if offset == 0 || offset >= input.len() {
+            String::new()
+        } else {
+            let (_, bytes) = take(header.message_length.cast::<usize>())(&input[offset..])?;
+            options.message(bytes)
         }
-        patterns
     };
 
+    let extras = header_extras(header_region(input, &header, tables_end), header.stored_flags);
+    let extensions = openmpt_extensions(&input[end..], instruments.len());
+    Ok(assemble_module(header, message, instruments, samples, patterns, extras, extensions))
+}
+
+/// Parses the patterns at the offsets, in parallel with the `rayon` feature.
+fn patterns<'i, E>(input: &'i [u8], offsets: &[u32], limits: &mut Limits) -> Result<Vec<Pattern>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    #[cfg(feature = "rayon")]
+    if let Some(patterns) = parallel::patterns(input, offsets) {
+        let mut checked = limits.clone();
+        let within_limits = offsets.iter()
+            .zip(&patterns)
+            .filter(|(&offset, _)| offset != 0)
+            .all(|(&offset, pattern)| checked.pattern::<VerboseError<&[u8]>>(&input[offset.cast::<usize>()..], pattern).is_ok());
+        if within_limits {
+            *limits = checked;
+            return Ok(patterns);
+        }
+    }
+
+    let mut patterns = Vec::with_capacity(offsets.len());
+    for (index, offset) in offsets.iter().copied().map(<_>::cast).enumerate() {
+        // Pattern parsing is inlined from `offset_list` because we need to handle the special
+        // case of offset 0 here.
+        if offset == 0 {
+            patterns.push(empty_pattern());
+            continue
+        }
+        if offset >= input.len() {
+            return Err(past_end(input, offset));
+        }
+        let (_, pat) = context!(
+            |input| {
+                let (rest, pat) = pattern(input)?;
+                limits.pattern(input, &pat)?;
+                Ok((rest, pat))
+            },
+            "pattern {}",
+            index,
+        )(&input[offset..])?;
+        patterns.push(pat);
+    }
+    Ok(patterns)
+}
+
+/// Decodes the data of the samples, in parallel with the `rayon` feature.
+///
+/// Data which can be loaded later is deferred unless `load_samples` is on.
+fn samples<'i, E>(
+    input: &'i [u8],
+    headers: Vec<SampleHeader>,
+    load_samples: bool,
+    limits: &mut Limits,
+) -> Result<Vec<Sample>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let decoded = |header: &SampleHeader| load_samples || !is_deferrable(header);
+
+    // The limits are checked before any data is decoded, the decoding is only started if all of
+    // the samples fit.
+    #[cfg(feature = "rayon")]
+    {
+        let mut checked = limits.clone();
+        let within_limits = headers.iter()
+            .filter(|header| decoded(header))
+            .all(|header| checked.sample::<VerboseError<&[u8]>>(sample_input(input, header), header).is_ok());
+        if within_limits {
+            if let Some(samples) = parallel::samples(input, &headers, load_samples) {
+                *limits = checked;
+                return Ok(samples);
+            }
+        }
+    }
+
+    headers.into_iter()
+        .enumerate()
+        .map(|(index, header)| {
+            if decoded(&header) {
+                numbered_sample_data(index, header, input, limits)
+            } else {
+                Ok(deferred_sample(header))
+            }
+        })
+        .collect()
+}
+
+/// Parses the module packed by MMCMP, see [`unpack_mmcmp`]
+///
+/// Errors in the unpacked module can't point into the packed input, they're reported at its start
+/// with the description of the failure located in the unpacked module. The sample data is always
+/// decoded, it can't be loaded from the packed input later.
+#[cfg(feature = "mmcmp")]
+fn unpacked_module<'i, E>(input: &'i [u8], options: ParseOptions) -> Result<Module, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let unpacked = unpack_mmcmp(input)?;
+    module::<VerboseError<&[u8]>>(&unpacked, true, options).map_err(|e| {
+        let failure = ParseFailure::new(&unpacked, e);
+        let expected = Cow::Borrowed("valid module after unpacking MMCMP");
+        Err::Error(E::expected(input, expected, Cow::Owned(failure.to_string())))
+    })
+}
+
+/// Parse Impulse Tracker module file (.it), recovering from problems which leave the rest of the
+/// file readable
+///
+/// Files saved by buggy trackers or cut short are often still mostly usable, the parts which
+/// can't be parsed are replaced and each problem is reported as a [`Warning`]:
+///
+/// - instruments, sample headers and patterns which fail to parse or whose offsets point past the
+///   end of the input are replaced by empty ones,
+/// - unknown effect codes in patterns are skipped,
+/// - truncated PCM sample data is padded with silence unless the missing part is longer than the
+///   whole input could store, sample data which can't be decoded is dropped together with the
+///   loops,
+/// - a truncated message is cut short, a message past the end of the input is left out,
+/// - out of range values in the module header and invalid sample loops are fixed the same way as
+///   by [`module_file`].
+///
+/// Only a module header which can't be parsed is a failure, there is nothing left to recover then.
+///
+/// With the feature `mmcmp` packed modules are unpacked first, the offsets of the warnings and of
+/// failures in the module are relative to the start of the unpacked module then.
+pub fn parse_lenient(input: &[u8]) -> Result<(Module, Vec<Warning>), ParseFailure> {
+    parse_lenient_with(input, ParseOptions::default())
+}
+
+/// Parse Impulse Tracker module file (.it) recovering from problems, using the options
+///
+/// Same as [`parse_lenient`] which uses the default options. Exceeding the limits of the options
+/// is a failure, not a warning.
+pub fn parse_lenient_with(input: &[u8], options: ParseOptions) -> Result<(Module, Vec<Warning>), ParseFailure> {
+    #[cfg(feature = "mmcmp")]
+    if mmcmp::is_mmcmp(input) {
+        let unpacked = unpack_mmcmp(input).map_err(|e| ParseFailure::new(input, e))?;
+        return parse_lenient_with(&unpacked, options);
+    }
+
+    let mut limits = Limits::new(options);
+    let (tables_end, mut header) = context!(module_header, "module header")(input)
+        .map_err(|e| ParseFailure::new(input, e))?;
+    limits.header::<VerboseError<&[u8]>>(input, &header)
+        .map_err(|e| located(input, String::from("module header"), e))?;
+    let mut warnings = fix_warnings(0, "module header", core::mem::take(&mut header.fixes)).collect::<Vec<_>>();
+
+    let parse_instrument = module_instrument(header.compatible_with_version);
+    let instruments = header.instrument_offsets
+        .iter()
+        .enumerate()
+        .map(|(index, &offset)| {
+            lenient_part(input, offset.cast(), format!("instrument {}", index + 1), &parse_instrument)
+                .unwrap_or_else(|failure| {
+                    warnings.push(Warning::from_failure(failure, "using an empty instrument"));
+                    empty_instrument()
+                })
+        })
+        .collect::<Vec<_>>();
+
+    let sample_headers = header.sample_offsets
+        .iter()
+        .enumerate()
+        .map(|(index, &offset)| {
+            let context = format!("sample header {}", index + 1);
+            match lenient_part(input, offset.cast(), context.clone(), sample_header) {
+                Ok(mut header) => {
+                    let fixes = core::mem::take(&mut header.fixes);
+                    warnings.extend(fix_warnings(offset.cast(), &context, fixes));
+                    Some(header)
+                }
+                Err(failure) => {
+                    warnings.push(Warning::from_failure(failure, "using an empty sample"));
+                    None
+                }
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let patterns = header.pattern_offsets
+        .iter()
+        .enumerate()
+        .map(|(index, &offset)| {
+            if offset == 0 {
+                return Ok(empty_pattern());
+            }
+            let context = format!("pattern {}", index);
+            let pattern = match lenient_part(input, offset.cast(), context.clone(), pattern::lenient_pattern) {
+                Ok((pattern, fixes)) => {
+                    warnings.extend(fix_warnings(offset.cast(), &context, fixes));
+                    pattern
+                }
+                Err(failure) => {
+                    warnings.push(Warning::from_failure(failure, "using an empty pattern"));
+                    empty_pattern()
+                }
+            };
+            let pattern_input = input.get(offset.cast::<usize>()..).unwrap_or(&input[input.len()..]);
+            limits.pattern::<VerboseError<&[u8]>>(pattern_input, &pattern)
+                .map_err(|e| located(input, context, e))?;
+            Ok(pattern)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let end = module_end(input, &header, tables_end, sample_headers.iter().flatten());
     let samples = sample_headers.into_iter()
-        .map(|header| sample_data(header, input))
+        .enumerate()
+        .map(|(index, header)| match header {
+            Some(header) => lenient_sample_data(index, header, input, &mut warnings, &mut limits),
+            None => Ok(empty_sample()),
+        })
         .collect::<Result<Vec<_>, _>>()?;
 
     let message = {
         let offset = header.message_offset.cast::<usize>();
-        if offset == 0 || offset >= input.len() {
+        let length = header.message_length.cast::<usize>();
+        let warning = |offset: usize, message: String| Warning {
+            offset: offset.cast(),
+            context: vec![String::from("message")],
+            message,
+        };
+        if offset == 0 {
+            String::new()
+        } else if offset >= input.len() {
+            warnings.push(warning(offset, format!("message offset {:#x} is past the end, left out", offset)));
             String::new()
         } else {
-            let (_, bytes) = take(header.message_length.cast::<usize>())(input)?;
-            String::from_utf8_lossy(bytes)
-                .to_string()
+            let bytes = &input[offset..];
+            if bytes.len() < length {
+                let message = format!("message of {} bytes has only {} left, cut short", length, bytes.len());
+                warnings.push(warning(input.len(), message));
+            }
+            options.message(&bytes[..length.min(bytes.len())])
         }
     };
 
-    Ok(Module {
+    let extras = header_extras(header_region(input, &header, tables_end), header.stored_flags);
+    let extensions = openmpt_extensions(&input[end..], instruments.len());
+    let module = assemble_module(header, message, instruments, samples, patterns, extras, extensions);
+    Ok((module, warnings))
+}
+
+/// Runs the parser on the part of the module at `offset`, for [`parse_lenient`].
+fn lenient_part<'i, O>(
+    input: &'i [u8],
+    offset: usize,
+    context: String,
+    parser: impl FnOnce(&'i [u8]) -> IResult<&'i [u8], O, VerboseError<&'i [u8]>>,
+) -> Result<O, ParseFailure> {
+    let result = if offset >= input.len() {
+        Err(past_end(input, offset))
+    } else {
+        parser(&input[offset..]).map(|(_, output)| output)
+    };
+    result.map_err(|e| located(input, context, e))
+}
+
+/// Locates the error of a part of the module, `context` names the part.
+fn located<'i>(input: &'i [u8], context: String, error: Err<VerboseError<&'i [u8]>>) -> ParseFailure {
+    let mut failure = ParseFailure::new(input, error);
+    failure.context.insert(0, context);
+    failure
+}
+
+/// Decodes the sample data for [`parse_lenient`], see there for how failures are handled.
+///
+/// Fails only if the sample exceeds the limits.
+fn lenient_sample_data(
+    index: usize,
+    header: SampleHeader,
+    input: &[u8],
+    warnings: &mut Vec<Warning>,
+    limits: &mut Limits,
+) -> Result<Sample, ParseFailure> {
+    let context = format!("sample {}", index + 1);
+    limits.sample::<VerboseError<&[u8]>>(sample_input(input, &header), &header)
+        .map_err(|e| located(input, context.clone(), e))?;
+    let failure = match sample_data::<VerboseError<&[u8]>>(header.clone(), input) {
+        Ok(sample) => return Ok(sample),
+        Err(e) => located(input, context, e),
+    };
+
+    // Uncompressed data can be decoded up to the end of the input.
+    let flags = header.flags;
+    let offset = header.data_offset.cast::<usize>();
+    let length = header.data_length.cast::<usize>();
+    // The channels of stereo data are stored one after the other, so they can't be padded. The
+    // padding is at most what the whole input could store, longer lengths are not a truncation.
+    let uncompressed = !flags.intersects(SampleFlags::COMPRESSED | SampleFlags::OPL_INSTRUMENT | SampleFlags::STEREO);
+    let available = pcm_length(flags, input.len().saturating_sub(offset));
+    if uncompressed && offset < input.len() && available < length && length - available <= pcm_length(flags, input.len()) {
+        let truncated = SampleHeader { data_length: available.cast(), ..header.clone() };
+        if let Ok(mut sample) = sample_data::<VerboseError<&[u8]>>(truncated, input) {
+            if let Some(data) = &mut sample.data {
+                data.make_mut().resize(length, 0.0);
+            }
+            let recovery = format!("padded the missing {} of {} samples with silence", length - available, length);
+            warnings.push(Warning::from_failure(failure, &recovery));
+            return Ok(sample);
+        }
+    }
+
+    warnings.push(Warning::from_failure(failure, "dropped the sample data"));
+    Ok(Sample {
+        loop_: None,
+        sustain_loop: None,
+        ..sample_from_header(header, None, None, None, None)
+    })
+}
+
+/// Reports the values fixed while parsing the part of the module at `offset`.
+fn fix_warnings<'a>(offset: usize, context: &'a str, fixes: Vec<(usize, String)>) -> impl Iterator<Item=Warning> + 'a {
+    fixes.into_iter().map(move |(position, message)| Warning {
+        offset: (offset + position).cast(),
+        context: vec![context.to_string()],
+        message,
+    })
+}
+
+/// Puts the parsed parts of a module together
+fn assemble_module(
+    header: ModuleHeader,
+    message: String,
+    mut instruments: Vec<Instrument>,
+    samples: Vec<Sample>,
+    patterns: Vec<Pattern>,
+    extras: HeaderExtras,
+    extensions: OpenMptExtensions,
+) -> Module {
+    for (instrument, properties) in instruments.iter_mut().zip(extensions.instruments.into_iter().flatten()) {
+        instrument.openmpt_extensions = Some(properties);
+    }
+    let mut init_channel_panning = [ChannelPan::CENTRE; MAX_CHANNELS];
+    let mut init_channel_volume = [ChannelVolume::FULL; MAX_CHANNELS];
+    init_channel_panning[..64].copy_from_slice(&header.init_channel_panning.map(ChannelPan::from));
+    init_channel_volume[..64].copy_from_slice(&header.init_channel_volume);
+    let channels = init_channel_panning[64..].iter_mut().zip(&mut init_channel_volume[64..]);
+    for ((pan, volume), settings) in channels.zip(extras.channel_settings.chunks_exact(2)) {
+        *pan = ChannelPan::from(settings[0]);
+        *volume = ChannelVolume::try_from(settings[1].min(64)).unwrap();
+    }
+    Module {
         name: header.name,
         highlight: header.highlight,
         made_with_version: header.made_with_version,
         compatible_with_version: header.compatible_with_version,
         flags: header.flags,
+        stored_flags: header.stored_flags,
         global_volume: header.global_volume,
         sample_volume: header.sample_volume,
         speed: header.speed,
@@ -93,12 +556,344 @@ where
         pitch_wheel_depth: header.pitch_wheel_depth,
         message,
         orders: header.orders,
-        init_channel_panning: header.init_channel_panning,
-        init_channel_volume: header.init_channel_volume,
+        init_channel_panning,
+        init_channel_volume,
         instruments,
         samples,
         patterns,
-    })
+        midi_config: extras.midi_config,
+        edit_history: extras.edit_history,
+        openmpt_channel_count: extensions.channel_count,
+        pattern_names: extras.pattern_names,
+        channel_names: extras.channel_names,
+        openmpt_extensions: extensions.song,
+        opaque: OpaqueData {
+            header_reserved: header.reserved,
+            header_chunks: extras.chunks,
+            trailing: extensions.trailing,
+        },
+    }
+}
+
+/// Pattern stored with offset 0, which stands for an empty pattern of 64 rows
+fn empty_pattern() -> Pattern {
+    Pattern {
+        active_channels: ActiveChannels::empty(),
+        rows: vec![Row::empty(); 64],
+        truncated: false,
+        highlight: None,
+    }
+}
+
+/// Returns the offset of the first part of the module stored after the offset tables, `None` if
+/// there is none.
+///
+/// The MIDI configuration, the name chunks and unknown chunks are stored between the tables and
+/// the first part.
+fn first_part_offset(header: &ModuleHeader, tables_end: usize) -> Option<usize> {
+    header.instrument_offsets
+        .iter()
+        .chain(&header.sample_offsets)
+        .chain(&header.pattern_offsets)
+        .chain(Some(&header.message_offset))
+        .map(|&offset| offset.cast::<usize>())
+        .filter(|&offset| offset >= tables_end)
+        .min()
+}
+
+/// Returns the data between the offset tables and the first part of the module, `tables_end` is
+/// the input after the tables.
+fn header_region<'i>(input: &'i [u8], header: &ModuleHeader, tables_end: &'i [u8]) -> &'i [u8] {
+    let start = input.len() - tables_end.len();
+    let end = first_part_offset(header, start).map_or(input.len(), |end| end.min(input.len()));
+    &input[start..end]
+}
+
+/// Returns the offset where the last part of the module ends, clamped to the input.
+///
+/// Covers the header, the instruments, sample headers and data, patterns and the message with its
+/// null terminator, the data following it is where the OpenMPT extensions and unknown trailing
+/// chunks are. `tables_end` is the input after the offset tables.
+fn module_end<'h>(
+    input: &[u8],
+    header: &ModuleHeader,
+    tables_end: &[u8],
+    sample_headers: impl IntoIterator<Item = &'h SampleHeader>,
+) -> usize {
+    let part = |offset: u32, size: usize| offset.cast::<usize>().saturating_add(size);
+    let header_end = input.len() - tables_end.len();
+    let header_end = first_part_offset(header, header_end).unwrap_or(input.len()).max(header_end);
+    let instruments = header.instrument_offsets.iter().map(|&offset| part(offset, INSTRUMENT_SIZE));
+    let headers = header.sample_offsets.iter().map(|&offset| part(offset, SAMPLE_HEADER_SIZE));
+    let patterns = header.pattern_offsets
+        .iter()
+        .filter(|&&offset| offset != 0)
+        .map(|&offset| {
+            let length = input.get(offset.cast::<usize>()..).and_then(|data| data.get(..2));
+            let length = length.map_or(0, |length| usize::from(u16::from_le_bytes([length[0], length[1]])));
+            part(offset, PATTERN_HEADER_SIZE + length)
+        });
+    let data = sample_headers.into_iter().map(|header| {
+        part(header.data_offset, sample_data_size(header.flags, header.data_length, sample_input(input, header)))
+    });
+    let message = Some(header.message_offset).filter(|&offset| offset != 0).map(|offset| {
+        let end = part(offset, header.message_length.into());
+        if input.get(end) == Some(&0) { end + 1 } else { end }
+    });
+    core::iter::once(header_end)
+        .chain(instruments)
+        .chain(headers)
+        .chain(patterns)
+        .chain(data)
+        .chain(message)
+        .max()
+        .unwrap()
+        .min(input.len())
+}
+
+/// Returns the size of the stored sample data in bytes, `input` starts with the data.
+///
+/// The size of compressed data is not stored, it's found by reading the block lengths up to the end
+/// of the input.
+fn sample_data_size(flags: SampleFlags, length: u32, input: &[u8]) -> usize {
+    let length = length.cast::<usize>();
+    if flags.contains(SampleFlags::OPL_INSTRUMENT) {
+        OPL_PATCH_SIZE
+    } else if !flags.contains(SampleFlags::DATA_PRESENT) {
+        0
+    } else if flags.contains(SampleFlags::COMPRESSED) {
+        let block_length = if flags.contains(SampleFlags::DATA_16BIT) {
+            compression::BLOCK_LENGTH_16BIT
+        } else {
+            compression::BLOCK_LENGTH_8BIT
+        };
+        // The channels of stereo samples are compressed one after the other.
+        let mut size = 0;
+        for _ in 0..channel_count(flags) {
+            let mut decoded = 0;
+            while decoded < length {
+                match input.get(size..size + 2) {
+                    Some(block_size) => size += 2 + usize::from(u16::from_le_bytes([block_size[0], block_size[1]])),
+                    None => break,
+                }
+                decoded += block_length;
+            }
+        }
+        size
+    } else {
+        pcm_size(flags, length)
+    }
+}
+
+/// Returns the number of channels of the sample data stored with the flags.
+pub(crate) fn channel_count(flags: SampleFlags) -> usize {
+    if flags.contains(SampleFlags::STEREO) { 2 } else { 1 }
+}
+
+/// Returns the size of uncompressed PCM data of `length` frames in bytes.
+pub(crate) fn pcm_size(flags: SampleFlags, length: usize) -> usize {
+    let length = length.saturating_mul(channel_count(flags));
+    if flags.contains(SampleFlags::TX_WAVE) {
+        length.saturating_mul(3).saturating_add(1) / 2
+    } else if flags.contains(SampleFlags::DATA_16BIT) {
+        length.saturating_mul(2)
+    } else {
+        length
+    }
+}
+
+/// Returns the number of whole frames in `size` bytes of uncompressed PCM data.
+fn pcm_length(flags: SampleFlags, size: usize) -> usize {
+    let samples = if flags.contains(SampleFlags::TX_WAVE) {
+        size * 2 / 3
+    } else if flags.contains(SampleFlags::DATA_16BIT) {
+        size / 2
+    } else {
+        size
+    };
+    samples / channel_count(flags)
+}
+
+/// Reads the extended instrument and song properties OpenMPT appends after the module data
+///
+/// The instrument properties start with the `XTPM` magic, each is a 4 byte code, a `u16` size and
+/// the value for every instrument. The song properties follow, starting with the `STPM` magic,
+/// each is a 4 byte code, a `u16` size and the value. The channel count is stored in the `C...`
+/// song property. Malformed data ends the properties, the ones before it are kept. `data` is the
+/// data following the module, the data after the last property read is left as trailing data.
+fn openmpt_extensions(data: &[u8], instruments: usize) -> OpenMptExtensions {
+    let mut end = 0;
+    let (input, song) = match data.windows(4).rposition(|magic| magic == b"STPM") {
+        Some(start) => (&data[..start], Some(&data[start + 4..])),
+        None => (data, None),
+    };
+    let instruments = input.windows(4)
+        .rposition(|magic| magic == b"XTPM")
+        .map(|start| {
+            let (properties, rest) = instrument_properties(&input[start + 4..], instruments);
+            end = input.len() - rest.len();
+            properties
+        });
+
+    let mut channel_count = None;
+    let song = song.map(|mut input| {
+        let mut properties = Vec::new();
+        end = data.len() - input.len();
+        while let Some((property, rest)) = property(input, 1) {
+            input = rest;
+            end = data.len() - input.len();
+            if property.code == *b"C..." {
+                channel_count = match *property.data {
+                    [low] => Some(u16::from(low)),
+                    [low, high, ..] => Some(u16::from_le_bytes([low, high])),
+                    [] => None,
+                };
+            } else {
+                properties.push(property);
+            }
+        }
+        SongExtensions::from_properties(properties)
+    });
+
+    OpenMptExtensions { channel_count, song, instruments, trailing: data[end..].to_vec() }
+}
+
+/// Reads the extended properties of every instrument, returns them and the rest of the input.
+fn instrument_properties(mut input: &[u8], instruments: usize) -> (Vec<InstrumentExtensions>, &[u8]) {
+    let mut properties = vec![Vec::new(); instruments];
+    while let Some((property, rest)) = property(input, instruments) {
+        input = rest;
+        if property.data.is_empty() {
+            continue;
+        }
+        let size = property.data.len() / instruments;
+        for (properties, value) in properties.iter_mut().zip(property.data.chunks_exact(size)) {
+            properties.push(ExtensionProperty { code: property.code, data: value.to_vec() });
+        }
+    }
+    (properties.into_iter().map(InstrumentExtensions::from_properties).collect(), input)
+}
+
+/// Reads the property with `count` values, returns the values together and the rest of the input.
+fn property(input: &[u8], count: usize) -> Option<(ExtensionProperty, &[u8])> {
+    let code = input.get(..4)?;
+    let size = input.get(4..6)?;
+    let size = usize::from(u16::from_le_bytes([size[0], size[1]])) * count;
+    let data = input.get(6..6 + size)?;
+    let property = ExtensionProperty { code: code.try_into().unwrap(), data: data.to_vec() };
+    Some((property, &input[6 + size..]))
+}
+
+/// Reads the edit history, the MIDI configuration and the pattern and channel names, if present
+///
+/// The data is stored right after the offset tables of the header. The edit history comes first,
+/// a `u16` count and 8 bytes for each entry, then the MIDI configuration, then the `PNAM`, `CNAM` and `ChnS` chunks of OpenMPT. Each chunk is
+/// a 4 byte code, a `u32` size and the data, names of 32 bytes for each pattern and 20 bytes for each
+/// channel and the panning and volume bytes of each channel above 64. `input` is the data between the offset tables and the first part of the module, any
+/// malformed data is ignored and the data following the known chunks is kept as it is.
+fn header_extras(input: &[u8], stored_flags: u32) -> HeaderExtras {
+    let mut input = input;
+    let mut edit_history = Vec::new();
+    if stored_flags & EDIT_HISTORY != 0 {
+        let entries = input.get(..2).map_or(0, |count| usize::from(u16::from_le_bytes([count[0], count[1]])));
+        edit_history = input.get(2..)
+            .unwrap_or(&[])
+            .chunks_exact(8)
+            .take(entries)
+            .map(|entry| EditHistoryEntry {
+                fat_date: u16::from_le_bytes([entry[0], entry[1]]),
+                fat_time: u16::from_le_bytes([entry[2], entry[3]]),
+                run_time: u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]),
+            })
+            .collect();
+        input = input.get(2 + 8 * entries..).unwrap_or(&[]);
+    }
+    let mut midi_config = None;
+    if stored_flags & ModuleFlags::MIDI_CONIFG_EMBEDDED.bits() != 0 {
+        midi_config = input.get(..MIDI_CONFIG_SIZE).map(self::midi_config);
+        input = input.get(MIDI_CONFIG_SIZE..).unwrap_or(&[]);
+    }
+    let pattern_names = name_chunk(&mut input, b"PNAM", PATTERN_NAME_LENGTH);
+    let channel_names = name_chunk(&mut input, b"CNAM", CHANNEL_NAME_LENGTH);
+    let channel_settings = chunk(&mut input, b"ChnS").to_vec();
+    HeaderExtras { edit_history, midi_config, pattern_names, channel_names, channel_settings, chunks: input.to_vec() }
+}
+
+/// Reads the MIDI configuration, the global, parametered and fixed macros of 32 bytes each.
+fn midi_config(input: &[u8]) -> MidiConfig {
+    let macros = input.chunks_exact(32)
+        .map(|bytes| MidiMacro { bytes: bytes.try_into().unwrap() })
+        .collect::<Vec<_>>();
+    MidiConfig {
+        global: macros[..9].try_into().unwrap(),
+        parametered: macros[9..25].try_into().unwrap(),
+        fixed: macros[25..].try_into().unwrap(),
+    }
+}
+
+/// Reads the data of the chunk if it's next in the input, returns no data otherwise.
+fn chunk<'i>(input: &mut &'i [u8], code: &[u8; 4]) -> &'i [u8] {
+    let data = match **input {
+        [a, b, c, d, s0, s1, s2, s3, ref rest @ ..] if [a, b, c, d] == *code => {
+            let size = usize::try_from(u32::from_le_bytes([s0, s1, s2, s3])).unwrap_or(usize::MAX);
+            match rest.get(..size) {
+                Some(data) => data,
+                None => return &[],
+            }
+        }
+        _ => return &[],
+    };
+    *input = &input[8 + data.len()..];
+    data
+}
+
+/// Reads the chunk of fixed length names if it's next in the input, returns no names otherwise.
+fn name_chunk(input: &mut &[u8], code: &[u8; 4], length: usize) -> Vec<String> {
+    chunk(input, code)
+        .chunks_exact(length)
+        .map(|name| {
+            let len = name.iter().position(|&byte| byte == 0).unwrap_or(name.len());
+            String::from_utf8_lossy(&name[..len]).to_string()
+        })
+        .collect()
+}
+
+/// Find and parse Impulse Tracker module embedded in a larger file
+///
+/// Scans the input for the `IMPM` magic number and tries to parse a module at every occurence
+/// that has a plausible header, the first one that parses successfully is returned together with
+/// its offset in the input. This can be used to read modules prefixed by junk data or stored
+/// verbatim inside of other containers (e.g. game data files).
+///
+/// All offsets stored in the module (instruments, samples, patterns, message) are interpreted
+/// relative to the position of the magic number, not to the start of the input.
+///
+/// The magic number is only four bytes long so it can also appear by chance in unrelated data. The
+/// header is checked for sane entry counts and offsets before parsing is attempted, which filters
+/// out most false positives, but a successful parse is no proof that the data is really a module.
+pub fn find_and_parse<'i, E>(input: &'i [u8]) -> Result<(usize, Module), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let mut last_error = None;
+
+    let candidates = input.windows(4)
+        .enumerate()
+        .filter(|(_, magic)| *magic == b"IMPM")
+        .map(|(offset, _)| offset);
+
+    for offset in candidates {
+        let embedded = &input[offset..];
+        if !plausible_module_header(embedded) {
+            continue;
+        }
+        match module_file(embedded) {
+            Ok(module) => return Ok((offset, module)),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| Err::Error(error!(input, "no module found in input"))))
 }
 
 /// Parse Impulse Tracker instrument file (.iti)
@@ -106,10 +901,14 @@ pub fn instrument_file<'i, E>(input: &'i [u8]) -> Result<InstrumentFile, Err<E>>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
 {
-    let (input2, instrument) = instrument(input)?;
-    let (_, sample_headers) = count(sample_header, instrument.number_of_samples.into())(input2)?;
+    let (input2, instrument) = context!(instrument, "instrument")(input)?;
+    let (_, sample_headers) = count(
+        context!(sample_header, "sample header"),
+        instrument.number_of_samples.into(),
+    )(input2)?;
     let samples = sample_headers.into_iter()
-        .map(|header| sample_data(header, input))
+        .enumerate()
+        .map(|(index, header)| numbered_sample_data(index, header, input, &mut Limits::new(ParseOptions::default())))
         .collect::<Result<Vec<_>, _>>()?;
     Ok(InstrumentFile { instrument, samples })
 }
@@ -128,8 +927,10 @@ fn module_header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], ModuleHeader, E>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
 {
+    let start = input;
+
     // Parse static parts.
-    let (input, _) = tag(b"IMPM")(input)?;
+    let (input, _) = magic(b"IMPM")(input)?;
     let (input, songname) = name(input)?;
     let (input, highlight_minor) = le_u8(input)?;
     let (input, highlight_major) = le_u8(input)?;
@@ -149,17 +950,23 @@ where
     let (input, pwd) = le_u8(input)?;
     let (input, msglength) = le_u16(input)?;
     let (input, msgoffset) = le_u32(input)?;
-    let (input, _reserved) = le_u32(input)?;
+    let (input, reserved) = le_u32(input)?;
     let (input, chnpan) = byte_array(input)?;
     let (input, chnvol) = byte_array(input)?;
 
     // Parse dynamic parts of the header.
     let (input, orders) = count(order, ordnum.into())(input)?;
+    let mut fixes = orders.iter()
+        .enumerate()
+        .filter(|(_, order)| order.is_none())
+        .map(|(index, _)| (0xC0 + index, format!("order value {} is out of range, skipped", start[0xC0 + index])))
+        .collect::<Vec<_>>();
     let orders = orders.into_iter().flatten().collect();
     let (input, ins_offsets) = count(le_u32, insnum.into())(input)?;
     let (input, sam_offsets) = count(le_u32, smpnum.into())(input)?;
-    let (_rest, pat_offsets) = count(le_u32, patnum.into())(input)?;
+    let (input, pat_offsets) = count(le_u32, patnum.into())(input)?;
 
+    let stored_flags = ModuleFlags::raw_from_parts(flags, special);
     let flags = ModuleFlags::from_parts(flags, special);
 
     // Check ranged values and canonicalize out-of-range values.
@@ -174,24 +981,34 @@ where
     }
     let globalvol = ranged(globalvol, 0..=128, |_| {
         info!(globalvol, "global_volume cannot be more than 128, clipping");
+        fixes.push((0x30, format!("global volume {} is more than 128, clipped", globalvol)));
         128
     });
     let mv = ranged(mv, 0..=128, |_| {
         info!(mv, "sample_volume cannot be more than 128, clipping");
+        fixes.push((0x31, format!("sample volume {} is more than 128, clipped", mv)));
         128
     });
     let speed = ranged(speed, 1..=255, |_| {
         info!("speed must be at least 1, using default of 6");
+        fixes.push((0x32, String::from("speed 0 is less than 1, using default of 6")));
         6
     });
     let tempo = ranged(tempo, 31..=255, |_| {
         info!("tempo must be at least 31, using default of 120");
+        fixes.push((0x33, format!("tempo {} is less than 31, using default of 120", tempo)));
         120
     });
     let sep = ranged(sep, 0..=128, |_| {
         info!("pan_separation cannot be more than 128, clipping");
+        fixes.push((0x34, format!("pan separation {} is more than 128, clipped", sep)));
         128
     });
+    for (channel, &volume) in chnvol.iter().enumerate().filter(|(_, &volume)| volume > 64) {
+        info!(channel, volume, "channel volume cannot be more than 64, clipping");
+        fixes.push((0x80 + channel, format!("channel {} volume {} is more than 64, clipped", channel + 1, volume)));
+    }
+    let chnvol = chnvol.map(ChannelVolume::new_clamped);
 
     Ok((
         input,
@@ -201,6 +1018,7 @@ where
             made_with_version: cwtv,
             compatible_with_version: cmwt,
             flags,
+            stored_flags,
             global_volume: globalvol.cast(),
             sample_volume: mv.cast(),
             speed: speed.cast(),
@@ -209,16 +1027,41 @@ where
             pitch_wheel_depth: pwd,
             message_length: msglength,
             message_offset: msgoffset,
+            reserved,
             init_channel_panning: chnpan,
             init_channel_volume: chnvol,
             orders,
             instrument_offsets: ins_offsets,
             sample_offsets: sam_offsets,
             pattern_offsets: pat_offsets,
+            fixes,
         },
     ))
 }
 
+/// Checks the entry counts and offset tables of a module header without parsing it.
+fn plausible_module_header(input: &[u8]) -> bool {
+    let u16_at = |offset: usize| input.get(offset..offset + 2)
+        .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]));
+    let u32_at = |offset: usize| input.get(offset..offset + 4)
+        .map(|bytes| u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]));
+
+    let (ordnum, insnum, smpnum, patnum) = match (u16_at(0x20), u16_at(0x22), u16_at(0x24), u16_at(0x26)) {
+        (Some(ordnum), Some(insnum), Some(smpnum), Some(patnum)) => (ordnum, insnum, smpnum, patnum),
+        _ => return false,
+    };
+    if ordnum > 256 || insnum > 99 || smpnum > 99 || patnum > 200 {
+        return false;
+    }
+
+    let offsets_start = 0xC0 + usize::from(ordnum);
+    let offsets_count = usize::from(insnum) + usize::from(smpnum) + usize::from(patnum);
+    (0..offsets_count).all(|i| match u32_at(offsets_start + 4 * i) {
+        Some(offset) => offset.cast::<usize>() < input.len(),
+        None => false,
+    })
+}
+
 fn order<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [u8]) -> IResult<&'i [u8], Option<Order>, E> {
     map(
         le_u8,
@@ -246,7 +1089,7 @@ fn dosfilename<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i
 }
 
 fn instrument<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [u8]) -> IResult<&'i [u8], Instrument, E> {
-    let (input, _) = tag(b"IMPI")(input)?;
+    let (input, _) = magic(b"IMPI")(input)?;
     let (input, filename) = dosfilename(input)?;
     let (input, nna) = le_u8(input)?;
     let (input, dct) = le_u8(input)?;
@@ -317,6 +1160,79 @@ fn instrument<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [
             volume_envelope: volenv,
             panning_envelope: panenv,
             pitch_filter_envelope: pitchenv,
+            openmpt_extensions: None,
+            tuning: None,
+        },
+    ))
+}
+
+/// Instrument parser for modules compatible with the version `cmwt`
+///
+/// Modules compatible with versions before 2.00 store the instruments in the old format, see
+/// [`old_instrument`].
+fn module_instrument<'i, E>(cmwt: u16) -> impl Fn(&'i [u8]) -> IResult<&'i [u8], Instrument, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    move |input| if cmwt < 0x0200 { old_instrument(input) } else { instrument(input) }
+}
+
+/// Parses the instrument header used by Impulse Tracker before version 2.00
+///
+/// The old format has only the volume envelope and no panning, pitch or filter settings, those
+/// are left disabled. The fadeout is stored in 0..=64 with a fadeout count of 512, it's doubled to
+/// fit the count of 1024 of the new format. The precomputed 200 tick volume table is skipped, the
+/// envelope is read from its nodes.
+fn old_instrument<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(input: &'i [u8]) -> IResult<&'i [u8], Instrument, E> {
+    let (input, _) = magic(b"IMPI")(input)?;
+    let (input, filename) = dosfilename(input)?;
+    let (input, flg) = le_u8(input)?;
+    let (input, vls) = le_u8(input)?;
+    let (input, vle) = le_u8(input)?;
+    let (input, sls) = le_u8(input)?;
+    let (input, sle) = le_u8(input)?;
+    let (input, _reserved) = byte_array::<_, 2>(input)?;
+    let (input, fadeout) = le_u16(input)?;
+    let (input, nna) = le_u8(input)?;
+    let (input, dnc) = le_u8(input)?;
+    let (input, trkver) = le_u16(input)?;
+    let (input, nos) = le_u8(input)?;
+    let (input, _reserved) = le_u8(input)?;
+    let (input, name) = name(input)?;
+    let (input, _reserved) = byte_array::<_, 6>(input)?;
+    let (input, sample_map) = sample_map(input)?;
+    let (input, _volume_table) = byte_array::<_, 200>(input)?;
+    let (input, data): (_, [(u8, u8); 25]) = array(tuple((le_u8, le_u8)))(input)?;
+
+    let nodes = data.iter()
+        .take_while(|&&(tick, _)| tick != 0xFF)
+        .map(|&(tick, value)| Node { value: i8::try_from(value.min(64)).unwrap(), tick: u16::from(tick) })
+        .collect::<Vec<_>>();
+    let num = nodes.len();
+    let envelope_loop = (vls <= vle && usize::from(vle) < num).then_some(EnvelopeLoop { start: vls, end: vle });
+    let sustain_loop = (sls <= sle && usize::from(sle) < num).then_some(EnvelopeLoop { start: sls, end: sle });
+    if fadeout > 64 {
+        info!(fadeout, "fadeout out of range 0..=64, using 64");
+    }
+
+    Ok((
+        input,
+        Instrument {
+            name,
+            filename,
+            new_note_action: nna,
+            duplicate_check_type: dnc,
+            instrument_fadeout: u8::try_from(fadeout.min(64) * 2).unwrap(),
+            trkver,
+            number_of_samples: nos,
+            sample_map,
+            volume_envelope: Envelope {
+                flags: EnvelopeFlags::from_bits_truncate(flg & 0b111),
+                envelope_loop,
+                sustain_loop,
+                nodes,
+            },
+            ..empty_instrument()
         },
     ))
 }
@@ -409,7 +1325,7 @@ fn sample_header<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], SampleHeader, E>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
 {
-    let (input, _) = tag(b"IMPS")(input)?;
+    let (input, _) = magic(b"IMPS")(input)?;
     let (input, filename) = dosfilename(input)?;
     let (input, gvl) = le_u8(input)?;
     let (input, flags) = le_u8(input)?;
@@ -432,31 +1348,35 @@ where
     let (input, vir) = le_u8(input)?;
     let (input, vit) = le_u8(input)?;
 
-    let loop_ = if flags.contains(SampleFlags::LOOP) {
-        // TODO canonicalize/skip invalid values
-        assert!(loopbegin < loopend);
-        assert!(loopend <= length);
-        Some(SampleLoop {
-            start: loopbegin,
-            end: loopend,
-            bidi: flags.contains(SampleFlags::BIDI_LOOP),
-        })
-    } else {
-        None
-    };
-
-    let sustain_loop = if flags.contains(SampleFlags::SUSTAIN) {
-        // TODO canonicalize/skip invalid values
-        assert!(susloopbegin < susloopend);
-        assert!(susloopend <= length);
-        Some(SampleLoop {
-            start: susloopbegin,
-            end: susloopend,
-            bidi: flags.contains(SampleFlags::BIDI_SUSTAIN),
-        })
-    } else {
-        None
+    // Invalid loops are ignored, they would make the sample unplayable.
+    let mut fixes = Vec::new();
+    let mut sample_loop = |enabled: bool, start: u32, end: u32, bidi: bool, offset: usize, name: &str| {
+        if !enabled {
+            None
+        } else if start < end && end <= length {
+            Some(SampleLoop { start, end, bidi })
+        } else {
+            info!(start, end, length, "invalid loop points, ignoring {}", name);
+            fixes.push((offset, format!("{} {}..{} is invalid for {} samples, ignored", name, start, end, length)));
+            None
+        }
     };
+    let loop_ = sample_loop(
+        flags.contains(SampleFlags::LOOP),
+        loopbegin,
+        loopend,
+        flags.contains(SampleFlags::BIDI_LOOP),
+        0x34,
+        "loop",
+    );
+    let sustain_loop = sample_loop(
+        flags.contains(SampleFlags::SUSTAIN),
+        susloopbegin,
+        susloopend,
+        flags.contains(SampleFlags::BIDI_SUSTAIN),
+        0x40,
+        "sustain loop",
+    );
 
     Ok((
         input,
@@ -477,48 +1397,192 @@ where
             flags,
             data_offset: samplepointer,
             data_length: length,
+            fixes,
         },
     ))
 }
 
+/// Decodes the data of the sample, the errors name the sample by its number.
+fn numbered_sample_data<'i, E>(
+    index: usize,
+    header: SampleHeader,
+    input: &'i [u8],
+    limits: &mut Limits,
+) -> Result<Sample, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let data = sample_input(input, &header);
+    limits.sample(data, &header)
+        .and_then(|()| sample_data(header, input))
+        .map_err(|e| e.map(|e| E::add_context(data, Cow::Owned(format!("sample {}", index + 1)), e)))
+}
+
+/// Returns the input starting with the data of the sample, empty if it's past the end.
+fn sample_input<'i>(input: &'i [u8], header: &SampleHeader) -> &'i [u8] {
+    input.get(header.data_offset.cast::<usize>()..).unwrap_or(&input[input.len()..])
+}
+
 fn sample_data<'i, E>(header: SampleHeader, input: &'i [u8]) -> Result<Sample, Err<E>>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
 {
     let flags = header.flags;
 
-    let data = if !flags.contains(SampleFlags::DATA_PRESENT) {
+    // OPL instruments store an FM patch in place of the sample data, it must not be decoded as PCM.
+    let fm_patch = if flags.contains(SampleFlags::OPL_INSTRUMENT) {
+        let offset = header.data_offset.cast::<usize>();
+        if offset >= input.len() {
+            return Err(past_end(input, offset));
+        }
+        let (_, patch) = context!(byte_array, "reading OPL patch")(&input[offset..])?;
+        Some(patch)
+    } else {
         None
+    };
+
+    let (data, encoded) = if !flags.contains(SampleFlags::DATA_PRESENT) || fm_patch.is_some() {
+        (None, None)
     } else {
-        // TODO add support for more sample formats, do not panic
-
-        assert!(flags.contains(SampleFlags::DATA_SIGNED), "only signed samples are supported");
-        assert!(!flags.contains(SampleFlags::STEREO), "only mono samples supported");
-
-        assert!(!flags.contains(SampleFlags::COMPRESSED), "sample compression is not supported");
-        assert!(!flags.contains(SampleFlags::OPL_INSTRUMENT), "OPL instrument is not supported");
-        assert!(!flags.contains(SampleFlags::EXTERNAL_SAMPLE), "external samples are not supported");
-        assert!(!flags.contains(SampleFlags::ADPCM_SAMPLE), "MODPlugin :(");
-        assert!(!flags.contains(SampleFlags::DELTA), "delta samples are not supported");
-        assert!(!flags.contains(SampleFlags::PTM8_TO_16), "PTM loader is not supported");
-
-        let offset = header.data_offset.cast();
-        let length = header.data_length.cast();
-        let input = &input[offset..];
-
-        let (_, data) = match (
-            flags.contains(SampleFlags::DATA_16BIT),
-            flags.contains(SampleFlags::DATA_BIG_ENDIAN),
-        ) {
-            (true, true) => count(map(be_i16, |s| f32::from(s) / f32::from(i16::MAX)), length)(input)?,
-            (true, false) => count(map(le_i16, |s| f32::from(s) / f32::from(i16::MAX)), length)(input)?,
-            (false, _) => count(map(le_i8, |s| f32::from(s) / f32::from(i8::MAX)), length)(input)?,
-        };
+        let (data, encoded) = pcm_data(flags, header.data_offset, header.data_length, input)?;
+        (Some(data), encoded)
+    };
+
+    Ok(sample_from_header(header, data, fm_patch, encoded, None))
+}
+
+/// Decodes PCM sample data
+///
+/// Returns the original encoding of the data too if it's worth keeping for the writer.
+fn pcm_data<'i, E>(
+    flags: SampleFlags,
+    offset: u32,
+    length: u32,
+    input: &'i [u8],
+) -> Result<(Vec<f32>, Option<EncodedData>), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let compressed = flags.contains(SampleFlags::COMPRESSED);
+    let unsupported = [
+        (compressed && !flags.contains(SampleFlags::DATA_SIGNED), "signed sample data", "unsigned sample data"),
+        (flags.contains(SampleFlags::EXTERNAL_SAMPLE), "sample data in the file", "external sample"),
+        (flags.contains(SampleFlags::ADPCM_SAMPLE), "PCM sample data", "ADPCM sample data"),
+    ];
+    if let Some(&(_, expected, found)) = unsupported.iter().find(|(unsupported, _, _)| *unsupported) {
+        let data = input.get(offset.cast::<usize>()..).unwrap_or(&input[input.len()..]);
+        return Err(Err::Error(E::expected(data, Cow::Borrowed(expected), Cow::Borrowed(found))));
+    }
 
-        Some(data)
+    let offset = offset.cast::<usize>();
+    let length = length.cast();
+    if offset > input.len() {
+        return Err(past_end(input, offset));
+    }
+    let input = &input[offset..];
+
+    let channels = channel_count(flags);
+    if compressed {
+        // For compressed samples the delta flag selects the IT2.15 variant of the compression.
+        let mut rest = input;
+        let mut data = Vec::new();
+        for _ in 0..channels {
+            let (left, channel) = context!(
+                |input| compressed_sample(
+                    input,
+                    length,
+                    flags.contains(SampleFlags::DATA_16BIT),
+                    flags.contains(SampleFlags::DELTA),
+                ),
+                "decompressing sample",
+            )(rest)?;
+            rest = left;
+            data.extend(channel);
+        }
+        let data = interleave(data, channels);
+        let bytes = input[..input.len() - rest.len()].to_vec();
+        let encoded = EncodedData::new(flags, bytes, Some(&data));
+        Ok((data, Some(encoded)))
+    } else {
+        let size = pcm_size(flags, length);
+        if input.len() < size {
+            // Report the missing data at the end of the input, where decoding stopped.
+            return Err(Err::Error(E::from_error_kind(&input[input.len()..], ErrorKind::Eof)));
+        }
+        Ok((interleave(decode_pcm(flags, length * channels, &input[..size]), channels), None))
+    }
+}
+
+/// Interleaves the channels stored one after the other into frames.
+pub(crate) fn interleave(data: Vec<f32>, channels: usize) -> Vec<f32> {
+    if channels == 1 {
+        return data;
+    }
+    let length = data.len() / channels;
+    (0..length * channels).map(|index| data[index % channels * length + index / channels]).collect()
+}
+
+/// Decodes uncompressed PCM data, applying the conversions of the convert field
+///
+/// The conversions are applied in the order they were done when storing the data in reverse: byte
+/// deltas are summed first, then the bytes are put together into samples, the sample deltas are
+/// summed and finally unsigned samples are made signed. TX-Wave data is decoded as 16-bit with the
+/// low 4 bits empty.
+fn decode_pcm(flags: SampleFlags, length: usize, bytes: &[u8]) -> Vec<f32> {
+    let mut bytes = Cow::Borrowed(bytes);
+    if flags.contains(SampleFlags::PTM8_TO_16) {
+        let mut sum = 0u8;
+        bytes = Cow::Owned(bytes.iter().map(|&delta| { sum = sum.wrapping_add(delta); sum }).collect());
+    }
+    let wide = flags.intersects(SampleFlags::DATA_16BIT | SampleFlags::TX_WAVE);
+    let mut values = if flags.contains(SampleFlags::TX_WAVE) {
+        // Two samples are packed in three bytes, the middle one holds the low nibbles of both.
+        bytes.chunks(3)
+            .flat_map(|chunk| {
+                let byte = |idx: usize| u16::from(chunk.get(idx).copied().unwrap_or(0));
+                [byte(0) << 8 | (byte(1) & 0xF0), byte(2) << 8 | (byte(1) & 0x0F) << 4]
+            })
+            .take(length)
+            .collect::<Vec<_>>()
+    } else if wide {
+        let big_endian = flags.contains(SampleFlags::DATA_BIG_ENDIAN);
+        bytes.chunks_exact(2)
+            .map(|pair| [pair[0], pair[1]])
+            .map(|pair| if big_endian { u16::from_be_bytes(pair) } else { u16::from_le_bytes(pair) })
+            .collect()
+    } else {
+        bytes.iter().copied().map(u16::from).collect()
     };
 
-    Ok(Sample {
+    if flags.contains(SampleFlags::DELTA) {
+        pcm::integrate(&mut values);
+    }
+    pcm::normalize(&values, wide, !flags.contains(SampleFlags::DATA_SIGNED))
+}
+
+/// Returns `true` if the sample has PCM data which can be loaded later.
+fn is_deferrable(header: &SampleHeader) -> bool {
+    header.flags.contains(SampleFlags::DATA_PRESENT) && !header.flags.contains(SampleFlags::OPL_INSTRUMENT)
+}
+
+/// Creates a sample with the data left in the input, see [`module_headers`].
+fn deferred_sample(header: SampleHeader) -> Sample {
+    let deferred = DeferredData {
+        flags: header.flags,
+        offset: header.data_offset,
+        length: header.data_length,
+    };
+    sample_from_header(header, None, None, None, Some(deferred))
+}
+
+fn sample_from_header(
+    header: SampleHeader,
+    data: Option<Vec<f32>>,
+    fm_patch: Option<[u8; 12]>,
+    encoded: Option<EncodedData>,
+    deferred: Option<DeferredData>,
+) -> Sample {
+    Sample {
         name: header.name,
         filename: header.filename,
         global_volume: header.global_volume,
@@ -531,6 +1595,222 @@ where
         vibrato_depth: header.vibrato_depth,
         vibrato_rate: header.vibrato_rate,
         vibrato_type: header.vibrato_type,
-        data,
-    })
+        channels: if header.flags.contains(SampleFlags::STEREO) { RangedU8::MAX } else { RangedU8::MIN },
+        data: data.map(SampleData::from),
+        fm_patch,
+        conversion: SampleConversion::from_flags(header.flags),
+        encoded,
+        deferred,
+    }
+}
+
+impl Sample {
+    /// Decodes the sample data left in the input by [`module_headers`]
+    ///
+    /// The input must be the same one the module was parsed from. Does nothing if the data was
+    /// already loaded.
+    pub fn load_data<'i, E>(&mut self, input: &'i [u8]) -> Result<(), Err<E>>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+    {
+        if let Some(deferred) = self.deferred {
+            let (data, encoded) = pcm_data(deferred.flags, deferred.offset, deferred.length, input)?;
+            self.data = Some(data.into());
+            self.encoded = encoded;
+            self.deferred = None;
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::writer::WriteOptions;
+
+    #[test]
+    fn parse_lenient() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.samples[0].data = Some((0..1000i16).map(|i| f32::from(i % 200 - 100) / 127.0).collect());
+        let mut file = Vec::new();
+        module.write_to_with(&mut file, WriteOptions::default()).unwrap();
+        let (lenient, warnings) = super::parse_lenient(&file).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(format!("{:?}", lenient), format!("{:?}", module_file::<VerboseError<&[u8]>>(&file).unwrap()));
+
+        let field = |offset: usize| usize::from(u16::from_le_bytes([file[offset], file[offset + 1]]));
+        let u32_at = |offset: usize| u32::from_le_bytes([file[offset], file[offset + 1], file[offset + 2], file[offset + 3]]);
+        let tables = 0xC0 + field(0x20);
+        let sample_header = u32_at(tables + 4 * field(0x22)).cast::<usize>();
+        let pattern_offset = tables + 4 * (field(0x22) + field(0x24));
+        let data_offset = u32_at(sample_header + 0x48).cast::<usize>();
+
+        // Out of range global volume, reversed loop, bogus pattern offset and truncated sample data.
+        file[0x30] = 200;
+        file[sample_header + 0x12] |= 0x10;
+        file[sample_header + 0x34..sample_header + 0x3C].copy_from_slice(&[5, 0, 0, 0, 2, 0, 0, 0]);
+        file[pattern_offset..pattern_offset + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        file.truncate(data_offset + 600);
+
+        let (module, warnings) = super::parse_lenient(&file).unwrap();
+        let summary = warnings.iter().map(|w| (w.offset, w.context.join(", "))).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            (0x30, String::from("module header")),
+            ((sample_header + 0x34).cast(), String::from("sample header 1")),
+            (file.len().cast(), String::from("pattern 0")),
+            (file.len().cast(), String::from("sample 1")),
+        ]);
+        assert_eq!(warnings[1].message, "loop 5..2 is invalid for 1000 samples, ignored");
+        assert_eq!(module.global_volume.as_u8(), 128);
+        assert!(module.samples[0].loop_.is_none());
+        assert!(module.patterns[0].rows.iter().all(|row| row.is_empty()));
+        let data = module.samples[0].data.as_deref().unwrap();
+        assert_eq!(data.len(), 1000);
+        assert!(data[600..].iter().all(|&x| x == 0.0) && data[..600] == lenient.samples[0].data.as_deref().unwrap()[..600]);
+    }
+
+    #[test]
+    fn lenient_unknown_effect() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let field = |offset: usize| usize::from(u16::from_le_bytes([DATA[offset], DATA[offset + 1]]));
+        let pattern_offset = 0xC0 + field(0x20) + 4 * (field(0x22) + field(0x24));
+        let mut file = DATA.to_vec();
+        let pattern = u32::from_le_bytes(file[pattern_offset..pattern_offset + 4].try_into().unwrap()).cast::<usize>();
+        file[pattern + 10] = 0xFF;
+
+        let (module, warnings) = super::parse_lenient(&file).unwrap();
+        assert_eq!(module.patterns[0].rows[0].get(Channel::new(1)).and_then(|command| command.effect), None);
+        let summary = warnings.iter().map(|w| (w.offset, w.context.join(", "), w.message.as_str())).collect::<Vec<_>>();
+        assert_eq!(summary, vec![
+            ((pattern + 10).cast(), String::from("pattern 0"), "effect code 0xff is out of range 0x01..=0x1A, skipped"),
+        ]);
+    }
+
+    #[test]
+    fn untrusted_input() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let field = |offset: usize| usize::from(u16::from_le_bytes([DATA[offset], DATA[offset + 1]]));
+        let tables = 0xC0 + field(0x20);
+
+        // Zero offset of the sample header.
+        let mut file = DATA.to_vec();
+        let sample_offset = tables + 4 * field(0x22);
+        file[sample_offset..sample_offset + 4].fill(0);
+        assert!(module_file::<VerboseError<&[u8]>>(&file).is_err());
+        assert!(module_file_with::<VerboseError<&[u8]>>(&file, ParseOptions::default()).is_err());
+
+        // Unknown effect in the first cell of the pattern.
+        let mut file = DATA.to_vec();
+        let pattern_offset = tables + 4 * (field(0x22) + field(0x24));
+        let pattern = u32::from_le_bytes(file[pattern_offset..pattern_offset + 4].try_into().unwrap()).cast::<usize>();
+        assert_eq!(file[pattern + 8..pattern + 12], [0x81, 0x08, 0x01, 0x12]);
+        file[pattern + 10] = 0xFF;
+        let module = module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert!(module.patterns[0].rows[0].get(Channel::new(1)).is_none_or(|command| command.effect.is_none()));
+    }
+
+    #[test]
+    fn huge_compressed_sample() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let field = |offset: usize| usize::from(u16::from_le_bytes([DATA[offset], DATA[offset + 1]]));
+        let sample_offset = 0xC0 + field(0x20) + 4 * field(0x22);
+        let mut file = DATA.to_vec();
+        let sample_header = u32::from_le_bytes(file[sample_offset..sample_offset + 4].try_into().unwrap()).cast::<usize>();
+        file[sample_header + 0x12] |= 0x09;
+        file[sample_header + 0x2E] |= 0x01;
+        file[sample_header + 0x30..sample_header + 0x34].copy_from_slice(&0xCC00_0000u32.to_le_bytes());
+
+        assert!(module_file::<VerboseError<&[u8]>>(&file).is_err());
+        let (module, warnings) = super::parse_lenient(&file).unwrap();
+        assert!(module.samples[0].data.is_none());
+        assert_eq!(warnings.len(), 1);
+    }
+
+    #[test]
+    fn lenient_huge_sample() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let field = |offset: usize| usize::from(u16::from_le_bytes([DATA[offset], DATA[offset + 1]]));
+        let sample_offset = 0xC0 + field(0x20) + 4 * field(0x22);
+        let mut file = DATA.to_vec();
+        let sample_header = u32::from_le_bytes(file[sample_offset..sample_offset + 4].try_into().unwrap()).cast::<usize>();
+        file[sample_header + 0x12] |= 0x01;
+        file[sample_header + 0x2E] |= 0x01;
+        let data_offset = u32::from_le_bytes(file[sample_header + 0x48..sample_header + 0x4C].try_into().unwrap()).cast::<usize>();
+
+        // Truncated data is padded with silence, a length past what the file could hold is dropped.
+        file[sample_header + 0x30..sample_header + 0x34].copy_from_slice(&200u32.to_le_bytes());
+        let (module, warnings) = super::parse_lenient(&file).unwrap();
+        assert_eq!(module.samples[0].data.as_deref().map(<[f32]>::len), Some(200));
+        let missing = 200 - (file.len() - data_offset);
+        assert!(warnings[0].message.ends_with(&format!("padded the missing {} of 200 samples with silence", missing)));
+
+        file[sample_header + 0x30..sample_header + 0x34].copy_from_slice(&0x7000_0000u32.to_le_bytes());
+        let (module, warnings) = super::parse_lenient(&file).unwrap();
+        assert!(module.samples[0].data.is_none());
+        assert!(warnings[0].message.ends_with("dropped the sample data"));
+    }
+
+    #[test]
+    fn find_and_parse_junk() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let field = |offset: usize| usize::from(u16::from_le_bytes([DATA[offset], DATA[offset + 1]]));
+        // A plausible header whose sample header offset is zero.
+        let mut fake = DATA.to_vec();
+        let sample_offset = 0xC0 + field(0x20) + 4 * field(0x22);
+        fake[sample_offset..sample_offset + 4].fill(0);
+
+        let mut input = b"junk data ".repeat(4);
+        input.extend_from_slice(&fake);
+        assert!(find_and_parse::<VerboseError<&[u8]>>(&input).is_err());
+
+        let offset = input.len() + 3;
+        input.extend_from_slice(b"pad");
+        input.extend_from_slice(DATA);
+        let (found, module) = find_and_parse::<VerboseError<&[u8]>>(&input).unwrap();
+        assert_eq!(found, offset);
+        assert_eq!(module.patterns.len(), 1);
+    }
+
+    #[test]
+    fn decode_pcm() {
+        let decode = |flags: SampleFlags, length, bytes: &[u8]| -> Vec<f32> {
+            super::decode_pcm(flags | SampleFlags::DATA_PRESENT, length, bytes)
+        };
+        let i8s = |values: &[i8]| values.iter().copied().map(convert::i8_to_f32).collect::<Vec<_>>();
+        let i16s = |values: &[i16]| values.iter().copied().map(convert::i16_to_f32).collect::<Vec<_>>();
+        let signed = SampleFlags::DATA_SIGNED;
+        let wide = SampleFlags::DATA_16BIT;
+
+        assert_eq!(decode(SampleFlags::empty(), 3, &[0x80, 0xFF, 0x00]), i8s(&[0, 127, -128]));
+        assert_eq!(decode(signed | SampleFlags::DELTA, 3, &[10, 0xFB, 0x7F]), i8s(&[10, 5, -124]));
+        assert_eq!(decode(wide, 2, &[0x00, 0x80, 0x00, 0x00]), i16s(&[0, i16::MIN]));
+        assert_eq!(decode(signed | wide | SampleFlags::DATA_BIG_ENDIAN, 1, &[0x12, 0x34]), i16s(&[0x1234]));
+        assert_eq!(decode(signed | wide | SampleFlags::PTM8_TO_16, 2, &[0x34, 0xDE, 0xEE, 0x12]), i16s(&[0x1234, 0x1200]));
+        assert_eq!(decode(signed | SampleFlags::TX_WAVE, 3, &[0x12, 0x3A, 0xBC, 0xF0, 0x10]), i16s(&[0x1230, -0x4360, -0x0FF0]));
+    }
+
+    #[test]
+    fn old_instrument() {
+        let mut data = vec![0; INSTRUMENT_SIZE];
+        data[..4].copy_from_slice(b"IMPI");
+        data[0x11..0x16].copy_from_slice(&[0b011, 1, 2, 0, 3]);
+        data[0x18..0x1C].copy_from_slice(&[40, 0, 3, 1]);
+        data[0x20..0x23].copy_from_slice(b"old");
+        data[0x40 + 2 * 60..0x40 + 2 * 60 + 2].copy_from_slice(&[60, 2]);
+        data[0x1F8..0x200].copy_from_slice(&[0, 64, 10, 32, 20, 0, 0xFF, 0xFF]);
+
+        let (_, instrument) = module_instrument::<VerboseError<&[u8]>>(0x0100)(&data).unwrap();
+        assert_eq!(instrument.name.to_string(), "old");
+        assert_eq!((instrument.new_note_action, instrument.duplicate_check_type), (3, 1));
+        assert_eq!(instrument.instrument_fadeout, 80);
+        assert!(!instrument.flags.contains(InstrumentFlags::ENABLE_PANNING));
+        assert_eq!(instrument.sample_map.map[60], Some(SampleId::try_from(1).unwrap()));
+        let envelope = &instrument.volume_envelope;
+        assert_eq!(envelope.flags, EnvelopeFlags::ENABLED | EnvelopeFlags::LOOP);
+        assert!(matches!(envelope.envelope_loop, Some(EnvelopeLoop { start: 1, end: 2 })));
+        assert!(envelope.sustain_loop.is_none());
+        assert_eq!(envelope.nodes.iter().map(|node| (node.tick, node.value)).collect::<Vec<_>>(), [(0, 64), (10, 32), (20, 0)]);
+    }
 }
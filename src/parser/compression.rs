@@ -0,0 +1,218 @@
+//! IT2.14 and IT2.15 sample decompression
+//!
+//! Compressed sample data is split into blocks of `0x8000` samples for 8-bit and `0x4000` samples
+//! for 16-bit data. Each block is stored as a `u16` length followed by that many bytes of
+//! a bitstream. The bitstream contains the differences between successive samples (IT2.15 stores
+//! the differences of the differences) packed into a variable number of bits, the bit width is
+//! changed by special values in the stream and is reset at the start of each block.
+//!
+//! The implementation follows `itsmp.c` from Schism Tracker and `ITCompression.cpp` from OpenMPT.
+
+use super::*;
+
+
+/// Number of samples in a compressed block of 8-bit data
+pub(super) const BLOCK_LENGTH_8BIT: usize = 0x8000;
+
+/// Number of samples in a compressed block of 16-bit data
+pub(super) const BLOCK_LENGTH_16BIT: usize = 0x4000;
+
+
+/// Decompresses `length` samples of IT2.14 or IT2.15 compressed sample data
+///
+/// Returns the decoded samples normalized the same way as uncompressed data, 8-bit samples are
+/// divided by `127` and 16-bit ones by `32767`.
+pub(crate) fn compressed_sample<'i, E>(
+    input: &'i [u8],
+    length: usize,
+    is_16bit: bool,
+    it215: bool,
+) -> IResult<&'i [u8], Vec<f32>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (bits, block_length) = if is_16bit {
+        (16, BLOCK_LENGTH_16BIT)
+    } else {
+        (8, BLOCK_LENGTH_8BIT)
+    };
+
+    // The length comes from the sample header, the memory is only reserved for the blocks that
+    // are present in the input.
+    let mut data = Vec::new();
+    let mut input = input;
+    while data.len() < length {
+        let (rest, block_size) = context!(le_u16, "reading compressed block length")(input)?;
+        let (rest, block) = context!(take(block_size), "reading compressed block")(rest)?;
+        let samples = (length - data.len()).min(block_length);
+        data.reserve(samples);
+        if let Err(message) = decompress_block(block, samples, bits, it215, &mut data) {
+            bail!(input, message);
+        }
+        input = rest;
+    }
+
+    Ok((input, pcm::normalize(&data, is_16bit, false)))
+}
+
+/// Decodes one block of `samples` samples of `bits` bits each and appends them to `out`.
+///
+/// The differences are unpacked from the bitstream first and the block is integrated afterwards,
+/// the sums wrap around in `u16` and only their lowest `bits` bits are used.
+fn decompress_block(
+    block: &[u8],
+    samples: usize,
+    bits: u8,
+    it215: bool,
+    out: &mut Vec<u16>,
+) -> Result<(), &'static str> {
+    let max_width = bits + 1;
+    let mut reader = BitReader { input: block, position: 0 };
+    let mut width = max_width;
+    let start = out.len();
+
+    let mut decoded = 0;
+    while decoded < samples {
+        if width == 0 || width > max_width {
+            return Err("invalid bit width in compressed sample");
+        }
+        let value = reader.read(width).ok_or("compressed block ended early")?;
+
+        // Check for bit width changes, these are encoded differently depending on the current
+        // width.
+        if width < 7 {
+            // Narrow widths use the lowest value followed by 3 bits of the new width.
+            if value == 1 << (width - 1) {
+                let new_width = reader.read(3).ok_or("compressed block ended early")? + 1;
+                width = next_width(new_width, width);
+                continue;
+            }
+        } else if width < max_width {
+            // Wider widths use a range of values around the middle of the range.
+            let border = (((1 << bits) - 1) >> (max_width - width)) - u32::from(bits / 2);
+            if value > border && value <= border + u32::from(bits) {
+                width = next_width(value - border, width);
+                continue;
+            }
+        } else if value & (1 << bits) != 0 {
+            // The full width uses the top bit.
+            width = u8::try_from((value + 1) & 0xFF).unwrap();
+            continue;
+        }
+
+        out.push(sign_extend(value, width.min(bits)));
+        decoded += 1;
+    }
+
+    pcm::integrate(&mut out[start..]);
+    if it215 {
+        pcm::integrate(&mut out[start..]);
+    }
+    Ok(())
+}
+
+/// Widths are encoded without the current width, values from it up are shifted by one.
+fn next_width(value: u32, width: u8) -> u8 {
+    let value = u8::try_from(value).unwrap();
+    if value < width { value } else { value + 1 }
+}
+
+/// Interprets the lowest `bits` bits of the value as a signed integer, returns it in two's
+/// complement.
+fn sign_extend(value: u32, bits: u8) -> u16 {
+    let value = value & ((1 << bits) - 1);
+    let value = if value >= 1 << (bits - 1) { value | !((1 << bits) - 1) } else { value };
+    u16::try_from(value & 0xFFFF).unwrap()
+}
+
+/// Reads values from the bitstream, the bits are stored starting from the least significant bit of
+/// each byte.
+pub(super) struct BitReader<'i> {
+    pub(super) input: &'i [u8],
+    pub(super) position: usize,
+}
+
+impl BitReader<'_> {
+    /// Reads a value of `width` bits, at most 24 bits are read at once.
+    pub(super) fn read(&mut self, width: u8) -> Option<u32> {
+        debug_assert!(width <= 24);
+        let end = self.position + usize::from(width);
+        if end > self.input.len() * 8 {
+            return None;
+        }
+        // The value spans at most 4 bytes, the bytes past the end of the input are zero.
+        let first = self.position / 8;
+        let mut bytes = [0; 4];
+        let available = &self.input[first..self.input.len().min(first + 4)];
+        bytes[..available.len()].copy_from_slice(available);
+        let shift = self.position % 8;
+        self.position = end;
+        Some((u32::from_le_bytes(bytes) >> shift) & ((1 << width) - 1))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    /// Packs the values into a single compressed block.
+    fn block(values: &[(u32, u8)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut position = 0;
+        for &(value, width) in values {
+            for bit in 0..width {
+                if position % 8 == 0 {
+                    bytes.push(0);
+                }
+                let last = bytes.len() - 1;
+                bytes[last] |= u8::try_from(value >> bit & 1).unwrap() << (position % 8);
+                position += 1;
+            }
+        }
+        let mut block = u16::try_from(bytes.len()).unwrap().to_le_bytes().to_vec();
+        block.extend(bytes);
+        block
+    }
+
+    fn decompress(input: &[u8], length: usize, is_16bit: bool, it215: bool) -> Vec<f32> {
+        let (rest, data) = compressed_sample::<VerboseError<_>>(input, length, is_16bit, it215).unwrap();
+        assert!(rest.is_empty());
+        data
+    }
+
+    #[test]
+    fn decompress_8bit() {
+        let input = block(&[
+            // 9 bits, delta 1
+            (0x001, 9),
+            // change to 3 bits
+            (0x100 | 2, 9),
+            // deltas 1, -1
+            (0b001, 3),
+            (0b111, 3),
+            // change to 8 bits, stored as 8 - 2 for the offset and the skipped current width
+            (0b100, 3),
+            (6, 3),
+            // delta 5
+            (5, 8),
+        ]);
+        let scale = |values: &[i8]| values.iter().map(|&s| f32::from(s) / 127.0).collect::<Vec<_>>();
+        assert_eq!(decompress(&input, 4, false, false), scale(&[1, 2, 1, 6]));
+        assert_eq!(decompress(&input, 4, false, true), scale(&[1, 3, 4, 10]));
+    }
+
+    #[test]
+    fn decompress_16bit() {
+        let input = block(&[(1000, 17), (0xFC18, 17)]);
+        assert_eq!(decompress(&input, 2, true, false), vec![1000.0 / 32767.0, 0.0]);
+    }
+
+    #[test]
+    fn huge_length() {
+        let input = block(&[(0x001, 9)]);
+        assert!(compressed_sample::<VerboseError<_>>(&input, u32::MAX.try_into().unwrap(), false, false).is_err());
+        assert!(compressed_sample::<VerboseError<_>>(&input, usize::MAX, true, true).is_err());
+    }
+}
@@ -0,0 +1,201 @@
+use super::util::Cast;
+use crate::data::*;
+use crate::error::ContextError;
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use core::mem::size_of;
+use nom::error::ParseError;
+use nom::Err;
+
+
+/// Offset of the pattern count in the module header
+const PATTERN_COUNT_FIELD: usize = 0x26;
+
+/// Offset of the message length in the module header
+const MESSAGE_LENGTH_FIELD: usize = 0x36;
+
+
+/// Options for parsing files
+///
+/// The sizes of the parts of a module are read from the file itself, a small crafted file can
+/// claim gigabytes of sample data or thousands of patterns sharing the same packed data. When
+/// parsing untrusted input the limits stop the parser before it allocates the memory, exceeding
+/// any of them fails the parsing. The default options have no limits.
+///
+/// ```
+/// # use ittech::parser::ParseOptions;
+/// let options = ParseOptions {
+///     max_sample_length: 1 << 22,
+///     max_allocation: 64 << 20,
+///     ..ParseOptions::default()
+/// };
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Maximum length of a single sample in samples
+    pub max_sample_length: u32,
+
+    /// Maximum number of patterns in the module
+    pub max_patterns: u16,
+
+    /// Maximum length of the song message in bytes
+    pub max_message_length: u16,
+
+    /// Maximum number of bytes allocated for the decoded sample data and pattern rows
+    ///
+    /// Counts the memory taken by the decoded data, the parts copied from the input like names or
+    /// the original encoding of compressed samples are bounded by the size of the input and are
+    /// not counted.
+    pub max_allocation: usize,
+
+    /// Encoding of the song message
+    ///
+    /// The message is decoded and its line breaks are converted to `\n`, see
+    /// [`Encoding::decode_message`]. If `None` the message is read as UTF-8 with the line breaks
+    /// kept as they are in the file.
+    pub message_encoding: Option<Encoding>,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        ParseOptions {
+            max_sample_length: u32::MAX,
+            max_patterns: u16::MAX,
+            max_message_length: u16::MAX,
+            max_allocation: usize::MAX,
+            message_encoding: None,
+        }
+    }
+}
+
+impl ParseOptions {
+    /// Decodes the song message.
+    pub(crate) fn message(&self, bytes: &[u8]) -> String {
+        match self.message_encoding {
+            Some(encoding) => encoding.decode_message(bytes),
+            None => String::from_utf8_lossy(bytes).into_owned(),
+        }
+    }
+}
+
+
+/// Checks the parts of a module against the [`ParseOptions`] as they are parsed
+#[derive(Clone)]
+pub(crate) struct Limits {
+    options: ParseOptions,
+    allocated: usize,
+}
+
+impl Limits {
+    pub(crate) fn new(options: ParseOptions) -> Limits {
+        Limits { options, allocated: 0 }
+    }
+
+    /// Checks the module header, `input` starts with the header.
+    pub(crate) fn header<'i, E>(&self, input: &'i [u8], header: &ModuleHeader) -> Result<(), Err<E>>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+    {
+        at_most(&input[PATTERN_COUNT_FIELD..], header.pattern_offsets.len(), self.options.max_patterns.into(), "patterns")?;
+        at_most(
+            &input[MESSAGE_LENGTH_FIELD..],
+            header.message_length.into(),
+            self.options.max_message_length.into(),
+            "bytes of message",
+        )
+    }
+
+    /// Checks the length of a sample before its data is decoded, `input` starts with the data.
+    pub(crate) fn sample<'i, E>(&mut self, input: &'i [u8], header: &SampleHeader) -> Result<(), Err<E>>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+    {
+        let flags = header.flags;
+        if !flags.contains(SampleFlags::DATA_PRESENT) || flags.contains(SampleFlags::OPL_INSTRUMENT) {
+            return Ok(());
+        }
+        at_most(input, header.data_length.cast(), self.options.max_sample_length.cast(), "samples")?;
+        self.allocate(input, header.data_length.cast::<usize>().saturating_mul(size_of::<f32>()))
+    }
+
+    /// Counts the rows of a parsed pattern, `input` starts with the pattern.
+    pub(crate) fn pattern<'i, E>(&mut self, input: &'i [u8], pattern: &Pattern) -> Result<(), Err<E>>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+    {
+        let commands = pattern.rows.iter().map(|row| row.iter().count()).sum::<usize>();
+        let size = pattern.rows.len() * size_of::<Row>() + commands * size_of::<(Channel, Command)>();
+        self.allocate(input, size)
+    }
+
+    fn allocate<'i, E>(&mut self, input: &'i [u8], size: usize) -> Result<(), Err<E>>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+    {
+        let allocated = self.allocated.saturating_add(size);
+        at_most(input, allocated, self.options.max_allocation, "bytes of decoded data")?;
+        self.allocated = allocated;
+        Ok(())
+    }
+}
+
+fn at_most<'i, E>(input: &'i [u8], value: usize, max: usize, what: &'static str) -> Result<(), Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    if value <= max {
+        Ok(())
+    } else {
+        Err(Err::Error(E::expected(
+            input,
+            Cow::Owned(format!("at most {} {}", max, what)),
+            Cow::Owned(format!("{} {}", value, what)),
+        )))
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::{ParseFailure, ReadError, VerboseError};
+    use crate::parser::{module_file_with, read_module_file_with};
+    use crate::writer::WriteOptions;
+    use alloc::vec::Vec;
+    use std::io::Cursor;
+
+    #[test]
+    fn limits() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = crate::parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.samples[0].data = Some(vec![0.0; 1000].into());
+        let mut file = Vec::new();
+        module.write_to_with(&mut file, WriteOptions { compress_samples: true, ..WriteOptions::default() }).unwrap();
+
+        let failure = |options: ParseOptions| {
+            let parsed = module_file_with::<VerboseError<&[u8]>>(&file, options).map(|_| ());
+            let failure = ParseFailure::new(&file, parsed.unwrap_err());
+            match read_module_file_with(Cursor::new(&file), options) {
+                Err(ReadError::Parse(read)) => assert_eq!(read.to_string(), failure.to_string()),
+                other => panic!("expected a parse failure, got {:?}", other.map(|_| ())),
+            }
+            failure
+        };
+        assert!(module_file_with::<VerboseError<&[u8]>>(&file, ParseOptions::default()).is_ok());
+
+        let sample = failure(ParseOptions { max_sample_length: 999, ..ParseOptions::default() });
+        assert_eq!(sample.context, ["sample 1"]);
+        assert_eq!(sample.expected.as_deref(), Some("at most 999 samples"));
+        assert_eq!(sample.found.as_deref(), Some("1000 samples"));
+
+        let patterns = failure(ParseOptions { max_patterns: 0, ..ParseOptions::default() });
+        assert_eq!((patterns.offset, patterns.found.as_deref()), (0x26, Some("1 patterns")));
+
+        // The pattern rows are counted first, the sample data doesn't fit after them.
+        let allocation = failure(ParseOptions { max_allocation: 4000, ..ParseOptions::default() });
+        assert_eq!(allocation.context, ["sample 1"]);
+        let allocation = failure(ParseOptions { max_allocation: 100, ..ParseOptions::default() });
+        assert_eq!(allocation.context, ["pattern 0"]);
+    }
+}
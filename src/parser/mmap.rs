@@ -0,0 +1,119 @@
+//! Parsing from memory mapped files
+
+use super::*;
+use crate::error::{ParseFailure, ReadError, VerboseError};
+use core::ops::{Deref, DerefMut};
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::path::Path;
+
+
+/// Module parsed from a memory mapped file, see [`Module::open_mmap`]
+///
+/// Dereferences to the module. The samples are left in the file until they are loaded, the
+/// mapping is kept for loading them and only the pages which are read are loaded by the system.
+pub struct MappedModule {
+    module: Module,
+    map: Mmap,
+}
+
+impl Module {
+    /// Maps the Impulse Tracker module file (.it) into memory and parses it without decoding the
+    /// sample data
+    ///
+    /// The module is parsed by [`module_headers`] directly from the mapping, the sample data is
+    /// decoded only when it's loaded by [`MappedModule::load_sample`] or
+    /// [`MappedModule::into_module`]. Scanning the metadata of many modules this way doesn't read
+    /// the sample data from the disk at all.
+    ///
+    /// The file must not be changed while it's mapped, changes made by other processes are seen
+    /// by the parser and the loading of the samples.
+    ///
+    /// # Errors
+    ///
+    /// Errors of opening and mapping the file are returned as [`ReadError::Io`], parse errors as
+    /// [`ReadError::Parse`].
+    pub fn open_mmap(path: impl AsRef<Path>) -> Result<MappedModule, ReadError> {
+        let file = File::open(path)?;
+        // SAFETY: The mapping is only read, the caller is told not to change the file.
+        let map = unsafe { Mmap::map(&file)? };
+        let module = module_headers::<VerboseError<&[u8]>>(&map).map_err(|e| ReadError::Parse(ParseFailure::new(&map, e)))?;
+        Ok(MappedModule { module, map })
+    }
+}
+
+impl MappedModule {
+    /// Returns the content of the mapped file.
+    pub fn data(&self) -> &[u8] {
+        &self.map
+    }
+
+    /// Decodes the data of the sample from the mapping, see [`Sample::load_data`].
+    ///
+    /// Does nothing if the sample was already loaded.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`ReadError::Io`] if there is no such sample and with [`ReadError::Parse`] if
+    /// the data can't be decoded.
+    pub fn load_sample(&mut self, index: usize) -> Result<&Sample, ReadError> {
+        let MappedModule { module, map } = self;
+        let sample = module.samples.as_mut_slice().get_mut(index).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, format!("module has no sample {}", index))
+        })?;
+        sample.load_data::<VerboseError<&[u8]>>(map).map_err(|e| ReadError::Parse(ParseFailure::new(map, e)))?;
+        Ok(sample)
+    }
+
+    /// Decodes the data of every sample which isn't loaded yet and unmaps the file.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`MappedModule::load_sample`].
+    pub fn into_module(mut self) -> Result<Module, ReadError> {
+        for index in 0..self.module.samples.len() {
+            self.load_sample(index)?;
+        }
+        Ok(self.module)
+    }
+}
+
+impl Deref for MappedModule {
+    type Target = Module;
+
+    fn deref(&self) -> &Module {
+        &self.module
+    }
+}
+
+impl DerefMut for MappedModule {
+    fn deref_mut(&mut self) -> &mut Module {
+        &mut self.module
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn open_mmap() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let expected = module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let path = std::env::temp_dir().join(format!("ittech-mmap-{}.it", std::process::id()));
+        std::fs::write(&path, DATA).unwrap();
+
+        let mut mapped = Module::open_mmap(&path).unwrap();
+        assert_eq!(format!("{:?}", mapped.name), format!("{:?}", expected.name));
+        assert!(mapped.samples.iter().all(|sample| sample.data.is_none() || sample.deferred.is_none()));
+        assert_eq!(mapped.load_sample(0).unwrap().data, expected.samples[0].data);
+        assert!(mapped.load_sample(mapped.samples.len()).is_err());
+        let module = mapped.into_module().unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(format!("{:?}", module), format!("{:?}", expected));
+    }
+}
@@ -0,0 +1,361 @@
+//! MMCMP (ziRCONia) unpacking
+//!
+//! MMCMP packs a whole module file into blocks, each block fills one or more ranges of the
+//! unpacked file called sub-blocks. Blocks are stored either verbatim or as a bitstream of codes
+//! of a variable bit width, the codes are either 8-bit values translated through a table stored
+//! with the block or 16-bit values, both optionally stored as differences to the previous value.
+//! The code width is changed by special codes in the stream.
+//!
+//! The implementation follows `load_mmcmp.cpp` from OpenMPT.
+
+use super::*;
+use compression::BitReader;
+use core::ops::Range;
+
+
+/// Magic number at the start of MMCMP packed files
+pub(super) const MAGIC: &[u8] = b"ziRCONia";
+
+/// Largest unpacked size accepted, the same limit as OpenMPT uses
+const MAX_SIZE: u32 = 0x0800_0000;
+
+/// Block is packed, otherwise it's stored verbatim
+const BLOCK_PACKED: u16 = 1 << 0;
+
+/// Values are stored as differences to the previous value
+const BLOCK_DELTA: u16 = 1 << 1;
+
+/// Values are 16-bit, otherwise 8-bit
+const BLOCK_16BIT: u16 = 1 << 2;
+
+/// 16-bit values are stored without flipping the sign bit
+const BLOCK_ABS16: u16 = 1 << 9;
+
+/// Smallest code announcing a width change for every code width of 8-bit blocks
+const COMMANDS_8BIT: [u32; 8] = [0x01, 0x03, 0x07, 0x0F, 0x1E, 0x3C, 0x78, 0xF8];
+
+/// Number of bits read after the width change code of 8-bit blocks
+const FETCH_8BIT: [u8; 8] = [3, 3, 3, 3, 2, 1, 0, 0];
+
+/// Smallest code announcing a width change for every code width of 16-bit blocks
+const COMMANDS_16BIT: [u32; 16] = [
+    0x01, 0x03, 0x07, 0x0F, 0x1E, 0x3C, 0x78, 0xF0,
+    0x1F0, 0x3F0, 0x7F0, 0xFF0, 0x1FF0, 0x3FF0, 0x7FF0, 0xFFF0,
+];
+
+/// Number of bits read after the width change code of 16-bit blocks
+const FETCH_16BIT: [u8; 16] = [4, 4, 4, 4, 3, 2, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+
+
+/// Returns `true` if the input starts with the MMCMP magic number.
+pub(super) fn is_mmcmp(input: &[u8]) -> bool {
+    input.starts_with(MAGIC)
+}
+
+/// Unpacks MMCMP (ziRCONia) packed file
+///
+/// Returns the unpacked file, usually an IT module which can be parsed by [`module_file`]. The
+/// parsers detect packed modules and unpack them on their own when the feature `mmcmp` is
+/// enabled, unpacking explicitly helps to locate errors in the unpacked module.
+///
+/// Errors are located in the packed input.
+pub fn unpack_mmcmp<'i, E>(input: &'i [u8]) -> Result<Vec<u8>, Err<E>>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    context!(unpack, "unpacking MMCMP")(input).map(|(_, unpacked)| unpacked)
+}
+
+fn unpack<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Vec<u8>, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, _) = magic(MAGIC)(input)?;
+    let (rest, header_size) = le_u16(rest)?;
+    if header_size != 14 {
+        bail!(rest, "MMCMP header size {} is not 14", header_size);
+    }
+    let (rest, _version) = le_u16(rest)?;
+    let (rest, blocks) = le_u16(rest)?;
+    let (rest, size) = le_u32(rest)?;
+    if size > MAX_SIZE {
+        bail!(rest, "unpacked size {:#x} is over the limit of {:#x} bytes", size, MAX_SIZE);
+    }
+    let (_, table) = le_u32(rest)?;
+
+    let table = table.cast::<usize>();
+    if table >= input.len() {
+        return Err(past_end(input, table));
+    }
+    let (_, offsets) = context!(count(le_u32, usize::from(blocks)), "reading block table")(&input[table..])?;
+
+    let mut unpacked = vec![0; size.cast()];
+    for (index, offset) in offsets.into_iter().enumerate() {
+        let offset = offset.cast::<usize>();
+        if offset >= input.len() {
+            return Err(past_end(input, offset));
+        }
+        context!(|input| block(input, &mut unpacked), "block {}", index)(&input[offset..])?;
+    }
+    Ok((&input[input.len()..], unpacked))
+}
+
+/// Unpacks one block into its sub-blocks of the output.
+fn block<'i, E>(input: &'i [u8], output: &mut [u8]) -> IResult<&'i [u8], (), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    let (rest, _unpacked_size) = le_u32(input)?;
+    let (rest, packed_size) = le_u32(rest)?;
+    let (rest, _checksum) = le_u32(rest)?;
+    let (rest, sub_blocks) = le_u16(rest)?;
+    let (rest, flags) = le_u16(rest)?;
+    let (rest, table_size) = le_u16(rest)?;
+    let (rest, width) = le_u16(rest)?;
+    let (rest, sub_blocks) = context!(
+        count(tuple((le_u32, le_u32)), usize::from(sub_blocks)),
+        "reading sub-blocks",
+    )(rest)?;
+
+    let mut ranges = Vec::with_capacity(sub_blocks.len());
+    for (position, size) in sub_blocks {
+        let start = position.cast::<usize>();
+        let end = start.saturating_add(size.cast());
+        if end > output.len() {
+            bail!(input, "sub-block {:#x}..{:#x} is past the end of {:#x} bytes", start, end, output.len());
+        }
+        ranges.push(start..end);
+    }
+
+    if flags & BLOCK_PACKED == 0 {
+        let mut rest = rest;
+        for range in ranges {
+            let (next, data) = context!(take(range.len()), "reading stored data")(rest)?;
+            output[range].copy_from_slice(data);
+            rest = next;
+        }
+        return Ok((rest, ()));
+    }
+
+    let packed_size = packed_size.cast::<usize>();
+    let table_size = usize::from(table_size);
+    if table_size > packed_size {
+        bail!(input, "translation table of {} bytes is larger than the block of {}", table_size, packed_size);
+    }
+    let (rest, packed) = context!(take(packed_size), "reading packed data")(rest)?;
+    let (table, data) = packed.split_at(table_size);
+    let result = if flags & BLOCK_16BIT != 0 {
+        unpack_16bit(data, width, flags, ranges, output)
+    } else {
+        unpack_8bit(data, table, width, flags, ranges, output)
+    };
+    match result {
+        Ok(()) => Ok((rest, ())),
+        Err(message) => bail!(input, message),
+    }
+}
+
+/// Decodes the bitstream of 8-bit values translated by the table.
+fn unpack_8bit(
+    data: &[u8],
+    table: &[u8],
+    width: u16,
+    flags: u16,
+    ranges: Vec<Range<usize>>,
+    output: &mut [u8],
+) -> Result<(), &'static str> {
+    let mut reader = BitReader { input: data, position: 0 };
+    let mut read = |width: u8| reader.read(width).ok_or("packed block ended early");
+    let mut width = usize::from(width);
+    if width >= COMMANDS_8BIT.len() {
+        return Err("invalid code width in packed block");
+    }
+    let mut previous = 0u8;
+    let mut positions = ranges.into_iter().flatten();
+    let mut position = positions.next();
+    while let Some(offset) = position {
+        let code = read(u8::try_from(width).unwrap() + 1)?;
+        let value = if code >= COMMANDS_8BIT[width] {
+            let fetch = FETCH_8BIT[width];
+            let new_width = read(fetch)? + ((code - COMMANDS_8BIT[width]) << fetch);
+            if new_width.cast::<usize>() != width {
+                width = (new_width & 0x07).cast();
+                continue;
+            }
+            match read(3)? {
+                0x07 if read(1)? != 0 => break,
+                0x07 => 0xFF,
+                low => 0xF8 + low,
+            }
+        } else {
+            code
+        };
+        let mut byte = *table.get(value.cast::<usize>()).ok_or("value missing in the translation table")?;
+        if flags & BLOCK_DELTA != 0 {
+            byte = byte.wrapping_add(previous);
+            previous = byte;
+        }
+        output[offset] = byte;
+        position = positions.next();
+    }
+    Ok(())
+}
+
+/// Decodes the bitstream of 16-bit values, the values are stored with the sign in the lowest bit.
+fn unpack_16bit(
+    data: &[u8],
+    width: u16,
+    flags: u16,
+    ranges: Vec<Range<usize>>,
+    output: &mut [u8],
+) -> Result<(), &'static str> {
+    let mut reader = BitReader { input: data, position: 0 };
+    let mut read = |width: u8| reader.read(width).ok_or("packed block ended early");
+    let mut width = usize::from(width);
+    if width >= COMMANDS_16BIT.len() {
+        return Err("invalid code width in packed block");
+    }
+    let mut previous = 0u16;
+    // An odd byte at the end of a sub-block is left empty.
+    let mut positions = ranges.into_iter().flat_map(|range| (range.start..range.end - range.len() % 2).step_by(2));
+    let mut position = positions.next();
+    while let Some(offset) = position {
+        let code = read(u8::try_from(width).unwrap() + 1)?;
+        let value = if code >= COMMANDS_16BIT[width] {
+            let fetch = FETCH_16BIT[width];
+            let new_width = read(fetch)? + ((code - COMMANDS_16BIT[width]) << fetch);
+            if new_width.cast::<usize>() != width {
+                width = (new_width & 0x0F).cast();
+                continue;
+            }
+            match read(4)? {
+                0x0F if read(1)? != 0 => break,
+                0x0F => 0xFFFF,
+                low => 0xFFF0 + low,
+            }
+        } else {
+            code
+        };
+        let mut value = if value & 1 != 0 {
+            0u16.wrapping_sub(u16::try_from((value + 1) >> 1).unwrap())
+        } else {
+            u16::try_from(value >> 1).unwrap()
+        };
+        if flags & BLOCK_DELTA != 0 {
+            value = value.wrapping_add(previous);
+            previous = value;
+        } else if flags & BLOCK_ABS16 == 0 {
+            value ^= 0x8000;
+        }
+        output[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        position = positions.next();
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+
+    /// Packs the values of the given bit widths into a bitstream.
+    fn bits(values: &[(u32, u8)]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        let mut position = 0;
+        for &(value, width) in values {
+            for bit in 0..width {
+                if position % 8 == 0 {
+                    bytes.push(0);
+                }
+                let last = bytes.len() - 1;
+                bytes[last] |= u8::try_from(value >> bit & 1).unwrap() << (position % 8);
+                position += 1;
+            }
+        }
+        bytes
+    }
+
+    /// Builds a block with the sub-blocks and the data (including the translation table).
+    fn block(flags: u16, table_size: u16, width: u16, sub_blocks: &[(u32, u32)], data: &[u8]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend_from_slice(&0u32.to_le_bytes());
+        block.extend_from_slice(&u32::try_from(data.len()).unwrap().to_le_bytes());
+        block.extend_from_slice(&0u32.to_le_bytes());
+        for value in [u16::try_from(sub_blocks.len()).unwrap(), flags, table_size, width] {
+            block.extend_from_slice(&value.to_le_bytes());
+        }
+        for &(position, size) in sub_blocks {
+            block.extend_from_slice(&position.to_le_bytes());
+            block.extend_from_slice(&size.to_le_bytes());
+        }
+        block.extend_from_slice(data);
+        block
+    }
+
+    /// Builds a packed file of the unpacked size from the blocks.
+    fn packed(size: usize, blocks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = MAGIC.to_vec();
+        file.extend_from_slice(&14u16.to_le_bytes());
+        file.extend_from_slice(&0x1310u16.to_le_bytes());
+        file.extend_from_slice(&u16::try_from(blocks.len()).unwrap().to_le_bytes());
+        file.extend_from_slice(&u32::try_from(size).unwrap().to_le_bytes());
+        file.extend_from_slice(&24u32.to_le_bytes());
+        file.extend_from_slice(&[0, 0]);
+        let mut offset = 24 + 4 * blocks.len();
+        for block in blocks {
+            file.extend_from_slice(&u32::try_from(offset).unwrap().to_le_bytes());
+            offset += block.len();
+        }
+        for block in blocks {
+            file.extend_from_slice(block);
+        }
+        file
+    }
+
+    #[test]
+    fn unpack_module() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let (half, end) = (DATA.len() / 2, DATA.len());
+
+        // 7 bit codes, 0xFF announces the values from 0xF8 up or keeps the width.
+        let codes = DATA[half..].iter().chain(&DATA[..half]).flat_map(|&byte| match byte {
+            0..=0xF7 => vec![(u32::from(byte), 8)],
+            0xFF => vec![(0xFF, 8), (7, 3), (0, 1)],
+            _ => vec![(0xFF, 8), (u32::from(byte - 0xF8), 3)],
+        });
+        let mut data = (0..=255).collect::<Vec<u8>>();
+        data.extend(bits(&codes.collect::<Vec<_>>()));
+        let sub_blocks = [(half.cast(), (end - half).cast()), (0, half.cast())];
+        let file = packed(end, &[block(BLOCK_PACKED, 256, 7, &sub_blocks, &data)]);
+
+        assert_eq!(unpack_mmcmp::<VerboseError<&[u8]>>(&file).unwrap(), DATA);
+        let module = module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let unpacked = module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(format!("{:?}", unpacked), format!("{:?}", module));
+        let (lenient, warnings) = parse_lenient(&file).unwrap();
+        assert!(warnings.is_empty());
+        assert_eq!(format!("{:?}", lenient), format!("{:?}", module));
+        #[cfg(feature = "std")]
+        assert_eq!(format!("{:?}", Module::read(std::io::Cursor::new(&file)).unwrap()), format!("{:?}", module));
+
+        let truncated = &file[..file.len() - 10];
+        assert!(unpack_mmcmp::<VerboseError<&[u8]>>(truncated).is_err());
+        assert!(module_file::<VerboseError<&[u8]>>(truncated).is_err());
+    }
+
+    #[test]
+    fn unpack_16bit() {
+        // Values 0, 1, -1 stored with the sign in the lowest bit, then one stored byte.
+        let data = bits(&[(0, 16), (2, 16), (1, 16)]);
+        let file = packed(7, &[
+            block(BLOCK_PACKED | BLOCK_16BIT, 0, 15, &[(0, 6)], &data),
+            block(0, 0, 0, &[(6, 1)], &[0x42]),
+        ]);
+        assert_eq!(unpack_mmcmp::<VerboseError<&[u8]>>(&file).unwrap(), [0x00, 0x80, 0x01, 0x80, 0xFF, 0x7F, 0x42]);
+
+        let delta = bits(&[(2, 16), (2, 16), (3, 16)]);
+        let file = packed(6, &[block(BLOCK_PACKED | BLOCK_16BIT | BLOCK_DELTA, 0, 15, &[(0, 6)], &delta)]);
+        assert_eq!(unpack_mmcmp::<VerboseError<&[u8]>>(&file).unwrap(), [1, 0, 2, 0, 0, 0]);
+    }
+}
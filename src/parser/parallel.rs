@@ -0,0 +1,63 @@
+//! Parallel decoding of patterns and sample data
+//!
+//! Patterns and samples are addressed by the offset tables of the header and decoded
+//! independently, so the parts are decoded on the rayon thread pool. The parts are decoded with
+//! [`VerboseError`] to keep the error type `Send`, any failure returns `None` and the caller
+//! decodes them again one by one to report the error the same way the serial parser does.
+
+use super::*;
+use rayon::prelude::*;
+
+
+/// Decodes the patterns at the offsets, offset `0` is an empty pattern.
+pub(super) fn patterns(input: &[u8], offsets: &[u32]) -> Option<Vec<Pattern>> {
+    offsets.par_iter()
+        .map(|&offset| match offset.cast::<usize>() {
+            0 => Some(empty_pattern()),
+            offset => {
+                let data = input.get(offset..)?;
+                pattern::<VerboseError<&[u8]>>(data).ok().map(|(_, pattern)| pattern)
+            }
+        })
+        .collect()
+}
+
+/// Decodes the data of the samples, or defers it if `load_samples` is off and the data can be
+/// loaded later.
+pub(super) fn samples(input: &[u8], headers: &[SampleHeader], load_samples: bool) -> Option<Vec<Sample>> {
+    headers.par_iter()
+        .map(|header| {
+            if load_samples || !is_deferrable(header) {
+                sample_data::<VerboseError<&[u8]>>(header.clone(), input).ok()
+            } else {
+                Some(deferred_sample(header.clone()))
+            }
+        })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn same_as_serial() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        assert_eq!(patterns(DATA, &[0, 0]).unwrap().len(), 2);
+        assert!(patterns(DATA, &[u32::try_from(DATA.len()).unwrap()]).is_none());
+
+        let (_, header) = module_header::<VerboseError<&[u8]>>(DATA).unwrap();
+        let mut limits = Limits::new(ParseOptions::default());
+        let serial = super::super::patterns::<VerboseError<&[u8]>>(DATA, &header.pattern_offsets, &mut limits).unwrap();
+        let parallel = patterns(DATA, &header.pattern_offsets).unwrap();
+        assert_eq!(format!("{:?}", parallel), format!("{:?}", serial));
+
+        let headers = header.sample_offsets.iter()
+            .map(|&offset| sample_header::<VerboseError<&[u8]>>(&DATA[offset.cast::<usize>()..]).unwrap().1)
+            .collect::<Vec<_>>();
+        let serial = super::super::samples::<VerboseError<&[u8]>>(DATA, headers.clone(), true, &mut limits).unwrap();
+        let parallel = samples(DATA, &headers, true).unwrap();
+        assert_eq!(format!("{:?}", parallel), format!("{:?}", serial));
+    }
+}
@@ -1,4 +1,6 @@
 use super::*;
+use core::cell::Cell;
+use core::marker::PhantomData;
 
 
 bitflags! {
@@ -9,7 +11,7 @@ bitflags! {
     /// - `READ_*` masks say the parser should read the value from the input
     /// - `LAST_*` masks say the last read value for that channel should be reused
     /// - if no mask is present the command does not contain that sub-command.
-    struct Mask: u8 {
+    pub(crate) struct Mask: u8 {
         const READ_NOTE = 1 << 0;
         const READ_INSTRUMENT = 1 << 1;
         const READ_VOLUME = 1 << 2;
@@ -22,7 +24,7 @@ bitflags! {
 }
 
 bitflags! {
-    struct ChannelMask: u8 {
+    pub(crate) struct ChannelMask: u8 {
         /// Top bit
         ///
         /// If present the parser should reuse the last command [`Mask`] for channel, otherwise it
@@ -36,31 +38,158 @@ bitflags! {
     }
 }
 
+/// Problems skipped while parsing, the position from the start of the part and what was done
+type Fixes = Vec<(usize, String)>;
+
 /// Command parser state
 ///
 /// Holds the previous values for command mask and sub-commands for each channel.
 struct State {
-    last_maskvar: [Mask; 64],
-    last_note: [Option<NoteCmd>; 64],
-    last_instrument: [Option<InstrumentId>; 64],
-    last_volume: [Option<VolumeCmd>; 64],
-    last_effect: [Option<EffectCmd>; 64],
+    last_maskvar: [Mask; MAX_CHANNELS],
+    last_note: [Option<NoteCmd>; MAX_CHANNELS],
+    last_instrument: [Option<InstrumentId>; MAX_CHANNELS],
+    last_volume: [Option<VolumeCmd>; MAX_CHANNELS],
+    last_effect: [Option<EffectCmd>; MAX_CHANNELS],
+
+    /// Last instrument, volume, effect and effect parameter bytes as stored, for parameter control
+    /// events which reuse them
+    last_raw: [[u8; 4]; MAX_CHANNELS],
+
+    /// Unknown effect codes which were skipped, with the length of the input left at the code
+    unknown_effects: Vec<(usize, u8)>,
 }
 
 impl Default for State {
     fn default() -> State {
         State {
-            last_maskvar: [Mask::empty(); 64],
-            last_note: [None; 64],
-            last_instrument: [None; 64],
-            last_volume: [None; 64],
-            last_effect: [None; 64],
+            last_maskvar: [Mask::empty(); MAX_CHANNELS],
+            last_note: [None; MAX_CHANNELS],
+            last_instrument: [None; MAX_CHANNELS],
+            last_volume: [None; MAX_CHANNELS],
+            last_effect: [None; MAX_CHANNELS],
+            last_raw: [[0; 4]; MAX_CHANNELS],
+            unknown_effects: Vec::new(),
         }
     }
 }
 
 
+impl Pattern {
+    /// Parses a packed pattern decoding at most `max_rows` rows.
+    ///
+    /// Meant for previews and bulk scanning where unpacking full patterns would be wasteful. If the
+    /// pattern has more rows than `max_rows` the rest is skipped without decoding and the result is
+    /// marked as [`Pattern::truncated`].
+    ///
+    /// The input must start with the pattern header (as referenced by the pattern offset in the
+    /// module header), the returned remaining input starts right after the packed pattern data.
+    pub fn parse_limited<'i, E>(input: &'i [u8], max_rows: u16) -> IResult<&'i [u8], Pattern, E>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+    {
+        pattern_rows(input, Some(max_rows))
+    }
+
+    /// Decodes a packed pattern command by command without building the [`Pattern`].
+    ///
+    /// Meant for analysis over many files where the rows are only looked at once. The iterator
+    /// returns the row index, channel and command of every command in the pattern in the order
+    /// they are stored, that is by row and within a row in the order the tracker wrote them.
+    /// Commands are decoded the same way as by the eager parser, the only state kept is the last
+    /// values of every channel.
+    ///
+    /// The input must start with the pattern header like for [`Pattern::parse_limited`], the
+    /// returned remaining input starts right after the packed pattern data. The iterator stops
+    /// after the last row of the pattern or the first error, which is returned as its last item.
+    pub fn parse_events<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], PatternEvents<'i, E>, E>
+    where
+        E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+    {
+        let (input, length) = le_u16(input)?;
+        let (input, rows) = le_u16(input)?;
+        let (input, _padding) = take(4usize)(input)?;
+        let (rest, input) = take(length)(input)?;
+        let events = PatternEvents {
+            input,
+            row: 0,
+            rows: usize::from(rows),
+            state: State::default(),
+            failed: false,
+            error: PhantomData,
+        };
+        Ok((rest, events))
+    }
+}
+
+/// Commands of a packed pattern decoded on the fly, see [`Pattern::parse_events`]
+pub struct PatternEvents<'i, E> {
+    input: &'i [u8],
+    row: usize,
+    rows: usize,
+    state: State,
+    failed: bool,
+    error: PhantomData<E>,
+}
+
+impl<'i, E> Iterator for PatternEvents<'i, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    type Item = Result<(usize, Channel, Command), Err<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed && self.row < self.rows {
+            let result = match self.input.split_first() {
+                Some((0, rest)) => {
+                    self.input = rest;
+                    self.row += 1;
+                    continue;
+                }
+                Some(_) => command(&mut self.state)(self.input),
+                None => Err(Err::Error(error!(self.input, "pattern data ends before the last row"))),
+            };
+            return Some(match result {
+                Ok((rest, (channel, command))) => {
+                    self.input = rest;
+                    Ok((self.row, channel, command))
+                }
+                Err(error) => {
+                    self.failed = true;
+                    Err(error)
+                }
+            });
+        }
+        None
+    }
+}
+
 pub(super) fn pattern<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], Pattern, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    pattern_rows(input, None)
+}
+
+/// Parses a pattern and returns the problems which were skipped, for
+/// [`parse_lenient`](super::parse_lenient).
+///
+/// The problems are the unknown effect codes, with their position from the start of the pattern.
+pub(super) fn lenient_pattern<'i, E>(input: &'i [u8]) -> IResult<&'i [u8], (Pattern, Fixes), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    pattern_fixes(input, None)
+}
+
+fn pattern_rows<'i, E>(input: &'i [u8], max_rows: Option<u16>) -> IResult<&'i [u8], Pattern, E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+{
+    let (rest, (pattern, _)) = pattern_fixes(input, max_rows)?;
+    Ok((rest, pattern))
+}
+
+fn pattern_fixes<'i, E>(input: &'i [u8], max_rows: Option<u16>) -> IResult<&'i [u8], (Pattern, Fixes), E>
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
 {
@@ -69,32 +198,82 @@ where
     let (input, _padding) = take(4usize)(input)?;
     let (rest, input) = take(length)(input)?;
 
+    let limit = max_rows.map_or(rows, |max_rows| max_rows.min(rows));
+    let truncated = limit < rows;
+
     let mut active_channels = ActiveChannels::empty();
     let mut state = State::default();
+    let mut scratch = Vec::new();
+    let row = &Cell::new(0);
+
+    let rows = count(
+        map(
+            context!(row_commands(&mut state, &mut scratch), "row {}", row.get()),
+            |commands: Row| {
+                row.set(row.get() + 1);
+                active_channels |= commands.iter().map(|(chan, _)| chan).collect();
+                commands
+            },
+        ),
+        limit.into(),
+    );
 
-    let (_empty, rows) = context!(
-        all_consuming(count(
-            map(
-                many_till(command(&mut state), tag(b"\0")),
-                |(commands, _)| {
-                    active_channels |= commands.iter().map(|(chan, _)| *chan).collect();
-                    Row::from_vec(commands)
-                },
-            ),
-            rows.into(),
-        )),
-        "in pattern",
-    )(input)?;
+    let (_, rows) = if truncated {
+        context!(rows, "in pattern")(input)?
+    } else {
+        context!(all_consuming(rows), "in pattern")(input)?
+    };
+
+    // The data starts after the 8 byte header.
+    let fixes = state.unknown_effects
+        .iter()
+        .map(|&(left, code)| (8 + input.len() - left, format!("effect code {:#04x} is out of range 0x01..=0x1A, skipped", code)))
+        .collect();
 
     Ok((
         rest,
-        Pattern {
-            active_channels,
-            rows,
-        },
+        (
+            Pattern {
+                active_channels,
+                rows,
+                truncated,
+                highlight: None,
+            },
+            fixes,
+        ),
     ))
 }
 
+/// Parses the commands of a row up to the zero byte ending it.
+///
+/// The commands are collected in `scratch` which is reused for all rows of the pattern, so the
+/// parsing itself doesn't allocate, only the finished row is allocated with its exact size.
+fn row_commands<'i, 's, E>(
+    state: &'s mut State,
+    scratch: &'s mut Vec<(Channel, Command)>,
+) -> impl FnMut(&'i [u8]) -> IResult<&'i [u8], Row, E> + 's
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
+    'i: 's,
+{
+    move |mut input: &'i [u8]| {
+        scratch.clear();
+        loop {
+            if let Some((0, rest)) = input.split_first() {
+                return Ok((rest, Row::from_slice(scratch)));
+            }
+            match command(state)(input) {
+                Ok((rest, command)) => {
+                    scratch.push(command);
+                    input = rest;
+                }
+                Err(Err::Error(e)) => return Err(Err::Error(E::append(input, ErrorKind::ManyTill, e))),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
 fn command<'i, 's, E>(state: &'s mut State) -> impl FnMut(&'i [u8]) -> IResult<&'i [u8], (Channel, Command), E> + 's
 where
     E: ParseError<&'i [u8]> + ContextError<&'i [u8]> + 'i,
@@ -113,8 +292,8 @@ where
 
                     let channel_mask = ChannelMask::from_bits_truncate(channel_var);
                     let channel_num = channel_var & ChannelMask::CHANNEL_INDEX.bits();
-                    if !(1..=64).contains(&channel_num) {
-                        bail!(input, "value is out of range 1..=64");
+                    if !(1..=127).contains(&channel_num) {
+                        bail!(input, "value is out of range 1..=127");
                     }
                     let channel = Channel::from_u8_index(channel_num - 1);
 
@@ -128,6 +307,10 @@ where
             let (input, (note, instrument, volume, effect)) = context!(
                 |input| {
                     let (input, note) = note(state, channel, mask_var, input)?;
+                    if let Some(NoteCmd::ParamControl { smooth, .. }) = note {
+                        let (input, note) = param_control(state, channel, mask_var, smooth, input)?;
+                        return Ok((input, (Some(note), None, None, None)));
+                    }
                     let (input, instrument) = instrument(state, channel, mask_var, input)?;
                     let (input, volume) = volume(state, channel, mask_var, input)?;
                     let (input, effect) = effect(state, channel, mask_var, input)?;
@@ -183,6 +366,7 @@ fn note<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
             0 ..= 119 => NoteCmd::Play(note_var.try_into().unwrap()),
             255 => NoteCmd::Off,
             254 => NoteCmd::Cut,
+            252 | 251 => NoteCmd::ParamControl { smooth: note_var == 251, plugin: 0, param: 0, value: 0 },
             _ => NoteCmd::Fade,
         };
         state.last_note[channel.as_usize()] = Some(note);
@@ -202,6 +386,7 @@ fn instrument<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
 ) -> IResult<&'i [u8], Option<InstrumentId>, E> {
     if mask_var.contains(Mask::READ_INSTRUMENT) && !mask_var.contains(Mask::LAST_INSTRUMENT) {
         let (input, instrument) = context!(le_u8, "reading instrument id")(input)?;
+        state.last_raw[channel.as_usize()][0] = instrument;
         let instrument = match instrument {
             0 => None,
             1 ..= 99 => Some((instrument - 1).try_into().unwrap()),
@@ -227,21 +412,10 @@ fn volume<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
 ) -> IResult<&'i [u8], Option<VolumeCmd>, E> {
     if mask_var.contains(Mask::READ_VOLUME) && !mask_var.contains(Mask::LAST_VOLUME) {
         let (input, x) = le_u8(input)?;
-        let volume = match x {
-              0 ..=  64 => VolumeCmd::SetVolume(x.cast()),
-            128 ..= 192 => VolumeCmd::Panning((x - 128).cast()),
-             65 ..=  74 => VolumeCmd::FineVolumeUp((x > 65).then(|| (x - 65).cast())),
-             75 ..=  84 => VolumeCmd::FineVolumeDown((x > 75).then(|| (x - 75).cast())),
-             85 ..=  94 => VolumeCmd::VolumeSlideUp((x > 85).then(|| (x - 85).cast())),
-             95 ..= 104 => VolumeCmd::VolumeSlideDown((x > 95).then(|| (x - 95).cast())),
-            105 ..= 114 => VolumeCmd::PortamentoDown((x > 105).then(|| (x - 105).cast())),
-            115 ..= 124 => VolumeCmd::PortamentoUp((x > 115).then(|| (x - 115).cast())),
-            193 ..= 202 => VolumeCmd::TonePortamento((x > 193).then(|| (x - 193).cast())),
-            203 ..= 212 => VolumeCmd::Vibrato((x > 203).then(|| (x - 203).cast())),
-            _ => {
-                // There is a gap in between the intervals so we can't simply use `ranged`.
-                bail!(input, "value is not a valid volume");
-            },
+        state.last_raw[channel.as_usize()][1] = x;
+        let volume = match VolumeCmd::try_from(x) {
+            Ok(volume) => volume,
+            Err(_) => bail!(input, "value is not a valid volume"),
         };
         state.last_volume[channel.as_usize()] = Some(volume);
         Ok((input, Some(volume)))
@@ -263,10 +437,14 @@ fn effect<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
             context!(le_u8, "reading effect number"),
             context!(le_u8, "reading effect parameter"),
         ))(input)?;
+        state.last_raw[channel.as_usize()][2..].copy_from_slice(&[effect, param]);
 
         let effect = if effect == 0x00 {
             None
         } else {
+            if effect > 26 {
+                state.unknown_effects.push((input.len(), effect));
+            }
             parse_effect(effect, param)
         };
         state.last_effect[channel.as_usize()] = effect;
@@ -278,6 +456,46 @@ fn effect<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
     }
 }
 
+/// Reads the columns of a parameter control event, which are stored as bytes without their usual
+/// meaning, see [`NoteCmd::ParamControl`].
+///
+/// The memory of the columns is shared with the other commands of the channel, the instrument,
+/// volume and effect are remembered as they would be by a regular command.
+fn param_control<'i, E: ParseError<&'i [u8]> + ContextError<&'i [u8]>>(
+    state: &mut State,
+    channel: Channel,
+    mask_var: Mask,
+    smooth: bool,
+    input: &'i [u8],
+) -> IResult<&'i [u8], NoteCmd, E> {
+    let chan = channel.as_usize();
+    let mut raw = [0; 4];
+    let mut input = input;
+    let columns = [
+        (Mask::READ_INSTRUMENT, Mask::LAST_INSTRUMENT, 0..1),
+        (Mask::READ_VOLUME, Mask::LAST_VOLUME, 1..2),
+        (Mask::READ_EFFECT, Mask::LAST_EFFECT, 2..4),
+    ];
+    for (read, last, range) in columns {
+        if mask_var.contains(read) && !mask_var.contains(last) {
+            let (rest, bytes) = context!(take(range.len()), "reading parameter control")(input)?;
+            state.last_raw[chan][range.clone()].copy_from_slice(bytes);
+            raw[range].copy_from_slice(bytes);
+            input = rest;
+        } else if mask_var.contains(last) {
+            raw[range.clone()].copy_from_slice(&state.last_raw[chan][range]);
+        }
+    }
+
+    let [plugin, param, high, low] = raw;
+    state.last_instrument[chan] = plugin.checked_sub(1).and_then(|id| InstrumentId::try_from(id).ok());
+    state.last_volume[chan] = VolumeCmd::try_from(param).ok();
+    // The value is not an effect, commands reusing the last effect after the event have none.
+    state.last_effect[chan] = None;
+    let value = u16::from_be_bytes([high, low]);
+    Ok((input, NoteCmd::ParamControl { smooth, plugin, param, value }))
+}
+
 /// Parse structured effect from raw effect number and parameter
 ///
 /// This function performs all the canonicalization and checking as described in the documentation
@@ -291,8 +509,9 @@ pub fn parse_effect(effect: u8, param: u8) -> Option<EffectCmd> {
 
     // Effects are numbered 0x1..=0x1A (or 1..=26 decimal), these numbers are represented
     // as capital leters in in tracker UI and documentation. We convert it to ascii char
-    // range here to make the large match statement more readable.
-    let effect_code = char::from(effect - 1 + b'A');
+    // range here to make the large match statement more readable, codes out of range fall through
+    // to the last arm.
+    let effect_code = effect.checked_sub(1).filter(|&code| code < 26).map_or('\0', |code| char::from(b'A' + code));
 
     // For more information on the values here, see the documentation for `EffectCmd`
     // and its child enums.
@@ -528,7 +747,7 @@ pub fn parse_effect(effect: u8, param: u8) -> Option<EffectCmd> {
         'Y' => EffectCmd::Panbrello((x > 0).then(|| x.cast()), (y > 0).then(|| y.cast())),
         'Z' => EffectCmd::Midi(param),
         _ => {
-            info!(code = effect, "invalid effect, code out of range 0x0..=0x1A, skipping");
+            info!(code = effect, "invalid effect, code out of range 0x1..=0x1A, skipping");
             return None;
         },
     })
@@ -696,4 +915,27 @@ mod test {
 
         assert_eq!(effects, alphabet);
     }
+
+    #[test]
+    fn parse_events() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let u16_at = |offset: usize| usize::from(u16::from_le_bytes([DATA[offset], DATA[offset + 1]]));
+        // The pattern offsets follow the orders and the instrument and sample offsets.
+        let field = 0xC0 + u16_at(0x20) + 4 * u16_at(0x22) + 4 * u16_at(0x24);
+        let offset = usize::try_from(u32::from_le_bytes(DATA[field..field + 4].try_into().unwrap())).unwrap();
+
+        let (_, pattern) = ensure_parse(pattern, &DATA[offset..]);
+        let (_, events) = ensure_parse(Pattern::parse_events, &DATA[offset..]);
+        let events = events.collect::<Result<Vec<_>, _>>().unwrap();
+        let expected = pattern.rows.iter()
+            .enumerate()
+            .flat_map(|(row, commands)| commands.iter().map(move |(channel, command)| (row, channel, command.effect)))
+            .collect::<Vec<_>>();
+        assert_eq!(events.iter().map(|(row, channel, command)| (*row, *channel, command.effect)).collect::<Vec<_>>(), expected);
+
+        // Data cut short ends the events with an error.
+        let (_, mut events) = Pattern::parse_events::<VerboseError<&[u8]>>(&DATA[offset..]).unwrap();
+        events.input = &events.input[..10];
+        assert!(events.last().unwrap().is_err());
+    }
 }
@@ -0,0 +1,115 @@
+//! Integration and normalization of integer PCM
+//!
+//! The loops run over all of the sample data of the module. With the feature `simd` they are
+//! vectorized with [`core::simd`], the values left at the end which don't fill a whole vector go
+//! through the scalar versions used otherwise. Both give exactly the same results.
+//!
+//! Values are kept as `u16` until they are normalized, 8-bit PCM is stored in the low byte. The
+//! additions wrap around, for 8-bit PCM the high byte is ignored so the sums also wrap around in
+//! 8 bits.
+
+#[cfg(feature = "simd")]
+use core::simd::{num::{SimdInt, SimdUint}, Simd};
+use crate::data::convert;
+use alloc::vec::Vec;
+
+
+/// Number of values processed at once by the vectorized loops
+#[cfg(feature = "simd")]
+const LANES: usize = 16;
+
+
+/// Replaces the differences by the running sums, the sum starts at `0`.
+pub(super) fn integrate(values: &mut [u16]) {
+    #[cfg(feature = "simd")]
+    let (values, sum) = {
+        let mut chunks = values.chunks_exact_mut(LANES);
+        let mut sum = 0;
+        for chunk in &mut chunks {
+            // Prefix sum in log2(LANES) steps, each adds the sums of the preceding lanes twice as
+            // far away.
+            let mut vector = Simd::<u16, LANES>::from_slice(chunk);
+            vector += vector.shift_elements_right::<1>(0);
+            vector += vector.shift_elements_right::<2>(0);
+            vector += vector.shift_elements_right::<4>(0);
+            vector += vector.shift_elements_right::<8>(0);
+            vector += Simd::splat(sum);
+            vector.copy_to_slice(chunk);
+            sum = chunk[LANES - 1];
+        }
+        (chunks.into_remainder(), sum)
+    };
+    #[cfg(not(feature = "simd"))]
+    let sum = 0;
+
+    values.iter_mut().fold(sum, |sum: u16, value| {
+        *value = sum.wrapping_add(*value);
+        *value
+    });
+}
+
+/// Normalizes signed PCM, flipping the sign bit of unsigned PCM first.
+pub(super) fn normalize(values: &[u16], wide: bool, unsigned: bool) -> Vec<f32> {
+    let sign = match (unsigned, wide) {
+        (false, _) => 0,
+        (true, false) => 0x80,
+        (true, true) => 0x8000,
+    };
+    let scalar = |value: u16| {
+        let [low, high] = (value ^ sign).to_le_bytes();
+        if wide {
+            convert::i16_to_f32(i16::from_le_bytes([low, high]))
+        } else {
+            convert::i8_to_f32(i8::from_le_bytes([low]))
+        }
+    };
+
+    let mut data = Vec::with_capacity(values.len());
+    #[cfg(feature = "simd")]
+    let values = {
+        let chunks = values.chunks_exact(LANES);
+        let rest = chunks.remainder();
+        let max = Simd::splat(f32::from(if wide { i16::MAX } else { i16::from(i8::MAX) }));
+        for chunk in chunks {
+            let vector = Simd::<u16, LANES>::from_slice(chunk) ^ Simd::splat(sign);
+            let vector = if wide {
+                vector.cast::<i16>().cast::<f32>()
+            } else {
+                vector.cast::<u8>().cast::<i8>().cast::<f32>()
+            };
+            data.extend_from_slice((vector / max).as_array());
+        }
+        rest
+    };
+    data.extend(values.iter().map(|&value| scalar(value)));
+    data
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn integrate_and_normalize() {
+        // Long enough to fill vectors and leave some values for the scalar loop.
+        let deltas = (0..100u16).map(|i| i.wrapping_mul(0x1234)).collect::<Vec<_>>();
+        let mut sums = deltas.clone();
+        integrate(&mut sums);
+        let mut sum = 0u16;
+        for (delta, value) in deltas.iter().zip(&sums) {
+            sum = sum.wrapping_add(*delta);
+            assert_eq!(*value, sum);
+        }
+
+        let data = normalize(&sums, false, true);
+        for (value, normalized) in sums.iter().zip(&data) {
+            let [low, _] = value.to_le_bytes();
+            assert_eq!(*normalized, convert::i8_to_f32(i8::from_le_bytes([low ^ 0x80])));
+        }
+        let data = normalize(&sums, true, false);
+        for (value, normalized) in sums.iter().zip(&data) {
+            assert_eq!(*normalized, convert::i16_to_f32(i16::from_le_bytes(value.to_le_bytes())));
+        }
+    }
+}
@@ -0,0 +1,566 @@
+//! Parsing from readers
+//!
+//! The parsers work on byte slices, reading from a reader is done by reading each part of the
+//! module into a buffer at the position given by the offset tables and running the parser on it.
+//!
+//! The reading is written once as `async` code over [`Input`], blocking readers are driven by
+//! [`block_on`] which never has to wait, asynchronous readers are awaited by the caller.
+
+use super::*;
+use crate::error::{ParseFailure, ReadError, VerboseError};
+use compression::{BLOCK_LENGTH_16BIT, BLOCK_LENGTH_8BIT};
+use core::future::Future;
+use core::pin::pin;
+use core::task::{Context, Poll, Waker};
+use std::io::{self, Read, Seek, SeekFrom};
+#[cfg(feature = "async")]
+use tokio::io::{AsyncRead, AsyncSeek};
+
+
+/// Size of the static part of the module header
+const MODULE_HEADER_SIZE: usize = 0xC0;
+
+/// Size of the buffer the parts are read through
+const READ_CHUNK_SIZE: usize = 4096;
+
+
+impl Module {
+    /// Reads Impulse Tracker module file (.it) from the reader
+    ///
+    /// See [`read_module_file`].
+    pub fn read(reader: impl Read + Seek) -> Result<Module, ReadError> {
+        read_module_file(reader)
+    }
+
+    /// Reads Impulse Tracker module file (.it) from the reader using the options
+    ///
+    /// See [`read_module_file_with`].
+    pub fn read_with(reader: impl Read + Seek, options: ParseOptions) -> Result<Module, ReadError> {
+        read_module_file_with(reader, options)
+    }
+
+    /// Reads Impulse Tracker module file (.it) from the asynchronous reader
+    ///
+    /// See [`read_module_file_async`].
+    #[cfg(feature = "async")]
+    pub async fn read_async(reader: impl AsyncRead + AsyncSeek + Unpin) -> Result<Module, ReadError> {
+        read_module_file_async(reader).await
+    }
+
+    /// Reads Impulse Tracker module file (.it) from the asynchronous reader using the options
+    ///
+    /// See [`read_module_file_async_with`].
+    #[cfg(feature = "async")]
+    pub async fn read_async_with(reader: impl AsyncRead + AsyncSeek + Unpin, options: ParseOptions) -> Result<Module, ReadError> {
+        read_module_file_async_with(reader, options).await
+    }
+}
+
+impl Sample {
+    /// Reads the sample data left in the file by [`read_module_headers`]
+    ///
+    /// The reader must be positioned at the start of the module, the same as when the module was
+    /// read. Does nothing if the data was already loaded.
+    pub fn read_data(&mut self, reader: impl Read + Seek) -> Result<(), ReadError> {
+        if let Some(deferred) = self.deferred {
+            let offset = u64::from(deferred.offset);
+            let data = block_on(async {
+                let mut source = Source::new(Blocking(reader)).await?;
+                source.read_sample_data(deferred.flags, offset, deferred.length).await
+            })?;
+            let (data, encoded) = parse(&data, offset, |input| pcm_data(deferred.flags, 0, deferred.length, input))?;
+            self.data = Some(data.into());
+            self.encoded = encoded;
+            self.deferred = None;
+        }
+        Ok(())
+    }
+
+    /// Reads Impulse Tracker sample file (.its) from the reader
+    ///
+    /// The whole file is read into memory and parsed with [`sample_file`], compressed data is
+    /// decompressed.
+    ///
+    /// # Errors
+    ///
+    /// Same as [`Instrument::read_iti`].
+    pub fn read_its(mut reader: impl Read) -> Result<Sample, ReadError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        parse(&data, 0, sample_file)
+    }
+}
+
+impl Instrument {
+    /// Reads Impulse Tracker instrument file (.iti) from the reader
+    ///
+    /// The whole file is read into memory and parsed with [`instrument_file`], the sample map of
+    /// the instrument refers to the samples of the file.
+    ///
+    /// # Errors
+    ///
+    /// Errors of the reader are returned as [`ReadError::Io`], parse errors as
+    /// [`ReadError::Parse`] located relative to the start of the file.
+    pub fn read_iti(mut reader: impl Read) -> Result<InstrumentFile, ReadError> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        parse(&data, 0, instrument_file)
+    }
+}
+
+/// Parse Impulse Tracker module file (.it) from a reader
+///
+/// Unlike [`module_file`] this doesn't require the whole file in memory, only the header is read
+/// in full, the other parts are read separately by seeking to the offsets stored in the header.
+/// The result is the same as parsing the whole file with [`module_file`].
+///
+/// All offsets are interpreted relative to the position of the reader when this function is
+/// called, a module embedded in a larger file can be read by seeking to its start first. The
+/// data following the last part of the module is read to look for OpenMPT extensions.
+///
+/// With the feature `mmcmp` modules packed by MMCMP are read whole and unpacked, parse errors are
+/// located relative to the start of the unpacked module then.
+///
+/// # Errors
+///
+/// Errors of the reader, including reaching the end of input in the middle of a part, are
+/// returned as [`ReadError::Io`], parse errors as [`ReadError::Parse`] located relative to the start
+/// of the module.
+pub fn read_module_file<R: Read + Seek>(reader: R) -> Result<Module, ReadError> {
+    read_module(reader, true, ParseOptions::default())
+}
+
+/// Parse Impulse Tracker module file (.it) from a reader using the options
+///
+/// Same as [`read_module_file`] which uses the default options, fails with [`ReadError::Parse`]
+/// if the module exceeds the limits of the options.
+pub fn read_module_file_with<R: Read + Seek>(reader: R, options: ParseOptions) -> Result<Module, ReadError> {
+    read_module(reader, true, options)
+}
+
+/// Parse Impulse Tracker module file (.it) from a reader without reading the sample data
+///
+/// Reads everything except the PCM sample data the same way as [`read_module_file`]. The samples
+/// have [`Sample::data`] set to `None` and [`Sample::deferred`] pointing to the data in the file,
+/// it can be read later by calling [`Sample::read_data`] with the reader positioned at the start
+/// of the module again. Modules packed by MMCMP are read whole, their sample data can't be read
+/// from the packed file later.
+///
+/// # Errors
+///
+/// Same as [`read_module_file`], the data of compressed samples is still checked to be present
+/// because the block lengths have to be read to find the end of the module.
+pub fn read_module_headers<R: Read + Seek>(reader: R) -> Result<Module, ReadError> {
+    read_module(reader, false, ParseOptions::default())
+}
+
+/// Parse Impulse Tracker module file (.it) from a reader without reading the sample data using
+/// the options
+///
+/// Same as [`read_module_headers`] which uses the default options. The limits on the sample data
+/// are not checked, they don't apply to [`Sample::read_data`] either.
+pub fn read_module_headers_with<R: Read + Seek>(reader: R, options: ParseOptions) -> Result<Module, ReadError> {
+    read_module(reader, false, options)
+}
+
+/// Parse Impulse Tracker module file (.it) from an asynchronous reader
+///
+/// Same as [`read_module_file`] except that the reader is awaited, the parts are read and parsed
+/// in the same order. Only the reading is asynchronous, the parsing of each part is done on the
+/// task awaiting the result.
+///
+/// # Errors
+///
+/// Same as [`read_module_file`].
+#[cfg(feature = "async")]
+pub async fn read_module_file_async<R: AsyncRead + AsyncSeek + Unpin>(reader: R) -> Result<Module, ReadError> {
+    read_parts(Source::new(Tokio(reader)).await?, true, ParseOptions::default()).await
+}
+
+/// Parse Impulse Tracker module file (.it) from an asynchronous reader using the options
+///
+/// Same as [`read_module_file_async`] which uses the default options, fails with
+/// [`ReadError::Parse`] if the module exceeds the limits of the options.
+#[cfg(feature = "async")]
+pub async fn read_module_file_async_with<R: AsyncRead + AsyncSeek + Unpin>(
+    reader: R,
+    options: ParseOptions,
+) -> Result<Module, ReadError> {
+    read_parts(Source::new(Tokio(reader)).await?, true, options).await
+}
+
+fn read_module<R: Read + Seek>(reader: R, load_samples: bool, options: ParseOptions) -> Result<Module, ReadError> {
+    block_on(async { read_parts(Source::new(Blocking(reader)).await?, load_samples, options).await })
+}
+
+async fn read_parts<I: Input>(mut source: Source<I>, load_samples: bool, options: ParseOptions) -> Result<Module, ReadError> {
+    // Packed modules are read whole and parsed from the unpacked data.
+    #[cfg(feature = "mmcmp")]
+    if mmcmp::is_mmcmp(&source.read_up_to(0, mmcmp::MAGIC.len()).await?) {
+        let data = source.read_up_to(0, usize::MAX).await?;
+        let unpacked = parse(&data, 0, unpack_mmcmp)?;
+        return read_module(io::Cursor::new(unpacked), true, options);
+    }
+    let mut limits = Limits::new(options);
+
+    let (header, tables_end) = {
+        let mut data = source.read_at(0, MODULE_HEADER_SIZE).await?;
+        let field = |offset: usize| usize::from(u16::from_le_bytes([data[offset], data[offset + 1]]));
+        let (ordnum, insnum, smpnum, patnum) = (field(0x20), field(0x22), field(0x24), field(0x26));
+        let dynamic_size = ordnum + 4 * (insnum + smpnum + patnum);
+        data.extend(source.read_at(u64::try_from(MODULE_HEADER_SIZE).unwrap(), dynamic_size).await?);
+        let mut parser = context!(
+            |input| {
+                let (rest, header) = module_header(input)?;
+                limits.header(input, &header)?;
+                Ok((rest, header))
+            },
+            "module header",
+        );
+        let header = parse(&data, 0, |input| parser(input).map(|(_, header)| header))?;
+        (header, u64::try_from(data.len()).unwrap())
+    };
+
+    // The MIDI configuration and the OpenMPT names are stored between the offset tables and the
+    // first part of the module.
+    let extras = {
+        let tables_end = usize::try_from(tables_end).unwrap();
+        let length = first_part_offset(&header, tables_end).map_or(usize::MAX, |first_part| first_part - tables_end);
+        let data = source.read_up_to(u64::try_from(tables_end).unwrap(), length).await?;
+        header_extras(&data, header.stored_flags)
+    };
+
+    let mut instruments = Vec::with_capacity(header.instrument_offsets.len());
+    for (index, offset) in header.instrument_offsets.iter().copied().map(u64::from).enumerate() {
+        let data = source.read_at(offset, INSTRUMENT_SIZE).await?;
+        let instrument = module_instrument(header.compatible_with_version);
+        let parser = |input| context!(instrument, "instrument {}", index + 1)(input).map(|(_, instrument)| instrument);
+        instruments.push(parse(&data, offset, parser)?);
+    }
+
+    let mut sample_headers = Vec::with_capacity(header.sample_offsets.len());
+    for (index, offset) in header.sample_offsets.iter().copied().map(u64::from).enumerate() {
+        let data = source.read_at(offset, SAMPLE_HEADER_SIZE).await?;
+        let parser = |input| context!(sample_header, "sample header {}", index + 1)(input).map(|(_, header)| header);
+        sample_headers.push(parse(&data, offset, parser)?);
+    }
+
+    let mut patterns = Vec::with_capacity(header.pattern_offsets.len());
+    for (index, offset) in header.pattern_offsets.iter().copied().map(u64::from).enumerate() {
+        if offset == 0 {
+            patterns.push(empty_pattern());
+            continue;
+        }
+        let length = source.read_at(offset, 2).await?;
+        let length = usize::from(u16::from_le_bytes([length[0], length[1]]));
+        let data = source.read_at(offset, PATTERN_HEADER_SIZE + length).await?;
+        let mut parser = context!(
+            |input| {
+                let (rest, pattern) = pattern(input)?;
+                limits.pattern(input, &pattern)?;
+                Ok((rest, pattern))
+            },
+            "pattern {}",
+            index,
+        );
+        patterns.push(parse(&data, offset, |input| parser(input).map(|(_, pattern)| pattern))?);
+    }
+
+    let mut samples = Vec::with_capacity(sample_headers.len());
+    for (index, mut header) in sample_headers.into_iter().enumerate() {
+        let offset = u64::from(header.data_offset);
+        if !load_samples && is_deferrable(&header) {
+            // The data is skipped but the end of the module must still be known to find the
+            // extensions following it.
+            source.skip_sample_data(header.flags, offset, header.data_length).await?;
+            samples.push(deferred_sample(header));
+            continue;
+        }
+        let data = source.read_sample_data(header.flags, offset, header.data_length).await?;
+        // The data is read into its own buffer, the offset is relative to it now.
+        header.data_offset = 0;
+        samples.push(parse(&data, offset, |input| numbered_sample_data(index, header, input, &mut limits))?);
+    }
+
+    let message = {
+        let offset = u64::from(header.message_offset);
+        let data = if offset == 0 {
+            Vec::new()
+        } else {
+            let data = source.read_up_to(offset, usize::from(header.message_length)).await?;
+            source.read_terminator(offset + u64::from(header.message_length)).await?;
+            data
+        };
+        if !data.is_empty() && data.len() < usize::from(header.message_length) {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "message is truncated").into());
+        }
+        options.message(&data)
+    };
+
+    let extensions = openmpt_extensions(&source.read_tail().await?, instruments.len());
+    Ok(assemble_module(header, message, instruments, samples, patterns, extras, extensions))
+}
+
+
+/// Runs the parser on the data read from `offset` and locates the error in the module.
+fn parse<'i, O>(
+    data: &'i [u8],
+    offset: u64,
+    parser: impl FnOnce(&'i [u8]) -> Result<O, Err<VerboseError<&'i [u8]>>>,
+) -> Result<O, ReadError> {
+    parser(data).map_err(|e| {
+        let mut failure = ParseFailure::new(data, e);
+        failure.offset += offset;
+        ReadError::Parse(failure)
+    })
+}
+
+/// Runs the future of reading from a blocking input to completion.
+///
+/// The reads of [`Blocking`] finish before returning, the future is ready the first time it's
+/// polled.
+fn block_on<F: Future>(future: F) -> F::Output {
+    match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(output) => output,
+        Poll::Pending => unreachable!("blocking reads never wait"),
+    }
+}
+
+/// Seekable input read by [`Source`]
+trait Input {
+    /// Moves to the absolute position in the input.
+    async fn seek(&mut self, position: u64) -> io::Result<u64>;
+
+    /// Reads into the buffer, returns `0` at the end of the input.
+    async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize>;
+
+    /// Returns the current position in the input.
+    async fn position(&mut self) -> io::Result<u64>;
+}
+
+/// Blocking reader from [`std::io`]
+struct Blocking<R>(R);
+
+impl<R: Read + Seek> Input for Blocking<R> {
+    async fn seek(&mut self, position: u64) -> io::Result<u64> {
+        self.0.seek(SeekFrom::Start(position))
+    }
+
+    async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buffer)
+    }
+
+    async fn position(&mut self) -> io::Result<u64> {
+        self.0.stream_position()
+    }
+}
+
+/// Asynchronous reader from [`tokio::io`]
+#[cfg(feature = "async")]
+struct Tokio<R>(R);
+
+#[cfg(feature = "async")]
+impl<R: AsyncRead + AsyncSeek + Unpin> Input for Tokio<R> {
+    async fn seek(&mut self, position: u64) -> io::Result<u64> {
+        tokio::io::AsyncSeekExt::seek(&mut self.0, SeekFrom::Start(position)).await
+    }
+
+    async fn read(&mut self, buffer: &mut [u8]) -> io::Result<usize> {
+        tokio::io::AsyncReadExt::read(&mut self.0, buffer).await
+    }
+
+    async fn position(&mut self) -> io::Result<u64> {
+        tokio::io::AsyncSeekExt::stream_position(&mut self.0).await
+    }
+}
+
+/// Reader with offsets relative to the start of the module
+///
+/// Keeps track of the end of the data read so far.
+struct Source<I> {
+    input: I,
+    base: u64,
+    end: u64,
+}
+
+impl<I: Input> Source<I> {
+    async fn new(mut input: I) -> io::Result<Source<I>> {
+        let base = input.position().await?;
+        Ok(Source { input, base, end: 0 })
+    }
+
+    /// Reads exactly `length` bytes at the offset.
+    async fn read_at(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        let data = self.read_up_to(offset, length).await?;
+        if data.len() < length {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("expected {} bytes at offset {:#x}, found only {}", length, offset, data.len()),
+            ));
+        }
+        Ok(data)
+    }
+
+    /// Reads at most `length` bytes at the offset, stops at the end of the input.
+    async fn read_up_to(&mut self, offset: u64, length: usize) -> io::Result<Vec<u8>> {
+        self.input.seek(self.base + offset).await?;
+        // Reading in chunks doesn't allocate the whole length up front, the lengths come from the
+        // input and can't be trusted.
+        let mut data = Vec::new();
+        let mut chunk = [0; READ_CHUNK_SIZE];
+        while data.len() < length {
+            let wanted = chunk.len().min(length - data.len());
+            match self.input.read(&mut chunk[..wanted]).await {
+                Ok(0) => break,
+                Ok(read) => data.extend_from_slice(&chunk[..read]),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        self.end = self.end.max(offset + u64::try_from(data.len()).unwrap());
+        Ok(data)
+    }
+
+    /// Marks the null byte at the offset as read if it's there.
+    async fn read_terminator(&mut self, offset: u64) -> io::Result<()> {
+        let end = self.end;
+        let byte = self.read_up_to(offset, 1).await?;
+        self.end = end;
+        if byte == [0] {
+            self.end = self.end.max(offset + 1);
+        }
+        Ok(())
+    }
+
+    /// Reads everything after the end of the data read so far.
+    async fn read_tail(&mut self) -> io::Result<Vec<u8>> {
+        self.read_up_to(self.end, usize::MAX).await
+    }
+
+    /// Reads the sample data region starting at the offset.
+    async fn read_sample_data(&mut self, flags: SampleFlags, offset: u64, length: u32) -> io::Result<Vec<u8>> {
+        let size = self.sample_data_size(flags, offset, length).await?;
+        self.read_at(offset, size).await
+    }
+
+    /// Marks the sample data region starting at the offset as read without reading it.
+    async fn skip_sample_data(&mut self, flags: SampleFlags, offset: u64, length: u32) -> io::Result<()> {
+        let size = self.sample_data_size(flags, offset, length).await?;
+        self.end = self.end.max(offset + u64::try_from(size).unwrap());
+        Ok(())
+    }
+
+    /// Returns the size of the sample data region in bytes.
+    ///
+    /// The size of compressed data is not stored, it's found by reading the block lengths.
+    async fn sample_data_size(&mut self, flags: SampleFlags, offset: u64, length: u32) -> io::Result<usize> {
+        let length = usize::try_from(length).unwrap();
+
+        if flags.contains(SampleFlags::OPL_INSTRUMENT) {
+            Ok(OPL_PATCH_SIZE)
+        } else if !flags.contains(SampleFlags::DATA_PRESENT) {
+            Ok(0)
+        } else if flags.contains(SampleFlags::COMPRESSED) {
+            let block_length = if flags.contains(SampleFlags::DATA_16BIT) {
+                BLOCK_LENGTH_16BIT
+            } else {
+                BLOCK_LENGTH_8BIT
+            };
+            // The channels of stereo samples are compressed one after the other.
+            let mut size = 0;
+            for _ in 0..channel_count(flags) {
+                let mut decoded = 0;
+                while decoded < length {
+                    let block_size = self.read_at(offset + u64::try_from(size).unwrap(), 2).await?;
+                    size += 2 + usize::from(u16::from_le_bytes([block_size[0], block_size[1]]));
+                    decoded += block_length;
+                }
+            }
+            Ok(size)
+        } else {
+            Ok(pcm_size(flags, length))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::writer::WriteOptions;
+    use pretty_assertions::assert_eq;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_embedded() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let expected = module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+
+        let mut input = vec![0xAA; 13];
+        input.extend_from_slice(DATA);
+        let mut reader = Cursor::new(input);
+        reader.seek(SeekFrom::Start(13)).unwrap();
+        let module = Module::read(&mut reader).unwrap();
+
+        assert_eq!(format!("{:?}", module), format!("{:?}", expected));
+    }
+
+    #[test]
+    fn load_deferred() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        assert!(!module.samples.is_empty());
+        let data = (0..1000i16).map(|i| f32::from(i % 200 - 100) / 127.0).collect::<Vec<_>>();
+        module.samples[0].data = Some(data.into());
+
+        for &compress_samples in &[false, true] {
+            let mut file = Vec::new();
+            module.write_to_with(&mut file, WriteOptions { compress_samples, ..WriteOptions::default() }).unwrap();
+            let expected = module_file::<VerboseError<&[u8]>>(&file).unwrap();
+
+            let mut parsed = module_headers::<VerboseError<&[u8]>>(&file).unwrap();
+            let mut read = read_module_headers(Cursor::new(&file)).unwrap();
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", read));
+            assert!(parsed.samples[0].data.is_none() && !parsed.samples[0].is_loaded());
+
+            for (sample, read_sample) in parsed.samples.iter_mut().zip(&mut read.samples) {
+                sample.load_data::<VerboseError<&[u8]>>(&file).unwrap();
+                read_sample.read_data(Cursor::new(&file)).unwrap();
+            }
+            assert_eq!(format!("{:?}", parsed), format!("{:?}", expected));
+            assert_eq!(format!("{:?}", read), format!("{:?}", expected));
+        }
+    }
+
+    #[test]
+    fn parse_failure() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let field = |offset: usize| usize::from(u16::from_le_bytes([DATA[offset], DATA[offset + 1]]));
+        let tables = MODULE_HEADER_SIZE + field(0x20) + 4 * field(0x22);
+        let offset = u32::from_le_bytes([DATA[tables], DATA[tables + 1], DATA[tables + 2], DATA[tables + 3]]);
+        let mut file = DATA.to_vec();
+        file[usize::try_from(offset).unwrap()] = b'X';
+
+        let expected = ParseFailure::new(&file, module_file::<VerboseError<&[u8]>>(&file).map(|_| ()).unwrap_err());
+        let failure = match read_module_file(Cursor::new(&file)) {
+            Err(ReadError::Parse(failure)) => failure,
+            other => panic!("expected a parse failure, got {:?}", other.map(|_| ())),
+        };
+        assert_eq!(failure.offset, u64::from(offset));
+        assert_eq!(failure.context, ["sample header 1"]);
+        assert_eq!(failure.expected.as_deref(), Some("\"IMPS\""));
+        assert_eq!(failure.found.as_deref(), Some("\"XMPS\""));
+        assert_eq!(failure.to_string(), expected.to_string());
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn read_async() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let expected = module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+
+        // Reading from memory never waits, the future completes on the first poll.
+        let module = block_on(Module::read_async(Cursor::new(DATA))).unwrap();
+        assert_eq!(format!("{:?}", module), format!("{:?}", expected));
+    }
+}
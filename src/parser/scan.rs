@@ -1,4 +1,4 @@
-use std::fmt::{self, Display};
+use core::fmt::{self, Display};
 
 
 /// Impulse Tracker file types
@@ -49,4 +49,5 @@ impl Display for ScanError {
     }
 }
 
+#[cfg(feature = "std")]
 impl std::error::Error for ScanError {}
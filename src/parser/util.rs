@@ -1,9 +1,15 @@
 use nom::bytes::complete::take;
-use nom::error::{make_error, ErrorKind, ParseError};
+use crate::error::ContextError;
+use nom::error::ParseError;
 use nom::multi::count;
 use nom::Err::Error;
 use nom::{IResult, Parser};
-use std::convert::{TryFrom, TryInto};
+use alloc::borrow::Cow;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::convert::{TryFrom, TryInto};
+use core::iter;
 
 
 /// Helper trait for `.try_into().unwrap()` for cases where a panic is meant to be a bug.
@@ -13,7 +19,7 @@ pub(crate) trait Cast {
     where
         Self: Sized,
         T: TryFrom<Self>,
-        <T as TryFrom<Self>>::Error: std::fmt::Debug,
+        <T as TryFrom<Self>>::Error: core::fmt::Debug,
     {
         T::try_from(self).unwrap()
     }
@@ -29,6 +35,38 @@ pub(crate) fn byte_array<'i, E: ParseError<&'i [u8]>, const N: usize>(input: &'i
     Ok((rest, array))
 }
 
+/// Consumes the magic number, the error tells the expected and the found bytes.
+pub(crate) fn magic<'i, E>(magic: &'static [u8]) -> impl Fn(&'i [u8]) -> IResult<&'i [u8], (), E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    move |input: &'i [u8]| match input.strip_prefix(magic) {
+        Some(rest) => Ok((rest, ())),
+        None => {
+            let found = &input[..magic.len().min(input.len())];
+            Err(Error(E::expected(input, Cow::Owned(escaped(magic)), Cow::Owned(escaped(found)))))
+        }
+    }
+}
+
+/// Error for an offset stored in the file which points past the end of the input.
+pub(crate) fn past_end<'i, E>(input: &'i [u8], offset: usize) -> nom::Err<E>
+where
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
+{
+    Error(E::expected(
+        &input[input.len()..],
+        Cow::Borrowed("offset within the input"),
+        Cow::Owned(format!("offset {:#x} in {:#x} bytes", offset, input.len())),
+    ))
+}
+
+/// Formats bytes as a quoted string with non-printable bytes escaped.
+fn escaped(bytes: &[u8]) -> String {
+    let escaped = bytes.iter().flat_map(|&byte| core::ascii::escape_default(byte)).map(char::from);
+    iter::once('"').chain(escaped).chain(iter::once('"')).collect()
+}
+
 /// Runs the embedded parser N times and returns the result as an array.
 pub(crate) fn array<I, O, E, F, const N: usize>(
     f: F,
@@ -45,25 +83,28 @@ where
     }
 }
 
+/// Runs the parser at every offset of the list, the errors name the structure with its number.
 pub(crate) fn offset_list<'i, O, E, F>(
     mut f: F,
     offset_list: Vec<u32>,
+    name: &'static str,
 ) -> impl FnMut(&'i [u8]) -> IResult<&'i [u8], Vec<O>, E>
 where
-    E: ParseError<&'i [u8]>,
+    E: ParseError<&'i [u8]> + ContextError<&'i [u8]>,
     F: Parser<&'i [u8], O, E>,
 {
     move |input: &'i [u8]| {
         let mut output_list = Vec::with_capacity(offset_list.len());
-        for &offset in &offset_list {
+        for (index, &offset) in offset_list.iter().enumerate() {
             let offset = offset.try_into().unwrap();
             if offset == 0 {
-                todo!("not yet handled")
+                let found = Cow::Owned(format!("offset 0 of {} {}", name, index + 1));
+                return Err(Error(E::expected(input, Cow::Borrowed("non-zero offset"), found)));
             }
             if offset >= input.len() {
-                return Err(Error(make_error(input, ErrorKind::Eof)));
+                return Err(past_end(input, offset));
             }
-            let (_, i) = f.parse(&input[offset..])?;
+            let (_, i) = context!(|input| f.parse(input), "{} {}", name, index + 1)(&input[offset..])?;
             output_list.push(i);
         }
         Ok((input, output_list))
@@ -0,0 +1,870 @@
+//! Module playback
+//!
+//! [`Player`] interprets a [`Module`] tick by tick the way Impulse Tracker does and renders it to
+//! interleaved stereo `f32` samples. The output is pulled from the player with [`Player::render`]
+//! in buffers of any size.
+//!
+//! The implementation covers the parts of the format most modules depend on:
+//!
+//! - speed, tempo, the order list with `Bxx`, `Cxx`, pattern loops (`SBx`) and row and tick
+//!   delays (`SEx`, `S6x`),
+//! - sample playback with linear interpolation, forward, bidirectional and sustain loops,
+//! - note, sample, instrument, channel and global volume, instrument fadeout and the module mixing
+//!   volume,
+//! - channel, instrument and sample panning, pitch/pan separation and the module pan separation,
+//! - volume, panning, pitch and filter envelopes with their loops and sustain loops,
+//! - the resonant filter with the instrument cutoff and resonance and the filter macros of `Zxx`,
+//! - sample auto-vibrato,
+//! - new note actions and past note actions (`S7x`),
+//! - the volume column and the effects `A`-`Y` with their memory, except for the ones listed
+//!   below.
+//!
+//! Not implemented are random volume and pan variation, duplicate note checks, MIDI macros other
+//! than the filter ones, glissando and finetune (`S1x`, `S2x`), reverb and surround modes (`S9x`). Surround panning is played in the centre.
+//! Amiga slides are approximated by linear slides.
+//!
+//! Playback ends at the end of the order list, on an [`Order::EndOfSong`] or when a jump would
+//! play a row that was already played (the song loops), whatever comes first. The song can be
+//! played repeatedly using [`Player::set_loop_count`]. Playback can start at any row with the
+//! state computed by [`PlaybackState::at`].
+//!
+//! With the feature `std` whole modules can be rendered to WAV files by [`render_to_wav`]. With
+//! the feature `cpal` modules can be played on the default output device by [`play`].
+
+use crate::*;
+use crate::data::EMPTY_PATTERN_ROWS;
+use alloc::vec::Vec;
+use core::borrow::Borrow;
+use core::convert::TryFrom;
+
+mod channel;
+mod filter;
+#[cfg(feature = "cpal")]
+mod realtime;
+mod voice;
+#[cfg(feature = "std")]
+mod wav;
+
+use channel::ChannelState;
+use voice::Voice;
+
+#[cfg(feature = "cpal")]
+pub use realtime::{play, Playback};
+#[cfg(feature = "std")]
+pub use wav::{render_to_wav, write_wav, RenderOptions};
+
+
+/// Maximum number of notes kept playing in the background by new note actions
+const MAX_BACKGROUND_VOICES: usize = 192;
+
+/// Number of rows of each order tracked to detect the song looping
+const TRACKED_ROWS: usize = 256;
+
+/// Sample rate the effects are processed at while seeking, the positions of the notes don't
+/// depend on it
+const SEEK_SAMPLE_RATE: u32 = 44_100;
+
+
+/// Module player rendering interleaved stereo `f32` samples
+///
+/// The player either borrows the module or owns it, for example in an [`Arc`](alloc::sync::Arc)
+/// to be moved into an audio callback. Rendering doesn't allocate memory or block, it's safe to
+/// call from real-time threads.
+///
+/// ```no_run
+/// # fn example(module: &ittech::Module) {
+/// use ittech::player::Player;
+///
+/// let mut player = Player::new(module, 44_100);
+/// let mut buffer = vec![0.0; 2 * 4096];
+/// while player.render(&mut buffer) > 0 {
+///     // ... write out the buffer ...
+/// }
+/// # }
+/// ```
+pub struct Player<M> {
+    module: M,
+    sample_rate: u32,
+    global: Global,
+    channels: Vec<ChannelState>,
+    background: Vec<Voice>,
+
+    /// Current position in the order list
+    order: usize,
+
+    /// Current row in the pattern
+    row: usize,
+
+    /// Current tick of the row
+    tick: u8,
+
+    /// The current row is repeated by `SEx`, notes are not triggered again
+    repeated_row: bool,
+
+    /// Rows already played for each order, used to detect the song looping
+    visited: Vec<[u64; TRACKED_ROWS / 64]>,
+
+    /// Number of times the song is still going to be repeated
+    loops_left: u32,
+
+    /// Frames left to render in the current tick
+    frames_left: usize,
+
+    /// Remainder of the division computing the tick length
+    frame_remainder: u32,
+
+    finished: bool,
+
+    /// Channels left out of the mix by [`Player::set_muted`] and [`Player::set_solo`]
+    muted: ActiveChannels,
+    solo: ActiveChannels,
+}
+
+/// State of the player at the start of a row, see [`PlaybackState::at`]
+///
+/// A player started from the state with [`Player::with_state`] plays from the row on as if it had
+/// played the song from the start.
+#[derive(Clone)]
+pub struct PlaybackState {
+    global: Global,
+    channels: Vec<ChannelState>,
+    background: Vec<Voice>,
+    order: usize,
+    row: usize,
+    visited: Vec<[u64; TRACKED_ROWS / 64]>,
+}
+
+/// Playback state shared by all channels
+#[derive(Clone)]
+pub(crate) struct Global {
+    pub(crate) speed: u8,
+    pub(crate) tempo: u8,
+    pub(crate) global_volume: u8,
+
+    /// `Bxx` on the current row
+    pub(crate) jump_order: Option<usize>,
+
+    /// `Cxx` on the current row
+    pub(crate) break_row: Option<usize>,
+
+    /// `SBx` jumping back to the row
+    pub(crate) loop_row: Option<usize>,
+
+    /// Remaining repeats of the current row set by `SEx`
+    pub(crate) row_delay: Option<u8>,
+
+    /// Extra ticks of the current row set by `S6x`
+    pub(crate) tick_delay: u8,
+
+    /// State of the random waveform generator
+    pub(crate) random: u32,
+}
+
+
+impl<M: Borrow<Module>> Player<M> {
+    /// Creates a player starting at the first order of the module.
+    ///
+    /// Panics if `sample_rate` is zero.
+    pub fn new(module: M, sample_rate: u32) -> Player<M> {
+        assert!(sample_rate > 0, "sample rate must not be zero");
+        let (global, channels, visited) = {
+            let module = module.borrow();
+            let global = Global {
+                speed: module.speed.as_u8(),
+                tempo: module.tempo.as_u8(),
+                global_volume: module.global_volume.as_u8(),
+                jump_order: None,
+                break_row: None,
+                loop_row: None,
+                row_delay: None,
+                tick_delay: 0,
+                random: 0x1234_5678,
+            };
+            let channels = ActiveChannels::all()
+                .iter()
+                .map(|channel| ChannelState::new(module, channel))
+                .collect();
+            (global, channels, vec![[0; TRACKED_ROWS / 64]; module.orders.len()])
+        };
+        Player {
+            module,
+            sample_rate,
+            global,
+            channels,
+            background: Vec::with_capacity(MAX_BACKGROUND_VOICES),
+            order: 0,
+            row: 0,
+            tick: 0,
+            repeated_row: false,
+            visited,
+            loops_left: 0,
+            frames_left: 0,
+            frame_remainder: 0,
+            finished: false,
+            muted: ActiveChannels::empty(),
+            solo: ActiveChannels::empty(),
+        }
+    }
+
+    /// Creates a player continuing from the state.
+    ///
+    /// The state must come from the same module. Panics if `sample_rate` is zero.
+    pub fn with_state(module: M, sample_rate: u32, state: PlaybackState) -> Player<M> {
+        assert!(sample_rate > 0, "sample rate must not be zero");
+        Player {
+            module,
+            sample_rate,
+            global: state.global,
+            channels: state.channels,
+            background: state.background,
+            order: state.order,
+            row: state.row,
+            tick: 0,
+            repeated_row: false,
+            visited: state.visited,
+            loops_left: 0,
+            frames_left: 0,
+            frame_remainder: 0,
+            finished: false,
+            muted: ActiveChannels::empty(),
+            solo: ActiveChannels::empty(),
+        }
+    }
+
+    /// Returns the module being played.
+    pub fn module(&self) -> &Module {
+        self.module.borrow()
+    }
+
+    /// Returns the output sample rate.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Returns the current position as the index into the order list and the row.
+    pub fn position(&self) -> (usize, usize) {
+        (self.order, self.row)
+    }
+
+    /// Sets how many times the song is repeated after it ends.
+    ///
+    /// The song continues from the row the loop jumps to, or from the first order if it reached
+    /// the end of the order list. The default is `0`, the song is only played once.
+    pub fn set_loop_count(&mut self, count: u32) {
+        self.loops_left = count;
+    }
+
+    /// Moves playback to the start of the order.
+    ///
+    /// All playing notes are stopped. Speed, tempo, global volume and the effect memory of the
+    /// channels carry over from the previous position. Seeking past the end of the order list
+    /// finishes the song, seeking back resumes a finished song.
+    pub fn seek(&mut self, order: usize) {
+        for channel in &mut self.channels {
+            channel.voice = None;
+        }
+        self.background.clear();
+        self.reset_pattern_loops();
+        self.global.jump_order = None;
+        self.global.break_row = None;
+        self.global.loop_row = None;
+        self.global.row_delay = None;
+        self.global.tick_delay = 0;
+        self.visited.iter_mut().for_each(|visited| *visited = [0; TRACKED_ROWS / 64]);
+        self.order = order;
+        self.row = 0;
+        self.tick = 0;
+        self.repeated_row = false;
+        self.frames_left = 0;
+        self.finished = false;
+    }
+
+    /// Mutes or unmutes the channel.
+    ///
+    /// Muted channels keep playing their effects, including the global ones, but are left out of
+    /// the output of [`Player::render`].
+    pub fn set_muted(&mut self, channel: Channel, muted: bool) {
+        if muted {
+            self.muted.insert(channel);
+        } else {
+            self.muted.remove(channel);
+        }
+    }
+
+    /// Solos the channel or ends its solo.
+    ///
+    /// If any channel is soloed, [`Player::render`] only outputs the soloed channels which are
+    /// not muted.
+    pub fn set_solo(&mut self, channel: Channel, solo: bool) {
+        if solo {
+            self.solo.insert(channel);
+        } else {
+            self.solo.remove(channel);
+        }
+    }
+
+    /// Returns the channels [`Player::render`] outputs according to the mutes and solos.
+    pub fn audible_channels(&self) -> ActiveChannels {
+        let channels = if self.solo.is_empty() { ActiveChannels::all() } else { self.solo };
+        channels - self.muted
+    }
+
+    /// Returns `true` if the song has ended, [`Player::render`] then only outputs silence.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Renders interleaved stereo samples into the buffer.
+    ///
+    /// The buffer is filled with pairs of left and right samples, a trailing odd sample is set to
+    /// silence. Returns the number of frames (sample pairs) rendered, if it's less than the buffer
+    /// can hold the song has ended and the rest of the buffer is filled with silence.
+    ///
+    /// The output is not clipped, loud modules can exceed the `-1.0..=1.0` range.
+    pub fn render(&mut self, buffer: &mut [f32]) -> usize {
+        self.render_channels(buffer, self.audible_channels())
+    }
+
+    /// Renders only the audio of the channel, like [`Player::render`].
+    ///
+    /// The whole song is played, the other channels are left out of the output. Rendering the
+    /// channels one by one with players started at the same position gives stems which add up to
+    /// the output of [`Player::render`]. Mutes and solos are ignored.
+    pub fn render_channel(&mut self, channel: Channel, buffer: &mut [f32]) -> usize {
+        self.render_channels(buffer, ActiveChannels::new([channel]))
+    }
+
+    /// Renders only the audio of the channels, like [`Player::render`]. Mutes and solos are
+    /// ignored.
+    ///
+    /// Notes moved to the background by new note actions belong to the channel which played them.
+    pub fn render_channels(&mut self, buffer: &mut [f32], channels: ActiveChannels) -> usize {
+        let frames = buffer.len() / 2;
+        let mut rendered = 0;
+        while rendered < frames {
+            if self.frames_left == 0 {
+                if !self.finished {
+                    self.process_tick();
+                }
+                if self.finished {
+                    break;
+                }
+            }
+            let count = self.frames_left.min(frames - rendered);
+            let chunk = &mut buffer[2 * rendered..2 * (rendered + count)];
+            chunk.fill(0.0);
+            let module = self.module.borrow();
+            let voices = self.channels.iter_mut().filter_map(|channel| channel.voice.as_mut());
+            for voice in voices.chain(&mut self.background) {
+                if channels.contains(voice.channel) {
+                    voice.mix(module, chunk);
+                } else {
+                    voice.skip(module, count);
+                }
+            }
+            rendered += count;
+            self.frames_left -= count;
+        }
+        buffer[2 * rendered..].fill(0.0);
+        rendered
+    }
+
+    /// Processes one tick of the song and computes its length.
+    fn process_tick(&mut self) {
+        if self.tick == 0 && !self.repeated_row {
+            if !self.enter_row() {
+                self.finished = true;
+                return;
+            }
+            let module = self.module.borrow();
+            let row = pattern_row(module, self.order, self.row);
+            for (channel, state) in self.channels.iter_mut().enumerate() {
+                let channel = Channel::from_u8_index(u8::try_from(channel).unwrap());
+                let command = row.and_then(|row| row.get(channel));
+                state.row_start(module, &mut self.global, &mut self.background, command, self.row);
+            }
+        } else {
+            let module = self.module.borrow();
+            for state in &mut self.channels {
+                state.tick(module, &mut self.global, &mut self.background, self.tick, self.row);
+            }
+        }
+
+        let module = self.module.borrow();
+        let global_volume = self.global.global_volume;
+        for state in &mut self.channels {
+            state.update_voice(module, global_volume, self.sample_rate);
+        }
+        for voice in &mut self.background {
+            voice.update(module, global_volume, self.sample_rate);
+        }
+        self.background.retain(Voice::is_active);
+
+        // Tick length is 2.5 / tempo seconds, the remainder is carried over to the next tick to
+        // keep the timing exact.
+        let numerator = self.sample_rate * 5 + self.frame_remainder;
+        let denominator = 2 * u32::from(self.global.tempo);
+        self.frames_left = usize::try_from(numerator / denominator).unwrap();
+        self.frame_remainder = numerator % denominator;
+
+        self.tick += 1;
+        if self.tick >= self.global.speed.saturating_add(self.global.tick_delay) {
+            self.tick = 0;
+            self.global.tick_delay = 0;
+            self.next_row();
+        }
+    }
+
+    /// Moves to the row following the current one, taking jumps into account.
+    fn next_row(&mut self) {
+        match &mut self.global.row_delay {
+            Some(0) => self.global.row_delay = None,
+            Some(delay) => {
+                *delay -= 1;
+                self.repeated_row = true;
+                return;
+            }
+            None => {}
+        }
+        self.repeated_row = false;
+
+        if let Some(loop_row) = self.global.loop_row.take() {
+            // The looped rows are going to be played again, that's not a song loop.
+            if let Some(visited) = self.visited.get_mut(self.order) {
+                for row in loop_row..TRACKED_ROWS {
+                    visited[row / 64] &= !(1 << (row % 64));
+                }
+            }
+            self.row = loop_row;
+        } else if self.global.jump_order.is_some() || self.global.break_row.is_some() {
+            self.order = self.global.jump_order.take().unwrap_or(self.order + 1);
+            self.row = self.global.break_row.take().unwrap_or(0);
+            self.reset_pattern_loops();
+        } else {
+            self.row += 1;
+            if self.row >= self.pattern_rows() {
+                self.order += 1;
+                self.row = 0;
+                self.reset_pattern_loops();
+            }
+        }
+    }
+
+    /// Resolves the current position to a row which is going to be played.
+    ///
+    /// Returns `false` if the song has ended and is not going to be repeated.
+    fn enter_row(&mut self) -> bool {
+        if !self.find_pattern() {
+            if !self.next_loop() {
+                return false;
+            }
+            self.order = 0;
+            self.row = 0;
+            if !self.find_pattern() {
+                return false;
+            }
+        }
+        if self.row >= self.pattern_rows() {
+            self.row = 0;
+        }
+        self.visit() || self.next_loop() && self.visit()
+    }
+
+    /// Marks the current row as visited, returns `false` if it already was.
+    fn visit(&mut self) -> bool {
+        let (order, row) = (self.order, self.row);
+        match self.visited.get_mut(order).filter(|_| row < TRACKED_ROWS) {
+            Some(visited) if visited[row / 64] & 1 << (row % 64) != 0 => false,
+            Some(visited) => {
+                visited[row / 64] |= 1 << (row % 64);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Skips separators and patterns without rows, returns `false` at the end of the song.
+    fn find_pattern(&mut self) -> bool {
+        loop {
+            match self.module.borrow().orders.as_slice().get(self.order) {
+                Some(Order::Index(_)) if self.pattern_rows() > 0 => return true,
+                Some(Order::Index(_)) | Some(Order::Separator) => {
+                    self.order += 1;
+                    self.row = 0;
+                }
+                Some(Order::EndOfSong) | None => return false,
+            }
+        }
+    }
+
+    /// Starts another repetition of the song, returns `false` if there are none left.
+    fn next_loop(&mut self) -> bool {
+        if self.loops_left == 0 {
+            return false;
+        }
+        self.loops_left -= 1;
+        self.visited.iter_mut().for_each(|visited| *visited = [0; TRACKED_ROWS / 64]);
+        true
+    }
+
+    /// Number of rows of the pattern at the current order.
+    fn pattern_rows(&self) -> usize {
+        order_rows(self.module.borrow(), self.order)
+    }
+
+    fn reset_pattern_loops(&mut self) {
+        for channel in &mut self.channels {
+            channel.reset_pattern_loop();
+        }
+    }
+}
+
+impl PlaybackState {
+    /// Computes the state at the start of the row by playing the song up to it without rendering.
+    ///
+    /// All effects are processed, the speed, tempo, global volume, the channel volumes and
+    /// panning, the effect memory and the playing notes are the same as when the song is played
+    /// from the start. The positions of the notes in their samples are approximated from the time
+    /// they played, within bidirectional loops the position may be off by a few samples.
+    ///
+    /// Returns `None` if the song ends or loops before it plays the row, e.g. if the order refers
+    /// to a separator or the row is skipped by a jump.
+    pub fn at(module: &Module, order: usize, row: usize) -> Option<PlaybackState> {
+        if row >= order_rows(module, order) {
+            return None;
+        }
+        let mut player = Player::new(module, SEEK_SAMPLE_RATE);
+        while player.tick != 0 || player.repeated_row || player.position() != (order, row) {
+            player.process_tick();
+            if player.finished {
+                return None;
+            }
+            let frames = core::mem::take(&mut player.frames_left);
+            for voice in player.channels.iter_mut().filter_map(|channel| channel.voice.as_mut()) {
+                voice.skip(module, frames);
+            }
+            for voice in &mut player.background {
+                voice.skip(module, frames);
+            }
+        }
+        Some(PlaybackState {
+            global: player.global,
+            channels: player.channels,
+            background: player.background,
+            order,
+            row,
+            visited: player.visited,
+        })
+    }
+
+    /// Returns the position as the index into the order list and the row.
+    pub fn position(&self) -> (usize, usize) {
+        (self.order, self.row)
+    }
+
+    /// Returns the speed in ticks per row.
+    pub fn speed(&self) -> u8 {
+        self.global.speed
+    }
+
+    /// Returns the tempo in beats per minute.
+    pub fn tempo(&self) -> u8 {
+        self.global.tempo
+    }
+
+    /// Returns the global volume (`0..=128`).
+    pub fn global_volume(&self) -> u8 {
+        self.global.global_volume
+    }
+
+    /// Returns the volume of the channel (`0..=64`).
+    pub fn channel_volume(&self, channel: Channel) -> u8 {
+        self.channels[channel.as_usize()].volume()
+    }
+
+    /// Returns the panning of the channel (`0..=64`), `None` for surround.
+    pub fn channel_pan(&self, channel: Channel) -> Option<u8> {
+        self.channels[channel.as_usize()].pan()
+    }
+
+    /// Returns `true` if a note is playing on the channel.
+    pub fn is_playing(&self, channel: Channel) -> bool {
+        self.channels[channel.as_usize()].voice.is_some()
+    }
+}
+
+impl Global {
+    /// Returns the next value of a pseudo-random sequence in range `-64..64`.
+    pub(crate) fn random(&mut self) -> i16 {
+        // xorshift32
+        self.random ^= self.random << 13;
+        self.random ^= self.random >> 17;
+        self.random ^= self.random << 5;
+        i16::try_from(self.random >> 25).unwrap() - 64
+    }
+}
+
+/// Number of rows of the pattern at the order, `0` for separators and the end of the song.
+fn order_rows(module: &Module, order: usize) -> usize {
+    match module.orders.as_slice().get(order) {
+        Some(Order::Index(pattern)) => module.get(pattern).map_or(EMPTY_PATTERN_ROWS, |pattern| pattern.rows.len()),
+        _ => 0,
+    }
+}
+
+/// Returns the row of the pattern at the order, `None` for rows of missing patterns.
+fn pattern_row(module: &Module, order: usize, row: usize) -> Option<&Row> {
+    match module.orders.as_slice().get(order) {
+        Some(Order::Index(pattern)) => module.get(pattern)?.row(row),
+        _ => None,
+    }
+}
+
+/// Pushes a voice to the background, dropping the oldest one if there are too many.
+pub(crate) fn push_background(background: &mut Vec<Voice>, voice: Voice) {
+    if background.len() >= MAX_BACKGROUND_VOICES {
+        background.remove(0);
+    }
+    background.push(voice);
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Builds a sample mode module with a looped square wave sample.
+    fn module(rows: Vec<Row>) -> Module {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<error::VerboseError<&[u8]>>(DATA).unwrap();
+        module.flags = ModuleFlags::STEREO;
+        module.speed = RangedU8::try_from(6).unwrap();
+        module.tempo = RangedU8::try_from(125).unwrap();
+        module.samples = vec![Sample {
+            samplerate_c5: 8363,
+            default_volume: 64,
+            global_volume: 64,
+            default_panning: 0,
+            loop_: Some(SampleLoop { start: 0, end: 64, bidi: false }),
+            sustain_loop: None,
+            data: Some((0..64).map(|i| if i < 32 { 0.5 } else { -0.5 }).collect()),
+            fm_patch: None,
+            conversion: SampleConversion::empty(),
+            encoded: None,
+            deferred: None,
+            ..module.samples[0].clone()
+        }];
+        module.patterns = vec![Pattern {
+            active_channels: ActiveChannels::all(),
+            rows,
+            truncated: false,
+            highlight: None,
+        }];
+        module.orders = vec![Order::Index(PatternId::try_from(0).unwrap())];
+        module
+    }
+
+    fn play(note: NoteCmd) -> Command {
+        Command {
+            note: Some(note),
+            instrument: Some(InstrumentId::try_from(0).unwrap()),
+            volume: None,
+            effect: None,
+        }
+    }
+
+    #[test]
+    fn song_length() {
+        // 4 rows at speed 6 and tempo 125 take 4 * 6 * 2.5 / 125 seconds.
+        let mut rows = vec![Row::empty(); 4];
+        rows[0].insert(Channel::new(1), play(NoteCmd::Play(Note::C_5)));
+        let module = module(rows);
+        let mut player = Player::new(&module, 1000);
+        let mut buffer = vec![0.0; 2 * 1000];
+        assert_eq!(player.render(&mut buffer), 480);
+        assert!(player.is_finished());
+        assert!(buffer[..960].iter().any(|&s| s != 0.0));
+        assert!(buffer[960..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn note_cut() {
+        let mut rows = vec![Row::empty(); 2];
+        rows[0].insert(Channel::new(1), play(NoteCmd::Play(Note::C_5)));
+        rows[1].insert(Channel::new(1), play(NoteCmd::Cut));
+        let module = module(rows);
+        let mut player = Player::new(&module, 1000);
+        let mut buffer = vec![0.0; 2 * 240];
+        assert_eq!(player.render(&mut buffer), 240);
+        assert!(buffer[..240].iter().any(|&s| s != 0.0));
+        assert!(buffer[240..].iter().all(|&s| s == 0.0));
+    }
+
+    #[test]
+    fn song_loop_ends_playback() {
+        let mut rows = vec![Row::empty(); 2];
+        rows[1].insert(Channel::new(1), Command {
+            note: None,
+            instrument: None,
+            volume: None,
+            effect: Some(EffectCmd::JumpOrder(0)),
+        });
+        let module = module(rows);
+        let mut player = Player::new(&module, 1000);
+        let mut buffer = vec![0.0; 2 * 1000];
+        assert_eq!(player.render(&mut buffer), 240);
+
+        let mut player = Player::new(&module, 1000);
+        player.set_loop_count(2);
+        assert_eq!(player.render(&mut buffer), 720);
+    }
+
+    #[test]
+    fn back_jump_ends_playback() {
+        // The second order jumps back to itself, the song loop is played once.
+        let mut rows = vec![Row::empty(); 4];
+        rows[0].insert(Channel::new(1), play(NoteCmd::Play(Note::C_5)));
+        rows[3].insert(Channel::new(1), Command {
+            note: None,
+            instrument: None,
+            volume: None,
+            effect: Some(EffectCmd::JumpOrder(1)),
+        });
+        let mut module = module(rows);
+        module.patterns.insert(0, Pattern { rows: vec![Row::empty(); 2], ..module.patterns[0].clone() });
+        module.orders = vec![Order::Index(PatternId::try_from(0).unwrap()), Order::Index(PatternId::try_from(1).unwrap())];
+        assert_eq!(module.song_loop().map(|song_loop| (song_loop.order, song_loop.rows)), Some((1, 4)));
+
+        let mut buffer = vec![0.0; 2 * 2000];
+        let mut player = Player::new(&module, 1000);
+        assert_eq!(player.render(&mut buffer), 720);
+        assert!(player.is_finished());
+        let mut player = Player::new(&module, 1000);
+        player.set_loop_count(1);
+        assert_eq!(player.render(&mut buffer), 1200);
+    }
+
+    #[test]
+    fn seek_restarts_finished_song() {
+        let mut rows = vec![Row::empty(); 2];
+        rows[0].insert(Channel::new(1), play(NoteCmd::Play(Note::C_5)));
+        let module = alloc::sync::Arc::new(module(rows));
+        let mut player = Player::new(module, 1000);
+        let mut buffer = vec![0.0; 2 * 1000];
+        assert_eq!(player.render(&mut buffer), 240);
+        assert!(player.is_finished());
+
+        player.seek(0);
+        assert!(!player.is_finished());
+        assert_eq!(player.position(), (0, 0));
+        assert_eq!(player.render(&mut buffer), 240);
+        assert!(buffer[..480].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn playback_state() {
+        let mut rows = vec![Row::empty(); 4];
+        rows[0].insert(Channel::new(1), Command {
+            effect: Some(EffectCmd::SetSpeed(RangedU8::try_from(3).unwrap())),
+            ..play(NoteCmd::Play(Note::C_5))
+        });
+        rows[1].insert(Channel::new(2), Command {
+            note: None,
+            instrument: None,
+            volume: None,
+            effect: Some(EffectCmd::SetGlobalVolume(RangedU8::try_from(32).unwrap())),
+        });
+        let module = module(rows);
+        assert!(PlaybackState::at(&module, 0, 4).is_none());
+        assert!(PlaybackState::at(&module, 1, 0).is_none());
+
+        let state = PlaybackState::at(&module, 0, 2).unwrap();
+        assert_eq!(state.position(), (0, 2));
+        assert_eq!((state.speed(), state.tempo(), state.global_volume()), (3, 125, 32));
+        assert!(state.is_playing(Channel::new(1)));
+        assert!(!state.is_playing(Channel::new(2)));
+        assert_eq!(state.channel_volume(Channel::new(1)), 64);
+
+        // Two rows of 3 ticks take 120 frames, the rest of the song plays the same from the state.
+        let mut expected = vec![0.0; 2 * 1000];
+        assert_eq!(Player::new(&module, 1000).render(&mut expected), 240);
+        let mut buffer = vec![0.0; 2 * 1000];
+        assert_eq!(Player::with_state(&module, 1000, state).render(&mut buffer), 120);
+        assert!(buffer[..240].iter().any(|&s| s != 0.0));
+        assert!(buffer[..240].iter().zip(&expected[240..480]).all(|(a, b)| (a - b).abs() < 1e-3));
+    }
+
+    #[test]
+    fn set_panning() {
+        let mut rows = vec![Row::empty(); 17];
+        for (pan, row) in (0..16).zip(&mut rows) {
+            row.insert(Channel::new(1), Command {
+                effect: Some(EffectCmd::Special(Some(Special::SetPanning(RangedU8::try_from(pan).unwrap())))),
+                ..play(NoteCmd::Play(Note::C_5))
+            });
+        }
+        let module = module(rows);
+        for pan in 0..16u8 {
+            let state = PlaybackState::at(&module, 0, usize::from(pan) + 1).unwrap();
+            assert_eq!(state.channel_pan(Channel::new(1)), Some(u8::try_from((u16::from(pan) * 64 + 7) / 15).unwrap()));
+        }
+        assert_eq!(PlaybackState::at(&module, 0, 16).unwrap().channel_pan(Channel::new(1)), Some(64));
+        let mut buffer = vec![0.0; 2 * 4000];
+        assert_eq!(Player::new(&module, 1000).render(&mut buffer), 2040);
+    }
+
+    #[test]
+    fn retrigger_without_memory() {
+        // 60 rows of 6 ticks, Q00 without effect memory runs for more than 255 ticks.
+        let mut rows = vec![Row::empty(); 60];
+        for row in &mut rows {
+            row.insert(Channel::new(1), Command {
+                note: None,
+                instrument: None,
+                volume: None,
+                effect: Some(EffectCmd::Retrigger(None)),
+            });
+        }
+        rows[0].insert(Channel::new(1), Command {
+            effect: Some(EffectCmd::Retrigger(None)),
+            ..play(NoteCmd::Play(Note::C_5))
+        });
+        let module = module(rows);
+        let mut buffer = vec![0.0; 2 * 8000];
+        assert_eq!(Player::new(&module, 1000).render(&mut buffer), 7200);
+        assert!(buffer[..14400].iter().any(|&s| s != 0.0));
+    }
+
+    #[test]
+    fn channel_stems() {
+        let mut rows = vec![Row::empty(); 4];
+        rows[0].insert(Channel::new(1), play(NoteCmd::Play(Note::C_5)));
+        rows[0].insert(Channel::new(2), play(NoteCmd::Play(Note::G_5)));
+        let module = module(rows);
+        let render = |player: &mut Player<&Module>| {
+            let mut buffer = vec![0.0; 2 * 1000];
+            assert_eq!(player.render(&mut buffer), 480);
+            buffer
+        };
+        let mix = render(&mut Player::new(&module, 1000));
+
+        let mut stems = Vec::new();
+        for channel in [Channel::new(1), Channel::new(2)] {
+            let mut player = Player::new(&module, 1000);
+            let mut buffer = vec![0.0; 2 * 1000];
+            assert_eq!(player.render_channel(channel, &mut buffer), 480);
+            assert!(buffer.iter().any(|&s| s != 0.0));
+            stems.push(buffer);
+        }
+        assert!(mix.iter().zip(&stems[0]).zip(&stems[1]).all(|((m, a), b)| (m - a - b).abs() < 1e-6));
+
+        let mut player = Player::new(&module, 1000);
+        player.set_muted(Channel::new(2), true);
+        assert_eq!(render(&mut player), stems[0]);
+        let mut player = Player::new(&module, 1000);
+        player.set_solo(Channel::new(2), true);
+        assert_eq!(render(&mut player), stems[1]);
+        player.set_muted(Channel::new(2), true);
+        assert!(player.audible_channels().is_empty());
+    }
+}
@@ -0,0 +1,825 @@
+//! Channel state and effect processing
+//!
+//! Effects are processed in two parts, [`ChannelState::row_start`] handles the first tick of the
+//! row (triggering notes, setting values and fine slides) and [`ChannelState::tick`] handles all
+//! the following ticks (slides and oscillators). Effect memory is kept per channel.
+
+use super::*;
+use super::filter::{self, Filter, MacroFilter};
+use super::voice::UNITS_PER_SEMITONE;
+
+
+/// Quarter of a sine wave with amplitude `64`, oscillators use 64 positions per period.
+const SINE: [i8; 17] = [0, 6, 12, 19, 24, 30, 36, 41, 45, 49, 53, 56, 59, 61, 63, 64, 64];
+
+#[derive(Clone)]
+pub(crate) struct ChannelState {
+    channel: Channel,
+    pub(crate) voice: Option<Voice>,
+
+    /// Channel volume (`0..=64`)
+    volume: u8,
+
+    /// Channel panning (`0..=64`)
+    pan: u8,
+    surround: bool,
+
+    /// Last instrument (or sample in sample mode) used on the channel
+    instrument: Option<InstrumentId>,
+
+    /// Filter cutoff and resonance (`0..=127`) of the notes played on the channel
+    cutoff: u8,
+    resonance: u8,
+
+    /// Parametered MIDI macro run by `Zxx` (`SFx`)
+    midi_macro: u8,
+
+    /// Commands of the current row processed on the following ticks
+    effect: Option<EffectCmd>,
+    volume_command: Option<VolumeCmd>,
+
+    /// Row the current command was triggered on
+    row: usize,
+
+    // Effect memory
+    volume_slide: Option<VolumeSlide>,
+    channel_volume_slide: Option<VolumeSlide>,
+    global_volume_slide: Option<VolumeSlide>,
+    panning_slide: Option<PanningSlide>,
+    portamento: Option<Portamento>,
+    volume_column_slide: u8,
+    tone_portamento: u8,
+    tone_portamento_target: Option<f64>,
+    vibrato: Oscillator,
+    tremolo: Oscillator,
+    panbrello: Oscillator,
+    tremor: (u8, u8),
+    tremor_ticks: u8,
+    arpeggio: (u8, u8),
+    retrigger: (u8, u8),
+    retrigger_ticks: u8,
+    sample_offset_low: u8,
+    sample_offset_high: u8,
+    tempo_slide: Option<Tempo>,
+    special: Option<Special>,
+
+    /// Tick of the current row the note is cut on (`SCx`)
+    note_cut: Option<u8>,
+
+    /// Tick of the current row the delayed command is triggered on (`SDx`)
+    note_delay: Option<(u8, Command)>,
+
+    /// Pattern loop start row and the remaining repeats (`SBx`)
+    loop_start: usize,
+    loop_count: u8,
+}
+
+/// Vibrato, tremolo or panbrello state
+#[derive(Clone, Copy)]
+struct Oscillator {
+    speed: u8,
+    depth: u8,
+    waveform: Waveform,
+    position: u8,
+}
+
+
+impl ChannelState {
+    pub(crate) fn new(module: &Module, channel: Channel) -> ChannelState {
+        let (pan, surround) = match resolve_initial_pan(module, channel, None, None, Note::C_5) {
+            Pan::Position(pan) => (pan.as_u8(), false),
+            Pan::Surround => (32, true),
+        };
+        ChannelState {
+            channel,
+            voice: None,
+            volume: module.init_channel_volume[channel.as_usize()].as_u8(),
+            pan,
+            surround,
+            instrument: None,
+            cutoff: filter::MAX,
+            resonance: 0,
+            midi_macro: 0,
+            effect: None,
+            volume_command: None,
+            row: 0,
+            volume_slide: None,
+            channel_volume_slide: None,
+            global_volume_slide: None,
+            panning_slide: None,
+            portamento: None,
+            volume_column_slide: 0,
+            tone_portamento: 0,
+            tone_portamento_target: None,
+            vibrato: Oscillator::default(),
+            tremolo: Oscillator::default(),
+            panbrello: Oscillator::default(),
+            tremor: (0, 0),
+            tremor_ticks: 0,
+            arpeggio: (0, 0),
+            retrigger: (0, 0),
+            retrigger_ticks: 0,
+            sample_offset_low: 0,
+            sample_offset_high: 0,
+            tempo_slide: None,
+            special: None,
+            note_cut: None,
+            note_delay: None,
+            loop_start: 0,
+            loop_count: 0,
+        }
+    }
+
+    /// Channel volume (`0..=64`)
+    pub(crate) fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    /// Channel panning (`0..=64`), `None` for surround
+    pub(crate) fn pan(&self) -> Option<u8> {
+        (!self.surround).then_some(self.pan)
+    }
+
+    pub(crate) fn reset_pattern_loop(&mut self) {
+        self.loop_start = 0;
+        self.loop_count = 0;
+    }
+
+    /// Processes the first tick of a row.
+    pub(crate) fn row_start(
+        &mut self,
+        module: &Module,
+        global: &mut Global,
+        background: &mut Vec<Voice>,
+        command: Option<&Command>,
+        row: usize,
+    ) {
+        self.effect = None;
+        self.volume_command = None;
+        self.note_cut = None;
+        self.note_delay = None;
+        self.clear_offsets();
+
+        let command = match command {
+            Some(command) => *command,
+            None => return,
+        };
+        if let Some(EffectCmd::Special(special)) = command.effect {
+            if let Some(Special::NoteDelay(delay)) = special.or(self.special) {
+                if delay.as_u8() > 0 {
+                    self.special = Some(Special::NoteDelay(delay));
+                    self.note_delay = Some((delay.as_u8(), command));
+                    return;
+                }
+            }
+        }
+        self.trigger(module, global, background, command, row);
+    }
+
+    /// Processes a tick other than the first one of a row.
+    pub(crate) fn tick(
+        &mut self,
+        module: &Module,
+        global: &mut Global,
+        background: &mut Vec<Voice>,
+        tick: u8,
+        row: usize,
+    ) {
+        self.clear_offsets();
+
+        if let Some((delay, command)) = self.note_delay {
+            if tick == delay {
+                self.note_delay = None;
+                self.trigger(module, global, background, command, row);
+            }
+            return;
+        }
+        if self.note_cut == Some(tick) {
+            self.voice = None;
+        }
+
+        if let Some(volume) = self.volume_command {
+            self.volume_column_tick(global, volume);
+        }
+        if let Some(effect) = self.effect {
+            self.effect_tick(global, effect, tick);
+        }
+    }
+
+    /// Copies the channel values to the voice and computes its mixing parameters.
+    pub(crate) fn update_voice(&mut self, module: &Module, global_volume: u8, sample_rate: u32) {
+        if let Some(voice) = &mut self.voice {
+            voice.channel_volume = self.volume;
+            voice.pan = (!self.surround).then_some(self.pan);
+            voice.update(module, global_volume, sample_rate);
+            if !voice.is_active() {
+                self.voice = None;
+            }
+        }
+    }
+
+    fn clear_offsets(&mut self) {
+        if let Some(voice) = &mut self.voice {
+            voice.pitch_offset = 0;
+            voice.volume_offset = 0;
+            voice.pan_offset = 0;
+        }
+    }
+
+    /// Triggers the note and processes the first tick of the commands.
+    fn trigger(
+        &mut self,
+        module: &Module,
+        global: &mut Global,
+        background: &mut Vec<Voice>,
+        command: Command,
+        row: usize,
+    ) {
+        self.row = row;
+        self.effect = command.effect;
+        self.volume_command = command.volume;
+
+        let tone_portamento = matches!(
+            command.effect,
+            Some(EffectCmd::TonePortamento(_)) | Some(EffectCmd::VolumeSlideAndPortamento(_))
+        ) || matches!(command.volume, Some(VolumeCmd::TonePortamento(_)));
+
+        if let Some(instrument) = command.instrument {
+            self.instrument = Some(instrument);
+        }
+        if let Some(EffectCmd::SetSampleOffset(SetSampleOffset::Low(low))) = command.effect {
+            if low > 0 {
+                self.sample_offset_low = low;
+            }
+        }
+
+        match command.note {
+            Some(NoteCmd::Play(note)) => match &mut self.voice {
+                Some(voice) if tone_portamento => {
+                    self.tone_portamento_target = Some(voice.note_frequency(module, note));
+                }
+                _ => {
+                    let offset = match command.effect {
+                        Some(EffectCmd::SetSampleOffset(SetSampleOffset::Low(_))) => {
+                            u32::from(self.sample_offset_high) << 16 | u32::from(self.sample_offset_low) << 8
+                        }
+                        _ => 0,
+                    };
+                    self.play_note(module, background, note, offset);
+                }
+            },
+            Some(NoteCmd::Off) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.note_off(module);
+                }
+            }
+            Some(NoteCmd::Cut) => self.voice = None,
+            Some(NoteCmd::Fade) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.note_fade();
+                }
+            }
+            // Plugins are not played.
+            Some(NoteCmd::ParamControl { .. }) => {}
+            None => {
+                // An instrument without a note resets the volume.
+                if let (Some(voice), Some(_)) = (&mut self.voice, command.instrument) {
+                    voice.volume = module[voice.sample()].default_volume.min(64);
+                }
+            }
+        }
+
+        if let Some(volume) = command.volume {
+            self.volume_column_first_tick(volume);
+        }
+        if let Some(effect) = command.effect {
+            self.effect_first_tick(module, global, background, effect);
+        }
+    }
+
+    /// Starts playing a new note, the previous note is moved to the background according to its
+    /// new note action.
+    fn play_note(&mut self, module: &Module, background: &mut Vec<Voice>, note: Note, offset: u32) {
+        let instrument_mode = module.flags.contains(ModuleFlags::USE_INSTRUMENTS);
+        let (instrument, sample) = if instrument_mode {
+            let sample = self.instrument
+                .and_then(|id| module.get(id))
+                .and_then(|instrument| instrument.sample_map[note]);
+            (self.instrument, sample)
+        } else {
+            let sample = self.instrument.and_then(|id| SampleId::try_from(id.as_u8()).ok());
+            (None, sample)
+        };
+
+        if let Some(mut voice) = self.voice.take() {
+            if instrument_mode {
+                voice.apply_nna(module, voice.nna);
+                if voice.is_active() {
+                    push_background(background, voice);
+                }
+            }
+        }
+
+        let mut voice = match sample.and_then(|id| Voice::new(module, self.channel, instrument, id, note, offset)) {
+            Some(voice) => voice,
+            None => return,
+        };
+        let sample = &module[voice.sample()];
+        let instrument = instrument.map(|id| &module[id]);
+
+        if let Some(instrument) = instrument {
+            if instrument.flags.contains(InstrumentFlags::ENABLE_FILTER_CUTOFF) {
+                self.cutoff = instrument.initial_filter_cutoff.as_u8();
+            }
+            if instrument.flags.contains(InstrumentFlags::ENABLE_FILTER_RESONANCE) {
+                self.resonance = instrument.initial_filter_resonance.as_u8();
+            }
+        }
+        voice.filter = Filter::new(self.cutoff, self.resonance);
+
+        // Default panning of the instrument or sample replaces the channel panning.
+        let default_pan = sample.default_pan().is_some() || instrument.is_some_and(|instrument| {
+            instrument.flags.contains(InstrumentFlags::ENABLE_PANNING)
+        });
+        if default_pan {
+            if let Pan::Position(pan) = resolve_initial_pan(module, self.channel, instrument, Some(sample), note) {
+                self.pan = pan.as_u8();
+                self.surround = false;
+            }
+        }
+        self.voice = Some(voice);
+        self.vibrato.position = 0;
+        self.tremolo.position = 0;
+        self.panbrello.position = 0;
+        self.retrigger_ticks = 0;
+        self.tremor_ticks = 0;
+    }
+
+    fn volume_column_first_tick(&mut self, volume: VolumeCmd) {
+        match volume {
+            VolumeCmd::SetVolume(value) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.volume = value.as_u8();
+                }
+            }
+            VolumeCmd::Panning(pan) => {
+                self.pan = pan.as_u8();
+                self.surround = false;
+            }
+            VolumeCmd::FineVolumeUp(value) => {
+                let value = remember_u8(&mut self.volume_column_slide, value);
+                self.slide_volume(i16::from(value));
+            }
+            VolumeCmd::FineVolumeDown(value) => {
+                let value = remember_u8(&mut self.volume_column_slide, value);
+                self.slide_volume(-i16::from(value));
+            }
+            VolumeCmd::VolumeSlideUp(value) | VolumeCmd::VolumeSlideDown(value) => {
+                remember_u8(&mut self.volume_column_slide, value);
+            }
+            VolumeCmd::PortamentoDown(value) | VolumeCmd::PortamentoUp(value) => {
+                if let Some(value) = value {
+                    self.portamento = Some(Portamento::Coarse(RangedU8::try_from(value.as_u8() * 4).unwrap()));
+                }
+            }
+            VolumeCmd::TonePortamento(value) => {
+                if let Some(value) = value {
+                    self.tone_portamento = TONE_PORTAMENTO_SPEEDS[usize::from(value.as_u8())];
+                }
+            }
+            VolumeCmd::Vibrato(depth) => {
+                if let Some(depth) = depth {
+                    self.vibrato.depth = depth.as_u8();
+                }
+            }
+        }
+    }
+
+    fn volume_column_tick(&mut self, global: &mut Global, volume: VolumeCmd) {
+        match volume {
+            VolumeCmd::VolumeSlideUp(_) => self.slide_volume(i16::from(self.volume_column_slide)),
+            VolumeCmd::VolumeSlideDown(_) => self.slide_volume(-i16::from(self.volume_column_slide)),
+            VolumeCmd::PortamentoDown(_) => self.portamento_tick(-4),
+            VolumeCmd::PortamentoUp(_) => self.portamento_tick(4),
+            VolumeCmd::TonePortamento(_) => self.tone_portamento_tick(),
+            VolumeCmd::Vibrato(_) => self.vibrato_tick(global, false),
+            _ => {}
+        }
+    }
+
+    fn effect_first_tick(&mut self, module: &Module, global: &mut Global, background: &mut [Voice], effect: EffectCmd) {
+        match effect {
+            EffectCmd::SetSpeed(speed) => global.speed = speed.as_u8(),
+            EffectCmd::JumpOrder(order) => global.jump_order = Some(usize::from(order)),
+            EffectCmd::BreakRow(row) => global.break_row = Some(usize::from(row)),
+            EffectCmd::VolumeSlide(slide)
+            | EffectCmd::VolumeSlideAndVibrato(slide)
+            | EffectCmd::VolumeSlideAndPortamento(slide) => {
+                remember(&mut self.volume_slide, slide);
+                if let Some(amount) = fine_slide(self.volume_slide) {
+                    self.slide_volume(amount);
+                }
+            }
+            EffectCmd::PortamentoDown(portamento) | EffectCmd::PortamentoUp(portamento) => {
+                remember(&mut self.portamento, portamento);
+                let direction = if let EffectCmd::PortamentoUp(_) = effect { 1 } else { -1 };
+                let units = match self.portamento {
+                    Some(Portamento::Fine(value)) => i16::from(value.as_u8()) * 4,
+                    Some(Portamento::ExtraFine(value)) => i16::from(value.as_u8()),
+                    _ => 0,
+                };
+                if let Some(voice) = &mut self.voice {
+                    voice.slide(direction * units);
+                }
+            }
+            EffectCmd::TonePortamento(speed) => {
+                if let Some(speed) = speed {
+                    self.tone_portamento = speed.as_u8();
+                }
+            }
+            EffectCmd::Vibrato(speed, depth) | EffectCmd::FineVibrato(speed, depth) => {
+                self.vibrato.set(speed, depth);
+            }
+            EffectCmd::Tremor(tremor) => {
+                if let Some((on, off)) = tremor {
+                    self.tremor = (on.as_u8(), off.as_u8());
+                }
+            }
+            EffectCmd::Arpeggio(arpeggio) => {
+                if let Some((x, y)) = arpeggio {
+                    self.arpeggio = (x.as_u8(), y.as_u8());
+                }
+            }
+            EffectCmd::SetChannelVolume(volume) => self.volume = volume.as_u8(),
+            EffectCmd::ChannelVolumeSlide(slide) => {
+                remember(&mut self.channel_volume_slide, slide);
+                if let Some(amount) = fine_slide(self.channel_volume_slide) {
+                    self.volume = add_clamped(self.volume, amount, 64);
+                }
+            }
+            EffectCmd::SetSampleOffset(SetSampleOffset::High(high)) => self.sample_offset_high = high.as_u8(),
+            EffectCmd::SetSampleOffset(SetSampleOffset::Low(_)) => {}
+            EffectCmd::PanningSlide(slide) => {
+                remember(&mut self.panning_slide, slide);
+                match self.panning_slide {
+                    Some(PanningSlide::FineRight(value)) => self.slide_pan(i16::from(value.as_u8())),
+                    Some(PanningSlide::FineLeft(value)) => self.slide_pan(-i16::from(value.as_u8())),
+                    _ => {}
+                }
+            }
+            EffectCmd::Retrigger(retrigger) => {
+                if let Some((volume, ticks)) = retrigger {
+                    self.retrigger = (volume.as_u8(), ticks.as_u8());
+                }
+            }
+            EffectCmd::Tremolo(speed, depth) => self.tremolo.set(speed, depth),
+            EffectCmd::Special(special) => {
+                remember(&mut self.special, special);
+                if let Some(special) = self.special {
+                    self.special_first_tick(module, global, background, special);
+                }
+            }
+            EffectCmd::Tempo(tempo) => match tempo.or(self.tempo_slide) {
+                Some(Tempo::Set(tempo)) => global.tempo = tempo.as_u8(),
+                slide => self.tempo_slide = slide,
+            },
+            EffectCmd::SetGlobalVolume(volume) => global.global_volume = volume.as_u8(),
+            EffectCmd::GlobalVolumeSlide(slide) => {
+                remember(&mut self.global_volume_slide, slide);
+                if let Some(amount) = fine_slide(self.global_volume_slide) {
+                    global.global_volume = add_clamped(global.global_volume, amount, 128);
+                }
+            }
+            EffectCmd::SetPanningPosition(pan) => {
+                self.pan = u8::try_from((u16::from(pan) * 64 + 127) / 255).unwrap();
+                self.surround = false;
+            }
+            EffectCmd::Panbrello(speed, depth) => self.panbrello.set(speed, depth),
+            EffectCmd::Midi(param) => {
+                let default;
+                let config = match &module.midi_config {
+                    Some(config) => config,
+                    None => {
+                        default = MidiConfig::default();
+                        &default
+                    }
+                };
+                match filter::macro_filter(config.zxx(self.midi_macro, param), param) {
+                    Some(MacroFilter::Cutoff(cutoff)) => self.cutoff = cutoff,
+                    Some(MacroFilter::Resonance(resonance)) => self.resonance = resonance,
+                    None => return,
+                }
+                if let Some(voice) = &mut self.voice {
+                    voice.filter.cutoff = self.cutoff;
+                    voice.filter.resonance = self.resonance;
+                }
+            }
+        }
+    }
+
+    fn special_first_tick(&mut self, module: &Module, global: &mut Global, background: &mut [Voice], special: Special) {
+        match special {
+            Special::SetVibratoWaveform(waveform) => self.vibrato.waveform = waveform,
+            Special::SetTremoloWaveform(waveform) => self.tremolo.waveform = waveform,
+            Special::SetPanbrelloWaveform(waveform) => self.panbrello.waveform = waveform,
+            Special::PatternTickDelay(ticks) => global.tick_delay = ticks.as_u8(),
+            Special::PastNote(action) => {
+                let action = match action {
+                    SetPastNote::Cut => NewNoteAction::Cut,
+                    SetPastNote::Off => NewNoteAction::Off,
+                    SetPastNote::Fade => NewNoteAction::Fade,
+                };
+                background.iter_mut()
+                    .filter(|voice| voice.channel == self.channel)
+                    .for_each(|voice| voice.apply_nna(module, action));
+            }
+            Special::SetNewNoteAction(action) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.nna = match action {
+                        SetNewNoteAction::Cut => NewNoteAction::Cut,
+                        SetNewNoteAction::Off => NewNoteAction::Off,
+                        SetNewNoteAction::Fade => NewNoteAction::Fade,
+                        SetNewNoteAction::Continue => NewNoteAction::Continue,
+                    };
+                }
+            }
+            Special::SetVolumeEnvelope(enabled) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.volume_envelope.enabled = enabled;
+                }
+            }
+            Special::SetPanningEnvelope(enabled) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.panning_envelope.enabled = enabled;
+                }
+            }
+            Special::SetPitchEnvelope(enabled) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.pitch_envelope.enabled = enabled;
+                }
+            }
+            Special::SetPanning(pan) => {
+                self.pan = u8::try_from((u16::from(pan.as_u8()) * 64 + 7) / 15).unwrap();
+                self.surround = false;
+            }
+            Special::SetSurround(surround) => self.surround = surround,
+            Special::SetDirection(direction) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.backwards = direction == PlayDirection::Backward;
+                }
+            }
+            Special::SetLoopbackPoint => self.loop_start = self.row,
+            Special::LoopbackTimes(times) => {
+                if self.loop_count == 0 {
+                    self.loop_count = times.as_u8();
+                    global.loop_row = Some(self.loop_start);
+                } else {
+                    self.loop_count -= 1;
+                    if self.loop_count > 0 {
+                        global.loop_row = Some(self.loop_start);
+                    } else {
+                        self.loop_start = self.row + 1;
+                    }
+                }
+            }
+            Special::NoteCut(tick) => self.note_cut = Some(tick.as_u8().max(1)),
+            Special::PatternRowDelay(rows) => {
+                if global.row_delay.is_none() {
+                    global.row_delay = Some(rows.as_u8());
+                }
+            }
+            Special::NoteDelay(_)
+            | Special::SetGlissando(_)
+            | Special::SetFinetune(_)
+            | Special::SetReverb(_)
+            | Special::SetSurroundMode(_)
+            | Special::SetFilterMode(_) => {}
+            Special::SetMidiParam(selected) => self.midi_macro = selected.as_u8(),
+        }
+    }
+
+    fn effect_tick(&mut self, global: &mut Global, effect: EffectCmd, tick: u8) {
+        match effect {
+            EffectCmd::VolumeSlide(_) => self.volume_slide_tick(),
+            EffectCmd::VolumeSlideAndVibrato(_) => {
+                self.volume_slide_tick();
+                self.vibrato_tick(global, false);
+            }
+            EffectCmd::VolumeSlideAndPortamento(_) => {
+                self.volume_slide_tick();
+                self.tone_portamento_tick();
+            }
+            EffectCmd::PortamentoDown(_) => self.portamento_tick(-4),
+            EffectCmd::PortamentoUp(_) => self.portamento_tick(4),
+            EffectCmd::TonePortamento(_) => self.tone_portamento_tick(),
+            EffectCmd::Vibrato(..) => self.vibrato_tick(global, false),
+            EffectCmd::FineVibrato(..) => self.vibrato_tick(global, true),
+            EffectCmd::Tremor(_) => {
+                let (on, off) = self.tremor;
+                if self.tremor_ticks >= on {
+                    if let Some(voice) = &mut self.voice {
+                        voice.volume_offset = -64;
+                    }
+                }
+                self.tremor_ticks += 1;
+                if self.tremor_ticks >= on + off {
+                    self.tremor_ticks = 0;
+                }
+            }
+            EffectCmd::Arpeggio(_) => {
+                let semitones = match tick % 3 {
+                    1 => self.arpeggio.0,
+                    2 => self.arpeggio.1,
+                    _ => 0,
+                };
+                if let Some(voice) = &mut self.voice {
+                    voice.pitch_offset = i16::from(semitones) * UNITS_PER_SEMITONE;
+                }
+            }
+            EffectCmd::ChannelVolumeSlide(_) => {
+                if let Some(amount) = coarse_slide(self.channel_volume_slide) {
+                    self.volume = add_clamped(self.volume, amount, 64);
+                }
+            }
+            EffectCmd::PanningSlide(_) => match self.panning_slide {
+                Some(PanningSlide::Right(value)) => self.slide_pan(i16::from(value.as_u8())),
+                Some(PanningSlide::Left(value)) => self.slide_pan(-i16::from(value.as_u8())),
+                _ => {}
+            },
+            EffectCmd::Retrigger(_) => {
+                // Without an interval from the effect memory the note is never retriggered.
+                let (volume, ticks) = self.retrigger;
+                if ticks > 0 {
+                    self.retrigger_ticks += 1;
+                }
+                if ticks > 0 && self.retrigger_ticks >= ticks {
+                    self.retrigger_ticks = 0;
+                    if let Some(voice) = &mut self.voice {
+                        voice.retrigger();
+                        voice.volume = retrigger_volume(voice.volume, volume);
+                    }
+                }
+            }
+            EffectCmd::Tremolo(..) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.volume_offset = self.tremolo.value(global) * i16::from(self.tremolo.depth) / 32;
+                }
+                self.tremolo.advance();
+            }
+            EffectCmd::Tempo(_) => {
+                let tempo = match self.tempo_slide {
+                    Some(Tempo::SlideDown(value)) => global.tempo.saturating_sub(value.as_u8()),
+                    Some(Tempo::SlideUp(value)) => global.tempo.saturating_add(value.as_u8()),
+                    _ => global.tempo,
+                };
+                global.tempo = tempo.max(0x20);
+            }
+            EffectCmd::GlobalVolumeSlide(_) => {
+                if let Some(amount) = coarse_slide(self.global_volume_slide) {
+                    global.global_volume = add_clamped(global.global_volume, amount, 128);
+                }
+            }
+            EffectCmd::Panbrello(..) => {
+                if let Some(voice) = &mut self.voice {
+                    voice.pan_offset = self.panbrello.value(global) * i16::from(self.panbrello.depth) / 32;
+                }
+                self.panbrello.advance();
+            }
+            _ => {}
+        }
+    }
+
+    fn slide_volume(&mut self, amount: i16) {
+        if let Some(voice) = &mut self.voice {
+            voice.volume = add_clamped(voice.volume, amount, 64);
+        }
+    }
+
+    fn slide_pan(&mut self, amount: i16) {
+        self.pan = add_clamped(self.pan, amount, 64);
+    }
+
+    fn volume_slide_tick(&mut self) {
+        if let Some(amount) = coarse_slide(self.volume_slide) {
+            self.slide_volume(amount);
+        }
+    }
+
+    /// Applies the coarse portamento from memory, the value is multiplied by the factor.
+    fn portamento_tick(&mut self, factor: i16) {
+        if let (Some(Portamento::Coarse(value)), Some(voice)) = (self.portamento, &mut self.voice) {
+            voice.slide(i16::from(value.as_u8()) * factor);
+        }
+    }
+
+    fn tone_portamento_tick(&mut self) {
+        if let (Some(target), Some(voice)) = (self.tone_portamento_target, &mut self.voice) {
+            voice.slide_to(target, i16::from(self.tone_portamento) * 4);
+        }
+    }
+
+    fn vibrato_tick(&mut self, global: &mut Global, fine: bool) {
+        if let Some(voice) = &mut self.voice {
+            let divisor = if fine { 64 } else { 16 };
+            voice.pitch_offset = self.vibrato.value(global) * i16::from(self.vibrato.depth) / divisor;
+        }
+        self.vibrato.advance();
+    }
+}
+
+impl Oscillator {
+    fn set(&mut self, speed: Option<RangedU8<1, 0x0F>>, depth: Option<RangedU8<1, 0x0F>>) {
+        if let Some(speed) = speed {
+            self.speed = speed.as_u8();
+        }
+        if let Some(depth) = depth {
+            self.depth = depth.as_u8();
+        }
+    }
+
+    fn value(&self, global: &mut Global) -> i16 {
+        let random = if self.waveform == Waveform::Random { global.random() } else { 0 };
+        waveform_value(self.waveform, self.position, random)
+    }
+
+    fn advance(&mut self) {
+        self.position = (self.position + self.speed) % 64;
+    }
+}
+
+impl Default for Oscillator {
+    fn default() -> Oscillator {
+        Oscillator { speed: 0, depth: 0, waveform: Waveform::Sine, position: 0 }
+    }
+}
+
+/// Returns the value of the waveform (`-64..=64`) at the position (`0..64`).
+fn waveform_value(waveform: Waveform, position: u8, random: i16) -> i16 {
+    let position = position % 64;
+    match waveform {
+        Waveform::Sine => {
+            let index = usize::from(position % 32);
+            let value = i16::from(if index <= 16 { SINE[index] } else { SINE[32 - index] });
+            if position < 32 { value } else { -value }
+        }
+        Waveform::Sawtooth => 64 - i16::from(position) * 2,
+        Waveform::Square if position < 32 => 64,
+        Waveform::Square => -64,
+        Waveform::Random => random,
+    }
+}
+
+/// Replaces the memory with the value if it is set.
+fn remember<T: Copy>(memory: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *memory = value;
+    }
+}
+
+/// Replaces the memory with the value if it is set and returns the value in effect.
+fn remember_u8<const LOW: u8, const HIGH: u8>(memory: &mut u8, value: Option<RangedU8<LOW, HIGH>>) -> u8 {
+    if let Some(value) = value {
+        *memory = value.as_u8();
+    }
+    *memory
+}
+
+/// Returns the amount of a volume slide applied on the first tick.
+fn fine_slide(slide: Option<VolumeSlide>) -> Option<i16> {
+    match slide? {
+        VolumeSlide::FineUp(value) => Some(i16::from(value.as_u8())),
+        VolumeSlide::FineDown(value) => Some(-i16::from(value.as_u8())),
+        // `D0F` slides on all ticks.
+        VolumeSlide::Down(value) if value.as_u8() == 0x0F => Some(-0x0F),
+        _ => None,
+    }
+}
+
+/// Returns the amount of a volume slide applied on the ticks after the first one.
+fn coarse_slide(slide: Option<VolumeSlide>) -> Option<i16> {
+    match slide? {
+        VolumeSlide::Up(value) => Some(i16::from(value.as_u8())),
+        VolumeSlide::Down(value) => Some(-i16::from(value.as_u8())),
+        _ => None,
+    }
+}
+
+/// Adds the amount to the value clamping the result to `0..=max`.
+fn add_clamped(value: u8, amount: i16, max: u8) -> u8 {
+    u8::try_from((i16::from(value) + amount).clamp(0, i16::from(max))).unwrap()
+}
+
+/// Applies the `Qxy` volume modifier `x` to the volume.
+fn retrigger_volume(volume: u8, modifier: u8) -> u8 {
+    let volume = i16::from(volume);
+    let volume = match modifier {
+        1..=5 => volume - (1 << (modifier - 1)),
+        6 => volume * 2 / 3,
+        7 => volume / 2,
+        9..=0x0D => volume + (1 << (modifier - 9)),
+        0x0E => volume * 3 / 2,
+        0x0F => volume * 2,
+        _ => volume,
+    };
+    u8::try_from(volume.clamp(0, 64)).unwrap()
+}
@@ -0,0 +1,151 @@
+//! Resonant low-pass filter
+//!
+//! The two-pole filter of Impulse Tracker. The cutoff and resonance (both `0..=127`) come from
+//! the instrument and are changed by `Zxx` through the MIDI macros, the filter envelope scales the
+//! cutoff. The frequency of the cutoff is `110 * 2^(0.25 + cutoff * modifier / 48)` Hz where the
+//! modifier goes from `0` to `2`, `2` without a filter envelope, the resonance damps by up to
+//! 24 dB. A voice with the cutoff at the maximum and no resonance is not filtered.
+
+use super::*;
+use core::f32::consts::PI;
+
+
+/// Maximum cutoff and resonance
+pub(crate) const MAX: u8 = 127;
+
+/// Filter parameter set by a MIDI macro
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum MacroFilter {
+    Cutoff(u8),
+    Resonance(u8),
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Filter {
+    /// Cutoff (`0..=127`)
+    pub(crate) cutoff: u8,
+
+    /// Resonance (`0..=127`)
+    pub(crate) resonance: u8,
+
+    /// Coefficients of the input and the two previous outputs, `None` if the filter is off
+    coefficients: Option<[f32; 3]>,
+
+    /// Two previous outputs of each channel
+    history: [[f32; 2]; 2],
+}
+
+
+impl Filter {
+    pub(crate) fn new(cutoff: u8, resonance: u8) -> Filter {
+        Filter {
+            cutoff: cutoff.min(MAX),
+            resonance: resonance.min(MAX),
+            coefficients: None,
+            history: [[0.0; 2]; 2],
+        }
+    }
+
+    /// Computes the coefficients for the envelope value (`-32.0..=32.0`) of the filter envelope,
+    /// `None` without a filter envelope.
+    pub(crate) fn update(&mut self, envelope: Option<f32>, sample_rate: u32) {
+        // The modifier is 256 for the top of the envelope and when there is no envelope.
+        let modifier = envelope.map_or(256.0, |value| value.clamp(-32.0, 32.0) * 8.0);
+        let cutoff = (f32::from(self.cutoff) * (modifier + 256.0) / 256.0).min(255.0);
+        if cutoff >= 254.0 && self.resonance == 0 {
+            if self.coefficients.take().is_some() {
+                self.history = [[0.0; 2]; 2];
+            }
+            return;
+        }
+
+        #[allow(clippy::as_conversions, clippy::cast_precision_loss)]
+        let sample_rate = sample_rate as f32;
+        let frequency = (110.0 * float::powf(2.0, 0.25 + cutoff / 48.0)).min(sample_rate / 2.0);
+        let damping = float::powf(10.0, -f32::from(self.resonance) * 24.0 / 128.0 / 20.0);
+        let r = sample_rate / (2.0 * PI * frequency);
+        let d = damping * r + damping - 1.0;
+        let e = r * r;
+        let norm = 1.0 + d + e;
+        self.coefficients = Some([1.0 / norm, (d + e + e) / norm, -e / norm]);
+    }
+
+    /// Filters the next value of the channel (`0` or `1`).
+    pub(crate) fn process(&mut self, value: f32, channel: usize) -> f32 {
+        match self.coefficients {
+            Some([input, first, second]) => {
+                let history = &mut self.history[channel];
+                let output = input * value + first * history[0] + second * history[1];
+                *history = [output, history[0]];
+                output
+            }
+            None => value,
+        }
+    }
+}
+
+/// Returns the filter parameter the macro sets, `None` if the macro doesn't control the filter.
+///
+/// The macro is a sequence of hexadecimal bytes and letters replaced by values, the filter is set
+/// by `F0 F0 00 v` (cutoff) and `F0 F0 01 v` (resonance). `z` is replaced by the parameter of
+/// `Zxx` and `0` by the other letters.
+pub(crate) fn macro_filter(midi_macro: &MidiMacro, param: u8) -> Option<MacroFilter> {
+    let mut bytes = [0u8; 4];
+    let mut count = 0;
+    let mut high = None;
+    for character in midi_macro.bytes.iter().take_while(|&&byte| byte != 0) {
+        let value = match character {
+            b' ' => continue,
+            b'0'..=b'9' | b'A'..=b'F' => {
+                let digit = char::from(*character).to_digit(16).unwrap();
+                match high.take() {
+                    Some(high) => u8::try_from(high * 16 + digit).unwrap(),
+                    None => {
+                        high = Some(digit);
+                        continue;
+                    }
+                }
+            }
+            b'z' => param & 0x7F,
+            _ => 0,
+        };
+        *bytes.get_mut(count)? = value;
+        count += 1;
+    }
+    match (count, bytes) {
+        (4, [0xF0, 0xF0, 0x00, value]) => Some(MacroFilter::Cutoff(value.min(MAX))),
+        (4, [0xF0, 0xF0, 0x01, value]) => Some(MacroFilter::Resonance(value.min(MAX))),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn macros() {
+        let config = MidiConfig::default();
+        assert_eq!(macro_filter(config.zxx(0, 0x40), 0x40), Some(MacroFilter::Cutoff(0x40)));
+        assert_eq!(macro_filter(&MidiMacro::new("F0 F0 01 z"), 0x90), Some(MacroFilter::Resonance(0x10)));
+        assert_eq!(macro_filter(&MidiMacro::new("F0F0017F"), 0), Some(MacroFilter::Resonance(0x7F)));
+        assert_eq!(macro_filter(&MidiMacro::new("9c n v"), 0), None);
+        assert_eq!(macro_filter(&MidiMacro::new("F0F000z00"), 0), None);
+    }
+
+    #[test]
+    fn low_pass() {
+        let mut filter = Filter::new(MAX, 0);
+        filter.update(None, 44_100);
+        assert_eq!(filter.process(0.5, 0), 0.5);
+
+        // A fast alternating signal is damped, a constant one passes.
+        let mut filter = Filter::new(0x20, 0);
+        filter.update(None, 44_100);
+        let alternating = (0..1000).map(|i| filter.process(if i % 2 == 0 { 1.0 } else { -1.0 }, 0)).last().unwrap();
+        assert!(alternating.abs() < 0.01);
+        let constant = (0..10_000).map(|_| filter.process(1.0, 0)).last().unwrap();
+        assert!((constant - 1.0).abs() < 0.01);
+    }
+}
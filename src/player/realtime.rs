@@ -0,0 +1,160 @@
+//! Real-time playback on the default output device
+
+use super::*;
+use crate::error::PlaybackError;
+use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use cpal::{Device, OutputCallbackInfo, SampleFormat, Stream, StreamConfig};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+
+/// Number of frames rendered at once when the device doesn't have two channels
+const BUFFER_FRAMES: usize = 1024;
+
+
+/// Module playing on an output device
+///
+/// Created by [`play`], playback stops when this is dropped.
+pub struct Playback {
+    // Kept alive for the sound to keep playing.
+    _stream: Stream,
+    shared: Arc<Mutex<Shared>>,
+    sample_rate: u32,
+}
+
+/// State shared with the audio callback
+struct Shared {
+    player: Player<Arc<Module>>,
+    paused: bool,
+}
+
+/// Starts playing the module on the default output device.
+///
+/// The module is played once at the sample rate of the device, the device is asked for `f32`
+/// samples. Errors reported by the stream after it has started are ignored.
+///
+/// # Errors
+///
+/// Returns an error if there is no output device or it can't output `f32` samples, or if the
+/// stream fails to open.
+pub fn play(module: Arc<Module>) -> Result<Playback, PlaybackError> {
+    let device = cpal::default_host()
+        .default_output_device()
+        .ok_or(PlaybackError::NoDevice)?;
+    let config = output_config(&device)?;
+    let sample_rate = config.sample_rate.0;
+    let channels = usize::from(config.channels);
+
+    let shared = Arc::new(Mutex::new(Shared {
+        player: Player::new(module, sample_rate),
+        paused: false,
+    }));
+    let callback_shared = Arc::clone(&shared);
+    let mut scratch = vec![0.0; 2 * BUFFER_FRAMES];
+    let stream = device.build_output_stream(
+        &config,
+        move |data: &mut [f32], _: &OutputCallbackInfo| {
+            // Never wait for the controls, a missed buffer is better than a stalled device.
+            match callback_shared.try_lock() {
+                Ok(mut shared) => shared.fill(data, channels, &mut scratch),
+                Err(_) => data.fill(0.0),
+            }
+        },
+        |_| {},
+        None,
+    )?;
+    stream.play()?;
+
+    Ok(Playback {
+        _stream: stream,
+        shared,
+        sample_rate,
+    })
+}
+
+/// Picks a stream configuration with `f32` samples, preferring the device default.
+fn output_config(device: &Device) -> Result<StreamConfig, PlaybackError> {
+    let default = device.default_output_config()?;
+    if default.sample_format() == SampleFormat::F32 {
+        return Ok(default.config());
+    }
+    let rate = default.sample_rate();
+    let mut ranges = device.supported_output_configs()?
+        .filter(|range| range.sample_format() == SampleFormat::F32)
+        .collect::<Vec<_>>();
+    match ranges.iter().position(|range| (range.min_sample_rate()..=range.max_sample_rate()).contains(&rate)) {
+        Some(index) => Ok(ranges.swap_remove(index).with_sample_rate(rate).config()),
+        None => ranges.pop()
+            .map(|range| range.with_max_sample_rate().config())
+            .ok_or(PlaybackError::UnsupportedFormat),
+    }
+}
+
+impl Playback {
+    /// Pauses playback, the device outputs silence until [`Playback::resume`] is called.
+    pub fn pause(&self) {
+        self.lock().paused = true;
+    }
+
+    /// Resumes paused playback.
+    pub fn resume(&self) {
+        self.lock().paused = false;
+    }
+
+    /// Returns `true` if playback is paused.
+    pub fn is_paused(&self) -> bool {
+        self.lock().paused
+    }
+
+    /// Moves playback to the start of the order, see [`Player::seek`].
+    pub fn seek(&self, order: usize) {
+        self.lock().player.seek(order);
+    }
+
+    /// Returns the current position as the index into the order list and the row.
+    pub fn position(&self) -> (usize, usize) {
+        self.lock().player.position()
+    }
+
+    /// Returns `true` if the song has ended.
+    pub fn is_finished(&self) -> bool {
+        self.lock().player.is_finished()
+    }
+
+    /// Returns the sample rate of the output device.
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Shared> {
+        // The state stays consistent even if a panic interrupted rendering.
+        self.shared.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Shared {
+    /// Fills the device buffer with `channels` interleaved channels.
+    ///
+    /// Mono devices get both channels mixed, devices with more channels get silence in the
+    /// extra ones.
+    fn fill(&mut self, data: &mut [f32], channels: usize, scratch: &mut [f32]) {
+        if self.paused || channels == 0 {
+            data.fill(0.0);
+        } else if channels == 2 {
+            self.player.render(data);
+        } else {
+            for frames in data.chunks_mut(channels * BUFFER_FRAMES) {
+                let count = frames.len() / channels;
+                let stereo = &mut scratch[..2 * count];
+                self.player.render(stereo);
+                for (frame, pair) in frames.chunks_exact_mut(channels).zip(stereo.chunks_exact(2)) {
+                    if channels == 1 {
+                        frame[0] = 0.5 * (pair[0] + pair[1]);
+                    } else {
+                        frame[..2].copy_from_slice(pair);
+                        frame[2..].fill(0.0);
+                    }
+                }
+            }
+        }
+    }
+}
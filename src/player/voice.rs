@@ -0,0 +1,477 @@
+//! Playing notes
+//!
+//! A [`Voice`] is a single note playing a sample. The voice of a channel is controlled by the
+//! channel effects, voices moved to the background by new note actions keep the last values they
+//! got from their channel and only progress their envelopes and fadeout.
+//!
+//! Voices refer to their sample and instrument by ID, the IDs are checked when the voice is
+//! created and the module doesn't change during playback.
+
+use super::*;
+use super::filter::Filter;
+use crate::data::float;
+
+
+/// Starting value of the note fade component
+const MAX_FADE: u16 = 1024;
+
+/// Number of pitch units in a semitone, slides are done in these units
+pub(crate) const UNITS_PER_SEMITONE: i16 = 64;
+
+#[derive(Clone)]
+pub(crate) struct Voice {
+    /// Channel which played the note
+    pub(crate) channel: Channel,
+
+    sample: SampleId,
+    instrument: Option<InstrumentId>,
+
+    /// Playback position in samples
+    position: f64,
+    pub(crate) backwards: bool,
+
+    /// Playback frequency without the temporary modulations
+    pub(crate) frequency: f64,
+
+    /// Note volume (`0..=64`)
+    pub(crate) volume: u8,
+
+    pub(crate) nna: NewNoteAction,
+    key_on: bool,
+    fading: bool,
+    fade: u16,
+
+    pub(crate) volume_envelope: EnvelopePosition,
+    pub(crate) panning_envelope: EnvelopePosition,
+    pub(crate) pitch_envelope: EnvelopePosition,
+
+    /// The pitch envelope controls the filter cutoff instead of the pitch
+    filter_envelope: bool,
+    pub(crate) filter: Filter,
+
+    /// Channel volume (`0..=64`)
+    pub(crate) channel_volume: u8,
+
+    /// Channel panning (`0..=64`), `None` for surround
+    pub(crate) pan: Option<u8>,
+
+    /// Temporary pitch offset in [`UNITS_PER_SEMITONE`] units (vibrato, arpeggio)
+    pub(crate) pitch_offset: i16,
+
+    /// Ticks played and the current pitch offset of the sample auto-vibrato
+    ticks: u32,
+    auto_vibrato: i16,
+
+    /// Temporary volume offset (tremolo, tremor)
+    pub(crate) volume_offset: i16,
+
+    /// Temporary panning offset (panbrello)
+    pub(crate) pan_offset: i16,
+
+    /// Position increment per output frame
+    step: f64,
+    gain_left: f32,
+    gain_right: f32,
+    active: bool,
+}
+
+/// Playback position in an envelope
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct EnvelopePosition {
+    pub(crate) enabled: bool,
+    tick: u16,
+}
+
+
+impl Voice {
+    /// Starts playing the note, returns `None` if the sample has no data to play.
+    pub(crate) fn new(
+        module: &Module,
+        channel: Channel,
+        instrument_id: Option<InstrumentId>,
+        sample_id: SampleId,
+        note: Note,
+        offset: u32,
+    ) -> Option<Voice> {
+        let sample = module.get(sample_id)?;
+        let instrument = match instrument_id {
+            Some(id) => Some(module.get(id)?),
+            None => None,
+        };
+        let data = sample.data.as_deref().filter(|data| !data.is_empty())?;
+        let frames = data.len() / usize::from(u8::from(sample.channels));
+        let position = if usize::try_from(offset).is_ok_and(|offset| offset < frames) {
+            f64::from(offset)
+        } else {
+            0.0
+        };
+        let envelope = |kind| EnvelopePosition::new(instrument.map(|instrument| instrument.envelope(kind)));
+        Some(Voice {
+            channel,
+            sample: sample_id,
+            instrument: instrument_id,
+            position,
+            backwards: false,
+            frequency: note_frequency(sample, instrument, note),
+            volume: sample.default_volume.min(64),
+            nna: instrument.map_or(NewNoteAction::Cut, Instrument::note_action),
+            key_on: true,
+            fading: false,
+            fade: MAX_FADE,
+            volume_envelope: envelope(EnvelopeKind::Volume),
+            panning_envelope: envelope(EnvelopeKind::Panning),
+            pitch_envelope: envelope(EnvelopeKind::PitchFilter),
+            filter_envelope: instrument.is_some_and(|instrument| {
+                instrument.pitch_filter_envelope.flags.contains(EnvelopeFlags::FILTER)
+            }),
+            filter: Filter::new(filter::MAX, 0),
+            channel_volume: 64,
+            pan: Some(32),
+            pitch_offset: 0,
+            ticks: 0,
+            auto_vibrato: 0,
+            volume_offset: 0,
+            pan_offset: 0,
+            step: 0.0,
+            gain_left: 0.0,
+            gain_right: 0.0,
+            active: true,
+        })
+    }
+
+    pub(crate) fn sample(&self) -> SampleId {
+        self.sample
+    }
+
+    pub(crate) fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Returns the frequency the sample of this voice plays the note at.
+    pub(crate) fn note_frequency(&self, module: &Module, note: Note) -> f64 {
+        note_frequency(&module[self.sample], self.instrument.map(|id| &module[id]), note)
+    }
+
+    /// Restarts the sample from the beginning.
+    pub(crate) fn retrigger(&mut self) {
+        self.position = 0.0;
+        self.backwards = false;
+    }
+
+    /// Slides the pitch by the number of units.
+    pub(crate) fn slide(&mut self, units: i16) {
+        self.frequency *= pitch_ratio(units);
+    }
+
+    /// Slides the pitch towards the target frequency by the number of units.
+    pub(crate) fn slide_to(&mut self, target: f64, units: i16) {
+        if self.frequency < target {
+            self.frequency = (self.frequency * pitch_ratio(units)).min(target);
+        } else {
+            self.frequency = (self.frequency * pitch_ratio(-units)).max(target);
+        }
+    }
+
+    /// Releases the note, sustain loops end and the note starts fading out if its volume envelope
+    /// wouldn't end it.
+    pub(crate) fn note_off(&mut self, module: &Module) {
+        self.key_on = false;
+        if let Some(instrument) = self.instrument {
+            let envelope = &module[instrument].volume_envelope;
+            if !self.volume_envelope.enabled || envelope.flags.contains(EnvelopeFlags::LOOP) {
+                self.fading = true;
+            }
+        }
+    }
+
+    /// Starts fading out the note, notes played without an instrument are cut.
+    pub(crate) fn note_fade(&mut self) {
+        if self.instrument.is_some() {
+            self.fading = true;
+        } else {
+            self.active = false;
+        }
+    }
+
+    pub(crate) fn apply_nna(&mut self, module: &Module, action: NewNoteAction) {
+        match action {
+            NewNoteAction::Cut => self.active = false,
+            NewNoteAction::Continue => {}
+            NewNoteAction::Off => self.note_off(module),
+            NewNoteAction::Fade => self.note_fade(),
+        }
+    }
+
+    /// Progresses envelopes and fadeout by one tick and computes the mixing parameters.
+    pub(crate) fn update(&mut self, module: &Module, global_volume: u8, sample_rate: u32) {
+        if !self.active {
+            return;
+        }
+        self.auto_vibrato = module[self.sample].auto_vibrato().offset_at(self.ticks);
+        self.ticks = self.ticks.saturating_add(1);
+
+        let instrument = match self.instrument {
+            Some(instrument) => &module[instrument],
+            None => {
+                self.compute_mix(module, global_volume, sample_rate, 64.0, 0.0, 0.0);
+                self.filter.update(None, sample_rate);
+                return;
+            }
+        };
+
+        let volume = self.volume_envelope.value(&instrument.volume_envelope, 64.0);
+        let pan = self.panning_envelope.value(&instrument.panning_envelope, 0.0);
+        let pitch = self.pitch_envelope.value(&instrument.pitch_filter_envelope, 0.0);
+        let (pitch, filter) = if self.filter_envelope {
+            (0.0, self.pitch_envelope.enabled.then_some(pitch))
+        } else {
+            (pitch, None)
+        };
+
+        if self.volume_envelope.advance(&instrument.volume_envelope, self.key_on) {
+            if volume == 0.0 {
+                self.active = false;
+                return;
+            }
+            self.fading = true;
+        }
+        self.panning_envelope.advance(&instrument.panning_envelope, self.key_on);
+        self.pitch_envelope.advance(&instrument.pitch_filter_envelope, self.key_on);
+
+        if self.fading {
+            self.fade = self.fade.saturating_sub(u16::from(instrument.instrument_fadeout));
+            if self.fade == 0 {
+                self.active = false;
+                return;
+            }
+        }
+
+        self.compute_mix(module, global_volume, sample_rate, volume, pan, pitch);
+        self.filter.update(filter, sample_rate);
+    }
+
+    /// Computes the gains and step from the current state and the envelope values.
+    fn compute_mix(
+        &mut self,
+        module: &Module,
+        global_volume: u8,
+        sample_rate: u32,
+        volume_envelope: f32,
+        panning_envelope: f32,
+        pitch_envelope: f32,
+    ) {
+        let volume = (i16::from(self.volume) + self.volume_offset).clamp(0, 64);
+        let mut amplitude = f32::from(volume) / 64.0
+            * f32::from(self.channel_volume) / 64.0
+            * f32::from(module[self.sample].global_volume.min(64)) / 64.0
+            * f32::from(global_volume) / 128.0
+            * volume_envelope / 64.0
+            * f32::from(self.fade) / f32::from(MAX_FADE)
+            * f32::from(module.sample_volume.as_u8()) / 128.0;
+        if let Some(instrument) = self.instrument {
+            amplitude *= f32::from(module[instrument].global_volume.min(128)) / 128.0;
+        }
+
+        let pan = match self.pan {
+            Some(pan) if module.flags.contains(ModuleFlags::STEREO) => {
+                let pan = f32::from((i16::from(pan) + self.pan_offset).clamp(0, 64));
+                // The envelope moves the pan at most to the nearer side.
+                let distance = if pan < 32.0 { pan } else { 64.0 - pan };
+                let pan = pan + panning_envelope * distance / 32.0;
+                32.0 + (pan - 32.0) * f32::from(module.pan_separation.as_u8()) / 128.0
+            }
+            _ => 32.0,
+        };
+        self.gain_left = amplitude * (64.0 - pan) / 64.0;
+        self.gain_right = amplitude * pan / 64.0;
+
+        // Pitch envelope values are in half semitones.
+        let units = (f32::from(self.pitch_offset.saturating_add(self.auto_vibrato)) + pitch_envelope * f32::from(UNITS_PER_SEMITONE) / 2.0)
+            / f32::from(UNITS_PER_SEMITONE) / 12.0;
+        self.step = self.frequency * f64::from(float::powf(2.0, units)) / f64::from(sample_rate);
+    }
+
+    /// Mixes the voice into the interleaved stereo buffer.
+    pub(crate) fn mix(&mut self, module: &Module, buffer: &mut [f32]) {
+        if !self.active {
+            return;
+        }
+        let sample = &module[self.sample];
+        let data = sample.data.as_deref().unwrap_or_default();
+        let stereo = sample.is_stereo();
+        for frame in buffer.chunks_exact_mut(2) {
+            match self.next_value(sample, data) {
+                Some([left, right]) => {
+                    let left = self.filter.process(left, 0);
+                    let right = if stereo { self.filter.process(right, 1) } else { left };
+                    frame[0] += left * self.gain_left;
+                    frame[1] += right * self.gain_right;
+                }
+                None => {
+                    self.active = false;
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Moves the position by the number of output frames without mixing, the loops are followed
+    /// but the position inside a bidirectional loop is approximated.
+    pub(crate) fn skip(&mut self, module: &Module, frames: usize) {
+        if !self.active {
+            return;
+        }
+        let sample = &module[self.sample];
+        let length = f64::from(sample.length());
+        let distance = self.step * f64::from(u32::try_from(frames).unwrap_or(u32::MAX));
+        match self.current_loop(sample, length) {
+            Some((start, end, true)) => {
+                // Going back from the end of the loop continues the forward position past it.
+                let span = end - start;
+                let position = if self.backwards { 2.0 * end - self.position } else { self.position } + distance;
+                if position >= end {
+                    let offset = (position - start) % (2.0 * span);
+                    self.backwards = offset >= span;
+                    self.position = if self.backwards { end - (offset - span) } else { start + offset };
+                } else {
+                    self.position = position;
+                    self.backwards = false;
+                }
+            }
+            Some((start, end, false)) => {
+                self.position += distance;
+                if self.position >= end {
+                    self.position = start + (self.position - end) % (end - start);
+                }
+            }
+            None => {
+                self.position += distance;
+                self.active = self.position < length;
+            }
+        }
+    }
+
+    /// Reads the interpolated left and right values at the current position and moves to the next
+    /// one, mono samples have the same value on both sides.
+    fn next_value(&mut self, sample: &Sample, data: &[f32]) -> Option<[f32; 2]> {
+        let channels = usize::from(u8::from(sample.channels));
+        let frames = data.len() / channels;
+        let length = f64::from(u32::try_from(frames).unwrap_or(u32::MAX));
+        let loop_ = self.current_loop(sample, length);
+
+        if self.backwards {
+            let start = loop_.map_or(0.0, |(start, _, _)| start);
+            if self.position < start {
+                match loop_ {
+                    Some((start, end, true)) => {
+                        self.position = (2.0 * start - self.position).clamp(start, end - 1.0);
+                        self.backwards = false;
+                    }
+                    Some((start, end, false)) => {
+                        self.position = end - (start - self.position) % (end - start);
+                    }
+                    None => return None,
+                }
+            }
+        } else {
+            let end = loop_.map_or(length, |(_, end, _)| end);
+            if self.position >= end {
+                match loop_ {
+                    Some((start, end, true)) => {
+                        self.position = (2.0 * (end - 1.0) - self.position).clamp(start, end - 1.0);
+                        self.backwards = true;
+                    }
+                    Some((start, end, false)) => {
+                        self.position = start + (self.position - end) % (end - start);
+                    }
+                    None => return None,
+                }
+            }
+        }
+
+        let (index, fraction) = split_position(self.position);
+        if index >= frames {
+            return None;
+        }
+        let next = match loop_ {
+            _ if index + 1 < frames => index + 1,
+            Some((start, _, false)) => split_position(start).0,
+            _ => index,
+        };
+        let value = |channel: usize| {
+            let (value, next) = (data[index * channels + channel], data[next * channels + channel]);
+            value + (next - value) * fraction
+        };
+
+        if self.backwards {
+            self.position -= self.step;
+        } else {
+            self.position += self.step;
+        }
+
+        let left = value(0);
+        Some([left, if channels > 1 { value(1) } else { left }])
+    }
+
+    /// Returns the loop currently in effect as `(start, end, bidi)`, the sustain loop is used
+    /// while the note is held.
+    fn current_loop(&self, sample: &Sample, length: f64) -> Option<(f64, f64, bool)> {
+        let loop_ = sample.sustain_loop
+            .filter(|_| self.key_on)
+            .or(sample.loop_)?;
+        let start = f64::from(loop_.start);
+        let end = f64::from(loop_.end).min(length);
+        (start < end).then_some((start, end, loop_.bidi))
+    }
+}
+
+impl EnvelopePosition {
+    fn new(envelope: Option<&Envelope>) -> EnvelopePosition {
+        let enabled = envelope.is_some_and(|envelope| envelope.flags.contains(EnvelopeFlags::ENABLED));
+        EnvelopePosition { enabled, tick: 0 }
+    }
+
+    /// Returns the envelope value at the current position, or `default` if it is disabled.
+    fn value(&self, envelope: &Envelope, default: f32) -> f32 {
+        if !self.enabled {
+            return default;
+        }
+        envelope.interpolate(self.tick).unwrap_or(default)
+    }
+
+    /// Moves to the next tick, returns `true` if the end of the envelope was reached.
+    fn advance(&mut self, envelope: &Envelope, key_on: bool) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let (tick, finished) = envelope.step(self.tick, key_on);
+        self.tick = tick;
+        finished
+    }
+}
+
+/// Returns the frequency the sample plays the note at, C-5 plays at the sample C-5 frequency.
+///
+/// Notes follow the tuning of the instrument if it has one.
+fn note_frequency(sample: &Sample, instrument: Option<&Instrument>, note: Note) -> f64 {
+    if let Some(tuning) = instrument.and_then(|instrument| instrument.tuning.as_ref()) {
+        return f64::from(tuning.frequency(note, sample.samplerate_c5));
+    }
+    let semitones = f32::from(u8::from(note)) - f32::from(u8::from(Note::C_5));
+    f64::from(sample.samplerate_c5) * f64::from(float::powf(2.0, semitones / 12.0))
+}
+
+/// Returns the frequency ratio of a pitch slide by the number of units.
+fn pitch_ratio(units: i16) -> f64 {
+    f64::from(float::powf(2.0, f32::from(units) / f32::from(UNITS_PER_SEMITONE) / 12.0))
+}
+
+/// Splits a playback position into the sample index and the fractional part.
+fn split_position(position: f64) -> (usize, f32) {
+    // The position is always kept in range of the sample data, the casts cannot truncate.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation, clippy::cast_sign_loss, clippy::cast_precision_loss)]
+    {
+        let index = position as usize;
+        (index, (position - index as f64) as f32)
+    }
+}
@@ -0,0 +1,127 @@
+//! Rendering modules to WAV files
+
+use super::*;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::Path;
+
+
+/// Number of frames rendered at once
+const BUFFER_FRAMES: usize = 4096;
+
+
+/// Options for rendering modules to WAV files
+#[derive(Clone, Copy, Debug)]
+pub struct RenderOptions {
+    /// Output sample rate in Hz
+    pub sample_rate: u32,
+
+    /// Number of times the song is repeated after it ends, see [`Player::set_loop_count`]
+    pub loop_count: u32,
+}
+
+impl Default for RenderOptions {
+    fn default() -> RenderOptions {
+        RenderOptions {
+            sample_rate: 44_100,
+            loop_count: 0,
+        }
+    }
+}
+
+
+/// Renders the module to a WAV file at the path
+///
+/// See [`write_wav`].
+pub fn render_to_wav(module: &Module, path: impl AsRef<Path>, options: RenderOptions) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    write_wav(module, &mut file, options)
+}
+
+/// Renders the module and writes it as a WAV file
+///
+/// The file is stereo 16-bit PCM, samples out of the `-1.0..=1.0` range are clipped. The whole
+/// file is rendered in memory and written with a single call to [`Write::write_all`].
+///
+/// # Errors
+///
+/// Returns an error if writing fails, or with [`io::ErrorKind::InvalidInput`] if the sample rate
+/// is zero or the rendered audio doesn't fit in a WAV file.
+pub fn write_wav(module: &Module, writer: &mut impl Write, options: RenderOptions) -> io::Result<()> {
+    if options.sample_rate == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "sample rate must not be zero"));
+    }
+    let mut player = Player::new(module, options.sample_rate);
+    player.set_loop_count(options.loop_count);
+
+    let mut data = Vec::new();
+    let mut buffer = vec![0.0; 2 * BUFFER_FRAMES];
+    loop {
+        let frames = player.render(&mut buffer);
+        for &sample in &buffer[..2 * frames] {
+            data.extend_from_slice(&pcm16(sample).to_le_bytes());
+        }
+        if frames < BUFFER_FRAMES {
+            break;
+        }
+    }
+
+    let too_long = || io::Error::new(io::ErrorKind::InvalidInput, "rendered audio is too long for a WAV file");
+    let data_size = u32::try_from(data.len()).map_err(|_| too_long())?;
+    let riff_size = data_size.checked_add(36).ok_or_else(too_long)?;
+    let channels: u16 = 2;
+    let bits: u16 = 16;
+    let block_align = channels * bits / 8;
+    let byte_rate = options.sample_rate.checked_mul(u32::from(block_align))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "sample rate is too high"))?;
+
+    let mut file = Vec::with_capacity(data.len() + 44);
+    file.extend_from_slice(b"RIFF");
+    file.extend_from_slice(&riff_size.to_le_bytes());
+    file.extend_from_slice(b"WAVE");
+    file.extend_from_slice(b"fmt ");
+    file.extend_from_slice(&16u32.to_le_bytes());
+    // PCM format
+    file.extend_from_slice(&1u16.to_le_bytes());
+    file.extend_from_slice(&channels.to_le_bytes());
+    file.extend_from_slice(&options.sample_rate.to_le_bytes());
+    file.extend_from_slice(&byte_rate.to_le_bytes());
+    file.extend_from_slice(&block_align.to_le_bytes());
+    file.extend_from_slice(&bits.to_le_bytes());
+    file.extend_from_slice(b"data");
+    file.extend_from_slice(&data_size.to_le_bytes());
+    file.extend_from_slice(&data);
+
+    writer.write_all(&file)
+}
+
+/// Converts the sample to 16-bit PCM clipping it to the valid range.
+fn pcm16(sample: f32) -> i16 {
+    let value = (sample.clamp(-1.0, 1.0) * f32::from(i16::MAX)).round();
+    // The value is clamped to the range of `i16`, NaN is converted to zero.
+    #[allow(clippy::as_conversions, clippy::cast_possible_truncation)]
+    { value as i16 }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::convert::TryInto;
+
+    #[test]
+    fn wav_header() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let module = parser::module_file::<error::VerboseError<&[u8]>>(DATA).unwrap();
+        let mut file = Vec::new();
+        write_wav(&module, &mut file, RenderOptions { sample_rate: 8000, loop_count: 0 }).unwrap();
+
+        assert_eq!(&file[0..4], b"RIFF");
+        assert_eq!(&file[8..16], b"WAVEfmt ");
+        assert_eq!(&file[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&file[36..40], b"data");
+        let data_size = u32::from_le_bytes(file[40..44].try_into().unwrap());
+        assert_eq!(usize::try_from(data_size).unwrap(), file.len() - 44);
+        assert_eq!(data_size % 4, 0);
+    }
+}
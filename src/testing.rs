@@ -0,0 +1,376 @@
+//! Strategies for property testing with [`proptest`](https://docs.rs/proptest)
+//!
+//! The strategies produce values upholding the same invariants as the values built by the parser:
+//! ranged fields are in range, orders, sample maps and pattern commands only reference existing
+//! patterns, samples and instruments, envelopes pass [`Envelope::validate`] and sample loops lie
+//! within the sample data. The generated modules survive writing and parsing unchanged.
+//!
+//! Unlike the `arbitrary` implementations, which spread their values uniformly over everything
+//! the format can store, the values here are shaped like real music: patterns are mostly empty,
+//! played notes cluster in the middle octaves and come with an instrument, columns are filled in
+//! the proportions trackers usually see. Samples deliberately hit the edge cases of the data
+//! (no data, empty data, the maximum length and loops covering the whole sample).
+//!
+//! ```
+//! use ittech::testing;
+//! use proptest::prelude::*;
+//! use proptest::test_runner::TestRunner;
+//!
+//! TestRunner::default()
+//!     .run(&testing::pattern(8, 4), |pattern| {
+//!         prop_assert!(pattern.rows.len() <= 200);
+//!         Ok(())
+//!     })
+//!     .unwrap();
+//! ```
+
+use crate::data::*;
+use crate::formats::empty_instrument;
+use crate::parser::effect as parse_effect;
+use core::convert::TryFrom;
+use proptest::prelude::*;
+
+
+/// Length of the samples in [`module`] at most, in samples
+const MODULE_SAMPLE_LENGTH: usize = 4096;
+
+/// Note column commands stopping the playing note
+const STOP_COMMANDS: &[NoteCmd] = &[NoteCmd::Off, NoteCmd::Cut, NoteCmd::Fade];
+
+/// Common sample rates
+const SAMPLE_RATES: &[u32] = &[8363, 22050, 44100, 48000];
+
+/// Silence and the extreme sample values
+const SPECIAL_VALUES: &[i16] = &[-i16::MAX, 0, i16::MAX];
+
+/// Returns a strategy for names of printable ASCII characters.
+pub fn name() -> impl Strategy<Value = Name> {
+    ascii(25).prop_map(|name| {
+        let mut bytes = [0; 26];
+        bytes[..name.len()].copy_from_slice(name.as_bytes());
+        Name { bytes }
+    })
+}
+
+/// Returns a strategy for notes, most of them in the octaves 3 to 6.
+pub fn note() -> impl Strategy<Value = Note> {
+    prop_oneof![4 => 36u8..=83, 1 => 0u8..=119]
+        .prop_map(|note| Note::try_from(note).unwrap())
+}
+
+/// Returns a strategy for volume column commands.
+pub fn volume() -> impl Strategy<Value = VolumeCmd> {
+    // Set volume is by far the most common command in the volume column.
+    prop_oneof![3 => 0u8..=64, 1 => 0u8..=212]
+        .prop_filter_map("invalid volume byte", |byte| VolumeCmd::try_from(byte).ok())
+}
+
+/// Returns a strategy for effect column commands.
+pub fn effect() -> impl Strategy<Value = EffectCmd> {
+    (1u8..=26, any::<u8>())
+        .prop_filter_map("invalid effect parameter", |(effect, param)| parse_effect(effect, param))
+}
+
+/// Returns a strategy for non-empty commands referencing only instruments below `instruments`.
+///
+/// Most commands play a note, the rest stop the playing note or only fill the volume and effect
+/// columns.
+pub fn command(instruments: usize) -> impl Strategy<Value = Command> {
+    let played = (note(), instrument_id(instruments), prop::option::weighted(0.3, volume()), prop::option::weighted(0.2, effect()))
+        .prop_map(|(note, instrument, volume, effect)| Command {
+            note: Some(NoteCmd::Play(note)),
+            instrument,
+            volume,
+            effect,
+        });
+    let stopped = prop::sample::select(STOP_COMMANDS)
+        .prop_map(|note| Command { note: Some(note), instrument: None, volume: None, effect: None });
+    let columns = (prop::option::weighted(0.3, volume()), effect())
+        .prop_map(|(volume, effect)| Command { note: None, instrument: None, volume, effect: Some(effect) });
+    prop_oneof![6 => played, 1 => stopped, 3 => columns]
+}
+
+/// Returns a strategy for patterns using the first `channels` channels and referencing only
+/// instruments below `instruments`.
+///
+/// Most patterns have the default 64 rows and about a quarter of the cells is filled.
+///
+/// # Panics
+///
+/// Panics if `channels` is not in `1..=64`.
+pub fn pattern(channels: u8, instruments: usize) -> impl Strategy<Value = Pattern> {
+    assert!((1..=64).contains(&channels), "pattern channel count {} out of range", channels);
+    prop_oneof![4 => Just(64), 1 => 1usize..=200]
+        .prop_flat_map(move |rows| prop::collection::vec(row(channels, instruments), rows))
+        .prop_map(|rows| Pattern {
+            active_channels: rows.iter()
+                .flat_map(|row| row.iter().map(|(channel, _)| channel))
+                .collect(),
+            rows,
+            truncated: false,
+            highlight: None,
+        })
+}
+
+/// Returns a strategy for samples of at most `max_length` samples.
+///
+/// Besides samples of random length the strategy often produces samples without data, with empty
+/// data and with exactly `max_length` samples, and loops covering the whole sample. The values
+/// lie on the 16-bit grid so they survive encoding, full scale values are overrepresented.
+pub fn sample(max_length: usize) -> impl Strategy<Value = Sample> {
+    let length = prop_oneof![1 => Just(0), 1 => Just(max_length), 4 => 0..=max_length];
+    let data = prop_oneof![
+        1 => Just(None),
+        6 => length.prop_flat_map(|len| prop::collection::vec(sample_value(), len)).prop_map(Some),
+    ];
+    let properties = (
+        name(),
+        0u8..=64,
+        0u8..=64,
+        prop::option::weighted(0.25, 0u8..=64),
+        prop_oneof![3 => prop::sample::select(SAMPLE_RATES), 1 => 0u32..=9_999_999],
+        prop::option::weighted(0.1, (0u8..=64, 0u8..=64, 0u8..=64, 0u8..=3)),
+    );
+    (data, properties)
+        .prop_flat_map(|(data, properties)| {
+            let len = data.as_ref().map_or(0, Vec::len);
+            (Just(data), Just(properties), sample_loop(len), sample_loop(len))
+        })
+        .prop_map(|(data, properties, loop_, sustain_loop)| {
+            let (name, global_volume, default_volume, pan, samplerate_c5, vibrato) = properties;
+            let (vibrato_speed, vibrato_depth, vibrato_rate, vibrato_type) = vibrato.unwrap_or((0, 0, 0, 0));
+            Sample {
+                name,
+                filename: DosFilename { bytes: [0; 13] },
+                global_volume,
+                default_volume,
+                default_panning: pan.map_or(32, |pan| pan | Sample::dfp_usePanning),
+                loop_,
+                sustain_loop,
+                samplerate_c5,
+                vibrato_speed,
+                vibrato_depth,
+                vibrato_rate,
+                vibrato_type,
+                channels: RangedU8::MIN,
+                data: data.map(SampleData::from),
+                fm_patch: None,
+                conversion: SampleConversion::empty(),
+                encoded: None,
+                deferred: None,
+            }
+        })
+}
+
+/// Returns a strategy for instruments mapping only to samples below `samples`.
+///
+/// The keyboard is split into at most four equally wide zones each playing one sample.
+pub fn instrument(samples: usize) -> impl Strategy<Value = Instrument> {
+    (
+        name(),
+        sample_map(samples),
+        0u8..=3,
+        prop_oneof![1 => Just(0), 1 => any::<u8>()],
+        prop_oneof![3 => Just(128), 1 => 0u8..=128],
+        envelope(EnvelopeKind::Volume),
+        envelope(EnvelopeKind::Panning),
+        envelope(EnvelopeKind::PitchFilter),
+    )
+        .prop_map(|properties| {
+            let (name, sample_map, new_note_action, instrument_fadeout, global_volume, volume_envelope, panning_envelope, pitch_filter_envelope) = properties;
+            let mut mapped = sample_map.map.iter().flatten().collect::<Vec<_>>();
+            mapped.sort_unstable();
+            mapped.dedup();
+            Instrument {
+                name,
+                new_note_action,
+                instrument_fadeout,
+                global_volume,
+                number_of_samples: u8::try_from(mapped.len()).unwrap(),
+                sample_map,
+                volume_envelope,
+                panning_envelope,
+                pitch_filter_envelope,
+                ..empty_instrument()
+            }
+        })
+}
+
+/// Returns a strategy for complete modules.
+///
+/// The modules have up to 8 samples of at most 4096 samples, up to 8 instruments, up to 8
+/// patterns using up to 16 channels and an order list playing the patterns.
+pub fn module() -> impl Strategy<Value = Module> {
+    (0usize..=8, any::<bool>(), 1u8..=16)
+        .prop_flat_map(|(samples, use_instruments, channels)| {
+            let instruments = if use_instruments { 1..=8 } else { 0..=0 };
+            (
+                prop::collection::vec(sample(MODULE_SAMPLE_LENGTH), samples),
+                prop::collection::vec(instrument(samples), instruments),
+                Just(channels),
+            )
+        })
+        .prop_flat_map(|(samples, instruments, channels)| {
+            // The instrument column references samples directly in sample mode.
+            let referenced = if instruments.is_empty() { samples.len() } else { instruments.len() };
+            let patterns = prop::collection::vec(pattern(channels, referenced), 1..=8)
+                .prop_flat_map(|patterns| {
+                    let count = patterns.len();
+                    (Just(patterns), prop::collection::vec(0..count, 1..=2 * count))
+                });
+            (
+                Just(samples),
+                Just(instruments),
+                patterns,
+                ascii(25),
+                prop_oneof![3 => Just(6), 1 => 1u8..=31],
+                prop_oneof![3 => Just(125), 1 => 31u8..=255],
+            )
+        })
+        .prop_map(|(samples, instruments, (patterns, orders), name, speed, tempo)| {
+            let mut builder = ModuleBuilder::new();
+            builder.set_name(&name);
+            builder.set_speed(RangedU8::try_from(speed).unwrap());
+            builder.set_tempo(RangedU8::try_from(tempo).unwrap());
+            for sample in samples {
+                builder.add_sample(sample).unwrap();
+            }
+            for instrument in instruments {
+                builder.add_instrument(instrument).unwrap();
+            }
+            for pattern in patterns {
+                builder.add_pattern(pattern).unwrap();
+            }
+            for idx in orders {
+                builder.push_order(Order::Index(PatternId::try_from(u8::try_from(idx).unwrap()).unwrap()));
+            }
+            let mut module = builder.build().expect("BUG: generated order of a missing pattern");
+            // Parsed modules store the flags they were read with.
+            module.stored_flags = module.raw_flags();
+            module
+        })
+}
+
+
+/// Returns a strategy for strings of at most `max_len` printable ASCII characters.
+fn ascii(max_len: usize) -> impl Strategy<Value = String> {
+    prop::collection::vec(b' '..=b'~', 0..=max_len)
+        .prop_map(|chars| chars.into_iter().map(char::from).collect())
+}
+
+/// Returns a strategy for optional instrument ids below `count`, usually `Some`.
+fn instrument_id(count: usize) -> BoxedStrategy<Option<InstrumentId>> {
+    if count == 0 {
+        return Just(None).boxed();
+    }
+    let id = (0..count.min(99)).prop_map(|idx| InstrumentId::try_from(u8::try_from(idx).unwrap()).unwrap());
+    prop::option::weighted(0.9, id).boxed()
+}
+
+/// Returns a strategy for rows with about a quarter of the first `channels` channels filled.
+fn row(channels: u8, instruments: usize) -> impl Strategy<Value = Row> {
+    prop::collection::vec(prop::option::weighted(0.25, command(instruments)), usize::from(channels))
+        .prop_map(|cells| {
+            let commands = cells.into_iter()
+                .zip(0..)
+                .filter_map(|(command, idx)| Some((Channel::from_u8_index(idx), command?)))
+                .collect();
+            Row::from_vec(commands)
+        })
+}
+
+/// Returns a strategy for sample values on the 16-bit grid.
+fn sample_value() -> impl Strategy<Value = f32> {
+    prop_oneof![
+        8 => -i16::MAX..=i16::MAX,
+        1 => prop::sample::select(SPECIAL_VALUES),
+    ]
+        .prop_map(|value| f32::from(value) / f32::from(i16::MAX))
+}
+
+/// Returns a strategy for optional loops lying within `len` samples, often the whole sample.
+fn sample_loop(len: usize) -> BoxedStrategy<Option<SampleLoop>> {
+    let len = u32::try_from(len).expect("sample length out of range");
+    if len < 2 {
+        return Just(None).boxed();
+    }
+    let whole = any::<bool>().prop_map(move |bidi| Some(SampleLoop { start: 0, end: len, bidi }));
+    let part = (0..len - 1).prop_flat_map(move |start| {
+        (start + 1..=len, any::<bool>()).prop_map(move |(end, bidi)| Some(SampleLoop { start, end, bidi }))
+    });
+    prop_oneof![2 => Just(None), 1 => whole, 2 => part].boxed()
+}
+
+/// Returns a strategy for sample maps splitting the keyboard between samples below `samples`.
+fn sample_map(samples: usize) -> BoxedStrategy<SampleMap> {
+    if samples == 0 {
+        return Just(SampleMap::default()).boxed();
+    }
+    prop::collection::vec(0..samples.min(99), 1..=4)
+        .prop_map(|zones| {
+            let mut sample_map = SampleMap::default();
+            for (note, entry) in sample_map.map.iter_mut().enumerate() {
+                let idx = zones[note * zones.len() / 120];
+                *entry = Some(SampleId::try_from(u8::try_from(idx).unwrap()).unwrap());
+            }
+            sample_map
+        })
+        .boxed()
+}
+
+/// Returns a strategy for valid envelopes of the given kind.
+fn envelope(kind: EnvelopeKind) -> impl Strategy<Value = Envelope> {
+    (prop::collection::vec((1u16..=64, kind.value_range()), 2..=Envelope::MAX_NODES), any::<u8>())
+        .prop_flat_map(|(steps, flags)| {
+            let last = u8::try_from(steps.len() - 1).unwrap();
+            (Just(steps), Just(flags), envelope_loop(last), envelope_loop(last))
+        })
+        .prop_map(move |(steps, flags, envelope_loop, sustain_loop)| {
+            let mut tick = 0;
+            let nodes = steps.into_iter()
+                .enumerate()
+                .map(|(idx, (step, value))| {
+                    if idx > 0 {
+                        tick += step;
+                    }
+                    Node { value, tick }
+                })
+                .collect();
+            let envelope = Envelope {
+                flags: EnvelopeFlags::from_bits_truncate(flags),
+                envelope_loop,
+                sustain_loop,
+                nodes,
+            };
+            debug_assert!(envelope.validate(kind).is_ok());
+            envelope
+        })
+}
+
+/// Returns a strategy for envelope loops between the nodes up to `last`.
+///
+/// The loop points are always stored, the envelope flags tell if the loops are used.
+fn envelope_loop(last: u8) -> impl Strategy<Value = Option<EnvelopeLoop>> {
+    (0..=last).prop_flat_map(move |start| (start..=last).prop_map(move |end| Some(EnvelopeLoop { start, end })))
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+    use proptest::test_runner::{Config, TestRunner};
+
+    #[test]
+    fn round_trip() {
+        let mut runner = TestRunner::new(Config { cases: 32, ..Config::default() });
+        runner.run(&module(), |module| {
+            let mut file = Vec::new();
+            module.write_to(&mut file).unwrap();
+            let parsed = parser::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+            prop_assert_eq!(format!("{:?}", parsed), format!("{:?}", module));
+            Ok(())
+        }).unwrap();
+    }
+}
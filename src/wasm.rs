@@ -0,0 +1,173 @@
+//! JavaScript interface
+//!
+//! Exposes the parser to JavaScript through `wasm-bindgen`, build the crate for
+//! `wasm32-unknown-unknown` with the feature `wasm` and run `wasm-bindgen` on the result. The
+//! parsed module is the class `Module`, its accessors copy the data out into typed arrays.
+//!
+//! ```js
+//! import { Module } from "./ittech.js";
+//!
+//! const module = new Module(new Uint8Array(buffer));
+//! console.log(module.name, module.orders());
+//! const grid = module.patternGrid(0);
+//! module.free();
+//! ```
+
+use crate::error::{ParseFailure, VerboseError};
+use crate::{Channel, Get, Order, MAX_CHANNELS};
+use wasm_bindgen::prelude::*;
+
+
+/// Number of bytes of a cell in [`WasmModule::pattern_grid`]
+pub const CELL_SIZE: usize = 6;
+
+
+/// Parsed module, the class `Module` in JavaScript
+#[wasm_bindgen(js_name = Module)]
+pub struct WasmModule {
+    module: crate::Module,
+}
+
+#[wasm_bindgen(js_class = Module)]
+impl WasmModule {
+    /// Parses the Impulse Tracker module, throws an `Error` with the description if it fails.
+    #[wasm_bindgen(constructor)]
+    pub fn new(data: &[u8]) -> Result<WasmModule, JsError> {
+        match crate::parser::module_file::<VerboseError<&[u8]>>(data) {
+            Ok(module) => Ok(WasmModule { module }),
+            Err(error) => Err(JsError::new(&ParseFailure::new(data, error).to_string())),
+        }
+    }
+
+    /// Name of the module
+    #[wasm_bindgen(getter)]
+    pub fn name(&self) -> String {
+        self.module.name.to_string()
+    }
+
+    /// Initial tempo of the module
+    #[wasm_bindgen(getter)]
+    pub fn tempo(&self) -> u8 {
+        self.module.tempo.as_u8()
+    }
+
+    /// Initial speed (ticks per row) of the module
+    #[wasm_bindgen(getter)]
+    pub fn speed(&self) -> u8 {
+        self.module.speed.as_u8()
+    }
+
+    /// Order list as stored in the file, pattern numbers with `254` for a separator and `255` for
+    /// the end of the song
+    pub fn orders(&self) -> Vec<u8> {
+        self.module.orders.iter().map(|order| match order {
+            Order::Index(pattern) => pattern.as_u8(),
+            Order::Separator => 254,
+            Order::EndOfSong => 255,
+        }).collect()
+    }
+
+    #[wasm_bindgen(getter, js_name = patternCount)]
+    pub fn pattern_count(&self) -> usize {
+        self.module.patterns.len()
+    }
+
+    /// Number of rows of the pattern, `0` if there is no such pattern
+    #[wasm_bindgen(js_name = patternRows)]
+    pub fn pattern_rows(&self, pattern: usize) -> usize {
+        self.module.patterns.as_slice().get(pattern).map_or(0, |pattern| pattern.rows.len())
+    }
+
+    /// Cells of the pattern, row by row with all [`MAX_CHANNELS`] channels, empty if there is no such pattern
+    ///
+    /// Every cell takes [`CELL_SIZE`] bytes encoded like `IttechCell` of the C interface: the
+    /// mask, note, instrument, volume, effect and parameter. Only the values marked in the mask
+    /// are set, the others are `0`.
+    #[wasm_bindgen(js_name = patternGrid)]
+    pub fn pattern_grid(&self, pattern: usize) -> Vec<u8> {
+        let rows = match self.module.patterns.as_slice().get(pattern) {
+            Some(pattern) => &pattern.rows,
+            None => return Vec::new(),
+        };
+        let mut grid = vec![0; rows.len() * MAX_CHANNELS * CELL_SIZE];
+        for (row, cells) in rows.iter().zip(grid.chunks_exact_mut(MAX_CHANNELS * CELL_SIZE)) {
+            for (channel, cell) in (0..).zip(cells.chunks_exact_mut(CELL_SIZE)) {
+                let command = match row.get(Channel::from_u8_index(channel)) {
+                    Some(command) => command,
+                    None => continue,
+                };
+                if let Some(note) = command.note {
+                    cell[0] |= 0x01;
+                    cell[1] = note.to_raw();
+                }
+                if let Some(instrument) = command.instrument {
+                    cell[0] |= 0x02;
+                    cell[2] = instrument.as_u8() + 1;
+                }
+                if let Some(volume) = command.volume {
+                    cell[0] |= 0x04;
+                    cell[3] = u8::from(volume);
+                }
+                if let Some(effect) = command.effect {
+                    cell[0] |= 0x08;
+                    let (effect, param) = effect.to_raw();
+                    cell[4] = effect;
+                    cell[5] = param;
+                }
+            }
+        }
+        grid
+    }
+
+    #[wasm_bindgen(getter, js_name = sampleCount)]
+    pub fn sample_count(&self) -> usize {
+        self.module.samples.len()
+    }
+
+    /// Name of the sample, `undefined` if there is no such sample
+    #[wasm_bindgen(js_name = sampleName)]
+    pub fn sample_name(&self, sample: usize) -> Option<String> {
+        self.module.samples.as_slice().get(sample).map(|sample| sample.name.to_string())
+    }
+
+    /// Playback rate of C-5 of the sample, `0` if there is no such sample
+    #[wasm_bindgen(js_name = sampleRate)]
+    pub fn sample_rate(&self, sample: usize) -> u32 {
+        self.module.samples.as_slice().get(sample).map_or(0, |sample| sample.samplerate_c5)
+    }
+
+    /// Number of channels of the sample, `1` or `2`, `0` if there is no such sample
+    #[wasm_bindgen(js_name = sampleChannels)]
+    pub fn sample_channels(&self, sample: usize) -> u8 {
+        self.module.samples.as_slice().get(sample).map_or(0, |sample| sample.channels.as_u8())
+    }
+
+    /// Normalized data of the sample (values from -1.0 to 1.0) with the channels of stereo
+    /// samples interleaved, `undefined` if there is no such sample or it has no data
+    #[wasm_bindgen(js_name = sampleData)]
+    pub fn sample_data(&self, sample: usize) -> Option<Vec<f32>> {
+        self.module.samples.as_slice().get(sample)?.data.as_deref().map(<[f32]>::to_vec)
+    }
+}
+
+
+#[cfg(all(test, target_arch = "wasm32"))]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse_inspect() {
+        let data = include_bytes!("../tests/effect_alphabet.it");
+        let module = WasmModule::new(data).unwrap();
+        assert_eq!(module.tempo(), 125);
+        assert_eq!(module.orders().len(), 2);
+
+        let grid = module.pattern_grid(0);
+        assert_eq!(grid.len(), module.pattern_rows(0) * MAX_CHANNELS * CELL_SIZE);
+        assert!(grid.chunks_exact(CELL_SIZE).any(|cell| cell[0] & 0x08 != 0));
+        assert!(module.pattern_grid(module.pattern_count()).is_empty());
+        assert!(module.sample_data(module.sample_count()).is_none());
+
+        assert!(WasmModule::new(&data[..10]).is_err());
+    }
+}
@@ -1,3 +1,1100 @@
 //! Writing functions
 //!
-//! **TODO** serialization is not yet implemented
+//! The writer is the counterpart of the [`parser`](crate::parser), writing a parsed module gives
+//! a file that parses back to the same module. Data the parser doesn't keep is not written, this
+//! includes the OpenMPT extensions other than the pattern and channel names, the channel count and
+//! the extended instrument and song properties.
+//!
+//! Modules can also be down-converted to Scream Tracker 3 files with [`Module::to_s3m`],
+//! FastTracker 2 files with [`Module::to_xm`] and ProTracker files with [`Module::to_mod`] and
+//! their notes exported to MIDI files with [`Module::to_midi`]. Samples can be written as WAV files with [`Sample::write_wav`].
+
+use crate::data::*;
+use crate::parser::{CHANNEL_NAME_LENGTH, EDIT_HISTORY, INSTRUMENT_SIZE, PATTERN_NAME_LENGTH, SAMPLE_HEADER_SIZE};
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+
+mod compression;
+mod midi;
+mod pattern;
+mod protracker;
+mod s3m;
+mod wav;
+mod xm;
+
+pub use midi::MidiTracks;
+pub use pattern::encode_effect as effect;
+pub use protracker::ModReport;
+pub use s3m::S3mReport;
+pub use xm::XmReport;
+
+use compression::compress;
+use pattern::pattern;
+
+
+/// Offset of the message offset field in the module header
+const MESSAGE_OFFSET_FIELD: usize = 0x38;
+
+/// Offset of the sample data pointer field in the sample header
+const SAMPLE_POINTER_FIELD: usize = 0x48;
+
+
+/// Options for writing files
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WriteOptions {
+    /// Compress sample data using IT2.15 compression
+    ///
+    /// Applies to all samples which have data, except the unmodified ones which were already
+    /// compressed in the parsed file, those are copied as they are. If off, only the unmodified
+    /// samples keep their compression.
+    pub compress_samples: bool,
+
+    /// Encoding of the song message
+    ///
+    /// The message is encoded and its line breaks are converted to `\r`, see
+    /// [`Encoding::encode_message`]. If `None` the message is written as UTF-8 as it is.
+    pub message_encoding: Option<Encoding>,
+
+    /// Program the file is written for
+    pub target: WriteTarget,
+}
+
+/// Program a module file is written for, see [`WriteOptions::target`]
+///
+/// The targets other than [`WriteTarget::Module`] set the version fields of the header to the
+/// ones the program writes itself. The Impulse Tracker targets leave out what Impulse Tracker
+/// can't load: the OpenMPT chunks (pattern and channel names, extended instrument and song
+/// properties, the channel count and the settings of the channels above 64), the commands of the
+/// channels above 64, the unknown header chunks and trailing data of
+/// [`Module::opaque`] and stereo sample data, which is mixed down to mono.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum WriteTarget {
+    /// Keep the versions of the module and write everything it has
+    #[default]
+    Module,
+
+    /// Impulse Tracker 2.14, compressed samples use the IT2.14 compression
+    ImpulseTracker214,
+
+    /// Impulse Tracker 2.15, compressed samples use the IT2.15 compression
+    ImpulseTracker215,
+
+    /// OpenMPT with its extensions, written as OpenMPT 1.29
+    OpenMpt,
+}
+
+impl WriteTarget {
+    /// Returns the made with and compatible with versions of the header, `None` keeps the ones of
+    /// the module.
+    fn versions(self) -> Option<(u16, u16)> {
+        match self {
+            WriteTarget::Module => None,
+            WriteTarget::ImpulseTracker214 => Some((0x0214, 0x0214)),
+            WriteTarget::ImpulseTracker215 => Some((0x0215, 0x0215)),
+            WriteTarget::OpenMpt => Some((0x5129, 0x0888)),
+        }
+    }
+
+    /// Returns `true` if the target loads the OpenMPT extensions and stereo samples.
+    fn extensions(self) -> bool {
+        matches!(self, WriteTarget::Module | WriteTarget::OpenMpt)
+    }
+
+    /// Returns the number of channels the target loads from the patterns.
+    fn channels(self) -> usize {
+        if self.extensions() { MAX_CHANNELS } else { 64 }
+    }
+
+    fn it215_compression(self) -> bool {
+        self != WriteTarget::ImpulseTracker214
+    }
+}
+
+
+impl Module {
+    /// Writes the module as an Impulse Tracker module file (.it)
+    ///
+    /// See [`module_file`].
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        module_file(self, writer)
+    }
+
+    /// Writes the module as an Impulse Tracker module file (.it) using the options
+    ///
+    /// See [`module_file_with`].
+    pub fn write_to_with(&self, writer: &mut impl Write, options: WriteOptions) -> io::Result<()> {
+        module_file_with(self, writer, options)
+    }
+}
+
+impl Instrument {
+    /// Writes the instrument as an Impulse Tracker instrument file (.iti)
+    ///
+    /// `samples` are the samples the sample map refers to, usually the samples of the module. The
+    /// samples the instrument maps to are stored in the file in the order of their IDs and the
+    /// sample map is renumbered to them, other samples are left out.
+    /// [`Instrument::number_of_samples`] is set to the number of stored samples. The instrument
+    /// and samples are written the same way as in modules, see [`module_file`], sample data is not
+    /// compressed.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the instrument maps to a sample missing from
+    /// `samples` or if the data of a stored sample was [not loaded](Sample::is_loaded). Errors of
+    /// the writer are passed through.
+    pub fn write_iti(&self, samples: &[Sample], writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&instrument_file_bytes(self, samples)?)
+    }
+}
+
+impl InstrumentFile {
+    /// Writes the instrument and its samples as an Impulse Tracker instrument file (.iti)
+    ///
+    /// See [`Instrument::write_iti`].
+    pub fn write_to(&self, writer: &mut impl Write) -> io::Result<()> {
+        self.instrument.write_iti(&self.samples, writer)
+    }
+}
+
+impl Sample {
+    /// Writes the sample as an Impulse Tracker sample file (.its)
+    ///
+    /// See [`Sample::write_its_with`], this uses the default options.
+    pub fn write_its(&self, writer: &mut impl Write) -> io::Result<()> {
+        self.write_its_with(writer, WriteOptions::default())
+    }
+
+    /// Writes the sample as an Impulse Tracker sample file (.its) using the options
+    ///
+    /// The sample header is followed by the sample data, both are written the same way as in
+    /// modules, see [`module_file`] and [`WriteOptions::compress_samples`].
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the data of the sample was [not
+    /// loaded](Sample::is_loaded). Errors of the writer are passed through.
+    pub fn write_its_with(&self, writer: &mut impl Write, options: WriteOptions) -> io::Result<()> {
+        writer.write_all(&sample_file_bytes(self, options)?)
+    }
+}
+
+/// Write Impulse Tracker module file (.it)
+///
+/// The file is laid out the same way Impulse Tracker does it, the header with orders, offset
+/// tables, the edit history and the embedded MIDI configuration is followed by the message, instruments, sample
+/// headers, patterns and finally the sample data. Empty patterns of 64 rows are stored as offset 0.
+/// Pattern and channel names are stored in the OpenMPT `PNAM` and `CNAM` chunks after the MIDI
+/// configuration followed by the `ChnS` chunk with the settings of the channels above 64, the OpenMPT extended instrument and song properties after the sample data.
+/// The data of [`Module::opaque`] is put back where the parser found it, the unknown header chunks
+/// after the name chunks and the trailing data at the end of the file.
+///
+/// The file is streamed to the writer part by part. The offsets are computed by a planning pass
+/// over the module first, then the parts are written in the order of the file, so only the header
+/// and a single part (an instrument, a pattern or the data of one sample) are in memory at a time.
+/// The patterns and the sample data which is not written as it was parsed are encoded in both
+/// passes. Many small writes are made, unbuffered writers should be wrapped in
+/// [`BufWriter`](std::io::BufWriter).
+///
+/// # Canonicalization
+///
+/// - [`ModuleFlags::MESSAGE_ATTACHED`] is set if and only if the message is not empty,
+/// - [`ModuleFlags::MIDI_CONIFG_EMBEDDED`] is set if and only if [`Module::midi_config`] is
+///   present,
+/// - the edit history flag is set if [`Module::edit_history`] is not empty, it's kept from
+///   [`Module::stored_flags`] otherwise,
+/// - loops of samples are dropped if they are not within the sample data,
+/// - modified or new sample data is stored as 8-bit PCM if that is lossless and as 16-bit PCM
+///   otherwise, unmodified data keeps its original encoding (see [`Sample::is_dirty`]), see
+///   [`WriteOptions::compress_samples`] for compression,
+/// - the version fields and the parts which are written depend on [`WriteOptions::target`],
+/// - names are truncated to the lengths OpenMPT stores, trailing empty names and names of
+///   patterns or channels which don't exist ([`Module::declared_channel_count`]) are dropped,
+///   so are the panning and volume of the channels above 64 which don't exist,
+/// - extended instrument properties are stored for all instruments, instruments without the
+///   property or with a shorter value are padded with zeros.
+///
+/// # Errors
+///
+/// Fails with [`io::ErrorKind::InvalidInput`] if the module can not be represented in the format,
+/// that is if there are more than 256 orders, 99 instruments, 99 samples or 200 patterns, if the
+/// message or an extended property is longer than 65535 bytes, if the edit history has more than
+/// 65535 entries, if a pattern doesn't fit into
+/// 64 KiB or has been [truncated](Pattern::truncated) during parsing or if the data of a sample
+/// was [not loaded](Sample::is_loaded), nothing is written then. Errors of the writer are passed
+/// through, the part of the file written before the error is left in the writer.
+pub fn module_file(module: &Module, writer: &mut impl Write) -> io::Result<()> {
+    module_file_with(module, writer, WriteOptions::default())
+}
+
+/// Write Impulse Tracker module file (.it) using the options
+///
+/// Same as [`module_file`] which uses the default options.
+pub fn module_file_with(module: &Module, writer: &mut impl Write, options: WriteOptions) -> io::Result<()> {
+    let layout = layout(module, options)?;
+    writer.write_all(&layout.header)?;
+    if !module.message.is_empty() {
+        writer.write_all(&layout.message)?;
+        writer.write_all(&[0])?;
+    }
+
+    let mut out = Vec::new();
+    for instrument in &module.instruments {
+        out.clear();
+        self::instrument(&mut out, instrument);
+        writer.write_all(&out)?;
+    }
+    for (sample, &(flags, length, pointer)) in module.samples.iter().zip(&layout.samples) {
+        out.clear();
+        sample_header(&mut out, sample, flags, length, pointer);
+        writer.write_all(&out)?;
+    }
+    for pattern in module.patterns.iter().filter(|pattern| is_stored(pattern)) {
+        out.clear();
+        self::pattern(&mut out, pattern, options.target.channels())?;
+        writer.write_all(&out)?;
+    }
+    for sample in &module.samples {
+        writer.write_all(&sample_data(sample, options).2)?;
+    }
+
+    if options.target.extensions() {
+        out.clear();
+        openmpt_extensions(&mut out, module)?;
+        writer.write_all(&out)?;
+        writer.write_all(&module.opaque.trailing)?;
+    }
+    Ok(())
+}
+
+
+/// Parts of a module file planned before writing it
+struct Layout {
+    /// Header with the offset tables filled in
+    header: Vec<u8>,
+
+    /// Encoded message without the terminator
+    message: Vec<u8>,
+
+    /// Format flags, length field and data pointer of every sample
+    samples: Vec<(SampleFlags, u32, u32)>,
+}
+
+/// Checks that the module can be written and computes the offsets of its parts.
+///
+/// The parts are measured in the order they are written by [`module_file_with`].
+fn layout(module: &Module, options: WriteOptions) -> io::Result<Layout> {
+    let ordnum = count(module.orders.len(), 256, "too many orders, at most 256 are allowed")?;
+    let insnum = count(module.instruments.len(), 99, "too many instruments, at most 99 are allowed")?;
+    let smpnum = count(module.samples.len(), 99, "too many samples, at most 99 are allowed")?;
+    let patnum = count(module.patterns.len(), 200, "too many patterns, at most 200 are allowed")?;
+    let message = module.message_bytes(options.message_encoding);
+    let msglength = u16::try_from(message.len())
+        .map_err(|_| invalid("message is too long, at most 65535 bytes are allowed"))?;
+    if !module.samples.iter().all(Sample::is_loaded) {
+        return Err(invalid("sample data was not loaded"));
+    }
+
+    let history = count(module.edit_history.len(), 65535, "edit history is too long, at most 65535 entries are allowed")?;
+    let mut raw_flags = module.raw_flags();
+    raw_flags &= !(ModuleFlags::MESSAGE_ATTACHED | ModuleFlags::MIDI_CONIFG_EMBEDDED).bits();
+    if !module.edit_history.is_empty() {
+        raw_flags |= EDIT_HISTORY;
+    }
+    if !module.message.is_empty() {
+        raw_flags |= ModuleFlags::MESSAGE_ATTACHED.bits();
+    }
+    if module.midi_config.is_some() {
+        raw_flags |= ModuleFlags::MIDI_CONIFG_EMBEDDED.bits();
+    }
+    let flags = u16::try_from(raw_flags & 0xFFFF).unwrap();
+    let special = u16::try_from(raw_flags >> 16).unwrap();
+
+    let mut out = Vec::new();
+
+    // Static parts of the header.
+    out.extend_from_slice(b"IMPM");
+    out.extend_from_slice(&module.name.bytes);
+    out.push(module.highlight.1);
+    out.push(module.highlight.0);
+    for value in &[ordnum, insnum, smpnum, patnum] {
+        u16(&mut out, *value);
+    }
+    let (made_with, compatible_with) = options.target.versions()
+        .unwrap_or((module.made_with_version, module.compatible_with_version));
+    u16(&mut out, made_with);
+    // Instruments are always written in the new format, which is read only from modules
+    // compatible with 2.00 and later.
+    let cmwt = if module.instruments.is_empty() {
+        compatible_with
+    } else {
+        compatible_with.max(0x0200)
+    };
+    u16(&mut out, cmwt);
+    u16(&mut out, flags);
+    u16(&mut out, special);
+    out.push(module.global_volume.as_u8());
+    out.push(module.sample_volume.as_u8());
+    out.push(module.speed.as_u8());
+    out.push(module.tempo.as_u8());
+    out.push(module.pan_separation.as_u8());
+    out.push(module.pitch_wheel_depth);
+    u16(&mut out, msglength);
+    u32(&mut out, 0); // message offset, patched below
+    u32(&mut out, module.opaque.header_reserved);
+    out.extend(module.init_channel_panning[..64].iter().map(|&pan| u8::from(pan)));
+    out.extend(module.init_channel_volume[..64].iter().map(|&volume| u8::from(volume)));
+
+    // Dynamic parts of the header, offsets get patched when the data is written.
+    for order in &module.orders {
+        out.push(match order {
+            Order::Index(pattern) => pattern.as_u8(),
+            Order::Separator => 254,
+            Order::EndOfSong => 255,
+        });
+    }
+    let instrument_offsets = reserve_offsets(&mut out, module.instruments.len());
+    let sample_offsets = reserve_offsets(&mut out, module.samples.len());
+    let pattern_offsets = reserve_offsets(&mut out, module.patterns.len());
+    if raw_flags & EDIT_HISTORY != 0 {
+        u16(&mut out, history);
+        for entry in &module.edit_history {
+            u16(&mut out, entry.fat_date);
+            u16(&mut out, entry.fat_time);
+            u32(&mut out, entry.run_time);
+        }
+    }
+    if let Some(config) = &module.midi_config {
+        for macro_ in config.global.iter().chain(&config.parametered).chain(&config.fixed) {
+            out.extend_from_slice(&macro_.bytes);
+        }
+    }
+    let extensions = options.target.extensions();
+    if extensions {
+        name_chunk(&mut out, b"PNAM", &module.pattern_names, module.patterns.len(), PATTERN_NAME_LENGTH);
+        name_chunk(&mut out, b"CNAM", &module.channel_names, module.declared_channel_count(), CHANNEL_NAME_LENGTH);
+        channel_settings(&mut out, module);
+        out.extend_from_slice(&module.opaque.header_chunks);
+    }
+
+    // Everything else follows the header in the order it's written.
+    let mut offset = out.len();
+    if !module.message.is_empty() {
+        set_offset(&mut out, MESSAGE_OFFSET_FIELD, offset)?;
+        offset += message.len() + 1;
+    }
+
+    for field in instrument_offsets {
+        set_offset(&mut out, field, offset)?;
+        offset += INSTRUMENT_SIZE;
+    }
+    for field in sample_offsets {
+        set_offset(&mut out, field, offset)?;
+        offset += SAMPLE_HEADER_SIZE;
+    }
+
+    let mut packed = Vec::new();
+    for (pattern, field) in module.patterns.iter().zip(pattern_offsets) {
+        if pattern.truncated {
+            return Err(invalid("pattern was truncated during parsing and cannot be written"));
+        }
+        if !is_stored(pattern) {
+            // Offset 0 marks an empty pattern of 64 rows, the offset is already zeroed.
+            continue;
+        }
+        set_offset(&mut out, field, offset)?;
+        packed.clear();
+        self::pattern(&mut packed, pattern, options.target.channels())?;
+        offset += packed.len();
+    }
+
+    let mut samples = Vec::with_capacity(module.samples.len());
+    for sample in &module.samples {
+        let (flags, length, data) = sample_data(sample, options);
+        let pointer = if data.is_empty() { 0 } else { offset_u32(offset)? };
+        offset += data.len();
+        samples.push((flags, length, pointer));
+    }
+    offset_u32(offset)?;
+
+    Ok(Layout { header: out, message, samples })
+}
+
+fn instrument_file_bytes(instrument: &Instrument, samples: &[Sample]) -> io::Result<Vec<u8>> {
+    let mut ids = instrument.sample_map.map.iter().flatten().copied().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids.dedup();
+    let stored = ids.iter()
+        .map(|id| samples.get(usize::from(id.as_u8())).ok_or_else(|| invalid("instrument maps to a missing sample")))
+        .collect::<io::Result<Vec<_>>>()?;
+    if !stored.iter().all(|sample| sample.is_loaded()) {
+        return Err(invalid("sample data was not loaded"));
+    }
+
+    let mut instrument = instrument.clone();
+    for id in instrument.sample_map.map.iter_mut().flatten() {
+        let index = ids.binary_search(id).unwrap();
+        *id = SampleId::try_from(u8::try_from(index).unwrap()).unwrap();
+    }
+    instrument.number_of_samples = u8::try_from(ids.len()).unwrap();
+
+    let mut out = Vec::new();
+    self::instrument(&mut out, &instrument);
+    let mut encoded = Vec::with_capacity(stored.len());
+    for sample in stored {
+        let header = out.len();
+        let (flags, length, data) = sample_data(sample, WriteOptions::default());
+        sample_header(&mut out, sample, flags, length, 0);
+        encoded.push((header + SAMPLE_POINTER_FIELD, data));
+    }
+    for (field, data) in encoded {
+        if !data.is_empty() {
+            patch_offset(&mut out, field)?;
+            out.extend_from_slice(&data);
+        }
+    }
+    Ok(out)
+}
+
+fn sample_file_bytes(sample: &Sample, options: WriteOptions) -> io::Result<Vec<u8>> {
+    if !sample.is_loaded() {
+        return Err(invalid("sample data was not loaded"));
+    }
+    let mut out = Vec::new();
+    let (flags, length, data) = sample_data(sample, options);
+    sample_header(&mut out, sample, flags, length, 0);
+    if !data.is_empty() {
+        patch_offset(&mut out, SAMPLE_POINTER_FIELD)?;
+        out.extend_from_slice(&data);
+    }
+    Ok(out)
+}
+
+/// Writes the OpenMPT extended instrument and song properties, nothing if there are none.
+fn openmpt_extensions(out: &mut Vec<u8>, module: &Module) -> io::Result<()> {
+    let instruments = module.instruments
+        .iter()
+        .map(|instrument| instrument.openmpt_extensions.as_ref().map_or_else(Vec::new, InstrumentExtensions::properties))
+        .collect::<Vec<_>>();
+    if module.instruments.iter().any(|instrument| instrument.openmpt_extensions.is_some()) {
+        let mut codes = Vec::new();
+        for property in instruments.iter().flatten() {
+            if !codes.contains(&property.code) {
+                codes.push(property.code);
+            }
+        }
+
+        out.extend_from_slice(b"XTPM");
+        for code in codes {
+            fn value(properties: &[ExtensionProperty], code: [u8; 4]) -> &[u8] {
+                properties.iter().find(|property| property.code == code).map_or(&[], |property| &property.data)
+            }
+            let size = instruments.iter().map(|properties| value(properties, code).len()).max().unwrap_or(0);
+            out.extend_from_slice(&code);
+            u16(out, property_size(size)?);
+            for properties in &instruments {
+                let start = out.len();
+                out.extend_from_slice(value(properties, code));
+                out.resize(start + size, 0);
+            }
+        }
+    }
+
+    if module.openmpt_extensions.is_some() || module.openmpt_channel_count.is_some() {
+        let channel_count = module.openmpt_channel_count.map(|channels| ExtensionProperty {
+            code: *b"C...",
+            data: channels.to_le_bytes().to_vec(),
+        });
+        let properties = channel_count
+            .into_iter()
+            .chain(module.openmpt_extensions.iter().flat_map(SongExtensions::properties));
+
+        out.extend_from_slice(b"STPM");
+        for property in properties {
+            out.extend_from_slice(&property.code);
+            u16(out, property_size(property.data.len())?);
+            out.extend_from_slice(&property.data);
+        }
+    }
+    Ok(())
+}
+
+fn property_size(size: usize) -> io::Result<u16> {
+    u16::try_from(size).map_err(|_| invalid("extended property is too long, at most 65535 bytes are allowed"))
+}
+
+/// Writes the OpenMPT chunk of the first `max` names, nothing if they are all empty.
+fn name_chunk(out: &mut Vec<u8>, code: &[u8; 4], names: &[String], max: usize, length: usize) {
+    let names = &names[..names.len().min(max)];
+    let count = names.iter().rposition(|name| !name.is_empty()).map_or(0, |last| last + 1);
+    if count == 0 {
+        return;
+    }
+    out.extend_from_slice(code);
+    u32(out, u32::try_from(count * length).unwrap());
+    for name in &names[..count] {
+        // Names are cut at a character boundary, the rest is padded with zeros.
+        let len = (0..=name.len().min(length)).rev().find(|&len| name.is_char_boundary(len)).unwrap();
+        let start = out.len();
+        out.extend_from_slice(&name.as_bytes()[..len]);
+        out.resize(start + length, 0);
+    }
+}
+
+/// Writes the OpenMPT `ChnS` chunk with the panning and volume of the declared channels above 64,
+/// nothing if there are none.
+fn channel_settings(out: &mut Vec<u8>, module: &Module) {
+    let channels = module.declared_channel_count().min(MAX_CHANNELS);
+    if channels <= 64 {
+        return;
+    }
+    out.extend_from_slice(b"ChnS");
+    u32(out, u32::try_from(2 * (channels - 64)).unwrap());
+    for (&pan, &volume) in module.init_channel_panning[64..channels].iter().zip(&module.init_channel_volume[64..]) {
+        out.push(u8::from(pan));
+        out.push(u8::from(volume));
+    }
+}
+
+fn instrument(out: &mut Vec<u8>, instrument: &Instrument) {
+    let flags = instrument.flags;
+    let enabled = |flag, bit| if flags.contains(flag) { bit } else { 0 };
+    let disabled = |flag, bit| if flags.contains(flag) { 0 } else { bit };
+
+    out.extend_from_slice(b"IMPI");
+    out.extend_from_slice(&instrument.filename.bytes);
+    out.push(instrument.new_note_action);
+    out.push(instrument.duplicate_check_type);
+    out.push(instrument.duplicate_check_action);
+    u16(out, instrument.instrument_fadeout.into());
+    out.extend_from_slice(&instrument.pitch_pan_separation.to_le_bytes());
+    out.push(instrument.pitch_pan_centre);
+    out.push(instrument.global_volume);
+    out.push(
+        instrument.default_panning.as_u8()
+            | disabled(InstrumentFlags::ENABLE_PANNING, Instrument::dfp_ignorePanning)
+    );
+    out.push(instrument.random_volume_variation.as_u8());
+    out.push(instrument.random_panning_variation.as_u8());
+    u16(out, instrument.trkver);
+    out.push(instrument.number_of_samples);
+    out.push(0); // reserved
+    out.extend_from_slice(&instrument.name.bytes);
+    out.push(
+        instrument.initial_filter_cutoff.as_u8()
+            | enabled(InstrumentFlags::ENABLE_FILTER_CUTOFF, Instrument::ifc_enableCutoff)
+    );
+    out.push(
+        instrument.initial_filter_resonance.as_u8()
+            | enabled(InstrumentFlags::ENABLE_FILTER_RESONANCE, Instrument::ifr_enableResonance)
+    );
+    out.push(instrument.mch);
+    out.push(instrument.mpr);
+    out.extend_from_slice(&instrument.mbank);
+    for (note, sample) in (0..=119).zip(instrument.sample_map.map.iter()) {
+        out.push(note);
+        out.push(sample.map_or(0, |id| id.as_u8() + 1));
+    }
+    envelope(out, &instrument.volume_envelope);
+    envelope(out, &instrument.panning_envelope);
+    envelope(out, &instrument.pitch_filter_envelope);
+    out.extend_from_slice(&[0; 4]); // dummy
+}
+
+fn envelope(out: &mut Vec<u8>, envelope: &Envelope) {
+    let nodes = &envelope.nodes[..envelope.nodes.len().min(Envelope::MAX_NODES)];
+    let envelope_loop = envelope.envelope_loop.map_or((0, 0), |l| (l.start, l.end));
+    let sustain_loop = envelope.sustain_loop.map_or((0, 0), |l| (l.start, l.end));
+
+    out.push(envelope.flags.bits());
+    out.push(u8::try_from(nodes.len()).unwrap());
+    out.push(envelope_loop.0);
+    out.push(envelope_loop.1);
+    out.push(sustain_loop.0);
+    out.push(sustain_loop.1);
+    for index in 0..Envelope::MAX_NODES {
+        let node = nodes.get(index).map_or((0, 0), |node| (node.value, node.tick));
+        out.extend_from_slice(&node.0.to_le_bytes());
+        u16(out, node.1);
+    }
+    out.push(0); // reserved
+}
+
+/// Writes the sample header for the data encoded by [`sample_data`] stored at the pointer.
+fn sample_header(out: &mut Vec<u8>, sample: &Sample, mut flags: SampleFlags, length: u32, pointer: u32) {
+
+    let mut loop_points = |sample_loop: Option<SampleLoop>, flag, bidi_flag| {
+        match sample_loop {
+            Some(l) if l.start < l.end && l.end <= length => {
+                flags |= flag;
+                if l.bidi {
+                    flags |= bidi_flag;
+                }
+                (l.start, l.end)
+            }
+            _ => (0, 0),
+        }
+    };
+    let loop_ = loop_points(sample.loop_, SampleFlags::LOOP, SampleFlags::BIDI_LOOP);
+    let sustain_loop = loop_points(sample.sustain_loop, SampleFlags::SUSTAIN, SampleFlags::BIDI_SUSTAIN);
+    let [flags, cvt] = flags.bits().to_le_bytes();
+
+    out.extend_from_slice(b"IMPS");
+    out.extend_from_slice(&sample.filename.bytes);
+    out.push(sample.global_volume);
+    out.push(flags);
+    out.push(sample.default_volume);
+    out.extend_from_slice(&sample.name.bytes);
+    out.push(cvt);
+    out.push(sample.default_panning);
+    u32(out, length);
+    u32(out, loop_.0);
+    u32(out, loop_.1);
+    u32(out, sample.samplerate_c5);
+    u32(out, sustain_loop.0);
+    u32(out, sustain_loop.1);
+    u32(out, pointer);
+    out.push(sample.vibrato_speed);
+    out.push(sample.vibrato_depth);
+    out.push(sample.vibrato_rate);
+    out.push(sample.vibrato_type);
+}
+
+/// Encodes the sample data, returns the format flags, the length field and the bytes.
+///
+/// The returned flags never contain any of the loop flags.
+fn sample_data(sample: &Sample, options: WriteOptions) -> (SampleFlags, u32, Cow<'_, [u8]>) {
+    let loop_flags = SampleFlags::LOOP
+        | SampleFlags::SUSTAIN
+        | SampleFlags::BIDI_LOOP
+        | SampleFlags::BIDI_SUSTAIN;
+
+    if let Some(patch) = &sample.fm_patch {
+        let flags = SampleFlags::DATA_PRESENT | SampleFlags::OPL_INSTRUMENT;
+        return (flags, 12, Cow::Borrowed(patch));
+    }
+
+    let data = match &sample.data {
+        Some(data) => data,
+        None => return (SampleFlags::empty(), 0, Cow::Borrowed(&[])),
+    };
+    let length = sample.length();
+    let mixdown = sample.is_stereo() && !options.target.extensions();
+    let it215 = options.target.it215_compression();
+
+    if let (false, false, Some(encoded)) = (sample.is_dirty(), mixdown, &sample.encoded) {
+        let compatible = it215 || !encoded.flags.contains(SampleFlags::COMPRESSED | SampleFlags::DELTA);
+        if compatible && (!options.compress_samples || encoded.flags.contains(SampleFlags::COMPRESSED)) {
+            return (encoded.flags - loop_flags, length, Cow::Borrowed(&encoded.bytes));
+        }
+    }
+
+    let mono;
+    let (data, channels) = if mixdown {
+        mono = convert::stereo_to_mono(data);
+        (&mono[..], 1)
+    } else {
+        (&data[..], usize::from(u8::from(sample.channels)))
+    };
+    let mut flags = SampleFlags::DATA_PRESENT | SampleFlags::DATA_SIGNED;
+    if channels > 1 {
+        flags |= SampleFlags::STEREO;
+    }
+    // The channels of stereo samples are stored one after the other.
+    let frames = usize::try_from(length).unwrap();
+    let planar = (0..frames * channels).map(|index| data[index % frames * channels + index / frames]);
+    let samples = match planar.clone().map(exact_8bit).collect::<Option<Vec<_>>>() {
+        Some(samples) => samples.into_iter().map(i16::from).collect(),
+        None => {
+            flags |= SampleFlags::DATA_16BIT;
+            planar.map(convert::f32_to_i16).collect::<Vec<_>>()
+        }
+    };
+    let is_16bit = flags.contains(SampleFlags::DATA_16BIT);
+
+    let bytes = if options.compress_samples {
+        // The delta flag marks the IT2.15 variant of the compression, each channel is compressed
+        // on its own.
+        flags |= SampleFlags::COMPRESSED;
+        if it215 {
+            flags |= SampleFlags::DELTA;
+        }
+        samples.chunks(frames.max(1))
+            .flat_map(|channel| compress(&channel.iter().copied().map(i32::from).collect::<Vec<_>>(), is_16bit, it215))
+            .collect()
+    } else if is_16bit {
+        samples.into_iter().flat_map(i16::to_le_bytes).collect()
+    } else {
+        samples.into_iter().map(|s| i8::try_from(s).unwrap()).flat_map(i8::to_le_bytes).collect()
+    };
+
+    (flags, length, Cow::Owned(bytes))
+}
+
+/// Returns the sample number (1-based) the instrument plays for the note, 0 if none.
+///
+/// In sample mode the instrument is the sample, in instrument mode the note defaults to C-5.
+fn sample_number(module: &Module, instrument: InstrumentId, note: Option<Note>) -> u8 {
+    let sample = if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        let note = note.unwrap_or_else(|| Note::try_from(60).unwrap());
+        module.get(instrument).and_then(|instrument| instrument.sample_map[note])
+    } else {
+        SampleId::try_from(instrument.as_u8()).ok()
+    };
+    sample.map_or(0, |sample| sample.as_u8() + 1)
+}
+
+fn count(len: usize, max: usize, message: &'static str) -> io::Result<u16> {
+    if len > max {
+        return Err(invalid(message));
+    }
+    Ok(u16::try_from(len).unwrap())
+}
+
+/// Appends zeroed `u32` offsets and returns their positions.
+fn reserve_offsets(out: &mut Vec<u8>, count: usize) -> Vec<usize> {
+    let start = out.len();
+    out.resize(start + 4 * count, 0);
+    (0..count).map(|i| start + 4 * i).collect()
+}
+
+/// Sets the `u32` offset at `field` to the current end of the output.
+fn patch_offset(out: &mut [u8], field: usize) -> io::Result<()> {
+    let offset = out.len();
+    set_offset(out, field, offset)
+}
+
+/// Sets the `u32` offset at `field` to the offset.
+fn set_offset(out: &mut [u8], field: usize, offset: usize) -> io::Result<()> {
+    out[field..field + 4].copy_from_slice(&offset_u32(offset)?.to_le_bytes());
+    Ok(())
+}
+
+fn offset_u32(offset: usize) -> io::Result<u32> {
+    u32::try_from(offset).map_err(|_| invalid("module is too large, offsets must fit into 32 bits"))
+}
+
+/// Returns `false` for the empty patterns of 64 rows which are stored as offset 0.
+fn is_stored(pattern: &Pattern) -> bool {
+    !(pattern.rows.len() == 64 && pattern.is_empty())
+}
+
+fn u16(out: &mut Vec<u8>, value: u16) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+fn invalid(message: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+    use pretty_assertions::assert_eq;
+
+    fn parse(data: &[u8]) -> Module {
+        parser::module_file::<VerboseError<&[u8]>>(data).expect("parser failed")
+    }
+
+    #[test]
+    fn module_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let effects = |module: &Module| module.patterns[0].rows.iter()
+            .flat_map(|row| row.iter().filter_map(|(_, cmd)| cmd.effect))
+            .collect::<Vec<_>>();
+
+        let mut module = parse(DATA);
+        module.pattern_names = vec![String::from("intro")];
+        module.channel_names = vec![String::new(), String::from("bass")];
+        module.openmpt_channel_count = Some(4);
+        let mut midi_config = MidiConfig::default();
+        midi_config.fixed[0] = MidiMacro::new("F0F00100");
+        module.midi_config = Some(midi_config);
+        module.openmpt_extensions = Some(SongExtensions { tempo_mode: Some(2), ..SongExtensions::default() });
+        let mut instrument = vec![0; 554];
+        instrument[..4].copy_from_slice(b"IMPI");
+        let mut instrument = parser::instrument_file::<VerboseError<&[u8]>>(&instrument).unwrap().instrument;
+        instrument.openmpt_extensions = Some(InstrumentExtensions {
+            volume_swing: Some(10),
+            other: vec![ExtensionProperty { code: *b"VR..", data: vec![1, 2] }],
+            ..InstrumentExtensions::default()
+        });
+        module.instruments = vec![instrument; 2];
+        module.opaque = OpaqueData {
+            header_reserved: u32::from_le_bytes(*b"OMPT"),
+            header_chunks: b"FX00\x04\0\0\0plug".to_vec(),
+            trailing: b"unknown chunk".to_vec(),
+        };
+        let mut written = Vec::new();
+        module.write_to(&mut written).unwrap();
+
+        let reparsed = parse(&written);
+        assert_eq!(effects(&module), effects(&reparsed));
+        assert_eq!(module.pattern_names, reparsed.pattern_names);
+        assert_eq!(module.channel_names, reparsed.channel_names);
+        assert_eq!(module.openmpt_channel_count, reparsed.openmpt_channel_count);
+        assert_eq!(module.openmpt_extensions, reparsed.openmpt_extensions);
+        assert_eq!(module.midi_config, reparsed.midi_config);
+        assert_eq!(module.opaque, reparsed.opaque);
+        let read = Module::read(io::Cursor::new(&written)).unwrap();
+        assert_eq!(module.midi_config, read.midi_config);
+        assert_eq!(module.opaque, read.opaque);
+        assert_eq!(module.channel_names, read.channel_names);
+        assert_eq!(module.instruments[0].openmpt_extensions, read.instruments[0].openmpt_extensions);
+        assert_eq!(format!("{:?}", module.samples), format!("{:?}", reparsed.samples));
+        assert_eq!(format!("{:?}", module.instruments), format!("{:?}", reparsed.instruments));
+        assert_eq!(module.orders, reparsed.orders);
+
+        // Writing is canonical, the written file is stable.
+        let mut rewritten = Vec::new();
+        reparsed.write_to(&mut rewritten).unwrap();
+        assert_eq!(written, rewritten);
+    }
+
+    #[test]
+    fn opaque_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = parse(DATA);
+        module.opaque = OpaqueData {
+            header_reserved: 0x1234_5678,
+            header_chunks: b"XTPM\x02\0\0\0ab".to_vec(),
+            trailing: b"trailing data".to_vec(),
+        };
+        let mut written = Vec::new();
+        module.write_to(&mut written).unwrap();
+        assert!(written.ends_with(b"trailing data"));
+
+        let reparsed = parse(&written);
+        assert_eq!(reparsed.opaque, module.opaque);
+        let mut rewritten = Vec::new();
+        reparsed.write_to(&mut rewritten).unwrap();
+        assert_eq!(written, rewritten);
+    }
+
+    #[test]
+    fn pattern_highlight_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let module = parse(DATA);
+        let mut highlighted = module.clone();
+        highlighted.patterns[0].highlight = Some((12, 3));
+        let mut written = Vec::new();
+        highlighted.write_to(&mut written).unwrap();
+
+        // IT files have no room for the override, the pattern is written without it.
+        let mut expected = Vec::new();
+        module.write_to(&mut expected).unwrap();
+        assert_eq!(written, expected);
+        assert_eq!(parse(&written).patterns[0].highlight, None);
+    }
+
+    #[test]
+    fn openmpt_extensions_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = parse(DATA);
+        module.openmpt_extensions = Some(SongExtensions {
+            tempo_mode: Some(1),
+            other: vec![ExtensionProperty { code: *b"RP..", data: vec![4, 0] }],
+            ..SongExtensions::default()
+        });
+        let mut instrument = vec![0; 554];
+        instrument[..4].copy_from_slice(b"IMPI");
+        let mut instrument = parser::instrument_file::<VerboseError<&[u8]>>(&instrument).unwrap().instrument;
+        instrument.openmpt_extensions = Some(InstrumentExtensions {
+            filter_mode: Some(1),
+            panning_swing: Some(20),
+            ..InstrumentExtensions::default()
+        });
+        module.instruments = vec![instrument];
+
+        let mut written = Vec::new();
+        module.write_to(&mut written).unwrap();
+        let reparsed = parse(&written);
+        assert_eq!(reparsed.openmpt_extensions, module.openmpt_extensions);
+        assert_eq!(reparsed.instruments[0].openmpt_extensions, module.instruments[0].openmpt_extensions);
+    }
+
+    #[test]
+    fn fm_patch_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = parse(DATA);
+        let patch = [0x21, 0x01, 0x8F, 0x0D, 0xF6, 0xF4, 0x74, 0x56, 0x00, 0x01, 0x0C, 0x00];
+        module.samples[0] = Sample { fm_patch: Some(patch), data: None, ..module.samples[0].clone() };
+        assert!(module.samples[0].is_fm());
+
+        let mut written = Vec::new();
+        module.write_to(&mut written).unwrap();
+        let sample = &parse(&written).samples[0];
+        assert!(sample.is_fm());
+        assert_eq!(sample.fm_patch, Some(patch));
+        assert!(sample.data.is_none());
+    }
+
+    #[test]
+    fn iti_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let sample = Sample { data: Some(vec![0.0, 1.0].into()), ..parse(DATA).samples[0].clone() };
+        let samples = [sample.clone(), sample.clone(), Sample { data: Some(vec![-1.0].into()), ..sample }];
+        let mut instrument = vec![0; 554];
+        instrument[..4].copy_from_slice(b"IMPI");
+        let mut instrument = parser::instrument_file::<VerboseError<&[u8]>>(&instrument).unwrap().instrument;
+        let note = |note| Note::try_from(note).unwrap();
+        instrument.sample_map.map[60] = Some(SampleId::try_from(2).unwrap());
+        instrument.sample_map.map[72] = Some(SampleId::try_from(0).unwrap());
+
+        let mut written = Vec::new();
+        instrument.write_iti(&samples, &mut written).unwrap();
+        let file = Instrument::read_iti(io::Cursor::new(&written)).unwrap();
+        assert_eq!(file.instrument.number_of_samples, 2);
+        assert_eq!(file.instrument.sample_map[note(60)], Some(SampleId::try_from(1).unwrap()));
+        assert_eq!(file.instrument.sample_map[note(72)], Some(SampleId::try_from(0).unwrap()));
+        assert_eq!(format!("{:?}", file.samples), format!("{:?}", [&samples[0], &samples[2]]));
+
+        let mut rewritten = Vec::new();
+        file.write_to(&mut rewritten).unwrap();
+        assert_eq!(written, rewritten);
+
+        instrument.sample_map.map[0] = Some(SampleId::try_from(3).unwrap());
+        assert!(instrument.write_iti(&samples, &mut Vec::new()).is_err());
+    }
+
+    #[test]
+    fn its_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let data = (0..1000).map(|i| f32::from(i16::try_from(i % 200).unwrap() - 100) / 127.0).collect();
+        let sample = Sample { data: Some(data), ..parse(DATA).samples[0].clone() };
+
+        let mut written = Vec::new();
+        sample.write_its_with(&mut written, WriteOptions { compress_samples: true, ..WriteOptions::default() }).unwrap();
+        let read = Sample::read_its(io::Cursor::new(&written)).unwrap();
+        assert!(read.encoded.is_some());
+        assert_eq!(read.data, sample.data);
+
+        // Unmodified compressed data is copied as it is.
+        let mut rewritten = Vec::new();
+        read.write_its(&mut rewritten).unwrap();
+        assert_eq!(written, rewritten);
+    }
+
+    #[test]
+    fn write_targets() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = parse(DATA);
+        module.pattern_names = vec![String::from("intro")];
+        module.openmpt_channel_count = Some(4);
+        module.samples[0].channels = RangedU8::MAX;
+        let half = 64.0 / 127.0;
+        module.samples[0].data = Some(vec![half, half, -1.0, -1.0].into());
+        let write = |target| {
+            let mut written = Vec::new();
+            module.write_to_with(&mut written, WriteOptions { compress_samples: true, target, ..WriteOptions::default() }).unwrap();
+            parse(&written)
+        };
+
+        for (target, versions) in [(WriteTarget::ImpulseTracker214, (0x0214, 0x0214)), (WriteTarget::ImpulseTracker215, (0x0215, 0x0215))] {
+            let written = write(target);
+            assert_eq!((written.made_with_version, written.compatible_with_version), versions);
+            assert!(written.pattern_names.is_empty());
+            assert_eq!(written.openmpt_channel_count, None);
+            assert!(!written.samples[0].is_stereo());
+            assert_eq!(written.samples[0].data.as_deref(), Some(&[half, -1.0][..]));
+        }
+
+        let written = write(WriteTarget::OpenMpt);
+        assert_eq!(written.detected_tracker(), Tracker::OpenMpt { version: 0x0129_0000 });
+        assert_eq!(written.pattern_names, module.pattern_names);
+        assert_eq!(written.samples[0].data, module.samples[0].data);
+    }
+
+    #[test]
+    fn extended_channels() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = parse(DATA);
+        let channel = Channel::new(100);
+        let (_, command) = module.patterns[0].rows.iter().flat_map(|row| row.iter()).find(|(_, cmd)| cmd.effect.is_some()).unwrap();
+        let command = *command;
+        module.patterns[0].rows[0].insert(channel, command);
+        module.patterns[0].active_channels |= ActiveChannels::new([channel]);
+        module.openmpt_channel_count = Some(100);
+        module.init_channel_panning[99] = ChannelPan::from(16);
+        module.init_channel_volume[99] = ChannelVolume::try_from(20).unwrap();
+        let write = |target| {
+            let mut written = Vec::new();
+            module.write_to_with(&mut written, WriteOptions { target, ..WriteOptions::default() }).unwrap();
+            parse(&written)
+        };
+
+        let written = write(WriteTarget::Module);
+        assert_eq!(written.openmpt_channel_count, Some(100));
+        assert_eq!(written.patterns[0].rows[0].get(channel).and_then(|cmd| cmd.effect), command.effect);
+        assert_eq!(written.init_channel_panning, module.init_channel_panning);
+        assert_eq!(written.init_channel_volume, module.init_channel_volume);
+
+        let written = write(WriteTarget::ImpulseTracker215);
+        assert!(written.patterns[0].rows[0].get(channel).is_none());
+        assert_eq!(written.init_channel_panning[99], ChannelPan::CENTRE);
+    }
+
+    #[test]
+    fn stereo_roundtrip() {
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let data = (0..1000).map(|i| f32::from(i16::try_from(i % 200).unwrap() - 100) / 127.0).collect::<Vec<_>>();
+        let sample = Sample {
+            channels: RangedU8::MAX,
+            data: Some(data.into()),
+            loop_: Some(SampleLoop { start: 100, end: 500, bidi: false }),
+            ..parse(DATA).samples[0].clone()
+        };
+        for compress_samples in [false, true] {
+            let mut written = Vec::new();
+            sample.write_its_with(&mut written, WriteOptions { compress_samples, ..WriteOptions::default() }).unwrap();
+            let read = Sample::read_its(io::Cursor::new(&written)).unwrap();
+            assert!(read.is_stereo());
+            assert_eq!(read.length(), 500);
+            assert_eq!(read.data, sample.data);
+        }
+    }
+
+    #[test]
+    fn streamed() {
+        /// Writer recording the length of every write
+        struct Chunks(Vec<u8>, Vec<usize>);
+
+        impl Write for Chunks {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.0.extend_from_slice(buf);
+                self.1.push(buf.len());
+                Ok(buf.len())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        const DATA: &[u8] = include_bytes!("../tests/effect_alphabet.it");
+        let mut module = parse(DATA);
+        module.samples[0].data = Some((0..1000i16).map(|i| f32::from(i % 200 - 100) / 127.0).collect::<Vec<_>>().into());
+        module.set_message("streamed").unwrap();
+
+        let mut chunks = Chunks(Vec::new(), Vec::new());
+        module.write_to(&mut chunks).unwrap();
+        assert!(chunks.1.iter().all(|&length| length <= 1000));
+        let reparsed = parse(&chunks.0);
+        assert_eq!(reparsed.samples[0].data, module.samples[0].data);
+        assert_eq!(reparsed.message, "streamed");
+
+        // Nothing is written if the module can't be represented.
+        module.patterns[0].truncated = true;
+        let mut chunks = Chunks(Vec::new(), Vec::new());
+        assert!(module.write_to(&mut chunks).is_err());
+        assert!(chunks.0.is_empty());
+    }
+}
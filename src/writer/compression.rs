@@ -0,0 +1,194 @@
+//! IT2.15 sample compression
+//!
+//! Inverse of the decompression done by the parser, the data is split into blocks of `0x8000`
+//! samples for 8-bit and `0x4000` samples for 16-bit data and the differences of the differences
+//! of successive samples are packed into a bitstream of variable width.
+//!
+//! The width is chosen greedily, it's increased as soon as a value doesn't fit and decreased when
+//! the following values fit into fewer bits and the savings outweigh the cost of the change. This
+//! doesn't give the smallest possible output but it's close to what other trackers produce.
+
+use std::convert::TryFrom;
+
+
+/// Number of samples in a compressed block of 8-bit data
+const BLOCK_LENGTH_8BIT: usize = 0x8000;
+
+/// Number of samples in a compressed block of 16-bit data
+const BLOCK_LENGTH_16BIT: usize = 0x4000;
+
+/// Number of values considered when deciding to decrease the width
+const LOOKAHEAD: usize = 16;
+
+
+/// Compresses signed PCM samples of 8 or 16 bits using IT2.14 or IT2.15 compression
+///
+/// IT2.15 compression stores the deltas of the deltas of the samples, IT2.14 only the deltas.
+/// Returns the compressed blocks, each prefixed by its length, as they are stored in the file.
+pub(super) fn compress(samples: &[i32], is_16bit: bool, it215: bool) -> Vec<u8> {
+    let (bits, block_length) = if is_16bit {
+        (16, BLOCK_LENGTH_16BIT)
+    } else {
+        (8, BLOCK_LENGTH_8BIT)
+    };
+
+    let mut out = Vec::new();
+    for block in samples.chunks(block_length) {
+        let mut last = 0;
+        let mut last_delta = 0;
+        let values = block.iter()
+            .map(|&sample| {
+                let delta = wrap(sample - last, bits);
+                let value = if it215 { wrap(delta - last_delta, bits) } else { delta };
+                last = sample;
+                last_delta = delta;
+                value
+            })
+            .collect::<Vec<_>>();
+
+        let bytes = compress_block(&values, bits);
+        let length = u16::try_from(bytes.len()).expect("BUG: compressed block doesn't fit into 64 KiB");
+        out.extend_from_slice(&length.to_le_bytes());
+        out.extend_from_slice(&bytes);
+    }
+    out
+}
+
+fn compress_block(values: &[i32], bits: u8) -> Vec<u8> {
+    let max_width = bits + 1;
+    let needed = values.iter()
+        .map(|&value| (1..=max_width).find(|&width| fits(value, width, bits)).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut writer = BitWriter::default();
+    let mut width = max_width;
+    for (index, &value) in values.iter().enumerate() {
+        let window = &needed[index..(index + LOOKAHEAD).min(needed.len())];
+        let window_width = *window.iter().max().unwrap();
+
+        let savings = usize::from(width.saturating_sub(window_width)) * window.len();
+        let target = if needed[index] > width || savings > usize::from(width) + 3 {
+            window_width
+        } else {
+            width
+        };
+
+        if target != width {
+            change_width(&mut writer, width, target, bits);
+            width = target;
+        }
+
+        let modulus = if width == max_width { 1 << bits } else { 1 << width };
+        writer.write(u32::try_from(value.rem_euclid(modulus)).unwrap(), width);
+    }
+    writer.bytes
+}
+
+/// Returns `true` if the value can be stored using the width without colliding with the width
+/// change markers.
+fn fits(value: i32, width: u8, bits: u8) -> bool {
+    let half = 1 << (width - 1);
+    if width == bits + 1 {
+        true
+    } else if width < 7 {
+        -half < value && value < half
+    } else {
+        let reserved = i32::from(bits / 2);
+        -half + reserved <= value && value < half - reserved
+    }
+}
+
+/// Writes the marker changing the width `from` to `to`.
+fn change_width(writer: &mut BitWriter, from: u8, to: u8, bits: u8) {
+    // Widths are encoded without the current width, values from it up are shifted by one.
+    let encoded = u32::from(if to < from { to } else { to - 1 });
+    if from < 7 {
+        if to > 9 {
+            // Only widths up to 9 can be reached in one step from the narrow widths.
+            change_width(writer, from, 9, bits);
+            change_width(writer, 9, to, bits);
+            return;
+        }
+        writer.write(1 << (from - 1), from);
+        writer.write(encoded - 1, 3);
+    } else if from < bits + 1 {
+        let border = (((1 << bits) - 1) >> (bits + 1 - from)) - u32::from(bits / 2);
+        writer.write(border + encoded, from);
+    } else {
+        writer.write((1 << bits) | (u32::from(to) - 1), from);
+    }
+}
+
+/// Wraps the value around to a signed integer of `bits` bits.
+fn wrap(value: i32, bits: u8) -> i32 {
+    let half = 1 << (bits - 1);
+    (value + half).rem_euclid(1 << bits) - half
+}
+
+/// Writes values to a bitstream starting from the least significant bit of each byte.
+#[derive(Default)]
+struct BitWriter {
+    bytes: Vec<u8>,
+    position: usize,
+}
+
+impl BitWriter {
+    fn write(&mut self, value: u32, width: u8) {
+        for bit in 0..width {
+            if self.position == 8 * self.bytes.len() {
+                self.bytes.push(0);
+            }
+            let last = self.bytes.len() - 1;
+            self.bytes[last] |= u8::try_from(value >> bit & 1).unwrap() << (self.position % 8);
+            self.position += 1;
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser::compressed_sample;
+
+    fn roundtrip(samples: &[i32], is_16bit: bool) {
+        for it215 in [false, true] {
+            let compressed = compress(samples, is_16bit, it215);
+            let (rest, data) = compressed_sample::<VerboseError<_>>(&compressed, samples.len(), is_16bit, it215)
+                .expect("decompression failed");
+            assert!(rest.is_empty());
+
+            let max = if is_16bit { 32767.0 } else { 127.0 };
+            let decoded = data.iter().map(|&x| (x * max).round()).collect::<Vec<f32>>();
+            let expected = samples.iter().map(|&s| f32::from(i16::try_from(s).unwrap())).collect::<Vec<f32>>();
+            assert!(decoded == expected, "decompressed data differs");
+        }
+    }
+
+    #[test]
+    fn compress_roundtrip() {
+        // Pseudo-random noise, silence, full scale jumps and a slow triangle wave, longer than
+        // a block.
+        let mut state = 1u32;
+        let mut noise = move |range: i32| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            i32::try_from(state >> 16).unwrap() % range
+        };
+        for &(is_16bit, min, max) in &[(false, -128, 127), (true, -32768, 32767)] {
+            let mut samples = Vec::new();
+            for i in 0..0x9000 {
+                let sample = match i / 0x1000 {
+                    0 => noise(max - min) + min,
+                    1 => noise(4) - 2,
+                    2 => 0,
+                    3 if i % 2 == 0 => min,
+                    3 => max,
+                    _ => (i % 200 - 100) * max / 100,
+                };
+                samples.push(sample);
+            }
+            roundtrip(&samples, is_16bit);
+        }
+    }
+}
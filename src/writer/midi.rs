@@ -0,0 +1,261 @@
+//! Export of the note data to Standard MIDI Files (.mid)
+
+use crate::data::*;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+
+
+/// MIDI ticks per quarter note, every tick of the module is one MIDI tick
+///
+/// A quarter note is 24 ticks of the module, four rows at speed 6, the MIDI tempo in beats per
+/// minute is then the same as the tempo of the module.
+const DIVISION: u16 = 24;
+
+/// MIDI channels given to the tracks in turn, channel 10 is left out as it plays percussion
+const MIDI_CHANNELS: [u8; 15] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 10, 11, 12, 13, 14, 15];
+
+
+/// Split of the notes into tracks by [`Module::to_midi`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MidiTracks {
+    /// One track for every channel the song plays notes in
+    Channels,
+
+    /// One track for every instrument the song plays notes with, the samples in sample mode
+    Instruments,
+}
+
+
+/// Note playing in a channel
+#[derive(Clone, Copy)]
+struct Playing {
+    track: usize,
+    key: u8,
+}
+
+/// Notes of a track, the velocity is `None` for the note off
+#[derive(Default)]
+struct Track {
+    events: Vec<(u32, u8, Option<u8>)>,
+}
+
+
+impl Module {
+    /// Exports the notes of the module as a Standard MIDI File (.mid)
+    ///
+    /// The song is followed in play order the same way as by [`Module::estimated_duration`], the
+    /// first track holds the tempo map and the notes are split into the following tracks as
+    /// selected by `tracks`. One MIDI tick is one tick of the module and a quarter note 24 ticks,
+    /// so speed changes move the notes and tempo changes become tempo events. The conversion is
+    /// lossy:
+    ///
+    /// - note C-5 is MIDI note 60 ([`Note::midi_number`]), the velocity is taken from the volume
+    ///   column or the default volume of the sample, the instrument and sample settings are lost,
+    /// - notes play until the next note, note off, cut or fade in their channel, or a note cut
+    ///   (`SCx`), new note actions are ignored, delayed notes (`SDx`) start late,
+    /// - all the other effects and volume column commands are dropped,
+    /// - the tracks get the MIDI channels in turn leaving out channel 10 (percussion), tracks past
+    ///   the 15th share the channels.
+    pub fn to_midi(&self, tracks: MidiTracks) -> Vec<u8> {
+        midi_bytes(self, tracks)
+    }
+}
+
+
+fn midi_bytes(module: &Module, tracks: MidiTracks) -> Vec<u8> {
+    let mut tempo_map = vec![(0, module.tempo.as_u8())];
+    let mut notes = BTreeMap::<usize, Track>::new();
+    let mut playing = [None::<Playing>; MAX_CHANNELS];
+    let mut instruments = [None::<InstrumentId>; MAX_CHANNELS];
+    let mut time = 0;
+
+    module.walk_ticks(|tick| {
+        if tempo_map.last().is_some_and(|&(_, tempo)| tempo != tick.tempo) {
+            tempo_map.push((time, tick.tempo));
+        }
+        if tick.tick == 0 {
+            let commands = match module.orders[tick.order] {
+                Order::Index(pattern) => module.get(pattern).and_then(|pattern| pattern.row(tick.row)),
+                _ => None,
+            };
+            for (channel, command) in commands.into_iter().flat_map(Row::iter) {
+                let index = channel.as_usize();
+                if let Some(instrument) = command.instrument {
+                    instruments[index] = Some(instrument);
+                }
+                let (delay, cut) = match command.effect {
+                    Some(EffectCmd::Special(Some(Special::NoteDelay(delay)))) => (u32::from(delay.as_u8()), None),
+                    Some(EffectCmd::Special(Some(Special::NoteCut(cut)))) => (0, Some(time + u32::from(cut.as_u8()))),
+                    _ => (0, None),
+                };
+                // Notes delayed past the end of the row are not played.
+                if delay >= u32::from(tick.speed) {
+                    continue;
+                }
+                let start = time + delay;
+
+                if command.note.is_some() {
+                    if let Some(note) = playing[index].take() {
+                        notes.entry(note.track).or_default().events.push((start, note.key, None));
+                    }
+                }
+                if let (Some(NoteCmd::Play(note)), Some(instrument)) = (command.note, instruments[index]) {
+                    let track = match tracks {
+                        MidiTracks::Channels => index,
+                        MidiTracks::Instruments => usize::from(instrument.as_u8()),
+                    };
+                    let key = note.midi_number();
+                    let sounds = cut.is_none_or(|cut| cut > start);
+                    if let (Some(velocity), true) = (velocity(module, command, instrument, note), sounds) {
+                        notes.entry(track).or_default().events.push((start, key, Some(velocity)));
+                        playing[index] = Some(Playing { track, key });
+                    }
+                }
+                if let Some(cut) = cut {
+                    if let Some(note) = playing[index].take() {
+                        notes.entry(note.track).or_default().events.push((cut.max(start), note.key, None));
+                    }
+                }
+            }
+        }
+        time += 1;
+    });
+    for note in playing.iter().flatten() {
+        notes.entry(note.track).or_default().events.push((time, note.key, None));
+    }
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"MThd");
+    out.extend_from_slice(&6u32.to_be_bytes());
+    out.extend_from_slice(&1u16.to_be_bytes()); // simultaneous tracks
+    out.extend_from_slice(&u16::try_from(notes.len() + 1).unwrap().to_be_bytes());
+    out.extend_from_slice(&DIVISION.to_be_bytes());
+
+    let mut events = vec![(0, name_event(&module.name.to_string()))];
+    for &(time, tempo) in &tempo_map {
+        // Microseconds per quarter note, 24 ticks of 2.5 / tempo seconds.
+        let tempo = 60_000_000 / u32::from(tempo.max(1));
+        let mut event = vec![0xFF, 0x51, 0x03];
+        event.extend_from_slice(&tempo.to_be_bytes()[1..]);
+        events.push((time, event));
+    }
+    track_chunk(&mut out, events, time);
+
+    for (index, (key, mut track)) in notes.into_iter().enumerate() {
+        let channel = MIDI_CHANNELS[index % MIDI_CHANNELS.len()];
+        let mut events = vec![(0, name_event(&track_name(module, tracks, key)))];
+        // Note offs are pushed before the notes starting at the same time, the sort is stable.
+        track.events.sort_by_key(|&(time, _, _)| time);
+        events.extend(track.events.into_iter().map(|(time, key, velocity)| match velocity {
+            Some(velocity) => (time, vec![0x90 | channel, key, velocity]),
+            None => (time, vec![0x80 | channel, key, 0x40]),
+        }));
+        track_chunk(&mut out, events, time);
+    }
+
+    out
+}
+
+/// Returns the velocity of the note, `None` if the instrument has no sample for it.
+fn velocity(module: &Module, command: &Command, instrument: InstrumentId, note: Note) -> Option<u8> {
+    let sample = if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) {
+        module.get(instrument).and_then(|instrument| instrument.sample_map[note])
+    } else {
+        SampleId::try_from(instrument.as_u8()).ok()
+    };
+    let sample = module.get(sample?)?;
+    let volume = match command.volume {
+        Some(VolumeCmd::SetVolume(volume)) => volume.as_u8(),
+        _ => sample.default_volume.min(64),
+    };
+    // Volume 0..=64 is scaled to velocity 1..=127, velocity 0 would be a note off.
+    Some(u8::try_from((u16::from(volume) * 127 + 32) / 64).unwrap().max(1))
+}
+
+fn track_name(module: &Module, tracks: MidiTracks, key: usize) -> String {
+    let (name, kind) = match tracks {
+        MidiTracks::Channels => (module.channel_names.as_slice().get(key).cloned(), "Channel"),
+        MidiTracks::Instruments if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) => {
+            (module.instruments.as_slice().get(key).map(|instrument| instrument.name.to_string()), "Instrument")
+        }
+        MidiTracks::Instruments => (module.samples.as_slice().get(key).map(|sample| sample.name.to_string()), "Sample"),
+    };
+    name.filter(|name| !name.is_empty())
+        .unwrap_or_else(|| format!("{} {}", kind, key + 1))
+}
+
+fn name_event(name: &str) -> Vec<u8> {
+    let mut event = vec![0xFF, 0x03];
+    variable_length(&mut event, u32::try_from(name.len()).unwrap());
+    event.extend_from_slice(name.as_bytes());
+    event
+}
+
+/// Writes the track chunk of the events sorted by time, the track ends at `end`.
+fn track_chunk(out: &mut Vec<u8>, events: Vec<(u32, Vec<u8>)>, end: u32) {
+    let mut data = Vec::new();
+    let mut last = 0;
+    for (time, event) in events {
+        variable_length(&mut data, time - last);
+        data.extend_from_slice(&event);
+        last = time;
+    }
+    variable_length(&mut data, end.saturating_sub(last));
+    data.extend_from_slice(&[0xFF, 0x2F, 0x00]);
+
+    out.extend_from_slice(b"MTrk");
+    out.extend_from_slice(&u32::try_from(data.len()).expect("MIDI track is too long").to_be_bytes());
+    out.extend_from_slice(&data);
+}
+
+/// Writes the value as a MIDI variable-length quantity, 7 bits per byte from the highest.
+fn variable_length(out: &mut Vec<u8>, value: u32) {
+    let mut shift = 28;
+    while shift > 0 && value >> shift == 0 {
+        shift -= 7;
+    }
+    while shift > 0 {
+        out.push(0x80 | u8::try_from((value >> shift) & 0x7F).unwrap());
+        shift -= 7;
+    }
+    out.push(u8::try_from(value & 0x7F).unwrap());
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn to_midi() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.flags = ModuleFlags::STEREO;
+        module.tempo = RangedU8::try_from(120).unwrap();
+        module.patterns = vec![Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: vec![Row::empty(); 2],
+            truncated: false,
+            highlight: None,
+        }];
+        module.orders = vec![Order::Index(PatternId::try_from(0).unwrap())];
+        let pattern = &mut module.patterns[0];
+        pattern.set_note(0, Channel::new(1), NoteCmd::Play(Note::try_from(60).unwrap()));
+        pattern.set_instrument(0, Channel::new(1), InstrumentId::try_from(0).unwrap());
+        pattern.set_volume(0, Channel::new(1), VolumeCmd::SetVolume(RangedU8::try_from(32).unwrap()));
+        pattern.set_effect(1, Channel::new(1), EffectCmd::Special(Some(Special::NoteCut(RangedU8::try_from(3).unwrap()))));
+
+        let file = module.to_midi(MidiTracks::Channels);
+        assert_eq!(file[..14], [b'M', b'T', b'h', b'd', 0, 0, 0, 6, 0, 1, 0, 2, 0, 24]);
+        // The tempo of 120 BPM is 500000 microseconds per quarter note.
+        let tempo = [0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20];
+        assert!(file.windows(tempo.len()).any(|window| window == tempo));
+        // The note starts at tick 0 and is cut at tick 9, 6 ticks of the first row and 3 of the
+        // second.
+        let note = [0x00, 0x90, 60, 64, 0x09, 0x80, 60, 0x40];
+        assert!(file.windows(note.len()).any(|window| window == note));
+        assert!(file.ends_with(&[0x03, 0xFF, 0x2F, 0x00]));
+    }
+}
@@ -0,0 +1,198 @@
+use super::*;
+use crate::parser::{ChannelMask, Mask};
+
+
+/// Largest length of packed pattern data, the pattern with its 8 byte header must be less than
+/// 64 KiB.
+const MAX_PACKED_LENGTH: usize = 0xFFFF - 8;
+
+/// Command packer state
+///
+/// Holds the previously written command mask and sub-commands for each channel, these are reused
+/// instead of writing the same values again.
+struct State {
+    last_maskvar: [Mask; MAX_CHANNELS],
+    last_note: [Option<u8>; MAX_CHANNELS],
+    last_instrument: [Option<u8>; MAX_CHANNELS],
+    last_volume: [Option<u8>; MAX_CHANNELS],
+    last_effect: [Option<(u8, u8)>; MAX_CHANNELS],
+
+    /// The last effect columns were written by parameter control events, the parser doesn't reuse
+    /// their values as effects
+    param_control_effect: [bool; MAX_CHANNELS],
+}
+
+impl Default for State {
+    fn default() -> State {
+        State {
+            last_maskvar: [Mask::empty(); MAX_CHANNELS],
+            last_note: [None; MAX_CHANNELS],
+            last_instrument: [None; MAX_CHANNELS],
+            last_volume: [None; MAX_CHANNELS],
+            last_effect: [None; MAX_CHANNELS],
+            param_control_effect: [false; MAX_CHANNELS],
+        }
+    }
+}
+
+
+/// Packs the pattern, the commands of the channels from `channels` on are left out.
+pub(super) fn pattern(out: &mut Vec<u8>, pattern: &Pattern, channels: usize) -> io::Result<()> {
+    let mut state = State::default();
+    let mut packed = Vec::new();
+    for row in &pattern.rows {
+        for (channel, command) in row.iter() {
+            if !command.is_empty() && channel.as_usize() < channels {
+                state.command(&mut packed, channel, command);
+            }
+        }
+        packed.push(0);
+    }
+
+    if packed.len() > MAX_PACKED_LENGTH {
+        return Err(invalid("packed pattern is too long, it must be less than 64 KiB"));
+    }
+    let rows = u16::try_from(pattern.rows.len())
+        .map_err(|_| invalid("pattern has too many rows, at most 65535 are allowed"))?;
+
+    u16(out, u16::try_from(packed.len()).unwrap());
+    u16(out, rows);
+    out.extend_from_slice(&[0; 4]); // padding
+    out.extend_from_slice(&packed);
+    Ok(())
+}
+
+impl State {
+    fn command(&mut self, out: &mut Vec<u8>, channel: Channel, command: &Command) {
+        let chan = channel.as_usize();
+        let mut mask_var = Mask::empty();
+        let mut values = Vec::with_capacity(5);
+
+        if let Some(note) = command.note {
+            let note = note.to_raw();
+            if reuse(&mut self.last_note[chan], note) {
+                mask_var |= Mask::LAST_NOTE;
+            } else {
+                mask_var |= Mask::READ_NOTE;
+                values.push(note);
+            }
+        }
+
+        // Parameter control events store their values in the other columns.
+        let (instrument, volume, effect) = match command.note {
+            Some(NoteCmd::ParamControl { plugin, param, value, .. }) => {
+                let [high, low] = value.to_be_bytes();
+                (Some(plugin), Some(param), Some((high, low)))
+            }
+            _ => (
+                command.instrument.map(|instrument| instrument.as_u8() + 1),
+                command.volume.map(u8::from),
+                command.effect.map(encode_effect),
+            ),
+        };
+
+        if let Some(instrument) = instrument {
+            if reuse(&mut self.last_instrument[chan], instrument) {
+                mask_var |= Mask::LAST_INSTRUMENT;
+            } else {
+                mask_var |= Mask::READ_INSTRUMENT;
+                values.push(instrument);
+            }
+        }
+
+        if let Some(volume) = volume {
+            if reuse(&mut self.last_volume[chan], volume) {
+                mask_var |= Mask::LAST_VOLUME;
+            } else {
+                mask_var |= Mask::READ_VOLUME;
+                values.push(volume);
+            }
+        }
+
+        if let Some((effect, param)) = effect {
+            let param_control = matches!(command.note, Some(NoteCmd::ParamControl { .. }));
+            if self.param_control_effect[chan] && !param_control {
+                self.last_effect[chan] = None;
+            }
+            self.param_control_effect[chan] = param_control;
+            if reuse(&mut self.last_effect[chan], (effect, param)) {
+                mask_var |= Mask::LAST_EFFECT;
+            } else {
+                mask_var |= Mask::READ_EFFECT;
+                values.extend_from_slice(&[effect, param]);
+            }
+        }
+
+        let channel_var = u8::try_from(chan + 1).unwrap();
+        if mask_var == self.last_maskvar[chan] {
+            out.push(channel_var);
+        } else {
+            out.push(channel_var | ChannelMask::LAST_MASKVAR.bits());
+            out.push(mask_var.bits());
+            self.last_maskvar[chan] = mask_var;
+        }
+        out.extend_from_slice(&values);
+    }
+}
+
+/// Returns `true` if the value is the same as the last one, otherwise remembers it.
+fn reuse<T: Copy + PartialEq>(last: &mut Option<T>, value: T) -> bool {
+    if *last == Some(value) {
+        true
+    } else {
+        *last = Some(value);
+        false
+    }
+}
+
+/// Encode structured effect into raw effect number and parameter
+///
+/// Same as [`EffectCmd::to_raw`].
+pub fn encode_effect(effect: EffectCmd) -> (u8, u8) {
+    effect.to_raw()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::parser;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn effect_roundtrip() {
+        for effect in 0x01..=0x1A {
+            for param in 0x00..=0xFF {
+                if let Some(parsed) = parser::effect(effect, param) {
+                    let (effect, param) = encode_effect(parsed);
+                    assert_eq!(parser::effect(effect, param), Some(parsed));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn param_control_roundtrip() {
+        let pattern = crate::pattern![
+            "PC  02 005 999", "PCs 02 005 999", "C-5 02 v64 ...", "PC  00 100 000", "PC  02 005 999", "... .. ... CE7",
+        ];
+        let mut out = Vec::new();
+        super::pattern(&mut out, &pattern, MAX_CHANNELS).unwrap();
+        // The second event reuses all columns, the note reuses the instrument of the events.
+        assert_eq!(&out[8..16], [0x81, 0x0F, 252, 2, 5, 3, 0xE7, 0]);
+        assert_eq!(&out[16..22], [0x81, 0xE1, 251, 0, 0x81, 0x25]);
+        // The effect with the same bytes as the value of the event before isn't reused.
+        assert_eq!(&out[out.len() - 5..], &[0x81, 0x08, 3, 0xE7, 0]);
+
+        let (_, parsed) = crate::Pattern::parse_limited::<crate::error::VerboseError<&[u8]>>(&out, 200).unwrap();
+        assert_eq!(parsed.render_text(parsed.active_channels), pattern.render_text(pattern.active_channels));
+    }
+
+    #[test]
+    fn volume_roundtrip() {
+        for x in 0x00..=0xFF {
+            if let Ok(volume) = VolumeCmd::try_from(x) {
+                assert_eq!(u8::from(volume), x);
+            }
+        }
+    }
+}
@@ -0,0 +1,435 @@
+//! Down-conversion to ProTracker module files (.mod)
+
+use super::{count, exact_8bit, invalid, sample_number};
+use crate::data::*;
+use crate::parser::util::Cast;
+use std::convert::TryFrom;
+use std::io;
+
+
+/// Number of samples of a file
+const SAMPLES: usize = 31;
+
+/// Number of rows of every pattern
+const ROWS: usize = 64;
+
+/// Number of patterns ProTracker plays, files with more use the `M!K!` signature
+const PROTRACKER_PATTERNS: usize = 64;
+
+/// Longest sample data, the length is stored in 16-bit words
+const MAX_SAMPLE_LENGTH: usize = 2 * 0xFFFF;
+
+/// IT note played with the first period of [`PERIODS`], that's C-1 in ProTracker
+const FIRST_NOTE: u8 = 48;
+
+/// Amiga periods of the three octaves of ProTracker without finetune
+const PERIODS: [u16; 36] = [
+    856, 808, 762, 720, 678, 640, 604, 570, 538, 508, 480, 453,
+    428, 404, 381, 360, 339, 320, 302, 285, 269, 254, 240, 226,
+    214, 202, 190, 180, 170, 160, 151, 143, 135, 127, 120, 113,
+];
+
+
+/// Parts of the module lost by [`Module::to_mod`]
+///
+/// The counts are of the entries which were dropped or played differently in the written file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModReport {
+    /// Instruments flattened to their samples, envelopes and all other instrument settings are
+    /// lost
+    pub instruments: usize,
+    /// Samples with settings MOD doesn't have: global volume, default panning, auto-vibrato,
+    /// sustain loops, ping-pong loops, C-5 speeds which are not one of the finetunes or FM
+    /// patches
+    pub samples: usize,
+    /// Samples whose data was not exact 8-bit mono PCM or was longer than 128 KiB
+    pub sample_data: usize,
+    /// Rows of patterns longer than 64 rows
+    pub rows: usize,
+    /// Notes outside of C-4..=B-6 transposed by octaves into the range, note fades, note offs and
+    /// note cuts which didn't fit into the effect column
+    pub notes: usize,
+    /// Volume column commands other than set volume and set volume which didn't fit into the
+    /// effect column
+    pub volume_commands: usize,
+    /// Effects without a MOD equivalent
+    pub effects: usize,
+    /// The module uses linear slides, MOD only has Amiga slides
+    pub linear_slides: bool,
+    /// The initial speed or tempo is not 6 and 125, MOD files don't store them
+    pub initial_tempo: bool,
+}
+
+
+impl Module {
+    /// Converts the module into a ProTracker module file (.mod)
+    ///
+    /// Returns the file and a report of what was lost. Only modules which fit are converted:
+    /// the channels with commands are packed together in order and stored as a 4 channel file
+    /// (`M.K.`, or `M!K!` with more than 64 patterns) or as an 8 channel file (`8CHN`). The rest is
+    /// down-converted:
+    ///
+    /// - in instrument mode every note plays the sample the instrument maps it to, the instrument
+    ///   settings are lost,
+    /// - only 64 rows of each pattern are kept, shorter patterns are padded with empty rows and end
+    ///   with a pattern break (`D00`) if there is room for one,
+    /// - notes are clamped to the three octaves of ProTracker by transposing them by octaves,
+    ///   note cuts and note offs are written as `EC0`,
+    /// - the volume column only keeps set volume, written as `Cxx` if there is no effect,
+    /// - effects are converted the inverse way of the [MOD importer](crate::formats::protracker),
+    ///   effects ProTracker doesn't have are dropped, as are the slides with memory and the volume
+    ///   slides up and down at the same time,
+    /// - the C-5 speed of the samples is rounded to the nearest finetune, loops are forward loops
+    ///   starting and ending on even bytes, sustain loops are dropped,
+    /// - sample data is stored as signed 8-bit mono PCM of at most 128 KiB, separators in the
+    ///   order list are skipped and the list ends at the first end of song.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the module can not be represented in the
+    /// format, that is if there are more than 128 orders, 31 samples or 8 channels with
+    /// commands, if an order plays a pattern past the 128th or if a pattern has been
+    /// [truncated](Pattern::truncated) during parsing or the data of a sample was [not
+    /// loaded](Sample::is_loaded).
+    pub fn to_mod(&self) -> io::Result<(Vec<u8>, ModReport)> {
+        module_bytes(self)
+    }
+}
+
+
+fn module_bytes(module: &Module) -> io::Result<(Vec<u8>, ModReport)> {
+    let orders = module.orders
+        .iter()
+        .take_while(|&&order| order != Order::EndOfSong)
+        .filter_map(|order| match order {
+            Order::Index(pattern) => Some(pattern.as_u8()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let song_length = count(orders.len(), 128, "too many orders, at most 128 are allowed")?;
+    count(module.samples.len(), SAMPLES, "too many samples, at most 31 are allowed")?;
+    if orders.iter().any(|&pattern| pattern >= 128) {
+        return Err(invalid("too many patterns, at most 128 are allowed"));
+    }
+    if !module.samples.iter().all(Sample::is_loaded) {
+        return Err(invalid("sample data was not loaded"));
+    }
+    if module.patterns.iter().any(|pattern| pattern.truncated) {
+        return Err(invalid("pattern was truncated during parsing and cannot be written"));
+    }
+
+    let mut used_channels = ActiveChannels::empty();
+    for (channel, _) in module.patterns.iter().flat_map(|pattern| &pattern.rows).flat_map(Row::iter) {
+        used_channels |= ActiveChannels::new([channel]);
+    }
+    let channels = used_channels.iter().collect::<Vec<_>>();
+    let (channel_count, signature) = match channels.len() {
+        0 ..= 4 => (4, b"M.K."),
+        5 ..= 8 => (8, b"8CHN"),
+        _ => return Err(invalid("too many channels, at most 8 channels can have commands")),
+    };
+
+    let mut report = ModReport {
+        instruments: if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) { module.instruments.len() } else { 0 },
+        samples: module.samples.iter().filter(|sample| loses_settings(sample)).count(),
+        linear_slides: module.flags.contains(ModuleFlags::LINEAR_SLIDES),
+        initial_tempo: module.speed.as_u8() != 6 || module.tempo.as_u8() != 125,
+        ..ModReport::default()
+    };
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&module.name.bytes[..20]);
+    let mut sample_data = Vec::with_capacity(SAMPLES);
+    for sample in &module.samples {
+        sample_data.push(self::sample(&mut out, sample, &mut report));
+    }
+    for _ in module.samples.len()..SAMPLES {
+        out.extend_from_slice(&[0; 22]);
+        out.extend_from_slice(&[0, 0, 0, 0, 0, 0, 0, 1]); // empty, no loop
+    }
+
+    // The patterns up to the highest one in the whole order table are stored, the unused part of
+    // the table is zero.
+    let mut order_table = [0; 128];
+    order_table[..orders.len()].copy_from_slice(&orders);
+    let pattern_count = usize::from(order_table.iter().copied().max().unwrap_or(0)) + 1;
+    out.push(song_length.cast());
+    out.push(127); // restart position, not used
+    out.extend_from_slice(&order_table);
+    out.extend_from_slice(if channel_count == 4 && pattern_count > PROTRACKER_PATTERNS { b"M!K!" } else { signature });
+
+    let empty = Pattern { active_channels: ActiveChannels::empty(), rows: Vec::new(), truncated: false, highlight: None };
+    for index in 0..pattern_count {
+        let pattern = module.patterns.as_slice().get(index).unwrap_or(&empty);
+        self::pattern(&mut out, module, pattern, &channels, channel_count, &mut report);
+    }
+
+    for data in sample_data {
+        out.extend(data);
+    }
+    Ok((out, report))
+}
+
+/// Returns `true` if the sample has settings which can't be stored, see [`ModReport::samples`].
+fn loses_settings(sample: &Sample) -> bool {
+    sample.global_volume != 64
+        || sample.default_panning & Sample::dfp_usePanning != 0
+        || sample.vibrato_depth != 0
+        || sample.sustain_loop.is_some()
+        || sample.loop_.is_some_and(|l| l.bidi)
+        || sample.fm_patch.is_some()
+        || {
+            let speed = C5Speed::from(sample.samplerate_c5);
+            C5Speed::from_mod_finetune(speed.to_mod_finetune()) != speed
+        }
+}
+
+/// Writes the sample header and returns the encoded sample data.
+fn sample(out: &mut Vec<u8>, sample: &Sample, report: &mut ModReport) -> Vec<u8> {
+    let mut data = match (&sample.fm_patch, &sample.data) {
+        (None, Some(data)) if sample.is_stereo() => {
+            report.sample_data += 1;
+            convert::stereo_to_mono(data).into_iter().map(convert::f32_to_i8).collect()
+        }
+        (None, Some(data)) => {
+            if !data.iter().all(|&x| exact_8bit(x).is_some()) {
+                report.sample_data += 1;
+            }
+            data.iter().map(|&x| convert::f32_to_i8(x)).collect()
+        }
+        _ => Vec::new(),
+    }
+    .into_iter()
+    .flat_map(i8::to_le_bytes)
+    .collect::<Vec<_>>();
+    if data.len() > MAX_SAMPLE_LENGTH {
+        report.sample_data += 1;
+        data.truncate(MAX_SAMPLE_LENGTH);
+    }
+    // Lengths are stored in words, odd samples are padded with silence.
+    let length = data.len();
+    data.resize(length.div_ceil(2) * 2, 0);
+
+    // Loops of one word are the way to store no loop, the loop points are rounded to words.
+    let (loop_start, loop_length) = match sample.loop_ {
+        Some(l) if l.start < l.end => {
+            let start = usize::try_from(l.start).unwrap_or(usize::MAX) / 2;
+            let end = usize::try_from(l.end).unwrap_or(usize::MAX).min(length) / 2;
+            if end > start + 1 { (start, end - start) } else { (0, 1) }
+        }
+        _ => (0, 1),
+    };
+
+    let finetune = C5Speed::from(sample.samplerate_c5).to_mod_finetune();
+
+    out.extend_from_slice(&sample.name.bytes[..22]);
+    out.extend_from_slice(&u16::try_from(data.len() / 2).unwrap().to_be_bytes());
+    out.push(finetune);
+    out.push(sample.default_volume.min(64));
+    out.extend_from_slice(&u16::try_from(loop_start).unwrap_or(0).to_be_bytes());
+    out.extend_from_slice(&u16::try_from(loop_length).unwrap_or(1).to_be_bytes());
+    data
+}
+
+/// Writes 64 rows of the channels of the pattern, `channels` lists the channels in the order
+/// they are stored.
+fn pattern(out: &mut Vec<u8>, module: &Module, pattern: &Pattern, channels: &[Channel], channel_count: usize, report: &mut ModReport) {
+    report.rows += pattern.rows.len().saturating_sub(ROWS);
+    let rows = pattern.rows.len().min(ROWS);
+    let mut last_notes = [None; 8];
+    for index in 0..ROWS {
+        let mut cells = [[0; 4]; 8];
+        if let Some(row) = pattern.rows.as_slice().get(index) {
+            for (cell, (&channel, last_note)) in cells.iter_mut().zip(channels.iter().zip(&mut last_notes)) {
+                if let Some(command) = row.get(channel) {
+                    *cell = self::cell(module, command, last_note, report);
+                }
+            }
+        }
+
+        // Patterns shorter than 64 rows end with a pattern break on the last row, which goes to
+        // the first cell without an effect.
+        let ends_row = cells.iter().any(|cell| matches!(cell[2] & 0x0F, 0x0B | 0x0D));
+        if index + 1 == rows && rows < ROWS && !ends_row {
+            if let Some(cell) = cells[..channel_count].iter_mut().find(|cell| cell[2] & 0x0F == 0 && cell[3] == 0) {
+                cell[2] |= 0x0D;
+            }
+        }
+
+        for cell in &cells[..channel_count] {
+            out.extend_from_slice(cell);
+        }
+    }
+}
+
+/// Converts the command to the 4 bytes of a cell, `last_note` is the last note played in the
+/// channel.
+fn cell(module: &Module, command: &Command, last_note: &mut Option<Note>, report: &mut ModReport) -> [u8; 4] {
+    let mut cut = false;
+    let period = match command.note {
+        Some(NoteCmd::Play(note)) => {
+            *last_note = Some(note);
+            let mut note = u8::from(note);
+            let last = FIRST_NOTE + 35;
+            if !(FIRST_NOTE..=last).contains(&note) {
+                report.notes += 1;
+                note = if note < FIRST_NOTE { note + (FIRST_NOTE - note).div_ceil(12) * 12 } else { note - (note - last).div_ceil(12) * 12 };
+            }
+            PERIODS[usize::from(note - FIRST_NOTE)]
+        }
+        Some(NoteCmd::Cut) => {
+            cut = true;
+            0
+        }
+        Some(NoteCmd::Off) => {
+            report.notes += 1;
+            cut = true;
+            0
+        }
+        Some(NoteCmd::Fade) | Some(NoteCmd::ParamControl { .. }) => {
+            report.notes += 1;
+            0
+        }
+        None => 0,
+    };
+    let sample = command.instrument.map_or(0, |instrument| sample_number(module, instrument, *last_note));
+
+    let mut effect = command.effect.and_then(|effect| {
+        let converted = self::effect(effect);
+        if converted.is_none() {
+            report.effects += 1;
+        }
+        converted
+    });
+    match command.volume {
+        Some(VolumeCmd::SetVolume(volume)) if effect.is_none() => effect = Some((0x0C, volume.as_u8())),
+        Some(_) => report.volume_commands += 1,
+        None => {}
+    }
+    if cut {
+        if effect.is_none() {
+            effect = Some((0x0E, 0xC0));
+        } else {
+            report.notes += 1;
+        }
+    }
+
+    let (effect, param) = effect.unwrap_or((0, 0));
+    let [high, low] = period.to_be_bytes();
+    [sample & 0xF0 | high, low, sample << 4 | effect, param]
+}
+
+/// Converts the IT effect to the MOD effect, `None` if ProTracker doesn't have it.
+///
+/// This is the inverse of the conversion of the MOD importer, which shares it with XM.
+fn effect(effect: EffectCmd) -> Option<(u8, u8)> {
+    let (code, param) = effect.to_raw();
+    let (x, y) = (param >> 4, param & 0x0F);
+    // MOD slides don't have memory, only slides in one direction are kept.
+    let slide = (param != 0 && (x == 0 || y == 0)).then_some(param);
+    Some(match code {
+        // `Axx`
+        0x01 if (1..0x20).contains(&param) => (0x0F, param),
+        // `Bxx`
+        0x02 => (0x0B, param),
+        // `Cxx` is stored in binary coded decimal, rows past the end of the pattern are dropped.
+        0x03 if usize::from(param) < ROWS => (0x0D, ((param / 10) << 4) | (param % 10)),
+        // `Dxy`, the fine slides are `DxF` and `DFy`
+        0x04 if x != 0 && y == 0xF => (0x0E, 0xA0 | x),
+        0x04 if x == 0xF && y != 0 => (0x0E, 0xB0 | y),
+        0x04 => (0x0A, slide?),
+        // `Exx` and `Fxx`, extra fine slides are dropped
+        0x05 if (1..0xE0).contains(&param) => (0x02, param),
+        0x05 if x == 0xF => (0x0E, 0x20 | y),
+        0x06 if (1..0xE0).contains(&param) => (0x01, param),
+        0x06 if x == 0xF => (0x0E, 0x10 | y),
+        // `Gxx`, `Hxy`
+        0x07 => (0x03, param),
+        0x08 => (0x04, param),
+        // `Jxy`
+        0x0A if param != 0 => (0x00, param),
+        // `Kxy`, `Lxy`
+        0x0B => (0x06, slide?),
+        0x0C => (0x05, slide?),
+        // `Oxx`
+        0x0F => (0x09, param),
+        // `Qxy` without volume change
+        0x11 if y != 0 && matches!(x, 0 | 8) => (0x0E, 0x90 | y),
+        // `Rxy`
+        0x12 => (0x07, param),
+        // `S1x`-`S4x`, `S8x`, `SBx`-`SEx`
+        0x13 => match x {
+            0x1 => (0x0E, 0x30 | y),
+            0x2 => (0x0E, 0x50 | y),
+            0x3 => (0x0E, 0x40 | y),
+            0x4 => (0x0E, 0x70 | y),
+            0x8 => (0x0E, 0x80 | y),
+            0xB => (0x0E, 0x60 | y),
+            0xC ..= 0xE => (0x0E, param),
+            _ => return None,
+        },
+        // `Txx` without the slides
+        0x14 if param >= 0x20 => (0x0F, param),
+        // `Xxx`
+        0x18 => (0x08, param),
+        _ => return None,
+    })
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::formats;
+
+    #[test]
+    fn to_mod() {
+        let mut sample = crate::formats::empty_sample();
+        sample.data = Some(vec![0.0, 1.0, -1.0, 0.0, convert::i8_to_f32(64)].into());
+        sample.loop_ = Some(SampleLoop { start: 1, end: 5, bidi: false });
+        sample.samplerate_c5 = 8280;
+        sample.default_volume = 48;
+
+        let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 32], truncated: false, highlight: None };
+        pattern.set_note(0, Channel::new(3), NoteCmd::Play(Note::try_from(60).unwrap()));
+        pattern.set_instrument(0, Channel::new(3), InstrumentId::try_from(0).unwrap());
+        pattern.set_volume(0, Channel::new(3), VolumeCmd::SetVolume(RangedU8::try_from(32).unwrap()));
+        pattern.set_note(1, Channel::new(10), NoteCmd::Play(Note::try_from(100).unwrap()));
+        pattern.set_effect(1, Channel::new(10), EffectCmd::Special(Some(Special::SetSurround(true))));
+        pattern.set_note(2, Channel::new(3), NoteCmd::Cut);
+        pattern.set_effect(3, Channel::new(10), EffectCmd::SetSpeed(RangedU8::try_from(3).unwrap()));
+
+        let mut builder = ModuleBuilder::new();
+        let id = builder.add_pattern(pattern).unwrap();
+        let mut module = builder.build().unwrap();
+        module.samples.push(sample);
+        module.orders = vec![Order::Index(id), Order::Separator, Order::Index(id), Order::EndOfSong, Order::Index(id)];
+
+        let (file, report) = module.to_mod().unwrap();
+        assert_eq!(report, ModReport { notes: 1, effects: 1, linear_slides: true, ..ModReport::default() });
+
+        let parsed = formats::protracker::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(parsed.name.bytes[..20], module.name.bytes[..20]);
+        assert_eq!(parsed.orders, [Order::Index(id), Order::Index(id)]);
+        let sample = &parsed.samples[0];
+        assert_eq!(sample.data.as_deref(), Some(&[0.0, 1.0, -1.0, 0.0, convert::i8_to_f32(64), 0.0][..]));
+        assert_eq!((sample.samplerate_c5, sample.default_volume), (8280, 48));
+        assert!(matches!(sample.loop_, Some(SampleLoop { start: 0, end: 4, bidi: false })));
+
+        // Channels 3 and 10 are stored as the first two ones.
+        let pattern = &parsed.patterns[0];
+        let command = pattern.command(0, Channel::new(1)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 60));
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(0).unwrap()));
+        assert!(matches!(command.volume, Some(VolumeCmd::SetVolume(volume)) if volume.as_u8() == 32));
+        let command = pattern.command(1, Channel::new(2)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 76));
+        assert!(command.effect.is_none());
+        let command = pattern.command(2, Channel::new(1)).unwrap();
+        assert_eq!(command.effect, Some(EffectCmd::Special(Some(Special::NoteCut(RangedU8::MIN)))));
+        assert_eq!(pattern.command(3, Channel::new(2)).unwrap().effect, Some(EffectCmd::SetSpeed(RangedU8::try_from(3).unwrap())));
+        assert_eq!(pattern.command(31, Channel::new(1)).unwrap().effect, Some(EffectCmd::BreakRow(0)));
+        assert!(pattern.rows[32..].iter().all(Row::is_empty));
+    }
+}
@@ -0,0 +1,510 @@
+//! Down-conversion to Scream Tracker 3 module files (.s3m)
+
+use super::{count, exact_8bit, invalid, sample_number, u16, u32};
+use crate::data::*;
+use crate::parser::util::Cast;
+use std::convert::TryFrom;
+use std::io;
+
+
+/// Number of rows of every pattern
+const ROWS: usize = 64;
+
+/// Number of channels an S3M file can store
+const CHANNELS: usize = 32;
+
+/// Offset of the channel settings in the module header
+const CHANNEL_SETTINGS_FIELD: usize = 0x40;
+
+/// Offset of the sample data pointer (memseg) in the sample header
+const MEMSEG_FIELD: usize = 0x0D;
+
+/// Default value of the header panning field requesting the stored channel panning
+const STORED_PANNING: u8 = 252;
+
+
+/// Parts of the module lost by [`Module::to_s3m`]
+///
+/// The counts are of the entries which were dropped or played differently in the written file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct S3mReport {
+    /// Instruments flattened to their samples, envelopes and all other instrument settings are
+    /// lost
+    pub instruments: usize,
+    /// Samples with settings S3M doesn't have: global volume, default panning, auto-vibrato,
+    /// sustain loops or ping-pong loops
+    pub samples: usize,
+    /// Channels past the 32nd with any commands
+    pub channels: usize,
+    /// Rows of patterns longer than 64 rows
+    pub rows: usize,
+    /// Notes outside of C-1..=B-8, note fades and note offs, note offs are written as note cuts
+    pub notes: usize,
+    /// Volume column commands other than set volume
+    pub volume_commands: usize,
+    /// Effects without an S3M equivalent
+    pub effects: usize,
+    /// The module uses linear slides, S3M only has Amiga slides
+    pub linear_slides: bool,
+}
+
+
+/// Converted cell, the parts which are `None` are not stored
+struct Cell {
+    note: Option<(u8, u8)>,
+    volume: Option<u8>,
+    effect: Option<(u8, u8)>,
+}
+
+
+impl Module {
+    /// Converts the module into a Scream Tracker 3 module file (.s3m)
+    ///
+    /// Returns the file and a report of what was lost, IT features without an S3M equivalent are
+    /// dropped or down-converted:
+    ///
+    /// - in instrument mode every note plays the sample the instrument maps it to, the instrument
+    ///   settings are lost,
+    /// - only the first 32 channels and 64 rows of each pattern are kept, shorter patterns are
+    ///   padded with empty rows and end with a pattern break (`C00`) if there is room for one,
+    /// - the volume column only keeps set volume, note offs are written as note cuts,
+    /// - effects are converted the inverse way of the [S3M importer](crate::formats::s3m),
+    ///   effects ST3 doesn't have are dropped: `M`, `N`, `P`, `W`, `Y`, `Z`, `Txx` slides and the
+    ///   `S` commands other than `S1x`-`S4x`, `S8x`, `S91` (written as `XA4`) and `SBx`-`SEx`,
+    /// - ping-pong loops become forward loops, sustain loops are dropped.
+    ///
+    /// Sample data is stored as unsigned 8-bit PCM if that is lossless and as 16-bit PCM
+    /// otherwise, stereo samples with the left channel followed by the right one. Channel panning is stored with the 16 positions of ST3, surround is centred.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the module can not be represented in the
+    /// format, that is if there are more than 256 orders, 99 samples or 200 patterns, if the file
+    /// would be too large for the 16-bit offsets of S3M or if a pattern has been
+    /// [truncated](Pattern::truncated) during parsing or the data of a sample was [not
+    /// loaded](Sample::is_loaded).
+    pub fn to_s3m(&self) -> io::Result<(Vec<u8>, S3mReport)> {
+        module_bytes(self)
+    }
+}
+
+
+fn module_bytes(module: &Module) -> io::Result<(Vec<u8>, S3mReport)> {
+    let mut orders = module.orders
+        .iter()
+        .map(|order| match order {
+            Order::Index(pattern) => pattern.as_u8(),
+            Order::Separator => 254,
+            Order::EndOfSong => 255,
+        })
+        .collect::<Vec<_>>();
+    // ST3 expects an even number of orders.
+    if orders.len() % 2 == 1 {
+        orders.push(255);
+    }
+    let ordnum = count(orders.len(), 256, "too many orders, at most 256 are allowed")?;
+    let insnum = count(module.samples.len(), 99, "too many samples, at most 99 are allowed")?;
+    let patnum = count(module.patterns.len(), 200, "too many patterns, at most 200 are allowed")?;
+    if !module.samples.iter().all(Sample::is_loaded) {
+        return Err(invalid("sample data was not loaded"));
+    }
+    if module.patterns.iter().any(|pattern| pattern.truncated) {
+        return Err(invalid("pattern was truncated during parsing and cannot be written"));
+    }
+
+    let mut report = S3mReport {
+        instruments: if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) { module.instruments.len() } else { 0 },
+        samples: module.samples.iter().filter(|sample| loses_settings(sample)).count(),
+        linear_slides: module.flags.contains(ModuleFlags::LINEAR_SLIDES),
+        ..S3mReport::default()
+    };
+    let mut dropped_channels = ActiveChannels::empty();
+    for (channel, _) in module.patterns.iter().flat_map(|pattern| &pattern.rows).flat_map(Row::iter) {
+        if channel.as_usize() >= CHANNELS {
+            dropped_channels |= ActiveChannels::new([channel]);
+        }
+    }
+    report.channels = dropped_channels.iter().count();
+
+    let stereo = module.flags.contains(ModuleFlags::STEREO);
+    let flags = if module.flags.contains(ModuleFlags::VOL_0_MIX_OPTIMIZATIONS) { 0x08 } else { 0 };
+
+    let mut out = Vec::new();
+    out.extend_from_slice(&module.name.bytes);
+    out.resize(28, 0);
+    out.push(0x1A);
+    out.push(0x10); // module
+    u16(&mut out, 0); // reserved
+    for value in &[ordnum, insnum, patnum, flags] {
+        u16(&mut out, *value);
+    }
+    u16(&mut out, 0x1320); // ST3.20
+    u16(&mut out, 2); // unsigned samples
+    out.extend_from_slice(b"SCRM");
+    out.push(module.global_volume.as_u8() / 2);
+    out.push(module.speed.as_u8());
+    out.push(module.tempo.as_u8());
+    out.push(module.sample_volume.as_u8().min(0x7F) | if stereo { 0x80 } else { 0 });
+    out.push(0); // ultra click removal
+    out.push(STORED_PANNING);
+    out.extend_from_slice(&[0; 8]); // reserved
+    u16(&mut out, 0); // special
+    out.extend_from_slice(&[255; CHANNELS]); // channel settings, patched below
+    out.extend_from_slice(&orders);
+    let sample_pointers = out.len();
+    out.resize(sample_pointers + 2 * module.samples.len(), 0);
+    let pattern_pointers = out.len();
+    out.resize(pattern_pointers + 2 * module.patterns.len(), 0);
+    for &pan in &module.init_channel_panning[..CHANNELS] {
+        out.push(0x20 | nibble_panning(pan.pan()));
+    }
+
+    let mut sample_data = Vec::with_capacity(module.samples.len());
+    for (index, sample) in module.samples.iter().enumerate() {
+        patch_parapointer(&mut out, sample_pointers + 2 * index)?;
+        let header = out.len();
+        let data = sample_header(&mut out, sample);
+        sample_data.push((header + MEMSEG_FIELD, data));
+    }
+
+    let mut used_channels = [false; CHANNELS];
+    for (index, pattern) in module.patterns.iter().enumerate() {
+        patch_parapointer(&mut out, pattern_pointers + 2 * index)?;
+        self::pattern(&mut out, module, pattern, &mut used_channels, &mut report);
+    }
+
+    for (field, data) in sample_data {
+        if !data.is_empty() {
+            patch_memseg(&mut out, field)?;
+            out.extend_from_slice(&data);
+        }
+    }
+
+    // Channels 0-7 of the settings are on the left and 8-15 on the right, bit 7 mutes the channel.
+    let (mut left, mut right) = (0, 0);
+    for (channel, &used) in used_channels.iter().enumerate() {
+        let pan = module.init_channel_panning[channel];
+        if !used {
+            continue;
+        }
+        let right_side = matches!(pan.pan(), Pan::Position(position) if position.as_u8() > 32);
+        let (side, count) = if stereo && right_side { (8, &mut right) } else { (0, &mut left) };
+        let muted = if pan.is_enabled() { 0 } else { 0x80 };
+        out[CHANNEL_SETTINGS_FIELD + channel] = (side + *count % 8) | muted;
+        *count += 1;
+    }
+
+    Ok((out, report))
+}
+
+/// Returns `true` if the sample has settings which can't be stored, see [`S3mReport::samples`].
+fn loses_settings(sample: &Sample) -> bool {
+    sample.global_volume != 64
+        || sample.default_panning & Sample::dfp_usePanning != 0
+        || sample.vibrato_depth != 0
+        || sample.sustain_loop.is_some()
+        || sample.loop_.is_some_and(|l| l.bidi)
+}
+
+/// Converts panning in range 0..=64 to range 0..=15, surround is centred.
+fn nibble_panning(pan: Pan) -> u8 {
+    let pan = match pan {
+        Pan::Position(position) => position.as_u8(),
+        Pan::Surround => 32,
+    };
+    ((u16::from(pan) * 15 + 32) / 64).cast()
+}
+
+/// Writes the sample header and returns the encoded sample data.
+///
+/// The memseg is left zeroed, the data has to be written separately and the memseg patched.
+fn sample_header(out: &mut Vec<u8>, sample: &Sample) -> Vec<u8> {
+    let (kind, flags, data) = match (&sample.fm_patch, &sample.data) {
+        (Some(_), _) => (2, 0, Vec::new()),
+        (None, Some(data)) if sample.is_stereo() => {
+            let planar = (0..2).flat_map(|channel| data.iter().skip(channel).step_by(2).copied()).collect::<Vec<_>>();
+            let (sixteen_bit, bytes) = pcm_data(&planar);
+            (1, if sixteen_bit { 0x06 } else { 0x02 }, bytes)
+        }
+        (None, Some(data)) => {
+            let (sixteen_bit, bytes) = pcm_data(data);
+            (1, if sixteen_bit { 0x04 } else { 0 }, bytes)
+        }
+        (None, None) => (0, 0, Vec::new()),
+    };
+
+    out.push(kind);
+    out.extend_from_slice(&sample.filename.bytes[..12]);
+    out.extend_from_slice(&[0; 3]); // memseg, patched when the data is written
+    let mut flags = flags;
+    if let Some(patch) = &sample.fm_patch {
+        out.extend_from_slice(patch);
+    } else {
+        let length = sample.length();
+        let loop_ = match sample.loop_ {
+            Some(l) if l.start < l.end && l.end <= length => {
+                flags |= 0x01;
+                (l.start, l.end)
+            }
+            _ => (0, 0),
+        };
+        u32(out, length);
+        u32(out, loop_.0);
+        u32(out, loop_.1);
+    }
+    out.push(sample.default_volume);
+    out.push(0); // reserved
+    out.push(0); // not packed
+    out.push(flags);
+    u32(out, sample.samplerate_c5);
+    out.extend_from_slice(&[0; 12]); // internal
+    out.extend_from_slice(&sample.name.bytes);
+    out.extend_from_slice(&[0; 2]);
+    out.extend_from_slice(if kind == 2 { b"SCRI" } else { b"SCRS" });
+
+    data
+}
+
+/// Encodes the sample data as unsigned PCM, returns whether it's 16-bit and the bytes.
+///
+/// The data is stored as 8-bit PCM if that is lossless and as 16-bit PCM otherwise.
+fn pcm_data(data: &[f32]) -> (bool, Vec<u8>) {
+    // Flipping the sign bit converts two's complement to the unsigned encoding.
+    match data.iter().map(|&x| exact_8bit(x)).collect::<Option<Vec<_>>>() {
+        Some(samples) => (false, samples.into_iter().flat_map(|s| (s ^ i8::MIN).to_le_bytes()).collect()),
+        None => (true, data.iter().flat_map(|&x| (convert::f32_to_i16(x) ^ i16::MIN).to_le_bytes()).collect()),
+    }
+}
+
+/// Writes the pattern packed into 64 rows of the first 32 channels.
+fn pattern(out: &mut Vec<u8>, module: &Module, pattern: &Pattern, used_channels: &mut [bool; CHANNELS], report: &mut S3mReport) {
+    let start = out.len();
+    u16(out, 0); // packed length, patched below
+
+    report.rows += pattern.rows.len().saturating_sub(ROWS);
+    let rows = pattern.rows.len().min(ROWS);
+    let mut last_notes = [None; CHANNELS];
+    for (index, row) in pattern.rows.iter().take(ROWS).enumerate() {
+        let mut cells = row.iter()
+            .filter(|(channel, _)| channel.as_usize() < CHANNELS)
+            .map(|(channel, command)| {
+                let channel = channel.as_usize();
+                (channel, cell(module, command, &mut last_notes[channel], report))
+            })
+            .collect::<Vec<_>>();
+
+        // Patterns shorter than 64 rows end with a pattern break on the last row, which goes to
+        // the first cell without an effect.
+        let ends_row = cells.iter().any(|(_, cell)| matches!(cell.effect, Some((0x02 | 0x03, _))));
+        if index + 1 == rows && rows < ROWS && !ends_row {
+            let free_channel = (0..CHANNELS).find(|channel| cells.iter().all(|(used, _)| used != channel));
+            if let Some((_, cell)) = cells.iter_mut().find(|(_, cell)| cell.effect.is_none()) {
+                cell.effect = Some((0x03, 0));
+            } else if let Some(channel) = free_channel {
+                cells.push((channel, Cell { note: None, volume: None, effect: Some((0x03, 0)) }));
+            }
+        }
+
+        for (channel, cell) in cells {
+            let mut what = channel.cast();
+            what |= if cell.note.is_some() { 0x20 } else { 0 };
+            what |= if cell.volume.is_some() { 0x40 } else { 0 };
+            what |= if cell.effect.is_some() { 0x80 } else { 0 };
+            if what & 0xE0 == 0 {
+                continue;
+            }
+            used_channels[channel] = true;
+            out.push(what);
+            if let Some((note, sample)) = cell.note {
+                out.extend_from_slice(&[note, sample]);
+            }
+            out.extend(cell.volume);
+            if let Some((effect, param)) = cell.effect {
+                out.extend_from_slice(&[effect, param]);
+            }
+        }
+        out.push(0);
+    }
+    out.resize(out.len() + ROWS - rows, 0);
+
+    // The length includes the length field itself.
+    let length = u16::try_from(out.len() - start).expect("packed pattern is too long");
+    out[start..start + 2].copy_from_slice(&length.to_le_bytes());
+}
+
+/// Converts the command, `last_note` is the last note played in the channel.
+fn cell(module: &Module, command: &Command, last_note: &mut Option<Note>, report: &mut S3mReport) -> Cell {
+    // The high nibble of an S3M note is the octave and the low nibble the semitone, IT notes are
+    // one octave higher.
+    let note = match command.note {
+        None => None,
+        Some(NoteCmd::Play(note)) => {
+            *last_note = Some(note);
+            match u8::from(note) {
+                note @ 12 ..= 107 => Some(((note / 12 - 1) << 4) | (note % 12)),
+                _ => {
+                    report.notes += 1;
+                    None
+                }
+            }
+        }
+        Some(NoteCmd::Cut) => Some(254),
+        Some(NoteCmd::Off) => {
+            report.notes += 1;
+            Some(254)
+        }
+        Some(NoteCmd::Fade) | Some(NoteCmd::ParamControl { .. }) => {
+            report.notes += 1;
+            None
+        }
+    };
+    let sample = command.instrument.map_or(0, |instrument| sample_number(module, instrument, *last_note));
+    let note = (note.is_some() || sample != 0).then(|| (note.unwrap_or(255), sample));
+
+    let volume = command.volume.and_then(|volume| match volume {
+        VolumeCmd::SetVolume(volume) => Some(volume.as_u8()),
+        _ => {
+            report.volume_commands += 1;
+            None
+        }
+    });
+    let effect = command.effect.and_then(|effect| {
+        let converted = self::effect(effect);
+        if converted.is_none() {
+            report.effects += 1;
+        }
+        converted
+    });
+
+    Cell { note, volume, effect }
+}
+
+/// Converts the IT effect to the S3M effect, `None` if ST3 doesn't have it.
+///
+/// This is the inverse of the conversion of the S3M importer.
+fn effect(effect: EffectCmd) -> Option<(u8, u8)> {
+    let (code, param) = effect.to_raw();
+    let x = param >> 4;
+    let param = match code {
+        // `Cxx` is stored in binary coded decimal, rows past the end of the pattern are dropped.
+        0x03 if usize::from(param) < ROWS => ((param / 10) << 4) | (param % 10),
+        0x03 => return None,
+        // `Mxx`, `Nxx`, `Pxx`, `Wxx`, `Yxy`, `Zxx`
+        0x0D | 0x0E | 0x10 | 0x17 | 0x19 | 0x1A => return None,
+        // `S91` is `XA4`
+        0x13 if param == 0x91 => return Some((0x18, 0xA4)),
+        // `S5x`, `S6x`, `S7x`, `S9x`, `SAx`, `SFx`
+        0x13 if matches!(x, 0x5 | 0x6 | 0x7 | 0x9 | 0xA | 0xF) => return None,
+        // `Txx` slides
+        0x14 if param < 0x20 => return None,
+        // `Vxx`
+        0x16 => param / 2,
+        // `Xxx`
+        0x18 => param / 2 + param % 2,
+        _ => param,
+    };
+    Some((code, param))
+}
+
+/// Aligns the output to 16 bytes and sets the parapointer at `field` to the end of the output.
+fn patch_parapointer(out: &mut Vec<u8>, field: usize) -> io::Result<()> {
+    let paragraph = align(out);
+    let paragraph = u16::try_from(paragraph)
+        .map_err(|_| invalid("module is too large, parapointers must fit into 16 bits"))?;
+    out[field..field + 2].copy_from_slice(&paragraph.to_le_bytes());
+    Ok(())
+}
+
+/// Aligns the output to 16 bytes and sets the memseg at `field` to the end of the output.
+///
+/// The memseg is a 24-bit parapointer, the high byte is stored first.
+fn patch_memseg(out: &mut Vec<u8>, field: usize) -> io::Result<()> {
+    let paragraph = align(out);
+    let [low, middle, high, rest] = u32::try_from(paragraph).unwrap_or(u32::MAX).to_le_bytes();
+    if rest != 0 {
+        return Err(invalid("module is too large, sample pointers must fit into 24 bits"));
+    }
+    out[field..field + 3].copy_from_slice(&[high, low, middle]);
+    Ok(())
+}
+
+/// Pads the output to a multiple of 16 bytes and returns its length in paragraphs.
+fn align(out: &mut Vec<u8>) -> usize {
+    let paragraphs = out.len().div_ceil(16);
+    out.resize(paragraphs * 16, 0);
+    paragraphs
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::{formats, parser};
+
+    #[test]
+    fn to_s3m() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let mut module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        module.flags = ModuleFlags::STEREO | ModuleFlags::OLD_EFFECTS;
+        module.samples = vec![Sample {
+            data: Some(vec![0.0, 1.0, -1.0, 0.0].into()),
+            loop_: Some(SampleLoop { start: 1, end: 4, bidi: true }),
+            sustain_loop: None,
+            global_volume: 64,
+            default_panning: 32,
+            vibrato_depth: 0,
+            deferred: None,
+            encoded: None,
+            fm_patch: None,
+            conversion: SampleConversion::empty(),
+            ..module.samples[0].clone()
+        }];
+        module.patterns = vec![Pattern {
+            active_channels: ActiveChannels::empty(),
+            rows: vec![Row::empty(); 32],
+            truncated: false,
+            highlight: None,
+        }];
+        module.orders = vec![Order::Index(PatternId::try_from(0).unwrap())];
+        let pattern = &mut module.patterns[0];
+        pattern.set_note(0, Channel::new(1), NoteCmd::Play(Note::try_from(60).unwrap()));
+        pattern.set_instrument(0, Channel::new(1), InstrumentId::try_from(0).unwrap());
+        pattern.set_volume(0, Channel::new(1), VolumeCmd::SetVolume(RangedU8::try_from(32).unwrap()));
+        pattern.set_effect(0, Channel::new(1), EffectCmd::BreakRow(10));
+        pattern.set_note(1, Channel::new(2), NoteCmd::Off);
+        pattern.set_volume(1, Channel::new(2), VolumeCmd::Panning(RangedU8::try_from(0).unwrap()));
+        pattern.set_effect(2, Channel::new(2), EffectCmd::Special(Some(Special::SetSurround(true))));
+        pattern.set_effect(3, Channel::new(40), EffectCmd::SetSpeed(RangedU8::try_from(3).unwrap()));
+
+        let (file, report) = module.to_s3m().unwrap();
+        assert_eq!(report, S3mReport {
+            samples: 1,
+            channels: 1,
+            notes: 1,
+            volume_commands: 1,
+            ..S3mReport::default()
+        });
+
+        let parsed = formats::s3m::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(parsed.name.bytes, module.name.bytes);
+        // The orders are padded to an even number.
+        assert_eq!(parsed.orders, [module.orders[0], Order::EndOfSong]);
+        assert_eq!(parsed.samples[0].data, module.samples[0].data);
+        assert!(matches!(parsed.samples[0].loop_, Some(SampleLoop { start: 1, end: 4, bidi: false })));
+
+        let pattern = &parsed.patterns[0];
+        assert_eq!(pattern.rows.len(), 64);
+        let command = pattern.command(0, Channel::new(1)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 60));
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(0).unwrap()));
+        assert!(matches!(command.volume, Some(VolumeCmd::SetVolume(volume)) if volume.as_u8() == 32));
+        assert!(matches!(command.effect, Some(EffectCmd::BreakRow(10))));
+        assert!(matches!(pattern.command(1, Channel::new(2)).unwrap().note, Some(NoteCmd::Cut)));
+        let command = pattern.command(2, Channel::new(2)).unwrap();
+        assert!(matches!(command.effect, Some(EffectCmd::Special(Some(Special::SetSurround(true))))));
+        assert!(matches!(pattern.command(31, Channel::new(1)).unwrap().effect, Some(EffectCmd::BreakRow(0))));
+        assert!(pattern.rows[3].is_empty());
+        assert!(pattern.rows[32..].iter().all(Row::is_empty));
+    }
+}
@@ -0,0 +1,147 @@
+//! Export of samples to WAV files (.wav)
+
+use super::{exact_8bit, invalid, u16, u32};
+use crate::data::*;
+use std::convert::TryFrom;
+use std::io::{self, Write};
+
+
+impl Sample {
+    /// Writes the sample as a WAV file (.wav)
+    ///
+    /// The file is mono or stereo PCM like the sample at the C-5 speed of the sample, 8-bit if that
+    /// is lossless and 16-bit otherwise. The loop and the sustain loop are stored in a `smpl` chunk in this order, with
+    /// MIDI note 60 (C-5) as the unity note. Samples without data give a file without any sample
+    /// frames.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the data of the sample was [not
+    /// loaded](Sample::is_loaded), the C-5 speed is zero or the data doesn't fit in a WAV file.
+    /// Errors of the writer are passed through.
+    pub fn write_wav(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(&wav_bytes(self)?)
+    }
+}
+
+
+fn wav_bytes(sample: &Sample) -> io::Result<Vec<u8>> {
+    if !sample.is_loaded() {
+        return Err(invalid("sample data was not loaded"));
+    }
+    if sample.samplerate_c5 == 0 {
+        return Err(invalid("sample rate must not be zero"));
+    }
+    let pcm = sample.data.as_deref().unwrap_or(&[]);
+    let channels = u32::from(sample.channels.as_u8());
+    let length = sample.length();
+
+    // 8-bit data is unsigned.
+    let exact = pcm.iter().map(|&x| exact_8bit(x)).collect::<Option<Vec<i8>>>();
+    let (bits, data) = match exact {
+        Some(exact) => (8, exact.into_iter().map(convert::i8_to_u8).collect::<Vec<_>>()),
+        None => (16, pcm.iter().flat_map(|&x| convert::f32_to_i16(x).to_le_bytes()).collect()),
+    };
+    let data_size = u32::try_from(data.len()).map_err(|_| invalid("sample is too long for a WAV file"))?;
+
+    let loops = [sample.loop_, sample.sustain_loop]
+        .iter()
+        .flatten()
+        .filter(|l| l.start < l.end && l.end <= length)
+        .copied()
+        .collect::<Vec<_>>();
+
+    let mut out = Vec::with_capacity(data.len() + 44);
+    out.extend_from_slice(b"RIFF");
+    u32(&mut out, 0); // size, patched below
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    u32(&mut out, 16);
+    u16(&mut out, 1); // PCM
+    u16(&mut out, u16::try_from(channels).unwrap());
+    u32(&mut out, sample.samplerate_c5);
+    let frame_size = channels * bits / 8;
+    u32(&mut out, sample.samplerate_c5.checked_mul(frame_size).ok_or_else(|| invalid("sample rate is too high"))?);
+    u16(&mut out, u16::try_from(frame_size).unwrap());
+    u16(&mut out, u16::try_from(bits).unwrap());
+
+    out.extend_from_slice(b"data");
+    u32(&mut out, data_size);
+    out.extend_from_slice(&data);
+    if data.len() % 2 == 1 {
+        out.push(0);
+    }
+
+    if !loops.is_empty() {
+        out.extend_from_slice(b"smpl");
+        u32(&mut out, 36 + 24 * u32::try_from(loops.len()).unwrap());
+        u32(&mut out, 0); // manufacturer
+        u32(&mut out, 0); // product
+        u32(&mut out, 1_000_000_000 / sample.samplerate_c5); // sample period in nanoseconds
+        u32(&mut out, 60); // unity note
+        u32(&mut out, 0); // pitch fraction
+        u32(&mut out, 0); // SMPTE format
+        u32(&mut out, 0); // SMPTE offset
+        u32(&mut out, u32::try_from(loops.len()).unwrap());
+        u32(&mut out, 0); // sampler data
+        for (index, l) in loops.iter().enumerate() {
+            // The end stored in the chunk is the last frame of the loop.
+            u32(&mut out, u32::try_from(index).unwrap());
+            u32(&mut out, if l.bidi { 1 } else { 0 });
+            u32(&mut out, l.start);
+            u32(&mut out, l.end - 1);
+            u32(&mut out, 0); // fraction
+            u32(&mut out, 0); // play count, 0 is forever
+        }
+    }
+
+    let riff_size = u32::try_from(out.len() - 8).map_err(|_| invalid("sample is too long for a WAV file"))?;
+    out[4..8].copy_from_slice(&riff_size.to_le_bytes());
+    Ok(out)
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::parser;
+
+    #[test]
+    fn write_wav() {
+        const DATA: &[u8] = include_bytes!("../../tests/effect_alphabet.it");
+        let module = parser::module_file::<VerboseError<&[u8]>>(DATA).unwrap();
+        let sample = Sample {
+            data: Some(vec![0.0, 0.5, -0.25, 1.0].into()),
+            loop_: Some(SampleLoop { start: 1, end: 3, bidi: false }),
+            sustain_loop: None,
+            samplerate_c5: 22_050,
+            deferred: None,
+            fm_patch: None,
+            conversion: SampleConversion::empty(),
+            ..module.samples[0].clone()
+        };
+
+        let mut file = Vec::new();
+        sample.write_wav(&mut file).unwrap();
+        assert_eq!(&file[..4], b"RIFF");
+        assert_eq!(usize::try_from(u32::from_le_bytes([file[4], file[5], file[6], file[7]])).unwrap(), file.len() - 8);
+
+        let read = Sample::from_wav(file.as_slice()).unwrap();
+        assert_eq!(read.samplerate_c5, 22_050);
+        assert!(matches!(read.loop_, Some(SampleLoop { start: 1, end: 3, bidi: false })));
+        let data = read.data.unwrap();
+        for (read, written) in data.iter().zip(sample.data.as_deref().unwrap()) {
+            assert!((read - written).abs() < 1e-4);
+        }
+
+        let stereo = Sample { channels: RangedU8::MAX, ..sample };
+        let mut file = Vec::new();
+        stereo.write_wav(&mut file).unwrap();
+        let read = Sample::from_wav(file.as_slice()).unwrap();
+        assert!(read.is_stereo());
+        assert_eq!(read.length(), 2);
+        assert!(read.loop_.is_none());
+    }
+}
@@ -0,0 +1,689 @@
+//! Down-conversion to FastTracker 2 extended module files (.xm)
+
+use super::{count, exact_8bit, invalid, u16, u32};
+use crate::data::*;
+use crate::parser::util::Cast;
+use std::convert::TryFrom;
+use std::io;
+
+
+/// Number of channels FastTracker 2 plays
+const CHANNELS: usize = 32;
+
+/// Number of rows a pattern can have
+const MAX_ROWS: usize = 256;
+
+/// Number of samples an instrument can have
+const INSTRUMENT_SAMPLES: usize = 16;
+
+/// Number of points of an envelope
+const ENVELOPE_POINTS: usize = 12;
+
+/// IT notes of the XM keyboard, C-0 to B-7 in XM
+const FIRST_NOTE: u8 = 12;
+const LAST_NOTE: u8 = 107;
+
+/// Size of the instrument header with and without the sample settings
+const INSTRUMENT_HEADER_SIZE: u32 = 263;
+const EMPTY_INSTRUMENT_HEADER_SIZE: u32 = 29;
+
+/// Size of a sample header
+const SAMPLE_HEADER_SIZE: u32 = 40;
+
+
+/// Parts of the module lost by [`Module::to_xm`]
+///
+/// The counts are of the entries which were dropped or played differently in the written file.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct XmReport {
+    /// Instruments with settings XM doesn't have: new note actions, duplicate checks, pitch-pan
+    /// separation, global volume, default panning, random variations, filters, MIDI, the
+    /// pitch/filter envelope, more than 16 samples or samples with different auto-vibratos
+    pub instruments: usize,
+    /// Envelopes reduced to 12 points or with a sustain loop shortened to a sustain point
+    pub envelopes: usize,
+    /// Samples with settings XM doesn't have: global volume, sustain loops, the random
+    /// auto-vibrato waveform or FM patches
+    pub samples: usize,
+    /// Channels past the 32nd with any commands
+    pub channels: usize,
+    /// Rows of patterns longer than 256 rows
+    pub rows: usize,
+    /// Notes outside of C-1..=B-8, note fades and note cuts which didn't fit into the effect
+    /// column
+    pub notes: usize,
+    /// Volume column commands without an XM equivalent which didn't fit into the effect column
+    pub volume_commands: usize,
+    /// Effects without an XM equivalent
+    pub effects: usize,
+    /// The global volume, mixing volume or the initial channel panning or volume are not the
+    /// defaults, XM files don't store them
+    pub global_settings: bool,
+}
+
+
+/// XM instrument with the samples it plays
+struct XmInstrument<'m> {
+    instrument: Option<&'m Instrument>,
+    samples: Vec<&'m Sample>,
+    keyboard: [u8; 96],
+}
+
+
+impl Module {
+    /// Converts the module into a FastTracker 2 extended module file (.xm)
+    ///
+    /// Returns the file and a report of what was lost, IT features without an XM equivalent are
+    /// dropped or down-converted:
+    ///
+    /// - every instrument keeps the first 16 samples it maps notes to, samples shared by several
+    ///   instruments are stored with each of them, in sample mode every sample becomes an
+    ///   instrument playing it on all notes,
+    /// - envelopes with more than 12 nodes drop the nodes which change the shape the least, the
+    ///   nodes of the loops are kept, sustain loops keep only their first node, the pitch/filter
+    ///   envelope is dropped,
+    /// - the auto-vibrato of the first sample is used for the whole instrument,
+    /// - only the first 32 channels and 256 rows of each pattern are kept,
+    /// - notes outside of C-1..=B-8 and note fades are dropped, note cuts are written as `EC0`,
+    /// - the volume column keeps everything but portamento up and down, which is moved to the
+    ///   effect column if it is free, panning is rounded to the 16 positions of XM,
+    /// - effects are converted the inverse way of the [XM importer](crate::formats::xm),
+    ///   effects FT2 doesn't have are dropped: `M`, `N`, `Y`, `Z`, `Txx` slides, slides in both
+    ///   directions and the `S` commands other than `S1x`-`S4x`, `S8x` and `SBx`-`SEx`,
+    /// - separators in the order list are skipped and the list ends at the first end of song.
+    ///
+    /// Sample data is stored as 8-bit PCM if that is lossless and as 16-bit PCM otherwise,
+    /// stereo samples with the left channel followed by the right one like OpenMPT does. The C-5
+    /// speed is converted to the relative note and finetune.
+    ///
+    /// # Errors
+    ///
+    /// Fails with [`io::ErrorKind::InvalidInput`] if the module can not be represented in the
+    /// format, that is if there are more than 256 orders, 128 instruments or 256 patterns or if
+    /// a pattern has been [truncated](Pattern::truncated) during parsing or the data of a sample
+    /// was [not loaded](Sample::is_loaded).
+    pub fn to_xm(&self) -> io::Result<(Vec<u8>, XmReport)> {
+        module_bytes(self)
+    }
+}
+
+
+fn module_bytes(module: &Module) -> io::Result<(Vec<u8>, XmReport)> {
+    let orders = module.orders
+        .iter()
+        .take_while(|&&order| order != Order::EndOfSong)
+        .filter_map(|order| match order {
+            Order::Index(pattern) => Some(pattern.as_u8()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let song_length = count(orders.len(), 256, "too many orders, at most 256 are allowed")?;
+    let pattern_count = count(module.patterns.len(), 256, "too many patterns, at most 256 are allowed")?;
+    if !module.samples.iter().all(Sample::is_loaded) {
+        return Err(invalid("sample data was not loaded"));
+    }
+    if module.patterns.iter().any(|pattern| pattern.truncated) {
+        return Err(invalid("pattern was truncated during parsing and cannot be written"));
+    }
+
+    let use_instruments = module.flags.contains(ModuleFlags::USE_INSTRUMENTS);
+    let instruments = if use_instruments {
+        module.instruments.iter().map(|instrument| xm_instrument(module, instrument)).collect::<Vec<_>>()
+    } else {
+        module.samples
+            .iter()
+            .map(|sample| XmInstrument { instrument: None, samples: vec![sample], keyboard: [0; 96] })
+            .collect()
+    };
+    let instrument_count = count(instruments.len(), 128, "too many instruments, at most 128 are allowed")?;
+
+    let mut report = XmReport {
+        samples: module.samples.iter().filter(|sample| loses_settings(sample)).count(),
+        ..XmReport::default()
+    };
+    let mut used_channels = ActiveChannels::empty();
+    for (channel, _) in module.patterns.iter().flat_map(|pattern| &pattern.rows).flat_map(Row::iter) {
+        used_channels |= ActiveChannels::new([channel]);
+    }
+    report.channels = used_channels.iter().filter(|channel| channel.as_usize() >= CHANNELS).count();
+    let channels = used_channels.iter()
+        .map(Channel::as_usize)
+        .filter(|&channel| channel < CHANNELS)
+        .max()
+        .map_or(2, |last| (last + 2) / 2 * 2);
+    report.global_settings = module.global_volume.as_u8() != 128
+        || module.sample_volume.as_u8() != 48
+        || used_channels.iter().any(|channel| {
+            let channel = channel.as_usize();
+            module.init_channel_panning[channel] != ChannelPan::CENTRE
+                || module.init_channel_volume[channel] != ChannelVolume::FULL
+        });
+
+    let mut out = Vec::new();
+    out.extend_from_slice(b"Extended Module: ");
+    out.extend_from_slice(&module.name.bytes[..20]);
+    out.push(0x1A);
+    out.extend_from_slice(b"ittech              ");
+    u16(&mut out, 0x0104);
+    // The header size is counted from its own position.
+    u32(&mut out, 276);
+    for value in [
+        song_length,
+        0, // restart position
+        u16::try_from(channels).unwrap(),
+        pattern_count,
+        instrument_count,
+        if module.flags.contains(ModuleFlags::LINEAR_SLIDES) { 0x01 } else { 0 },
+        module.speed.as_u8().into(),
+        module.tempo.as_u8().into(),
+    ] {
+        u16(&mut out, value);
+    }
+    let mut order_table = [0; 256];
+    order_table[..orders.len()].copy_from_slice(&orders);
+    out.extend_from_slice(&order_table);
+
+    for pattern in &module.patterns {
+        self::pattern(&mut out, module, pattern, channels, &mut report);
+    }
+    for instrument in &instruments {
+        self::instrument(&mut out, instrument, &mut report);
+    }
+    Ok((out, report))
+}
+
+/// Collects the samples the instrument maps the notes of the XM keyboard to.
+fn xm_instrument<'m>(module: &'m Module, instrument: &'m Instrument) -> XmInstrument<'m> {
+    let mut ids = Vec::new();
+    let mut keyboard = [0; 96];
+    for (key, note) in keyboard.iter_mut().zip(FIRST_NOTE..=LAST_NOTE) {
+        let note = Note::try_from(note).unwrap();
+        let Some(id) = instrument.sample_map[note].filter(|&id| module.get(id).is_some()) else {
+            continue;
+        };
+        let index = ids.iter().position(|&known| known == id).unwrap_or_else(|| {
+            ids.push(id);
+            ids.len() - 1
+        });
+        // Notes of the samples past the 16th play the first one.
+        if index < INSTRUMENT_SAMPLES {
+            *key = index.cast();
+        }
+    }
+    ids.truncate(INSTRUMENT_SAMPLES);
+    let samples = ids.into_iter().filter_map(|id| module.get(id)).collect::<Vec<_>>();
+    XmInstrument { instrument: Some(instrument), samples, keyboard }
+}
+
+/// Returns `true` if the sample has settings which can't be stored, see [`XmReport::samples`].
+fn loses_settings(sample: &Sample) -> bool {
+    sample.global_volume != 64
+        || sample.sustain_loop.is_some()
+        || sample.vibrato_type == 3
+        || sample.fm_patch.is_some()
+}
+
+/// Returns `true` if the instrument has settings which can't be stored, see
+/// [`XmReport::instruments`].
+fn instrument_loses_settings(instrument: &Instrument, samples: usize) -> bool {
+    instrument.new_note_action != 0
+        || instrument.duplicate_check_type != 0
+        || instrument.pitch_pan_separation != 0
+        || instrument.global_volume != 128
+        || instrument.flags.intersects(InstrumentFlags::ENABLE_PANNING | InstrumentFlags::ENABLE_FILTER_CUTOFF | InstrumentFlags::ENABLE_FILTER_RESONANCE)
+        || instrument.random_volume_variation.as_u8() != 0
+        || instrument.random_panning_variation.as_u8() != 0
+        || instrument.mch != 0
+        || instrument.pitch_filter_envelope.flags.contains(EnvelopeFlags::ENABLED)
+        || samples > INSTRUMENT_SAMPLES
+}
+
+/// Writes the pattern packed into the first `channels` channels.
+fn pattern(out: &mut Vec<u8>, module: &Module, pattern: &Pattern, channels: usize, report: &mut XmReport) {
+    report.rows += pattern.rows.len().saturating_sub(MAX_ROWS);
+    let rows = pattern.rows.len().clamp(1, MAX_ROWS);
+    let mut data = Vec::new();
+    if !pattern.is_empty() {
+        for index in 0..rows {
+            let row = pattern.rows.as_slice().get(index);
+            for channel in 0..channels {
+                let command = row.and_then(|row| row.get(Channel::from_u8_index(channel.cast())));
+                let cell = command.map_or([0; 5], |command| self::cell(module, command, report));
+                // The high bit of the first byte marks a packed cell, the low bits select the bytes
+                // which follow.
+                let mask = cell.iter().enumerate().fold(0x80, |mask, (bit, &byte)| if byte != 0 { mask | 1 << bit } else { mask });
+                data.push(mask);
+                data.extend(cell.iter().filter(|&&byte| byte != 0));
+            }
+        }
+    }
+
+    // The header length includes the length field itself.
+    u32(out, 9);
+    out.push(0); // packing
+    u16(out, u16::try_from(rows).unwrap());
+    u16(out, u16::try_from(data.len()).expect("packed pattern is too long"));
+    out.extend_from_slice(&data);
+}
+
+/// Converts the command to the note, instrument, volume, effect and parameter bytes.
+fn cell(module: &Module, command: &Command, report: &mut XmReport) -> [u8; 5] {
+    let mut cut = false;
+    let note = match command.note {
+        None => 0,
+        Some(NoteCmd::Play(note)) => match u8::from(note) {
+            note @ FIRST_NOTE ..= LAST_NOTE => note - FIRST_NOTE + 1,
+            _ => {
+                report.notes += 1;
+                0
+            }
+        },
+        Some(NoteCmd::Off) => 97,
+        Some(NoteCmd::Cut) => {
+            cut = true;
+            0
+        }
+        Some(NoteCmd::Fade) | Some(NoteCmd::ParamControl { .. }) => {
+            report.notes += 1;
+            0
+        }
+    };
+    // In sample mode the instrument is the sample, which is stored as an instrument.
+    let instrument = command.instrument.map_or(0, |instrument| {
+        let limit = if module.flags.contains(ModuleFlags::USE_INSTRUMENTS) { module.instruments.len() } else { module.samples.len() };
+        if usize::from(instrument.as_u8()) < limit { instrument.as_u8() + 1 } else { 0 }
+    });
+
+    let mut effect = command.effect.and_then(|effect| {
+        let converted = self::effect(effect);
+        if converted.is_none() {
+            report.effects += 1;
+        }
+        converted
+    });
+    let volume = match command.volume.map(volume) {
+        None => 0,
+        Some(Ok(volume)) => volume,
+        Some(Err(moved)) => {
+            if effect.is_none() {
+                effect = Some(moved);
+            } else {
+                report.volume_commands += 1;
+            }
+            0
+        }
+    };
+    if cut {
+        if effect.is_none() {
+            effect = Some((0x0E, 0xC0));
+        } else {
+            report.notes += 1;
+        }
+    }
+
+    let (effect, param) = effect.unwrap_or((0, 0));
+    [note, instrument, volume, effect, param]
+}
+
+/// Converts the volume column, the error is the effect portamento is moved to.
+fn volume(volume: VolumeCmd) -> Result<u8, (u8, u8)> {
+    let param = |value: Option<RangedU8<1, 9>>| value.map_or(0, RangedU8::as_u8);
+    Ok(match volume {
+        VolumeCmd::SetVolume(volume) => 0x10 + volume.as_u8(),
+        VolumeCmd::Panning(pan) => 0xC0 | ((pan.as_u8() + 2) / 4).min(0x0F),
+        VolumeCmd::VolumeSlideDown(value) => 0x60 | param(value),
+        VolumeCmd::VolumeSlideUp(value) => 0x70 | param(value),
+        VolumeCmd::FineVolumeDown(value) => 0x80 | param(value),
+        VolumeCmd::FineVolumeUp(value) => 0x90 | param(value),
+        VolumeCmd::Vibrato(value) => 0xB0 | param(value),
+        // XM speeds are 16 times the parameter.
+        VolumeCmd::TonePortamento(value) => {
+            let speed = u16::from(TONE_PORTAMENTO_SPEEDS[usize::from(param(value))]);
+            0xF0 | if speed == 0 { 0 } else { ((speed + 8) / 16).clamp(1, 0x0F).cast::<u8>() }
+        }
+        VolumeCmd::PortamentoDown(value) => return Err((0x02, param(value) * 4)),
+        VolumeCmd::PortamentoUp(value) => return Err((0x01, param(value) * 4)),
+    })
+}
+
+/// Converts the IT effect to the XM effect, `None` if FT2 doesn't have it.
+///
+/// This is the inverse of the conversion of the XM importer.
+fn effect(effect: EffectCmd) -> Option<(u8, u8)> {
+    let (code, param) = effect.to_raw();
+    let (x, y) = (param >> 4, param & 0x0F);
+    // XM can't slide in both directions, a slide with one nibble set is stored as it is.
+    let slide = (x == 0 || y == 0).then_some(param);
+    Some(match code {
+        // `Axx`
+        0x01 if (1..0x20).contains(&param) => (0x0F, param),
+        // `Bxx`
+        0x02 => (0x0B, param),
+        // `Cxx` is stored in binary coded decimal.
+        0x03 if param < 100 => (0x0D, ((param / 10) << 4) | (param % 10)),
+        // `Dxy`, the fine slides are `DxF` and `DFy`
+        0x04 if x != 0 && y == 0xF => (0x0E, 0xA0 | x),
+        0x04 if x == 0xF && y != 0 => (0x0E, 0xB0 | y),
+        0x04 => (0x0A, slide?),
+        // `Exx` and `Fxx`, the fine slides are `E2x` and `E1x`, the extra fine ones `X2x` and `X1x`
+        0x05 if param < 0xE0 => (0x02, param),
+        0x05 if x == 0xF => (0x0E, 0x20 | y),
+        0x05 => (0x21, 0x20 | y),
+        0x06 if param < 0xE0 => (0x01, param),
+        0x06 if x == 0xF => (0x0E, 0x10 | y),
+        0x06 => (0x21, 0x10 | y),
+        // `Gxx`, `Hxy`
+        0x07 => (0x03, param),
+        0x08 => (0x04, param),
+        // `Ixy`
+        0x09 => (0x1D, param),
+        // `Jxy`
+        0x0A if param != 0 => (0x00, param),
+        // `Kxy`, `Lxy`
+        0x0B => (0x06, slide?),
+        0x0C => (0x05, slide?),
+        // `Oxx`
+        0x0F => (0x09, param),
+        // `Pxy`, XM slides right with the high nibble, IT with the low one.
+        0x10 if x == 0 => (0x19, y << 4),
+        0x10 if y == 0 => (0x19, x),
+        // `Qxy`, `Rxy`
+        0x11 => (0x1B, param),
+        0x12 => (0x07, param),
+        // `S1x`-`S4x`, `S8x`, `SBx`-`SEx`
+        0x13 => match x {
+            0x1 => (0x0E, 0x30 | y),
+            0x2 => (0x0E, 0x50 | y),
+            0x3 => (0x0E, 0x40 | y),
+            0x4 => (0x0E, 0x70 | y),
+            0x8 => (0x0E, 0x80 | y),
+            0xB => (0x0E, 0x60 | y),
+            0xC ..= 0xE => (0x0E, param),
+            _ => return None,
+        },
+        // `Txx` without the slides
+        0x14 if param >= 0x20 => (0x0F, param),
+        // `Vxx`, `Wxy`
+        0x16 => (0x10, param / 2),
+        0x17 => (0x11, slide?),
+        // `Xxx`
+        0x18 => (0x08, param),
+        _ => return None,
+    })
+}
+
+/// Writes the instrument header followed by the sample headers and the sample data.
+fn instrument(out: &mut Vec<u8>, xm: &XmInstrument<'_>, report: &mut XmReport) {
+    let header = out.len();
+    u32(out, if xm.samples.is_empty() { EMPTY_INSTRUMENT_HEADER_SIZE } else { INSTRUMENT_HEADER_SIZE });
+    let name = xm.instrument.map_or(&xm.samples[0].name, |instrument| &instrument.name);
+    out.extend_from_slice(&name.bytes[..22]);
+    out.push(0); // type
+    u16(out, u16::try_from(xm.samples.len()).unwrap());
+    if xm.samples.is_empty() {
+        return;
+    }
+
+    let first = xm.samples[0];
+    if let Some(instrument) = xm.instrument {
+        let mapped = count_mapped(instrument);
+        if instrument_loses_settings(instrument, mapped) || xm.samples.iter().any(|sample| !same_vibrato(sample, first)) {
+            report.instruments += 1;
+        }
+    }
+
+    u32(out, SAMPLE_HEADER_SIZE);
+    out.extend_from_slice(&xm.keyboard);
+    let disabled = Envelope { flags: EnvelopeFlags::empty(), envelope_loop: None, sustain_loop: None, nodes: Vec::new() };
+    let (volume, panning) = match xm.instrument {
+        Some(instrument) => (&instrument.volume_envelope, &instrument.panning_envelope),
+        None => (&disabled, &disabled),
+    };
+    let volume = envelope(volume, |value| value.clamp(0, 64), report);
+    let panning = envelope(panning, |value| value.clamp(-32, 32) + 32, report);
+    for (points, ..) in [&volume, &panning] {
+        for &(tick, value) in points {
+            u16(out, tick);
+            u16(out, value);
+        }
+    }
+    out.extend_from_slice(&[volume.1, panning.1]);
+    out.extend_from_slice(&volume.2);
+    out.extend_from_slice(&panning.2);
+    out.extend_from_slice(&[volume.3, panning.3]);
+
+    // XM has sine, square, ramp down and ramp up, IT has sine, ramp down, square and random.
+    out.push(match first.vibrato_type {
+        1 => 2,
+        2 => 1,
+        _ => 0,
+    });
+    out.extend_from_slice(&[first.vibrato_rate, first.vibrato_depth, first.vibrato_speed]);
+    let fadeout = xm.instrument.map_or(0, |instrument| (u16::from(instrument.instrument_fadeout) * 32).min(0xFFF));
+    u16(out, fadeout);
+    out.resize(header + usize::try_from(INSTRUMENT_HEADER_SIZE).unwrap(), 0);
+
+    let data = xm.samples.iter().map(|sample| sample_header(out, sample)).collect::<Vec<_>>();
+    for data in data {
+        out.extend_from_slice(&data);
+    }
+}
+
+/// Returns the number of different samples the instrument maps the notes of the XM keyboard to.
+fn count_mapped(instrument: &Instrument) -> usize {
+    let mut ids = Vec::new();
+    for note in FIRST_NOTE..=LAST_NOTE {
+        if let Some(id) = instrument.sample_map[Note::try_from(note).unwrap()] {
+            if !ids.contains(&id) {
+                ids.push(id);
+            }
+        }
+    }
+    ids.len()
+}
+
+fn same_vibrato(a: &Sample, b: &Sample) -> bool {
+    (a.vibrato_type, a.vibrato_rate, a.vibrato_depth, a.vibrato_speed) == (b.vibrato_type, b.vibrato_rate, b.vibrato_depth, b.vibrato_speed)
+}
+
+/// Converts the envelope to the points, the number of points, the sustain point, loop start and
+/// loop end and the XM flags.
+///
+/// Envelopes with more than 12 nodes drop the nodes which are the closest to the line between
+/// their neighbours until 12 are left, the first and last node and the nodes of the loops are
+/// kept.
+fn envelope(envelope: &Envelope, value: impl Fn(i8) -> i8, report: &mut XmReport) -> ([(u16, u16); ENVELOPE_POINTS], u8, [u8; 3], u8) {
+    let nodes = &envelope.nodes;
+    let loops = [envelope.sustain_loop, envelope.envelope_loop];
+    let protected = |index: usize| {
+        index == 0
+            || index + 1 == nodes.len()
+            || loops.iter().flatten().any(|l| usize::from(l.start) == index || usize::from(l.end) == index)
+    };
+
+    let mut kept = (0..nodes.len()).collect::<Vec<_>>();
+    while kept.len() > ENVELOPE_POINTS {
+        let error = |position: usize| {
+            let [previous, node, next] = [kept[position - 1], kept[position], kept[position + 1]].map(|index| &nodes[index]);
+            let span = f32::from(next.tick) - f32::from(previous.tick);
+            let line = if span > 0.0 {
+                let t = (f32::from(node.tick) - f32::from(previous.tick)) / span;
+                f32::from(previous.value) + t * (f32::from(next.value) - f32::from(previous.value))
+            } else {
+                f32::from(previous.value)
+            };
+            (line - f32::from(node.value)).abs()
+        };
+        let removed = (1..kept.len() - 1)
+            .filter(|&position| !protected(kept[position]))
+            .min_by(|&a, &b| error(a).total_cmp(&error(b)));
+        match removed {
+            Some(position) => {
+                kept.remove(position);
+            }
+            None => break,
+        }
+    }
+    kept.truncate(ENVELOPE_POINTS);
+    let lossy = kept.len() < nodes.len() || envelope.sustain_loop.is_some_and(|l| l.start != l.end);
+    if lossy {
+        report.envelopes += 1;
+    }
+
+    let mut points = [(0, 0); ENVELOPE_POINTS];
+    for (point, &index) in points.iter_mut().zip(&kept) {
+        let node = nodes[index];
+        *point = (node.tick, u16::try_from(value(node.value)).unwrap_or(0));
+    }
+    let position = |node: u8| kept.iter().position(|&index| index == usize::from(node)).map_or(0, |position| position.cast());
+
+    let mut flags = 0;
+    if envelope.flags.contains(EnvelopeFlags::ENABLED) && !kept.is_empty() {
+        flags |= 0x01;
+    }
+    let mut loop_points = [0; 3];
+    if let Some(sustain) = envelope.sustain_loop.filter(|_| envelope.flags.contains(EnvelopeFlags::SUSTAIN)) {
+        flags |= 0x02;
+        loop_points[0] = position(sustain.start);
+    }
+    if let Some(l) = envelope.envelope_loop.filter(|_| envelope.flags.contains(EnvelopeFlags::LOOP)) {
+        flags |= 0x04;
+        loop_points[1] = position(l.start);
+        loop_points[2] = position(l.end);
+    }
+    (points, kept.len().cast(), loop_points, flags)
+}
+
+/// Writes the sample header and returns the encoded sample data.
+fn sample_header(out: &mut Vec<u8>, sample: &Sample) -> Vec<u8> {
+    let data = match (&sample.fm_patch, &sample.data) {
+        (None, Some(data)) => &data[..],
+        _ => &[][..],
+    };
+    let channels = if sample.is_stereo() { 2 } else { 1 };
+    let planar = (0..channels).flat_map(|channel| data.iter().skip(channel).step_by(channels).copied()).collect::<Vec<_>>();
+    let eight_bit = planar.iter().all(|&x| exact_8bit(x).is_some());
+    let width = if eight_bit { 1 } else { 2 };
+
+    // Sample data is stored as deltas of signed values, for each channel from zero.
+    let mut bytes = Vec::with_capacity(planar.len() * width);
+    for channel in planar.chunks((data.len() / channels).max(1)) {
+        if eight_bit {
+            let mut last = 0i8;
+            for &x in channel {
+                let value = exact_8bit(x).unwrap();
+                bytes.extend(value.wrapping_sub(last).to_le_bytes());
+                last = value;
+            }
+        } else {
+            let mut last = 0i16;
+            for &x in channel {
+                let value = convert::f32_to_i16(x);
+                bytes.extend(value.wrapping_sub(last).to_le_bytes());
+                last = value;
+            }
+        }
+    }
+
+    // The length and the loop count the bytes of all channels.
+    let frame_size = u32::try_from(width * channels).unwrap();
+    let (loop_type, loop_start, loop_end) = match sample.loop_ {
+        Some(l) if l.start < l.end && l.end <= sample.length() => (if l.bidi { 2 } else { 1 }, l.start, l.end),
+        _ => (0, 0, 0),
+    };
+    let (relative_note, finetune) = C5Speed::from(sample.samplerate_c5).to_relative_note();
+    let panning = sample.default_pan().map_or(128, |pan| ((u16::from(pan.as_u8()) * 255 + 32) / 64).cast::<u8>());
+
+    u32(out, u32::try_from(bytes.len()).unwrap());
+    u32(out, loop_start * frame_size);
+    u32(out, (loop_end - loop_start) * frame_size);
+    out.push(sample.default_volume.min(64));
+    out.extend_from_slice(&finetune.to_le_bytes());
+    out.push(loop_type | if eight_bit { 0 } else { 0x10 } | if channels == 2 { 0x20 } else { 0 });
+    out.push(panning);
+    out.extend_from_slice(&relative_note.to_le_bytes());
+    out.push(0); // not compressed
+    out.extend_from_slice(&sample.name.bytes[..22]);
+    bytes
+}
+
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::error::VerboseError;
+    use crate::formats::{self, empty_instrument, empty_sample};
+
+    #[test]
+    fn to_xm() {
+        let mut sample = empty_sample();
+        sample.data = Some(vec![0.0, 1.0, -1.0, convert::i16_to_f32(0x4000)].into());
+        sample.loop_ = Some(SampleLoop { start: 1, end: 3, bidi: true });
+        sample.samplerate_c5 = 16726;
+        sample.default_volume = 48;
+        sample.default_panning = 64 | Sample::dfp_usePanning;
+
+        // A volume envelope of 15 nodes looping from the 3rd to the 13th one.
+        let mut instrument = empty_instrument();
+        instrument.sample_map.map[60] = Some(SampleId::try_from(0).unwrap());
+        instrument.instrument_fadeout = 2;
+        instrument.volume_envelope = Envelope {
+            flags: EnvelopeFlags::ENABLED | EnvelopeFlags::LOOP,
+            envelope_loop: Some(EnvelopeLoop { start: 2, end: 12 }),
+            sustain_loop: None,
+            nodes: (0..15).map(|index| Node { tick: index * 10, value: if index == 7 { 0 } else { 64 } }).collect(),
+        };
+
+        let mut pattern = Pattern { active_channels: ActiveChannels::empty(), rows: vec![Row::empty(); 2], truncated: false, highlight: None };
+        pattern.set_note(0, Channel::new(1), NoteCmd::Play(Note::try_from(60).unwrap()));
+        pattern.set_instrument(0, Channel::new(1), InstrumentId::try_from(0).unwrap());
+        pattern.set_volume(0, Channel::new(1), VolumeCmd::SetVolume(RangedU8::try_from(32).unwrap()));
+        pattern.set_effect(0, Channel::new(1), EffectCmd::Special(Some(Special::SetSurround(true))));
+        pattern.set_note(1, Channel::new(2), NoteCmd::Cut);
+        pattern.set_volume(1, Channel::new(2), VolumeCmd::PortamentoUp(Some(RangedU8::try_from(2).unwrap())));
+        pattern.set_effect(1, Channel::new(3), EffectCmd::Panbrello(None, None));
+
+        let mut builder = ModuleBuilder::new();
+        let id = builder.add_pattern(pattern).unwrap();
+        let mut module = builder.build().unwrap();
+        module.flags |= ModuleFlags::USE_INSTRUMENTS;
+        module.samples.push(sample);
+        module.instruments.push(instrument);
+        module.orders = vec![Order::Index(id), Order::Separator, Order::Index(id)];
+        module.init_channel_panning = [ChannelPan::CENTRE; MAX_CHANNELS];
+
+        let (file, report) = module.to_xm().unwrap();
+        assert_eq!(report, XmReport { envelopes: 1, notes: 1, effects: 2, ..XmReport::default() });
+
+        let parsed = formats::xm::module_file::<VerboseError<&[u8]>>(&file).unwrap();
+        assert_eq!(parsed.orders, [Order::Index(id), Order::Index(id)]);
+        assert!(parsed.flags.contains(ModuleFlags::LINEAR_SLIDES));
+
+        let instrument = &parsed.instruments[0];
+        assert_eq!(instrument.instrument_fadeout, 2);
+        assert_eq!(instrument.sample_map.map[60], Some(SampleId::try_from(0).unwrap()));
+        let envelope = &instrument.volume_envelope;
+        assert_eq!(envelope.nodes.len(), 12);
+        assert!(envelope.nodes.iter().any(|node| (node.tick, node.value) == (70, 0)));
+        let envelope_loop = envelope.envelope_loop.unwrap();
+        assert_eq!([envelope_loop.start, envelope_loop.end].map(|node| envelope.nodes[usize::from(node)].tick), [20, 120]);
+
+        let sample = &parsed.samples[0];
+        assert_eq!(sample.data, module.samples[0].data);
+        assert_eq!((sample.samplerate_c5, sample.default_volume), (16726, 48));
+        assert_eq!(sample.default_pan().map(RangedU8::as_u8), Some(64));
+        assert!(matches!(sample.loop_, Some(SampleLoop { start: 1, end: 3, bidi: true })));
+
+        let pattern = &parsed.patterns[0];
+        let command = pattern.command(0, Channel::new(1)).unwrap();
+        assert!(matches!(command.note, Some(NoteCmd::Play(note)) if u8::from(note) == 60));
+        assert_eq!(command.instrument, Some(InstrumentId::try_from(0).unwrap()));
+        assert!(matches!(command.volume, Some(VolumeCmd::SetVolume(volume)) if volume.as_u8() == 32));
+        assert!(command.effect.is_none());
+        // The portamento takes the effect column, the note cut doesn't fit anymore.
+        let command = pattern.command(1, Channel::new(2)).unwrap();
+        assert!(command.note.is_none());
+        assert_eq!(command.effect.map(EffectCmd::to_raw), Some((b'F' - b'A' + 1, 0x08)));
+    }
+}